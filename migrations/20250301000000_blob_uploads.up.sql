@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS blob_uploads (
+    id TEXT PRIMARY KEY NOT NULL,
+    did TEXT NOT NULL,
+    mime TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    FOREIGN KEY (did) REFERENCES accounts(did)
+);
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS backlinks_subject;
+DROP TABLE IF EXISTS backlinks;
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS backlinks (
+    -- The URI referenced by the record (e.g. the post being replied to).
+    subject TEXT NOT NULL,
+    did TEXT NOT NULL,
+    collection TEXT NOT NULL,
+    rkey TEXT NOT NULL,
+    PRIMARY KEY (did, collection, rkey, subject),
+    FOREIGN KEY (did) REFERENCES accounts(did)
+);
+
+CREATE INDEX IF NOT EXISTS backlinks_subject ON backlinks(subject);
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS repo_stats (
+    did TEXT PRIMARY KEY NOT NULL,
+    block_bytes INTEGER NOT NULL DEFAULT 0,
+    blob_bytes INTEGER NOT NULL DEFAULT 0,
+    records INTEGER NOT NULL DEFAULT 0,
+    -- The start of the current growth window (unix seconds), and the account's total storage at
+    -- that point.
+    window_start INTEGER NOT NULL,
+    window_base INTEGER NOT NULL DEFAULT 0,
+    -- Whether a growth alert has been raised during the current window.
+    alerted BOOLEAN NOT NULL DEFAULT FALSE,
+    FOREIGN KEY (did) REFERENCES accounts(did)
+);
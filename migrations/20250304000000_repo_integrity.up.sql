@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS repo_integrity (
+    did TEXT PRIMARY KEY NOT NULL,
+    -- The last head that passed an integrity check.
+    good_root TEXT,
+    good_rev TEXT,
+    checked_at TIMESTAMP,
+    -- The most recent head that failed an integrity check, and why.
+    failed_root TEXT,
+    failure TEXT,
+    failed_at TIMESTAMP,
+    FOREIGN KEY (did) REFERENCES accounts(did)
+);
@@ -0,0 +1,2 @@
+-- Account listings filter by status and paginate by DID.
+CREATE INDEX IF NOT EXISTS accounts_status ON accounts (status, did);
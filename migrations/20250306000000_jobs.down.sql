@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS jobs_state;
+DROP INDEX IF EXISTS jobs_unfinished;
+DROP TABLE IF EXISTS jobs;
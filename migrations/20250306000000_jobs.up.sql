@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS jobs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- The kind of job (see `jobs::KINDS`), and what it operates on (e.g. a DID).
+    kind TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    -- One of 'pending', 'running', 'done' or 'failed'.
+    state TEXT NOT NULL DEFAULT 'pending',
+    error TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    started_at TIMESTAMP,
+    finished_at TIMESTAMP
+);
+
+-- At most one unfinished job of each kind per subject.
+CREATE UNIQUE INDEX IF NOT EXISTS jobs_unfinished ON jobs(kind, subject)
+    WHERE state IN ('pending', 'running');
+CREATE INDEX IF NOT EXISTS jobs_state ON jobs(state);
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS migrations (
+    did TEXT PRIMARY KEY NOT NULL,
+    -- The last step taken (see `migration::Step`).
+    step TEXT NOT NULL,
+    started_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    repo_imported_at TIMESTAMP,
+    blobs_imported INTEGER NOT NULL DEFAULT 0,
+    preferences_imported_at TIMESTAMP,
+    -- The error of the last failed step, cleared by the next successful one.
+    error TEXT,
+    -- Whether the migration has been reported as stuck.
+    stuck BOOLEAN NOT NULL DEFAULT FALSE,
+    FOREIGN KEY (did) REFERENCES accounts(did)
+);
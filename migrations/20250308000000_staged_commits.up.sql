@@ -0,0 +1,12 @@
+-- Commits whose blocks have been appended to a repository's blockstore and committed to by the
+-- head pointer, but not yet marked durable (see `storage`). Normally empty.
+CREATE TABLE IF NOT EXISTS staged_commits (
+    did TEXT PRIMARY KEY NOT NULL,
+    -- The commit the staged blocks belong to.
+    root TEXT NOT NULL,
+    -- The length of the blockstore before and after the blocks were appended, in bytes.
+    len_before INTEGER NOT NULL,
+    len_after INTEGER NOT NULL,
+    staged_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (did) REFERENCES accounts(did)
+);
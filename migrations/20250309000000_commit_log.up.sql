@@ -0,0 +1,17 @@
+-- Commits made to each hosted repository through the write endpoints (see `commitlog`).
+CREATE TABLE IF NOT EXISTS commit_log (
+    did TEXT NOT NULL,
+    rev TEXT NOT NULL,
+    cid TEXT NOT NULL,
+    -- The number of records created, updated and deleted by the commit.
+    creates INTEGER NOT NULL,
+    updates INTEGER NOT NULL,
+    deletes INTEGER NOT NULL,
+    -- The firehose sequence number of the commit's event, once broadcast.
+    seq INTEGER,
+    committed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (did, rev),
+    FOREIGN KEY (did) REFERENCES accounts(did)
+);
+
+CREATE INDEX IF NOT EXISTS commit_log_committed_at ON commit_log (committed_at);
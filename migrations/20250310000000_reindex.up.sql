@@ -0,0 +1,13 @@
+-- Repositories whose metadata (storage statistics, blob references and backlinks) must be rebuilt
+-- from their blockstores (see `reindex`). Rows are removed once done.
+CREATE TABLE IF NOT EXISTS reindex (
+    did TEXT PRIMARY KEY NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    -- The error of the last failed attempt, if any.
+    error TEXT,
+    FOREIGN KEY (did) REFERENCES accounts(did)
+);
+
+-- Every account that exists at this point was written by an earlier version, which may not have
+-- maintained all of that metadata.
+INSERT OR IGNORE INTO reindex (did) SELECT did FROM accounts;
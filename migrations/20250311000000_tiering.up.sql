@@ -0,0 +1,13 @@
+-- The storage tier of each account's repository and blobs (see `tiering`), and when the account
+-- was last active.
+CREATE TABLE IF NOT EXISTS account_tiers (
+    did TEXT PRIMARY KEY NOT NULL,
+    -- One of 'hot', 'cold' (in the archive) or 'warming' (being moved back).
+    tier TEXT NOT NULL DEFAULT 'hot',
+    last_active TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    tiered_at TIMESTAMP,
+    FOREIGN KEY (did) REFERENCES accounts(did)
+);
+
+INSERT OR IGNORE INTO account_tiers (did, last_active)
+    SELECT did, COALESCE(created_at, CURRENT_TIMESTAMP) FROM accounts;
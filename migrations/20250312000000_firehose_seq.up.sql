@@ -0,0 +1,6 @@
+-- The highest firehose sequence number assigned so far (see `firehose::enqueue`). Sequence
+-- numbers continue after it across restarts, so that they never go backwards.
+CREATE TABLE IF NOT EXISTS firehose_seq (
+    id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+    seq INTEGER NOT NULL
+);
@@ -0,0 +1,14 @@
+-- Every event broadcast on the firehose, for backfilling subscribers whose cursor predates the
+-- in-memory history. Pruned by garbage collection (see `gc::STORES`).
+CREATE TABLE IF NOT EXISTS firehose_events (
+    seq INTEGER PRIMARY KEY NOT NULL,
+    -- The event's type (e.g. `#commit`).
+    type TEXT NOT NULL,
+    -- The repository the event pertains to, if any.
+    did TEXT,
+    -- The serialized frame, as broadcast.
+    frame BLOB NOT NULL,
+    sequenced_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS firehose_events_sequenced_at ON firehose_events (sequenced_at);
@@ -0,0 +1,15 @@
+-- Events sequenced but not yet broadcast on the firehose (see `firehose::enqueue`). Commits are
+-- queued in the transaction that moves the repository's head, so that none are lost to a crash;
+-- the firehose moves each to `firehose_events` as it broadcasts it.
+CREATE TABLE IF NOT EXISTS firehose_outbox (
+    seq INTEGER PRIMARY KEY NOT NULL,
+    -- The event's type (e.g. `#commit`).
+    type TEXT NOT NULL,
+    -- The repository the event pertains to, if any.
+    did TEXT,
+    -- The revision of the repository, for commits.
+    rev TEXT,
+    -- The serialized frame, stamped with its sequence number.
+    frame BLOB NOT NULL,
+    queued_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,10 @@
+-- Outstanding refresh tokens, by their `jti` claim. A refresh token is only usable while its row
+-- exists; refreshing a session deletes it (see `auth::rotate_session`), so each is used once.
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id TEXT PRIMARY KEY NOT NULL,
+    did TEXT NOT NULL,
+    -- The token's `exp` claim, in seconds since the epoch.
+    expires_at INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS refresh_tokens_did ON refresh_tokens (did);
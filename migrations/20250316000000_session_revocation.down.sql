@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS revoked_sessions;
+DROP INDEX IF EXISTS refresh_tokens_session;
+ALTER TABLE refresh_tokens DROP COLUMN session;
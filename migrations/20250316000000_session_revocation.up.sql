@@ -0,0 +1,13 @@
+-- The session each refresh token belongs to (its `sid` claim). A session's tokens share its ID
+-- across rotations, so that ending the session can revoke all of them.
+ALTER TABLE refresh_tokens ADD COLUMN session TEXT;
+CREATE INDEX IF NOT EXISTS refresh_tokens_session ON refresh_tokens (session);
+
+-- Sessions ended before their tokens expired (see `auth::Revocations`). A revocation is kept until
+-- every access token issued in the session has expired.
+CREATE TABLE IF NOT EXISTS revoked_sessions (
+    id TEXT PRIMARY KEY NOT NULL,
+    -- In seconds since the epoch.
+    expires_at INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
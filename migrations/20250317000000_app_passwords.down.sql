@@ -0,0 +1,2 @@
+ALTER TABLE refresh_tokens DROP COLUMN app_password;
+DROP TABLE IF EXISTS app_passwords;
@@ -0,0 +1,15 @@
+-- Passwords an account issues to third-party clients, so that they needn't be given its password.
+CREATE TABLE IF NOT EXISTS app_passwords (
+    did TEXT NOT NULL,
+    name TEXT NOT NULL,
+    -- An argon2 hash of the password.
+    password TEXT NOT NULL,
+    -- Whether sessions created with the password may use direct messages.
+    privileged BOOLEAN NOT NULL DEFAULT FALSE,
+    -- RFC 3339, as returned to clients.
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (did, name)
+);
+
+-- The app password each refresh token's session was created with, if any.
+ALTER TABLE refresh_tokens ADD COLUMN app_password TEXT;
@@ -0,0 +1,9 @@
+-- Single-use codes emailed to account holders to confirm an action (e.g. deleting the account).
+-- An account has at most one outstanding code per purpose; requesting another replaces it.
+CREATE TABLE IF NOT EXISTS email_tokens (
+    purpose TEXT NOT NULL,
+    did TEXT NOT NULL,
+    token TEXT NOT NULL,
+    requested_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (purpose, did)
+);
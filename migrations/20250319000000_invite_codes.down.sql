@@ -0,0 +1,6 @@
+DROP TABLE IF EXISTS invite_uses;
+DROP INDEX IF EXISTS invites_created_by;
+DROP INDEX IF EXISTS invites_did;
+ALTER TABLE invites DROP COLUMN created_by;
+ALTER TABLE invites DROP COLUMN disabled;
+ALTER TABLE invites DROP COLUMN available;
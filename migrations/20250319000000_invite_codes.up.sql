@@ -0,0 +1,17 @@
+-- The number of uses each code was created with; `count` is the number remaining.
+ALTER TABLE invites ADD COLUMN available INTEGER NOT NULL DEFAULT 1;
+UPDATE invites SET available = MAX(count, 1);
+-- Disabled codes can no longer be used.
+ALTER TABLE invites ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT FALSE;
+-- 'admin', or the DID of the account that earned the code (see `invites::create_available`).
+ALTER TABLE invites ADD COLUMN created_by TEXT NOT NULL DEFAULT 'admin';
+CREATE INDEX IF NOT EXISTS invites_did ON invites (did);
+CREATE INDEX IF NOT EXISTS invites_created_by ON invites (created_by);
+
+-- The accounts that signed up with each code.
+CREATE TABLE IF NOT EXISTS invite_uses (
+    code TEXT NOT NULL,
+    used_by TEXT NOT NULL,
+    used_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (code, used_by)
+);
@@ -0,0 +1,5 @@
+-- When the account's email address was confirmed, if it has been since it was last changed.
+ALTER TABLE accounts ADD COLUMN email_confirmed_at TIMESTAMP;
+
+-- Email tokens are now stored hashed; outstanding plaintext tokens can no longer be checked.
+DELETE FROM email_tokens;
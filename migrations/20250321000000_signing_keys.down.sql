@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS account_keys;
+DROP TABLE IF EXISTS reserved_keys;
@@ -0,0 +1,17 @@
+-- Signing keys generated by `reserveSigningKey` for accounts migrating here, optionally for a
+-- specific DID. A reservation is claimed by the signup whose DID document references it.
+CREATE TABLE IF NOT EXISTS reserved_keys (
+    -- The public key, in `did:key` form.
+    key TEXT PRIMARY KEY NOT NULL,
+    did TEXT,
+    secret BLOB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS reserved_keys_did ON reserved_keys (did);
+
+-- The signing keys of accounts that don't use the PDS's own key (see `keys::account_key`).
+CREATE TABLE IF NOT EXISTS account_keys (
+    did TEXT PRIMARY KEY NOT NULL,
+    secret BLOB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
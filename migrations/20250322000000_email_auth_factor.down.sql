@@ -0,0 +1,2 @@
+ALTER TABLE email_tokens DROP COLUMN attempts;
+ALTER TABLE accounts DROP COLUMN email_auth_factor;
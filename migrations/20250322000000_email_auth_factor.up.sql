@@ -0,0 +1,5 @@
+-- Whether signing in with the account's password also requires a token sent to its email address.
+ALTER TABLE accounts ADD COLUMN email_auth_factor BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- The number of wrong tokens presented against the outstanding one (see `mail::MAX_ATTEMPTS`).
+ALTER TABLE email_tokens ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
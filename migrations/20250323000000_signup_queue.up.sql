@@ -0,0 +1,6 @@
+-- New accounts waiting to be activated, in order (see `signup_queue`).
+CREATE TABLE IF NOT EXISTS signup_queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    did TEXT NOT NULL UNIQUE,
+    queued_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,2 @@
+ALTER TABLE accounts DROP COLUMN collections_root;
+DROP TABLE IF EXISTS repo_collections;
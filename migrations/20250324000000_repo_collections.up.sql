@@ -0,0 +1,11 @@
+-- The number of records in each collection of each hosted repository (see `collections`).
+CREATE TABLE IF NOT EXISTS repo_collections (
+    did TEXT NOT NULL,
+    collection TEXT NOT NULL,
+    records INTEGER NOT NULL,
+    PRIMARY KEY (did, collection),
+    FOREIGN KEY (did) REFERENCES accounts(did)
+);
+
+-- The head the collection index is up to date with, if any.
+ALTER TABLE accounts ADD COLUMN collections_root TEXT;
@@ -0,0 +1 @@
+DROP INDEX IF EXISTS blob_ref_did_cid;
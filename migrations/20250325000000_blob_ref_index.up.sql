@@ -0,0 +1,2 @@
+-- Blob references are listed per account, in CID order (e.g. by `listMissingBlobs`).
+CREATE INDEX IF NOT EXISTS blob_ref_did_cid ON blob_ref (did, cid);
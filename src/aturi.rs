@@ -0,0 +1,322 @@
+//! AT URI parsing and validation.
+//!
+//! Only the restricted syntax used by the `at-uri` Lexicon string format is supported: an
+//! authority (a DID or handle), optionally followed by a collection NSID and record key.
+//!
+//! Reference: https://atproto.com/specs/at-uri-scheme
+
+use std::{fmt, str::FromStr};
+
+use anyhow::Context;
+use sqlx::SqliteExecutor;
+use thiserror::Error;
+
+use crate::nsid;
+
+/// The maximum length of an AT URI.
+const MAX_LENGTH: usize = 8 * 1024;
+/// The maximum length of a handle.
+const MAX_HANDLE_LENGTH: usize = 253;
+/// The maximum length of a DID.
+const MAX_DID_LENGTH: usize = 2 * 1024;
+/// The maximum length of a record key.
+const MAX_RKEY_LENGTH: usize = 512;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AtUriError {
+    #[error("AT URI is longer than {MAX_LENGTH} characters")]
+    TooLong,
+    #[error("AT URI must begin with at://")]
+    InvalidScheme,
+    #[error("invalid authority {0:?}")]
+    InvalidAuthority(String),
+    #[error("invalid collection: {0}")]
+    InvalidCollection(#[from] nsid::NsidError),
+    #[error("invalid record key {0:?}")]
+    InvalidRecordKey(String),
+    #[error("AT URI has unexpected trailing content")]
+    TrailingContent,
+}
+
+/// A parsed AT URI, e.g. `at://did:plc:abc/app.bsky.feed.post/3jzfcijpj2z2a`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AtUri {
+    authority: String,
+    collection: Option<String>,
+    rkey: Option<String>,
+}
+
+/// Validate the syntax of a DID.
+pub(crate) fn valid_did(s: &str) -> bool {
+    let Some(rest) = s.strip_prefix("did:") else {
+        return false;
+    };
+    let Some((method, id)) = rest.split_once(':') else {
+        return false;
+    };
+
+    s.len() <= MAX_DID_LENGTH
+        && !method.is_empty()
+        && method.bytes().all(|c| c.is_ascii_lowercase())
+        && !id.is_empty()
+        && !id.ends_with([':', '%'])
+        && id
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"._:%-".contains(&c))
+}
+
+/// Validate the syntax of a handle.
+pub(crate) fn valid_handle(s: &str) -> bool {
+    let labels = s.split('.').collect::<Vec<_>>();
+
+    s.len() <= MAX_HANDLE_LENGTH
+        && labels.len() >= 2
+        && labels.iter().all(|l| {
+            !l.is_empty()
+                && l.len() <= 63
+                && l.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-')
+                && !l.starts_with('-')
+                && !l.ends_with('-')
+        })
+        // The top-level domain may not start with a digit.
+        && !labels
+            .last()
+            .is_some_and(|l| l.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+/// Validate the syntax of a record key.
+pub(crate) fn valid_rkey(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= MAX_RKEY_LENGTH
+        && s != "."
+        && s != ".."
+        && s.bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"._:~-".contains(&c))
+}
+
+impl AtUri {
+    /// Construct the URI of a record.
+    pub fn record(did: &str, collection: &str, rkey: &str) -> Self {
+        Self {
+            authority: did.to_string(),
+            collection: Some(collection.to_string()),
+            rkey: Some(rkey.to_string()),
+        }
+    }
+
+    /// The authority (a DID or handle).
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    pub fn collection(&self) -> Option<&str> {
+        self.collection.as_deref()
+    }
+
+    pub fn rkey(&self) -> Option<&str> {
+        self.rkey.as_deref()
+    }
+
+    /// Normalize the URI by replacing a handle authority with the DID of the local account that
+    /// holds it. URIs with a DID authority, or a handle not hosted here, are returned unchanged.
+    pub async fn normalize<'c>(self, db: impl SqliteExecutor<'c>) -> anyhow::Result<Self> {
+        if self.authority.starts_with("did:") {
+            return Ok(self);
+        }
+
+        let did: Option<String> = sqlx::query_scalar(r#"SELECT did FROM handles WHERE handle = ?"#)
+            .bind(&self.authority)
+            .fetch_optional(db)
+            .await
+            .context("failed to query handle")?;
+
+        Ok(match did {
+            Some(did) => Self {
+                authority: did,
+                ..self
+            },
+            None => self,
+        })
+    }
+}
+
+impl FromStr for AtUri {
+    type Err = AtUriError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() > MAX_LENGTH {
+            return Err(AtUriError::TooLong);
+        }
+
+        let rest = s.strip_prefix("at://").ok_or(AtUriError::InvalidScheme)?;
+
+        // N.B: A trailing slash produces an empty segment, which is rejected below.
+        let mut parts = rest.split('/');
+
+        let authority = parts.next().unwrap_or_default();
+        let authority = if valid_did(authority) {
+            authority.to_string()
+        } else if valid_handle(authority) {
+            // Handles are case-insensitive.
+            authority.to_ascii_lowercase()
+        } else {
+            return Err(AtUriError::InvalidAuthority(authority.to_string()));
+        };
+
+        let collection = match parts.next() {
+            Some(c) => {
+                nsid::validate(c)?;
+                Some(c.to_string())
+            }
+            None => None,
+        };
+
+        let rkey = match parts.next() {
+            Some(r) if valid_rkey(r) => Some(r.to_string()),
+            Some(r) => return Err(AtUriError::InvalidRecordKey(r.to_string())),
+            None => None,
+        };
+
+        if parts.next().is_some() {
+            return Err(AtUriError::TrailingContent);
+        }
+
+        Ok(Self {
+            authority,
+            collection,
+            rkey,
+        })
+    }
+}
+
+impl fmt::Display for AtUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at://{}", self.authority)?;
+        if let Some(collection) = &self.collection {
+            write!(f, "/{collection}")?;
+        }
+        if let Some(rkey) = &self.rkey {
+            write!(f, "/{rkey}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{seq::SliceRandom, Rng};
+
+    use super::*;
+
+    #[test]
+    fn parse() {
+        #[rustfmt::skip]
+        let valid = [
+            "at://did:plc:44ybard66vv44zksje25o7dz",
+            "at://did:plc:44ybard66vv44zksje25o7dz/app.bsky.feed.post",
+            "at://did:plc:44ybard66vv44zksje25o7dz/app.bsky.feed.post/3jwdwj2ctlk26",
+            "at://did:web:example.com/app.bsky.actor.profile/self",
+            "at://alice.example.com/app.bsky.feed.post/3jwdwj2ctlk26",
+        ];
+
+        for uri in valid {
+            let parsed = AtUri::from_str(uri).unwrap();
+            assert_eq!(parsed.to_string(), uri);
+        }
+
+        // Handles are normalized to lowercase.
+        assert_eq!(
+            AtUri::from_str("at://Alice.Example.com")
+                .unwrap()
+                .authority(),
+            "alice.example.com"
+        );
+
+        #[rustfmt::skip]
+        let invalid = [
+            "",
+            "at://",
+            "at:/did:plc:abc",
+            "https://did:plc:abc/app.bsky.feed.post/abc",
+            "at://did:plc:abc/",
+            "at://did:plc:abc/app.bsky.feed.post/",
+            "at://did:plc:abc//abc",
+            "at://did:plc:abc/app.bsky.feed.post/abc/",
+            "at://did:plc:abc/app.bsky.feed.post/abc/def",
+            "at://did:plc:abc/app.bsky.feed.post/..",
+            "at://did:plc:abc/app.bsky.feed.post/a%20b",
+            "at://did:plc:abc/app.bsky.feed.post/abc?x=1",
+            "at://did:plc:abc/app.bsky.feed.post/abc#frag",
+            "at://did:plc:abc/not-an-nsid/abc",
+            "at://did:PLC:abc",
+            "at://did:plc:",
+            "at://localhost/app.bsky.feed.post/abc",
+            "at://alice.123/app.bsky.feed.post/abc",
+            "at://-alice.example.com",
+        ];
+
+        for uri in invalid {
+            assert!(AtUri::from_str(uri).is_err(), "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn normalize() {
+        let db = crate::testing::memory_db().await;
+
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev)
+                VALUES ('did:plc:alice', 'alice@example.com', '', '', '', '');
+            INSERT INTO handles (handle, did) VALUES ('alice.example.com', 'did:plc:alice');
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let uri: AtUri = "at://Alice.Example.com/app.bsky.feed.post/3jzfcijpj2z2a"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            uri.normalize(&db).await.unwrap().to_string(),
+            "at://did:plc:alice/app.bsky.feed.post/3jzfcijpj2z2a"
+        );
+
+        // Handles not hosted here are left alone.
+        let uri: AtUri = "at://bob.example.com".parse().unwrap();
+        assert_eq!(uri.clone().normalize(&db).await.unwrap(), uri);
+    }
+
+    #[test]
+    fn fuzz() {
+        // Mutate valid URIs at random and ensure that parsing never panics, and that anything
+        // accepted round-trips.
+        let seeds = [
+            "at://did:plc:44ybard66vv44zksje25o7dz/app.bsky.feed.post/3jwdwj2ctlk26",
+            "at://alice.example.com/app.bsky.feed.like/self",
+        ];
+        let alphabet = b"at:/.-_~%?#@ aZ09\x00\xff";
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..10_000 {
+            let mut uri = seeds.choose(&mut rng).unwrap().as_bytes().to_vec();
+            for _ in 0..rng.gen_range(1..4) {
+                let i = rng.gen_range(0..uri.len());
+                match rng.gen_range(0..3) {
+                    0 => uri[i] = *alphabet.choose(&mut rng).unwrap(),
+                    1 => uri.insert(i, *alphabet.choose(&mut rng).unwrap()),
+                    _ => {
+                        uri.remove(i);
+                    }
+                }
+            }
+
+            let uri = String::from_utf8_lossy(&uri);
+            if let Ok(parsed) = AtUri::from_str(&uri) {
+                assert_eq!(AtUri::from_str(&parsed.to_string()), Ok(parsed));
+            }
+        }
+    }
+}
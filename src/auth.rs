@@ -1,14 +1,31 @@
 //! Authentication primitives.
 
-use anyhow::{anyhow, Context};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, ensure, Context};
 use atrium_crypto::{
     keypair::{Did, Secp256k1Keypair},
     verify::Verifier,
 };
-use axum::{extract::FromRequestParts, http::StatusCode};
+use axum::{
+    extract::{FromRef, FromRequestParts, Request},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
 use base64::Engine;
+use metrics::counter;
+use sha2::{Digest, Sha256};
+use sqlx::SqliteConnection;
+use tracing::warn;
 
-use crate::{auth, AppState, Error};
+use crate::{
+    auth, config::AppConfig, did::DidDocument, error::ErrorMessage, metrics::AUTH_FAILED,
+    status::AccountStatus, Db, Error, SigningKey,
+};
 
 /// This is an axum request extractor that represents an authenticated user.
 ///
@@ -16,20 +33,102 @@ use crate::{auth, AppState, Error};
 /// by an authenticated user.
 pub struct AuthenticatedUser {
     did: String,
+    scope: Scope,
+    status: AccountStatus,
 }
 
 impl AuthenticatedUser {
     pub fn did(&self) -> String {
         self.did.clone()
     }
+
+    pub fn scope(&self) -> Scope {
+        self.scope
+    }
+
+    /// The account's status as of the request. A session outlives the account's activity, so
+    /// e.g. a deactivated account is still authenticated; what it may do is up to
+    /// [`crate::status::enforce`].
+    pub fn status(&self) -> AccountStatus {
+        self.status
+    }
+
+    /// An active account with a full session, for tests that call endpoints directly.
+    #[cfg(test)]
+    pub fn for_test(did: &str) -> Self {
+        Self {
+            did: did.to_string(),
+            scope: Scope::Full,
+            status: AccountStatus::Active,
+        }
+    }
+
+    /// Reject sessions created with an app password, for endpoints that manage the account
+    /// itself (e.g. its credentials, or its identity).
+    pub fn require_full(&self) -> Result<(), Error> {
+        match self.scope {
+            Scope::Full => Ok(()),
+            scope => Err(bad_scope(scope)),
+        }
+    }
+
+    /// Reject sessions created with an unprivileged app password.
+    pub fn require_privileged(&self) -> Result<(), Error> {
+        match self.scope {
+            Scope::Full | Scope::AppPassPrivileged => Ok(()),
+            scope => Err(bad_scope(scope)),
+        }
+    }
+}
+
+fn bad_scope(scope: Scope) -> Error {
+    Error::with_message(
+        StatusCode::UNAUTHORIZED,
+        anyhow!("{} session is not permitted here", scope.as_str()),
+        ErrorMessage::new("InvalidToken", "bad token scope"),
+    )
+}
+
+/// What an access token grants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// A session created with the account's password.
+    Full,
+    /// A session created with an app password: everything but account management.
+    AppPass,
+    /// A session created with a privileged app password, which may also use direct messages.
+    AppPassPrivileged,
+}
+
+impl Scope {
+    /// The token's `scope` claim.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Full => "com.atproto.access",
+            Self::AppPass => "com.atproto.appPass",
+            Self::AppPassPrivileged => "com.atproto.appPassPrivileged",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        [Self::Full, Self::AppPass, Self::AppPassPrivileged]
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+    }
 }
 
-impl FromRequestParts<AppState> for AuthenticatedUser {
+impl<S> FromRequestParts<S> for AuthenticatedUser
+where
+    S: Send + Sync,
+    SigningKey: FromRef<S>,
+    Db: FromRef<S>,
+    Revocations: FromRef<S>,
+{
     type Rejection = crate::Error;
 
     async fn from_request_parts(
         parts: &mut axum::http::request::Parts,
-        state: &AppState,
+        state: &S,
     ) -> std::result::Result<Self, Self::Rejection> {
         let token = parts
             .headers
@@ -52,12 +151,13 @@ impl FromRequestParts<AppState> for AuthenticatedUser {
 
         // N.B: We ignore all fields inside of the token up until this point because they can be
         // attacker-controlled.
-        let (typ, claims) = auth::verify(&state.signing_key.did(), token).map_err(|e| {
-            Error::with_status(
-                StatusCode::UNAUTHORIZED,
-                e.context("failed to verify auth token"),
-            )
-        })?;
+        let (typ, claims) =
+            auth::verify(&SigningKey::from_ref(state).did(), token).map_err(|e| {
+                Error::with_status(
+                    StatusCode::UNAUTHORIZED,
+                    e.context("failed to verify auth token"),
+                )
+            })?;
 
         // Ensure this is an authentication token.
         if typ != "at+jwt" {
@@ -70,22 +170,49 @@ impl FromRequestParts<AppState> for AuthenticatedUser {
         if let Some(exp) = claims.get("exp").and_then(serde_json::Value::as_i64) {
             let now = chrono::Utc::now().timestamp();
             if now >= exp {
-                // FIXME: This should return BAD_REQUEST with a simple JSON body: {"error": "InvalidToken", "message": "..."}
-                return Err(Error::with_status(
-                    StatusCode::BAD_REQUEST,
-                    anyhow!("token has expired"),
-                ));
+                return Err(Error::expired_token(anyhow!("token has expired")));
+            }
+        }
+
+        // Tokens from before scopes were introduced were only ever issued for full sessions.
+        let scope = match claims.get("scope").and_then(serde_json::Value::as_str) {
+            Some(scope) => Scope::parse(scope).ok_or_else(|| {
+                Error::with_status(
+                    StatusCode::UNAUTHORIZED,
+                    anyhow!("invalid token scope {scope}"),
+                )
+            })?,
+            None => Scope::Full,
+        };
+
+        // The session may have been ended early (e.g. by logging out).
+        if let Some(sid) = claims.get("sid").and_then(serde_json::Value::as_str) {
+            if Revocations::from_ref(state).is_revoked(sid) {
+                return Err(Error::expired_token(anyhow!("session {sid} was revoked")));
             }
         }
 
         if let Some(did) = claims.get("iss").and_then(serde_json::Value::as_str) {
-            let _status = sqlx::query_scalar!(r#"SELECT status FROM accounts WHERE did = ?"#, did)
-                .fetch_one(&state.db)
+            let status = sqlx::query_scalar!(r#"SELECT status FROM accounts WHERE did = ?"#, did)
+                .fetch_optional(&Db::from_ref(state))
                 .await
                 .with_context(|| format!("failed to query account {did}"))?;
 
+            // The account may have been deleted since the token was issued.
+            let Some(status) = status else {
+                return Err(Error::invalid_token(anyhow!(
+                    "account {did} no longer exists"
+                )));
+            };
+            let status = AccountStatus::parse(&status).unwrap_or_else(|| {
+                warn!("account {did} has unknown status {status:?}; treating as active");
+                AccountStatus::Active
+            });
+
             Ok(AuthenticatedUser {
                 did: did.to_string(),
+                scope,
+                status,
             })
         } else {
             Err(Error::with_status(
@@ -96,6 +223,380 @@ impl FromRequestParts<AppState> for AuthenticatedUser {
     }
 }
 
+/// This is an axum request extractor that represents an authenticated administrator.
+///
+/// Administrators authenticate with HTTP basic auth, using the username `admin` and the
+/// configured `admin_password`. If no password is configured, all admin requests are rejected.
+pub struct AdminUser;
+
+impl<S> FromRequestParts<S> for AdminUser
+where
+    S: Send + Sync,
+    AppConfig: FromRef<S>,
+{
+    type Rejection = crate::Error;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let config = AppConfig::from_ref(state);
+        let password = match &config.admin_password {
+            Some(password) => password,
+            None => {
+                return Err(Error::with_status(
+                    StatusCode::FORBIDDEN,
+                    anyhow!("admin endpoints are disabled"),
+                ))
+            }
+        };
+
+        let creds = parts
+            .headers
+            .get(axum::http::header::AUTHORIZATION)
+            .and_then(|auth| auth.to_str().ok())
+            .and_then(|auth| auth.strip_prefix("Basic "))
+            .and_then(|creds| base64::prelude::BASE64_STANDARD.decode(creds).ok());
+
+        let expected = format!("admin:{password}");
+
+        // SEC: Compare digests rather than the raw strings so the comparison time doesn't depend
+        // on how much of the password matched.
+        match creds {
+            Some(creds) if Sha256::digest(&creds) == Sha256::digest(expected.as_bytes()) => {
+                Ok(AdminUser)
+            }
+            _ => {
+                counter!(AUTH_FAILED).increment(1);
+
+                Err(Error::with_status(
+                    StatusCode::UNAUTHORIZED,
+                    anyhow!("invalid admin credentials"),
+                ))
+            }
+        }
+    }
+}
+
+/// Middleware that only lets administrators through, for routers that consist of admin endpoints.
+pub async fn require_admin(_admin: AdminUser, request: Request, next: Next) -> Response {
+    next.run(request).await
+}
+
+/// How long an access token is valid for.
+pub const ACCESS_TOKEN_TTL: std::time::Duration = std::time::Duration::from_secs(2 * 60 * 60);
+/// How long a refresh token is valid for, unless it is used (and so rotated) first.
+pub const REFRESH_TOKEN_TTL: std::time::Duration =
+    std::time::Duration::from_secs(90 * 24 * 60 * 60);
+
+/// The `scope` claim of refresh tokens. Refresh tokens are only accepted by `refreshSession`.
+pub const REFRESH_SCOPE: &str = "com.atproto.refresh";
+
+/// A newly issued pair of session tokens.
+pub struct SessionTokens {
+    pub access: String,
+    pub refresh: String,
+}
+
+/// An app password, as used to create a session.
+#[derive(Clone, Debug)]
+pub struct AppPassword {
+    pub name: String,
+    pub privileged: bool,
+}
+
+impl AppPassword {
+    fn scope(&self) -> Scope {
+        if self.privileged {
+            Scope::AppPassPrivileged
+        } else {
+            Scope::AppPass
+        }
+    }
+}
+
+/// Issue a new session for an account, created with its password or one of its app passwords.
+pub async fn issue_session(
+    conn: &mut SqliteConnection,
+    skey: &SigningKey,
+    config: &AppConfig,
+    did: &str,
+    app_password: Option<&AppPassword>,
+) -> anyhow::Result<SessionTokens> {
+    let sid = uuid::Uuid::new_v4().to_string();
+    issue_tokens(conn, skey, config, did, &sid, app_password).await
+}
+
+/// Issue a pair of tokens in a session, recording the refresh token so that it can later be
+/// rotated or revoked.
+async fn issue_tokens(
+    conn: &mut SqliteConnection,
+    skey: &SigningKey,
+    config: &AppConfig,
+    did: &str,
+    sid: &str,
+    app_password: Option<&AppPassword>,
+) -> anyhow::Result<SessionTokens> {
+    let scope = app_password.map_or(Scope::Full, AppPassword::scope);
+    let now = chrono::Utc::now();
+    let aud = config.did();
+
+    let access = sign(
+        skey,
+        "at+jwt",
+        serde_json::json!({
+            "scope": scope.as_str(),
+            "iss": did,
+            "aud": aud,
+            "sid": sid,
+            "iat": now.timestamp(),
+            "exp": (now + ACCESS_TOKEN_TTL).timestamp(),
+        }),
+    )
+    .context("failed to sign jwt")?;
+
+    let jti = uuid::Uuid::new_v4().to_string();
+    let exp = (now + REFRESH_TOKEN_TTL).timestamp();
+    let refresh = sign(
+        skey,
+        "refresh+jwt",
+        serde_json::json!({
+            "scope": REFRESH_SCOPE,
+            "iss": did,
+            "aud": aud,
+            "sid": sid,
+            "jti": jti,
+            "iat": now.timestamp(),
+            "exp": exp,
+        }),
+    )
+    .context("failed to sign refresh jwt")?;
+
+    // Sweep the account's expired refresh tokens while we're here.
+    sqlx::query(r#"DELETE FROM refresh_tokens WHERE did = ? AND expires_at <= ?"#)
+        .bind(did)
+        .bind(now.timestamp())
+        .execute(&mut *conn)
+        .await
+        .context("failed to clean up refresh tokens")?;
+    sqlx::query(
+        r#"
+        INSERT INTO refresh_tokens (id, did, session, app_password, expires_at)
+            VALUES (?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(&jti)
+    .bind(did)
+    .bind(sid)
+    .bind(app_password.map(|a| a.name.as_str()))
+    .bind(exp)
+    .execute(&mut *conn)
+    .await
+    .context("failed to record refresh token")?;
+
+    Ok(SessionTokens { access, refresh })
+}
+
+/// The claims of a verified refresh token.
+struct RefreshClaims {
+    did: String,
+    jti: String,
+    /// The session, unless the token predates session IDs.
+    sid: Option<String>,
+}
+
+/// Verify a refresh token, signed by this PDS and not yet expired. Whether it is still usable is
+/// up to the database.
+fn verify_refresh(skey: &SigningKey, token: &str) -> Result<RefreshClaims, Error> {
+    // N.B: As in `AuthenticatedUser`, the claims can't be trusted until the token is verified.
+    let (typ, claims) = verify(&skey.did(), token)
+        .map_err(|e| Error::invalid_token(e.context("failed to verify refresh token")))?;
+    let claim = |name: &str| claims.get(name).and_then(serde_json::Value::as_str);
+
+    if typ != "refresh+jwt" || claim("scope") != Some(REFRESH_SCOPE) {
+        return Err(Error::invalid_token(anyhow!("not a refresh token")));
+    }
+    let (Some(did), Some(jti)) = (claim("iss"), claim("jti")) else {
+        return Err(Error::invalid_token(anyhow!(
+            "refresh token is missing claims"
+        )));
+    };
+    let exp = claims
+        .get("exp")
+        .and_then(serde_json::Value::as_i64)
+        .ok_or_else(|| Error::invalid_token(anyhow!("refresh token has no expiry")))?;
+    if chrono::Utc::now().timestamp() >= exp {
+        return Err(Error::expired_token(anyhow!("refresh token has expired")));
+    }
+
+    Ok(RefreshClaims {
+        did: did.to_string(),
+        jti: jti.to_string(),
+        sid: claim("sid").map(str::to_string),
+    })
+}
+
+/// Exchange a refresh token for a new pair of session tokens. Returns the account's DID.
+///
+/// The refresh token is consumed: its record is deleted in the same transaction that records its
+/// replacement, so of several concurrent refreshes with the same token, exactly one succeeds.
+pub async fn rotate_session(
+    db: &Db,
+    skey: &SigningKey,
+    config: &AppConfig,
+    token: &str,
+) -> Result<(String, SessionTokens), Error> {
+    let RefreshClaims { did, jti, sid } = verify_refresh(skey, token)?;
+
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+
+    let owner: Option<(String, Option<String>)> =
+        sqlx::query_as(r#"DELETE FROM refresh_tokens WHERE id = ? RETURNING did, app_password"#)
+            .bind(&jti)
+            .fetch_optional(&mut *tx)
+            .await
+            .context("failed to consume refresh token")?;
+    let app_password = match owner {
+        Some((owner, app_password)) if owner == did => app_password,
+        _ => {
+            return Err(Error::invalid_token(anyhow!(
+                "refresh token {jti} has already been used or revoked"
+            )))
+        }
+    };
+
+    // A session created with an app password ends with it.
+    let app_password = match app_password {
+        Some(name) => {
+            let privileged: Option<bool> = sqlx::query_scalar(
+                r#"SELECT privileged FROM app_passwords WHERE did = ? AND name = ?"#,
+            )
+            .bind(&did)
+            .bind(&name)
+            .fetch_optional(&mut *tx)
+            .await
+            .context("failed to query app password")?;
+            let privileged = privileged.ok_or_else(|| {
+                Error::invalid_token(anyhow!("app password {name} has been revoked"))
+            })?;
+
+            Some(AppPassword { name, privileged })
+        }
+        None => None,
+    };
+
+    // Tokens from before session IDs start a session here.
+    let sid = sid.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let tokens = issue_tokens(&mut tx, skey, config, &did, &sid, app_password.as_ref()).await?;
+    tx.commit().await.context("failed to commit transaction")?;
+
+    Ok((did, tokens))
+}
+
+/// End the session a refresh token belongs to: none of its refresh tokens can be used again, and
+/// its access tokens are rejected from now on.
+pub async fn delete_session(
+    db: &Db,
+    revocations: &Revocations,
+    skey: &SigningKey,
+    token: &str,
+) -> Result<(), Error> {
+    let RefreshClaims { jti, sid, .. } = verify_refresh(skey, token)?;
+
+    match sid {
+        Some(sid) => revocations.revoke(db, &sid).await?,
+        // Without a session ID, only the refresh token itself can be revoked.
+        None => {
+            sqlx::query(r#"DELETE FROM refresh_tokens WHERE id = ?"#)
+                .bind(&jti)
+                .execute(db)
+                .await
+                .context("failed to revoke refresh token")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sessions that were ended before their tokens expired, by session ID.
+///
+/// Every authenticated request checks its token against these, so they are mirrored in memory
+/// rather than queried. Revocations are written through to the database, and loaded from it on
+/// startup.
+#[derive(Clone, Debug, Default)]
+pub struct Revocations(Arc<RwLock<HashMap<String, i64>>>);
+
+impl Revocations {
+    /// Load the revocations that are still in effect, discarding the rest.
+    pub async fn load(db: &Db) -> anyhow::Result<Self> {
+        let now = chrono::Utc::now().timestamp();
+        sqlx::query(r#"DELETE FROM revoked_sessions WHERE expires_at <= ?"#)
+            .bind(now)
+            .execute(db)
+            .await
+            .context("failed to clean up revoked sessions")?;
+        let revoked: Vec<(String, i64)> =
+            sqlx::query_as(r#"SELECT id, expires_at FROM revoked_sessions"#)
+                .fetch_all(db)
+                .await
+                .context("failed to load revoked sessions")?;
+
+        Ok(Self(Arc::new(RwLock::new(revoked.into_iter().collect()))))
+    }
+
+    /// Whether a session has been revoked.
+    pub fn is_revoked(&self, sid: &str) -> bool {
+        self.0.read().unwrap().contains_key(sid)
+    }
+
+    /// Revoke a session, and delete its refresh tokens.
+    pub async fn revoke(&self, db: &Db, sid: &str) -> anyhow::Result<()> {
+        // Access tokens are the longest-lived tokens left once the refresh tokens are gone.
+        let now = chrono::Utc::now().timestamp();
+        let expires_at = now + ACCESS_TOKEN_TTL.as_secs() as i64;
+
+        let mut tx = db.begin().await.context("failed to begin transaction")?;
+        sqlx::query(r#"DELETE FROM refresh_tokens WHERE session = ?"#)
+            .bind(sid)
+            .execute(&mut *tx)
+            .await
+            .context("failed to revoke refresh tokens")?;
+        sqlx::query(
+            r#"
+            INSERT INTO revoked_sessions (id, expires_at) VALUES (?, ?)
+                ON CONFLICT (id) DO UPDATE SET expires_at = excluded.expires_at
+            "#,
+        )
+        .bind(sid)
+        .bind(expires_at)
+        .execute(&mut *tx)
+        .await
+        .context("failed to record revoked session")?;
+        tx.commit().await.context("failed to commit transaction")?;
+
+        let mut revoked = self.0.write().unwrap();
+        revoked.retain(|_, exp| *exp > now);
+        revoked.insert(sid.to_string(), expires_at);
+        Ok(())
+    }
+
+    /// Revoke every session of an account.
+    pub async fn revoke_all(&self, db: &Db, did: &str) -> anyhow::Result<()> {
+        let sessions: Vec<String> = sqlx::query_scalar(
+            r#"SELECT DISTINCT session FROM refresh_tokens WHERE did = ? AND session IS NOT NULL"#,
+        )
+        .bind(did)
+        .fetch_all(db)
+        .await
+        .context("failed to query sessions")?;
+
+        for sid in &sessions {
+            self.revoke(db, sid).await?;
+        }
+        Ok(())
+    }
+}
+
 /// Cryptographically sign a JSON web token with the specified key.
 pub fn sign(
     key: &Secp256k1Keypair,
@@ -150,3 +651,273 @@ pub fn verify(key: &str, token: &str) -> anyhow::Result<(String, serde_json::Val
 
     Ok((typ.to_string(), claims))
 }
+
+/// Verify a service auth token (as minted by `getServiceAuth`) issued by the subject of a DID
+/// document, with its `atproto` signing key, for the specified audience and method. Returns the
+/// token's claims.
+pub fn verify_service_auth(
+    doc: &DidDocument,
+    token: &str,
+    aud: &str,
+    lxm: &str,
+) -> anyhow::Result<serde_json::Value> {
+    let did = doc.id.as_str();
+    let key = doc
+        .verification_method
+        .iter()
+        .find(|m| m.id == format!("{did}#atproto") || m.id == "#atproto")
+        .map(|m| format!("did:key:{}", m.public_key_multibase))
+        .with_context(|| format!("{did} has no atproto signing key"))?;
+
+    let (_, claims) = verify(&key, token)?;
+    let claim = |name: &str| claims.get(name).and_then(serde_json::Value::as_str);
+
+    ensure!(claim("iss") == Some(did), "token was not issued by {did}");
+    ensure!(claim("aud") == Some(aud), "token is not intended for {aud}");
+    // A token without a method may be used for any.
+    ensure!(
+        claim("lxm").is_none_or(|m| m == lxm),
+        "token is not intended for {lxm}"
+    );
+    let exp = claims
+        .get("exp")
+        .and_then(serde_json::Value::as_i64)
+        .context("token has no expiry")?;
+    ensure!(chrono::Utc::now().timestamp() < exp, "token has expired");
+
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod test {
+    use atrium_api::types::string::Did as AtDid;
+    use axum::response::IntoResponse;
+
+    use super::*;
+    use crate::did::DidVerificationMethod;
+
+    async fn error_name(e: Error) -> String {
+        let resp = e.into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        body["error"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn refresh_rotation() {
+        let dir = crate::testing::temp_dir("refresh");
+
+        // A file-backed database, so that concurrent refreshes race on real connections.
+        let db = crate::testing::file_db(&dir, 4).await;
+
+        let config = crate::testing::config("", serde_json::json!({}));
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let did = "did:plc:alice";
+
+        let mut conn = db.acquire().await.unwrap();
+        let first = issue_session(&mut conn, &skey, &config, did, None)
+            .await
+            .unwrap();
+        drop(conn);
+
+        // An access token isn't a refresh token.
+        let e = rotate_session(&db, &skey, &config, &first.access)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_name(e).await, "InvalidToken");
+
+        // Of several concurrent refreshes, exactly one succeeds.
+        let tasks = (0..4)
+            .map(|_| {
+                let (db, skey, config, token) = (
+                    db.clone(),
+                    skey.clone(),
+                    config.clone(),
+                    first.refresh.clone(),
+                );
+                tokio::spawn(async move { rotate_session(&db, &skey, &config, &token).await })
+            })
+            .collect::<Vec<_>>();
+        let mut second = None;
+        for task in tasks {
+            match task.await.unwrap() {
+                Ok((d, tokens)) => {
+                    assert_eq!(d, did);
+                    assert!(second.replace(tokens).is_none());
+                }
+                Err(e) => assert_eq!(error_name(e).await, "InvalidToken"),
+            }
+        }
+
+        // The replacement is good for exactly one more refresh.
+        let second = second.unwrap();
+        rotate_session(&db, &skey, &config, &second.refresh)
+            .await
+            .unwrap();
+        let e = rotate_session(&db, &skey, &config, &second.refresh)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_name(e).await, "InvalidToken");
+
+        let expired = sign(
+            &skey,
+            "refresh+jwt",
+            serde_json::json!({
+                "scope": REFRESH_SCOPE,
+                "iss": did,
+                "jti": "expired",
+                "exp": chrono::Utc::now().timestamp() - 1,
+            }),
+        )
+        .unwrap();
+        let e = rotate_session(&db, &skey, &config, &expired)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_name(e).await, "ExpiredToken");
+
+        db.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deleted_session() {
+        #[derive(Clone, FromRef)]
+        struct AuthState {
+            signing_key: SigningKey,
+            db: Db,
+            revocations: Revocations,
+        }
+
+        let db = crate::testing::memory_db().await;
+
+        let config = crate::testing::config("", serde_json::json!({}));
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let did = "did:plc:alice";
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', '', '', '')"#,
+        )
+        .bind(did)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let state = AuthState {
+            signing_key: skey.clone(),
+            db: db.clone(),
+            revocations: Revocations::load(&db).await.unwrap(),
+        };
+        let authenticate = |token: String| {
+            let state = state.clone();
+            async move {
+                let (mut parts, _) = axum::http::Request::builder()
+                    .header(axum::http::header::AUTHORIZATION, format!("Bearer {token}"))
+                    .body(())
+                    .unwrap()
+                    .into_parts();
+                AuthenticatedUser::from_request_parts(&mut parts, &state).await
+            }
+        };
+
+        let mut conn = db.acquire().await.unwrap();
+        let session = issue_session(&mut conn, &skey, &config, did, None)
+            .await
+            .unwrap();
+        let other = issue_session(&mut conn, &skey, &config, did, None)
+            .await
+            .unwrap();
+        drop(conn);
+
+        // Access tokens issued earlier in the session are revoked along with the latest.
+        let (_, rotated) = rotate_session(&db, &skey, &config, &session.refresh)
+            .await
+            .unwrap();
+        assert_eq!(
+            authenticate(session.access.clone()).await.unwrap().did(),
+            did
+        );
+
+        delete_session(&db, &state.revocations, &skey, &rotated.refresh)
+            .await
+            .unwrap();
+
+        for access in [&session.access, &rotated.access] {
+            let e = authenticate(access.clone()).await.err().unwrap();
+            assert_eq!(error_name(e).await, "ExpiredToken");
+        }
+        let e = rotate_session(&db, &skey, &config, &rotated.refresh)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error_name(e).await, "InvalidToken");
+
+        // Other sessions are unaffected.
+        authenticate(other.access.clone()).await.unwrap();
+        rotate_session(&db, &skey, &config, &other.refresh)
+            .await
+            .unwrap();
+
+        // Revocations survive a restart.
+        let revocations = Revocations::load(&db).await.unwrap();
+        assert_eq!(revocations.0.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn service_auth() {
+        let key = Secp256k1Keypair::create(&mut rand::thread_rng());
+        let did = "did:plc:alice";
+        let doc = DidDocument {
+            context: vec![],
+            id: AtDid::new(did.to_string()).unwrap(),
+            also_known_as: vec![],
+            verification_method: vec![DidVerificationMethod {
+                id: format!("{did}#atproto"),
+                ty: "Multikey".to_string(),
+                controller: did.to_string(),
+                public_key_multibase: key.did().strip_prefix("did:key:").unwrap().to_string(),
+            }],
+            service: vec![],
+        };
+        let aud = "did:web:pds.example.com";
+        let lxm = "com.atproto.server.createAccount";
+        let token =
+            |key: &Secp256k1Keypair, claims: serde_json::Value| sign(key, "JWT", claims).unwrap();
+        let exp = chrono::Utc::now().timestamp() + 60;
+
+        let valid = token(
+            &key,
+            serde_json::json!({ "iss": did, "aud": aud, "lxm": lxm, "exp": exp }),
+        );
+        verify_service_auth(&doc, &valid, aud, lxm).unwrap();
+
+        let other = Secp256k1Keypair::create(&mut rand::thread_rng());
+        for token in [
+            // Signed by another key.
+            token(
+                &other,
+                serde_json::json!({ "iss": did, "aud": aud, "lxm": lxm, "exp": exp }),
+            ),
+            // For another audience, or method.
+            token(
+                &key,
+                serde_json::json!({ "iss": did, "aud": "did:web:other.example.com", "exp": exp }),
+            ),
+            token(
+                &key,
+                serde_json::json!({ "iss": did, "aud": aud, "lxm": "com.atproto.repo.importRepo", "exp": exp }),
+            ),
+            // Expired.
+            token(
+                &key,
+                serde_json::json!({ "iss": did, "aud": aud, "lxm": lxm, "exp": exp - 120 }),
+            ),
+        ] {
+            assert!(verify_service_auth(&doc, &token, aud, lxm).is_err());
+        }
+    }
+}
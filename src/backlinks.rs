@@ -0,0 +1,210 @@
+//! An index of records in hosted repositories that reference other records.
+//!
+//! This maps a subject URI (e.g. a post) to the local records that reference it (e.g. replies
+//! and likes), so that local features can find them without scanning every repository.
+//!
+//! Only the fields configured in `repo.backlinks` are indexed, to keep the index bounded.
+
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sqlx::SqliteConnection;
+
+use crate::{aturi::AtUri, Db};
+
+/// A local record that references a subject.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Backlink {
+    pub did: String,
+    pub collection: String,
+    pub rkey: String,
+}
+
+/// Extract the subject URIs referenced by a record, given the configured paths for each
+/// collection.
+///
+/// Paths are dot-separated field names, e.g. `reply.parent.uri`. Values that are not valid AT
+/// URIs are ignored.
+pub fn extract(
+    paths: &HashMap<String, Vec<String>>,
+    collection: &str,
+    record: &serde_json::Value,
+) -> Vec<AtUri> {
+    let Some(paths) = paths.get(collection) else {
+        return Vec::new();
+    };
+
+    let mut subjects = paths
+        .iter()
+        .filter_map(|p| {
+            p.split('.')
+                .try_fold(record, |v, k| v.get(k))
+                .and_then(|v| v.as_str())
+                .and_then(|s| AtUri::from_str(s).ok())
+        })
+        .collect::<Vec<_>>();
+
+    subjects.sort_by_key(|s| s.to_string());
+    subjects.dedup();
+    subjects
+}
+
+/// Index the subjects referenced by a record.
+///
+/// Subjects are normalized first, so that references to a local account by handle are indexed
+/// under its DID.
+pub async fn link(
+    conn: &mut SqliteConnection,
+    did: &str,
+    collection: &str,
+    rkey: &str,
+    subjects: &[AtUri],
+) -> Result<()> {
+    for subject in subjects {
+        let subject = subject.clone().normalize(&mut *conn).await?.to_string();
+
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO backlinks (subject, did, collection, rkey)
+                VALUES (?, ?, ?, ?)
+            "#,
+        )
+        .bind(&subject)
+        .bind(did)
+        .bind(collection)
+        .bind(rkey)
+        .execute(&mut *conn)
+        .await
+        .context("failed to insert backlink")?;
+    }
+
+    Ok(())
+}
+
+/// Remove all backlinks from a record, e.g. because it was updated or deleted.
+pub async fn unlink(
+    conn: &mut SqliteConnection,
+    did: &str,
+    collection: &str,
+    rkey: &str,
+) -> Result<()> {
+    sqlx::query(r#"DELETE FROM backlinks WHERE did = ? AND collection = ? AND rkey = ?"#)
+        .bind(did)
+        .bind(collection)
+        .bind(rkey)
+        .execute(&mut *conn)
+        .await
+        .context("failed to remove backlinks")?;
+
+    Ok(())
+}
+
+/// Find the local records that reference a subject URI.
+pub async fn lookup(db: &Db, subject: AtUri) -> Result<Vec<Backlink>> {
+    let subject = subject.normalize(db).await?.to_string();
+
+    let r: Vec<(String, String, String)> = sqlx::query_as(
+        r#"
+        SELECT did, collection, rkey FROM backlinks
+            WHERE subject = ?
+            ORDER BY did, collection, rkey
+        "#,
+    )
+    .bind(&subject)
+    .fetch_all(db)
+    .await
+    .context("failed to query backlinks")?;
+
+    Ok(r.into_iter()
+        .map(|(did, collection, rkey)| Backlink {
+            did,
+            collection,
+            rkey,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reply_backlink() {
+        let db = crate::testing::memory_db().await;
+
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev)
+                VALUES ('did:plc:alice', 'alice@example.com', '', '', '', '');
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let paths = HashMap::from([(
+            "app.bsky.feed.post".to_string(),
+            vec!["reply.parent.uri".to_string()],
+        )]);
+        let post = "at://did:plc:alice/app.bsky.feed.post/3jzfcijpj2z2a";
+
+        // A top-level post references nothing.
+        let top = serde_json::json!({ "text": "hello" });
+        assert!(extract(&paths, "app.bsky.feed.post", &top).is_empty());
+
+        let reply = serde_json::json!({
+            "text": "hi!",
+            "reply": {
+                "root": { "uri": post, "cid": "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm" },
+                "parent": { "uri": post, "cid": "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm" },
+            },
+        });
+        let subjects = extract(&paths, "app.bsky.feed.post", &reply);
+        assert_eq!(subjects, [post.parse().unwrap()]);
+
+        // Malformed URIs are ignored.
+        let bad = serde_json::json!({ "reply": { "parent": { "uri": "at://did:plc:alice/" } } });
+        assert!(extract(&paths, "app.bsky.feed.post", &bad).is_empty());
+
+        // Unconfigured collections are not indexed.
+        assert!(extract(&paths, "app.example.record", &reply).is_empty());
+
+        let mut conn = db.acquire().await.unwrap();
+        link(
+            &mut conn,
+            "did:plc:alice",
+            "app.bsky.feed.post",
+            "3jzfcijpj2z2b",
+            &subjects,
+        )
+        .await
+        .unwrap();
+        drop(conn);
+
+        assert_eq!(
+            lookup(&db, post.parse().unwrap()).await.unwrap(),
+            [Backlink {
+                did: "did:plc:alice".to_string(),
+                collection: "app.bsky.feed.post".to_string(),
+                rkey: "3jzfcijpj2z2b".to_string(),
+            }]
+        );
+
+        // Deleting the reply removes the backlink.
+        let mut conn = db.acquire().await.unwrap();
+        unlink(
+            &mut conn,
+            "did:plc:alice",
+            "app.bsky.feed.post",
+            "3jzfcijpj2z2b",
+        )
+        .await
+        .unwrap();
+        drop(conn);
+
+        assert!(lookup(&db, post.parse().unwrap()).await.unwrap().is_empty());
+    }
+}
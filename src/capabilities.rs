@@ -0,0 +1,158 @@
+//! Machine-readable catalogue of the endpoints and limits of this server.
+//!
+//! Clients can fetch `GET /xrpc/_server/capabilities` to discover which optional endpoints are
+//! implemented (e.g. resumable uploads), rather than probing for 404s. The endpoint list is
+//! collected from the routes as they are registered (see [`Routes`]), so it can't drift from what
+//! is actually served.
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use axum::{extract::State, routing::MethodRouter, Json, Router};
+use serde::Serialize;
+
+use crate::{config::AppConfig, endpoints::MAX_APPLY_WRITES, AppState, APP_USER_AGENT};
+
+/// A router that records the path of every endpoint registered on it.
+///
+/// Each namespace of endpoints builds its own set of routes, with its own middleware (see
+/// [`Routes::map_router`]). They're generic over the router state, so that a namespace whose
+/// handlers only need part of [`AppState`] can be mounted on its own (e.g. in tests).
+pub struct Routes<S = AppState> {
+    router: Router<S>,
+    endpoints: BTreeSet<String>,
+}
+
+impl<S: Clone + Send + Sync + 'static> Default for Routes<S> {
+    fn default() -> Self {
+        Self {
+            router: Router::new(),
+            endpoints: BTreeSet::new(),
+        }
+    }
+}
+
+impl<S: Clone + Send + Sync + 'static> Routes<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register an endpoint, as with [`Router::route`].
+    pub fn route(mut self, path: &str, method_router: MethodRouter<S>) -> Self {
+        self.router = self.router.route(path, method_router);
+        self.endpoints
+            .insert(path.trim_start_matches('/').to_string());
+        self
+    }
+
+    /// Merge the endpoints of another set of routes, as with [`Router::merge`].
+    pub fn merge(mut self, other: Routes<S>) -> Self {
+        self.router = self.router.merge(other.router);
+        self.endpoints.extend(other.endpoints);
+        self
+    }
+
+    /// Transform the underlying router without registering endpoints, e.g. to add layers that
+    /// apply to the endpoints registered so far.
+    pub fn map_router(mut self, f: impl FnOnce(Router<S>) -> Router<S>) -> Self {
+        self.router = f(self.router);
+        self
+    }
+
+    /// Split into the router, and the (sorted) paths of its endpoints.
+    pub fn into_parts(self) -> (Router<S>, Vec<String>) {
+        (self.router, self.endpoints.into_iter().collect())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimits {
+    pub write_points_hourly: u32,
+    pub write_points_daily: u32,
+    /// Expensive sync operations (e.g. `getRepo`) a client may have in flight at once.
+    pub sync_concurrency: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Limits {
+    /// The maximum size of a blob, in bytes.
+    pub max_blob_size: u64,
+    /// The maximum number of operations in a single `applyWrites` call.
+    pub max_apply_writes: usize,
+    pub rate_limits: RateLimits,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    pub version: String,
+    /// Every endpoint served under `/xrpc`: the NSID of standard endpoints, or the path of
+    /// non-standard extensions (e.g. `_blob/createUpload`).
+    pub endpoints: Vec<String>,
+    /// Whether calls to endpoints not listed here are proxied to other services (e.g. the AppView
+    /// or a chat service) with the `atproto-proxy` header.
+    pub service_proxy: bool,
+    pub limits: Limits,
+}
+
+impl Capabilities {
+    pub fn new(config: &AppConfig, endpoints: Vec<String>) -> Self {
+        Self {
+            version: APP_USER_AGENT.to_string(),
+            endpoints,
+            service_proxy: true,
+            limits: Limits {
+                max_blob_size: config.blob.limit,
+                max_apply_writes: MAX_APPLY_WRITES,
+                rate_limits: RateLimits {
+                    write_points_hourly: config.rate_limit.write_points_hourly,
+                    write_points_daily: config.rate_limit.write_points_daily,
+                    sync_concurrency: config.rate_limit.sync_concurrency,
+                },
+            },
+        }
+    }
+}
+
+pub async fn capabilities(State(caps): State<Arc<Capabilities>>) -> Json<Capabilities> {
+    Json(caps.as_ref().clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::endpoints;
+
+    fn config(resumable: bool) -> AppConfig {
+        crate::testing::config(
+            "",
+            serde_json::json!({ "blob": { "resumable": resumable } }),
+        )
+    }
+
+    #[test]
+    fn catalogue() {
+        let (_, enabled) = endpoints::routes(&config(true)).into_parts();
+        let (_, disabled) = endpoints::routes(&config(false)).into_parts();
+
+        for e in [
+            "com.atproto.repo.applyWrites",
+            "com.atproto.sync.subscribeRepos",
+            "_server/capabilities",
+        ] {
+            assert!(enabled.contains(&e.to_string()), "{e} missing");
+            assert!(disabled.contains(&e.to_string()), "{e} missing");
+        }
+
+        // Disabling a feature withdraws its endpoints from the catalogue.
+        let upload = "_blob/createUpload".to_string();
+        assert!(enabled.contains(&upload));
+        assert!(!disabled.contains(&upload));
+
+        let caps = serde_json::to_value(Capabilities::new(&config(true), enabled)).unwrap();
+        assert_eq!(caps["limits"]["maxBlobSize"], 1024);
+        assert_eq!(caps["limits"]["maxApplyWrites"], MAX_APPLY_WRITES);
+        assert_eq!(caps["limits"]["rateLimits"]["writePointsHourly"], 5000);
+    }
+}
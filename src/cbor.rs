@@ -0,0 +1,236 @@
+//! Bounded decoding of untrusted DAG-CBOR.
+//!
+//! Input from the network (e.g. imported CAR files) may be crafted to exhaust the stack with
+//! deeply nested structures, or memory with enormous length prefixes. [`decode`] scans the
+//! encoding first, without recursion or allocation proportional to declared lengths, and only
+//! hands it to `serde_ipld_dagcbor` once it is known to be within [`Limits`].
+
+use anyhow::anyhow;
+use axum::http::StatusCode;
+use metrics::counter;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+use crate::{error::ErrorMessage, metrics::CBOR_REJECTED, Error};
+
+/// Limits applied to an untrusted DAG-CBOR payload.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// The maximum size of the encoded payload, in bytes.
+    pub max_size: usize,
+    /// The maximum nesting depth of lists, maps and tags.
+    pub max_depth: usize,
+    /// The maximum number of entries in a single list or map.
+    pub max_length: u64,
+}
+
+impl Limits {
+    /// Limits for a single repository block (commit, MST node, or record).
+    pub const BLOCK: Self = Self {
+        max_size: 2 * 1024 * 1024,
+        max_depth: 64,
+        max_length: 64 * 1024,
+    };
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CborError {
+    #[error("payload of {0} bytes exceeds the size limit")]
+    TooLarge(usize),
+    #[error("payload exceeds the nesting depth limit")]
+    TooDeep,
+    #[error("collection of {0} entries exceeds the length limit")]
+    TooLong(u64),
+    #[error("payload is truncated")]
+    Truncated,
+    #[error("indefinite-length items are not permitted in DAG-CBOR")]
+    Indefinite,
+    #[error("failed to decode payload: {0}")]
+    Decode(String),
+}
+
+impl CborError {
+    fn reason(&self) -> &'static str {
+        match self {
+            Self::TooLarge(_) => "size",
+            Self::TooDeep => "depth",
+            Self::TooLong(_) => "length",
+            Self::Truncated | Self::Indefinite | Self::Decode(_) => "malformed",
+        }
+    }
+}
+
+impl From<CborError> for Error {
+    fn from(e: CborError) -> Self {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("rejected untrusted CBOR: {e}"),
+            ErrorMessage::new("InvalidRequest", e.to_string()),
+        )
+    }
+}
+
+/// Read the argument of a CBOR item head, returning `(major type, argument)`.
+fn read_head(buf: &[u8], pos: &mut usize) -> Result<(u8, u64), CborError> {
+    let b = *buf.get(*pos).ok_or(CborError::Truncated)?;
+    *pos += 1;
+
+    let (major, info) = (b >> 5, b & 0x1f);
+    let n = match info {
+        0..=23 => return Ok((major, info as u64)),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        31 => return Err(CborError::Indefinite),
+        _ => {
+            return Err(CborError::Decode(format!(
+                "reserved additional info {info}"
+            )))
+        }
+    };
+
+    let bytes = buf.get(*pos..*pos + n).ok_or(CborError::Truncated)?;
+    *pos += n;
+
+    Ok((major, bytes.iter().fold(0u64, |v, b| (v << 8) | *b as u64)))
+}
+
+/// Check that a payload is within limits, without decoding it.
+fn check(buf: &[u8], limits: &Limits) -> Result<(), CborError> {
+    if buf.len() > limits.max_size {
+        return Err(CborError::TooLarge(buf.len()));
+    }
+
+    let mut pos = 0;
+    // The number of items remaining in each open container (and one for the top-level item).
+    let mut stack = vec![1u64];
+    while let Some(remaining) = stack.last_mut() {
+        if *remaining == 0 {
+            stack.pop();
+            continue;
+        }
+        *remaining -= 1;
+
+        let (major, arg) = read_head(buf, &mut pos)?;
+        let left = (buf.len() - pos) as u64;
+        let children = match major {
+            // Byte and text strings.
+            2 | 3 => {
+                if arg > left {
+                    return Err(CborError::Truncated);
+                }
+                pos += arg as usize;
+                continue;
+            }
+            4 => arg,
+            5 => arg.checked_mul(2).ok_or(CborError::TooLong(arg))?,
+            // Tags wrap exactly one item.
+            6 => 1,
+            _ => continue,
+        };
+
+        if major != 6 && arg > limits.max_length {
+            return Err(CborError::TooLong(arg));
+        }
+        // Every item occupies at least one byte, so this cannot be satisfied.
+        if children > left {
+            return Err(CborError::Truncated);
+        }
+        if stack.len() > limits.max_depth {
+            return Err(CborError::TooDeep);
+        }
+
+        stack.push(children);
+    }
+
+    Ok(())
+}
+
+/// Decode an untrusted DAG-CBOR payload, subject to `limits`.
+///
+/// `source` identifies the kind of payload for the rejection counter (e.g. `import`).
+pub fn decode<T: DeserializeOwned>(
+    buf: &[u8],
+    limits: &Limits,
+    source: &'static str,
+) -> Result<T, CborError> {
+    let r = check(buf, limits).and_then(|()| {
+        serde_ipld_dagcbor::from_slice(buf).map_err(|e| CborError::Decode(e.to_string()))
+    });
+
+    if let Err(e) = &r {
+        counter!(CBOR_REJECTED, "source" => source, "reason" => e.reason()).increment(1);
+    }
+
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use ipld_core::ipld::Ipld;
+    use rand::Rng;
+
+    use super::*;
+
+    fn decode_ipld(buf: &[u8]) -> Result<Ipld, CborError> {
+        decode(buf, &Limits::BLOCK, "test")
+    }
+
+    #[test]
+    fn valid() {
+        let v = Ipld::List(vec![
+            Ipld::Integer(1),
+            Ipld::String("hello".to_string()),
+            Ipld::Map([("a".to_string(), Ipld::Bytes(vec![1, 2, 3]))].into()),
+        ]);
+        let buf = serde_ipld_dagcbor::to_vec(&v).unwrap();
+
+        assert_eq!(decode_ipld(&buf), Ok(v));
+    }
+
+    #[test]
+    fn deeply_nested() {
+        // A list nested a million levels deep: [[[[...]]]]
+        let mut buf = vec![0x81; 1_000_000];
+        buf.push(0x80);
+        assert_eq!(decode_ipld(&buf), Err(CborError::TooDeep));
+
+        // Likewise with maps and tags.
+        let buf = [0xa1, 0x61, b'a'].repeat(100_000);
+        assert_eq!(decode_ipld(&buf), Err(CborError::TooDeep));
+        let buf = [0xd8, 0x2a].repeat(100_000);
+        assert_eq!(decode_ipld(&buf), Err(CborError::TooDeep));
+    }
+
+    #[test]
+    fn huge_lengths() {
+        // A list, map, and byte string each claiming 2^64 - 1 entries.
+        let list = [0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(decode_ipld(&list), Err(CborError::TooLong(u64::MAX)));
+        let map = [0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(decode_ipld(&map), Err(CborError::TooLong(u64::MAX)));
+        let bytes = [0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(decode_ipld(&bytes), Err(CborError::Truncated));
+
+        // A list within the length limit, but longer than the payload.
+        assert_eq!(decode_ipld(&[0x99, 0x10, 0x00]), Err(CborError::Truncated));
+
+        let buf = vec![0; Limits::BLOCK.max_size + 1];
+        assert_eq!(
+            decode_ipld(&buf),
+            Err(CborError::TooLarge(Limits::BLOCK.max_size + 1))
+        );
+    }
+
+    #[test]
+    fn fuzz() {
+        // Random payloads must be rejected (or accepted) without panicking.
+        let mut rng = rand::thread_rng();
+        for _ in 0..10_000 {
+            let len = rng.gen_range(0..64);
+            let buf = (0..len).map(|_| rng.gen()).collect::<Vec<u8>>();
+            let _ = decode_ipld(&buf);
+        }
+    }
+}
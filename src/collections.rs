@@ -0,0 +1,267 @@
+//! An index of the collections in each hosted repository, with the number of records in each, so
+//! that they can be listed without walking the repository's MST.
+//!
+//! The index is valid for the head recorded in `accounts.collections_root`. Writes update it in the
+//! transaction that moves the head, but only if it was valid for the head they started from; any
+//! other change to the head (e.g. an import, or a rollback to a known-good head) leaves it stale,
+//! and it's rebuilt from the MST the next time it's read.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use atrium_repo::{blockstore::AsyncBlockStoreRead, Cid, Repository};
+use futures::TryStreamExt;
+use sqlx::SqliteConnection;
+
+use crate::{firehose::RepoOp, Db};
+
+/// Update the index of a repository for a commit moving its head from `prev` to `root`. This
+/// should happen in the transaction that moves the head.
+pub async fn apply(
+    conn: &mut SqliteConnection,
+    did: &str,
+    prev: &Cid,
+    root: &Cid,
+    ops: &[RepoOp],
+) -> Result<()> {
+    let moved = sqlx::query(
+        r#"UPDATE accounts SET collections_root = ? WHERE did = ? AND collections_root = ?"#,
+    )
+    .bind(root.to_string())
+    .bind(did)
+    .bind(prev.to_string())
+    .execute(&mut *conn)
+    .await
+    .context("failed to update collection index")?
+    .rows_affected();
+    if moved == 0 {
+        // The index is stale already, and will be rebuilt.
+        return Ok(());
+    }
+
+    let mut deltas: BTreeMap<&str, i64> = BTreeMap::new();
+    for op in ops {
+        let (path, delta) = match op {
+            RepoOp::Create { path, .. } => (path, 1),
+            RepoOp::Delete { path, .. } => (path, -1),
+            RepoOp::Update { .. } => continue,
+        };
+        if let Some((collection, _rkey)) = path.split_once('/') {
+            *deltas.entry(collection).or_default() += delta;
+        }
+    }
+
+    for (collection, delta) in deltas.into_iter().filter(|(_, d)| *d != 0) {
+        sqlx::query(
+            r#"
+            INSERT INTO repo_collections (did, collection, records) VALUES (?, ?, ?)
+                ON CONFLICT (did, collection) DO UPDATE SET records = records + excluded.records
+            "#,
+        )
+        .bind(did)
+        .bind(collection)
+        .bind(delta)
+        .execute(&mut *conn)
+        .await
+        .context("failed to update collection index")?;
+    }
+
+    sqlx::query(r#"DELETE FROM repo_collections WHERE did = ? AND records <= 0"#)
+        .bind(did)
+        .execute(&mut *conn)
+        .await
+        .context("failed to update collection index")?;
+
+    Ok(())
+}
+
+/// List the collections of a repository, with the number of records in each, rebuilding the index
+/// from the MST if it isn't valid for the repository's head.
+pub async fn list(
+    db: &Db,
+    did: &str,
+    repo: &mut Repository<impl AsyncBlockStoreRead>,
+) -> Result<Vec<(String, i64)>> {
+    let root = repo.root();
+    let indexed: Option<String> =
+        sqlx::query_scalar(r#"SELECT collections_root FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_optional(db)
+            .await
+            .context("failed to query collection index")?
+            .flatten();
+
+    if indexed.as_deref() == Some(root.to_string().as_str()) {
+        return sqlx::query_as(
+            r#"SELECT collection, records FROM repo_collections WHERE did = ? ORDER BY collection"#,
+        )
+        .bind(did)
+        .fetch_all(db)
+        .await
+        .context("failed to query collection index");
+    }
+
+    let mut counts: BTreeMap<String, i64> = BTreeMap::new();
+    let mut tree = repo.tree();
+    let mut it = Box::pin(tree.keys());
+    while let Some(key) = it.try_next().await.context("failed to iterate repo keys")? {
+        if let Some((collection, _rkey)) = key.split_once('/') {
+            *counts.entry(collection.to_string()).or_default() += 1;
+        }
+    }
+
+    rebuild(db, did, &root, &counts).await?;
+    Ok(counts.into_iter().collect())
+}
+
+/// Replace the index of a repository with one built at `root`, unless the head has moved since.
+async fn rebuild(db: &Db, did: &str, root: &Cid, counts: &BTreeMap<String, i64>) -> Result<()> {
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+
+    let current =
+        sqlx::query(r#"UPDATE accounts SET collections_root = root WHERE did = ? AND root = ?"#)
+            .bind(did)
+            .bind(root.to_string())
+            .execute(&mut *tx)
+            .await
+            .context("failed to update collection index")?
+            .rows_affected();
+    if current == 0 {
+        // Another write got in first; the next read will try again.
+        return Ok(());
+    }
+
+    sqlx::query(r#"DELETE FROM repo_collections WHERE did = ?"#)
+        .bind(did)
+        .execute(&mut *tx)
+        .await
+        .context("failed to clear collection index")?;
+    for (collection, records) in counts {
+        sqlx::query(r#"INSERT INTO repo_collections (did, collection, records) VALUES (?, ?, ?)"#)
+            .bind(did)
+            .bind(collection)
+            .bind(records)
+            .execute(&mut *tx)
+            .await
+            .context("failed to rebuild collection index")?;
+    }
+
+    tx.commit().await.context("failed to commit transaction")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, sync::Arc};
+
+    use atrium_api::types::string::Did;
+    use atrium_crypto::keypair::Secp256k1Keypair;
+    use atrium_repo::blockstore::CarStore;
+    use serde_json::json;
+
+    use super::*;
+    use crate::SigningKey;
+
+    #[tokio::test]
+    async fn index() {
+        let db = crate::testing::memory_db().await;
+        let did = "did:plc:alice";
+
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let mut store = CarStore::create(Cursor::new(Vec::new())).await.unwrap();
+        let builder = Repository::create(&mut store, Did::new(did.to_string()).unwrap())
+            .await
+            .unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        let mut repo = builder.finalize(sig).await.unwrap();
+        for key in [
+            "app.bsky.feed.like/a",
+            "app.bsky.feed.post/a",
+            "app.bsky.feed.post/b",
+        ] {
+            let (builder, _) = repo.add_raw(key, json!({})).await.unwrap();
+            let sig = skey.sign(&builder.bytes()).unwrap();
+            builder.finalize(sig).await.unwrap();
+        }
+
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', ?, '', '')"#,
+        )
+        .bind(did)
+        .bind(repo.root().to_string())
+        .execute(&db)
+        .await
+        .unwrap();
+
+        // Built from the MST on first use.
+        let expected = vec![
+            ("app.bsky.feed.like".to_string(), 1),
+            ("app.bsky.feed.post".to_string(), 2),
+        ];
+        assert_eq!(list(&db, did, &mut repo).await.unwrap(), expected);
+        let indexed: Option<String> =
+            sqlx::query_scalar(r#"SELECT collections_root FROM accounts"#)
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert_eq!(indexed, Some(repo.root().to_string()));
+
+        // Then kept up to date by writes.
+        let prev = repo.root();
+        let builder = repo.delete_raw("app.bsky.feed.like/a").await.unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        builder.finalize(sig).await.unwrap();
+        let (builder, cid) = repo
+            .add_raw("app.bsky.graph.follow/a", json!({}))
+            .await
+            .unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        builder.finalize(sig).await.unwrap();
+
+        let ops = [
+            RepoOp::Delete {
+                path: "app.bsky.feed.like/a".to_string(),
+                prev: cid,
+            },
+            RepoOp::Create {
+                path: "app.bsky.graph.follow/a".to_string(),
+                cid,
+            },
+        ];
+        let mut conn = db.acquire().await.unwrap();
+        apply(&mut conn, did, &prev, &repo.root(), &ops)
+            .await
+            .unwrap();
+        sqlx::query(r#"UPDATE accounts SET root = ?"#)
+            .bind(repo.root().to_string())
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        drop(conn);
+
+        let expected = vec![
+            ("app.bsky.feed.post".to_string(), 2),
+            ("app.bsky.graph.follow".to_string(), 1),
+        ];
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            r#"SELECT collection, records FROM repo_collections ORDER BY collection"#,
+        )
+        .fetch_all(&db)
+        .await
+        .unwrap();
+        assert_eq!(rows, expected);
+        assert_eq!(list(&db, did, &mut repo).await.unwrap(), expected);
+
+        // A write from a head the index isn't valid for leaves it stale, to be rebuilt.
+        sqlx::query(r#"UPDATE accounts SET collections_root = NULL"#)
+            .execute(&db)
+            .await
+            .unwrap();
+        let mut conn = db.acquire().await.unwrap();
+        apply(&mut conn, did, &repo.root(), &repo.root(), &ops)
+            .await
+            .unwrap();
+        drop(conn);
+        assert_eq!(list(&db, did, &mut repo).await.unwrap(), expected);
+    }
+}
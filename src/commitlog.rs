@@ -0,0 +1,209 @@
+//! A log of the commits made to each hosted repository.
+//!
+//! Commits made through the write endpoints are logged in the same transaction that moves the
+//! repository's head, along with the number of operations they contain and the firehose sequence
+//! number of the commit's event (queued in that transaction too), so that an operator can match a
+//! commit to what relays saw.
+//!
+//! Entries are eventually expired by the garbage collector (see `gc::STORES`).
+
+use anyhow::{Context, Result};
+use atrium_repo::Cid;
+use serde::Serialize;
+use sqlx::SqliteConnection;
+
+use crate::{firehose::RepoOp, Db};
+
+/// A logged commit.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct LoggedCommit {
+    pub cid: String,
+    pub rev: String,
+    pub committed_at: String,
+    pub creates: i64,
+    pub updates: i64,
+    pub deletes: i64,
+    /// The firehose sequence number of the commit's event. Unset for commits logged before events
+    /// were sequenced with them.
+    pub seq: Option<i64>,
+}
+
+/// Log a commit, sequenced on the firehose as `seq`. This should happen in the transaction that
+/// updates the repository's head.
+pub async fn record(
+    conn: &mut SqliteConnection,
+    did: &str,
+    cid: &Cid,
+    rev: &str,
+    ops: &[RepoOp],
+    seq: u64,
+) -> Result<()> {
+    let (mut creates, mut updates, mut deletes) = (0, 0, 0);
+    for op in ops {
+        match op {
+            RepoOp::Create { .. } => creates += 1,
+            RepoOp::Update { .. } => updates += 1,
+            RepoOp::Delete { .. } => deletes += 1,
+        }
+    }
+
+    sqlx::query(
+        r#"
+        INSERT OR REPLACE INTO commit_log (did, rev, cid, creates, updates, deletes, seq)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(did)
+    .bind(rev)
+    .bind(cid.to_string())
+    .bind(creates)
+    .bind(updates)
+    .bind(deletes)
+    .bind(seq as i64)
+    .execute(conn)
+    .await
+    .context("failed to log commit")?;
+
+    Ok(())
+}
+
+/// List a repository's logged commits, most recent first, starting before the revision `cursor`.
+pub async fn list(
+    db: &Db,
+    did: &str,
+    cursor: Option<&str>,
+    limit: u32,
+) -> Result<Vec<LoggedCommit>> {
+    // Revisions are TIDs, which sort chronologically.
+    sqlx::query_as(
+        r#"
+        SELECT cid, rev, committed_at, creates, updates, deletes, seq
+        FROM commit_log
+        WHERE did = ? AND (? IS NULL OR rev < ?)
+        ORDER BY rev DESC
+        LIMIT ?
+        "#,
+    )
+    .bind(did)
+    .bind(cursor)
+    .bind(cursor)
+    .bind(limit)
+    .fetch_all(db)
+    .await
+    .context("failed to list commits")
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use atrium_api::types::{
+        string::{Datetime, Did, Tid},
+        LimitedU32,
+    };
+
+    use super::*;
+    use crate::firehose;
+
+    #[tokio::test]
+    async fn history() {
+        let db = crate::testing::memory_db().await;
+
+        let did = "did:plc:alice";
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', '', '', '')"#,
+        )
+        .bind(did)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let config = crate::testing::config("", serde_json::json!({}));
+
+        // Another event, so that sequence numbers don't line up with commits by accident.
+        let mut conn = db.acquire().await.unwrap();
+        firehose::enqueue(
+            &mut conn,
+            atrium_api::com::atproto::sync::subscribe_repos::Message::Account(Box::new(
+                atrium_api::com::atproto::sync::subscribe_repos::AccountData {
+                    active: true,
+                    did: Did::from_str(did).unwrap(),
+                    seq: 0,
+                    status: None,
+                    time: Datetime::now(),
+                }
+                .into(),
+            )),
+        )
+        .await
+        .unwrap();
+        drop(conn);
+
+        let cid = crate::record::block_cid(b"commit");
+        let mut committed = Vec::new();
+        for i in 0..3 {
+            // Revisions have microsecond resolution.
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            let rev = Tid::now(LimitedU32::MIN).as_str().to_string();
+            let ops = (0..=i)
+                .map(|n| RepoOp::Create {
+                    cid,
+                    path: format!("app.bsky.feed.post/{n}"),
+                })
+                .chain((i == 2).then(|| RepoOp::Delete {
+                    path: "app.bsky.feed.post/0".to_string(),
+                    prev: cid,
+                }))
+                .collect::<Vec<_>>();
+
+            // The commit's event is sequenced with it.
+            let commit = firehose::Commit {
+                car: Vec::new(),
+                ops: ops.clone(),
+                cid,
+                rev: rev.clone(),
+                did: Did::from_str(did).unwrap(),
+                pcid: None,
+                since: None,
+                blobs: Vec::new(),
+            };
+            let mut tx = db.begin().await.unwrap();
+            let seq = firehose::enqueue_commit(&mut tx, &config.firehose, &commit)
+                .await
+                .unwrap();
+            record(&mut tx, did, &cid, &rev, &ops, seq).await.unwrap();
+            tx.commit().await.unwrap();
+            committed.push((rev, seq));
+        }
+
+        // Sequenced after the account event, in commit order.
+        assert_eq!(
+            committed.iter().map(|(_, seq)| *seq).collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+
+        // Most recent first, paginated by revision.
+        let page = list(&db, did, None, 2).await.unwrap();
+        assert_eq!(
+            page.iter()
+                .map(|c| (c.rev.clone(), c.seq.unwrap() as u64))
+                .collect::<Vec<_>>(),
+            [committed[2].clone(), committed[1].clone()]
+        );
+        assert_eq!(
+            (page[0].creates, page[0].updates, page[0].deletes),
+            (3, 0, 1)
+        );
+        assert_eq!(page[0].cid, cid.to_string());
+
+        let rest = list(&db, did, Some(&page[1].rev), 2).await.unwrap();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].rev, committed[0].0);
+        assert_eq!(rest[0].seq, Some(2));
+        assert!(list(&db, did, Some(&rest[0].rev), 2)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,329 @@
+//! Compaction of repository files.
+//!
+//! A repository's CAR file only ever grows: each commit appends its blocks, and blocks that are no
+//! longer reachable from the head (old commits, replaced MST nodes, deleted records) are never
+//! removed. Compaction rewrites the file with only the blocks reachable from the head.
+//!
+//! The compacted file is written alongside the repository, then renamed over it, so that the
+//! repository is always either the original or the fully compacted file. If compaction is
+//! interrupted, recovery (see [`crate::jobs`]) removes the partially written file.
+//!
+//! Compaction doesn't coordinate with concurrent writes, so only repositories of accounts that
+//! can't write (i.e. that aren't active) are compacted.
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context};
+use atrium_repo::blockstore::CarStore;
+use axum::http::StatusCode;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    config::{AppConfig, RepoConfig},
+    error::ErrorMessage,
+    integrity::RepoIntegrity,
+    jobs::{self, Job, JobKind, Recovery},
+    stats::{StorageDelta, StorageStats},
+    status::AccountStatus,
+    storage, Db, Error, Result,
+};
+
+pub const JOB: JobKind = JobKind {
+    name: "compact",
+    recover: recover_job,
+};
+
+/// The result of compacting a repository.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Compaction {
+    pub did: String,
+    /// The size of the repository file before and after compaction, in bytes.
+    pub before: u64,
+    pub after: u64,
+}
+
+/// Return the path the compacted repository is written to, before it replaces the original.
+fn compact_path(config: &RepoConfig, did: &str) -> anyhow::Result<PathBuf> {
+    Ok(storage::repo_path(config, did)?.with_extension("car.compact"))
+}
+
+/// Compact a repository, discarding all blocks unreachable from its head.
+pub async fn compact(
+    config: &RepoConfig,
+    db: &Db,
+    integrity: &RepoIntegrity,
+    stats: &StorageStats,
+    did: &str,
+) -> Result<Compaction> {
+    let status: Option<String> = sqlx::query_scalar(r#"SELECT status FROM accounts WHERE did = ?"#)
+        .bind(did)
+        .fetch_optional(db)
+        .await
+        .context("failed to query account status")?;
+
+    match status.as_deref().map(AccountStatus::parse) {
+        None => {
+            return Err(Error::with_message(
+                StatusCode::BAD_REQUEST,
+                anyhow!("repository {did} not found"),
+                ErrorMessage::new("RepoNotFound", format!("Could not find repo: {did}")),
+            ))
+        }
+        Some(Some(AccountStatus::Active)) => {
+            return Err(Error::with_message(
+                StatusCode::BAD_REQUEST,
+                anyhow!("refusing to compact active repository {did}"),
+                ErrorMessage::new(
+                    "InvalidRequest",
+                    "only repositories of inactive accounts can be compacted",
+                ),
+            ))
+        }
+        Some(_) => {}
+    }
+
+    // Only ever compact down to a verified head.
+    let mut repo = integrity.open(did).await?;
+
+    let Some(id) = jobs::create(db, &JOB, did).await? else {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("repository {did} is already being compacted"),
+            ErrorMessage::new("InvalidRequest", "repository is already being compacted"),
+        ));
+    };
+    jobs::start(db, id).await?;
+
+    let tmp = compact_path(config, did)?;
+    let r = async {
+        let before = storage::repo_size(config, did).await?;
+
+        let mut file = tokio::fs::File::create(&tmp)
+            .await
+            .context("failed to create compacted repository")?;
+        let mut store = CarStore::create_with_roots(&mut file, [repo.root()])
+            .await
+            .context("failed to create compacted carstore")?;
+        repo.export_into(&mut store)
+            .await
+            .context("failed to export repository")?;
+        drop(store);
+        file.sync_all()
+            .await
+            .context("failed to sync compacted repository")?;
+
+        tokio::fs::rename(&tmp, storage::repo_path(config, did)?)
+            .await
+            .context("failed to replace repository")?;
+
+        // Any staged commit was either compacted in (if it's the head), or discarded.
+        let mut conn = db.acquire().await.context("failed to acquire connection")?;
+        storage::forget_staged(&mut conn, did).await?;
+
+        let after = storage::repo_size(config, did).await?;
+        anyhow::Ok((before, after))
+    }
+    .await;
+
+    if r.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    jobs::finish(
+        db,
+        id,
+        r.as_ref().err().map(|e| format!("{e:#}")).as_deref(),
+    )
+    .await?;
+    let (before, after) = r?;
+
+    if let Err(e) = stats
+        .record(
+            did,
+            StorageDelta {
+                block_bytes: after as i64 - before as i64,
+                ..Default::default()
+            },
+        )
+        .await
+    {
+        warn!("failed to update storage statistics for {did}: {e:?}");
+    }
+
+    Ok(Compaction {
+        did: did.to_string(),
+        before,
+        after,
+    })
+}
+
+/// Recover an interrupted compaction. The repository itself is always consistent, so only the
+/// partially written compacted file needs to be removed.
+async fn recover(config: AppConfig, job: Job) -> anyhow::Result<Recovery> {
+    let tmp = compact_path(&config.repo, &job.subject)?;
+    match tokio::fs::remove_file(&tmp).await {
+        Ok(()) => Ok(Recovery::RolledBack),
+        // The compacted file was either never created, or has already replaced the repository.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Recovery::Completed),
+        Err(e) => Err(e).context("failed to remove partially compacted repository"),
+    }
+}
+
+fn recover_job(
+    config: AppConfig,
+    _db: Db,
+    job: Job,
+) -> BoxFuture<'static, anyhow::Result<Recovery>> {
+    Box::pin(recover(config, job))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use atrium_api::types::string::Did;
+    use atrium_crypto::keypair::Secp256k1Keypair;
+    use atrium_repo::Repository;
+
+    use super::*;
+    use crate::SigningKey;
+
+    #[tokio::test]
+    async fn interrupted() {
+        let dir = crate::testing::temp_dir("compact");
+        let config = crate::testing::config(&dir, serde_json::json!({}));
+
+        let db = crate::testing::memory_db().await;
+
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+        let integrity = RepoIntegrity::new(
+            config.repo.clone(),
+            db.clone(),
+            client.clone(),
+            skey.clone(),
+        );
+        let stats = StorageStats::new(None, client, db.clone());
+
+        // A repository with a record that has been rewritten many times, leaving garbage behind.
+        let did = "did:plc:alice";
+        let key = "app.bsky.actor.profile/self";
+        let mut file = tokio::fs::File::create(storage::repo_path(&config.repo, did).unwrap())
+            .await
+            .unwrap();
+        let mut store = CarStore::create(&mut file).await.unwrap();
+        let builder = Repository::create(&mut store, Did::new(did.to_string()).unwrap())
+            .await
+            .unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        let mut repo = builder.finalize(sig).await.unwrap();
+
+        let (builder, _) = repo
+            .add_raw(key, &serde_json::json!({ "displayName": "0" }))
+            .await
+            .unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        builder.finalize(sig).await.unwrap();
+        for i in 1..50 {
+            let (builder, _) = repo
+                .update_raw(key, &serde_json::json!({ "displayName": i.to_string() }))
+                .await
+                .unwrap();
+            let sig = skey.sign(&builder.bytes()).unwrap();
+            builder.finalize(sig).await.unwrap();
+        }
+        let (root, rev) = (repo.root(), repo.commit().rev().to_string());
+        drop(repo);
+        drop(store);
+        file.sync_all().await.unwrap();
+
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', ?, '', ?)"#,
+        )
+        .bind(did)
+        .bind(root.to_string())
+        .bind(&rev)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        // Active repositories are left alone.
+        assert!(compact(&config.repo, &db, &integrity, &stats, did)
+            .await
+            .is_err());
+
+        sqlx::query(r#"UPDATE accounts SET status = 'deactivated' WHERE did = ?"#)
+            .bind(did)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let c = compact(&config.repo, &db, &integrity, &stats, did)
+            .await
+            .unwrap();
+        assert!(c.after < c.before, "{c:?}");
+        assert_eq!(
+            storage::repo_size(&config.repo, did).await.unwrap(),
+            c.after
+        );
+
+        let assert_consistent = || async {
+            let mut repo = storage::open_repo(&config.repo, did, root).await.unwrap();
+            let record = repo
+                .get_raw::<serde_json::Value>(key)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(record["displayName"], "49");
+        };
+        assert_consistent().await;
+
+        let job: Job = sqlx::query_as(r#"SELECT * FROM jobs"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(job.state, "done");
+
+        // Simulate a crash partway through writing the compacted file.
+        let tmp = compact_path(&config.repo, did).unwrap();
+        std::fs::write(&tmp, b"half a car file").unwrap();
+        let id = jobs::create(&db, &JOB, did).await.unwrap().unwrap();
+        jobs::start(&db, id).await.unwrap();
+
+        // Only one compaction runs at a time.
+        assert_eq!(jobs::create(&db, &JOB, did).await.unwrap(), None);
+
+        // On restart, the partial file is removed and the repository is intact.
+        assert_eq!(jobs::recover(&config, &db).await.unwrap(), 1);
+        assert!(!tmp.exists());
+        assert_consistent().await;
+
+        let job: Job = sqlx::query_as(r#"SELECT * FROM jobs WHERE id = ?"#)
+            .bind(id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(job.state, "failed");
+        assert_eq!(job.error.as_deref(), Some("interrupted, and rolled back"));
+
+        // A crash after the compacted file replaced the repository needs nothing undone.
+        let id = jobs::create(&db, &JOB, did).await.unwrap().unwrap();
+        jobs::start(&db, id).await.unwrap();
+        assert_eq!(jobs::recover(&config, &db).await.unwrap(), 1);
+        assert_consistent().await;
+
+        let job: Job = sqlx::query_as(r#"SELECT * FROM jobs WHERE id = ?"#)
+            .bind(id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(job.state, "done");
+
+        // Nothing is left to recover.
+        assert_eq!(jobs::recover(&config, &db).await.unwrap(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
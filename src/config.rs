@@ -1,4 +1,8 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
 
 use serde::Deserialize;
 use url::Url;
@@ -23,12 +27,159 @@ pub enum MetricConfig {
 pub struct FirehoseConfig {
     /// A list of upstream relays that this PDS will try to reach out to.
     pub relays: Vec<Url>,
+    /// The maximum lifetime of a subscribeRepos connection, in seconds.
+    /// Connections are closed and asked to reconnect once this elapses. Disabled if unset.
+    #[serde(default)]
+    pub connection_lifetime: Option<u64>,
+    /// Known source addresses of relay crawlers, keyed by relay hostname.
+    /// Used to identify subscribers as relays in addition to a DNS lookup of the relay hostname.
+    #[serde(default)]
+    pub relay_addresses: HashMap<String, Vec<IpAddr>>,
+    /// Periodic verification that a relay's view of hosted repositories matches local state.
+    #[serde(default)]
+    pub verify: Option<RelayVerifyConfig>,
+    /// The size of a commit's blocks, in bytes, past which it's broadcast as `tooBig`, without
+    /// them. Consumers fetch the blocks with `getRepo` or `getBlocks` instead.
+    #[serde(default = "FirehoseConfig::default_too_big")]
+    pub too_big: usize,
+    /// Compress subscribeRepos frames with permessage-deflate, for clients that offer it.
+    #[serde(default)]
+    pub deflate: bool,
+    /// The number of recent events retained in memory to backfill subscribers from. Older events
+    /// are backfilled from the database instead.
+    #[serde(default = "FirehoseConfig::default_history_size")]
+    pub history_size: usize,
+    /// The maximum total size of the events retained in memory, in bytes. Unlimited if unset.
+    #[serde(default)]
+    pub history_max_bytes: Option<usize>,
+    /// The maximum number of subscribeRepos connections open at once. Further connections are
+    /// rejected until some close.
+    #[serde(default = "FirehoseConfig::default_max_subscribers")]
+    pub max_subscribers: usize,
+    /// The maximum number of subscribeRepos connections open at once from a single address.
+    #[serde(default = "FirehoseConfig::default_max_subscribers_per_ip")]
+    pub max_subscribers_per_ip: usize,
+}
+
+impl FirehoseConfig {
+    fn default_too_big() -> usize {
+        1_000_000
+    }
+
+    fn default_history_size() -> usize {
+        1000
+    }
+
+    fn default_max_subscribers() -> usize {
+        1000
+    }
+
+    fn default_max_subscribers_per_ip() -> usize {
+        16
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RelayVerifyConfig {
+    /// The relay to compare against.
+    pub relay: Url,
+    /// How often to compare repository heads, in seconds.
+    #[serde(default = "RelayVerifyConfig::default_interval")]
+    pub interval: u64,
+    /// How long a repository may diverge from the relay before an alert is raised, in seconds.
+    #[serde(default = "RelayVerifyConfig::default_grace_period")]
+    pub grace_period: u64,
+}
+
+impl RelayVerifyConfig {
+    fn default_interval() -> u64 {
+        10 * 60
+    }
+
+    fn default_grace_period() -> u64 {
+        15 * 60
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct RepoConfig {
     /// The path to the repository storage.
     pub path: PathBuf,
+    /// The maximum size of a repository imported via `com.atproto.repo.importRepo`, in bytes.
+    #[serde(default = "RepoConfig::default_import_limit")]
+    pub import_limit: u64,
+    /// The number of blocks unreachable from the head commit tolerated in an imported repository.
+    #[serde(default)]
+    pub import_orphan_tolerance: usize,
+    /// Collection namespaces that records may not be written into.
+    /// Entries are either exact NSIDs or prefixes ending in `*` (e.g. `com.atproto.*`).
+    #[serde(default = "RepoConfig::default_reserved_collections")]
+    pub reserved_collections: Vec<String>,
+    /// Record fields to index in the backlink index, keyed by collection.
+    /// Each field is a dot-separated path to an AT URI within the record (e.g. `subject.uri`).
+    #[serde(default = "RepoConfig::default_backlinks")]
+    pub backlinks: HashMap<String, Vec<String>>,
+    /// Alerting on accounts whose storage grows quickly.
+    #[serde(default)]
+    pub growth_alert: Option<GrowthAlertConfig>,
+    /// Create an `app.bsky.actor.profile` record for new accounts, with the display name
+    /// defaulted to the handle.
+    #[serde(default)]
+    pub create_profile: bool,
+    /// Read back each written record and check that it re-serializes to the CID returned to the
+    /// client. Defaults to enabled in debug builds only.
+    #[serde(default = "RepoConfig::default_verify_cids")]
+    pub verify_cids: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GrowthAlertConfig {
+    /// The growth in storage (blocks and blobs) within a window that raises an alert, in bytes.
+    pub threshold: u64,
+    /// The length of the window, in seconds.
+    #[serde(default = "GrowthAlertConfig::default_window")]
+    pub window: u64,
+    /// A URL that alerts are POSTed to (as JSON), in addition to being logged.
+    #[serde(default)]
+    pub webhook: Option<Url>,
+}
+
+impl GrowthAlertConfig {
+    fn default_window() -> u64 {
+        24 * 60 * 60
+    }
+}
+
+impl RepoConfig {
+    fn default_import_limit() -> u64 {
+        1024 * 1024 * 1024
+    }
+
+    fn default_verify_cids() -> bool {
+        cfg!(debug_assertions)
+    }
+
+    fn default_reserved_collections() -> Vec<String> {
+        // `com.atproto` defines no record types.
+        vec!["com.atproto.*".to_string()]
+    }
+
+    fn default_backlinks() -> HashMap<String, Vec<String>> {
+        HashMap::from([
+            (
+                "app.bsky.feed.post".to_string(),
+                vec!["reply.parent.uri".to_string()],
+            ),
+            (
+                "app.bsky.feed.like".to_string(),
+                vec!["subject.uri".to_string()],
+            ),
+            (
+                "app.bsky.feed.repost".to_string(),
+                vec!["subject.uri".to_string()],
+            ),
+        ])
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -37,34 +188,661 @@ pub struct PlcConfig {
     pub path: PathBuf,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct DidCacheConfig {
+    /// How long a resolved DID document is served from the cache, in seconds.
+    #[serde(default = "DidCacheConfig::default_ttl")]
+    pub ttl: u64,
+    /// The maximum number of cached documents. The least recently used are evicted first.
+    #[serde(default = "DidCacheConfig::default_capacity")]
+    pub capacity: usize,
+}
+
+impl DidCacheConfig {
+    fn default_ttl() -> u64 {
+        60 * 60
+    }
+
+    fn default_capacity() -> usize {
+        10_000
+    }
+}
+
+impl Default for DidCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Self::default_ttl(),
+            capacity: Self::default_capacity(),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct BlobConfig {
     /// The path to store blobs into.
     pub path: PathBuf,
     /// The maximum size limit of blobs.
     pub limit: u64,
+    /// The lifetime of an incomplete resumable upload, in seconds.
+    #[serde(default = "BlobConfig::default_upload_ttl")]
+    pub upload_ttl: u64,
+    /// Whether resumable uploads (the `_blob` endpoints) are enabled.
+    #[serde(default = "BlobConfig::default_resumable")]
+    pub resumable: bool,
+}
+
+impl BlobConfig {
+    fn default_upload_ttl() -> u64 {
+        24 * 60 * 60
+    }
+
+    fn default_resumable() -> bool {
+        true
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RateLimitConfig {
+    /// The number of write points an account may spend per hour.
+    #[serde(default = "RateLimitConfig::default_hourly")]
+    pub write_points_hourly: u32,
+    /// The number of write points an account may spend per day.
+    #[serde(default = "RateLimitConfig::default_daily")]
+    pub write_points_daily: u32,
+    /// The number of expensive sync operations (e.g. `getRepo`) a single client (IP address or
+    /// account) may have in flight at once.
+    #[serde(default = "RateLimitConfig::default_sync_concurrency")]
+    pub sync_concurrency: usize,
+    /// How long an expensive sync operation may wait for one of its client's others to finish
+    /// before being rejected, in milliseconds.
+    #[serde(default = "RateLimitConfig::default_sync_queue_timeout")]
+    pub sync_queue_timeout: u64,
+    /// The number of CIDs past which a `getBlocks` request counts as an expensive operation.
+    #[serde(default = "RateLimitConfig::default_sync_blocks_threshold")]
+    pub sync_blocks_threshold: usize,
+    /// The number of failed sign-ins an account may have within a login window before further
+    /// attempts are rejected without being checked.
+    #[serde(default = "RateLimitConfig::default_login_failures_account")]
+    pub login_failures_account: u32,
+    /// The number of failed sign-ins a single IP address may make within a login window before
+    /// further attempts are rejected without being checked.
+    #[serde(default = "RateLimitConfig::default_login_failures_ip")]
+    pub login_failures_ip: u32,
+    /// The length of a login window, in seconds.
+    #[serde(default = "RateLimitConfig::default_login_window")]
+    pub login_window: u64,
+    /// The number of accounts a single IP address may attempt to create per hour.
+    #[serde(default = "RateLimitConfig::default_signups_hourly")]
+    pub signups_hourly: u32,
+    /// The number of accounts a single IP address may attempt to create per day.
+    #[serde(default = "RateLimitConfig::default_signups_daily")]
+    pub signups_daily: u32,
+    /// The address ranges of reverse proxies in front of this PDS. Only for connections from these
+    /// is `client_ip_header` trusted to give the client's real address.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpRange>,
+    /// The header in which trusted proxies give the address of the client, as a comma-separated
+    /// list of the addresses each hop saw, in the manner of `X-Forwarded-For`.
+    #[serde(default = "RateLimitConfig::default_client_ip_header")]
+    pub client_ip_header: String,
+}
+
+impl RateLimitConfig {
+    // Defaults match the reference PDS.
+    fn default_hourly() -> u32 {
+        5000
+    }
+
+    fn default_daily() -> u32 {
+        35000
+    }
+
+    fn default_sync_concurrency() -> usize {
+        2
+    }
+
+    fn default_sync_queue_timeout() -> u64 {
+        5000
+    }
+
+    fn default_sync_blocks_threshold() -> usize {
+        100
+    }
+
+    fn default_login_failures_account() -> u32 {
+        10
+    }
+
+    fn default_login_failures_ip() -> u32 {
+        50
+    }
+
+    fn default_login_window() -> u64 {
+        15 * 60
+    }
+
+    fn default_signups_hourly() -> u32 {
+        10
+    }
+
+    fn default_signups_daily() -> u32 {
+        30
+    }
+
+    fn default_client_ip_header() -> String {
+        "x-forwarded-for".to_string()
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            write_points_hourly: Self::default_hourly(),
+            write_points_daily: Self::default_daily(),
+            sync_concurrency: Self::default_sync_concurrency(),
+            sync_queue_timeout: Self::default_sync_queue_timeout(),
+            sync_blocks_threshold: Self::default_sync_blocks_threshold(),
+            login_failures_account: Self::default_login_failures_account(),
+            login_failures_ip: Self::default_login_failures_ip(),
+            login_window: Self::default_login_window(),
+            signups_hourly: Self::default_signups_hourly(),
+            signups_daily: Self::default_signups_daily(),
+            trusted_proxies: Vec::new(),
+            client_ip_header: Self::default_client_ip_header(),
+        }
+    }
+}
+
+/// A range of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A bare address is a
+/// range of one.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    /// Whether `ip` is within this range. IPv4-mapped IPv6 addresses match IPv4 ranges.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((&s, ""));
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|e| format!("invalid address in {s:?}: {e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix.trim() {
+            "" => max,
+            p => p
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length in {s:?}"))?,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GcConfig {
+    /// How often to collect expired entries, in seconds.
+    #[serde(default = "GcConfig::default_interval")]
+    pub interval: u64,
+    /// The maximum number of entries deleted per statement.
+    #[serde(default = "GcConfig::default_batch_size")]
+    pub batch_size: u32,
+    /// Overrides of how long each store's entries are retained past their timestamp, in seconds,
+    /// keyed by store name (see `gc::STORES`).
+    #[serde(default)]
+    pub retention: HashMap<String, u64>,
+}
+
+impl GcConfig {
+    fn default_interval() -> u64 {
+        60 * 60
+    }
+
+    fn default_batch_size() -> u32 {
+        500
+    }
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            interval: Self::default_interval(),
+            batch_size: Self::default_batch_size(),
+            retention: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MigrationConfig {
+    /// How long an inbound migration may go without progress before it is reported as stuck, in
+    /// seconds.
+    #[serde(default = "MigrationConfig::default_stuck_after")]
+    pub stuck_after: u64,
+    /// How often to check for stuck migrations, in seconds.
+    #[serde(default = "MigrationConfig::default_interval")]
+    pub interval: u64,
+}
+
+impl MigrationConfig {
+    fn default_stuck_after() -> u64 {
+        24 * 60 * 60
+    }
+
+    fn default_interval() -> u64 {
+        60 * 60
+    }
+}
+
+impl Default for MigrationConfig {
+    fn default() -> Self {
+        Self {
+            stuck_after: Self::default_stuck_after(),
+            interval: Self::default_interval(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReindexConfig {
+    /// The number of records read per second while rebuilding the metadata of repositories
+    /// written by an earlier version.
+    #[serde(default = "ReindexConfig::default_records_per_second")]
+    pub records_per_second: u32,
+    /// The number of times indexing a repository is attempted before giving up on it.
+    #[serde(default = "ReindexConfig::default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+impl ReindexConfig {
+    fn default_records_per_second() -> u32 {
+        2000
+    }
+
+    fn default_max_attempts() -> u32 {
+        3
+    }
+}
+
+impl Default for ReindexConfig {
+    fn default() -> Self {
+        Self {
+            records_per_second: Self::default_records_per_second(),
+            max_attempts: Self::default_max_attempts(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PolicyConfig {
+    /// A file of rules that records written must satisfy (see `policy::Rules`).
+    #[serde(default)]
+    pub rules: Option<PathBuf>,
+    /// How long each policy may take to check a record, in milliseconds.
+    #[serde(default = "PolicyConfig::default_timeout")]
+    pub timeout: u64,
+    /// Whether a write is allowed (true) or rejected (false) if a policy fails or times out.
+    #[serde(default = "PolicyConfig::default_fail_open")]
+    pub fail_open: bool,
+}
+
+impl PolicyConfig {
+    fn default_timeout() -> u64 {
+        500
+    }
+
+    fn default_fail_open() -> bool {
+        true
+    }
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            rules: None,
+            timeout: Self::default_timeout(),
+            fail_open: Self::default_fail_open(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TieringConfig {
+    /// Where the repositories and blobs of inactive accounts are moved to. Tiering is disabled if
+    /// unset.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// How long an account may be inactive before it is moved to the archive, in days.
+    #[serde(default = "TieringConfig::default_after_days")]
+    pub after_days: u64,
+    /// How often to check for inactive accounts, in seconds.
+    #[serde(default = "TieringConfig::default_interval")]
+    pub interval: u64,
+    /// How long clients are asked to wait while an account is moved back, in seconds.
+    #[serde(default = "TieringConfig::default_retry_after")]
+    pub retry_after: u64,
+}
+
+impl TieringConfig {
+    fn default_after_days() -> u64 {
+        180
+    }
+
+    fn default_interval() -> u64 {
+        24 * 60 * 60
+    }
+
+    fn default_retry_after() -> u64 {
+        30
+    }
+}
+
+impl Default for TieringConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            after_days: Self::default_after_days(),
+            interval: Self::default_interval(),
+            retry_after: Self::default_retry_after(),
+        }
+    }
+}
+
+/// Information about this PDS, published by `describeServer`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ServiceInfoConfig {
+    /// The URL of the PDS's privacy policy.
+    #[serde(default)]
+    pub privacy_policy: Option<Url>,
+    /// The URL of the PDS's terms of service.
+    #[serde(default)]
+    pub terms_of_service: Option<Url>,
+    /// An email address at which the operator can be contacted.
+    #[serde(default)]
+    pub contact_email: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct InviteConfig {
+    /// Require an invite code to sign up.
+    #[serde(default = "InviteConfig::default_required")]
+    pub required: bool,
+    /// How often an account earns an invite code of its own, in seconds. Earned codes are created
+    /// on request (see `getAccountInviteCodes`). Accounts earn no codes if unset.
+    #[serde(default)]
+    pub interval: Option<u64>,
+}
+
+impl InviteConfig {
+    fn default_required() -> bool {
+        true
+    }
+}
+
+impl Default for InviteConfig {
+    fn default() -> Self {
+        Self {
+            required: Self::default_required(),
+            interval: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PasswordConfig {
+    /// The minimum length of a password, in characters.
+    #[serde(default = "PasswordConfig::default_min_length")]
+    pub min_length: usize,
+    /// Reject the most common passwords.
+    #[serde(default = "PasswordConfig::default_reject_common")]
+    pub reject_common: bool,
+}
+
+impl PasswordConfig {
+    fn default_min_length() -> usize {
+        8
+    }
+
+    fn default_reject_common() -> bool {
+        true
+    }
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        Self {
+            min_length: Self::default_min_length(),
+            reject_common: Self::default_reject_common(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SignupQueueConfig {
+    /// The number of queued accounts activated at a time.
+    #[serde(default = "SignupQueueConfig::default_batch")]
+    pub batch: u32,
+    /// How often the next batch of queued accounts is activated, in seconds.
+    #[serde(default = "SignupQueueConfig::default_interval")]
+    pub interval: u64,
+}
+
+impl SignupQueueConfig {
+    fn default_batch() -> u32 {
+        10
+    }
+
+    fn default_interval() -> u64 {
+        60
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MailConfig {
+    /// A URL that outgoing mail is POSTed to (as JSON, with `from`, `to`, `subject` and `text`),
+    /// e.g. an email delivery service's HTTP API or a relay in front of an SMTP server.
+    pub webhook: Url,
+    /// The sender address of outgoing mail.
+    pub from: String,
+}
+
+/// How user identifiers (DIDs, handles, emails and IP addresses) are redacted in logs.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Redaction {
+    /// Log identifiers as-is.
+    #[default]
+    Off,
+    /// Replace identifiers with a short hash, so that log lines can still be correlated.
+    Hash,
+    /// Keep only a prefix of each identifier (e.g. the /16 network of an IPv4 address).
+    Truncate,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct LogConfig {
+    /// Redaction of user identifiers in logs. The audit log is never redacted.
+    #[serde(default)]
+    pub redact: Redaction,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct AppConfig {
     /// The primary signing keys for all PLC/DID operations.
     pub key: PathBuf,
-    /// The hostname of the PDS. Typically a domain name.
+    /// The primary hostname of the PDS. Typically a domain name.
     pub host_name: String,
+    /// Additional hostnames the PDS is served under (e.g. vanity domains). Responses to requests
+    /// made under an alias refer to the alias, but DID documents and relays always get the
+    /// primary hostname.
+    #[serde(default)]
+    pub host_aliases: Vec<String>,
+    /// Reject requests made under any hostname other than the primary or an alias.
+    #[serde(default)]
+    pub strict_host: bool,
+    /// The domains under which accounts may take a handle (e.g. `pds.example.com`, for handles
+    /// like `alice.pds.example.com`). Defaults to the primary hostname and its aliases.
+    #[serde(default)]
+    pub handle_domains: Vec<String>,
+    /// Handles that accounts may not take: single labels (e.g. `admin`, reserved under each of the
+    /// handle domains), or full handles. Defaults to names of staff, services and well-known brands.
+    #[serde(default = "AppConfig::default_reserved_handles")]
+    pub reserved_handles: Vec<String>,
+    /// Information about this PDS published to clients.
+    #[serde(default)]
+    pub service: ServiceInfoConfig,
+    /// The password for administrative endpoints. Admin endpoints are disabled if unset.
+    #[serde(default)]
+    pub admin_password: Option<String>,
+    /// The DID of a labeler service operated by this PDS. It's listed among the labelers applied
+    /// (`atproto-content-labelers`) to responses proxied from other services.
+    #[serde(default)]
+    pub labeler: Option<String>,
     /// The listen address for the PDS.
     pub listen_address: Option<SocketAddr>,
+    /// How long to wait (in seconds) on shutdown for in-flight requests to finish and the firehose
+    /// to drain before exiting anyway.
+    #[serde(default = "AppConfig::default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
     /// The metrics configuration block.
     pub metrics: Option<MetricConfig>,
     /// The firehose configuration block.
     pub firehose: FirehoseConfig,
     /// The PLC configuration block.
     pub plc: PlcConfig,
+    /// Caching of DID documents resolved from the PLC directory or `did:web` hosts.
+    #[serde(default)]
+    pub did_cache: DidCacheConfig,
     /// The repo configuration block.
     pub repo: RepoConfig,
     /// The blob configuration block.
     pub blob: BlobConfig,
+    /// The rate limiting configuration block.
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Invite codes, and whether they're required to sign up.
+    #[serde(default)]
+    pub invites: InviteConfig,
+    /// The policy that account passwords must meet.
+    #[serde(default)]
+    pub password: PasswordConfig,
+    /// Queueing of new accounts for activation. If unset, new accounts are active at once.
+    #[serde(default)]
+    pub signup_queue: Option<SignupQueueConfig>,
+    /// Garbage collection of expired sessions, tokens and codes.
+    #[serde(default)]
+    pub gc: GcConfig,
+    /// The logging configuration block.
+    #[serde(default)]
+    pub log: LogConfig,
+    /// Tracking of inbound account migrations.
+    #[serde(default)]
+    pub migration: MigrationConfig,
+    /// Rebuilding of metadata for repositories written by an earlier version.
+    #[serde(default)]
+    pub reindex: ReindexConfig,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    /// Moving the storage of inactive accounts to an archive.
+    #[serde(default)]
+    pub tiering: TieringConfig,
+    /// Delivery of email to account holders. If unset, mail is only logged.
+    #[serde(default)]
+    pub mail: Option<MailConfig>,
     /// The sqlite database connection options.
     pub db: String,
     /// Test mode.
     pub test: bool,
 }
+
+impl AppConfig {
+    fn default_reserved_handles() -> Vec<String> {
+        [
+            "about",
+            "abuse",
+            "admin",
+            "administrator",
+            "api",
+            "atproto",
+            "blog",
+            "bluesky",
+            "bsky",
+            "help",
+            "hostmaster",
+            "info",
+            "mail",
+            "mod",
+            "moderation",
+            "moderator",
+            "official",
+            "pds",
+            "postmaster",
+            "root",
+            "security",
+            "staff",
+            "status",
+            "support",
+            "system",
+            "team",
+            "webmaster",
+            "www",
+        ]
+        .into_iter()
+        .map(str::to_string)
+        .collect()
+    }
+
+    fn default_shutdown_timeout() -> u64 {
+        10
+    }
+
+    /// The DID of this PDS.
+    pub fn did(&self) -> String {
+        format!("did:web:{}", self.host_name)
+    }
+
+    /// The domains under which accounts may take a handle, in lowercase and without a leading
+    /// dot (see `handle_domains`).
+    pub fn handle_domains(&self) -> Vec<String> {
+        let domains = if self.handle_domains.is_empty() {
+            std::iter::once(&self.host_name)
+                .chain(&self.host_aliases)
+                .collect::<Vec<_>>()
+        } else {
+            self.handle_domains.iter().collect()
+        };
+
+        domains
+            .into_iter()
+            .map(|d| d.trim_start_matches('.').to_ascii_lowercase())
+            .collect()
+    }
+}
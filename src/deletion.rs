@@ -0,0 +1,250 @@
+//! Deletion of accounts.
+//!
+//! An account is deleted in two steps. First, [`delete_account`] removes the account's rows in a
+//! single transaction, so that the account is gone at once: its handle no longer resolves, its
+//! repository is no longer served, and it can no longer log in. Then its storage (its repository,
+//! and the blobs that no other account references) is purged in the background, as that may take
+//! a while for a large account. An interrupted purge is run again on recovery (see
+//! [`crate::jobs`]).
+//!
+//! The account's blob references are kept until its blobs are purged, as they're what identifies
+//! the blobs. The account's DID isn't tombstoned with the PLC directory, and its log of PLC
+//! operations is kept, so that an operator can still recover the identity.
+
+use anyhow::{anyhow, Context};
+use axum::http::StatusCode;
+use futures::future::BoxFuture;
+use tracing::{info, warn};
+
+use crate::{
+    config::AppConfig,
+    endpoints::upload_path,
+    error::ErrorMessage,
+    jobs::{self, Job, JobKind, Recovery},
+    tiering::Tiering,
+    Db, Error, Result,
+};
+
+pub const JOB: JobKind = JobKind {
+    name: "delete_account",
+    recover: recover_job,
+};
+
+/// Tables holding an account's rows, deleted along with it. Rows referencing the account are
+/// deleted before the account itself.
+const TABLES: &[&str] = &[
+    "handles",
+    "sessions",
+    "backlinks",
+    "repo_stats",
+    "repo_integrity",
+    "migrations",
+    "staged_commits",
+    "commit_log",
+    "reindex",
+    "account_tiers",
+    "refresh_tokens",
+    "app_passwords",
+    "email_tokens",
+    "account_keys",
+    "signup_queue",
+    "repo_collections",
+];
+
+/// Delete an account, and start purging its storage in the background. The account's sessions
+/// must be revoked separately.
+pub async fn delete_account(config: &AppConfig, db: &Db, did: &str) -> Result<()> {
+    let Some(id) = jobs::create(db, &JOB, did).await? else {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("account {did} is already being deleted"),
+            ErrorMessage::new("InvalidRequest", "account is already being deleted"),
+        ));
+    };
+
+    jobs::start(db, id).await?;
+
+    let r = async {
+        let mut tx = db.begin().await.context("failed to begin transaction")?;
+        for table in TABLES {
+            sqlx::query(&format!("DELETE FROM {table} WHERE did = ?"))
+                .bind(did)
+                .execute(&mut *tx)
+                .await
+                .with_context(|| format!("failed to delete {did} from {table}"))?;
+        }
+        let uploads: Vec<String> =
+            sqlx::query_scalar(r#"DELETE FROM blob_uploads WHERE did = ? RETURNING id"#)
+                .bind(did)
+                .fetch_all(&mut *tx)
+                .await
+                .context("failed to delete uploads")?;
+        let deleted = sqlx::query(r#"DELETE FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .execute(&mut *tx)
+            .await
+            .context("failed to delete account")?
+            .rows_affected();
+        anyhow::ensure!(deleted == 1, "account {did} not found");
+        tx.commit().await.context("failed to commit transaction")?;
+
+        anyhow::Ok(uploads)
+    }
+    .await;
+
+    let uploads = match r {
+        Ok(uploads) => uploads,
+        Err(e) => {
+            jobs::finish(db, id, Some(&format!("{e:#}"))).await?;
+            return Err(e.into());
+        }
+    };
+
+    for upload in &uploads {
+        let _ = tokio::fs::remove_file(upload_path(config, upload)).await;
+    }
+
+    let (config, db, did) = (config.clone(), db.clone(), did.to_string());
+    tokio::spawn(async move {
+        let r = purge(&config, &db, &did).await;
+        if let Err(e) = &r {
+            warn!("failed to purge the storage of deleted account {did}: {e:?}");
+        }
+        let error = r.err().map(|e| format!("{e:#}"));
+        if let Err(e) = jobs::finish(&db, id, error.as_deref()).await {
+            warn!("failed to record the purge of {did}: {e:?}");
+        }
+    });
+
+    Ok(())
+}
+
+/// Remove a deleted account's repository, and the blobs no other account references, along with
+/// its blob references. Idempotent.
+async fn purge(config: &AppConfig, db: &Db, did: &str) -> anyhow::Result<()> {
+    let blobs: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT DISTINCT cid FROM blob_ref
+            WHERE did = ? AND cid NOT IN (SELECT cid FROM blob_ref WHERE did != ?)
+        "#,
+    )
+    .bind(did)
+    .bind(did)
+    .fetch_all(db)
+    .await
+    .context("failed to query blobs")?;
+
+    Tiering::new(config, db.clone())
+        .remove(did, &blobs)
+        .await
+        .context("failed to remove storage")?;
+
+    sqlx::query(r#"DELETE FROM blob_ref WHERE did = ?"#)
+        .bind(did)
+        .execute(db)
+        .await
+        .context("failed to delete blob references")?;
+
+    info!(
+        "purged the storage of deleted account {did} ({} blobs)",
+        blobs.len()
+    );
+    Ok(())
+}
+
+/// Recover an interrupted deletion: if the account was deleted, finish purging its storage.
+async fn recover(config: AppConfig, db: Db, job: Job) -> anyhow::Result<Recovery> {
+    let exists: bool = sqlx::query_scalar(r#"SELECT EXISTS(SELECT 1 FROM accounts WHERE did = ?)"#)
+        .bind(&job.subject)
+        .fetch_one(&db)
+        .await
+        .context("failed to query account")?;
+    if exists {
+        // The account's rows were never deleted, so nothing was purged either.
+        return Ok(Recovery::RolledBack);
+    }
+
+    purge(&config, &db, &job.subject).await?;
+    Ok(Recovery::Completed)
+}
+
+fn recover_job(
+    config: AppConfig,
+    db: Db,
+    job: Job,
+) -> BoxFuture<'static, anyhow::Result<Recovery>> {
+    Box::pin(recover(config, db, job))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn deleted() {
+        let dir = crate::testing::temp_dir("delete");
+        let config = crate::testing::config(&dir, serde_json::json!({}));
+
+        let db = crate::testing::memory_db().await;
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES
+                ('did:plc:alice', 'alice@example.com', '', '', '', ''),
+                ('did:plc:bob', 'bob@example.com', '', '', '', '');
+            INSERT INTO handles (handle, did) VALUES ('alice.pds.example.com', 'did:plc:alice');
+            INSERT INTO repo_stats (did, window_start) VALUES ('did:plc:alice', 0);
+            INSERT INTO blob_ref (cid, did, record) VALUES
+                ('own', 'did:plc:alice', NULL),
+                ('shared', 'did:plc:alice', NULL),
+                ('shared', 'did:plc:bob', NULL);
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let repo = dir.join("repo").join("alice.car");
+        std::fs::write(&repo, b"repo").unwrap();
+        for cid in ["own", "shared"] {
+            std::fs::write(dir.join("blob").join(format!("{cid}.blob")), b"blob").unwrap();
+        }
+
+        delete_account(&config, &db, "did:plc:alice").await.unwrap();
+
+        // The account is gone at once.
+        let accounts: Vec<String> = sqlx::query_scalar(r#"SELECT did FROM accounts"#)
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(accounts, ["did:plc:bob"]);
+        let handles: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM handles"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(handles, 0);
+        assert!(delete_account(&config, &db, "did:plc:alice").await.is_err());
+
+        // Its storage is purged in the background.
+        let state = loop {
+            // The first job is the deletion; the second, the failed attempt to repeat it.
+            let jobs = jobs::list(&db, None, 10).await.unwrap();
+            match jobs.last().unwrap().state.as_str() {
+                "running" => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+                state => break state.to_string(),
+            }
+        };
+        assert_eq!(state, "done");
+        assert!(!repo.exists());
+        assert!(!dir.join("blob").join("own.blob").exists());
+        assert!(dir.join("blob").join("shared.blob").exists());
+
+        let refs: Vec<String> = sqlx::query_scalar(r#"SELECT did FROM blob_ref"#)
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(refs, ["did:plc:bob"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
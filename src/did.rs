@@ -1,12 +1,22 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
 use anyhow::{bail, Context, Result};
 use atrium_api::types::string::Did;
+use metrics::counter;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::Client;
-
-/// URL whitelist for DID document resolution.
-const ALLOWED_URLS: &[&str] = &["bsky.app", "bsky.chat"];
+use crate::{
+    config::{AppConfig, DidCacheConfig},
+    metrics::{DID_CACHE_HITS, DID_CACHE_MISSES},
+    plc::{self, PlcService},
+    Client, Db,
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -34,26 +44,61 @@ pub struct DidDocument {
     pub context: Vec<Url>,
     pub id: Did,
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub also_known_as: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub verification_method: Vec<DidVerificationMethod>,
     pub service: Vec<DidService>,
 }
 
+impl DidDocument {
+    /// The endpoint of the account's PDS.
+    pub fn pds(&self) -> Option<&Url> {
+        let id = self.id.as_str();
+        self.service
+            .iter()
+            .find(|s| s.id == format!("{id}#atproto_pds") || s.id == "#atproto_pds")
+            .map(|s| &s.service_endpoint)
+    }
+
+    /// The handle the identity claims: the first `at://` URI in `alsoKnownAs`, in lowercase. It's
+    /// only the account's handle if the handle resolves back to the DID.
+    pub fn handle(&self) -> Option<String> {
+        self.also_known_as
+            .iter()
+            .find_map(|aka| aka.strip_prefix("at://"))
+            .map(str::to_lowercase)
+    }
+
+    /// The account's signing key, in `did:key` form.
+    pub fn signing_key(&self) -> Option<String> {
+        let id = self.id.as_str();
+        self.verification_method
+            .iter()
+            .find(|m| m.id == format!("{id}#atproto") || m.id == "#atproto")
+            .map(|m| format!("did:key:{}", m.public_key_multibase))
+    }
+}
+
 /// Resolve a DID document using the specified reqwest client.
 pub async fn resolve(client: &Client, did: Did) -> Result<DidDocument> {
     let url = match did.method() {
         "did:web" => {
-            // N.B: This is a potentially hostile operation, so we are only going to allow
-            // certain URLs for now.
+            // N.B: This is a potentially hostile operation, as the host is chosen by whoever made
+            // up the DID. Only public hostnames are fetched: no addresses, ports or paths.
             let host = did
                 .as_str()
                 .strip_prefix("did:web:")
                 .context("invalid DID format")?;
 
-            if !ALLOWED_URLS.iter().any(|u| host.ends_with(u)) {
-                bail!("forbidden URL {host}");
+            let url = Url::parse(&format!("https://{host}/.well-known/did.json"))
+                .context("invalid did:web host")?;
+            match url.host() {
+                Some(url::Host::Domain(d))
+                    if d == host && d.contains('.') && !d.ends_with(".localhost") => {}
+                _ => bail!("forbidden did:web host {host}"),
             }
 
-            format!("https://{}/.well-known/did.json", host)
+            url.to_string()
         }
         "did:plc" => {
             format!("https://plc.directory/{}", did.as_str())
@@ -63,12 +108,400 @@ pub async fn resolve(client: &Client, did: Did) -> Result<DidDocument> {
     .parse::<Url>()
     .context("failed to resolve DID URL")?;
 
-    client
+    let doc: DidDocument = client
         .get(url)
         .send()
         .await
         .context("failed to fetch DID document")?
         .json()
         .await
-        .context("failed to decode DID document")
+        .context("failed to decode DID document")?;
+
+    if doc.id.as_str() != did.as_str() {
+        bail!(
+            "DID document for {} is for {}",
+            did.as_str(),
+            doc.id.as_str()
+        );
+    }
+    Ok(doc)
+}
+
+/// Where a DID document was obtained from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DidSource {
+    /// The public PLC directory.
+    Plc,
+    /// A `did:web` host.
+    Web,
+    /// Synthesized from the latest PLC operation stored by this PDS (test mode only).
+    Local,
+}
+
+#[derive(Clone, Debug)]
+pub struct CachedDidDocument {
+    pub doc: DidDocument,
+    pub source: DidSource,
+    fetched: Instant,
+}
+
+impl CachedDidDocument {
+    /// The time elapsed since this document was fetched.
+    pub fn age(&self) -> Duration {
+        self.age_at(Instant::now())
+    }
+
+    fn age_at(&self, now: Instant) -> Duration {
+        now.duration_since(self.fetched)
+    }
+}
+
+/// A cached document, and when it was last used.
+#[derive(Debug)]
+struct Entry {
+    doc: CachedDidDocument,
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<String, Entry>,
+    /// The cached DIDs by when they were last used, least recently first.
+    lru: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, did: &str) {
+        if let Some(e) = self.map.remove(did) {
+            self.lru.remove(&e.used);
+        }
+    }
+
+    fn touch(&mut self, did: &str) {
+        self.clock += 1;
+        if let Some(e) = self.map.get_mut(did) {
+            self.lru.remove(&e.used);
+            e.used = self.clock;
+            self.lru.insert(self.clock, did.to_string());
+        }
+    }
+
+    fn insert(&mut self, did: &str, doc: CachedDidDocument, capacity: usize) {
+        self.remove(did);
+        self.clock += 1;
+        self.map.insert(
+            did.to_string(),
+            Entry {
+                doc,
+                used: self.clock,
+            },
+        );
+        self.lru.insert(self.clock, did.to_string());
+
+        while self.map.len() > capacity.max(1) {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            self.map.remove(&oldest);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<Entries>,
+    /// Held while a document is fetched, so that concurrent misses for the same DID wait for a
+    /// single fetch rather than each making their own.
+    flights: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+/// An in-memory cache of resolved DID documents, shared by everything that needs them.
+///
+/// Documents expire after a configurable TTL, and beyond a maximum number of documents, the least
+/// recently used are evicted. Concurrent misses for the same DID are served by a single fetch.
+#[derive(Clone, Debug)]
+pub struct DidCache(Arc<Inner>);
+
+impl Default for DidCache {
+    fn default() -> Self {
+        Self::new(&DidCacheConfig::default())
+    }
+}
+
+impl DidCache {
+    pub fn new(config: &DidCacheConfig) -> Self {
+        Self(Arc::new(Inner {
+            ttl: Duration::from_secs(config.ttl),
+            capacity: config.capacity,
+            entries: Mutex::new(Entries::default()),
+            flights: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Fetch an unexpired document from the cache.
+    pub fn get(&self, did: &str) -> Option<CachedDidDocument> {
+        self.get_at(did, Instant::now())
+    }
+
+    fn get_at(&self, did: &str, now: Instant) -> Option<CachedDidDocument> {
+        let mut entries = self.0.entries.lock().unwrap();
+        let doc = entries.map.get(did).map(|e| e.doc.clone())?;
+        if doc.age_at(now) >= self.0.ttl {
+            entries.remove(did);
+            return None;
+        }
+
+        entries.touch(did);
+        Some(doc)
+    }
+
+    fn insert_at(
+        &self,
+        did: &str,
+        doc: DidDocument,
+        source: DidSource,
+        now: Instant,
+    ) -> CachedDidDocument {
+        let d = CachedDidDocument {
+            doc,
+            source,
+            fetched: now,
+        };
+
+        self.0
+            .entries
+            .lock()
+            .unwrap()
+            .insert(did, d.clone(), self.0.capacity);
+        d
+    }
+
+    /// Drop a document from the cache, e.g. after the identity has been updated.
+    pub fn invalidate(&self, did: &str) {
+        self.0.entries.lock().unwrap().remove(did);
+    }
+
+    /// Resolve a DID document through the cache. If `refresh` is set, the cache is bypassed and
+    /// the document is re-fetched from its source.
+    pub async fn resolve(
+        &self,
+        client: &Client,
+        config: &AppConfig,
+        db: &Db,
+        did: Did,
+        refresh: bool,
+    ) -> Result<CachedDidDocument> {
+        self.resolve_with(did.as_str(), refresh, || async {
+            // In test mode, PLC operations for hosted accounts are never submitted to the
+            // directory.
+            if config.test && did.method() == "did:plc" {
+                return Ok((resolve_local(config, db, &did).await?, DidSource::Local));
+            }
+
+            let source = match did.method() {
+                "did:web" => DidSource::Web,
+                _ => DidSource::Plc,
+            };
+            Ok((resolve(client, did.clone()).await?, source))
+        })
+        .await
+    }
+
+    async fn resolve_with<F, Fut>(
+        &self,
+        did: &str,
+        refresh: bool,
+        fetch: F,
+    ) -> Result<CachedDidDocument>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(DidDocument, DidSource)>>,
+    {
+        let requested = Instant::now();
+        if !refresh {
+            if let Some(d) = self.get(did) {
+                counter!(DID_CACHE_HITS).increment(1);
+                return Ok(d);
+            }
+        }
+
+        let flight = self
+            .0
+            .flights
+            .lock()
+            .unwrap()
+            .entry(did.to_string())
+            .or_default()
+            .clone();
+
+        let r = async {
+            let _guard = flight.lock().await;
+
+            // Whoever waited on another fetch is served its result, as long as it was made after
+            // they asked (for a refresh).
+            if let Some(d) = self.get(did).filter(|d| !refresh || d.fetched >= requested) {
+                counter!(DID_CACHE_HITS).increment(1);
+                return Ok(d);
+            }
+
+            counter!(DID_CACHE_MISSES).increment(1);
+            let (doc, source) = fetch().await?;
+            Ok(self.insert_at(did, doc, source, Instant::now()))
+        }
+        .await;
+
+        // The flight is done with once nobody else is waiting on it.
+        let mut flights = self.0.flights.lock().unwrap();
+        if Arc::strong_count(&flight) == 2 {
+            flights.remove(did);
+        }
+
+        r
+    }
+}
+
+/// Synthesize a DID document from the latest PLC operation this PDS holds for a hosted account.
+async fn resolve_local(config: &AppConfig, db: &Db, did: &Did) -> Result<DidDocument> {
+    let plc_root: String = sqlx::query_scalar(r#"SELECT plc_root FROM accounts WHERE did = ?"#)
+        .bind(did.as_str())
+        .fetch_one(db)
+        .await
+        .context("failed to fetch PLC root")?;
+
+    let op = plc::read_op(&config.plc, did.as_str(), &plc_root).await?;
+
+    Ok(DidDocument {
+        context: vec![
+            Url::parse("https://www.w3.org/ns/did/v1").unwrap(),
+            Url::parse("https://w3id.org/security/multikey/v1").unwrap(),
+        ],
+        id: did.clone(),
+        also_known_as: op.also_known_as,
+        verification_method: op
+            .verification_methods
+            .into_iter()
+            .map(|(id, key)| DidVerificationMethod {
+                id: format!("{}#{}", did.as_str(), id),
+                ty: "Multikey".to_string(),
+                controller: did.as_str().to_string(),
+                public_key_multibase: key.strip_prefix("did:key:").unwrap_or(&key).to_string(),
+            })
+            .collect(),
+        service: op
+            .services
+            .into_iter()
+            .filter_map(|(id, s)| match s {
+                PlcService::Pds { endpoint } => Some(DidService {
+                    id: format!("#{id}"),
+                    ty: "AtprotoPersonalDataServer".to_string(),
+                    service_endpoint: Url::parse(&endpoint).ok()?,
+                }),
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn doc() -> DidDocument {
+        DidDocument {
+            context: vec![],
+            id: Did::new("did:plc:test".to_string()).unwrap(),
+            also_known_as: vec![],
+            verification_method: vec![],
+            service: vec![],
+        }
+    }
+
+    #[test]
+    fn cache_age() {
+        let cache = DidCache::default();
+        let now = Instant::now();
+
+        cache.insert_at("did:plc:test", doc(), DidSource::Plc, now);
+
+        let a = cache.get_at("did:plc:test", now).unwrap().age_at(now);
+        let later = now + Duration::from_secs(5);
+        let b = cache.get_at("did:plc:test", later).unwrap().age_at(later);
+        assert!(b > a);
+
+        // A forced refresh replaces the entry, resetting its age.
+        cache.insert_at("did:plc:test", doc(), DidSource::Plc, later);
+        let c = cache.get_at("did:plc:test", later).unwrap().age_at(later);
+        assert_eq!(c, Duration::ZERO);
+
+        // Expired entries are not returned.
+        assert!(cache
+            .get_at("did:plc:test", later + Duration::from_secs(60 * 60))
+            .is_none());
+    }
+
+    #[test]
+    fn eviction() {
+        let cache = DidCache::new(&DidCacheConfig {
+            ttl: 60,
+            capacity: 2,
+        });
+        let now = Instant::now();
+
+        cache.insert_at("did:plc:a", doc(), DidSource::Plc, now);
+        cache.insert_at("did:plc:b", doc(), DidSource::Plc, now);
+        // Using `a` makes `b` the least recently used.
+        assert!(cache.get_at("did:plc:a", now).is_some());
+        cache.insert_at("did:plc:c", doc(), DidSource::Plc, now);
+        assert!(cache.get_at("did:plc:a", now).is_some());
+        assert!(cache.get_at("did:plc:b", now).is_none());
+        assert!(cache.get_at("did:plc:c", now).is_some());
+
+        cache.invalidate("did:plc:a");
+        assert!(cache.get_at("did:plc:a", now).is_none());
+        assert!(cache.get_at("did:plc:c", now).is_some());
+
+        let entries = cache.0.entries.lock().unwrap();
+        assert_eq!(entries.map.len(), entries.lru.len());
+    }
+
+    #[tokio::test]
+    async fn single_flight() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let cache = DidCache::default();
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok((doc(), DidSource::Plc))
+        };
+
+        let resolved = futures::future::join_all(
+            (0..5).map(|_| cache.resolve_with("did:plc:test", false, fetch)),
+        )
+        .await;
+        assert!(resolved.iter().all(Result::is_ok));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert!(cache.0.flights.lock().unwrap().is_empty());
+
+        // Refreshes bypass the cache, but concurrent ones still share a fetch.
+        let resolved = futures::future::join_all(
+            (0..5).map(|_| cache.resolve_with("did:plc:test", true, fetch)),
+        )
+        .await;
+        assert!(resolved.iter().all(Result::is_ok));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // Failures aren't cached.
+        let r = cache
+            .resolve_with("did:plc:other", false, || async {
+                anyhow::bail!("unreachable")
+            })
+            .await;
+        assert!(r.is_err());
+        assert!(cache.get("did:plc:other").is_none());
+    }
 }
@@ -0,0 +1,80 @@
+//! Private user preferences (`app.bsky.actor`).
+//!
+//! HACK: We shouldn't have to know about any bsky endpoints to store private user data.
+//! This will _very likely_ be changed in the future.
+
+use anyhow::Context;
+use atrium_api::app::bsky::actor;
+use axum::{
+    extract::{DefaultBodyLimit, State},
+    routing::{get, post},
+    Json,
+};
+use constcat::concat;
+use tracing::warn;
+
+use crate::{auth::AuthenticatedUser, capabilities::Routes, migration, Db, Result};
+
+use super::MAX_JSON_BODY;
+
+async fn put_preferences(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    Json(input): Json<actor::put_preferences::Input>,
+) -> Result<()> {
+    let did = user.did();
+    let prefs = sqlx::types::Json(input.preferences.clone());
+    sqlx::query!(
+        r#"UPDATE accounts SET private_prefs = ? WHERE did = ?"#,
+        prefs,
+        did
+    )
+    .execute(&db)
+    .await
+    .context("failed to update user preferences")?;
+
+    if let Err(e) = migration::record(&db, &did, migration::Step::PreferencesImported).await {
+        warn!("failed to record migration step for {did}: {e:?}");
+    }
+
+    Ok(())
+}
+
+async fn get_preferences(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+) -> Result<Json<actor::get_preferences::Output>> {
+    let did = user.did();
+    let json: Option<sqlx::types::Json<actor::defs::Preferences>> =
+        sqlx::query_scalar(r#"SELECT private_prefs FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_one(&db)
+            .await
+            .context("failed to fetch preferences")?;
+
+    if let Some(prefs) = json {
+        Ok(Json(
+            actor::get_preferences::OutputData {
+                preferences: prefs.0,
+            }
+            .into(),
+        ))
+    } else {
+        Ok(Json(
+            actor::get_preferences::OutputData {
+                preferences: Vec::new(),
+            }
+            .into(),
+        ))
+    }
+}
+
+#[rustfmt::skip]
+pub fn routes() -> Routes {
+    // AP /xrpc/app.bsky.actor.putPreferences
+    // AG /xrpc/app.bsky.actor.getPreferences
+    Routes::new()
+        .route(concat!("/", actor::put_preferences::NSID), post(put_preferences))
+        .route(concat!("/", actor::get_preferences::NSID),  get(get_preferences))
+        .map_router(|r| r.layer(DefaultBodyLimit::max(MAX_JSON_BODY)))
+}
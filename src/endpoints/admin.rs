@@ -0,0 +1,494 @@
+//! Administrative endpoints.
+//!
+//! Most of these are not part of the AT protocol. All of them require the admin password (see
+//! [`auth::AdminUser`]). This is checked for the whole namespace (see [`routes`]), so individual
+//! handlers don't need to.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context};
+use atrium_api::com::atproto::admin;
+use axum::{
+    extract::{DefaultBodyLimit, Query, State},
+    http::StatusCode,
+    middleware,
+    routing::{get, post},
+    Json,
+};
+use constcat::concat;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    aturi::AtUri,
+    auth,
+    backlinks::{self, Backlink},
+    capabilities::Routes,
+    compact::{self, Compaction},
+    config::AppConfig,
+    did::DidCache,
+    error::ErrorMessage,
+    events::EventBus,
+    firehose::{FirehoseProducer, RelayState, SeqAudit, SubscriberInfo},
+    gc,
+    handle::HandleResolver,
+    integrity::{self, IntegrityStatus, RepoIntegrity},
+    invites,
+    jobs::{self, Job},
+    migration::{self, MigrationStatus},
+    stats::{self, RepoStats, StorageStats},
+    status::{self, AccountListing, AccountStatus},
+    unsupported::{MethodTally, TallyEntry},
+    verify::{RelayVerifier, RepoDivergence},
+    Client, Db, Error, Result, RotationKey,
+};
+
+use super::{
+    identity::{announce_handle, cached_identity, change_handle, handles_of, IdentityInfo},
+    repo::{commit_log_with, CommitLogOutput},
+    sync::list_repos_with,
+    MAX_JSON_BODY,
+};
+
+#[derive(Deserialize, Debug, Clone)]
+struct DidDocInput {
+    did: String,
+    /// Bypass the cache and re-fetch the document from its source.
+    #[serde(default)]
+    refresh: bool,
+}
+
+/// Report the state of each upstream relay: crawl requests and active firehose connections.
+async fn relay_status(
+    State(fhp): State<FirehoseProducer>,
+) -> Result<Json<HashMap<String, RelayState>>> {
+    Ok(Json(fhp.relays().snapshot()))
+}
+
+/// List the subscribers currently connected to the firehose.
+async fn list_firehose_subscribers(
+    State(fhp): State<FirehoseProducer>,
+) -> Result<Json<Vec<SubscriberInfo>>> {
+    Ok(Json(fhp.subscribers().snapshot()))
+}
+
+/// Report the result of the last audit of the firehose history for sequence gaps and duplicates.
+async fn firehose_audit(State(fhp): State<FirehoseProducer>) -> Result<Json<Option<SeqAudit>>> {
+    Ok(Json(fhp.last_audit()))
+}
+
+/// Immediately compare hosted repositories against the configured relay, returning any that
+/// have diverged.
+async fn verify_relay(State(verifier): State<RelayVerifier>) -> Result<Json<Vec<RepoDivergence>>> {
+    if !verifier.enabled() {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("relay verification is not configured"),
+            ErrorMessage::new("InvalidRequest", "relay verification is not configured"),
+        ));
+    }
+
+    Ok(Json(verifier.check().await?))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct BacklinksInput {
+    /// The AT URI of the referenced record.
+    subject: String,
+}
+
+/// List the local records that reference a subject URI.
+async fn list_backlinks(
+    State(db): State<Db>,
+    Query(input): Query<BacklinksInput>,
+) -> Result<Json<Vec<Backlink>>> {
+    let subject = AtUri::from_str(&input.subject).map_err(|e| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("invalid subject {:?}: {e}", input.subject),
+            ErrorMessage::new("InvalidRequest", e.to_string()),
+        )
+    })?;
+
+    Ok(Json(backlinks::lookup(&db, subject).await?))
+}
+
+/// Show the DID document this PDS currently holds for a hosted account, optionally refreshing it.
+async fn did_doc(
+    State(client): State<Client>,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(cache): State<DidCache>,
+    Query(input): Query<DidDocInput>,
+) -> Result<Json<IdentityInfo>> {
+    Ok(Json(
+        cached_identity(&client, &config, &db, &cache, &input.did, input.refresh).await?,
+    ))
+}
+
+/// Immediately delete expired sessions, tokens and codes, returning the number of entries
+/// deleted from each store.
+async fn collect_garbage(
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+) -> Result<Json<BTreeMap<&'static str, u64>>> {
+    Ok(Json(gc::sweep(&config.gc, &db).await?))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct RepoIntegrityInput {
+    /// Limit the results to a single repository.
+    did: Option<String>,
+}
+
+/// List repositories whose head has failed an integrity check (or the integrity state of one
+/// repository).
+async fn repo_integrity(
+    State(db): State<Db>,
+    Query(input): Query<RepoIntegrityInput>,
+) -> Result<Json<Vec<IntegrityStatus>>> {
+    Ok(Json(match input.did {
+        Some(did) => integrity::status(&db, &did).await?.into_iter().collect(),
+        None => integrity::failures(&db).await?,
+    }))
+}
+
+/// Flag a repository (or if unspecified, every repository) to have its head verified again.
+/// A single repository is verified immediately, returning its integrity state.
+async fn check_repo(
+    State(db): State<Db>,
+    State(integrity): State<RepoIntegrity>,
+    Json(input): Json<RepoIntegrityInput>,
+) -> Result<Json<Vec<IntegrityStatus>>> {
+    integrity.invalidate(input.did.as_deref());
+
+    let Some(did) = input.did else {
+        return Ok(Json(Vec::new()));
+    };
+
+    // A failure is recorded in the integrity state, which is what's reported.
+    let _ = integrity.head(&did).await;
+    Ok(Json(
+        integrity::status(&db, &did).await?.into_iter().collect(),
+    ))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct CompactRepoInput {
+    did: String,
+}
+
+/// Compact the repository of an inactive account, discarding blocks unreachable from its head.
+async fn compact_repo(
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(integrity): State<RepoIntegrity>,
+    State(stats): State<StorageStats>,
+    Json(input): Json<CompactRepoInput>,
+) -> Result<Json<Compaction>> {
+    Ok(Json(
+        compact::compact(&config.repo, &db, &integrity, &stats, &input.did).await?,
+    ))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct UpdateAccountStatusInput {
+    did: String,
+    /// The new status: `active`, `deactivated`, `takendown` or `suspended`.
+    status: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct UpdateAccountStatusOutput {
+    previous: &'static str,
+    status: &'static str,
+}
+
+/// Change the status of an account (e.g. take it down, or reinstate it), announcing the change on
+/// the firehose.
+async fn update_account_status(
+    State(db): State<Db>,
+    State(events): State<EventBus>,
+    Json(input): Json<UpdateAccountStatusInput>,
+) -> Result<Json<UpdateAccountStatusOutput>> {
+    let status = AccountStatus::parse(&input.status).ok_or_else(|| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("invalid status {:?}", input.status),
+            ErrorMessage::new(
+                "InvalidRequest",
+                format!("unknown status {:?}", input.status),
+            ),
+        )
+    })?;
+
+    let previous = status::set_status(&db, &events, &input.did, status).await?;
+    Ok(Json(UpdateAccountStatusOutput {
+        previous: previous.as_str(),
+        status: status.as_str(),
+    }))
+}
+
+/// Disable invite codes, by code or by the account they were issued to.
+async fn disable_invite_codes(
+    State(db): State<Db>,
+    Json(input): Json<admin::disable_invite_codes::Input>,
+) -> Result<()> {
+    invites::disable(
+        &db,
+        input.codes.as_deref().unwrap_or_default(),
+        input.accounts.as_deref().unwrap_or_default(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Change the handle of a hosted account, with the same checks as the account changing it itself.
+async fn update_account_handle(
+    State(rkey): State<RotationKey>,
+    State(client): State<Client>,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(events): State<EventBus>,
+    State(cache): State<DidCache>,
+    State(resolver): State<HandleResolver>,
+    Json(input): Json<admin::update_account_handle::Input>,
+) -> Result<()> {
+    let did = input.did.as_str();
+    let exists: bool = sqlx::query_scalar(r#"SELECT EXISTS(SELECT 1 FROM accounts WHERE did = ?)"#)
+        .bind(did)
+        .fetch_one(&db)
+        .await
+        .context("failed to query account")?;
+    if !exists {
+        return Err(Error::with_message(
+            StatusCode::NOT_FOUND,
+            anyhow!("account {did} not found"),
+            ErrorMessage::new("AccountNotFound", "account not found"),
+        ));
+    }
+
+    let old = handles_of(&db, did).await?;
+    change_handle(
+        &config,
+        &db,
+        &client,
+        &resolver,
+        &rkey,
+        did,
+        input.handle.as_str(),
+    )
+    .await?;
+
+    announce_handle(&cache, &resolver, &events, did, &old, input.handle.as_str()).await;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ListJobsInput {
+    /// Only list jobs in this state (e.g. `failed`).
+    state: Option<String>,
+    limit: Option<u32>,
+}
+
+/// List the most recent background jobs, including those recovered after a crash.
+async fn list_jobs(
+    State(db): State<Db>,
+    Query(input): Query<ListJobsInput>,
+) -> Result<Json<Vec<Job>>> {
+    let limit = input.limit.unwrap_or(100).clamp(1, 1000);
+    Ok(Json(jobs::list(&db, input.state.as_deref(), limit).await?))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct MigrationInput {
+    /// Only report the migration of this account.
+    did: Option<String>,
+}
+
+/// Report inbound account migrations in progress, least recently updated first.
+async fn list_migrations(
+    State(db): State<Db>,
+    Query(input): Query<MigrationInput>,
+) -> Result<Json<Vec<MigrationStatus>>> {
+    Ok(Json(match input.did {
+        Some(did) => migration::status(&db, &did).await?.into_iter().collect(),
+        None => migration::list(&db).await?,
+    }))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct CommitLogInput {
+    did: String,
+    /// Only list commits before this revision.
+    cursor: Option<String>,
+    limit: Option<u32>,
+}
+
+/// List the most recent commits to an account's repository.
+async fn commit_log(
+    State(db): State<Db>,
+    Query(input): Query<CommitLogInput>,
+) -> Result<Json<CommitLogOutput>> {
+    Ok(Json(
+        commit_log_with(&db, &input.did, input.cursor.as_deref(), input.limit).await?,
+    ))
+}
+
+/// Report today's requests for XRPC methods this PDS doesn't serve, most requested first.
+async fn unsupported_methods(State(tally): State<MethodTally>) -> Result<Json<Vec<TallyEntry>>> {
+    Ok(Json(tally.snapshot()))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ListAccountsInput {
+    /// Include deactivated accounts (default: true).
+    include_deactivated: Option<bool>,
+    /// Include taken down and suspended accounts (default: true).
+    include_takendown: Option<bool>,
+    /// Only list accounts with this status. Overrides the other flags.
+    status: Option<String>,
+    limit: Option<u32>,
+    cursor: Option<String>,
+}
+
+impl ListAccountsInput {
+    fn statuses(&self) -> Result<Vec<AccountStatus>> {
+        status::listing_statuses(
+            self.include_deactivated,
+            self.include_takendown,
+            self.status.as_deref(),
+            true,
+        )
+    }
+
+    fn limit(&self) -> u32 {
+        self.limit.unwrap_or(500).clamp(1, 1000)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct ListAccountsOutput {
+    accounts: Vec<AccountListing>,
+    cursor: Option<String>,
+}
+
+/// List hosted accounts, including non-active accounts unless excluded.
+async fn list_accounts(
+    State(db): State<Db>,
+    Query(input): Query<ListAccountsInput>,
+) -> Result<Json<ListAccountsOutput>> {
+    let limit = input.limit();
+    let accounts =
+        status::list_accounts(&db, &input.statuses()?, input.cursor.as_deref(), limit).await?;
+    let cursor = (accounts.len() == limit as usize)
+        .then(|| accounts.last().map(|a| a.did.clone()))
+        .flatten();
+
+    Ok(Json(ListAccountsOutput { accounts, cursor }))
+}
+
+/// The admin variant of `com.atproto.sync.listRepos`, including non-active repositories unless
+/// excluded.
+async fn list_repos(
+    State(db): State<Db>,
+    Query(input): Query<ListAccountsInput>,
+) -> Result<Json<atrium_api::com::atproto::sync::list_repos::Output>> {
+    Ok(Json(
+        list_repos_with(
+            &db,
+            &input.statuses()?,
+            input.cursor.as_deref(),
+            input.limit(),
+        )
+        .await?,
+    ))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct TopStorageInput {
+    limit: Option<u32>,
+    cursor: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct TopStorageOutput {
+    repos: Vec<RepoStats>,
+    cursor: Option<String>,
+}
+
+/// List hosted repositories by storage used, largest first.
+async fn top_storage(
+    State(db): State<Db>,
+    Query(input): Query<TopStorageInput>,
+) -> Result<Json<TopStorageOutput>> {
+    let limit = input.limit.unwrap_or(50).clamp(1, 100);
+    let offset = match input.cursor.as_deref().map(str::parse::<u32>) {
+        Some(Ok(offset)) => offset,
+        Some(Err(e)) => {
+            return Err(Error::with_message(
+                StatusCode::BAD_REQUEST,
+                anyhow!("invalid cursor: {e}"),
+                ErrorMessage::new("InvalidRequest", "invalid cursor"),
+            ))
+        }
+        None => 0,
+    };
+
+    let repos = stats::top(&db, limit, offset).await?;
+    let cursor = (repos.len() == limit as usize).then(|| (offset + limit).to_string());
+
+    Ok(Json(TopStorageOutput { repos, cursor }))
+}
+
+#[rustfmt::skip]
+pub fn routes(config: &AppConfig) -> Routes {
+    // AG /xrpc/_admin/relayStatus
+    // AG /xrpc/_admin/firehoseAudit
+    // AG /xrpc/_admin/listFirehoseSubscribers
+    // AG /xrpc/_admin/didDoc
+    // AG /xrpc/_admin/backlinks
+    // AG /xrpc/_admin/topStorage
+    // AP /xrpc/_admin/verifyRelay
+    // AP /xrpc/_admin/collectGarbage
+    // AG /xrpc/_admin/repoIntegrity
+    // AP /xrpc/_admin/checkRepo
+    // AG /xrpc/_admin/listAccounts
+    // AP /xrpc/_admin/updateAccountStatus
+    // AG /xrpc/_admin/listRepos
+    // AP /xrpc/_admin/compactRepo
+    // AG /xrpc/_admin/listJobs
+    // AG /xrpc/_admin/listMigrations
+    // AG /xrpc/_admin/unsupportedMethods
+    // AG /xrpc/_admin/commitLog
+    // AP /xrpc/com.atproto.admin.disableInviteCodes
+    // AP /xrpc/com.atproto.admin.updateAccountHandle
+    Routes::new()
+        .route("/_admin/relayStatus",             get(relay_status))
+        .route("/_admin/firehoseAudit",           get(firehose_audit))
+        .route("/_admin/listFirehoseSubscribers", get(list_firehose_subscribers))
+        .route("/_admin/didDoc",                  get(did_doc))
+        .route("/_admin/backlinks",               get(list_backlinks))
+        .route("/_admin/topStorage",              get(top_storage))
+        .route("/_admin/verifyRelay",             post(verify_relay))
+        .route("/_admin/collectGarbage",          post(collect_garbage))
+        .route("/_admin/repoIntegrity",           get(repo_integrity))
+        .route("/_admin/checkRepo",               post(check_repo))
+        .route("/_admin/listAccounts",            get(list_accounts))
+        .route("/_admin/updateAccountStatus",     post(update_account_status))
+        .route("/_admin/listRepos",               get(list_repos))
+        .route("/_admin/compactRepo",             post(compact_repo))
+        .route("/_admin/listJobs",                get(list_jobs))
+        .route("/_admin/listMigrations",          get(list_migrations))
+        .route("/_admin/unsupportedMethods",      get(unsupported_methods))
+        .route("/_admin/commitLog",               get(commit_log))
+        .route(concat!("/", admin::disable_invite_codes::NSID), post(disable_invite_codes))
+        .route(concat!("/", admin::update_account_handle::NSID), post(update_account_handle))
+        .map_router(|r| {
+            r.route_layer(middleware::from_fn_with_state(config.clone(), auth::require_admin))
+                .layer(DefaultBodyLimit::max(MAX_JSON_BODY))
+        })
+}
@@ -3,43 +3,45 @@ use std::collections::HashMap;
 use anyhow::{anyhow, Context};
 use atrium_api::{
     com::atproto::identity,
-    types::string::{Datetime, Handle},
+    types::{
+        string::{Datetime, Handle},
+        TryFromUnknown, TryIntoUnknown, Unknown,
+    },
 };
-use atrium_crypto::keypair::Did;
-use atrium_repo::blockstore::{AsyncBlockStoreWrite, CarStore, DAG_CBOR, SHA2_256};
+use atrium_crypto::keypair::Did as _;
 use axum::{
-    extract::{Query, State},
+    extract::{DefaultBodyLimit, Query, State},
     http::StatusCode,
     routing::{get, post},
-    Json, Router,
+    Json,
 };
 use constcat::concat;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
 use crate::{
     auth::AuthenticatedUser,
+    capabilities::Routes,
     config::AppConfig,
-    did,
-    firehose::FirehoseProducer,
-    plc::{self, PlcOperation, PlcService},
-    AppState, Client, Db, Error, Result, RotationKey, SigningKey,
+    did::{self, DidCache, DidDocument, DidSource},
+    error::ErrorMessage,
+    events::{Event, EventBus},
+    handle::{self, HandleResolver},
+    keys,
+    mail::{self, Mailer, Purpose},
+    plc::{self, PlcOperation, PlcService, SignedPlcOperation},
+    Client, Db, Error, Result, RotationKey, SigningKey,
 };
 
-async fn resolve_handle(
-    State(db): State<Db>,
-    State(client): State<Client>,
-    Query(input): Query<identity::resolve_handle::ParametersData>,
-) -> Result<Json<identity::resolve_handle::Output>> {
-    let handle = input.handle.as_str();
-    if let Ok(did) = sqlx::query_scalar!(r#"SELECT did FROM handles WHERE handle = ?"#, handle)
-        .fetch_one(&db)
-        .await
-    {
-        let did = atrium_api::types::string::Did::new(did).unwrap();
-        return Ok(Json(identity::resolve_handle::OutputData { did }.into()));
-    }
+use super::MAX_JSON_BODY;
 
+/// Resolve a handle that is not hosted on this PDS.
+pub(super) async fn resolve_handle_remote(
+    client: &Client,
+    handle: &str,
+) -> anyhow::Result<identity::resolve_handle::Output> {
     // HACK: Query bsky to see if they have this handle cached.
-    let r = client
+    client
         .get(format!(
             "https://api.bsky.app/xrpc/com.atproto.identity.resolveHandle?handle={handle}"
         ))
@@ -48,141 +50,1071 @@ async fn resolve_handle(
         .context("failed to query upstream server")?
         .json()
         .await
-        .context("failed to decode response as JSON")?;
+        .context("failed to decode response as JSON")
+}
 
-    Ok(Json(r))
+#[derive(Deserialize, Debug, Clone)]
+struct ResolveHandleInput {
+    /// Validated by the handler, to report invalid handles as `InvalidRequest`.
+    handle: String,
 }
 
-async fn request_plc_operation_signature(user: AuthenticatedUser) -> Result<()> {
-    todo!()
+async fn resolve_handle(
+    State(db): State<Db>,
+    State(client): State<Client>,
+    State(resolver): State<HandleResolver>,
+    Query(input): Query<ResolveHandleInput>,
+) -> Result<Json<identity::resolve_handle::Output>> {
+    let handle = handle::normalize(&input.handle).map_err(|e| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            e.context(format!("invalid handle {:?}", input.handle)),
+            ErrorMessage::new("InvalidRequest", "Invalid handle"),
+        )
+    })?;
+    let did = lookup_handle(&db, &client, &resolver, &handle)
+        .await?
+        .ok_or_else(|| handle_not_found(&handle))?;
+
+    let did = atrium_api::types::string::Did::new(did).map_err(|e| anyhow!("invalid DID: {e}"))?;
+    Ok(Json(identity::resolve_handle::OutputData { did }.into()))
 }
 
-async fn sign_plc_operation(
-    user: AuthenticatedUser,
-    State(skey): State<SigningKey>,
-    State(rkey): State<RotationKey>,
+/// Resolve a normalized handle to the DID it claims. Our own accounts need no resolution; other
+/// handles go through the resolver (and its cache).
+async fn lookup_handle(
+    db: &Db,
+    client: &Client,
+    resolver: &HandleResolver,
+    handle: &str,
+) -> Result<Option<String>> {
+    let did: Option<String> = sqlx::query_scalar(r#"SELECT did FROM handles WHERE handle = ?"#)
+        .bind(handle)
+        .fetch_optional(db)
+        .await
+        .context("failed to query handle")?;
+
+    match did {
+        Some(did) => Ok(Some(did)),
+        None => Ok(resolver.resolve(client, handle).await),
+    }
+}
+
+fn handle_not_found(handle: &str) -> Error {
+    Error::with_message(
+        StatusCode::BAD_REQUEST,
+        anyhow!("failed to resolve handle {handle}"),
+        ErrorMessage::new("HandleNotFound", "Unable to resolve handle"),
+    )
+}
+
+/// Look up the DID of a hosted account by its handle or DID.
+async fn find_account(db: &Db, repo: &str) -> anyhow::Result<Option<String>> {
+    let q = if repo.starts_with("did:") {
+        sqlx::query_scalar(r#"SELECT did FROM accounts WHERE did = ?"#).bind(repo.to_string())
+    } else {
+        sqlx::query_scalar(r#"SELECT did FROM handles WHERE handle = ?"#).bind(repo.to_lowercase())
+    };
+
+    q.fetch_optional(db)
+        .await
+        .context("failed to query account")
+}
+
+/// Resolve a `repo` parameter, which may be either a handle or a DID, to the DID of an account
+/// hosted on this PDS.
+///
+/// Handles are first looked up among local accounts, and then through the identity resolver.
+pub(super) async fn parse_repo_param(
+    db: &Db,
+    client: &Client,
+    repo: &str,
+) -> Result<atrium_api::types::string::Did> {
+    let mut did = find_account(db, repo).await?;
+
+    if did.is_none() && !repo.starts_with("did:") {
+        // The handle may be stale locally; see if it resolves to an account hosted here.
+        if let Ok(r) = resolve_handle_remote(client, repo).await {
+            did = find_account(db, r.did.as_str()).await?;
+        }
+    }
+
+    match did {
+        Some(did) => Ok(atrium_api::types::string::Did::new(did).unwrap()),
+        None => Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("could not find repo {repo}"),
+            ErrorMessage::new("RepoNotFound", format!("Could not find repo: {repo}")),
+        )),
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ResolveDidInput {
+    did: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ResolveDidOutput {
+    did_doc: DidDocument,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct IdentityInput {
+    /// A handle or DID.
+    identifier: String,
+}
+
+/// An identity, with its handle verified in both directions
+/// (`com.atproto.identity.defs#identityInfo`).
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct VerifiedIdentity {
+    did: String,
+    /// The handle, or `handle.invalid` if the handle the DID document claims doesn't resolve back
+    /// to the DID.
+    handle: String,
+    did_doc: DidDocument,
+}
+
+/// Resolve a DID document through the cache, reporting any failure as `DidNotFound`. If `refresh`
+/// is set, the document is re-fetched from its source.
+async fn resolve_did_doc(
+    client: &Client,
+    config: &AppConfig,
+    db: &Db,
+    cache: &DidCache,
+    did: &str,
+    refresh: bool,
+) -> Result<DidDocument> {
+    let not_found = |e: anyhow::Error| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            e.context(format!("failed to resolve {did}")),
+            ErrorMessage::new("DidNotFound", format!("Unable to resolve DID: {did}")),
+        )
+    };
+
+    let parsed = atrium_api::types::string::Did::new(did.to_string())
+        .map_err(|e| not_found(anyhow!("invalid DID: {e}")))?;
+    if refresh {
+        cache.invalidate(did);
+    }
+
+    let d = cache
+        .resolve(client, config, db, parsed, refresh)
+        .await
+        .map_err(not_found)?;
+    Ok(d.doc)
+}
+
+/// Resolve an identity by handle or DID, verifying its handle in both directions: the handle must
+/// resolve to the DID, and the DID document must claim the handle. If `refresh` is set, everything
+/// is re-resolved rather than served from the caches.
+///
+/// Starting from a handle that fails verification, there's no identity to report
+/// (`HandleNotFound`); starting from a DID, its handle is reported as `handle.invalid`.
+async fn verified_identity(
+    client: &Client,
+    config: &AppConfig,
+    db: &Db,
+    cache: &DidCache,
+    resolver: &HandleResolver,
+    identifier: &str,
+    refresh: bool,
+) -> Result<VerifiedIdentity> {
+    let lookup = |handle: String| async move {
+        if refresh {
+            resolver.invalidate(&handle);
+        }
+        lookup_handle(db, client, resolver, &handle).await
+    };
+
+    if identifier.starts_with("did:") {
+        let doc = resolve_did_doc(client, config, db, cache, identifier, refresh).await?;
+        let handle = match doc.handle().and_then(|h| handle::normalize(&h).ok()) {
+            Some(handle) if lookup(handle.clone()).await?.as_deref() == Some(identifier) => handle,
+            _ => "handle.invalid".to_string(),
+        };
+
+        return Ok(VerifiedIdentity {
+            did: identifier.to_string(),
+            handle,
+            did_doc: doc,
+        });
+    }
+
+    let handle = handle::normalize(identifier).map_err(|e| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            e.context(format!("invalid identifier {identifier:?}")),
+            ErrorMessage::new("InvalidRequest", "Invalid handle or DID"),
+        )
+    })?;
+    let did = lookup(handle.clone())
+        .await?
+        .ok_or_else(|| handle_not_found(&handle))?;
+    let doc = resolve_did_doc(client, config, db, cache, &did, refresh).await?;
+    if doc.handle().as_deref() != Some(handle.as_str()) {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("DID document of {did} does not claim {handle}"),
+            ErrorMessage::new("HandleNotFound", "Handle is not claimed by its DID"),
+        ));
+    }
+
+    Ok(VerifiedIdentity {
+        did,
+        handle,
+        did_doc: doc,
+    })
+}
+
+async fn resolve_did(
+    State(client): State<Client>,
     State(config): State<AppConfig>,
-    Json(input): Json<identity::sign_plc_operation::Input>,
-) -> Result<Json<identity::sign_plc_operation::Output>> {
-    todo!()
+    State(db): State<Db>,
+    State(cache): State<DidCache>,
+    Query(input): Query<ResolveDidInput>,
+) -> Result<Json<ResolveDidOutput>> {
+    let did_doc = resolve_did_doc(&client, &config, &db, &cache, &input.did, false).await?;
+    Ok(Json(ResolveDidOutput { did_doc }))
 }
 
-async fn update_handle(
-    user: AuthenticatedUser,
-    State(skey): State<SigningKey>,
-    State(rkey): State<RotationKey>,
+async fn resolve_identity(
     State(client): State<Client>,
     State(config): State<AppConfig>,
     State(db): State<Db>,
-    State(fhp): State<FirehoseProducer>,
-    Json(input): Json<identity::update_handle::Input>,
-) -> Result<()> {
-    let handle = input.handle.as_str();
-    let did_str = user.did();
-    let did = atrium_api::types::string::Did::new(user.did()).unwrap();
+    State(cache): State<DidCache>,
+    State(resolver): State<HandleResolver>,
+    Query(input): Query<IdentityInput>,
+) -> Result<Json<VerifiedIdentity>> {
+    Ok(Json(
+        verified_identity(
+            &client,
+            &config,
+            &db,
+            &cache,
+            &resolver,
+            &input.identifier,
+            false,
+        )
+        .await?,
+    ))
+}
+
+/// Re-resolve an identity, evicting it from our caches.
+async fn refresh_identity(
+    _user: AuthenticatedUser,
+    State(client): State<Client>,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(cache): State<DidCache>,
+    State(resolver): State<HandleResolver>,
+    Json(input): Json<IdentityInput>,
+) -> Result<Json<VerifiedIdentity>> {
+    Ok(Json(
+        verified_identity(
+            &client,
+            &config,
+            &db,
+            &cache,
+            &resolver,
+            &input.identifier,
+            true,
+        )
+        .await?,
+    ))
+}
+
+/// A hosted account's identity, as currently cached by this PDS.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct IdentityInfo {
+    did: String,
+    handle: Option<String>,
+    did_doc: DidDocument,
+    /// Where the DID document was obtained from.
+    source: DidSource,
+    /// Seconds since the DID document was fetched from its source.
+    cache_age: u64,
+}
+
+/// Fetch the cached identity of an account hosted on this PDS.
+pub(super) async fn cached_identity(
+    client: &Client,
+    config: &AppConfig,
+    db: &Db,
+    cache: &DidCache,
+    did: &str,
+    refresh: bool,
+) -> Result<IdentityInfo> {
+    let handle: Option<Option<String>> = sqlx::query_scalar(
+        r#"
+        SELECT h.handle FROM accounts a
+            LEFT JOIN handles h ON h.did = a.did
+            WHERE a.did = ?
+        "#,
+    )
+    .bind(did)
+    .fetch_optional(db)
+    .await
+    .context("failed to query account")?;
 
-    let existing_did = sqlx::query_scalar!(r#"SELECT did FROM handles WHERE handle = ?"#, handle)
-        .fetch_optional(&db)
+    let Some(handle) = handle else {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("{did} is not hosted here"),
+            ErrorMessage::new("DidNotFound", "DID is not hosted on this server"),
+        ));
+    };
+
+    let d = cache
+        .resolve(
+            client,
+            config,
+            db,
+            atrium_api::types::string::Did::new(did.to_string()).unwrap(),
+            refresh,
+        )
         .await
-        .context("failed to query did count")?;
+        .with_context(|| format!("failed to resolve DID document for {did}"))?;
 
-    if let Some(existing_did) = existing_did {
-        if existing_did != did_str {
-            return Err(Error::with_status(
-                StatusCode::BAD_REQUEST,
-                anyhow!("attempted to update handle to one that is already in use"),
-            ));
-        }
+    Ok(IdentityInfo {
+        did: did.to_string(),
+        handle,
+        cache_age: d.age().as_secs(),
+        source: d.source,
+        did_doc: d.doc,
+    })
+}
+
+/// Reject PLC operations for identities other than `did:plc`, i.e. `did:web` identities, whose
+/// documents are managed by their owners.
+fn require_plc(did: &str) -> Result<()> {
+    if !did.starts_with("did:plc:") {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("PLC operation requested for {did}"),
+            ErrorMessage::new(
+                "InvalidRequest",
+                format!("{did} is managed by its owner: edit the DID document instead"),
+            ),
+        ));
     }
 
-    let plc_cid = sqlx::query_scalar!(r#"SELECT plc_root FROM accounts WHERE did = ?"#, did_str)
-        .fetch_one(&db)
-        .await
-        .context("failed to fetch user PLC root")?;
+    Ok(())
+}
 
-    // Ensure the existing DID is resolvable.
-    // If not, we need to register the original handle.
-    let _did = did::resolve(&client, did.clone())
-        .await
-        .with_context(|| format!("failed to resolve DID for {did_str}"))?;
+/// The latest operation of a hosted identity, and its CID. This is fetched from the directory,
+/// which has the final say; in test mode, nothing is ever submitted, so the local log is used.
+async fn last_op(
+    config: &AppConfig,
+    db: &Db,
+    client: &Client,
+    did: &str,
+) -> Result<(String, SignedPlcOperation)> {
+    if config.test {
+        let plc_root: String = sqlx::query_scalar(r#"SELECT plc_root FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_one(db)
+            .await
+            .context("failed to fetch user PLC root")?;
+        let op = plc::read_op(&config.plc, did, &plc_root).await?;
+        return Ok((plc_root, op));
+    }
+
+    let op = plc::last_op(client, did).await?;
+    Ok((plc::op_cid(&op)?, op))
+}
+
+/// Convert an object from a request into the type it's expected to be.
+fn from_unknown<T: serde::de::DeserializeOwned>(value: Unknown, name: &str) -> Result<T> {
+    serde_json::Value::try_from_unknown(value)
+        .map_err(anyhow::Error::new)
+        .and_then(|v| serde_json::from_value(v).map_err(anyhow::Error::new))
+        .map_err(|e| {
+            Error::with_message(
+                StatusCode::BAD_REQUEST,
+                e.context(format!("invalid {name}")),
+                ErrorMessage::new("InvalidRequest", format!("invalid {name}")),
+            )
+        })
+}
 
-    let op = PlcOperation {
+/// The credentials this PDS would like an account's identity to name: its rotation key, the
+/// account's signing key, its current handle and this PDS.
+async fn recommended_credentials(
+    config: &AppConfig,
+    db: &Db,
+    skey: &SigningKey,
+    rkey: &RotationKey,
+    did: &str,
+) -> Result<PlcOperation> {
+    let handle: Option<String> = sqlx::query_scalar(
+        r#"SELECT handle FROM handles WHERE did = ? ORDER BY created_at DESC LIMIT 1"#,
+    )
+    .bind(did)
+    .fetch_optional(db)
+    .await
+    .context("failed to query handle")?;
+    let skey = keys::account_key(db, skey, did).await?;
+
+    Ok(PlcOperation {
         typ: "plc_operation".to_string(),
         rotation_keys: vec![rkey.did().to_string()],
         verification_methods: HashMap::from([("atproto".to_string(), skey.did().to_string())]),
-        also_known_as: vec![input.handle.as_str().to_string()],
+        also_known_as: handle.into_iter().map(|h| format!("at://{h}")).collect(),
         services: HashMap::from([(
             "atproto_pds".to_string(),
             PlcService::Pds {
-                endpoint: config.host_name.clone(),
+                endpoint: format!("https://{}", config.host_name),
             },
         )]),
-        prev: Some(plc_cid),
+        prev: None,
+    })
+}
+
+async fn get_recommended_did_credentials(
+    user: AuthenticatedUser,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(skey): State<SigningKey>,
+    State(rkey): State<RotationKey>,
+) -> Result<Json<identity::get_recommended_did_credentials::Output>> {
+    let op = recommended_credentials(&config, &db, &skey, &rkey, &user.did()).await?;
+
+    let unknown = |v: serde_json::Value| v.try_into_unknown().context("failed to encode");
+    Ok(Json(
+        identity::get_recommended_did_credentials::OutputData {
+            also_known_as: Some(op.also_known_as),
+            rotation_keys: Some(op.rotation_keys),
+            services: Some(unknown(serde_json::to_value(op.services).unwrap())?),
+            verification_methods: Some(unknown(
+                serde_json::to_value(op.verification_methods).unwrap(),
+            )?),
+        }
+        .into(),
+    ))
+}
+
+async fn request_plc_operation_signature(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    State(mailer): State<Mailer>,
+) -> Result<()> {
+    user.require_full()?;
+    let did = user.did();
+    require_plc(&did)?;
+
+    let email: String = sqlx::query_scalar(r#"SELECT email FROM accounts WHERE did = ?"#)
+        .bind(&did)
+        .fetch_one(&db)
+        .await
+        .context("failed to query account email")?;
+    let token = mail::create_token(&db, Purpose::PlcOperation, &did).await?;
+
+    mailer
+        .send(
+            &email,
+            "Identity update request",
+            &format!(
+                "To confirm an update to the identity of your account ({did}), such as moving it \
+                 to another server, enter the following code:\n\n\
+                 {token}\n\n\
+                 The code expires in {} minutes. If you didn't request this, change your password.",
+                mail::TOKEN_TTL / 60
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Build the operation following `prev` (whose CID is `prev_cid`), with the requested changes.
+/// Anything not changed carries over.
+fn next_op(
+    prev: SignedPlcOperation,
+    prev_cid: String,
+    changes: identity::sign_plc_operation::InputData,
+) -> Result<PlcOperation> {
+    Ok(PlcOperation {
+        typ: "plc_operation".to_string(),
+        rotation_keys: changes.rotation_keys.unwrap_or(prev.rotation_keys),
+        verification_methods: match changes.verification_methods {
+            Some(v) => from_unknown(v, "verificationMethods")?,
+            None => prev.verification_methods,
+        },
+        also_known_as: changes.also_known_as.unwrap_or(prev.also_known_as),
+        services: match changes.services {
+            Some(v) => from_unknown(v, "services")?,
+            None => prev.services,
+        },
+        prev: Some(prev_cid),
+    })
+}
+
+/// Sign an update to an account's identity with our rotation key, given a token from
+/// `requestPlcOperationSignature`. The operation is returned, not submitted: it's typically
+/// submitted by the PDS the account is moving to.
+async fn sign_plc_operation(
+    user: AuthenticatedUser,
+    State(rkey): State<RotationKey>,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(client): State<Client>,
+    Json(input): Json<identity::sign_plc_operation::Input>,
+) -> Result<Json<identity::sign_plc_operation::Output>> {
+    user.require_full()?;
+    let did = user.did();
+    require_plc(&did)?;
+
+    let input = input.data;
+    let Some(token) = input.token.as_deref() else {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("no token provided"),
+            ErrorMessage::new("InvalidRequest", "email confirmation token required"),
+        ));
     };
+    mail::consume_token(&db, Purpose::PlcOperation, &did, token).await?;
 
+    let (prev_cid, prev) = last_op(&config, &db, &client, &did).await?;
+    let op = next_op(prev, prev_cid, input)?;
     let op = plc::sign_op(&rkey, op)
         .await
         .context("failed to sign plc op")?;
 
+    Ok(Json(
+        identity::sign_plc_operation::OutputData {
+            operation: serde_json::to_value(&op)
+                .context("failed to encode operation")?
+                .try_into_unknown()
+                .context("failed to encode operation")?,
+        }
+        .into(),
+    ))
+}
+
+/// Check that an operation submitted by an account keeps its identity usable from here: it must
+/// name our rotation key, the account's signing key, and this PDS.
+fn check_submitted_op(
+    config: &AppConfig,
+    rkey: &str,
+    skey: &str,
+    op: &SignedPlcOperation,
+) -> Result<()> {
+    let pds = match op.services.get("atproto_pds") {
+        Some(PlcService::Pds { endpoint }) => Some(endpoint.as_str()),
+        None => None,
+    };
+
+    let problem = if !op.rotation_keys.iter().any(|k| k == rkey) {
+        Some("the operation must include this server's rotation key")
+    } else if op.verification_methods.get("atproto").map(String::as_str) != Some(skey) {
+        Some("the operation must use the account's signing key on this server")
+    } else if pds != Some(format!("https://{}", config.host_name).as_str()) {
+        Some("the operation must name this server as the account's PDS")
+    } else {
+        None
+    };
+
+    match problem {
+        Some(msg) => Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("rejected PLC operation: {msg}"),
+            ErrorMessage::new("InvalidRequest", msg),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Submit a signed update to an account's identity (e.g. one signed by the PDS it's moving here
+/// from) to the directory, and refresh our copy of its DID document.
+async fn submit_plc_operation(
+    user: AuthenticatedUser,
+    State(skey): State<SigningKey>,
+    State(rkey): State<RotationKey>,
+    State(client): State<Client>,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(events): State<EventBus>,
+    State(cache): State<DidCache>,
+    Json(input): Json<identity::submit_plc_operation::Input>,
+) -> Result<()> {
+    user.require_full()?;
+    let did = user.did();
+    require_plc(&did)?;
+
+    let op: SignedPlcOperation = from_unknown(input.data.operation, "operation")?;
+    let skey = keys::account_key(&db, &skey, &did).await?;
+    check_submitted_op(&config, &rkey.did(), &skey.did(), &op)?;
+
     if !config.test {
-        plc::submit(&client, did.as_str(), &op)
+        plc::submit(&client, &did, &op)
             .await
             .context("failed to submit PLC operation")?;
     }
 
-    // FIXME: Properly abstract these implementation details.
-    let did_hash = did_str.strip_prefix("did:plc:").unwrap();
-    let doc = tokio::fs::File::options()
-        .read(true)
-        .write(true)
-        .open(config.plc.path.join(format!("{}.car", did_hash)))
+    // Keep the local log up to date, if we keep one for this identity.
+    let plc_root: String = sqlx::query_scalar(r#"SELECT plc_root FROM accounts WHERE did = ?"#)
+        .bind(&did)
+        .fetch_one(&db)
+        .await
+        .context("failed to fetch user PLC root")?;
+    if !plc_root.is_empty() {
+        let plc_root = plc::append_op(&config.plc, &did, &op).await?;
+        sqlx::query(r#"UPDATE accounts SET plc_root = ? WHERE did = ?"#)
+            .bind(&plc_root)
+            .bind(&did)
+            .execute(&db)
+            .await
+            .context("failed to update account PLC root")?;
+    }
+
+    cache.invalidate(&did);
+    let doc = cache
+        .resolve(
+            &client,
+            &config,
+            &db,
+            atrium_api::types::string::Did::new(did.clone()).unwrap(),
+            true,
+        )
         .await
-        .context("failed to open did doc")?;
+        .inspect_err(|e| warn!("failed to refresh DID document of {did}: {e:?}"))
+        .ok();
 
-    let mut plc_doc = CarStore::open(doc)
+    let handle = doc
+        .and_then(|d| d.doc.handle())
+        .and_then(|h| Handle::new(h).ok());
+    events
+        .publish(Event::Identity(
+            atrium_api::com::atproto::sync::subscribe_repos::IdentityData {
+                did: atrium_api::types::string::Did::new(did).unwrap(),
+                handle,
+                seq: 0, // Filled by firehose later.
+                time: Datetime::now(),
+            },
+        ))
+        .await;
+
+    Ok(())
+}
+
+/// List the handles of a hosted account (normally at most one).
+pub(super) async fn handles_of(db: &Db, did: &str) -> anyhow::Result<Vec<String>> {
+    sqlx::query_scalar(r#"SELECT handle FROM handles WHERE did = ?"#)
+        .bind(did)
+        .fetch_all(db)
         .await
-        .context("failed to open did carstore")?;
+        .context("failed to query handles")
+}
+
+/// Announce the new handle of a hosted account, after [`change_handle`]: drop the cached
+/// resolutions of its identity and of its old and new handles, and broadcast an identity event.
+pub(super) async fn announce_handle(
+    cache: &DidCache,
+    resolver: &HandleResolver,
+    events: &EventBus,
+    did: &str,
+    old: &[String],
+    handle: &str,
+) {
+    let handle = handle.to_lowercase();
+    cache.invalidate(did);
+    for h in old.iter().chain([&handle]) {
+        resolver.invalidate(h);
+    }
+
+    // Broadcast the identity event now that the new identity is resolvable on the public directory.
+    events
+        .publish(Event::Identity(
+            atrium_api::com::atproto::sync::subscribe_repos::IdentityData {
+                did: atrium_api::types::string::Did::new(did.to_string()).unwrap(),
+                handle: Handle::new(handle).ok(),
+                seq: 0, // Filled by firehose later.
+                time: Datetime::now(),
+            },
+        ))
+        .await;
+}
+
+/// Change the handle of a hosted account, replacing its old handle.
+///
+/// The handle must be valid (see [`handle::validate`]), and either be under one of our domains, or
+/// already resolve to the account. For a `did:plc` identity, the change is submitted to the PLC
+/// directory before it is committed locally, and rolled back if the directory rejects it (in test
+/// mode, nothing is submitted). The change must then be announced (see [`announce_handle`]).
+pub(super) async fn change_handle(
+    config: &AppConfig,
+    db: &Db,
+    client: &Client,
+    resolver: &HandleResolver,
+    rkey: &RotationKey,
+    did: &str,
+    handle: &str,
+) -> Result<()> {
+    let (handle, local) = handle::validate(config, handle)?;
+    if !local && resolver.resolve(client, &handle).await.as_deref() != Some(did) {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("external handle {handle} does not resolve to {did}"),
+            ErrorMessage::new("InvalidRequest", "External handle did not resolve to DID"),
+        ));
+    }
 
-    let op_bytes = serde_ipld_dagcbor::to_vec(&op).context("failed to encode plc op")?;
-    let plc_cid = plc_doc
-        .write_block(DAG_CBOR, SHA2_256, &op_bytes)
+    // A did:web identity is managed by its owner, so the handle can only follow their document.
+    if did.starts_with("did:web:") {
+        let doc = did::resolve(
+            client,
+            atrium_api::types::string::Did::new(did.to_string()).unwrap(),
+        )
         .await
-        .context("failed to write genesis commit")?;
+        .context("failed to resolve DID document")?;
+        if !doc.also_known_as.contains(&format!("at://{handle}")) {
+            return Err(Error::with_message(
+                StatusCode::BAD_REQUEST,
+                anyhow!("DID document of {did} does not claim {handle}"),
+                ErrorMessage::new(
+                    "InvalidRequest",
+                    format!(
+                        "{did} is managed by its owner: add at://{handle} to alsoKnownAs in the \
+                         DID document, then try again"
+                    ),
+                ),
+            ));
+        }
+    }
+
+    let taken = || {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("handle {handle} is already taken"),
+            ErrorMessage::new("HandleNotAvailable", "handle already taken"),
+        )
+    };
+
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
 
-    let cid_str = plc_cid.to_string();
+    let existing: Option<String> =
+        sqlx::query_scalar(r#"SELECT did FROM handles WHERE handle = ?"#)
+            .bind(&handle)
+            .fetch_optional(&mut *tx)
+            .await
+            .context("failed to query handle")?;
+    if existing.is_some_and(|d| d != did) {
+        return Err(taken());
+    }
 
-    sqlx::query!(
-        r#"UPDATE accounts SET plc_root = ? WHERE did = ?"#,
-        cid_str,
-        did_str
+    sqlx::query(r#"DELETE FROM handles WHERE did = ?"#)
+        .bind(did)
+        .execute(&mut *tx)
+        .await
+        .context("failed to free old handle")?;
+    match sqlx::query(
+        r#"INSERT INTO handles (did, handle, created_at) VALUES (?, ?, datetime('now'))"#,
     )
-    .execute(&db)
+    .bind(did)
+    .bind(&handle)
+    .execute(&mut *tx)
     .await
-    .context("failed to update account PLC root")?;
+    {
+        Ok(_) => {}
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => return Err(taken()),
+        Err(e) => {
+            return Err(anyhow::Error::new(e)
+                .context("failed to insert handle")
+                .into())
+        }
+    }
 
-    // Broadcast the identity event now that the new identity is resolvable on the public directory.
-    fhp.identity(
-        atrium_api::com::atproto::sync::subscribe_repos::IdentityData {
-            did: did.clone(),
-            handle: Some(Handle::new(handle.to_string()).unwrap()),
-            seq: 0, // Filled by firehose later.
-            time: Datetime::now(),
-        },
+    if did.starts_with("did:plc:") {
+        let plc_root: String = sqlx::query_scalar(r#"SELECT plc_root FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_one(&mut *tx)
+            .await
+            .context("failed to fetch user PLC root")?;
+        let prev = plc::read_op(&config.plc, did, &plc_root).await?;
+
+        // Only the handle changes; everything else carries over from the previous operation.
+        let op = PlcOperation {
+            typ: "plc_operation".to_string(),
+            rotation_keys: prev.rotation_keys,
+            verification_methods: prev.verification_methods,
+            also_known_as: vec![format!("at://{handle}")],
+            services: prev.services,
+            prev: Some(plc_root),
+        };
+        let op = plc::sign_op(rkey, op)
+            .await
+            .context("failed to sign plc op")?;
+
+        // The transaction is still open, so a rejected operation leaves nothing changed.
+        if !config.test {
+            plc::submit(client, did, &op)
+                .await
+                .context("failed to submit PLC operation")?;
+        }
+
+        let plc_root = plc::append_op(&config.plc, did, &op).await?;
+        sqlx::query(r#"UPDATE accounts SET plc_root = ? WHERE did = ?"#)
+            .bind(&plc_root)
+            .bind(did)
+            .execute(&mut *tx)
+            .await
+            .context("failed to update account PLC root")?;
+    }
+
+    tx.commit().await.context("failed to commit transaction")?;
+    Ok(())
+}
+
+async fn update_handle(
+    user: AuthenticatedUser,
+    State(rkey): State<RotationKey>,
+    State(client): State<Client>,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(events): State<EventBus>,
+    State(cache): State<DidCache>,
+    State(resolver): State<HandleResolver>,
+    Json(input): Json<identity::update_handle::Input>,
+) -> Result<()> {
+    let did = user.did();
+    let old = handles_of(&db, &did).await?;
+
+    change_handle(
+        &config,
+        &db,
+        &client,
+        &resolver,
+        &rkey,
+        &did,
+        input.handle.as_str(),
     )
-    .await;
+    .await?;
 
+    announce_handle(
+        &cache,
+        &resolver,
+        &events,
+        &did,
+        &old,
+        input.handle.as_str(),
+    )
+    .await;
     Ok(())
 }
 
 #[rustfmt::skip]
-pub fn routes() -> Router<AppState> {
+pub fn routes() -> Routes {
     // AP /xrpc/com.atproto.identity.updateHandle
     // AP /xrpc/com.atproto.identity.requestPlcOperationSignature
     // AP /xrpc/com.atproto.identity.signPlcOperation
+    // AP /xrpc/com.atproto.identity.submitPlcOperation
+    // AG /xrpc/com.atproto.identity.getRecommendedDidCredentials
     // UG /xrpc/com.atproto.identity.resolveHandle
-    Router::new()
+    // UG /xrpc/com.atproto.identity.resolveDid
+    // UG /xrpc/com.atproto.identity.resolveIdentity
+    // AP /xrpc/com.atproto.identity.refreshIdentity
+    Routes::new()
         .route(concat!("/", identity::update_handle::NSID),                   post(update_handle))
         .route(concat!("/", identity::request_plc_operation_signature::NSID), post(request_plc_operation_signature))
         .route(concat!("/", identity::sign_plc_operation::NSID),              post(sign_plc_operation))
+        .route(concat!("/", identity::submit_plc_operation::NSID),            post(submit_plc_operation))
+        .route(concat!("/", identity::get_recommended_did_credentials::NSID),  get(get_recommended_did_credentials))
         .route(concat!("/", identity::resolve_handle::NSID),                   get(resolve_handle))
+        .route("/com.atproto.identity.resolveDid",                             get(resolve_did))
+        .route("/com.atproto.identity.resolveIdentity",                        get(resolve_identity))
+        .route("/com.atproto.identity.refreshIdentity",                        post(refresh_identity))
+        .map_router(|r| r.layer(DefaultBodyLimit::max(MAX_JSON_BODY)))
+}
+
+#[cfg(test)]
+mod test {
+    use atrium_repo::blockstore::CarStore;
+    use axum::{body::to_bytes, response::IntoResponse};
+
+    use super::*;
+
+    async fn error_name(e: Error) -> String {
+        let body = to_bytes(e.into_response().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        body["error"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn identities() {
+        let dir = crate::testing::temp_dir("identity");
+        let config = crate::testing::config(&dir, serde_json::json!({}));
+
+        let db = crate::testing::memory_db().await;
+
+        // Bob's identity claims Alice's handle, and his own is stale.
+        for (name, claimed, handle) in [
+            ("alice", "alice.pds.example.com", "alice.pds.example.com"),
+            ("bob", "alice.pds.example.com", "stale.pds.example.com"),
+        ] {
+            let did = format!("did:plc:{name}");
+            let log = tokio::fs::File::create(dir.join("plc").join(format!("{name}.car")))
+                .await
+                .unwrap();
+            drop(CarStore::create(log).await.unwrap());
+            let op = SignedPlcOperation {
+                typ: "plc_operation".to_string(),
+                rotation_keys: vec![],
+                verification_methods: HashMap::new(),
+                also_known_as: vec![format!("at://{claimed}")],
+                services: HashMap::new(),
+                prev: None,
+                sig: String::new(),
+            };
+            let plc_root = plc::append_op(&config.plc, &did, &op).await.unwrap();
+
+            sqlx::query(
+                r#"
+                INSERT INTO accounts (did, email, password, root, plc_root, rev)
+                    VALUES (?, ?, '', '', ?, '')
+                "#,
+            )
+            .bind(&did)
+            .bind(format!("{name}@example.com"))
+            .bind(plc_root)
+            .execute(&db)
+            .await
+            .unwrap();
+            sqlx::query(r#"INSERT INTO handles (did, handle) VALUES (?, ?)"#)
+                .bind(&did)
+                .bind(handle)
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+        let (cache, resolver) = (DidCache::default(), HandleResolver::default());
+        let verify = |identifier: &'static str, refresh: bool| {
+            let (client, config, db, cache, resolver) = (&client, &config, &db, &cache, &resolver);
+            async move {
+                verified_identity(client, config, db, cache, resolver, identifier, refresh).await
+            }
+        };
+
+        for identifier in ["Alice.pds.example.com", "did:plc:alice"] {
+            for refresh in [false, true] {
+                let identity = verify(identifier, refresh).await.unwrap();
+                assert_eq!(identity.did, "did:plc:alice");
+                assert_eq!(identity.handle, "alice.pds.example.com");
+                assert_eq!(identity.did_doc.id.as_str(), "did:plc:alice");
+            }
+        }
+
+        // Alice's handle doesn't resolve to Bob.
+        let identity = verify("did:plc:bob", false).await.unwrap();
+        assert_eq!(identity.handle, "handle.invalid");
+
+        for (identifier, error) in [
+            ("stale.pds.example.com", "HandleNotFound"),
+            ("did:plc:nobody", "DidNotFound"),
+            ("did:nope", "DidNotFound"),
+            ("not a handle", "InvalidRequest"),
+        ] {
+            let e = verify(identifier, false).await.unwrap_err();
+            assert_eq!(error_name(e).await, error, "{identifier}");
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn repo_param() {
+        let db = crate::testing::memory_db().await;
+
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev)
+                VALUES ('did:plc:alice', 'alice@example.com', '', '', '', '');
+            INSERT INTO handles (handle, did) VALUES ('alice.example.com', 'did:plc:alice');
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        #[rustfmt::skip]
+        let cases = [
+            ("alice.example.com", Some("did:plc:alice")),
+            ("Alice.Example.com", Some("did:plc:alice")),
+            ("did:plc:alice",     Some("did:plc:alice")),
+            ("bob.example.com",   None),
+            ("did:plc:bob",       None),
+        ];
+
+        for (repo, did) in cases {
+            assert_eq!(
+                find_account(&db, repo).await.unwrap().as_deref(),
+                did,
+                "{repo}"
+            );
+        }
+    }
+
+    #[test]
+    fn plc_operations() {
+        let prev = SignedPlcOperation {
+            typ: "plc_operation".to_string(),
+            rotation_keys: vec!["did:key:rotation".to_string()],
+            verification_methods: HashMap::from([(
+                "atproto".to_string(),
+                "did:key:signing".to_string(),
+            )]),
+            also_known_as: vec!["at://alice.pds.example.com".to_string()],
+            services: HashMap::from([(
+                "atproto_pds".to_string(),
+                PlcService::Pds {
+                    endpoint: "https://pds.example.com".to_string(),
+                },
+            )]),
+            prev: None,
+            sig: String::new(),
+        };
+
+        // Only the requested changes are made.
+        let changes: identity::sign_plc_operation::InputData =
+            serde_json::from_value(serde_json::json!({
+                "services": {
+                    "atproto_pds": {
+                        "type": "AtprotoPersonalDataServer",
+                        "endpoint": "https://elsewhere.example.com",
+                    },
+                },
+            }))
+            .unwrap();
+        let op = next_op(prev.clone(), "bafyprev".to_string(), changes).unwrap();
+        assert_eq!(op.prev.as_deref(), Some("bafyprev"));
+        assert_eq!(op.rotation_keys, prev.rotation_keys);
+        assert_eq!(op.also_known_as, prev.also_known_as);
+        assert!(matches!(
+            &op.services["atproto_pds"],
+            PlcService::Pds { endpoint } if endpoint == "https://elsewhere.example.com"
+        ));
+
+        let changes: identity::sign_plc_operation::InputData =
+            serde_json::from_value(serde_json::json!({ "verificationMethods": ["nope"] })).unwrap();
+        assert!(next_op(prev.clone(), String::new(), changes).is_err());
+
+        // Submitted operations must keep the identity usable from here.
+        let config = crate::testing::config("", serde_json::json!({}));
+        check_submitted_op(&config, "did:key:rotation", "did:key:signing", &prev).unwrap();
+        assert!(check_submitted_op(&config, "did:key:other", "did:key:signing", &prev).is_err());
+        assert!(check_submitted_op(&config, "did:key:rotation", "did:key:other", &prev).is_err());
+        let mut moved = prev.clone();
+        moved.services.clear();
+        assert!(
+            check_submitted_op(&config, "did:key:rotation", "did:key:signing", &moved).is_err()
+        );
+    }
 }
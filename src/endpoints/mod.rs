@@ -1,24 +1,82 @@
-use axum::{routing::get, Json, Router};
+//! The XRPC endpoints served by this PDS.
+//!
+//! Each namespace lives in its own module, which builds its own [`Routes`] along with any
+//! middleware that applies to the whole namespace (e.g. admin authentication, or body limits).
+//! They're merged into one router by [`routes`].
+
+use axum::{
+    extract::{Query, State},
+    routing::get,
+    Json,
+};
+use serde::Deserialize;
 use serde_json::json;
 
-use crate::{AppState, Result};
+use crate::{
+    capabilities::{self, Routes},
+    config::AppConfig,
+    reindex, unsupported, Db, Result,
+};
 
+mod actor;
+mod admin;
 mod identity;
 mod repo;
 mod server;
 mod sync;
+mod temp;
+mod upload;
+
+pub use repo::MAX_APPLY_WRITES;
+pub use upload::{cleanup_uploads, upload_path, UploadLocks};
+
+/// The maximum size of the body of requests to namespaces that don't take bulk data (records,
+/// blobs or repositories), in bytes.
+const MAX_JSON_BODY: usize = 150 * 1024;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HealthInput {
+    /// Also report the progress of background work that the PDS isn't fully functional without.
+    deep: Option<String>,
+}
 
-pub async fn health() -> Result<Json<serde_json::Value>> {
-    Ok(Json(json!({
+pub async fn health(
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    Query(input): Query<HealthInput>,
+) -> Result<Json<serde_json::Value>> {
+    let mut health = json!({
         "version": "bluepds"
-    })))
+    });
+
+    if matches!(input.deep.as_deref(), Some("1" | "true")) {
+        let reindex = reindex::progress(&db, config.reindex.max_attempts).await?;
+        if reindex.remaining != 0 {
+            health["reindex"] = json!(reindex);
+        }
+    }
+
+    Ok(Json(health))
 }
 
-pub fn routes() -> Router<AppState> {
-    Router::new()
+pub fn routes(config: &AppConfig) -> Routes {
+    let routes = Routes::new()
         .route("/_health", get(health))
+        .route("/_server/capabilities", get(capabilities::capabilities))
+        .merge(actor::routes()) // app.bsky.actor (preferences)
+        .merge(admin::routes(config)) // Administrative endpoints
         .merge(identity::routes()) // com.atproto.identity
         .merge(repo::routes()) // com.atproto.repo
         .merge(server::routes()) // com.atproto.server
         .merge(sync::routes()) // com.atproto.sync
+        .merge(temp::routes()); // com.atproto.temp
+
+    let routes = if config.blob.resumable {
+        routes.merge(upload::routes()) // Resumable blob uploads
+    } else {
+        routes
+    };
+
+    // Everything else is proxied to other services, or unsupported.
+    routes.map_router(|r| r.fallback(unsupported::fallback))
 }
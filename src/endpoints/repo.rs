@@ -1,45 +1,88 @@
-use std::{collections::HashSet, str::FromStr};
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::Arc,
+    time::Instant,
+};
 
 use anyhow::{anyhow, Context};
 use atrium_api::{
     com::atproto::repo::{self, defs::CommitMetaData},
     types::{
-        string::{AtIdentifier, Nsid, Tid},
+        string::{AtIdentifier, Nsid, RecordKey, Tid},
         LimitedU32, Object, TryFromUnknown, TryIntoUnknown, Unknown,
     },
 };
-use atrium_repo::{blockstore::CarStore, Cid};
+use atrium_repo::{
+    blockstore::{AsyncBlockStoreRead, CarStore},
+    Cid, Repository,
+};
 use axum::{
     body::Body,
     extract::{Query, Request, State},
     http::{self, StatusCode},
     routing::{get, post},
-    Json, Router,
+    Json,
 };
 use constcat::concat;
 use futures::TryStreamExt;
+use ipld_core::ipld::Ipld;
 use metrics::counter;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
 
 use crate::{
+    aturi::AtUri,
     auth::AuthenticatedUser,
+    backlinks,
+    capabilities::Routes,
+    cbor::{self, Limits},
+    collections,
+    commitlog::{self, LoggedCommit},
     config::AppConfig,
-    firehose::{self, FirehoseProducer, RepoOp},
+    did::DidCache,
+    error::ErrorMessage,
+    events::{Event, EventBus},
+    firehose::{self, RepoOp},
+    import::{self, ImportError, ImportOptions},
+    integrity::RepoIntegrity,
+    keys,
     metrics::{REPO_COMMITS, REPO_OP_CREATE, REPO_OP_DELETE, REPO_OP_UPDATE},
-    storage, AppState, Db, Error, Result, SigningKey,
+    migration, nsid,
+    policy::Policies,
+    ratelimit::{self, WriteLimiter},
+    record, reindex,
+    stats::{StorageDelta, StorageStats},
+    status,
+    storage::{self, RepoLocks},
+    timing::{CommitTimer, Stage},
+    Client, Db, Error, Result, SigningKey,
 };
 
+use super::identity::parse_repo_param;
+
+/// The maximum number of operations in a single `applyWrites` call (as in the lexicon).
+pub const MAX_APPLY_WRITES: usize = 200;
+
+/// The number of records `listRecords` returns if no `limit` is specified (as in the lexicon).
+const DEFAULT_LIST_LIMIT: u8 = 50;
+
+/// The number of blobs `listMissingBlobs` returns if no `limit` is specified (as in the lexicon).
+const DEFAULT_MISSING_BLOBS_LIMIT: u16 = 500;
+
 /// IPLD CID raw binary
 const IPLD_RAW: u64 = 0x55;
 /// SHA2-256 mulithash
 const IPLD_MH_SHA2_256: u64 = 0x12;
 
-#[derive(Deserialize, Debug, Clone)]
-struct BlobRef {
-    #[serde(rename = "$link")]
-    link: String,
+/// Calculate the CID of a blob from the SHA2-256 hash of its contents.
+pub(super) fn blob_cid(hash: &[u8]) -> Cid {
+    Cid::new_v1(
+        IPLD_RAW,
+        atrium_repo::Multihash::wrap(IPLD_MH_SHA2_256, hash).unwrap(),
+    )
 }
 
 async fn swap_commit(
@@ -83,71 +126,107 @@ async fn swap_commit(
     }
 }
 
-async fn resolve_did(
-    db: &Db,
-    ident: &AtIdentifier,
-) -> anyhow::Result<(
-    atrium_api::types::string::Did,
-    atrium_api::types::string::Handle,
-)> {
-    let (handle, did) = match &ident {
-        AtIdentifier::Handle(handle) => {
-            let handle = handle.as_str();
-            let did = sqlx::query_scalar!(r#"SELECT did FROM handles WHERE handle = ?"#, handle)
-                .fetch_one(db)
-                .await
-                .context("failed to query did")?;
+/// The error for a failed compare-and-swap (`swapCommit` or `swapRecord`), reporting what the
+/// value actually was, so that the client can retry against it.
+fn invalid_swap(what: &str, current: Option<Cid>) -> Error {
+    let current = current.map_or_else(|| "null".to_string(), |c| c.to_string());
+    Error::with_message(
+        StatusCode::BAD_REQUEST,
+        anyhow!("{what} swap failed: it is at {current}"),
+        ErrorMessage::new("InvalidSwap", format!("{what} was at {current}")),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WriteKind {
+    Create,
+    Update,
+    Delete,
+}
+
+/// Conditions on a write that `applyWrites` can't express, set by `putRecord` and `deleteRecord`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Condition {
+    /// The CID the record must be at, or `Some(None)` if it must not exist (`swapRecord`).
+    swap_record: Option<Option<Cid>>,
+    /// Create the record if it doesn't exist, rather than failing to update it.
+    upsert: bool,
+}
+
+#[derive(Debug, Clone)]
+struct PlannedWrite {
+    kind: WriteKind,
+    key: String,
+    condition: Condition,
+}
+
+/// Check that each write of a batch applies to the repository as the writes before it leave it: a
+/// record can only be created where there is none, and only updated or deleted where there is
+/// one. Upserts of records that don't exist are turned into creates. This runs before anything is
+/// written, so that a batch applies in full or not at all.
+///
+/// `swapRecord` is checked against the record as of the head the batch is applied to.
+async fn check_writes(
+    repo: &mut Repository<impl AsyncBlockStoreRead>,
+    writes: &mut [PlannedWrite],
+) -> Result<()> {
+    let mut present: HashMap<String, bool> = HashMap::new();
+    for write in writes {
+        let stored = repo
+            .tree()
+            .get(&write.key)
+            .await
+            .context("failed to search MST")?;
+        if let Some(expected) = write.condition.swap_record {
+            if expected != stored {
+                return Err(invalid_swap("Record", stored));
+            }
+        }
 
-            (handle.to_string(), did)
+        let exists = present.get(&write.key).copied().unwrap_or(stored.is_some());
+        if write.kind == WriteKind::Update && write.condition.upsert && !exists {
+            write.kind = WriteKind::Create;
         }
-        AtIdentifier::Did(did) => {
-            let did = did.as_str();
-            let handle = sqlx::query_scalar!(r#"SELECT handle FROM handles WHERE did = ?"#, did)
-                .fetch_one(db)
-                .await
-                .context("failed to query did")?;
 
-            (handle, did.to_string())
+        let problem = match (write.kind, exists) {
+            (WriteKind::Create, true) => Some("Record already exists"),
+            (WriteKind::Update | WriteKind::Delete, false) => Some("Could not find record"),
+            _ => None,
+        };
+        if let Some(problem) = problem {
+            let key = &write.key;
+            return Err(Error::with_message(
+                StatusCode::BAD_REQUEST,
+                anyhow!("{problem}: {key}"),
+                ErrorMessage::new("InvalidRequest", format!("{problem}: {key}")),
+            ));
         }
-    };
 
-    Ok((
-        atrium_api::types::string::Did::new(did).unwrap(),
-        atrium_api::types::string::Handle::new(handle).unwrap(),
-    ))
+        present.insert(write.key.clone(), write.kind != WriteKind::Delete);
+    }
+
+    Ok(())
+}
+
+/// Extract the URIs a record references, for the backlink index.
+fn record_backlinks(config: &AppConfig, collection: &str, value: &Unknown) -> Vec<AtUri> {
+    serde_json::Value::try_from_unknown(value.clone())
+        .map(|v| backlinks::extract(&config.repo.backlinks, collection, &v))
+        .unwrap_or_default()
+}
+
+fn repo_ident(ident: &AtIdentifier) -> &str {
+    match ident {
+        AtIdentifier::Did(did) => did.as_str(),
+        AtIdentifier::Handle(handle) => handle.as_str(),
+    }
 }
 
 fn scan_blobs(o: &Unknown) -> anyhow::Result<Vec<Cid>> {
-    // { "$type": "blob", "ref": { "$link": "bafyrei..." } }
     let v = serde_json::Value::try_from_unknown(o.clone())
         .context("failed to convert unknown into json")?;
 
-    let mut cids = Vec::new();
-    let mut stack = vec![v];
-    while let Some(v) = stack.pop() {
-        match v {
-            serde_json::Value::Null => (),
-            serde_json::Value::Bool(_) => (),
-            serde_json::Value::Number(_) => (),
-            serde_json::Value::String(_) => (),
-            serde_json::Value::Array(values) => stack.extend(values.into_iter()),
-            serde_json::Value::Object(map) => {
-                let (ty, rf) = (map.get("$type"), map.get("ref"));
-
-                if let (Some(ty), Some(rf)) = (ty, rf) {
-                    if ty == &serde_json::Value::String("blob".to_string()) {
-                        if let Ok(rf) = serde_json::from_value::<BlobRef>(rf.clone()) {
-                            cids.push(Cid::from_str(&rf.link).context("failed to convert cid")?);
-                        }
-                    }
-                }
-
-                stack.extend(map.values().cloned());
-            }
-        }
-    }
-
-    Ok(cids)
+    record::blob_refs(&v).context("failed to convert cid")
 }
 
 #[test]
@@ -176,16 +255,56 @@ async fn apply_writes(
     State(skey): State<SigningKey>,
     State(config): State<AppConfig>,
     State(db): State<Db>,
-    State(fhp): State<FirehoseProducer>,
+    State(events): State<EventBus>,
+    State(policies): State<Policies>,
+    State(limiter): State<WriteLimiter>,
+    State(client): State<Client>,
+    State(stats): State<StorageStats>,
+    State(integrity): State<RepoIntegrity>,
+    State(locks): State<RepoLocks>,
     Json(input): Json<repo::apply_writes::Input>,
+) -> Result<Json<repo::apply_writes::Output>> {
+    write_records(
+        user,
+        State(skey),
+        State(config),
+        State(db),
+        State(events),
+        State(policies),
+        State(limiter),
+        State(client),
+        State(stats),
+        State(integrity),
+        State(locks),
+        input,
+        Vec::new(),
+    )
+    .await
+}
+
+/// Apply a batch of writes, each subject to the condition at the same index, if any.
+async fn write_records(
+    user: AuthenticatedUser,
+    State(skey): State<SigningKey>,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(events): State<EventBus>,
+    State(policies): State<Policies>,
+    State(limiter): State<WriteLimiter>,
+    State(client): State<Client>,
+    State(stats): State<StorageStats>,
+    State(integrity): State<RepoIntegrity>,
+    State(locks): State<RepoLocks>,
+    input: repo::apply_writes::Input,
+    conditions: Vec<Condition>,
 ) -> Result<Json<repo::apply_writes::Output>> {
     use atrium_api::com::atproto::repo::apply_writes::{self, InputWritesItem, OutputResultsItem};
 
+    let mut timer = CommitTimer::new();
+
     // TODO: `input.validate`
 
-    let (target_did, _) = resolve_did(&db, &input.repo)
-        .await
-        .context("failed to resolve did")?;
+    let target_did = parse_repo_param(&db, &client, repo_ident(&input.repo)).await?;
 
     // Ensure that we are updating the correct repository.
     if target_did.as_str() != user.did() {
@@ -195,38 +314,172 @@ async fn apply_writes(
         ));
     }
 
-    let mut repo = storage::open_repo_db(&config.repo, &db, user.did())
-        .await
-        .context("failed to open user repo")?;
+    let skey = keys::account_key(&db, &skey, target_did.as_str()).await?;
+
+    if input.writes.len() > MAX_APPLY_WRITES {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("too many writes: {}", input.writes.len()),
+            ErrorMessage::new(
+                "InvalidRequest",
+                format!("Too many writes. Max: {MAX_APPLY_WRITES}"),
+            ),
+        ));
+    }
+
+    // Reject writes into malformed or reserved collections.
+    let mut prepared: Vec<(String, Option<Unknown>)> = Vec::with_capacity(input.writes.len());
+    for write in &input.writes {
+        let collection = match write {
+            InputWritesItem::Create(w) => w.collection.as_str(),
+            InputWritesItem::Update(w) => w.collection.as_str(),
+            InputWritesItem::Delete(w) => w.collection.as_str(),
+        };
+
+        nsid::validate(collection)
+            .and_then(|_| nsid::check_reserved(collection, &config.repo.reserved_collections))
+            .map_err(|e| {
+                Error::with_message(
+                    StatusCode::BAD_REQUEST,
+                    anyhow!("invalid collection {collection}: {e}"),
+                    ErrorMessage::new(
+                        "InvalidRequest",
+                        format!("invalid collection {collection:?}: {e}"),
+                    ),
+                )
+            })?;
+
+        // Assign record keys to new records up front, so that policies can see them.
+        let (rkey, value) = match write {
+            InputWritesItem::Create(w) => (
+                w.rkey
+                    .as_deref()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| Tid::now(LimitedU32::MIN).as_str().to_string()),
+                Some(&w.value),
+            ),
+            InputWritesItem::Update(w) => (w.rkey.as_str().to_string(), Some(&w.value)),
+            InputWritesItem::Delete(w) => (w.rkey.as_str().to_string(), None),
+        };
+
+        // Reject records that can't be canonically encoded as DAG-CBOR, or that operator policies
+        // reject. Records that policies annotate are written as annotated.
+        let annotated = match value {
+            Some(value) => {
+                let json = serde_json::to_value(value).context("failed to encode record")?;
+                record::validate(&json)?;
+
+                let mut checked = json.clone();
+                policies
+                    .apply(&user.did(), collection, &rkey, &mut checked)
+                    .await?;
+                if checked != json {
+                    Some(serde_json::from_value(checked).context("failed to decode record")?)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+
+        prepared.push((rkey, annotated));
+    }
+
+    // Hold the repository's write lock until its head is moved, so that the batch is checked
+    // against the head it's applied to, and no other write can slip in between.
+    let _lock = timer.time(Stage::LockWait, locks.lock(&user.did())).await;
+
+    let orig_size = storage::repo_size(&config.repo, &user.did()).await?;
+    let mut repo = integrity.open(user.did()).await?;
     let orig_cid = repo.root();
     let orig_rev = repo.commit().rev();
 
+    if let Some(swap) = &input.swap_commit {
+        if swap.as_ref() != &orig_cid {
+            return Err(invalid_swap("Commit", Some(orig_cid)));
+        }
+    }
+
+    let mut planned = input
+        .writes
+        .iter()
+        .zip(&prepared)
+        .enumerate()
+        .map(|(i, (write, (rkey, _)))| {
+            let (kind, collection) = match write {
+                InputWritesItem::Create(w) => (WriteKind::Create, w.collection.as_str()),
+                InputWritesItem::Update(w) => (WriteKind::Update, w.collection.as_str()),
+                InputWritesItem::Delete(w) => (WriteKind::Delete, w.collection.as_str()),
+            };
+            PlannedWrite {
+                kind,
+                key: format!("{collection}/{rkey}"),
+                condition: conditions.get(i).copied().unwrap_or_default(),
+            }
+        })
+        .collect::<Vec<_>>();
+    check_writes(&mut repo, &mut planned).await?;
+
+    // Charge the account for each individual write (as it will be applied) once the batch is known
+    // to apply, and before anything is written. A batch that's rejected costs nothing.
+    let points = planned
+        .iter()
+        .map(|w| match w.kind {
+            WriteKind::Create => ratelimit::POINTS_CREATE,
+            WriteKind::Update => ratelimit::POINTS_UPDATE,
+            WriteKind::Delete => ratelimit::POINTS_DELETE,
+        })
+        .sum();
+    limiter.consume(&user.did(), points)?;
+
+    // Upserts of records that don't exist are applied as creates.
+    let writes = input
+        .writes
+        .iter()
+        .zip(&planned)
+        .map(|(write, planned)| match write {
+            InputWritesItem::Update(w) if planned.kind == WriteKind::Create => {
+                InputWritesItem::Create(Box::new(
+                    apply_writes::CreateData {
+                        collection: w.collection.clone(),
+                        rkey: Some(w.rkey.clone()),
+                        value: w.value.clone(),
+                    }
+                    .into(),
+                ))
+            }
+            write => write.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    // Each write is applied as a commit of its own, but only the last is ever referred to: the head
+    // moves once, from the original commit to one that includes the whole batch.
     let mut blobs = vec![];
+    let mut links = vec![];
     let mut res = vec![];
     let mut ops = vec![];
     let mut keys = vec![];
-    for write in &input.writes {
-        let (builder, key) = match write {
+    for (write, (rkey, annotated)) in writes.iter().zip(&prepared) {
+        let (builder, key, cid) = match write {
             InputWritesItem::Create(object) => {
-                let key = format!(
-                    "{}/{}",
-                    object.collection.as_str(),
-                    object
-                        .rkey
-                        .as_deref()
-                        .unwrap_or(Tid::now(LimitedU32::MIN).as_str())
-                );
-                let uri = format!("at://{}/{}", user.did(), key);
-
-                let (b, c) = repo
-                    .add_raw(&key, &object.value)
+                let value = annotated.as_ref().unwrap_or(&object.value);
+                let key = format!("{}/{}", object.collection.as_str(), rkey);
+                let uri = AtUri::record(&user.did(), object.collection.as_str(), rkey).to_string();
+
+                let (b, c) = timer
+                    .time(Stage::Mst, repo.add_raw(&key, value))
                     .await
                     .context("failed to add record")?;
 
-                if let Ok(new_blobs) = scan_blobs(&object.value) {
+                if let Ok(new_blobs) = scan_blobs(value) {
                     blobs.extend(new_blobs.into_iter().map(|b| (key.to_string(), b)));
                 }
 
+                links.push((
+                    key.clone(),
+                    record_backlinks(&config, object.collection.as_str(), value),
+                ));
+
                 ops.push(RepoOp::Create {
                     cid: c,
                     path: key.clone(),
@@ -236,33 +489,43 @@ async fn apply_writes(
                     apply_writes::CreateResultData {
                         cid: atrium_api::types::string::Cid::new(c),
                         uri,
-                        validation_status: None,
+                        validation_status: Some("unknown".to_string()),
                     }
                     .into(),
                 )));
 
-                (b, key)
+                (b, key, Some(c))
             }
             InputWritesItem::Update(object) => {
+                let value = annotated.as_ref().unwrap_or(&object.value);
                 let key = format!("{}/{}", object.collection.as_str(), object.rkey.as_str());
-                let uri = format!("at://{}/{}", user.did(), key);
+                let uri = AtUri::record(
+                    &user.did(),
+                    object.collection.as_str(),
+                    object.rkey.as_str(),
+                )
+                .to_string();
 
-                let prev = repo
-                    .tree()
-                    .get(&key)
+                let prev = timer
+                    .time(Stage::Mst, async { repo.tree().get(&key).await })
                     .await
                     .context("failed to search MST")?
                     .context("previous record does not exist")?;
 
-                let (b, c) = repo
-                    .update_raw(&key, &object.value)
+                let (b, c) = timer
+                    .time(Stage::Mst, repo.update_raw(&key, value))
                     .await
                     .context("failed to add record")?;
 
-                if let Ok(new_blobs) = scan_blobs(&object.value) {
+                if let Ok(new_blobs) = scan_blobs(value) {
                     blobs.extend(new_blobs.into_iter().map(|b| (key.to_string(), b)));
                 }
 
+                links.push((
+                    key.clone(),
+                    record_backlinks(&config, object.collection.as_str(), value),
+                ));
+
                 ops.push(RepoOp::Update {
                     cid: c,
                     path: key.clone(),
@@ -273,19 +536,18 @@ async fn apply_writes(
                     apply_writes::UpdateResultData {
                         cid: atrium_api::types::string::Cid::new(c),
                         uri,
-                        validation_status: None,
+                        validation_status: Some("unknown".to_string()),
                     }
                     .into(),
                 )));
 
-                (b, key)
+                (b, key, Some(c))
             }
             InputWritesItem::Delete(object) => {
                 let key = format!("{}/{}", object.collection.as_str(), object.rkey.as_str());
 
-                let prev = repo
-                    .tree()
-                    .get(&key)
+                let prev = timer
+                    .time(Stage::Mst, async { repo.tree().get(&key).await })
                     .await
                     .context("failed to search MST")?
                     .context("previous record does not exist")?;
@@ -299,69 +561,91 @@ async fn apply_writes(
                     apply_writes::DeleteResultData {}.into(),
                 )));
 
-                let b = repo
-                    .delete_raw(&key)
+                let b = timer
+                    .time(Stage::Mst, repo.delete_raw(&key))
                     .await
                     .context("failed to add record")?;
 
-                (b, key)
+                (b, key, None)
             }
         };
 
-        let sig = skey
-            .sign(&builder.bytes())
+        let sig = timer
+            .time(Stage::Sign, async { skey.sign(&builder.bytes()) })
+            .await
             .context("failed to sign commit")?;
 
-        builder
-            .finalize(sig)
+        timer
+            .time(Stage::BlockWrite, builder.finalize(sig))
             .await
             .context("failed to write signed commit")?;
 
+        if let Some(cid) = cid.filter(|_| config.repo.verify_cids) {
+            let record: Ipld = repo
+                .get_raw(&key)
+                .await
+                .context("failed to read back record")?
+                .context("record missing after write")?;
+            record::verify_cid(&cid, &record).map_err(|e| {
+                Error::with_message(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    e.context(format!("record {key} failed verification")),
+                    ErrorMessage::new("InternalServerError", "record failed CID verification"),
+                )
+            })?;
+        }
+
         keys.push(key);
     }
 
     // Construct a firehose record.
     let mut mem = Vec::new();
-    let mut store = CarStore::create_with_roots(std::io::Cursor::new(&mut mem), [repo.root()])
-        .await
-        .context("failed to create temp store")?;
+    timer
+        .time(Stage::BlockWrite, async {
+            let mut store =
+                CarStore::create_with_roots(std::io::Cursor::new(&mut mem), [repo.root()])
+                    .await
+                    .context("failed to create temp store")?;
 
-    // Extract the records out of the user's repository.
-    for key in keys {
-        repo.extract_raw_into(&key, &mut store)
-            .await
-            .context("failed to extract key")?;
-    }
+            // Extract the records out of the user's repository.
+            for key in keys {
+                repo.extract_raw_into(&key, &mut store)
+                    .await
+                    .context("failed to extract key")?;
+            }
 
-    let mut tx = db.begin().await.context("failed to begin transaction")?;
+            anyhow::Ok(())
+        })
+        .await?;
 
+    // Sync the commit's blocks to disk before the head pointer refers to them (see `storage`).
+    let staged = timer
+        .time(
+            Stage::BlockWrite,
+            storage::stage(&config.repo, &user.did(), repo.root(), orig_size),
+        )
+        .await?;
+
+    let mut tx = timer
+        .time(Stage::DbBegin, db.begin())
+        .await
+        .context("failed to begin transaction")?;
+    let sequence_start = Instant::now();
+
+    // The write lock makes this swap a formality, unless the repository was replaced wholesale
+    // (e.g. imported) in the meantime.
     if !swap_commit(
         &mut *tx,
         repo.root(),
         repo.commit().rev(),
-        input.swap_commit.as_ref().map(|c| c.as_ref().clone()),
+        Some(orig_cid),
         &user.did(),
     )
     .await
     .context("failed to swap commit")?
     {
-        // This should always succeed.
-        let old = input.swap_commit.clone().unwrap();
-
-        // The swap failed. Return the old commit and do not update the repository.
-        return Ok(Json(
-            repo::apply_writes::OutputData {
-                results: None,
-                commit: Some(
-                    CommitMetaData {
-                        cid: old.clone(),
-                        rev: orig_rev,
-                    }
-                    .into(),
-                ),
-            }
-            .into(),
-        ));
+        let current = integrity.head(&user.did()).await.ok();
+        return Err(invalid_swap("Commit", current));
     }
 
     let did_str = user.did();
@@ -379,11 +663,21 @@ async fn apply_writes(
                 .execute(&mut *tx)
                 .await
                 .context("failed to remove blob_ref")?;
+
+                if let Some((collection, rkey)) = path.split_once('/') {
+                    backlinks::unlink(&mut tx, &did_str, collection, rkey).await?;
+                }
             }
             _ => {}
         }
     }
 
+    for (key, subjects) in &links {
+        if let Some((collection, rkey)) = key.split_once('/') {
+            backlinks::link(&mut tx, &did_str, collection, rkey, subjects).await?;
+        }
+    }
+
     for (key, cid) in &blobs {
         let cid_str = cid.to_string();
         let r = sqlx::query!(
@@ -410,13 +704,68 @@ async fn apply_writes(
         }
     }
 
+    // The commit's event is sequenced with it, so that it's broadcast even if we crash once the
+    // transaction has committed.
+    let rev = repo.commit().rev().to_string();
+    let commit = Arc::new(firehose::Commit {
+        car: mem,
+        ops,
+        cid: repo.root(),
+        rev: rev.clone(),
+        did: atrium_api::types::string::Did::new(user.did()).unwrap(),
+        pcid: Some(orig_cid),
+        since: Some(orig_rev.to_string()),
+        blobs: blobs.into_iter().map(|(_, c)| c).collect::<Vec<_>>(),
+    });
+    let seq = firehose::enqueue_commit(&mut tx, &config.firehose, &commit).await?;
+    commitlog::record(&mut tx, &did_str, &repo.root(), &rev, &commit.ops, seq).await?;
+    collections::apply(&mut tx, &did_str, &orig_cid, &repo.root(), &commit.ops).await?;
+    storage::record_staged(&mut tx, &staged).await?;
+    let reindexing = reindex::pending(&mut tx, &did_str).await?;
+
     tx.commit()
         .await
         .context("failed to commit blob ref to database")?;
+    timer.add(Stage::Sequence, sequence_start.elapsed());
+
+    // The commit stands regardless; recovery marks it durable if this doesn't.
+    if let Err(e) = storage::mark_durable(&db, &staged).await {
+        warn!("failed to mark commit of {did_str} durable: {e:?}");
+    }
+
+    // Update storage statistics. The repository file only ever grows as blocks are appended.
+    // Those of a repository queued for reindexing are about to be recomputed from scratch.
+    let size = staged.len_after;
+    let records = commit
+        .ops
+        .iter()
+        .map(|op| match op {
+            RepoOp::Create { .. } => 1,
+            RepoOp::Update { .. } => 0,
+            RepoOp::Delete { .. } => -1,
+        })
+        .sum();
+    let r = if reindexing {
+        Ok(())
+    } else {
+        stats
+            .record(
+                &did_str,
+                StorageDelta {
+                    block_bytes: size as i64 - orig_size as i64,
+                    blob_bytes: 0,
+                    records,
+                },
+            )
+            .await
+    };
+    if let Err(e) = r {
+        warn!("failed to update storage statistics for {did_str}: {e:?}");
+    }
 
     // Update counters.
     counter!(REPO_COMMITS).increment(1);
-    for op in &ops {
+    for op in &commit.ops {
         match op {
             RepoOp::Create { .. } => counter!(REPO_OP_CREATE).increment(1),
             RepoOp::Update { .. } => counter!(REPO_OP_UPDATE).increment(1),
@@ -426,17 +775,11 @@ async fn apply_writes(
 
     // We've committed the transaction to the database, and the commit is now stored in the user's
     // canonical repository.
-    // We can now broadcast this on the firehose.
-    fhp.commit(firehose::Commit {
-        car: mem,
-        ops: ops,
-        cid: repo.root(),
-        rev: repo.commit().rev().to_string(),
-        did: atrium_api::types::string::Did::new(user.did()).unwrap(),
-        pcid: Some(orig_cid),
-        blobs: blobs.into_iter().map(|(_, c)| c).collect::<Vec<_>>(),
-    })
-    .await;
+    // We can now publish it (to be broadcast on the firehose, among others).
+    timer
+        .time(Stage::Firehose, events.publish(Event::Commit(commit)))
+        .await;
+    timer.record();
 
     Ok(Json(
         repo::apply_writes::OutputData {
@@ -458,7 +801,13 @@ async fn create_record(
     State(skey): State<SigningKey>,
     State(config): State<AppConfig>,
     State(db): State<Db>,
-    State(fhp): State<FirehoseProducer>,
+    State(events): State<EventBus>,
+    State(policies): State<Policies>,
+    State(limiter): State<WriteLimiter>,
+    State(client): State<Client>,
+    State(stats): State<StorageStats>,
+    State(integrity): State<RepoIntegrity>,
+    State(locks): State<RepoLocks>,
     Json(input): Json<repo::create_record::Input>,
 ) -> Result<Json<repo::create_record::Output>> {
     let input = (*input).clone();
@@ -477,16 +826,22 @@ async fn create_record(
     }
     .into();
 
-    let r = apply_writes(
+    let r = write_records(
         user,
         State(skey),
         State(config),
         State(db),
-        State(fhp),
-        Json(input),
+        State(events),
+        State(policies),
+        State(limiter),
+        State(client),
+        State(stats),
+        State(integrity),
+        State(locks),
+        input,
+        Vec::new(),
     )
-    .await
-    .context("failed to apply writes")?;
+    .await?;
     let r = (**r).clone();
 
     let res = r
@@ -516,14 +871,37 @@ async fn put_record(
     State(skey): State<SigningKey>,
     State(config): State<AppConfig>,
     State(db): State<Db>,
-    State(fhp): State<FirehoseProducer>,
-    Json(input): Json<repo::put_record::Input>,
+    State(events): State<EventBus>,
+    State(policies): State<Policies>,
+    State(limiter): State<WriteLimiter>,
+    State(client): State<Client>,
+    State(stats): State<StorageStats>,
+    State(integrity): State<RepoIntegrity>,
+    State(locks): State<RepoLocks>,
+    Json(raw): Json<serde_json::Value>,
 ) -> Result<Json<repo::put_record::Output>> {
-    // TODO: `input.swap_record`
+    // An explicitly null `swapRecord` means that the record must not exist yet, which is lost when
+    // parsing the input.
+    let must_create = raw
+        .get("swapRecord")
+        .is_some_and(serde_json::Value::is_null);
+    let input: repo::put_record::Input = serde_json::from_value(raw).map_err(|e| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("invalid putRecord input: {e}"),
+            ErrorMessage::new("InvalidRequest", format!("Invalid input: {e}")),
+        )
+    })?;
 
-    // FIXME: "put" implies that we will create the record if it does not exist.
-    // We currently only update existing records and/or throw an error if one doesn't exist.
     let input = (*input).clone();
+    let condition = Condition {
+        swap_record: match &input.swap_record {
+            Some(cid) => Some(Some(*cid.as_ref())),
+            None if must_create => Some(None),
+            None => None,
+        },
+        upsert: true,
+    };
     let input = repo::apply_writes::InputData {
         repo: input.repo,
         validate: input.validate,
@@ -539,34 +917,39 @@ async fn put_record(
     }
     .into();
 
-    let r = apply_writes(
+    let r = write_records(
         user,
         State(skey),
         State(config),
         State(db),
-        State(fhp),
-        Json(input),
+        State(events),
+        State(policies),
+        State(limiter),
+        State(client),
+        State(stats),
+        State(integrity),
+        State(locks),
+        input,
+        vec![condition],
     )
-    .await
-    .context("failed to apply writes")?;
+    .await?;
     let r = (**r).clone();
 
     let res = r
         .results
         .and_then(|r| r.get(0).cloned())
         .context("unexpected output from apply_writes")?;
-    let res = if let repo::apply_writes::OutputResultsItem::UpdateResult(c) = res {
-        Some(c)
-    } else {
-        None
+    let (cid, uri) = match res {
+        repo::apply_writes::OutputResultsItem::CreateResult(c) => (c.cid.clone(), c.uri.clone()),
+        repo::apply_writes::OutputResultsItem::UpdateResult(u) => (u.cid.clone(), u.uri.clone()),
+        _ => return Err(anyhow!("unexpected result from apply_writes").into()),
     };
-    let res = res.context("unexpected result from apply_writes")?;
 
     Ok(Json(
         repo::put_record::OutputData {
-            cid: res.cid.clone(),
+            cid,
             commit: r.commit,
-            uri: res.uri.clone(),
+            uri,
             validation_status: Some("unknown".to_string()),
         }
         .into(),
@@ -578,12 +961,20 @@ async fn delete_record(
     State(skey): State<SigningKey>,
     State(config): State<AppConfig>,
     State(db): State<Db>,
-    State(fhp): State<FirehoseProducer>,
+    State(events): State<EventBus>,
+    State(policies): State<Policies>,
+    State(limiter): State<WriteLimiter>,
+    State(client): State<Client>,
+    State(stats): State<StorageStats>,
+    State(integrity): State<RepoIntegrity>,
+    State(locks): State<RepoLocks>,
     Json(input): Json<repo::delete_record::Input>,
 ) -> Result<Json<repo::delete_record::Output>> {
-    // TODO: `input.swap_record`
-
     let input = (*input).clone();
+    let condition = Condition {
+        swap_record: input.swap_record.as_ref().map(|cid| Some(*cid.as_ref())),
+        upsert: false,
+    };
     let input = repo::apply_writes::InputData {
         repo: input.repo,
         swap_commit: input.swap_commit,
@@ -598,16 +989,22 @@ async fn delete_record(
     }
     .into();
 
-    let r = apply_writes(
+    let r = write_records(
         user,
         State(skey),
         State(config),
         State(db),
-        State(fhp),
-        Json(input),
+        State(events),
+        State(policies),
+        State(limiter),
+        State(client),
+        State(stats),
+        State(integrity),
+        State(locks),
+        input,
+        vec![condition],
     )
-    .await
-    .context("failed to apply writes")?;
+    .await?;
     let r = (**r).clone();
 
     Ok(Json(
@@ -618,118 +1015,219 @@ async fn delete_record(
 async fn describe_repo(
     State(config): State<AppConfig>,
     State(db): State<Db>,
+    State(client): State<Client>,
+    State(cache): State<DidCache>,
+    State(integrity): State<RepoIntegrity>,
     Query(input): Query<repo::describe_repo::ParametersData>,
 ) -> Result<Json<repo::describe_repo::Output>> {
-    // Lookup the DID by the provided handle.
-    let (did, handle) = resolve_did(&db, &input.repo)
-        .await
-        .context("failed to resolve handle")?;
+    let did = parse_repo_param(&db, &client, repo_ident(&input.repo)).await?;
 
-    let mut repo = storage::open_repo_db(&config.repo, &db, did.as_str())
+    let handle: String = sqlx::query_scalar(r#"SELECT handle FROM handles WHERE did = ?"#)
+        .bind(did.as_str())
+        .fetch_one(&db)
         .await
-        .context("failed to open user repo")?;
+        .context("failed to query handle")?;
+    let handle = atrium_api::types::string::Handle::new(handle).unwrap();
 
-    let mut collections = HashSet::new();
+    let mut repo = integrity.open(did.as_str()).await?;
+    let collections = collections::list(&db, did.as_str(), &mut repo).await?;
 
-    let mut tree = repo.tree();
-    let mut it = Box::pin(tree.keys());
-    while let Some(key) = it.try_next().await.context("failed to iterate repo keys")? {
-        if let Some((collection, _rkey)) = key.split_once('/') {
-            collections.insert(collection.to_string());
+    // The handle is ours, so it's correct if the DID document claims it too.
+    let doc = match cache
+        .resolve(&client, &config, &db, did.clone(), false)
+        .await
+    {
+        Ok(d) => Some(d.doc),
+        Err(e) => {
+            warn!(
+                "failed to resolve the DID document of {}: {e:?}",
+                did.as_str()
+            );
+            None
         }
-    }
+    };
+    let handle_is_correct = doc
+        .as_ref()
+        .and_then(|d| d.handle())
+        .is_some_and(|h| h == handle.as_str());
+    let did_doc = match &doc {
+        Some(doc) => serde_json::to_value(doc)
+            .context("failed to encode DID document")?
+            .try_into_unknown()
+            .context("failed to encode DID document")?,
+        None => Unknown::Null,
+    };
 
     Ok(Json(
         repo::describe_repo::OutputData {
             collections: collections
                 .into_iter()
-                .map(|s| Nsid::new(s).unwrap())
+                .map(|(s, _)| Nsid::new(s).unwrap())
                 .collect::<Vec<_>>(),
             did: did.clone(),
-            did_doc: Unknown::Null, // TODO: Fetch the DID document from the PLC directory
+            did_doc,
             handle: handle.clone(),
-            handle_is_correct: true, // TODO
+            handle_is_correct,
         }
         .into(),
     ))
 }
 
+/// Read a version of a record from a repository's blockstore by its CID, which may have been
+/// superseded since (but not yet collected). The block must be a record of the collection; as
+/// records are content-addressed, which record key it was written under isn't checked.
+async fn read_record_version(
+    store: &mut impl AsyncBlockStoreRead,
+    cid: Cid,
+    collection: &str,
+) -> Option<serde_json::Value> {
+    let block = store.read_block(cid).await.ok()?;
+    if record::block_cid(&block) != cid {
+        return None;
+    }
+
+    let record: serde_json::Value = cbor::decode(&block, &Limits::BLOCK, "record").ok()?;
+    (record.get("$type").and_then(serde_json::Value::as_str) == Some(collection)).then_some(record)
+}
+
 async fn get_record(
     State(config): State<AppConfig>,
     State(db): State<Db>,
+    State(client): State<Client>,
+    State(integrity): State<RepoIntegrity>,
     Query(input): Query<repo::get_record::ParametersData>,
 ) -> Result<Json<repo::get_record::Output>> {
-    if input.cid.is_some() {
-        return Err(Error::unimplemented(anyhow!(
-            "looking up old records is unsupported"
-        )));
-    }
+    let did = parse_repo_param(&db, &client, repo_ident(&input.repo)).await?;
+    status::require_hosted(&db, did.as_str()).await?;
 
-    // Lookup the DID by the provided handle.
-    let (did, _handle) = resolve_did(&db, &input.repo)
-        .await
-        .context("failed to resolve handle")?;
-
-    let mut repo = storage::open_repo_db(&config.repo, &db, did.as_str())
-        .await
-        .context("failed to open user repo")?;
+    let mut repo = integrity.open(did.as_str()).await?;
 
     let key = format!("{}/{}", input.collection.as_str(), input.rkey.as_str());
-    let uri = format!("at://{}/{}", did.as_str(), &key);
+    let uri = AtUri::record(did.as_str(), input.collection.as_str(), input.rkey.as_str());
+    let not_found = || {
+        Error::with_message(
+            StatusCode::NOT_FOUND,
+            anyhow!("could not find record {uri}"),
+            ErrorMessage::new("RecordNotFound", format!("Could not locate record: {uri}")),
+        )
+    };
 
-    let cid = repo
+    let latest = repo
         .tree()
         .get(&key)
         .await
         .context("failed to find record")?;
 
-    let record: Option<serde_json::Value> =
-        repo.get_raw(&key).await.context("failed to read record")?;
+    let (cid, record) = match input.cid.as_ref().map(|c| *c.as_ref()) {
+        // An earlier version, which may no longer be in the tree at all.
+        Some(cid) if Some(cid) != latest => {
+            drop(repo);
+            let mut store = storage::open_store(&config.repo, did.as_str()).await?;
+            let record = read_record_version(&mut store, cid, input.collection.as_str())
+                .await
+                .ok_or_else(not_found)?;
+            (cid, record)
+        }
+        _ => {
+            let cid = latest.ok_or_else(not_found)?;
+            let record: serde_json::Value = repo
+                .get_raw(&key)
+                .await
+                .context("failed to read record")?
+                .ok_or_else(not_found)?;
+            (cid, record)
+        }
+    };
 
-    if let Some(record) = record {
-        Ok(Json(
-            repo::get_record::OutputData {
-                cid: cid.map(atrium_api::types::string::Cid::new),
-                uri,
-                value: record.try_into_unknown().unwrap(),
+    Ok(Json(
+        repo::get_record::OutputData {
+            cid: Some(atrium_api::types::string::Cid::new(cid)),
+            uri: uri.to_string(),
+            value: record.try_into_unknown().unwrap(),
+        }
+        .into(),
+    ))
+}
+
+/// Select a page of a collection's records by record key: descending (i.e. newest first, for
+/// TIDs), or ascending if `reverse`, starting after `cursor`. The cursor needn't be a record that
+/// still exists. The collection is streamed from the MST, holding no more than a page in memory.
+///
+/// Returns the page, and whether there are more records after it.
+async fn list_page(
+    repo: &mut Repository<impl AsyncBlockStoreRead>,
+    collection: &str,
+    cursor: Option<&str>,
+    limit: usize,
+    reverse: bool,
+) -> Result<(Vec<(String, Cid)>, bool)> {
+    let prefix = format!("{collection}/");
+    let mut tree = repo.tree();
+    let mut it = Box::pin(tree.entries_prefixed(&prefix));
+
+    let mut page = VecDeque::with_capacity(limit);
+    let mut more = false;
+    while let Some((key, cid)) = it.try_next().await.context("failed to iterate keys")? {
+        let rkey = &key[prefix.len()..];
+        if reverse {
+            if cursor.is_some_and(|c| rkey <= c) {
+                continue;
             }
-            .into(),
-        ))
-    } else {
-        return Err(Error::with_status(
-            StatusCode::NOT_FOUND,
-            anyhow!("could not find record {uri}"),
-        ));
+            if page.len() == limit {
+                more = true;
+                break;
+            }
+        } else {
+            // Keep the last page before the cursor.
+            if cursor.is_some_and(|c| rkey >= c) {
+                break;
+            }
+            if page.len() == limit {
+                page.pop_front();
+                more = true;
+            }
+        }
+        page.push_back((key, cid));
+    }
+
+    let mut page = Vec::from(page);
+    if !reverse {
+        page.reverse();
     }
+    Ok((page, more))
 }
 
 async fn list_records(
-    State(config): State<AppConfig>,
     State(db): State<Db>,
+    State(client): State<Client>,
+    State(integrity): State<RepoIntegrity>,
     Query(input): Query<Object<repo::list_records::ParametersData>>,
 ) -> Result<Json<repo::list_records::Output>> {
-    // TODO: `input.reverse`
-
-    // Lookup the DID by the provided handle.
-    let (did, _handle) = resolve_did(&db, &input.repo)
-        .await
-        .context("failed to resolve handle")?;
-
-    let mut repo = storage::open_repo_db(&config.repo, &db, did.as_str())
-        .await
-        .context("failed to open user repo")?;
-
-    let mut keys = Vec::new();
-    let mut tree = repo.tree();
-
-    let mut it = Box::pin(tree.entries_prefixed(input.collection.as_str()));
-    while let Some((key, cid)) = it.try_next().await.context("failed to iterate keys")? {
-        keys.push((key, cid));
+    let limit: u8 = input.limit.map(u8::from).unwrap_or(DEFAULT_LIST_LIMIT);
+
+    // Cursors are record keys, so that they're stable across writes.
+    if let Some(cursor) = &input.cursor {
+        if let Err(e) = RecordKey::new(cursor.clone()) {
+            return Err(Error::with_message(
+                StatusCode::BAD_REQUEST,
+                anyhow!("invalid listRecords cursor {cursor:?}: {e}"),
+                ErrorMessage::new("InvalidRequest", "Invalid cursor"),
+            ));
+        }
     }
 
-    drop(it);
+    let did = parse_repo_param(&db, &client, repo_ident(&input.repo)).await?;
 
-    // TODO: Calculate the view on `keys` using `cursor` and `limit`.
+    let mut repo = integrity.open(did.as_str()).await?;
+
+    let (keys, more) = list_page(
+        &mut repo,
+        input.collection.as_str(),
+        input.cursor.as_deref(),
+        limit as usize,
+        input.reverse.unwrap_or(false),
+    )
+    .await?;
 
     let mut records = Vec::new();
     for (key, cid) in &keys {
@@ -742,19 +1240,23 @@ async fn list_records(
         records.push(
             repo::list_records::RecordData {
                 cid: atrium_api::types::string::Cid::new(*cid),
-                uri: format!("at://{}/{}", did.as_str(), key),
+                uri: {
+                    let (collection, rkey) = key.split_once('/').context("invalid record key")?;
+                    AtUri::record(did.as_str(), collection, rkey).to_string()
+                },
                 value: value.try_into_unknown().unwrap(),
             }
             .into(),
         )
     }
 
+    let cursor = more
+        .then(|| keys.last().and_then(|(k, _)| k.split_once('/')))
+        .flatten()
+        .map(|(_, rkey)| rkey.to_string());
+
     Ok(Json(
-        repo::list_records::OutputData {
-            cursor: keys.last().map(|(k, _)| k.clone()),
-            records,
-        }
-        .into(),
+        repo::list_records::OutputData { cursor, records }.into(),
     ))
 }
 
@@ -762,6 +1264,8 @@ async fn upload_blob(
     user: AuthenticatedUser,
     State(config): State<AppConfig>,
     State(db): State<Db>,
+    State(stats): State<StorageStats>,
+    State(events): State<EventBus>,
     request: Request<Body>,
 ) -> Result<Json<repo::upload_blob::Output>> {
     let length = request
@@ -825,10 +1329,7 @@ async fn upload_blob(
     drop(file);
     let hash = sha.finalize();
 
-    let cid = atrium_repo::Cid::new_v1(
-        IPLD_RAW,
-        atrium_repo::Multihash::wrap(IPLD_MH_SHA2_256, hash.as_slice()).unwrap(),
-    );
+    let cid = blob_cid(hash.as_slice());
 
     let cid_str = cid.to_string();
 
@@ -850,6 +1351,27 @@ async fn upload_blob(
     .await
     .context("failed to insert blob into database")?;
 
+    if let Err(e) = stats
+        .record(
+            &did_str,
+            StorageDelta {
+                blob_bytes: len as i64,
+                ..Default::default()
+            },
+        )
+        .await
+    {
+        warn!("failed to update storage statistics for {did_str}: {e:?}");
+    }
+
+    events
+        .publish(Event::BlobUploaded {
+            did: did_str,
+            cid,
+            size: len as u64,
+        })
+        .await;
+
     Ok(Json(
         repo::upload_blob::OutputData {
             blob: atrium_api::types::BlobRef::Typed(atrium_api::types::TypedBlobRef::Blob(
@@ -864,23 +1386,991 @@ async fn upload_blob(
     ))
 }
 
+/// Reject imports into accounts that aren't deactivated.
+async fn require_deactivated(db: &Db, did: &str) -> Result<()> {
+    let status: String = sqlx::query_scalar(r#"SELECT status FROM accounts WHERE did = ?"#)
+        .bind(did)
+        .fetch_one(db)
+        .await
+        .context("failed to query account status")?;
+    if status != status::AccountStatus::Deactivated.as_str() {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("{did} is {status}, and may not import a repository"),
+            ErrorMessage::new(
+                "InvalidRequest",
+                "Account must be deactivated to import a repository",
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Import a repository from a CAR file, e.g. when migrating an account from another PDS.
+///
+/// The upload is streamed to disk rather than buffered in memory, and validated before it
+/// replaces the account's existing repository. The account must be deactivated; consumers are
+/// told to resynchronize the repository when it's activated.
+async fn import_repo(
+    user: AuthenticatedUser,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(cache): State<DidCache>,
+    State(client): State<Client>,
+    State(locks): State<RepoLocks>,
+    request: Request<Body>,
+) -> Result<()> {
+    let did = user.did();
+    let limit = config.repo.import_limit;
+
+    require_deactivated(&db, &did).await?;
+
+    if let Some(length) = request.headers().get(http::header::CONTENT_LENGTH) {
+        let length = length
+            .to_str()
+            .map_err(anyhow::Error::from)
+            .and_then(|s| s.parse::<u64>().map_err(anyhow::Error::from))
+            .context("invalid content-length header")?;
+
+        if length > limit {
+            return Err(Error::with_status(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                anyhow!("size {} above limit {}", length, limit),
+            ));
+        }
+    }
+
+    let filename = config
+        .repo
+        .path
+        .join(format!("import-{}.car", uuid::Uuid::new_v4()));
+
+    let r = async {
+        let mut file = tokio::fs::File::create(&filename)
+            .await
+            .context("failed to create temporary file")?;
+
+        // N.B: The body is pulled from the client only as fast as we can write it to disk.
+        let mut len = 0u64;
+        let mut stream = request.into_body().into_data_stream();
+        while let Some(bytes) = stream.try_next().await.context("failed to receive repo")? {
+            len += bytes.len() as u64;
+            if len > limit {
+                return Err(Error::with_status(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    anyhow!("size above limit {}", limit),
+                ));
+            }
+
+            file.write_all(&bytes)
+                .await
+                .context("failed to write repo")?;
+        }
+
+        file.flush().await.context("failed to flush repo")?;
+        file.sync_all().await.context("failed to sync repo")?;
+        drop(file);
+
+        // Hold the repository's write lock until it's replaced, so that it's replaced as validated.
+        let _lock = locks.lock(&did).await;
+        require_deactivated(&db, &did).await?;
+
+        let (current_root, current_rev): (String, Option<String>) =
+            sqlx::query_as(r#"SELECT root, rev FROM accounts WHERE did = ?"#)
+                .bind(&did)
+                .fetch_one(&db)
+                .await
+                .context("failed to query account")?;
+
+        // The commit must be signed by the key the account's DID document currently advertises.
+        let doc = cache
+            .resolve(
+                &client,
+                &config,
+                &db,
+                atrium_api::types::string::Did::new(did.clone()).unwrap(),
+                true,
+            )
+            .await
+            .context("failed to resolve DID document")?
+            .doc;
+        let key = doc
+            .verification_method
+            .iter()
+            .find(|m| m.id.ends_with("#atproto"))
+            .map(|m| format!("did:key:{}", m.public_key_multibase))
+            .context("DID document has no atproto signing key")?;
+
+        let path = filename.clone();
+        let orphan_tolerance = config.repo.import_orphan_tolerance;
+        let did2 = did.clone();
+        let rev2 = current_rev.clone();
+        let repo = tokio::task::spawn_blocking(move || {
+            import::validate_car(
+                &path,
+                &ImportOptions {
+                    did: &did2,
+                    signing_key: &key,
+                    current_rev: rev2.as_deref(),
+                    orphan_tolerance,
+                },
+            )
+        })
+        .await
+        .context("failed to join validation task")?
+        .map_err(|e| match e {
+            ImportError::Io(e) => Error::from(anyhow::Error::from(e)),
+            e => Error::with_message(
+                StatusCode::BAD_REQUEST,
+                anyhow!("rejected repo import for {did}: {e}"),
+                ErrorMessage::new("InvalidRequest", e.to_string()),
+            ),
+        })?;
+
+        // The head pointer is moved before the validated file is renamed into place (see
+        // `storage`), so that a failed transaction leaves the repository as it was. The account is
+        // deactivated, so the repository isn't served in between.
+        let mut tx = db.begin().await.context("failed to begin transaction")?;
+        sqlx::query(r#"UPDATE accounts SET root = ?, rev = ? WHERE did = ?"#)
+            .bind(repo.root.to_string())
+            .bind(&repo.rev)
+            .bind(&did)
+            .execute(&mut *tx)
+            .await
+            .context("failed to update account root")?;
+        storage::forget_staged(&mut tx, &did).await?;
+        tx.commit().await.context("failed to commit transaction")?;
+
+        if let Err(e) = tokio::fs::rename(&filename, storage::repo_path(&config.repo, &did)?).await
+        {
+            // Point the head back at the repository that's still in place.
+            sqlx::query(r#"UPDATE accounts SET root = ?, rev = ? WHERE did = ? AND root = ?"#)
+                .bind(&current_root)
+                .bind(&current_rev)
+                .bind(&did)
+                .bind(repo.root.to_string())
+                .execute(&db)
+                .await
+                .context("failed to restore account root")?;
+
+            return Err(Error::from(
+                anyhow::Error::from(e).context("failed to replace repository"),
+            ));
+        }
+
+        info!(
+            "imported repo for {} at rev {} ({} records)",
+            did, repo.rev, repo.records
+        );
+
+        // The imported repository replaces the old one wholesale, along with everything derived
+        // from it.
+        let index = reindex::scan(&config, &did, repo.root, None).await?;
+        reindex::apply(&config, &db, &did, &repo.root, &index).await?;
+
+        Ok(())
+    }
+    .await;
+
+    if r.is_err() {
+        // Best-effort cleanup, unless the file was already moved into place (and so is gone).
+        let _ = tokio::fs::remove_file(&filename).await;
+    }
+
+    migration::record_result(&db, &did, migration::Step::RepoImported, &r).await;
+    r
+}
+
+/// Find blobs referenced by an account's records that aren't in the blob store, one record each,
+/// in CID order after `cursor`. Returns `(cid, record path)` pairs, and the cursor to continue
+/// from if there may be more.
+///
+/// Pages are keyed by CID, so that blobs uploaded between pages don't shift the ones after them.
+async fn missing_blobs(
+    config: &AppConfig,
+    db: &Db,
+    did: &str,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<(Vec<(String, String)>, Option<String>)> {
+    let mut missing = Vec::new();
+    let mut after = cursor.unwrap_or_default().to_string();
+    loop {
+        let refs: Vec<(String, String)> = sqlx::query_as(
+            r#"
+            SELECT cid, MIN(record) FROM blob_ref
+                WHERE did = ? AND record IS NOT NULL AND cid > ?
+                GROUP BY cid ORDER BY cid LIMIT ?
+            "#,
+        )
+        .bind(did)
+        .bind(&after)
+        .bind(limit as i64)
+        .fetch_all(db)
+        .await
+        .context("failed to query blob references")?;
+        let exhausted = refs.len() < limit;
+
+        for (cid, record) in refs {
+            after.clone_from(&cid);
+            let present = tokio::fs::try_exists(config.blob.path.join(format!("{cid}.blob")))
+                .await
+                .unwrap_or(false);
+            if !present {
+                missing.push((cid, record));
+                if missing.len() == limit {
+                    return Ok((missing, Some(after)));
+                }
+            }
+        }
+
+        if exhausted {
+            return Ok((missing, None));
+        }
+    }
+}
+
+/// List the blobs the authenticated user's records reference that haven't been uploaded, e.g.
+/// while migrating an account.
+async fn list_missing_blobs(
+    user: AuthenticatedUser,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    Query(input): Query<repo::list_missing_blobs::ParametersData>,
+) -> Result<Json<repo::list_missing_blobs::Output>> {
+    let did = user.did();
+    let limit: u16 = input
+        .limit
+        .map(u16::from)
+        .unwrap_or(DEFAULT_MISSING_BLOBS_LIMIT);
+
+    let (missing, cursor) =
+        missing_blobs(&config, &db, &did, input.cursor.as_deref(), limit as usize).await?;
+    let blobs = missing
+        .into_iter()
+        .map(|(cid, record)| {
+            let (collection, rkey) = record.split_once('/').context("invalid record path")?;
+            Ok(repo::list_missing_blobs::RecordBlobData {
+                cid: atrium_api::types::string::Cid::new(
+                    Cid::from_str(&cid).context("invalid blob CID")?,
+                ),
+                record_uri: AtUri::record(&did, collection, rkey).to_string(),
+            }
+            .into())
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Json(
+        repo::list_missing_blobs::OutputData { blobs, cursor }.into(),
+    ))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WriteBudgetWindow {
+    limit: u32,
+    remaining: u32,
+    /// Seconds until the window resets.
+    reset: u64,
+}
+
+impl From<ratelimit::BudgetStatus> for WriteBudgetWindow {
+    fn from(s: ratelimit::BudgetStatus) -> Self {
+        Self {
+            limit: s.limit,
+            remaining: s.remaining,
+            reset: s.reset.as_secs(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WriteBudgetOutput {
+    hourly: WriteBudgetWindow,
+    daily: WriteBudgetWindow,
+}
+
+/// Report the authenticated user's remaining write budget, so clients can warn users before
+/// they get rate limited.
+async fn get_write_budget(
+    user: AuthenticatedUser,
+    State(limiter): State<WriteLimiter>,
+) -> Result<Json<WriteBudgetOutput>> {
+    let budget = limiter.budget(&user.did());
+
+    Ok(Json(WriteBudgetOutput {
+        hourly: budget.hourly.into(),
+        daily: budget.daily.into(),
+    }))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct CommitLogInput {
+    /// Only list commits before this revision.
+    cursor: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(super) struct CommitLogOutput {
+    commits: Vec<LoggedCommit>,
+    cursor: Option<String>,
+}
+
+/// List a repository's logged commits, most recent first.
+pub(super) async fn commit_log_with(
+    db: &Db,
+    did: &str,
+    cursor: Option<&str>,
+    limit: Option<u32>,
+) -> Result<CommitLogOutput> {
+    let limit = limit.unwrap_or(50).clamp(1, 1000);
+    let commits = commitlog::list(db, did, cursor, limit).await?;
+    let cursor = (commits.len() == limit as usize)
+        .then(|| commits.last().map(|c| c.rev.clone()))
+        .flatten();
+
+    Ok(CommitLogOutput { commits, cursor })
+}
+
+/// List the most recent commits to the authenticated user's repository.
+async fn get_commit_log(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    Query(input): Query<CommitLogInput>,
+) -> Result<Json<CommitLogOutput>> {
+    Ok(Json(
+        commit_log_with(&db, &user.did(), input.cursor.as_deref(), input.limit).await?,
+    ))
+}
+
 #[rustfmt::skip]
-pub fn routes() -> Router<AppState> {
+pub fn routes() -> Routes {
     // AP /xrpc/com.atproto.repo.applyWrites
     // AP /xrpc/com.atproto.repo.createRecord
     // AP /xrpc/com.atproto.repo.putRecord
     // AP /xrpc/com.atproto.repo.deleteRecord
     // AP /xrpc/com.atproto.repo.uploadBlob
+    // AP /xrpc/com.atproto.repo.importRepo
     // UG /xrpc/com.atproto.repo.describeRepo
     // UG /xrpc/com.atproto.repo.getRecord
     // UG /xrpc/com.atproto.repo.listRecords
-    Router::new()
+    // AG /xrpc/com.atproto.repo.listMissingBlobs
+    // AG /xrpc/_account/writeBudget
+    // AG /xrpc/_account/commitLog
+    Routes::new()
         .route(concat!("/", repo::apply_writes::NSID),  post(apply_writes))
         .route(concat!("/", repo::create_record::NSID), post(create_record))
         .route(concat!("/", repo::put_record::NSID),    post(put_record))
         .route(concat!("/", repo::delete_record::NSID), post(delete_record))
         .route(concat!("/", repo::upload_blob::NSID),   post(upload_blob))
+        .route(concat!("/", repo::import_repo::NSID),   post(import_repo))
         .route(concat!("/", repo::describe_repo::NSID), get(describe_repo))
         .route(concat!("/", repo::get_record::NSID),    get(get_record))
         .route(concat!("/", repo::list_records::NSID),  get(list_records))
+        .route(concat!("/", repo::list_missing_blobs::NSID), get(list_missing_blobs))
+        .route("/_account/writeBudget",                  get(get_write_budget))
+        .route("/_account/commitLog",                    get(get_commit_log))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use atrium_api::types::string::Did;
+    use atrium_crypto::keypair::{Did as _, Secp256k1Keypair};
+    use axum::{body::to_bytes, response::IntoResponse};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{config::PolicyConfig, plc};
+
+    async fn error_body(e: Error) -> serde_json::Value {
+        let body = to_bytes(e.into_response().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    async fn error_name(e: Error) -> String {
+        error_body(e).await["error"].as_str().unwrap().to_string()
+    }
+
+    fn write(kind: WriteKind, key: &str, condition: Condition) -> PlannedWrite {
+        PlannedWrite {
+            kind,
+            key: key.to_string(),
+            condition,
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_checks() {
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let mut store = CarStore::create(Cursor::new(Vec::new())).await.unwrap();
+        let builder =
+            Repository::create(&mut store, Did::new("did:plc:alice".to_string()).unwrap())
+                .await
+                .unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        let mut repo = builder.finalize(sig).await.unwrap();
+
+        let existing = "app.bsky.feed.post/3jzfcijpj2z2a";
+        let (builder, cid) = repo
+            .add_raw(existing, json!({ "text": "one" }))
+            .await
+            .unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        builder.finalize(sig).await.unwrap();
+
+        let new = "app.bsky.feed.post/3jzfcijpj2z2b";
+        let none = Condition::default();
+
+        // Each write sees the writes before it in the batch.
+        check_writes(
+            &mut repo,
+            &mut [
+                write(WriteKind::Update, existing, none),
+                write(WriteKind::Delete, existing, none),
+                write(WriteKind::Create, existing, none),
+                write(WriteKind::Create, new, none),
+                write(WriteKind::Update, new, none),
+            ],
+        )
+        .await
+        .unwrap();
+
+        for mut writes in [
+            vec![write(WriteKind::Create, existing, none)],
+            vec![write(WriteKind::Update, new, none)],
+            vec![
+                write(WriteKind::Create, new, none),
+                write(WriteKind::Delete, new, none),
+                write(WriteKind::Delete, new, none),
+            ],
+        ] {
+            let e = check_writes(&mut repo, &mut writes).await.unwrap_err();
+            assert_eq!(error_name(e).await, "InvalidRequest");
+        }
+
+        // Upserts create records that don't exist.
+        let upsert = Condition {
+            upsert: true,
+            ..none
+        };
+        let mut writes = [
+            write(WriteKind::Update, existing, upsert),
+            write(WriteKind::Update, new, upsert),
+        ];
+        check_writes(&mut repo, &mut writes).await.unwrap();
+        assert_eq!(writes[0].kind, WriteKind::Update);
+        assert_eq!(writes[1].kind, WriteKind::Create);
+
+        // Swaps report where the record actually is.
+        let swap = |swap_record| Condition {
+            swap_record: Some(swap_record),
+            upsert: true,
+        };
+        for (key, expected) in [(existing, Some(cid)), (new, None)] {
+            check_writes(
+                &mut repo,
+                &mut [write(WriteKind::Update, key, swap(expected))],
+            )
+            .await
+            .unwrap();
+        }
+        for (key, expected, actual) in [
+            (existing, None, cid.to_string()),
+            (new, Some(cid), "null".to_string()),
+        ] {
+            let e = check_writes(
+                &mut repo,
+                &mut [write(WriteKind::Update, key, swap(expected))],
+            )
+            .await
+            .unwrap_err();
+            let body = error_body(e).await;
+            assert_eq!(body["error"], "InvalidSwap");
+            assert!(body["message"].as_str().unwrap().contains(&actual));
+        }
+    }
+
+    #[tokio::test]
+    async fn pages() {
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let mut store = CarStore::create(Cursor::new(Vec::new())).await.unwrap();
+        let builder =
+            Repository::create(&mut store, Did::new("did:plc:alice".to_string()).unwrap())
+                .await
+                .unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        let mut repo = builder.finalize(sig).await.unwrap();
+
+        // A neighbouring collection, whose name the listed one is a prefix of.
+        let keys = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|rkey| format!("app.bsky.feed.post/{rkey}"))
+            .chain(["app.bsky.feed.postgate/a".to_string()]);
+        for key in keys {
+            let (builder, _) = repo.add_raw(&key, json!({ "key": key })).await.unwrap();
+            let sig = skey.sign(&builder.bytes()).unwrap();
+            builder.finalize(sig).await.unwrap();
+        }
+
+        async fn page(
+            repo: &mut Repository<impl AsyncBlockStoreRead>,
+            cursor: Option<&str>,
+            reverse: bool,
+        ) -> (Vec<String>, bool) {
+            let (page, more) = list_page(repo, "app.bsky.feed.post", cursor, 2, reverse)
+                .await
+                .unwrap();
+            let rkeys = page
+                .into_iter()
+                .map(|(k, _)| k.rsplit_once('/').unwrap().1.to_string())
+                .collect();
+            (rkeys, more)
+        }
+
+        // Newest first, by default.
+        assert_eq!(
+            page(&mut repo, None, false).await,
+            (vec!["e".into(), "d".into()], true)
+        );
+        assert_eq!(
+            page(&mut repo, Some("d"), false).await,
+            (vec!["c".into(), "b".into()], true)
+        );
+        assert_eq!(
+            page(&mut repo, Some("b"), false).await,
+            (vec!["a".into()], false)
+        );
+
+        assert_eq!(
+            page(&mut repo, None, true).await,
+            (vec!["a".into(), "b".into()], true)
+        );
+        assert_eq!(
+            page(&mut repo, Some("b"), true).await,
+            (vec!["c".into(), "d".into()], true)
+        );
+        assert_eq!(
+            page(&mut repo, Some("d"), true).await,
+            (vec!["e".into()], false)
+        );
+
+        // A cursor at a record that has since been deleted resumes where it left off.
+        let builder = repo.delete_raw("app.bsky.feed.post/c").await.unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        builder.finalize(sig).await.unwrap();
+        assert_eq!(
+            page(&mut repo, Some("c"), false).await,
+            (vec!["b".into(), "a".into()], false)
+        );
+        assert_eq!(
+            page(&mut repo, Some("c"), true).await,
+            (vec!["d".into(), "e".into()], false)
+        );
+
+        let (empty, more) = list_page(&mut repo, "app.bsky.feed.like", None, 2, false)
+            .await
+            .unwrap();
+        assert!(empty.is_empty() && !more);
+    }
+
+    struct TestAccount {
+        dir: std::path::PathBuf,
+        config: AppConfig,
+        db: Db,
+        skey: SigningKey,
+        /// The CID of each write, in order.
+        cids: Vec<Cid>,
+    }
+
+    /// Create an account hosting a repository with the specified writes applied, creating or
+    /// updating records, on disk and in a file-backed database, so that requests race on real
+    /// connections.
+    async fn test_account(did: &str, writes: &[(&str, serde_json::Value)]) -> TestAccount {
+        let dir = crate::testing::temp_dir("repo");
+        let config = crate::testing::config(&dir, json!({}));
+
+        let db = crate::testing::file_db(&dir, 16).await;
+
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let file = tokio::fs::File::create(storage::repo_path(&config.repo, did).unwrap())
+            .await
+            .unwrap();
+        let mut store = CarStore::create(file).await.unwrap();
+        let builder = Repository::create(&mut store, Did::new(did.to_string()).unwrap())
+            .await
+            .unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        let mut repo = builder.finalize(sig).await.unwrap();
+
+        let mut cids = Vec::new();
+        for (key, value) in writes {
+            let (builder, cid) = match repo.tree().get(key).await.unwrap() {
+                Some(_) => repo.update_raw(key, value).await.unwrap(),
+                None => repo.add_raw(key, value).await.unwrap(),
+            };
+            let sig = skey.sign(&builder.bytes()).unwrap();
+            builder.finalize(sig).await.unwrap();
+            cids.push(cid);
+        }
+
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', ?, '', ?)"#,
+        )
+        .bind(did)
+        .bind(repo.root().to_string())
+        .bind(repo.commit().rev().to_string())
+        .execute(&db)
+        .await
+        .unwrap();
+
+        TestAccount {
+            dir,
+            config,
+            db,
+            skey,
+            cids,
+        }
+    }
+
+    #[tokio::test]
+    async fn versions() {
+        let did = "did:plc:alice";
+        let post = |text: &str| json!({ "$type": "app.bsky.feed.post", "text": text });
+        let TestAccount {
+            dir,
+            config,
+            db,
+            skey,
+            cids,
+        } = test_account(
+            did,
+            &[
+                ("app.bsky.feed.post/3jzfcijpj2z2a", post("one")),
+                ("app.bsky.feed.post/3jzfcijpj2z2a", post("two")),
+            ],
+        )
+        .await;
+
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+        let integrity = RepoIntegrity::new(config.repo.clone(), db.clone(), client.clone(), skey);
+        let get = |rkey: &str, cid: Option<Cid>| {
+            let input = serde_json::from_value(json!({
+                "repo": did,
+                "collection": "app.bsky.feed.post",
+                "rkey": rkey,
+                "cid": cid.map(|c| c.to_string()),
+            }))
+            .unwrap();
+            get_record(
+                State(config.clone()),
+                State(db.clone()),
+                State(client.clone()),
+                State(integrity.clone()),
+                Query(input),
+            )
+        };
+
+        // The latest version, by default or by its CID.
+        for cid in [None, Some(cids[1])] {
+            let r = get("3jzfcijpj2z2a", cid).await.unwrap();
+            assert_eq!(r.cid.as_ref().map(|c| *c.as_ref()), Some(cids[1]));
+            assert_eq!(
+                r.uri,
+                format!("at://{did}/app.bsky.feed.post/3jzfcijpj2z2a")
+            );
+            assert_eq!(serde_json::to_value(&r.value).unwrap()["text"], "two");
+        }
+
+        // A superseded version, by its CID.
+        let r = get("3jzfcijpj2z2a", Some(cids[0])).await.unwrap();
+        assert_eq!(r.cid.as_ref().map(|c| *c.as_ref()), Some(cids[0]));
+        assert_eq!(serde_json::to_value(&r.value).unwrap()["text"], "one");
+
+        // Missing records, and blocks that aren't records of the collection.
+        let root = integrity.head(did).await.unwrap();
+        for (rkey, cid) in [
+            ("3jzfcijpj2z2b", None),
+            ("3jzfcijpj2z2a", Some(root)),
+            ("3jzfcijpj2z2a", Some(record::block_cid(b"missing"))),
+        ] {
+            let e = get(rkey, cid).await.unwrap_err();
+            assert_eq!(error_name(e).await, "RecordNotFound");
+        }
+
+        // Records of accounts that were taken down aren't served.
+        sqlx::query(r#"UPDATE accounts SET status = 'takendown'"#)
+            .execute(&db)
+            .await
+            .unwrap();
+        let e = get("3jzfcijpj2z2a", None).await.unwrap_err();
+        assert_eq!(error_name(e).await, "RepoTakendown");
+
+        db.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn import() {
+        let did = "did:plc:alice";
+        let TestAccount {
+            dir,
+            config,
+            db,
+            skey,
+            ..
+        } = test_account(did, &[]).await;
+
+        // The account's identity advertises the key the imported repository is signed with.
+        let log = tokio::fs::File::create(dir.join("plc").join("alice.car"))
+            .await
+            .unwrap();
+        drop(CarStore::create(log).await.unwrap());
+        let op = plc::SignedPlcOperation {
+            typ: "plc_operation".to_string(),
+            rotation_keys: vec![],
+            verification_methods: HashMap::from([("atproto".to_string(), skey.did().to_string())]),
+            also_known_as: vec![],
+            services: HashMap::new(),
+            prev: None,
+            sig: String::new(),
+        };
+        let plc_root = plc::append_op(&config.plc, did, &op).await.unwrap();
+        sqlx::query(r#"UPDATE accounts SET plc_root = ?"#)
+            .bind(plc_root)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        // The repository from the old PDS.
+        let mut store = CarStore::create(Cursor::new(Vec::new())).await.unwrap();
+        let builder = Repository::create(&mut store, Did::new(did.to_string()).unwrap())
+            .await
+            .unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        let mut repo = builder.finalize(sig).await.unwrap();
+        for rkey in ["3jzfcijpj2z2a", "3jzfcijpj2z2b"] {
+            let (builder, _) = repo
+                .add_raw(
+                    &format!("app.bsky.feed.post/{rkey}"),
+                    json!({ "$type": "app.bsky.feed.post", "text": rkey }),
+                )
+                .await
+                .unwrap();
+            let sig = skey.sign(&builder.bytes()).unwrap();
+            builder.finalize(sig).await.unwrap();
+        }
+        let root = repo.root();
+        let mut car = Vec::new();
+        let mut export = CarStore::create_with_roots(Cursor::new(&mut car), [root])
+            .await
+            .unwrap();
+        repo.export_into(&mut export).await.unwrap();
+        drop(export);
+
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+        let import = |car: Vec<u8>| {
+            import_repo(
+                AuthenticatedUser::for_test(did),
+                State(config.clone()),
+                State(db.clone()),
+                State(DidCache::default()),
+                State(client.clone()),
+                State(RepoLocks::default()),
+                Request::new(Body::from(car)),
+            )
+        };
+
+        // Only deactivated accounts may import.
+        let e = import(car.clone()).await.unwrap_err();
+        assert_eq!(error_name(e).await, "InvalidRequest");
+
+        sqlx::query(r#"UPDATE accounts SET status = 'deactivated'"#)
+            .execute(&db)
+            .await
+            .unwrap();
+        import(car).await.unwrap();
+
+        let head: String = sqlx::query_scalar(r#"SELECT root FROM accounts"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(head, root.to_string());
+        let records: i64 = sqlx::query_scalar(r#"SELECT records FROM repo_stats"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(records, 2);
+
+        db.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing() {
+        let did = "did:plc:alice";
+        let TestAccount {
+            dir, config, db, ..
+        } = test_account(did, &[]).await;
+
+        // Blob `b` is present, `c` is referenced twice, and `e` by no record yet.
+        sqlx::query(
+            r#"
+            INSERT INTO blob_ref (cid, did, record) VALUES
+                ('a', 'did:plc:alice', 'app.bsky.feed.post/1'),
+                ('b', 'did:plc:alice', 'app.bsky.feed.post/2'),
+                ('c', 'did:plc:alice', 'app.bsky.feed.post/4'),
+                ('c', 'did:plc:alice', 'app.bsky.feed.post/3'),
+                ('d', 'did:plc:alice', 'app.bsky.feed.post/5'),
+                ('e', 'did:plc:alice', NULL),
+                ('f', 'did:plc:bob', 'app.bsky.feed.post/6');
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        std::fs::write(dir.join("blob").join("b.blob"), b"b").unwrap();
+
+        let cids = |missing: &[(String, String)]| {
+            missing
+                .iter()
+                .map(|(cid, _)| cid.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let (missing, cursor) = missing_blobs(&config, &db, did, None, 2).await.unwrap();
+        assert_eq!(cids(&missing), ["a", "c"]);
+        assert_eq!(missing[1].1, "app.bsky.feed.post/3");
+        assert_eq!(cursor.as_deref(), Some("c"));
+
+        // Uploads between pages don't disturb the pagination.
+        std::fs::write(dir.join("blob").join("a.blob"), b"a").unwrap();
+        let (missing, cursor) = missing_blobs(&config, &db, did, cursor.as_deref(), 2)
+            .await
+            .unwrap();
+        assert_eq!(cids(&missing), ["d"]);
+        assert_eq!(cursor, None);
+
+        let (missing, _) = missing_blobs(&config, &db, did, None, 10).await.unwrap();
+        assert_eq!(cids(&missing), ["c", "d"]);
+
+        db.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn conditional_races() {
+        const N: usize = 8;
+
+        let did = "did:plc:alice";
+        let TestAccount {
+            dir,
+            config,
+            db,
+            skey,
+            cids,
+        } = test_account(
+            did,
+            &[("app.bsky.feed.post/3jzfcijpj2z2a", json!({ "text": "0" }))],
+        )
+        .await;
+        let original = cids[0];
+
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+        let events = EventBus::new();
+        let policies = Policies::new(&PolicyConfig::default(), Vec::new());
+        let limiter = WriteLimiter::new(&config.rate_limit);
+        let stats = StorageStats::new(None, client.clone(), db.clone());
+        let integrity = RepoIntegrity::new(
+            config.repo.clone(),
+            db.clone(),
+            client.clone(),
+            skey.clone(),
+        );
+        let locks = RepoLocks::default();
+
+        let put = |rkey: &str, i: usize, swap: serde_json::Value| {
+            let input = json!({
+                "repo": did,
+                "collection": "app.bsky.feed.post",
+                "rkey": rkey,
+                "record": { "text": i.to_string() },
+                "swapRecord": swap,
+            });
+            let (skey, config, db, events, policies, limiter, client, stats, integrity, locks) = (
+                skey.clone(),
+                config.clone(),
+                db.clone(),
+                events.clone(),
+                policies.clone(),
+                limiter.clone(),
+                client.clone(),
+                stats.clone(),
+                integrity.clone(),
+                locks.clone(),
+            );
+            tokio::spawn(async move {
+                put_record(
+                    AuthenticatedUser::for_test(did),
+                    State(skey),
+                    State(config),
+                    State(db),
+                    State(events),
+                    State(policies),
+                    State(limiter),
+                    State(client),
+                    State(stats),
+                    State(integrity),
+                    State(locks),
+                    Json(input),
+                )
+                .await
+                .map(|r| r.cid.clone())
+            })
+        };
+
+        // Writers racing to update the record from the same CID, and to create a new one: exactly
+        // one of each wins, and the others learn what the winner wrote.
+        let updates = (0..N)
+            .map(|i| put("3jzfcijpj2z2a", i, json!(original.to_string())))
+            .collect::<Vec<_>>();
+        let creates = (0..N)
+            .map(|i| put("3jzfcijpj2z2b", i, serde_json::Value::Null))
+            .collect::<Vec<_>>();
+
+        for tasks in [updates, creates] {
+            let mut winners = Vec::new();
+            let mut losers = Vec::new();
+            for task in tasks {
+                match task.await.unwrap() {
+                    Ok(cid) => winners.push(cid.as_ref().to_string()),
+                    Err(e) => {
+                        let body = error_body(e).await;
+                        assert_eq!(body["error"], "InvalidSwap");
+                        losers.push(body["message"].as_str().unwrap().to_string());
+                    }
+                }
+            }
+
+            assert_eq!(winners.len(), 1);
+            assert_eq!(losers.len(), N - 1);
+            assert!(losers.iter().all(|m| m.contains(&winners[0])));
+        }
+
+        // Only the winners were charged for their writes.
+        let budget = limiter.budget(did).hourly;
+        assert_eq!(
+            budget.limit - budget.remaining,
+            ratelimit::POINTS_UPDATE + ratelimit::POINTS_CREATE
+        );
+
+        db.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
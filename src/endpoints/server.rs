@@ -1,10 +1,13 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{collections::HashMap, net::IpAddr, path::PathBuf, str::FromStr, sync::Arc};
 
 use anyhow::{anyhow, Context};
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use atrium_api::{
     com::atproto::server,
-    types::string::{Datetime, Did, Handle, Tid},
+    types::{
+        string::{Datetime, Did, Handle, Tid},
+        TryIntoUnknown,
+    },
 };
 use atrium_crypto::keypair::Did as _;
 use atrium_repo::{
@@ -12,132 +15,200 @@ use atrium_repo::{
     Cid, Repository,
 };
 use axum::{
-    extract::{Query, Request, State},
-    http::StatusCode,
+    extract::{DefaultBodyLimit, Query, State},
+    http::{HeaderMap, HeaderName, StatusCode},
     routing::{get, post},
-    Json, Router,
+    Json,
 };
 use constcat::concat;
 use metrics::counter;
 use rand::Rng;
 use sha2::Digest;
-use uuid::Uuid;
+use tracing::warn;
+use url::Url;
 
 use crate::{
-    auth::{self, AuthenticatedUser},
+    auth::{self, AdminUser, AuthenticatedUser, Revocations},
+    capabilities::Routes,
     config::AppConfig,
-    firehose::{Commit, FirehoseProducer},
-    metrics::AUTH_FAILED,
+    deletion,
+    did::{self, DidCache, DidDocument},
+    error::ErrorMessage,
+    events::{self, Event, EventBus},
+    firehose::{self, Commit, RepoOp},
+    handle,
+    host::RequestHost,
+    import,
+    integrity::RepoIntegrity,
+    invites, keys,
+    mail::{self, Mailer, Purpose},
+    metrics::{AUTH_FAILED, AUTH_LOGIN_FAILED},
+    password,
     plc::{self, PlcOperation, PlcService},
-    AppState, Client, Db, Error, Result, RotationKey, SigningKey,
+    ratelimit::{ClientIp, LoginLimiter, SignupLimiter},
+    signup_queue,
+    status::{self, AccountStatus},
+    storage, tiering, Client, Db, Error, Result, RotationKey, SigningKey,
 };
 
+use super::MAX_JSON_BODY;
+
 /// This is a dummy password that can be used in absence of a real password.
 const DUMMY_PASSWORD: &str = "$argon2id$v=19$m=19456,t=2,p=1$En2LAfHjeO0SZD5IUU1Abg$RpS8nHhhqY4qco2uyd41p9Y/1C+Lvi214MAWukzKQMI";
 
 async fn create_invite_code(
-    _user: AuthenticatedUser,
+    _admin: AdminUser,
     State(db): State<Db>,
     Json(input): Json<server::create_invite_code::Input>,
 ) -> Result<Json<server::create_invite_code::Output>> {
-    let uuid = Uuid::new_v4().to_string();
     let did = input.for_account.as_deref();
-    let count = std::cmp::min(input.use_count, 100); // Maximum of 100 uses for any code.
+    let codes = invites::create(&db, did, invites::ADMIN, input.use_count, 1).await?;
 
-    if count <= 0 {
-        return Err(anyhow!("use_count must be greater than 0").into());
-    }
+    Ok(Json(
+        server::create_invite_code::OutputData {
+            code: codes.into_iter().next().context("no invite code created")?,
+        }
+        .into(),
+    ))
+}
 
-    let r: String = sqlx::query_scalar!(
-        r#"
-        INSERT INTO invites (id, did, count, created_at)
-            VALUES (?, ?, ?, datetime('now'))
-            RETURNING id
-        "#,
-        uuid,
-        did,
-        count,
-    )
-    .fetch_one(&db)
-    .await
-    .context("failed to create new invite code")?;
+async fn create_invite_codes(
+    _admin: AdminUser,
+    State(db): State<Db>,
+    Json(input): Json<server::create_invite_codes::Input>,
+) -> Result<Json<server::create_invite_codes::Output>> {
+    let n = usize::try_from(input.code_count)
+        .ok()
+        .filter(|n| (1..=100).contains(n))
+        .ok_or_else(|| {
+            Error::with_message(
+                StatusCode::BAD_REQUEST,
+                anyhow!("invalid invite code count {}", input.code_count),
+                ErrorMessage::new("InvalidRequest", "codeCount must be between 1 and 100"),
+            )
+        })?;
+
+    let accounts = match &input.for_accounts {
+        Some(accounts) if !accounts.is_empty() => {
+            accounts.iter().map(|did| Some(did.as_str())).collect()
+        }
+        _ => vec![None],
+    };
+
+    let mut codes = Vec::with_capacity(accounts.len());
+    for account in accounts {
+        codes.push(
+            server::create_invite_codes::AccountCodesData {
+                account: account.unwrap_or(invites::ADMIN).to_string(),
+                codes: invites::create(&db, account, invites::ADMIN, input.use_count, n).await?,
+            }
+            .into(),
+        );
+    }
 
     Ok(Json(
-        server::create_invite_code::OutputData { code: r }.into(),
+        server::create_invite_codes::OutputData { codes }.into(),
     ))
 }
 
-async fn create_account(
-    State(db): State<Db>,
-    State(skey): State<SigningKey>,
-    State(rkey): State<RotationKey>,
-    State(client): State<Client>,
+/// List the account's invite codes, first creating those it has earned (see `invites.interval`).
+async fn get_account_invite_codes(
+    user: AuthenticatedUser,
     State(config): State<AppConfig>,
-    State(fhp): State<FirehoseProducer>,
-    Json(input): Json<server::create_account::Input>,
-) -> Result<Json<server::create_account::Output>> {
-    let email = input.email.as_deref().context("no email provided")?;
-    let pass = input.password.as_deref().context("no password provided")?;
-
-    // TODO: Handle the account migration flow.
-    // Users will hit this endpoint with a service-level authentication token.
-    //
-    // https://github.com/bluesky-social/pds/blob/main/ACCOUNT_MIGRATION.md
+    State(db): State<Db>,
+    Query(input): Query<server::get_account_invite_codes::ParametersData>,
+) -> Result<Json<server::get_account_invite_codes::Output>> {
+    let did = user.did();
 
-    // TODO: `input.plc_op`
-    if input.plc_op.is_some() {
-        return Err(Error::unimplemented(anyhow!("plc_op")));
+    if input.create_available.unwrap_or(true) {
+        invites::create_available(&config.invites, &db, &did).await?;
     }
+    let codes = invites::list(&db, &did, input.include_used.unwrap_or(true)).await?;
 
-    let recovery_keys = if let Some(key) = &input.recovery_key {
-        // Ensure the provided recovery key is valid.
-        if let Err(e) = atrium_crypto::did::parse_did_key(&key) {
-            return Err(Error::with_status(
-                StatusCode::BAD_REQUEST,
-                anyhow::Error::new(e).context("provided recovery key is in invalid format"),
-            ));
-        }
+    Ok(Json(
+        server::get_account_invite_codes::OutputData { codes }.into(),
+    ))
+}
 
-        // Enroll the user-provided recovery key at a higher priority than our own.
-        vec![key.clone(), rkey.did().to_string()]
-    } else {
-        vec![rkey.did().to_string()]
-    };
+/// A newly created account. Its commits are queued for the firehose, but its identity is not yet
+/// announced.
+struct NewAccount {
+    did: String,
+    /// The head commit of the new repository.
+    cid: Cid,
+    rev: Tid,
+    /// The commits made to the new repository, in order.
+    commits: Vec<Commit>,
+    status: AccountStatus,
+}
 
-    // Begin a new transaction to actually create the user's profile.
-    // Unless committed, the transaction will be automatically rolled back.
-    let mut tx = db.begin().await.context("failed to begin transaction")?;
+/// Files created on behalf of an account that is not yet committed to the database.
+/// Unless kept, they are removed when this is dropped.
+struct StagedFiles(Vec<PathBuf>);
 
-    let _invite = match &input.invite_code {
-        Some(code) => {
-            let invite: Option<String> = sqlx::query_scalar!(
-                r#"
-                UPDATE invites
-                    SET count = count - 1
-                    WHERE id = ?
-                    AND count > 0
-                    RETURNING id
-                "#,
-                code
-            )
-            .fetch_optional(&mut *tx)
-            .await
-            .context("failed to check invite code")?;
+impl StagedFiles {
+    /// Keep the staged files, now that the account owning them is committed.
+    fn keep(mut self) {
+        self.0.clear();
+    }
+}
 
-            invite.context("invalid invite code")?
+impl Drop for StagedFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("failed to remove {}: {e}", path.display());
+            }
         }
-        None => {
-            return Err(anyhow!("invite code required").into());
+    }
+}
+
+/// Map a constraint violation upon inserting a new account to the appropriate XRPC error.
+fn account_conflict(e: sqlx::Error, handle: &str) -> Error {
+    if let sqlx::Error::Database(db_err) = &e {
+        if db_err.is_unique_violation() {
+            let msg = db_err.message();
+
+            // The DID is derived from the handle and keys, so two signups for the same handle
+            // may collide on the DID before they collide on the handle.
+            if msg.contains("handles.handle") || msg.contains("accounts.did") {
+                return Error::with_message(
+                    StatusCode::BAD_REQUEST,
+                    anyhow!("handle {handle} is already taken"),
+                    ErrorMessage::new("HandleNotAvailable", "handle already taken"),
+                );
+            }
+            if msg.contains("accounts.email") {
+                return Error::with_message(
+                    StatusCode::BAD_REQUEST,
+                    anyhow!("email is already taken"),
+                    ErrorMessage::new("InvalidRequest", "email already taken"),
+                );
+            }
         }
-    };
+    }
+
+    anyhow::Error::new(e)
+        .context("failed to create new account")
+        .into()
+}
 
-    // Account can be created. Synthesize a new DID for the user.
-    // https://github.com/did-method-plc/did-method-plc?tab=readme-ov-file#did-creation
+/// Create and sign the genesis operation of a new `did:plc` identity. Returns the DID, the
+/// operation and its encoding.
+///
+/// https://github.com/did-method-plc/did-method-plc?tab=readme-ov-file#did-creation
+async fn genesis_op(
+    skey: &SigningKey,
+    rkey: &RotationKey,
+    config: &AppConfig,
+    handle: &str,
+    recovery_keys: Vec<String>,
+) -> Result<(String, plc::SignedPlcOperation, Vec<u8>)> {
     let op = PlcOperation {
         typ: "plc_operation".to_string(),
         rotation_keys: recovery_keys,
         verification_methods: HashMap::from([("atproto".to_string(), skey.did().to_string())]),
-        also_known_as: vec![format!("at://{}", input.handle.as_str())],
+        also_known_as: vec![format!("at://{}", handle)],
         services: HashMap::from([(
             "atproto_pds".to_string(),
             PlcService::Pds {
@@ -147,7 +218,7 @@ async fn create_account(
         prev: None,
     };
 
-    let op = plc::sign_op(&rkey, op)
+    let op = plc::sign_op(rkey, op)
         .await
         .context("failed to sign genesis op")?;
     let op_bytes = serde_ipld_dagcbor::to_vec(&op).context("failed to encode genesis op")?;
@@ -157,43 +228,266 @@ async fn create_account(
         sha2::Sha256::digest(&op_bytes).as_slice(),
     );
 
-    let did_hash = &digest[..24];
-    let did = format!("did:plc:{}", did_hash);
+    Ok((format!("did:plc:{}", &digest[..24]), op, op_bytes))
+}
 
-    let doc = tokio::fs::File::create(config.plc.path.join(format!("{}.car", did_hash)))
-        .await
-        .context("failed to create did doc")?;
+/// Create a new account and its repository.
+///
+/// If the input names an existing `did:plc` (i.e. the account is migrating here), the caller must
+/// have verified that the DID's owner asked for it. No identity is created for it; the account
+/// starts out deactivated, with an empty repository, until the migration completes.
+///
+/// If the input names a `did:web`, its owner manages the identity: its document must already name
+/// this PDS, and a signing key reserved for it here. The PLC directory is not involved.
+///
+/// The handle (and DID) are reserved in the database before any files are created, and the
+/// reservation is held until the account is committed. Of several concurrent signups for the same
+/// handle, exactly one succeeds; the rest fail with `HandleNotAvailable` and leave nothing behind.
+async fn insert_account(
+    db: &Db,
+    skey: &SigningKey,
+    rkey: &RotationKey,
+    client: &Client,
+    config: &AppConfig,
+    input: &server::create_account::Input,
+) -> Result<NewAccount> {
+    let email = input.email.as_deref().ok_or_else(|| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("no email provided"),
+            ErrorMessage::new("InvalidRequest", "email is required"),
+        )
+    })?;
+    let pass = input.password.as_deref().ok_or_else(|| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("no password provided"),
+            ErrorMessage::new("InvalidPassword", "password is required"),
+        )
+    })?;
+    let handle = handle::validate_local(config, input.handle.as_str())?;
+    password::check(
+        &config.password,
+        pass,
+        password::Account {
+            handle: Some(&handle),
+            email: Some(email),
+        },
+    )?;
 
-    let mut plc_doc = CarStore::create(doc)
-        .await
-        .context("failed to create did doc")?;
+    // TODO: `input.plc_op`
+    if input.plc_op.is_some() {
+        return Err(Error::unimplemented(anyhow!("plc_op")));
+    }
 
-    let plc_cid = plc_doc
-        .write_block(DAG_CBOR, SHA2_256, &op_bytes)
-        .await
-        .context("failed to write genesis commit")?;
+    let recovery_keys = if let Some(key) = &input.recovery_key {
+        // Ensure the provided recovery key is valid.
+        if let Err(e) = atrium_crypto::did::parse_did_key(&key) {
+            return Err(Error::with_status(
+                StatusCode::BAD_REQUEST,
+                anyhow::Error::new(e).context("provided recovery key is in invalid format"),
+            ));
+        }
 
-    if !config.test {
-        // Send the new account's data to the PLC directory.
-        plc::submit(&client, &did, &op)
-            .await
-            .context("failed to submit PLC operation to directory")?;
+        // Enroll the user-provided recovery key at a higher priority than our own.
+        vec![key.clone(), rkey.did().to_string()]
+    } else {
+        vec![rkey.did().to_string()]
+    };
+
+    // Fail early if the handle is obviously taken. This is only a hint; the reservation below is
+    // what guarantees uniqueness.
+    let taken: Option<String> = sqlx::query_scalar(r#"SELECT did FROM handles WHERE handle = ?"#)
+        .bind(&handle)
+        .fetch_optional(db)
+        .await
+        .context("failed to query handle")?;
+    if taken.is_some() {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("handle {handle} is already taken"),
+            ErrorMessage::new("HandleNotAvailable", "handle already taken"),
+        ));
+    }
+    let taken: Option<String> = sqlx::query_scalar(r#"SELECT did FROM accounts WHERE email = ?"#)
+        .bind(email)
+        .fetch_optional(db)
+        .await
+        .context("failed to query email")?;
+    if taken.is_some() {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("email is already taken"),
+            ErrorMessage::new("InvalidRequest", "email already taken"),
+        ));
     }
 
-    // Now hash the user's password.
+    // Hash the user's password before taking any locks.
     let salt = SaltString::generate(&mut rand::thread_rng());
     let pass = Argon2::default()
         .hash_password(pass.as_bytes(), salt.as_salt())
         .context("failed to hash password")?
         .to_string();
-    let handle = input.handle.as_str().to_owned();
+
+    // Synthesize a new DID for the user, unless they're bringing their own.
+    let (did, genesis) = match &input.did {
+        Some(did) => {
+            if !["did:plc", "did:web"].contains(&did.method()) {
+                return Err(Error::with_message(
+                    StatusCode::BAD_REQUEST,
+                    anyhow!("unsupported DID method for {}", did.as_str()),
+                    ErrorMessage::new(
+                        "InvalidRequest",
+                        "only did:plc and did:web accounts are supported",
+                    ),
+                ));
+            }
+
+            (did.as_str().to_owned(), None)
+        }
+        None => {
+            let (did, op, op_bytes) =
+                genesis_op(skey, rkey, config, &handle, recovery_keys).await?;
+            (did, Some((op, op_bytes)))
+        }
+    };
+    let did_hash = storage::file_id(&did).map_err(|e| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            e,
+            ErrorMessage::new("InvalidRequest", "unsupported DID"),
+        )
+    })?;
+
+    // A did:web identity is managed by its owner, not migrated: it's hosted here from the start.
+    let web = did.starts_with("did:web:");
+    let migrating = genesis.is_none() && !web;
+
+    // A self-hosted identity must name a key reserved for it here, which is how its owner proves
+    // that they asked for it. A migrating account may have moved its DID document to one, too.
+    let reserved_key = if web {
+        Some(web_identity_key(client, config, &did).await?)
+    } else if migrating && keys::has_reservation(db, &did).await? {
+        let doc = did::resolve(client, Did::new(did.clone()).unwrap())
+            .await
+            .context("failed to resolve DID document")?;
+        doc.signing_key()
+    } else {
+        None
+    };
+
+    // Begin a new transaction to actually create the user's profile.
+    // Unless committed, the transaction will be automatically rolled back.
+    //
+    // The first write below takes the database's write lock, which is held until the transaction
+    // completes, so concurrent signups are serialized from here on.
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+
+    if config.invites.required {
+        let Some(code) = &input.invite_code else {
+            return Err(Error::with_message(
+                StatusCode::BAD_REQUEST,
+                anyhow!("invite code required"),
+                ErrorMessage::new("InvalidInviteCode", "invite code required"),
+            ));
+        };
+        invites::redeem(&mut tx, code, &did).await?;
+    }
+
+    // A migrating account remains deactivated until its migration completes, and a new account
+    // until its turn in the signup queue, if any.
+    let status = if migrating || config.signup_queue.is_some() {
+        AccountStatus::Deactivated
+    } else {
+        AccountStatus::Active
+    };
+
+    // Reserve the DID and handle. The repository is filled in once it has been created.
+    sqlx::query(
+        r#"
+        INSERT INTO accounts (did, email, password, root, plc_root, rev, status, created_at)
+            VALUES (?, ?, ?, '', '', '', ?, datetime('now'))
+        "#,
+    )
+    .bind(&did)
+    .bind(email)
+    .bind(&pass)
+    .bind(status.as_str())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| account_conflict(e, &handle))?;
+    if !migrating && config.signup_queue.is_some() {
+        signup_queue::enqueue(&mut tx, &did).await?;
+    }
+
+    sqlx::query(r#"INSERT INTO handles (did, handle, created_at) VALUES (?, ?, datetime('now'))"#)
+        .bind(&did)
+        .bind(&handle)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| account_conflict(e, &handle))?;
+
+    let skey = match &reserved_key {
+        Some(key) => match keys::claim(&mut tx, &did, key).await? {
+            Some(key) => key,
+            None if web => {
+                return Err(Error::with_message(
+                    StatusCode::BAD_REQUEST,
+                    anyhow!("signing key {key} of {did} is not reserved for it"),
+                    ErrorMessage::new(
+                        "InvalidRequest",
+                        "the DID document's signing key must be one reserved for it here \
+                         (see com.atproto.server.reserveSigningKey)",
+                    ),
+                ))
+            }
+            None => skey.clone(),
+        },
+        None => skey.clone(),
+    };
+
+    // The reservation is certain (as long as the transaction commits), so no other account owns
+    // these files. Anything left at these paths is debris from an earlier failed signup.
+    let plc_path = config.plc.path.join(format!("{}.car", did_hash));
+    let repo_path = storage::repo_path(&config.repo, &did)?;
+    let mut staged = StagedFiles(Vec::new());
+
+    // A migrating identity is still managed by its previous host, and a did:web identity by its
+    // owner, so there's no log to keep.
+    let plc_cid = match &genesis {
+        Some((_, op_bytes)) => {
+            let doc = tokio::fs::File::create(&plc_path)
+                .await
+                .context("failed to create did doc")?;
+            staged.0.push(plc_path);
+
+            let mut plc_doc = CarStore::create(doc)
+                .await
+                .context("failed to create did doc")?;
+
+            plc_doc
+                .write_block(DAG_CBOR, SHA2_256, op_bytes)
+                .await
+                .context("failed to write genesis commit")?
+                .to_string()
+        }
+        None => String::new(),
+    };
 
     // Write out an initial commit for the user.
     // https://atproto.com/guides/account-lifecycle
-    let (cid, rev, store) = async {
-        let file = tokio::fs::File::create_new(config.repo.path.join(format!("{}.car", did_hash)))
-            .await
-            .context("failed to create repo file")?;
+    // The store is read back when the repository is exported, and an existing repository must not
+    // be clobbered.
+    let file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&repo_path)
+        .await
+        .context("failed to create repo file")?;
+    staged.0.push(repo_path);
+
+    let (cid, rev, commits) = async {
         let mut store = CarStore::create(file)
             .await
             .context("failed to create carstore")?;
@@ -224,130 +518,448 @@ async fn create_account(
             .await
             .context("failed to export repository")?;
 
-        Ok::<(Cid, Tid, Vec<u8>), anyhow::Error>((root, rev, mem))
+        let mut commits = vec![Commit {
+            car: mem,
+            ops: Vec::new(),
+            cid: root,
+            rev: rev.to_string(),
+            did: Did::from_str(&did).unwrap(),
+            pcid: None,
+            since: None,
+            blobs: Vec::new(),
+        }];
+
+        if config.repo.create_profile {
+            // Follow the genesis commit with a default profile, so that clients can immediately
+            // update it in place (i.e. via `putRecord` on `app.bsky.actor.profile/self`).
+            let key = "app.bsky.actor.profile/self";
+            let profile = serde_json::json!({
+                "$type": "app.bsky.actor.profile",
+                "displayName": handle.chars().take(64).collect::<String>(),
+            });
+
+            let (builder, record) = repo
+                .add_raw(key, &profile)
+                .await
+                .context("failed to add profile")?;
+            let sig = skey
+                .sign(&builder.bytes())
+                .context("failed to sign profile commit")?;
+            builder
+                .finalize(sig)
+                .await
+                .context("failed to write profile commit")?;
+
+            let mut mem = Vec::new();
+            let mut firehose_store =
+                CarStore::create_with_roots(std::io::Cursor::new(&mut mem), [repo.root()])
+                    .await
+                    .context("failed to create temp carstore")?;
+            repo.extract_raw_into(key, &mut firehose_store)
+                .await
+                .context("failed to extract profile")?;
+
+            commits.push(Commit {
+                car: mem,
+                ops: vec![RepoOp::Create {
+                    cid: record,
+                    path: key.to_string(),
+                }],
+                cid: repo.root(),
+                rev: repo.commit().rev().to_string(),
+                did: Did::from_str(&did).unwrap(),
+                pcid: Some(root),
+                since: Some(rev.to_string()),
+                blobs: Vec::new(),
+            });
+        }
+
+        Ok::<(Cid, Tid, Vec<Commit>), anyhow::Error>((repo.root(), repo.commit().rev(), commits))
     }
     .await
     .context("failed to create user repo")?;
 
-    let plc_cid = plc_cid.to_string();
     let cid_str = cid.to_string();
     let rev_str = rev.as_str();
 
-    sqlx::query!(
-        r#"
-        INSERT INTO accounts (did, email, password, root, plc_root, rev, created_at)
-            VALUES (?, ?, ?, ?, ?, ?, datetime('now'));
+    sqlx::query(r#"UPDATE accounts SET root = ?, plc_root = ?, rev = ? WHERE did = ?"#)
+        .bind(&cid_str)
+        .bind(&plc_cid)
+        .bind(rev_str)
+        .bind(&did)
+        .execute(&mut *tx)
+        .await
+        .context("failed to create new account")?;
 
-        INSERT INTO handles (did, handle, created_at)
-            VALUES (?, ?, datetime('now'));
+    // The repository's commits are sequenced with the account, so that they're broadcast even if we
+    // crash once the transaction has committed.
+    for commit in &commits {
+        firehose::enqueue_commit(&mut tx, &config.firehose, commit).await?;
+    }
 
-        -- Cleanup stale invite codes
-        DELETE FROM invites
-            WHERE count <= 0;
-        "#,
-        did,
-        email,
-        pass,
-        cid_str,
-        plc_cid,
-        rev_str,
-        did,
-        handle
-    )
-    .execute(&mut *tx)
-    .await
-    .context("failed to create new account")?;
+    if let Some((op, _)) = &genesis {
+        if !config.test {
+            // Send the new account's data to the PLC directory.
+            plc::submit(client, &did, op)
+                .await
+                .context("failed to submit PLC operation to directory")?;
+        }
+    }
 
     // The account is fully created. Commit the SQL transaction to the database.
     tx.commit().await.context("failed to commit transaction")?;
+    staged.keep();
 
-    // Broadcast the identity event now that the new identity is resolvable on the public directory.
-    fhp.identity(
-        atrium_api::com::atproto::sync::subscribe_repos::IdentityData {
-            did: Did::from_str(&did).unwrap(),
-            handle: Some(Handle::new(handle).unwrap()),
-            seq: 0, // Filled by firehose later.
-            time: Datetime::now(),
-        },
-    )
-    .await;
+    Ok(NewAccount {
+        did,
+        cid,
+        rev,
+        commits,
+        status,
+    })
+}
 
-    // The new account is now active on this PDS, so we can broadcast the account firehose event.
-    fhp.account(
-        atrium_api::com::atproto::sync::subscribe_repos::AccountData {
-            active: true,
-            did: Did::from_str(&did).unwrap(),
-            seq: 0,       // Filled by firehose later.
-            status: None, // "takedown" / "suspended" / "deactivated"
-            time: Datetime::now(),
-        },
-    )
-    .await;
+/// Check that a `did:web` identity is ready to be hosted here: its document must name this PDS as
+/// the account's PDS, and a signing key. Returns the signing key, which the caller must check was
+/// reserved for the DID here.
+async fn web_identity_key(client: &Client, config: &AppConfig, did: &str) -> Result<String> {
+    let invalid = |e: anyhow::Error, msg: &str| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            e,
+            ErrorMessage::new("InvalidRequest", msg),
+        )
+    };
 
-    let did = Did::from_str(&did).unwrap();
+    let doc = did::resolve(client, Did::new(did.to_string()).unwrap())
+        .await
+        .map_err(|e| invalid(e, "failed to fetch the DID document"))?;
+
+    let pds = doc.pds().and_then(|u| u.host_str());
+    if pds != Some(config.host_name.as_str()) {
+        return Err(invalid(
+            anyhow!("DID document of {did} names PDS {pds:?}"),
+            &format!(
+                "the DID document must name https://{} as the account's PDS",
+                config.host_name
+            ),
+        ));
+    }
 
-    fhp.commit(Commit {
-        car: store,
-        ops: Vec::new(),
-        cid: cid,
-        rev: rev.to_string(),
-        did: did.clone(),
-        pcid: None,
-        blobs: Vec::new(),
+    doc.signing_key().ok_or_else(|| {
+        invalid(
+            anyhow!("DID document of {did} has no signing key"),
+            "the DID document must name a signing key",
+        )
     })
-    .await;
+}
 
-    // Finally, sign some authentication tokens for the new user.
-    let token = auth::sign(
-        &skey,
-        "at+jwt",
-        serde_json::json!({
-            "iss": did.clone(),
-            "aud": format!("did:web:{}", config.host_name),
-            "exp": (chrono::Utc::now() + std::time::Duration::from_secs(2 * 60 * 60)).timestamp()
-        }),
-    )
-    .context("failed to sign jwt")?;
+/// Verify that the owner of an existing DID asked to create an account for it here, with a service
+/// auth token signed by the DID's current signing key.
+///
+/// https://github.com/bluesky-social/pds/blob/main/ACCOUNT_MIGRATION.md
+async fn verify_migration(
+    headers: &HeaderMap,
+    cache: &DidCache,
+    client: &Client,
+    config: &AppConfig,
+    db: &Db,
+    did: &Did,
+) -> Result<()> {
+    let invalid = |e: anyhow::Error| {
+        counter!(AUTH_FAILED).increment(1);
+        Error::with_message(
+            StatusCode::UNAUTHORIZED,
+            e.context(format!(
+                "failed to authorize account creation for {}",
+                did.as_str()
+            )),
+            ErrorMessage::new(
+                "InvalidToken",
+                "a valid service auth token for the DID is required",
+            ),
+        )
+    };
 
-    let refresh_token = auth::sign(
-        &skey,
-        "refresh+jwt",
-        serde_json::json!({
-            "iss": did.clone(),
-            "aud": format!("did:web:{}", config.host_name),
-            "exp": (chrono::Utc::now() + std::time::Duration::from_secs(2 * 60 * 60)).timestamp()
-        }),
-    )
-    .context("failed to sign refresh jwt")?;
+    let token = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or_else(|| invalid(anyhow!("no service auth token")))?;
+
+    let doc = cache
+        .resolve(client, config, db, did.clone(), false)
+        .await
+        .context("failed to resolve DID")?
+        .doc;
+    let _claims =
+        auth::verify_service_auth(&doc, token, &config.did(), server::create_account::NSID)
+            .map_err(invalid)?;
+
+    Ok(())
+}
+
+async fn create_account(
+    State(db): State<Db>,
+    State(skey): State<SigningKey>,
+    State(rkey): State<RotationKey>,
+    State(client): State<Client>,
+    State(config): State<AppConfig>,
+    State(events): State<EventBus>,
+    State(cache): State<DidCache>,
+    State(limiter): State<SignupLimiter>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Json(input): Json<server::create_account::Input>,
+) -> Result<Json<server::create_account::Output>> {
+    limiter.consume(ip)?;
+
+    // A did:web identity is verified through its document instead (see `insert_account`).
+    if let Some(did) = input.did.as_ref().filter(|d| d.method() == "did:plc") {
+        verify_migration(&headers, &cache, &client, &config, &db, did).await?;
+    }
+
+    let NewAccount {
+        did,
+        commits,
+        status,
+        ..
+    } = insert_account(&db, &skey, &rkey, &client, &config, &input).await?;
+    let handle = input.handle.as_str().to_lowercase();
+
+    // Broadcast the identity event now that the new identity is resolvable on the public directory.
+    events
+        .publish(Event::Identity(
+            atrium_api::com::atproto::sync::subscribe_repos::IdentityData {
+                did: Did::from_str(&did).unwrap(),
+                handle: Some(Handle::new(handle).unwrap()),
+                seq: 0, // Filled by firehose later.
+                time: Datetime::now(),
+            },
+        ))
+        .await;
+
+    // A new account is now hosted on this PDS, so we can broadcast the account firehose event.
+    events
+        .publish(Event::Account(status::account_event(&did, status)?))
+        .await;
+
+    let did = Did::from_str(&did).unwrap();
+
+    for commit in commits {
+        events.publish(Event::Commit(Arc::new(commit))).await;
+    }
+
+    // Finally, start a session for the new user.
+    let mut conn = db.acquire().await.context("failed to acquire connection")?;
+    let tokens = auth::issue_session(&mut conn, &skey, &config, did.as_str(), None).await?;
 
     Ok(Json(
         server::create_account::OutputData {
-            access_jwt: token,
+            access_jwt: tokens.access,
             did,
             did_doc: None,
             handle: input.handle.clone(),
-            refresh_jwt: refresh_token,
+            refresh_jwt: tokens.refresh,
         }
         .into(),
     ))
 }
 
-async fn create_session(
+/// Find the app password of an account that matches a password, if any.
+async fn verify_app_password(
+    db: &Db,
+    did: &str,
+    password: &str,
+) -> Result<Option<auth::AppPassword>> {
+    let candidates: Vec<(String, String, bool)> =
+        sqlx::query_as(r#"SELECT name, password, privileged FROM app_passwords WHERE did = ?"#)
+            .bind(did)
+            .fetch_all(db)
+            .await
+            .context("failed to query app passwords")?;
+
+    for (name, hash, privileged) in candidates {
+        let hash = PasswordHash::new(&hash).context("invalid app password hash in db")?;
+        if Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+        {
+            return Ok(Some(auth::AppPassword { name, privileged }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Generate an app password, in the `xxxx-xxxx-xxxx-xxxx` form clients recognize.
+fn generate_app_password() -> String {
+    const CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+    let mut rng = rand::thread_rng();
+    (0..4)
+        .map(|_| {
+            (0..4)
+                .map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char)
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+async fn create_app_password(
+    user: AuthenticatedUser,
     State(db): State<Db>,
-    State(skey): State<SigningKey>,
-    State(config): State<AppConfig>,
-    Json(input): Json<server::create_session::Input>,
-) -> Result<Json<server::create_session::Output>> {
-    let handle = &input.identifier;
-    let password = &input.password;
+    Json(input): Json<server::create_app_password::Input>,
+) -> Result<Json<server::create_app_password::Output>> {
+    user.require_full()?;
+
+    let name = input.name.trim();
+    if name.is_empty() {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("empty app password name"),
+            ErrorMessage::new("InvalidRequest", "app password name is required"),
+        ));
+    }
 
-    // TODO: `input.allow_takedown`
-    // TODO: `input.auth_factor_token`
+    let password = generate_app_password();
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), salt.as_salt())
+        .context("failed to hash app password")?
+        .to_string();
+    let privileged = input.privileged.unwrap_or(false);
+    let created_at = Datetime::now();
 
-    let account = sqlx::query!(
+    sqlx::query(
         r#"
-        WITH LatestHandles AS (
-            SELECT did, handle
+        INSERT INTO app_passwords (did, name, password, privileged, created_at)
+            VALUES (?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(user.did())
+    .bind(name)
+    .bind(&hash)
+    .bind(privileged)
+    .bind(created_at.as_str())
+    .execute(&db)
+    .await
+    .map_err(|e| match &e {
+        sqlx::Error::Database(db_err) if db_err.is_unique_violation() => Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("app password {name} already exists"),
+            ErrorMessage::new(
+                "InvalidRequest",
+                "an app password with this name already exists",
+            ),
+        ),
+        _ => anyhow::Error::new(e)
+            .context("failed to create app password")
+            .into(),
+    })?;
+
+    Ok(Json(
+        server::create_app_password::AppPasswordData {
+            created_at,
+            name: name.to_string(),
+            password,
+            privileged: Some(privileged),
+        }
+        .into(),
+    ))
+}
+
+async fn list_app_passwords(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+) -> Result<Json<server::list_app_passwords::Output>> {
+    let passwords: Vec<(String, bool, String)> = sqlx::query_as(
+        r#"SELECT name, privileged, created_at FROM app_passwords WHERE did = ? ORDER BY created_at"#,
+    )
+    .bind(user.did())
+    .fetch_all(&db)
+    .await
+    .context("failed to list app passwords")?;
+
+    let passwords = passwords
+        .into_iter()
+        .map(|(name, privileged, created_at)| {
+            Ok(server::list_app_passwords::AppPasswordData {
+                created_at: Datetime::from_str(&created_at)
+                    .map_err(|e| anyhow!("invalid app password timestamp: {e}"))?,
+                name,
+                privileged: Some(privileged),
+            }
+            .into())
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(Json(
+        server::list_app_passwords::OutputData { passwords }.into(),
+    ))
+}
+
+/// Delete an app password, ending the sessions created with it.
+async fn revoke_app_password(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    State(revocations): State<Revocations>,
+    Json(input): Json<server::revoke_app_password::Input>,
+) -> Result<()> {
+    user.require_full()?;
+    let did = user.did();
+
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+    sqlx::query(r#"DELETE FROM app_passwords WHERE did = ? AND name = ?"#)
+        .bind(&did)
+        .bind(&input.name)
+        .execute(&mut *tx)
+        .await
+        .context("failed to revoke app password")?;
+    let sessions: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT DISTINCT session FROM refresh_tokens
+            WHERE did = ? AND app_password = ? AND session IS NOT NULL
+        "#,
+    )
+    .bind(&did)
+    .bind(&input.name)
+    .fetch_all(&mut *tx)
+    .await
+    .context("failed to find app password sessions")?;
+    tx.commit().await.context("failed to commit transaction")?;
+
+    // The sessions' refresh tokens can't be rotated without the app password, but their access
+    // tokens remain valid until revoked.
+    for sid in sessions {
+        revocations.revoke(&db, &sid).await?;
+    }
+
+    Ok(())
+}
+
+async fn create_session(
+    State(db): State<Db>,
+    State(skey): State<SigningKey>,
+    State(config): State<AppConfig>,
+    State(mailer): State<Mailer>,
+    State(limiter): State<LoginLimiter>,
+    ClientIp(ip): ClientIp,
+    Json(input): Json<server::create_session::Input>,
+) -> Result<Json<server::create_session::Output>> {
+    let handle = &input.identifier;
+    let password = &input.password;
+
+    // Past too many failures, don't even check the password.
+    limiter.check(handle, ip)?;
+
+    // TODO: `input.allow_takedown`
+
+    let account = sqlx::query!(
+        r#"
+        WITH LatestHandles AS (
+            SELECT did, handle
             FROM handles
             WHERE (did, created_at) IN (
                 SELECT did, MAX(created_at) AS max_created_at
@@ -369,8 +981,6 @@ async fn create_session(
     let account = if let Some(account) = account {
         account
     } else {
-        counter!(AUTH_FAILED).increment(1);
-
         // SEC: Call argon2's `verify_password` to simulate password verification and discard the result.
         // We do this to avoid exposing a timing attack where attackers can measure the response time to
         // determine whether or not an account exists.
@@ -379,61 +989,47 @@ async fn create_session(
             &PasswordHash::new(DUMMY_PASSWORD).unwrap(),
         );
 
-        return Err(Error::with_status(
-            StatusCode::UNAUTHORIZED,
-            anyhow!("failed to validate credentials"),
-        ));
+        return Err(failed_sign_in(&limiter, handle, ip));
     };
 
-    match argon2::Argon2::default().verify_password(
+    let did = account.did;
+
+    // The account's password grants a full session; failing that, try its app passwords.
+    let app_password = match argon2::Argon2::default().verify_password(
         password.as_bytes(),
         &PasswordHash::new(account.password.as_str()).context("invalid password hash in db")?,
     ) {
-        Ok(_) => {}
-        Err(_e) => {
-            counter!(AUTH_FAILED).increment(1);
-
-            return Err(Error::with_status(
-                StatusCode::UNAUTHORIZED,
-                anyhow!("failed to validate credentials"),
-            ));
-        }
-    }
+        Ok(_) => None,
+        Err(_e) => match verify_app_password(&db, &did, password).await? {
+            Some(app_password) => Some(app_password),
+            None => return Err(failed_sign_in(&limiter, handle, ip)),
+        },
+    };
+    limiter.succeed(handle);
 
-    let did = account.did;
+    // App passwords bypass the email auth factor, as in the reference implementation: they're
+    // meant for clients that can't prompt for a token.
+    let email_auth_factor = match app_password {
+        Some(_) => false,
+        None => check_auth_factor(&db, &mailer, &did, input.auth_factor_token.as_deref()).await?,
+    };
 
-    let token = auth::sign(
-        &skey,
-        "at+jwt",
-        serde_json::json!({
-            "iss": did.clone(),
-            "aud": format!("did:web:{}", config.host_name),
-            "exp": (chrono::Utc::now() + std::time::Duration::from_secs(2 * 60 * 60)).timestamp()
-        }),
-    )
-    .context("failed to sign jwt")?;
+    tiering::touch(&db, &did).await?;
 
-    let refresh_token = auth::sign(
-        &skey,
-        "refresh+jwt",
-        serde_json::json!({
-            "iss": did.clone(),
-            "aud": format!("did:web:{}", config.host_name),
-            "exp": (chrono::Utc::now() + std::time::Duration::from_secs(2 * 60 * 60)).timestamp()
-        }),
-    )
-    .context("failed to sign refresh jwt")?;
+    let mut conn = db.acquire().await.context("failed to acquire connection")?;
+    let tokens =
+        auth::issue_session(&mut conn, &skey, &config, &did, app_password.as_ref()).await?;
 
     Ok(Json(
         server::create_session::OutputData {
-            access_jwt: token,
-            refresh_jwt: refresh_token,
+            access_jwt: tokens.access,
+            refresh_jwt: tokens.refresh,
 
             active: Some(true),
             did: Did::from_str(&did).unwrap(),
             did_doc: None,
             email: None,
-            email_auth_factor: None,
+            email_auth_factor: Some(email_auth_factor),
             email_confirmed: None,
             handle: Handle::new(account.handle).unwrap(),
             status: None,
@@ -442,35 +1038,94 @@ async fn create_session(
     ))
 }
 
+/// Record a failed sign-in, returning the error reported for it. An unknown identifier and a wrong
+/// password are reported identically, so as not to reveal which accounts exist.
+fn failed_sign_in(limiter: &LoginLimiter, identifier: &str, ip: IpAddr) -> Error {
+    counter!(AUTH_FAILED).increment(1);
+    counter!(AUTH_LOGIN_FAILED).increment(1);
+    limiter.fail(identifier, ip);
+
+    Error::with_message(
+        StatusCode::UNAUTHORIZED,
+        anyhow!("failed to validate credentials for {identifier}"),
+        ErrorMessage::new("AuthenticationRequired", "Invalid identifier or password"),
+    )
+}
+
+/// Check the email auth factor of an account signing in with its password, if it's enabled,
+/// returning whether it is. Without a token, one is emailed to the account, and the sign-in is
+/// rejected with `AuthFactorTokenRequired` so that the client prompts for it.
+async fn check_auth_factor(
+    db: &Db,
+    mailer: &Mailer,
+    did: &str,
+    token: Option<&str>,
+) -> Result<bool> {
+    let (email, enabled): (String, bool) =
+        sqlx::query_as(r#"SELECT email, email_auth_factor FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_one(db)
+            .await
+            .context("failed to query account")?;
+    if !enabled {
+        return Ok(false);
+    }
+
+    if let Some(token) = token.filter(|t| !t.trim().is_empty()) {
+        mail::consume_token(db, Purpose::SignIn, did, token).await?;
+        return Ok(true);
+    }
+
+    let token = mail::create_token(db, Purpose::SignIn, did).await?;
+    mailer
+        .send(
+            &email,
+            "Sign-in code",
+            &format!(
+                "To sign in to your account ({did}), enter the following code:\n\n\
+                 {token}\n\n\
+                 The code expires in {} minutes. If you didn't try to sign in, change your \
+                 password.",
+                mail::TOKEN_TTL / 60
+            ),
+        )
+        .await?;
+
+    Err(Error::with_message(
+        StatusCode::UNAUTHORIZED,
+        anyhow!("email auth factor token required for {did}"),
+        ErrorMessage::new(
+            "AuthFactorTokenRequired",
+            "a sign-in code has been sent to your email address",
+        ),
+    ))
+}
+
+/// The refresh token in a request's `Authorization` header.
+fn bearer_token(headers: &HeaderMap) -> Result<&str> {
+    headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        .ok_or_else(|| {
+            Error::with_message(
+                StatusCode::UNAUTHORIZED,
+                anyhow!("no refresh token provided"),
+                ErrorMessage::new("AuthenticationRequired", "refresh token required"),
+            )
+        })
+}
+
+/// Exchange a refresh token (in the `Authorization` header) for a new session. The refresh token
+/// can't be used again.
 async fn refresh_session(
     State(db): State<Db>,
     State(skey): State<SigningKey>,
     State(config): State<AppConfig>,
-    req: Request,
+    headers: HeaderMap,
 ) -> Result<Json<server::refresh_session::Output>> {
-    let auth = req
-        .headers()
-        .get(axum::http::header::AUTHORIZATION)
-        .context("no authorization header provided")?;
-    let token = auth
-        .to_str()
-        .ok()
-        .and_then(|auth| auth.strip_prefix("Bearer "))
-        .context("invalid authentication token")?;
-
-    let (typ, claims) =
-        auth::verify(&skey.did(), token).context("failed to verify refresh token")?;
-    if typ != "refresh+jwt" {
-        return Err(Error::with_status(
-            StatusCode::UNAUTHORIZED,
-            anyhow!("invalid refresh token"),
-        ));
-    }
-
-    let did = claims
-        .get("iss")
-        .and_then(|s| s.as_str())
-        .context("invalid jwt")?;
+    let token = bearer_token(&headers)?;
+    let (did, tokens) = auth::rotate_session(&db, &skey, &config, token).await?;
 
     let user = sqlx::query!(
         r#"
@@ -487,55 +1142,74 @@ async fn refresh_session(
     .await
     .context("failed to fetch user account")?;
 
-    let token = auth::sign(
-        &skey,
-        "at+jwt",
-        serde_json::json!({
-            "iss": did,
-            "aud": format!("did:web:{}", config.host_name),
-            "exp": (chrono::Utc::now() + std::time::Duration::from_secs(2 * 60 * 60)).timestamp()
-        }),
-    )
-    .context("failed to sign jwt")?;
-
-    let refresh_token = auth::sign(
-        &skey,
-        "refresh+jwt",
-        serde_json::json!({
-            "iss": format!("did:web:{}", config.host_name),
-            "aud": did,
-            "exp": (chrono::Utc::now() + std::time::Duration::from_secs(30 * 60 * 60)).timestamp()
-        }),
-    )
-    .context("failed to sign refresh jwt")?;
-
     let active = user.status == "active";
     let status = if active { None } else { Some(user.status) };
 
     Ok(Json(
         server::refresh_session::OutputData {
-            access_jwt: token,
-            refresh_jwt: refresh_token,
+            access_jwt: tokens.access,
+            refresh_jwt: tokens.refresh,
 
-            active: Some(active), // TODO?
-            did: atrium_api::types::string::Did::new(did.to_string()).unwrap(),
+            active: Some(active),
+            did: Did::new(did).unwrap(),
             did_doc: None,
-            handle: atrium_api::types::string::Handle::new(user.handle).unwrap(),
-            status: status,
+            handle: Handle::new(user.handle).unwrap(),
+            status,
         }
         .into(),
     ))
 }
 
-async fn get_service_auth(
-    user: AuthenticatedUser,
+/// End the session a refresh token (in the `Authorization` header) belongs to, revoking its
+/// refresh and access tokens.
+async fn delete_session(
+    State(db): State<Db>,
     State(skey): State<SigningKey>,
-    Query(input): Query<server::get_service_auth::ParametersData>,
-) -> Result<Json<server::get_service_auth::Output>> {
-    let user_did = user.did();
-    let aud = input.aud.as_str();
+    State(revocations): State<Revocations>,
+    headers: HeaderMap,
+) -> Result<()> {
+    let token = bearer_token(&headers)?;
+    auth::delete_session(&db, &revocations, &skey, token).await
+}
+
+/// The longest a service auth token may be valid for.
+const MAX_SERVICE_AUTH_TTL: i64 = 60 * 60;
+/// The longest a service auth token that isn't bound to a method may be valid for.
+const MAX_UNBOUND_SERVICE_AUTH_TTL: i64 = 60;
+
+/// Mint a service auth token, on behalf of an account, for another service (`aud`). The token may
+/// be bound to a method (`lxm`), and expires at `exp` (by default, in a minute).
+fn service_auth_token(
+    skey: &SigningKey,
+    did: &str,
+    aud: &str,
+    exp: Option<i64>,
+    lxm: Option<&str>,
+) -> Result<String> {
+    let now = chrono::Utc::now().timestamp();
+    let exp = exp.unwrap_or(now + MAX_UNBOUND_SERVICE_AUTH_TTL);
+
+    let bad_expiration = |msg: &str| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("bad service auth expiration {exp}: {msg}"),
+            ErrorMessage::new("BadExpiration", msg),
+        )
+    };
+    if exp <= now {
+        return Err(bad_expiration("expiration is in the past"));
+    }
+    if exp - now > MAX_SERVICE_AUTH_TTL {
+        return Err(bad_expiration(
+            "expiration may be at most an hour in the future",
+        ));
+    }
+    if lxm.is_none() && exp - now > MAX_UNBOUND_SERVICE_AUTH_TTL {
+        return Err(bad_expiration(
+            "expiration of a token without a method may be at most a minute in the future",
+        ));
+    }
 
-    let exp = (chrono::Utc::now() + std::time::Duration::from_secs(60)).timestamp();
     let jti = rand::thread_rng()
         .sample_iter(rand::distributions::Alphanumeric)
         .take(10)
@@ -543,102 +1217,1569 @@ async fn get_service_auth(
         .collect::<String>();
 
     let mut claims = serde_json::json!({
-        "iss": user_did.as_str(),
+        "iss": did,
         "aud": aud,
+        "iat": now,
         "exp": exp,
         "jti": jti,
     });
 
-    if let Some(lxm) = &input.lxm {
+    if let Some(lxm) = lxm {
         claims["lxm"] = serde_json::Value::String(lxm.to_string());
     }
 
-    // Mint a bearer token by signing a JSON web token.
-    let token = auth::sign(&skey, "JWT", claims).context("failed to sign jwt")?;
+    // Mint a bearer token by signing a JSON web token, with the key in the account's DID document.
+    let token = auth::sign(skey, "JWT", claims).context("failed to sign jwt")?;
+    Ok(token)
+}
+
+async fn get_service_auth(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    State(skey): State<SigningKey>,
+    Query(input): Query<server::get_service_auth::ParametersData>,
+) -> Result<Json<server::get_service_auth::Output>> {
+    let did = user.did();
+    let skey = keys::account_key(&db, &skey, &did).await?;
+    let token = service_auth_token(
+        &skey,
+        &did,
+        input.aud.as_str(),
+        input.exp,
+        input.lxm.as_ref().map(|lxm| lxm.as_str()),
+    )?;
 
     Ok(Json(server::get_service_auth::OutputData { token }.into()))
 }
 
 async fn get_session(
     user: AuthenticatedUser,
+    State(client): State<Client>,
+    State(config): State<AppConfig>,
     State(db): State<Db>,
+    State(cache): State<DidCache>,
 ) -> Result<Json<server::get_session::Output>> {
     let did = user.did();
 
-    let user = sqlx::query!(
+    // The DID document is a convenience for the client; the session stands without it.
+    let doc = match cache
+        .resolve(&client, &config, &db, Did::new(did.clone()).unwrap(), false)
+        .await
+    {
+        Ok(d) => Some(d.doc),
+        Err(e) => {
+            warn!("failed to resolve DID document for {did}: {e:?}");
+            None
+        }
+    };
+
+    Ok(Json(session_info(&db, &did, doc).await?.into()))
+}
+
+/// Describe an account's session. Accounts that aren't active still have sessions, reported with
+/// `active: false` and their status.
+async fn session_info(
+    db: &Db,
+    did: &str,
+    doc: Option<DidDocument>,
+) -> Result<server::get_session::OutputData> {
+    let account: Option<(String, String, Option<String>, bool, bool)> = sqlx::query_as(
         r#"
         SELECT a.email, a.status, (
             SELECT h.handle
             FROM handles h
             WHERE h.did = a.did
-            ORDER BY h.created_at ASC
+            ORDER BY h.created_at DESC
             LIMIT 1
-        ) AS handle
+        ) AS handle, a.email_confirmed_at IS NOT NULL, a.email_auth_factor
         FROM accounts a
         WHERE a.did = ?
         "#,
-        did
     )
-    .fetch_optional(&db)
+    .bind(did)
+    .fetch_optional(db)
     .await
     .context("failed to fetch session")?;
 
-    if let Some(user) = user {
-        let active = user.status == "active";
-        let status = if active { None } else { Some(user.status) };
-
-        Ok(Json(
-            server::get_session::OutputData {
-                active: Some(active),
-                did: Did::from_str(&did).unwrap(),
-                did_doc: None,
-                email: Some(user.email),
-                email_auth_factor: None,
-                email_confirmed: None,
-                handle: Handle::new(user.handle).unwrap(),
-                status,
-            }
-            .into(),
-        ))
-    } else {
-        Err(Error::with_status(
+    let Some((email, status, handle, email_confirmed, email_auth_factor)) = account else {
+        return Err(Error::with_status(
             StatusCode::UNAUTHORIZED,
             anyhow!("user not found"),
-        ))
+        ));
+    };
+
+    let active = status == AccountStatus::Active.as_str();
+    let did_doc = doc
+        .map(|doc| {
+            serde_json::to_value(doc)
+                .context("failed to encode DID document")?
+                .try_into_unknown()
+                .context("failed to encode DID document")
+        })
+        .transpose()?;
+
+    Ok(server::get_session::OutputData {
+        active: Some(active),
+        did: Did::from_str(did).unwrap(),
+        did_doc,
+        email: Some(email),
+        email_auth_factor: Some(email_auth_factor),
+        email_confirmed: Some(email_confirmed),
+        // An account without a valid handle is reported as such, per the identity spec.
+        handle: Handle::new(handle.unwrap_or_else(|| "handle.invalid".to_string())).unwrap(),
+        status: (!active).then_some(status),
+    })
+}
+
+async fn deactivate_account(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    State(events): State<EventBus>,
+    Json(_input): Json<server::deactivate_account::Input>,
+) -> Result<()> {
+    user.require_full()?;
+
+    // TODO: `input.delete_after`
+    status::set_own_status(&db, &events, &user.did(), AccountStatus::Deactivated).await?;
+    Ok(())
+}
+
+async fn activate_account(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    State(config): State<AppConfig>,
+    State(events): State<EventBus>,
+) -> Result<()> {
+    user.require_full()?;
+    let did = user.did();
+
+    if signup_queue::place(&db, &did).await?.is_some() {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("{did} is waiting in the signup queue"),
+            ErrorMessage::new("InvalidRequest", "account is waiting in the signup queue"),
+        ));
+    }
+
+    let previous = status::set_own_status(&db, &events, &did, AccountStatus::Active).await?;
+    if previous != AccountStatus::Active {
+        // The repository may have changed wholesale while the account was inactive (e.g. it was
+        // imported), so consumers must resynchronize it.
+        let (root, rev): (String, String) =
+            sqlx::query_as(r#"SELECT root, rev FROM accounts WHERE did = ?"#)
+                .bind(&did)
+                .fetch_one(&db)
+                .await
+                .context("failed to query repository head")?;
+        let root = Cid::from_str(&root).context("invalid repository head")?;
+        events::publish_sync(&events, &config.repo, &did, root, &rev).await;
+    }
+
+    Ok(())
+}
+
+/// Whether an account's DID document points at this PDS, and its signing key.
+async fn did_is_valid(
+    client: &Client,
+    config: &AppConfig,
+    db: &Db,
+    cache: &DidCache,
+    skey: &SigningKey,
+    did: &str,
+) -> Result<bool> {
+    let skey = keys::account_key(db, skey, did).await?;
+    let did = Did::new(did.to_string()).map_err(|e| anyhow!("invalid did: {e}"))?;
+    let doc = match cache.resolve(client, config, db, did, true).await {
+        Ok(d) => d.doc,
+        Err(e) => {
+            warn!("failed to resolve DID document: {e:?}");
+            return Ok(false);
+        }
+    };
+
+    let key = doc.signing_key();
+    let pds = doc.pds().and_then(|u| u.host_str().map(str::to_string));
+
+    Ok(key.as_deref() == Some(skey.did().as_str()) && pds.as_deref() == Some(&config.host_name))
+}
+
+async fn check_account_status(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    State(client): State<Client>,
+    State(config): State<AppConfig>,
+    State(cache): State<DidCache>,
+    State(skey): State<SigningKey>,
+    State(integrity): State<RepoIntegrity>,
+) -> Result<Json<server::check_account_status::Output>> {
+    let did = user.did();
+
+    let status: String = sqlx::query_scalar(r#"SELECT status FROM accounts WHERE did = ?"#)
+        .bind(&did)
+        .fetch_one(&db)
+        .await
+        .context("failed to query account status")?;
+    let valid_did = did_is_valid(&client, &config, &db, &cache, &skey, &did).await?;
+
+    // Count the blocks reachable from the head, as an export of the repository would contain.
+    let mut repo = integrity.open(&did).await?;
+    let mut contents = Vec::new();
+    let mut store = CarStore::create_with_roots(std::io::Cursor::new(&mut contents), [repo.root()])
+        .await
+        .context("failed to create car store")?;
+    repo.export_into(&mut store)
+        .await
+        .context("failed to export repository")?;
+    drop(store);
+    let repo_blocks = import::count_blocks(&mut std::io::Cursor::new(&contents))
+        .context("failed to count repository blocks")?;
+
+    let indexed_records: i64 =
+        sqlx::query_scalar(r#"SELECT COALESCE((SELECT records FROM repo_stats WHERE did = ?), 0)"#)
+            .bind(&did)
+            .fetch_one(&db)
+            .await
+            .context("failed to query record count")?;
+    let expected_blobs: i64 = sqlx::query_scalar(
+        r#"SELECT COUNT(DISTINCT cid) FROM blob_ref WHERE did = ? AND record IS NOT NULL"#,
+    )
+    .bind(&did)
+    .fetch_one(&db)
+    .await
+    .context("failed to count expected blobs")?;
+
+    // Records may reference blobs that haven't been uploaded (yet).
+    let cids: Vec<String> =
+        sqlx::query_scalar(r#"SELECT DISTINCT cid FROM blob_ref WHERE did = ?"#)
+            .bind(&did)
+            .fetch_all(&db)
+            .await
+            .context("failed to query blobs")?;
+    let mut imported_blobs = 0;
+    for cid in cids {
+        if tokio::fs::try_exists(config.blob.path.join(format!("{cid}.blob")))
+            .await
+            .unwrap_or(false)
+        {
+            imported_blobs += 1;
+        }
     }
+
+    Ok(Json(
+        server::check_account_status::OutputData {
+            activated: status == AccountStatus::Active.as_str(),
+            expected_blobs,
+            imported_blobs,
+            indexed_records,
+            private_state_values: 0,
+            repo_blocks: repo_blocks as i64,
+            repo_commit: atrium_api::types::string::Cid::new(repo.root()),
+            repo_rev: repo.commit().rev().to_string(),
+            valid_did,
+        }
+        .into(),
+    ))
+}
+
+/// Describe this PDS to clients (e.g. before signing up), from its configuration. The handle
+/// domain matching the hostname the client addressed comes first, as clients offer it by default.
+fn describe(config: &AppConfig, host: &str) -> server::describe_server::Output {
+    let mut domains = config.handle_domains();
+    if let Some(i) = domains.iter().position(|d| d == host) {
+        domains[..=i].rotate_right(1);
+    }
+
+    let links = server::describe_server::LinksData {
+        privacy_policy: config.service.privacy_policy.as_ref().map(Url::to_string),
+        terms_of_service: config.service.terms_of_service.as_ref().map(Url::to_string),
+    };
+    let contact = server::describe_server::ContactData {
+        email: config.service.contact_email.clone(),
+    };
+
+    server::describe_server::OutputData {
+        available_user_domains: domains
+            .into_iter()
+            .map(|domain| format!(".{domain}"))
+            .collect(),
+        contact: contact.email.is_some().then(|| contact.into()),
+        did: Did::new(config.did()).expect("host_name should form a valid did:web"),
+        invite_code_required: Some(config.invites.required),
+        links: (links.privacy_policy.is_some() || links.terms_of_service.is_some())
+            .then(|| links.into()),
+        phone_verification_required: Some(false), // email verification
+    }
+    .into()
 }
 
 async fn describe_server(
+    host: RequestHost,
+    State(config): State<AppConfig>,
+) -> (
+    [(HeaderName, &'static str); 1],
+    Json<server::describe_server::Output>,
+) {
+    // Clients fetch this before every signup, and it only changes with the configuration.
+    (
+        [(axum::http::header::CACHE_CONTROL, "public, max-age=300")],
+        Json(describe(&config, host.as_str())),
+    )
+}
+
+/// Email the account a token with which to confirm its deletion (see `deleteAccount`).
+async fn request_account_delete(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    State(mailer): State<Mailer>,
+) -> Result<()> {
+    user.require_full()?;
+    let did = user.did();
+
+    let email: String = sqlx::query_scalar(r#"SELECT email FROM accounts WHERE did = ?"#)
+        .bind(&did)
+        .fetch_one(&db)
+        .await
+        .context("failed to query account email")?;
+    let token = mail::create_token(&db, Purpose::DeleteAccount, &did).await?;
+
+    mailer
+        .send(
+            &email,
+            "Account deletion request",
+            &format!(
+                "To confirm the deletion of your account ({did}), enter the following code:\n\n\
+                 {token}\n\n\
+                 The code expires in {} minutes. If you didn't request this, change your password.",
+                mail::TOKEN_TTL / 60
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Delete an account, given its password and a token from `requestAccountDelete`.
+async fn delete_account(
     State(config): State<AppConfig>,
-) -> Result<Json<server::describe_server::Output>> {
+    State(db): State<Db>,
+    State(events): State<EventBus>,
+    State(revocations): State<Revocations>,
+    Json(input): Json<server::delete_account::Input>,
+) -> Result<()> {
+    let did = input.did.as_str();
+
+    let hash: Option<String> = sqlx::query_scalar(r#"SELECT password FROM accounts WHERE did = ?"#)
+        .bind(did)
+        .fetch_optional(&db)
+        .await
+        .context("failed to query account")?;
+
+    // SEC: Verify against a dummy password if the account doesn't exist, so that the response
+    // time doesn't reveal whether it does.
+    let valid = argon2::Argon2::default()
+        .verify_password(
+            input.password.as_bytes(),
+            &PasswordHash::new(hash.as_deref().unwrap_or(DUMMY_PASSWORD))
+                .context("invalid password hash in db")?,
+        )
+        .is_ok();
+    if !valid || hash.is_none() {
+        counter!(AUTH_FAILED).increment(1);
+
+        return Err(Error::with_status(
+            StatusCode::UNAUTHORIZED,
+            anyhow!("failed to validate credentials"),
+        ));
+    }
+
+    mail::consume_token(&db, Purpose::DeleteAccount, did, &input.token).await?;
+
+    revocations.revoke_all(&db, did).await?;
+    deletion::delete_account(&config, &db, did).await?;
+    events
+        .publish(Event::Account(status::deleted_event(did)?))
+        .await;
+
+    Ok(())
+}
+
+/// Email the account a token with which to confirm its email address (see `confirmEmail`).
+async fn request_email_confirmation(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    State(mailer): State<Mailer>,
+) -> Result<()> {
+    user.require_full()?;
+    let did = user.did();
+
+    let email: String = sqlx::query_scalar(r#"SELECT email FROM accounts WHERE did = ?"#)
+        .bind(&did)
+        .fetch_one(&db)
+        .await
+        .context("failed to query account email")?;
+    let token = mail::create_token(&db, Purpose::ConfirmEmail, &did).await?;
+
+    mailer
+        .send(
+            &email,
+            "Confirm your email address",
+            &format!(
+                "To confirm this email address for your account ({did}), enter the following \
+                 code:\n\n\
+                 {token}\n\n\
+                 The code expires in {} minutes.",
+                mail::TOKEN_TTL / 60
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Confirm an account's email address, given a token from `requestEmailConfirmation`.
+async fn confirm_email(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    Json(input): Json<server::confirm_email::Input>,
+) -> Result<()> {
+    user.require_full()?;
+    confirm_account_email(&db, &user.did(), &input.email, &input.token).await
+}
+
+/// Mark an account's email address as confirmed, if it's `email` and `token` is valid.
+async fn confirm_account_email(db: &Db, did: &str, email: &str, token: &str) -> Result<()> {
+    let current: String = sqlx::query_scalar(r#"SELECT email FROM accounts WHERE did = ?"#)
+        .bind(did)
+        .fetch_one(db)
+        .await
+        .context("failed to query account email")?;
+    if !current.eq_ignore_ascii_case(email.trim()) {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("email does not match the account's"),
+            ErrorMessage::new("InvalidEmail", "email does not match the account's email"),
+        ));
+    }
+
+    mail::consume_token(db, Purpose::ConfirmEmail, did, token).await?;
+
+    // Only confirm the address that was checked, in case it changed since.
+    sqlx::query(
+        r#"UPDATE accounts SET email_confirmed_at = datetime('now') WHERE did = ? AND email = ?"#,
+    )
+    .bind(did)
+    .bind(&current)
+    .execute(db)
+    .await
+    .context("failed to confirm email")?;
+
+    Ok(())
+}
+
+/// Start changing an account's email address. If the current address is confirmed, the change
+/// must be confirmed with a token emailed to it.
+async fn request_email_update(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    State(mailer): State<Mailer>,
+) -> Result<Json<server::request_email_update::Output>> {
+    user.require_full()?;
+    let did = user.did();
+
+    let (email, confirmed): (String, bool) = sqlx::query_as(
+        r#"SELECT email, email_confirmed_at IS NOT NULL FROM accounts WHERE did = ?"#,
+    )
+    .bind(&did)
+    .fetch_one(&db)
+    .await
+    .context("failed to query account email")?;
+
+    if confirmed {
+        let token = mail::create_token(&db, Purpose::UpdateEmail, &did).await?;
+        mailer
+            .send(
+                &email,
+                "Email address change request",
+                &format!(
+                    "To change the email address of your account ({did}), enter the following \
+                     code:\n\n\
+                     {token}\n\n\
+                     The code expires in {} minutes. If you didn't request this, change your \
+                     password.",
+                    mail::TOKEN_TTL / 60
+                ),
+            )
+            .await?;
+    }
+
     Ok(Json(
-        server::describe_server::OutputData {
-            available_user_domains: vec![],
-            contact: None,
-            did: Did::from_str(&format!("did:web:{}", config.host_name)).unwrap(),
-            invite_code_required: Some(true),
-            links: None,
-            phone_verification_required: Some(false), // email verification
+        server::request_email_update::OutputData {
+            token_required: confirmed,
         }
         .into(),
     ))
 }
 
+/// Change an account's email address, given a token from `requestEmailUpdate` if required.
+async fn update_email(
+    user: AuthenticatedUser,
+    State(db): State<Db>,
+    Json(input): Json<server::update_email::Input>,
+) -> Result<()> {
+    user.require_full()?;
+    change_email(
+        &db,
+        &user.did(),
+        input.email.trim(),
+        input.token.as_deref(),
+        input.email_auth_factor,
+    )
+    .await
+}
+
+/// Change an account's email address, and whether it's required to sign in (`auth_factor`). A new
+/// address starts out unconfirmed, and only a confirmed address may be required to sign in.
+async fn change_email(
+    db: &Db,
+    did: &str,
+    email: &str,
+    token: Option<&str>,
+    auth_factor: Option<bool>,
+) -> Result<()> {
+    if !email.contains('@') {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("invalid email {email:?}"),
+            ErrorMessage::new("InvalidRequest", "invalid email address"),
+        ));
+    }
+
+    let (current, confirmed): (String, bool) = sqlx::query_as(
+        r#"SELECT email, email_confirmed_at IS NOT NULL FROM accounts WHERE did = ?"#,
+    )
+    .bind(did)
+    .fetch_one(db)
+    .await
+    .context("failed to query account email")?;
+    if auth_factor == Some(true) && !(confirmed && current.eq_ignore_ascii_case(email)) {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("cannot require an unconfirmed email to sign in"),
+            ErrorMessage::new(
+                "InvalidRequest",
+                "email must be confirmed before it can be required to sign in",
+            ),
+        ));
+    }
+    if confirmed {
+        let Some(token) = token else {
+            return Err(Error::with_message(
+                StatusCode::BAD_REQUEST,
+                anyhow!("no token provided to change a confirmed email"),
+                ErrorMessage::new("TokenRequired", "confirmation token required"),
+            ));
+        };
+        mail::consume_token(db, Purpose::UpdateEmail, did, token).await?;
+    }
+
+    let taken = || {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("email is already taken"),
+            ErrorMessage::new("InvalidRequest", "email already taken"),
+        )
+    };
+
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+    // A single statement, so that two accounts can't claim the same address concurrently; the
+    // unique constraint on `email` is the backstop.
+    let updated = sqlx::query(
+        r#"
+        UPDATE accounts SET
+            email_confirmed_at = iif(lower(email) = lower(?1), email_confirmed_at, NULL),
+            email = ?1,
+            email_auth_factor = coalesce(?3, email_auth_factor)
+            WHERE did = ?2
+            AND NOT EXISTS (SELECT 1 FROM accounts WHERE lower(email) = lower(?1) AND did != ?2)
+        "#,
+    )
+    .bind(email)
+    .bind(did)
+    .bind(auth_factor)
+    .execute(&mut *tx)
+    .await;
+    match updated {
+        Ok(r) if r.rows_affected() == 1 => {}
+        Ok(_) => return Err(taken()),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => return Err(taken()),
+        Err(e) => {
+            return Err(anyhow::Error::new(e)
+                .context("failed to update email")
+                .into())
+        }
+    }
+    if !current.eq_ignore_ascii_case(email) {
+        // Confirmation tokens were sent to the old address.
+        sqlx::query(r#"DELETE FROM email_tokens WHERE did = ? AND purpose = ?"#)
+            .bind(did)
+            .bind(Purpose::ConfirmEmail.as_str())
+            .execute(&mut *tx)
+            .await
+            .context("failed to delete email tokens")?;
+    }
+    tx.commit().await.context("failed to commit transaction")?;
+
+    Ok(())
+}
+
+/// Email a token with which to reset the password of the account with the given address, if any.
+async fn request_password_reset(
+    State(db): State<Db>,
+    State(mailer): State<Mailer>,
+    Json(input): Json<server::request_password_reset::Input>,
+) -> Result<()> {
+    let did: Option<String> =
+        sqlx::query_scalar(r#"SELECT did FROM accounts WHERE lower(email) = lower(?)"#)
+            .bind(input.email.trim())
+            .fetch_optional(&db)
+            .await
+            .context("failed to query account")?;
+
+    // SEC: Respond the same way, and as quickly, whether or not the account exists, so that the
+    // response doesn't reveal it.
+    if let Some(did) = did {
+        let email = input.email.trim().to_string();
+        tokio::spawn(async move {
+            let r = async {
+                let token = mail::create_token(&db, Purpose::ResetPassword, &did).await?;
+                mailer
+                    .send(
+                        &email,
+                        "Password reset request",
+                        &format!(
+                            "To reset the password of your account ({did}), enter the following \
+                             code:\n\n\
+                             {token}\n\n\
+                             The code expires in {} minutes. If you didn't request this, you can \
+                             ignore this email.",
+                            mail::TOKEN_TTL / 60
+                        ),
+                    )
+                    .await
+            }
+            .await;
+            if let Err(e) = r {
+                warn!("failed to send password reset for {did}: {e:?}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Reset an account's password, given a token from `requestPasswordReset`.
+async fn reset_password(
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(revocations): State<Revocations>,
+    Json(input): Json<server::reset_password::Input>,
+) -> Result<()> {
+    set_password_with_token(&config, &db, &revocations, &input.token, &input.password).await
+}
+
+/// Set the password of the account a password reset token was issued to, and end all of its
+/// sessions, including those created with app passwords.
+async fn set_password_with_token(
+    config: &AppConfig,
+    db: &Db,
+    revocations: &Revocations,
+    token: &str,
+    password: &str,
+) -> Result<()> {
+    // Check what can be checked before spending the token; the rest depends on the account.
+    password::check(&config.password, password, password::Account::default())?;
+    let did = mail::redeem_token(db, Purpose::ResetPassword, token).await?;
+
+    let (handle, email): (Option<String>, String) = sqlx::query_as(
+        r#"
+        SELECT (SELECT handle FROM handles WHERE handles.did = accounts.did
+            ORDER BY created_at DESC LIMIT 1), email
+            FROM accounts WHERE did = ?
+        "#,
+    )
+    .bind(&did)
+    .fetch_one(db)
+    .await
+    .context("failed to query account")?;
+    password::check(
+        &config.password,
+        password,
+        password::Account {
+            handle: handle.as_deref(),
+            email: Some(&email),
+        },
+    )?;
+
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), salt.as_salt())
+        .context("failed to hash password")?
+        .to_string();
+    sqlx::query(r#"UPDATE accounts SET password = ? WHERE did = ?"#)
+        .bind(hash)
+        .bind(&did)
+        .execute(db)
+        .await
+        .context("failed to update password")?;
+
+    revocations.revoke_all(db, &did).await?;
+    Ok(())
+}
+
+/// Generate a signing key for an account migrating here, to be referenced by its DID document
+/// before it's created here (see `keys`).
+async fn reserve_signing_key(
+    State(db): State<Db>,
+    Json(input): Json<server::reserve_signing_key::Input>,
+) -> Result<Json<server::reserve_signing_key::Output>> {
+    let signing_key = keys::reserve(&db, input.did.as_ref().map(|did| did.as_str())).await?;
+
+    Ok(Json(
+        server::reserve_signing_key::OutputData { signing_key }.into(),
+    ))
+}
+
 #[rustfmt::skip]
-pub fn routes() -> Router<AppState> {
+pub fn routes() -> Routes {
     // UG /xrpc/com.atproto.server.describeServer
     // UP /xrpc/com.atproto.server.createAccount
     // UP /xrpc/com.atproto.server.createSession
     // AP /xrpc/com.atproto.server.refreshSession
+    // AP /xrpc/com.atproto.server.deleteSession
     // AG /xrpc/com.atproto.server.getServiceAuth
     // AG /xrpc/com.atproto.server.getSession
     // AP /xrpc/com.atproto.server.createInviteCode
-    Router::new()
+    // AP /xrpc/com.atproto.server.createInviteCodes
+    // AG /xrpc/com.atproto.server.getAccountInviteCodes
+    // AP /xrpc/com.atproto.server.createAppPassword
+    // AG /xrpc/com.atproto.server.listAppPasswords
+    // AP /xrpc/com.atproto.server.revokeAppPassword
+    // AP /xrpc/com.atproto.server.deactivateAccount
+    // AP /xrpc/com.atproto.server.activateAccount
+    // AG /xrpc/com.atproto.server.checkAccountStatus
+    // AP /xrpc/com.atproto.server.requestAccountDelete
+    // UP /xrpc/com.atproto.server.deleteAccount
+    // AP /xrpc/com.atproto.server.requestEmailConfirmation
+    // AP /xrpc/com.atproto.server.confirmEmail
+    // AP /xrpc/com.atproto.server.requestEmailUpdate
+    // AP /xrpc/com.atproto.server.updateEmail
+    // UP /xrpc/com.atproto.server.requestPasswordReset
+    // UP /xrpc/com.atproto.server.resetPassword
+    // UP /xrpc/com.atproto.server.reserveSigningKey
+    Routes::new()
         .route(concat!("/", server::describe_server::NSID),     get(describe_server))
         .route(concat!("/", server::create_account::NSID),     post(create_account))
         .route(concat!("/", server::create_session::NSID),     post(create_session))
         .route(concat!("/", server::refresh_session::NSID),    post(refresh_session))
+        .route(concat!("/", server::delete_session::NSID),     post(delete_session))
         .route(concat!("/", server::get_service_auth::NSID),    get(get_service_auth))
         .route(concat!("/", server::get_session::NSID),         get(get_session))
         .route(concat!("/", server::create_invite_code::NSID), post(create_invite_code))
+        .route(concat!("/", server::create_invite_codes::NSID), post(create_invite_codes))
+        .route(concat!("/", server::get_account_invite_codes::NSID), get(get_account_invite_codes))
+        .route(concat!("/", server::create_app_password::NSID), post(create_app_password))
+        .route(concat!("/", server::list_app_passwords::NSID),  get(list_app_passwords))
+        .route(concat!("/", server::revoke_app_password::NSID), post(revoke_app_password))
+        .route(concat!("/", server::deactivate_account::NSID),  post(deactivate_account))
+        .route(concat!("/", server::activate_account::NSID),    post(activate_account))
+        .route(concat!("/", server::check_account_status::NSID), get(check_account_status))
+        .route(concat!("/", server::request_account_delete::NSID), post(request_account_delete))
+        .route(concat!("/", server::delete_account::NSID),     post(delete_account))
+        .route(concat!("/", server::request_email_confirmation::NSID), post(request_email_confirmation))
+        .route(concat!("/", server::confirm_email::NSID),      post(confirm_email))
+        .route(concat!("/", server::request_email_update::NSID), post(request_email_update))
+        .route(concat!("/", server::update_email::NSID),       post(update_email))
+        .route(concat!("/", server::request_password_reset::NSID), post(request_password_reset))
+        .route(concat!("/", server::reset_password::NSID),     post(reset_password))
+        .route(concat!("/", server::reserve_signing_key::NSID), post(reserve_signing_key))
+        .map_router(|r| r.layer(DefaultBodyLimit::max(MAX_JSON_BODY)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use atrium_crypto::keypair::Secp256k1Keypair;
+    use axum::response::IntoResponse;
+
+    use super::*;
+
+    fn test_config(dir: &std::path::Path, create_profile: bool) -> AppConfig {
+        crate::testing::config(
+            dir,
+            serde_json::json!({ "repo": { "create_profile": create_profile } }),
+        )
+    }
+
+    fn signup(i: usize, handle: &str) -> server::create_account::Input {
+        server::create_account::InputData {
+            did: None,
+            email: Some(format!("user{i}@example.com")),
+            handle: Handle::new(handle.to_string()).unwrap(),
+            invite_code: Some("invite".to_string()),
+            password: Some("correct horse battery".to_string()),
+            plc_op: None,
+            recovery_key: None,
+            verification_code: None,
+            verification_phone: None,
+        }
+        .into()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_signups() {
+        const N: usize = 16;
+
+        let dir = crate::testing::temp_dir("signup");
+        let (plc_path, repo_path) = (dir.join("plc"), dir.join("repo"));
+
+        // A file-backed database, so that signups race on real connections.
+        let db = crate::testing::file_db(&dir, N as u32).await;
+        sqlx::query(r#"INSERT INTO invites (id, count) VALUES ('invite', ?)"#)
+            .bind(N as i64)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let config = test_config(&dir, false);
+
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let rkey = RotationKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+
+        let tasks = (0..N)
+            .map(|i| {
+                let (db, skey, rkey, client, config) = (
+                    db.clone(),
+                    skey.clone(),
+                    rkey.clone(),
+                    client.clone(),
+                    config.clone(),
+                );
+
+                tokio::spawn(async move {
+                    let input = signup(i, "alice.pds.example.com");
+                    insert_account(&db, &skey, &rkey, &client, &config, &input).await
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut winners = Vec::new();
+        for task in tasks {
+            match task.await.unwrap() {
+                Ok(account) => winners.push(account.did),
+                Err(e) => {
+                    let resp = e.into_response();
+                    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+                    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                        .await
+                        .unwrap();
+                    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                    assert_eq!(body["error"], "HandleNotAvailable");
+                }
+            }
+        }
+
+        // Exactly one signup succeeded, and only its files remain.
+        assert_eq!(winners.len(), 1);
+        let did_hash = winners[0].strip_prefix("did:plc:").unwrap();
+        for path in [&plc_path, &repo_path] {
+            let files = std::fs::read_dir(path)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(files, [format!("{did_hash}.car")]);
+        }
+
+        let accounts: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM accounts"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(accounts, 1);
+
+        // Losing signups don't consume invites.
+        let remaining: i64 = sqlx::query_scalar(r#"SELECT count FROM invites WHERE id = 'invite'"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(remaining, N as i64 - 1);
+
+        db.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn profile_hook() {
+        let dir = crate::testing::temp_dir("profile");
+
+        let db = crate::testing::memory_db().await;
+        sqlx::query(r#"INSERT INTO invites (id, count) VALUES ('invite', 1)"#)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let config = test_config(&dir, true);
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let rkey = RotationKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+
+        let account = insert_account(
+            &db,
+            &skey,
+            &rkey,
+            &client,
+            &config,
+            &signup(0, "alice.pds.example.com"),
+        )
+        .await
+        .unwrap();
+
+        // The genesis commit, followed by the profile.
+        assert_eq!(account.commits.len(), 2);
+        let genesis = &account.commits[0];
+        let profile = &account.commits[1];
+        assert!(genesis.ops.is_empty());
+        assert_eq!(profile.pcid, Some(genesis.cid));
+        assert_eq!(genesis.since, None);
+        assert_eq!(profile.since.as_ref(), Some(&genesis.rev));
+        assert_eq!(profile.cid, account.cid);
+        assert!(matches!(
+            &profile.ops[..],
+            [RepoOp::Create { path, .. }] if path == "app.bsky.actor.profile/self"
+        ));
+
+        // Both are queued for the firehose, in order.
+        let queued: Vec<(String, String)> =
+            sqlx::query_as(r#"SELECT type, rev FROM firehose_outbox ORDER BY seq"#)
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        assert_eq!(
+            queued,
+            [
+                ("#commit".to_string(), genesis.rev.clone()),
+                ("#commit".to_string(), profile.rev.clone()),
+            ]
+        );
+
+        // The account's head includes the profile.
+        let root: String = sqlx::query_scalar(r#"SELECT root FROM accounts WHERE did = ?"#)
+            .bind(&account.did)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(root, account.cid.to_string());
+
+        let mut repo = crate::storage::open_repo(&config.repo, &account.did, account.cid)
+            .await
+            .unwrap();
+        let record = repo
+            .get_raw::<serde_json::Value>("app.bsky.actor.profile/self")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(record["$type"], "app.bsky.actor.profile");
+        assert_eq!(record["displayName"], "alice.pds.example.com");
+
+        db.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn signup_errors() {
+        let dir = crate::testing::temp_dir("signup-errors");
+
+        let db = crate::testing::memory_db().await;
+        sqlx::query(r#"INSERT INTO invites (id, count) VALUES ('invite', 10)"#)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let config = test_config(&dir, false);
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let rkey = RotationKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+
+        let create = |input: server::create_account::Input| {
+            let (db, skey, rkey, client, config) = (&db, &skey, &rkey, &client, &config);
+            async move { insert_account(db, skey, rkey, client, config, &input).await }
+        };
+        let error = |e: Error| async move {
+            let resp = e.into_response();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            body["error"].as_str().unwrap().to_string()
+        };
+
+        let alice = create(signup(0, "alice.pds.example.com")).await.unwrap();
+
+        let mut input = signup(1, "bob.pds.example.com");
+        input.invite_code = None;
+        let e = create(input).await.err().unwrap();
+        assert_eq!(error(e).await, "InvalidInviteCode");
+
+        let mut input = signup(1, "bob.pds.example.com");
+        input.invite_code = Some("nope".to_string());
+        let e = create(input).await.err().unwrap();
+        assert_eq!(error(e).await, "InvalidInviteCode");
+
+        let disabled = invites::create(&db, None, invites::ADMIN, 1, 1)
+            .await
+            .unwrap();
+        invites::disable(&db, &disabled, &[]).await.unwrap();
+        let mut input = signup(1, "bob.pds.example.com");
+        input.invite_code = Some(disabled[0].clone());
+        let e = create(input).await.err().unwrap();
+        assert_eq!(error(e).await, "InvalidInviteCode");
+
+        for handle in ["bob.example.com", "bob.alice.pds.example.com"] {
+            let e = create(signup(1, handle)).await.err().unwrap();
+            assert_eq!(error(e).await, "UnsupportedDomain");
+        }
+
+        let e = create(signup(1, "alice.pds.example.com"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error(e).await, "HandleNotAvailable");
+
+        let e = create(signup(0, "bob.pds.example.com"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error(e).await, "InvalidRequest");
+
+        let mut input = signup(1, "bob.pds.example.com");
+        input.password = None;
+        let e = create(input).await.err().unwrap();
+        assert_eq!(error(e).await, "InvalidPassword");
+        let mut input = signup(1, "bob.pds.example.com");
+        input.password = Some("password".to_string());
+        let e = create(input).await.err().unwrap();
+        assert_eq!(error(e).await, "InvalidPassword");
+
+        // A migrating account keeps its DID, and is created deactivated, with no operation log.
+        let mut input = signup(1, "bob.pds.example.com");
+        input.did = Some(Did::new("did:plc:bob".to_string()).unwrap());
+        let bob = create(input).await.unwrap();
+        assert_eq!(bob.did, "did:plc:bob");
+
+        for (did, status, plc) in [
+            (&alice.did, "active", true),
+            (&bob.did, "deactivated", false),
+        ] {
+            let (s, plc_root): (String, String) =
+                sqlx::query_as(r#"SELECT status, plc_root FROM accounts WHERE did = ?"#)
+                    .bind(did)
+                    .fetch_one(&db)
+                    .await
+                    .unwrap();
+            assert_eq!(s, status);
+            assert_eq!(!plc_root.is_empty(), plc);
+        }
+
+        db.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn app_passwords() {
+        let db = crate::testing::memory_db().await;
+
+        let config = test_config(std::path::Path::new("."), false);
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let did = "did:plc:alice";
+
+        let password = generate_app_password();
+        assert_eq!(password.len(), 19);
+        assert!(password
+            .split('-')
+            .all(|group| group.len() == 4 && group.chars().all(|c| c.is_ascii_alphanumeric())));
+
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), salt.as_salt())
+            .unwrap()
+            .to_string();
+        sqlx::query(
+            r#"INSERT INTO app_passwords (did, name, password, privileged, created_at) VALUES (?, 'client', ?, TRUE, ?)"#,
+        )
+        .bind(did)
+        .bind(&hash)
+        .bind(Datetime::now().as_str())
+        .execute(&db)
+        .await
+        .unwrap();
+
+        assert!(verify_app_password(&db, did, "hunter2")
+            .await
+            .unwrap()
+            .is_none());
+        let app_password = verify_app_password(&db, did, &password)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(app_password.name, "client");
+        assert!(app_password.privileged);
+
+        // The session can be refreshed for as long as the app password exists.
+        let mut conn = db.acquire().await.unwrap();
+        let session = auth::issue_session(&mut conn, &skey, &config, did, Some(&app_password))
+            .await
+            .unwrap();
+        drop(conn);
+        let (_, session) = auth::rotate_session(&db, &skey, &config, &session.refresh)
+            .await
+            .unwrap();
+
+        sqlx::query(r#"DELETE FROM app_passwords WHERE did = ? AND name = 'client'"#)
+            .bind(did)
+            .execute(&db)
+            .await
+            .unwrap();
+        let e = auth::rotate_session(&db, &skey, &config, &session.refresh)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+
+    async fn email_confirmed(db: &Db, did: &str) -> bool {
+        sqlx::query_scalar(r#"SELECT email_confirmed_at IS NOT NULL FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn email_confirmation() {
+        let db = crate::testing::memory_db().await;
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev)
+                VALUES ('did:plc:alice', 'alice@example.com', '', '', '', '')
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        let did = "did:plc:alice";
+
+        let token = mail::create_token(&db, Purpose::ConfirmEmail, did)
+            .await
+            .unwrap();
+        let e = confirm_account_email(&db, did, "mallory@example.com", &token)
+            .await
+            .unwrap_err();
+        let body = axum::body::to_bytes(e.into_response().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "InvalidEmail");
+        assert!(!email_confirmed(&db, did).await);
+
+        confirm_account_email(&db, did, "Alice@example.com", &token)
+            .await
+            .unwrap();
+        assert!(email_confirmed(&db, did).await);
+
+        // The token can't be used again.
+        assert!(confirm_account_email(&db, did, "alice@example.com", &token)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn email_update() {
+        let db = crate::testing::memory_db().await;
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev, email_confirmed_at)
+                VALUES
+                ('did:plc:alice', 'alice@example.com', '', '', '', '', datetime('now')),
+                ('did:plc:bob', 'bob@example.com', '', '', '', '', NULL)
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        let did = "did:plc:alice";
+
+        // A confirmed address can only be changed with a token sent to it.
+        assert!(change_email(&db, did, "alice@example.net", None, None)
+            .await
+            .is_err());
+        let token = mail::create_token(&db, Purpose::UpdateEmail, did)
+            .await
+            .unwrap();
+        change_email(&db, did, "alice@example.net", Some(&token), None)
+            .await
+            .unwrap();
+        assert!(!email_confirmed(&db, did).await);
+
+        // Addresses are unique, regardless of case.
+        assert!(change_email(&db, did, "Bob@example.com", None, None)
+            .await
+            .is_err());
+        change_email(&db, "did:plc:bob", "bob@example.net", None, None)
+            .await
+            .unwrap();
+        let emails: Vec<String> = sqlx::query_scalar(r#"SELECT email FROM accounts ORDER BY did"#)
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(emails, ["alice@example.net", "bob@example.net"]);
+    }
+
+    #[tokio::test]
+    async fn password_reset() {
+        let db = crate::testing::memory_db().await;
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev)
+                VALUES ('did:plc:alice', 'alice@example.com', '', '', '', '')
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        let config = test_config(std::path::Path::new("."), false);
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let revocations = Revocations::default();
+        let did = "did:plc:alice";
+
+        let mut conn = db.acquire().await.unwrap();
+        auth::issue_session(&mut conn, &skey, &config, did, None)
+            .await
+            .unwrap();
+        drop(conn);
+        let sid: String = sqlx::query_scalar(r#"SELECT session FROM refresh_tokens"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+
+        // An expired token is rejected.
+        let token = mail::create_token(&db, Purpose::ResetPassword, did)
+            .await
+            .unwrap();
+        sqlx::query(r#"UPDATE email_tokens SET requested_at = datetime('now', '-1 hours')"#)
+            .execute(&db)
+            .await
+            .unwrap();
+        assert!(
+            set_password_with_token(&config, &db, &revocations, &token, "hunter22")
+                .await
+                .is_err()
+        );
+        assert!(!revocations.is_revoked(&sid));
+
+        // A valid token sets the password, and ends every session.
+        let token = mail::create_token(&db, Purpose::ResetPassword, did)
+            .await
+            .unwrap();
+        set_password_with_token(&config, &db, &revocations, &token, "hunter22")
+            .await
+            .unwrap();
+        let hash: String = sqlx::query_scalar(r#"SELECT password FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert!(Argon2::default()
+            .verify_password(b"hunter22", &PasswordHash::new(&hash).unwrap())
+            .is_ok());
+        assert!(revocations.is_revoked(&sid));
+        let refresh_tokens: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM refresh_tokens"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(refresh_tokens, 0);
+
+        // The token can't be used again.
+        assert!(
+            set_password_with_token(&config, &db, &revocations, &token, "hunter23")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn session() {
+        let db = crate::testing::memory_db().await;
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev, status)
+                VALUES ('did:plc:alice', 'alice@example.com', '', '', '', '', 'deactivated')
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        let did = "did:plc:alice";
+
+        let info = session_info(&db, did, None).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(&info).unwrap(),
+            serde_json::json!({
+                "active": false,
+                "did": did,
+                "email": "alice@example.com",
+                "emailAuthFactor": false,
+                "emailConfirmed": false,
+                "handle": "handle.invalid",
+                "status": "deactivated",
+            })
+        );
+
+        sqlx::query(
+            r#"
+            UPDATE accounts SET status = 'active', email_confirmed_at = datetime('now');
+            INSERT INTO handles (did, handle, created_at)
+                VALUES ('did:plc:alice', 'alice.pds.example.com', datetime('now'));
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        let doc = DidDocument {
+            context: vec![],
+            id: Did::new(did.to_string()).unwrap(),
+            also_known_as: vec!["at://alice.pds.example.com".to_string()],
+            verification_method: vec![],
+            service: vec![],
+        };
+
+        let info = session_info(&db, did, Some(doc)).await.unwrap();
+        assert_eq!(info.active, Some(true));
+        assert_eq!(info.status, None);
+        assert_eq!(info.email_confirmed, Some(true));
+        assert_eq!(info.handle.as_str(), "alice.pds.example.com");
+        let doc = serde_json::to_value(info.did_doc.unwrap()).unwrap();
+        assert_eq!(doc["id"], did);
+    }
+
+    #[tokio::test]
+    async fn auth_factor() {
+        let db = crate::testing::memory_db().await;
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev, email_confirmed_at)
+                VALUES ('did:plc:alice', 'alice@example.com', '', '', '', '', datetime('now'))
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+        let mailer = Mailer::new(None, client);
+        let did = "did:plc:alice";
+
+        assert!(!check_auth_factor(&db, &mailer, did, None).await.unwrap());
+
+        change_email(&db, did, "alice@example.com", None, Some(true))
+            .await
+            .unwrap_err();
+        let token = mail::create_token(&db, Purpose::UpdateEmail, did)
+            .await
+            .unwrap();
+        change_email(&db, did, "alice@example.com", Some(&token), Some(true))
+            .await
+            .unwrap();
+        assert!(email_confirmed(&db, did).await);
+
+        // Without a token, one is sent, and the client is asked for it.
+        let e = check_auth_factor(&db, &mailer, did, None)
+            .await
+            .unwrap_err();
+        let resp = e.into_response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "AuthFactorTokenRequired");
+
+        assert!(check_auth_factor(&db, &mailer, did, Some("000000"))
+            .await
+            .is_err());
+        let token = mail::create_token(&db, Purpose::SignIn, did).await.unwrap();
+        assert!(check_auth_factor(&db, &mailer, did, Some(&token))
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn description() {
+        let mut config = test_config(std::path::Path::new("."), false);
+        assert_eq!(
+            serde_json::to_value(describe(&config, "pds.example.com")).unwrap(),
+            serde_json::json!({
+                "availableUserDomains": [".pds.example.com"],
+                "did": "did:web:pds.example.com",
+                "inviteCodeRequired": true,
+                "phoneVerificationRequired": false,
+            })
+        );
+
+        config.host_aliases = vec!["vanity.example".to_string()];
+        config.service.privacy_policy = Some("https://pds.example.com/privacy".parse().unwrap());
+        config.service.contact_email = Some("admin@pds.example.com".to_string());
+        config.invites.required = false;
+        assert_eq!(
+            serde_json::to_value(describe(&config, "vanity.example")).unwrap(),
+            serde_json::json!({
+                "availableUserDomains": [".vanity.example", ".pds.example.com"],
+                "contact": { "email": "admin@pds.example.com" },
+                "did": "did:web:pds.example.com",
+                "inviteCodeRequired": false,
+                "links": { "privacyPolicy": "https://pds.example.com/privacy" },
+                "phoneVerificationRequired": false,
+            })
+        );
+    }
+
+    #[test]
+    fn service_auth() {
+        use crate::did::DidVerificationMethod;
+
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let did = "did:plc:alice";
+        let aud = "did:web:api.bsky.app";
+        let lxm = "app.bsky.feed.getTimeline";
+        // The account's DID document, as created on signup.
+        let doc = DidDocument {
+            context: vec![],
+            id: Did::new(did.to_string()).unwrap(),
+            also_known_as: vec![],
+            verification_method: vec![DidVerificationMethod {
+                id: format!("{did}#atproto"),
+                ty: "Multikey".to_string(),
+                controller: did.to_string(),
+                public_key_multibase: skey.did().strip_prefix("did:key:").unwrap().to_string(),
+            }],
+            service: vec![],
+        };
+        let now = chrono::Utc::now().timestamp();
+
+        let token = service_auth_token(&skey, did, aud, Some(now + 30 * 60), Some(lxm)).unwrap();
+        let claims = auth::verify_service_auth(&doc, &token, aud, lxm).unwrap();
+        assert_eq!(claims["iss"], did);
+        assert_eq!(claims["exp"], now + 30 * 60);
+
+        // By default, tokens are short-lived.
+        let token = service_auth_token(&skey, did, aud, None, None).unwrap();
+        let claims = auth::verify_service_auth(&doc, &token, aud, lxm).unwrap();
+        assert!(claims["exp"].as_i64().unwrap() <= now + MAX_UNBOUND_SERVICE_AUTH_TTL + 1);
+        assert!(claims.get("lxm").is_none());
+
+        for (exp, lxm) in [
+            (now - 1, Some(lxm)),
+            (now + 2 * 60 * 60, Some(lxm)),
+            (now + 5 * 60, None),
+        ] {
+            let e = service_auth_token(&skey, did, aud, Some(exp), lxm)
+                .err()
+                .unwrap();
+            assert_eq!(e.into_response().status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_change() {
+        let dir = crate::testing::temp_dir("handle");
+
+        let db = crate::testing::memory_db().await;
+        sqlx::query(r#"INSERT INTO invites (id, count) VALUES ('invite', 10)"#)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let config = test_config(&dir, false);
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let rkey = RotationKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+        let resolver = crate::handle::HandleResolver::default();
+
+        let alice = insert_account(
+            &db,
+            &skey,
+            &rkey,
+            &client,
+            &config,
+            &signup(0, "alice.pds.example.com"),
+        )
+        .await
+        .unwrap();
+        insert_account(
+            &db,
+            &skey,
+            &rkey,
+            &client,
+            &config,
+            &signup(1, "bob.pds.example.com"),
+        )
+        .await
+        .unwrap();
+
+        let change = |handle: &'static str| {
+            let (config, db, client, resolver, rkey, did) =
+                (&config, &db, &client, &resolver, &rkey, &alice.did);
+            async move {
+                super::super::identity::change_handle(
+                    config, db, client, resolver, rkey, did, handle,
+                )
+                .await
+            }
+        };
+        let error = |e: Error| async move {
+            let body = axum::body::to_bytes(e.into_response().into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            body["error"].as_str().unwrap().to_string()
+        };
+
+        let e = change("bob.pds.example.com").await.unwrap_err();
+        assert_eq!(error(e).await, "HandleNotAvailable");
+        let e = change("a.b.pds.example.com").await.unwrap_err();
+        assert_eq!(error(e).await, "UnsupportedDomain");
+
+        change("Alice2.pds.example.com").await.unwrap();
+
+        // The old handle is freed, and the identity names the new one.
+        let handles: Vec<String> =
+            sqlx::query_scalar(r#"SELECT handle FROM handles WHERE did = ?"#)
+                .bind(&alice.did)
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        assert_eq!(handles, ["alice2.pds.example.com"]);
+
+        let plc_root: String = sqlx::query_scalar(r#"SELECT plc_root FROM accounts WHERE did = ?"#)
+            .bind(&alice.did)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        let op = plc::read_op(&config.plc, &alice.did, &plc_root)
+            .await
+            .unwrap();
+        assert_eq!(op.also_known_as, ["at://alice2.pds.example.com"]);
+        assert!(op.prev.is_some());
+        assert_eq!(op.rotation_keys, [rkey.did().to_string()]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -1,64 +1,221 @@
-use std::str::FromStr;
+use std::{net::SocketAddr, str::FromStr};
 
 use anyhow::{anyhow, Context};
 use atrium_api::{
     com::atproto::sync,
-    types::{string::Did, LimitedNonZeroU16},
+    types::{
+        string::{Did, Nsid, RecordKey},
+        LimitedNonZeroU16,
+    },
 };
 use atrium_repo::{
     blockstore::{AsyncBlockStoreRead, AsyncBlockStoreWrite, CarStore, DAG_CBOR, SHA2_256},
     Cid,
 };
 use axum::{
-    body::Body,
-    extract::{Query, State, WebSocketUpgrade},
-    http::{self, Response, StatusCode},
+    body::{Body, Bytes},
+    extract::{ConnectInfo, DefaultBodyLimit, FromRef, Query, State},
+    http::{self, HeaderMap, Response, StatusCode},
     response::IntoResponse,
-    routing::get,
-    Json, Router,
+    routing::{get, post},
+    Json,
 };
 use constcat::concat;
-use futures::stream::TryStreamExt;
+use serde::Deserialize;
 use tokio_util::io::ReaderStream;
+use tracing::{info, warn};
 
 use crate::{
+    auth::Revocations,
+    capabilities::Routes,
     config::AppConfig,
-    firehose::FirehoseProducer,
-    storage::{open_repo_db, open_store},
-    AppState, Db, Error, Result,
+    error::ErrorMessage,
+    firehose::{self, DidFilter, FirehoseProducer},
+    integrity::RepoIntegrity,
+    ratelimit::{ClientId, SyncLimiter},
+    reindex,
+    status::{self, AccountStatus},
+    storage::open_store,
+    tiering::Tiering,
+    websocket::WebSocketUpgrade,
+    Client, Db, Error, Result, SigningKey,
 };
 
+use super::{identity::parse_repo_param, MAX_JSON_BODY};
+
+// N.B: The lexicons type `did` as a DID, but we also accept handles (see `parse_repo_param`).
+// The atrium parameter types reject handles, so the affected endpoints use these instead.
+
+#[derive(Deserialize, Debug, Clone)]
+struct RepoParams {
+    did: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct GetBlocksParams {
+    did: String,
+    cids: Vec<atrium_api::types::string::Cid>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct GetRecordParams {
+    did: String,
+    collection: Nsid,
+    rkey: RecordKey,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct SubscribeReposParams {
+    cursor: Option<i64>,
+    /// Non-standard: a comma-separated list of repository DIDs to filter events to.
+    dids: Option<String>,
+}
+
+/// Validate the `cursor` parameter to `subscribeRepos`. Sequence numbers start at 1, so a cursor
+/// of 0 replays every event retained.
+fn parse_cursor(cursor: Option<i64>) -> Result<Option<u64>> {
+    match cursor {
+        Some(cursor) if cursor < 0 => Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("negative subscribeRepos cursor {cursor}"),
+            ErrorMessage::new("InvalidRequest", "cursor must not be negative"),
+        )),
+        cursor => Ok(cursor.map(|cursor| cursor as u64)),
+    }
+}
+
+/// Parse the non-standard `dids` parameter to `subscribeRepos`.
+fn parse_did_filter(dids: Option<&str>) -> Option<DidFilter> {
+    let dids = dids?;
+    let filter = dids
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(str::to_string)
+        .collect::<DidFilter>();
+
+    if filter.is_empty() {
+        warn!("subscribeRepos: ignoring empty `dids` filter");
+        return None;
+    }
+
+    info!(
+        "subscribeRepos: filtering events to {} repositories (non-standard extension)",
+        filter.len()
+    );
+    Some(filter)
+}
+
+/// Blobs are addressed by their CID and never change, so they can be cached indefinitely.
+const BLOB_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+/// Repository exports change with every commit, so caches must revalidate them (by `ETag`).
+const REPO_CACHE_CONTROL: &str = "private, no-cache";
+
+/// Whether a request's `if-none-match` header matches the current `ETag` of a resource.
+fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(http::header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|v| v == "*" || v == etag)
+}
+
+/// Look up the size of a blob, without opening it.
+async fn blob_len(config: &AppConfig, cid: &str) -> Result<u64> {
+    match tokio::fs::metadata(config.blob.path.join(format!("{cid}.blob"))).await {
+        Ok(m) => Ok(m.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::with_message(
+            StatusCode::NOT_FOUND,
+            anyhow!("blob {cid} not found"),
+            ErrorMessage::new("BlobNotFound", "Blob not found"),
+        )),
+        Err(e) => Err(anyhow::Error::from(e)
+            .context("failed to query blob metadata")
+            .into()),
+    }
+}
+
+/// The headers of a response carrying a blob, shared by `GET` and `HEAD` requests.
+fn blob_response(cid: &str, len: u64) -> http::response::Builder {
+    Response::builder()
+        .header(http::header::CONTENT_LENGTH, len)
+        .header(http::header::CONTENT_TYPE, "application/octet-stream")
+        .header(http::header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(http::header::ETAG, format!("\"{cid}\""))
+        .header(http::header::CACHE_CONTROL, BLOB_CACHE_CONTROL)
+}
+
 async fn get_blob(
     State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(tiering): State<Tiering>,
     Query(input): Query<sync::get_blob::ParametersData>,
+    headers: HeaderMap,
 ) -> Result<Response<Body>> {
-    let blob = config
-        .blob
-        .path
-        .join(format!("{}.blob", input.cid.as_ref().to_string()));
+    status::require_hosted(&db, input.did.as_str()).await?;
+    tiering.ensure_hot(input.did.as_str()).await?;
+
+    let cid = input.cid.as_ref().to_string();
+    let len = blob_len(&config, &cid).await?;
+    let response = blob_response(&cid, len);
+
+    if not_modified(&headers, &format!("\"{cid}\"")) {
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .context("failed to construct response")?);
+    }
 
-    let f = tokio::fs::File::open(blob)
+    let f = tokio::fs::File::open(config.blob.path.join(format!("{cid}.blob")))
         .await
-        .context("blob not found")?;
-    let len = f
-        .metadata()
-        .await
-        .context("failed to query file metadata")?
-        .len();
+        .context("failed to open blob")?;
 
     let s = ReaderStream::new(f);
 
-    Ok(Response::builder()
-        .header(http::header::CONTENT_LENGTH, format!("{}", len))
+    Ok(response
         .body(Body::from_stream(s))
         .context("failed to construct response")?)
 }
 
+/// `HEAD` for `getBlob`: the headers of the blob, from its metadata alone.
+async fn head_blob(
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(tiering): State<Tiering>,
+    Query(input): Query<sync::get_blob::ParametersData>,
+) -> Result<Response<Body>> {
+    status::require_hosted(&db, input.did.as_str()).await?;
+    tiering.ensure_hot(input.did.as_str()).await?;
+
+    let cid = input.cid.as_ref().to_string();
+    let len = blob_len(&config, &cid).await?;
+
+    Ok(blob_response(&cid, len)
+        .body(Body::empty())
+        .context("failed to construct response")?)
+}
+
 async fn get_blocks(
+    client_id: ClientId,
     State(config): State<AppConfig>,
-    Query(input): Query<sync::get_blocks::ParametersData>,
+    State(db): State<Db>,
+    State(client): State<Client>,
+    State(limiter): State<SyncLimiter>,
+    State(tiering): State<Tiering>,
+    Query(input): Query<GetBlocksParams>,
 ) -> Result<Response<Body>> {
-    let mut repo = open_store(&config.repo, input.did.as_str())
+    let did = parse_repo_param(&db, &client, &input.did).await?;
+    status::require_hosted(&db, did.as_str()).await?;
+    tiering.ensure_hot(did.as_str()).await?;
+    let _permit = if input.cids.len() > config.rate_limit.sync_blocks_threshold {
+        Some(limiter.acquire(&client_id).await?)
+    } else {
+        None
+    };
+
+    let mut repo = open_store(&config.repo, did.as_str())
         .await
         .context("failed to open repository")?;
 
@@ -90,13 +247,14 @@ async fn get_blocks(
 }
 
 async fn get_latest_commit(
-    State(config): State<AppConfig>,
     State(db): State<Db>,
-    Query(input): Query<sync::get_latest_commit::ParametersData>,
+    State(client): State<Client>,
+    State(integrity): State<RepoIntegrity>,
+    Query(input): Query<RepoParams>,
 ) -> Result<Json<sync::get_latest_commit::Output>> {
-    let repo = open_repo_db(&config.repo, &db, input.did.as_str())
-        .await
-        .context("failed to open repository")?;
+    let did = parse_repo_param(&db, &client, &input.did).await?;
+    status::require_hosted(&db, did.as_str()).await?;
+    let repo = integrity.open(did.as_str()).await?;
 
     let cid = repo.root();
     let commit = repo.commit();
@@ -111,13 +269,14 @@ async fn get_latest_commit(
 }
 
 async fn get_record(
-    State(config): State<AppConfig>,
     State(db): State<Db>,
-    Query(input): Query<sync::get_record::ParametersData>,
+    State(client): State<Client>,
+    State(integrity): State<RepoIntegrity>,
+    Query(input): Query<GetRecordParams>,
 ) -> Result<Response<Body>> {
-    let mut repo = open_repo_db(&config.repo, &db, input.did.as_str())
-        .await
-        .context("failed to open repo")?;
+    let did = parse_repo_param(&db, &client, &input.did).await?;
+    status::require_hosted(&db, did.as_str()).await?;
+    let mut repo = integrity.open(did.as_str()).await?;
 
     let key = format!("{}/{}", input.collection.as_str(), input.rkey.as_str());
 
@@ -139,10 +298,12 @@ async fn get_record(
 
 async fn get_repo_status(
     State(db): State<Db>,
-    Query(input): Query<sync::get_repo::ParametersData>,
+    State(client): State<Client>,
+    Query(input): Query<RepoParams>,
 ) -> Result<Json<sync::get_repo_status::Output>> {
-    let did = input.did.as_str();
-    let r = sqlx::query!(r#"SELECT rev, status FROM accounts WHERE did = ?"#, did)
+    let did = parse_repo_param(&db, &client, &input.did).await?;
+    let did_str = did.as_str();
+    let r = sqlx::query!(r#"SELECT rev, status FROM accounts WHERE did = ?"#, did_str)
         .fetch_optional(&db)
         .await
         .context("failed to execute query")?;
@@ -163,21 +324,43 @@ async fn get_repo_status(
         sync::get_repo_status::OutputData {
             active,
             status,
-            did: input.did.clone(),
+            did: did.clone(),
             rev: Some(atrium_api::types::string::Tid::new(r.rev).unwrap()),
         }
         .into(),
     ))
 }
 
+/// The headers of a response carrying a repository export, shared by `GET` and `HEAD` requests.
+fn repo_response(root: &Cid) -> http::response::Builder {
+    Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/vnd.ipld.car")
+        .header(http::header::ETAG, format!("\"{root}\""))
+        .header(http::header::CACHE_CONTROL, REPO_CACHE_CONTROL)
+}
+
 async fn get_repo(
-    State(config): State<AppConfig>,
+    client_id: ClientId,
     State(db): State<Db>,
-    Query(input): Query<sync::get_repo::ParametersData>,
+    State(client): State<Client>,
+    State(integrity): State<RepoIntegrity>,
+    State(limiter): State<SyncLimiter>,
+    Query(input): Query<RepoParams>,
+    headers: HeaderMap,
 ) -> Result<Response<Body>> {
-    let mut repo = open_repo_db(&config.repo, &db, input.did.as_str())
-        .await
-        .context("failed to open repo")?;
+    let did = parse_repo_param(&db, &client, &input.did).await?;
+    status::require_hosted(&db, did.as_str()).await?;
+
+    let root = integrity.head(did.as_str()).await?;
+    if not_modified(&headers, &format!("\"{root}\"")) {
+        return Ok(repo_response(&root)
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .context("failed to construct response")?);
+    }
+
+    let _permit = limiter.acquire(&client_id).await?;
+    let mut repo = integrity.open(did.as_str()).await?;
 
     let mut contents = Vec::new();
     let mut store = CarStore::create_with_roots(std::io::Cursor::new(&mut contents), [repo.root()])
@@ -188,26 +371,51 @@ async fn get_repo(
         .await
         .context("failed to extract records")?;
 
-    Ok(Response::builder()
-        .header(http::header::CONTENT_TYPE, "application/vnd.ipld.car")
+    Ok(repo_response(&repo.root())
+        .header(http::header::CONTENT_LENGTH, contents.len())
         .body(Body::from(contents))
         .context("failed to construct response")?)
 }
 
+/// `HEAD` for `getRepo`: the headers of the export, from the repository's head alone.
+///
+/// N.B: The length of the export isn't known without producing it, so unlike `GET`, this has no
+/// `content-length`.
+async fn head_repo(
+    State(db): State<Db>,
+    State(client): State<Client>,
+    State(integrity): State<RepoIntegrity>,
+    Query(input): Query<RepoParams>,
+) -> Result<Response<Body>> {
+    let did = parse_repo_param(&db, &client, &input.did).await?;
+    status::require_hosted(&db, did.as_str()).await?;
+    let root = integrity.head(did.as_str()).await?;
+
+    // An empty body of unknown length, so that no `content-length: 0` is filled in.
+    let body = Body::from_stream(futures::stream::empty::<
+        std::result::Result<Bytes, std::io::Error>,
+    >());
+    Ok(repo_response(&root)
+        .body(body)
+        .context("failed to construct response")?)
+}
+
 async fn list_blobs(
+    State(config): State<AppConfig>,
     State(db): State<Db>,
-    Query(input): Query<sync::list_blobs::ParametersData>,
+    State(client): State<Client>,
+    State(integrity): State<RepoIntegrity>,
+    Query(input): Query<RepoParams>,
 ) -> Result<Json<sync::list_blobs::Output>> {
-    let did_str = input.did.as_str();
+    let did = parse_repo_param(&db, &client, &input.did).await?;
+    status::require_hosted(&db, did.as_str()).await?;
 
     // TODO: `input.since`
     // TODO: `input.limit`
     // TODO: `input.cursor`
 
-    let cids = sqlx::query_scalar!(r#"SELECT cid FROM blob_ref WHERE did = ?"#, did_str)
-        .fetch_all(&db)
-        .await
-        .context("failed to query blobs")?;
+    let root = integrity.head(did.as_str()).await?;
+    let cids = reindex::blobs(&config, &db, did.as_str(), root).await?;
 
     let cids = cids
         .into_iter()
@@ -224,73 +432,113 @@ async fn list_blobs(
     ))
 }
 
-async fn list_repos(
-    State(db): State<Db>,
-    Query(input): Query<sync::list_repos::ParametersData>,
-) -> Result<Json<sync::list_repos::Output>> {
-    struct Record {
-        did: String,
-        root: String,
-        rev: String,
-    }
-
-    let limit: u16 = input.limit.unwrap_or(LimitedNonZeroU16::MAX).into();
-
-    let r = if let Some(cursor) = &input.cursor {
-        let r = sqlx::query_as!(
-            Record,
-            r#"SELECT did, root, rev FROM accounts WHERE did > ? LIMIT ?"#,
-            cursor,
-            limit
-        )
-        .fetch(&db);
-
-        r.try_collect::<Vec<_>>()
-            .await
-            .context("failed to fetch profiles")?
-    } else {
-        let r = sqlx::query_as!(
-            Record,
-            r#"SELECT did, root, rev FROM accounts LIMIT ?"#,
-            limit
-        )
-        .fetch(&db);
-
-        r.try_collect::<Vec<_>>()
-            .await
-            .context("failed to fetch profiles")?
-    };
-
-    let cursor = r.last().map(|r| r.did.clone());
+/// List repositories with one of the specified statuses, starting after `cursor`.
+pub(super) async fn list_repos_with(
+    db: &Db,
+    statuses: &[AccountStatus],
+    cursor: Option<&str>,
+    limit: u32,
+) -> Result<sync::list_repos::Output> {
+    let r = status::list_accounts(db, statuses, cursor, limit).await?;
+
+    let cursor = (r.len() == limit as usize)
+        .then(|| r.last().map(|r| r.did.clone()))
+        .flatten();
     let repos = r
         .into_iter()
         .map(|r| {
+            let active = r.status == AccountStatus::Active.as_str();
+
             sync::list_repos::RepoData {
-                active: Some(true),
+                active: Some(active),
                 did: Did::new(r.did).unwrap(),
                 head: atrium_api::types::string::Cid::new(Cid::from_str(&r.root).unwrap()),
                 rev: atrium_api::types::string::Tid::new(r.rev).unwrap(),
-                status: None,
+                status: (!active).then_some(r.status),
             }
             .into()
         })
         .collect::<Vec<_>>();
 
-    Ok(Json(sync::list_repos::OutputData { cursor, repos }.into()))
+    Ok(sync::list_repos::OutputData { cursor, repos }.into())
+}
+
+async fn list_repos(
+    State(db): State<Db>,
+    Query(input): Query<sync::list_repos::ParametersData>,
+) -> Result<Json<sync::list_repos::Output>> {
+    let limit: u16 = input.limit.unwrap_or(LimitedNonZeroU16::MAX).into();
+
+    // Only active repositories are listed publicly. See `_admin/listRepos` for the rest.
+    Ok(Json(
+        list_repos_with(
+            &db,
+            &[AccountStatus::Active],
+            input.cursor.as_deref(),
+            limit as u32,
+        )
+        .await?,
+    ))
+}
+
+/// Record that another host (e.g. a relay) has an update for us, so that it can be taken into
+/// account in the bookkeeping for upstream relays.
+async fn notify_of_update(
+    State(fh): State<FirehoseProducer>,
+    Json(input): Json<sync::notify_of_update::Input>,
+) -> Result<()> {
+    let host = firehose::parse_hostname(&input.hostname).map_err(|e| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            e,
+            ErrorMessage::new("InvalidHostname", "hostname must be a bare hostname"),
+        )
+    })?;
+
+    info!("{host} notified us of an update");
+    fh.relays().notified(&host);
+    Ok(())
 }
 
 async fn subscribe_repos(
     ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(fh): State<FirehoseProducer>,
-    Query(input): Query<sync::subscribe_repos::ParametersData>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |ws| async move {
-        fh.client_connection(ws, input.cursor).await;
-    })
+    Query(input): Query<SubscribeReposParams>,
+) -> Result<impl IntoResponse> {
+    let cursor = parse_cursor(input.cursor)?;
+    let filter = parse_did_filter(input.dids.as_deref());
+
+    // Turn away connections past the limits before upgrading them.
+    let admission = fh.admit(addr).map_err(|rejection| {
+        Error::with_message(
+            StatusCode::TOO_MANY_REQUESTS,
+            anyhow!("rejected firehose connection from {addr}: {rejection}"),
+            ErrorMessage::new("RateLimitExceeded", rejection.to_string()),
+        )
+    })?;
+
+    Ok(ws.on_upgrade(fh.deflate(), move |ws| async move {
+        fh.client_connection(ws, cursor, admission, filter).await;
+    }))
 }
 
+/// The sync endpoints. These only need part of the application state, so they can be mounted on
+/// their own.
 #[rustfmt::skip]
-pub fn routes() -> axum::Router<AppState> {
+pub fn routes<S>() -> Routes<S>
+where
+    S: Clone + Send + Sync + 'static,
+    AppConfig: FromRef<S>,
+    Db: FromRef<S>,
+    Client: FromRef<S>,
+    SigningKey: FromRef<S>,
+    SyncLimiter: FromRef<S>,
+    RepoIntegrity: FromRef<S>,
+    FirehoseProducer: FromRef<S>,
+    Tiering: FromRef<S>,
+    Revocations: FromRef<S>,
+{
     // UG /xrpc/com.atproto.sync.getBlob
     // UG /xrpc/com.atproto.sync.getBlocks
     // UG /xrpc/com.atproto.sync.getLatestCommit
@@ -299,15 +547,296 @@ pub fn routes() -> axum::Router<AppState> {
     // UG /xrpc/com.atproto.sync.getRepo
     // UG /xrpc/com.atproto.sync.listBlobs
     // UG /xrpc/com.atproto.sync.listRepos
+    // UP /xrpc/com.atproto.sync.notifyOfUpdate
     // UG /xrpc/com.atproto.sync.subscribeRepos
-    Router::new()
-        .route(concat!("/", sync::get_blob::NSID),          get(get_blob))
+    Routes::new()
+        .route(concat!("/", sync::get_blob::NSID),          get(get_blob).head(head_blob))
         .route(concat!("/", sync::get_blocks::NSID),        get(get_blocks))
         .route(concat!("/", sync::get_latest_commit::NSID), get(get_latest_commit))
         .route(concat!("/", sync::get_record::NSID),        get(get_record))
         .route(concat!("/", sync::get_repo_status::NSID),   get(get_repo_status))
-        .route(concat!("/", sync::get_repo::NSID),          get(get_repo))
+        .route(concat!("/", sync::get_repo::NSID),          get(get_repo).head(head_repo))
         .route(concat!("/", sync::list_blobs::NSID),        get(list_blobs))
         .route(concat!("/", sync::list_repos::NSID),        get(list_repos))
+        .route(concat!("/", sync::notify_of_update::NSID),  post(notify_of_update))
         .route(concat!("/", sync::subscribe_repos::NSID),   get(subscribe_repos))
+        .map_router(|r| r.layer(DefaultBodyLimit::max(MAX_JSON_BODY)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use atrium_crypto::keypair::Secp256k1Keypair;
+    use atrium_repo::Repository;
+    use sha2::{Digest, Sha256};
+
+    use crate::{auth::Revocations, endpoints::repo::blob_cid, storage, SigningKey};
+
+    use super::*;
+
+    #[test]
+    fn cursor() {
+        assert_eq!(parse_cursor(None).unwrap(), None);
+        assert_eq!(parse_cursor(Some(0)).unwrap(), Some(0));
+        assert_eq!(parse_cursor(Some(42)).unwrap(), Some(42));
+
+        let resp = parse_cursor(Some(-1)).unwrap_err().into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// Check that the `HEAD` response carries the same headers as the `GET` response, and no body.
+    async fn assert_parity(get: Response<Body>, head: Response<Body>, names: &[http::HeaderName]) {
+        assert_eq!(get.status(), StatusCode::OK);
+        assert_eq!(head.status(), StatusCode::OK);
+        for name in names {
+            assert!(get.headers().contains_key(name), "{name}");
+            assert_eq!(get.headers().get(name), head.headers().get(name), "{name}");
+        }
+
+        let body = axum::body::to_bytes(head.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn head_parity() {
+        let dir = crate::testing::temp_dir("head");
+        let config = crate::testing::config(&dir, serde_json::json!({}));
+
+        let db = crate::testing::memory_db().await;
+
+        // A blob.
+        let data = b"hello, world";
+        let cid = blob_cid(Sha256::digest(data).as_slice());
+        std::fs::write(dir.join("blob").join(format!("{cid}.blob")), data).unwrap();
+        let params = || {
+            Query(sync::get_blob::ParametersData {
+                cid: atrium_api::types::string::Cid::new(cid),
+                did: Did::new("did:plc:alice".to_string()).unwrap(),
+            })
+        };
+
+        let tiering = || State(Tiering::new(&config, db.clone()));
+        let get = get_blob(
+            State(config.clone()),
+            State(db.clone()),
+            tiering(),
+            params(),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let head = head_blob(
+            State(config.clone()),
+            State(db.clone()),
+            tiering(),
+            params(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(head.headers()[http::header::ETAG], format!("\"{cid}\""));
+        assert_parity(
+            get,
+            head,
+            &[
+                http::header::CONTENT_LENGTH,
+                http::header::CONTENT_TYPE,
+                http::header::ETAG,
+                http::header::CACHE_CONTROL,
+            ],
+        )
+        .await;
+
+        // A repository export.
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let did = "did:plc:alice";
+        let (root, rev) = {
+            let file = tokio::fs::File::create(storage::repo_path(&config.repo, did).unwrap())
+                .await
+                .unwrap();
+            let mut store = CarStore::create(file).await.unwrap();
+            let builder = Repository::create(&mut store, Did::new(did.to_string()).unwrap())
+                .await
+                .unwrap();
+            let sig = skey.sign(&builder.bytes()).unwrap();
+            let repo = builder.finalize(sig).await.unwrap();
+            (repo.root(), repo.commit().rev().to_string())
+        };
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', ?, '', ?)"#,
+        )
+        .bind(did)
+        .bind(root.to_string())
+        .bind(&rev)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+        let integrity = RepoIntegrity::new(config.repo.clone(), db.clone(), client.clone(), skey);
+        let limiter = SyncLimiter::new(&config.rate_limit);
+        let params = || {
+            Query(RepoParams {
+                did: did.to_string(),
+            })
+        };
+
+        let get = get_repo(
+            ClientId::Ip("127.0.0.1".parse().unwrap()),
+            State(db.clone()),
+            State(client.clone()),
+            State(integrity.clone()),
+            State(limiter),
+            params(),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let head = head_repo(State(db), State(client), State(integrity), params())
+            .await
+            .unwrap();
+        assert_eq!(head.headers()[http::header::ETAG], format!("\"{root}\""));
+        assert!(!head.headers().contains_key(http::header::CONTENT_LENGTH));
+        assert_parity(
+            get,
+            head,
+            &[
+                http::header::CONTENT_TYPE,
+                http::header::ETAG,
+                http::header::CACHE_CONTROL,
+            ],
+        )
+        .await;
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Just the state the sync endpoints need: no credentials, keys for other purposes, etc.
+    #[derive(Clone, FromRef)]
+    struct SyncState {
+        config: AppConfig,
+        db: Db,
+        client: Client,
+        signing_key: SigningKey,
+        sync_limiter: SyncLimiter,
+        repo_integrity: RepoIntegrity,
+        firehose: FirehoseProducer,
+        tiering: Tiering,
+        revocations: Revocations,
+    }
+
+    #[tokio::test]
+    async fn standalone() {
+        let dir = crate::testing::temp_dir("sync");
+        let config = crate::testing::config(&dir, serde_json::json!({}));
+
+        let db = crate::testing::memory_db().await;
+
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let did = "did:plc:alice";
+        let (root, rev) = {
+            let file = tokio::fs::File::create(storage::repo_path(&config.repo, did).unwrap())
+                .await
+                .unwrap();
+            let mut store = CarStore::create(file).await.unwrap();
+            let builder = Repository::create(&mut store, Did::new(did.to_string()).unwrap())
+                .await
+                .unwrap();
+            let sig = skey.sign(&builder.bytes()).unwrap();
+            let repo = builder.finalize(sig).await.unwrap();
+            (repo.root(), repo.commit().rev().to_string())
+        };
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', ?, '', ?)"#,
+        )
+        .bind(did)
+        .bind(root.to_string())
+        .bind(&rev)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+        let (_, firehose) = crate::firehose::spawn(config.clone(), db.clone())
+            .await
+            .unwrap();
+        let state = SyncState {
+            sync_limiter: SyncLimiter::new(&config.rate_limit),
+            tiering: Tiering::new(&config, db.clone()),
+            repo_integrity: RepoIntegrity::new(
+                config.repo.clone(),
+                db.clone(),
+                client.clone(),
+                skey.clone(),
+            ),
+            config,
+            db,
+            client,
+            signing_key: skey,
+            revocations: Revocations::default(),
+            firehose,
+        };
+
+        let relays = state.firehose.relays().clone();
+        let (router, endpoints) = routes().into_parts();
+        assert!(endpoints.contains(&sync::get_repo::NSID.to_string()));
+        let app = axum::Router::new().nest("/xrpc", router).with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
+
+        let repos: serde_json::Value =
+            reqwest::get(format!("http://{addr}/xrpc/{}", sync::list_repos::NSID))
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+        assert_eq!(repos["repos"][0]["did"], did);
+        assert_eq!(repos["repos"][0]["head"], root.to_string());
+
+        let resp = reqwest::get(format!(
+            "http://{addr}/xrpc/{}?did={did}",
+            sync::get_repo::NSID
+        ))
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[http::header::CONTENT_TYPE],
+            "application/vnd.ipld.car"
+        );
+        assert!(!resp.bytes().await.unwrap().is_empty());
+
+        // Another host notifies us of an update, and is recorded among the relays.
+        let notify = |hostname: &str| {
+            reqwest::Client::new()
+                .post(format!(
+                    "http://{addr}/xrpc/{}",
+                    sync::notify_of_update::NSID
+                ))
+                .json(&serde_json::json!({ "hostname": hostname }))
+                .send()
+        };
+        let resp = notify("https://relay.example.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(relays.snapshot()["relay.example.com"]
+            .last_notified
+            .is_some());
+
+        let resp = notify("relay.example.com/xrpc").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["error"], "InvalidHostname");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,40 @@
+//! Temporary endpoints (`com.atproto.temp`).
+
+use atrium_api::com::atproto::temp;
+use axum::{extract::State, routing::get, Json};
+use constcat::concat;
+
+use crate::{
+    auth::AuthenticatedUser, capabilities::Routes, config::AppConfig, signup_queue, Db, Result,
+};
+
+/// Report an account's place in the signup queue (see `signup_queue`).
+async fn check_signup_queue(
+    user: AuthenticatedUser,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+) -> Result<Json<temp::check_signup_queue::Output>> {
+    let place = signup_queue::place(&db, &user.did()).await?;
+    let estimated_time_ms = match (&config.signup_queue, place) {
+        (Some(queue), Some(place)) => {
+            Some(signup_queue::estimated_time(queue, place).as_millis() as i64)
+        }
+        _ => None,
+    };
+
+    Ok(Json(
+        temp::check_signup_queue::OutputData {
+            activated: place.is_none(),
+            estimated_time_ms,
+            place_in_queue: place,
+        }
+        .into(),
+    ))
+}
+
+#[rustfmt::skip]
+pub fn routes() -> Routes {
+    // AG /xrpc/com.atproto.temp.checkSignupQueue
+    Routes::new()
+        .route(concat!("/", temp::check_signup_queue::NSID), get(check_signup_queue))
+}
@@ -0,0 +1,648 @@
+//! Resumable blob uploads.
+//!
+//! This is a non-standard extension to `com.atproto.repo.uploadBlob` for clients uploading large
+//! blobs over unreliable connections. The protocol is a simplified variant of tus:
+//!
+//! 1. `POST /xrpc/_blob/createUpload` creates an upload session.
+//! 2. `PATCH /xrpc/_blob/upload?id=...` appends a chunk. The `upload-offset` header must match the
+//!    number of bytes the server has already received.
+//! 3. `GET /xrpc/_blob/upload?id=...` reports the current offset, for resuming after a disconnect.
+//! 4. `POST /xrpc/_blob/finalizeUpload` computes the CID and produces the blob ref.
+//!
+//! Sessions are persisted in the database, so uploads survive a restart of either end. Requests
+//! for the same upload are serialized by its lock ([`UploadLocks`]), so that concurrent chunks
+//! can't both be appended at the same offset.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use atrium_api::com::atproto::repo;
+use axum::{
+    body::Body,
+    extract::{Query, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    routing::{get, post},
+    Json,
+};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::AuthenticatedUser,
+    capabilities::Routes,
+    config::AppConfig,
+    error::ErrorMessage,
+    events::{Event, EventBus},
+    stats::{StorageDelta, StorageStats},
+    storage::RepoLocks,
+    Db, Error, Result,
+};
+
+use super::repo::blob_cid;
+
+/// The header carrying the upload offset, in both directions.
+const UPLOAD_OFFSET: &str = "upload-offset";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct CreateUploadInput {
+    /// The MIME type of the completed blob.
+    mime_type: String,
+    /// The expected total size of the blob, if known.
+    size: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct UploadParams {
+    id: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct UploadStatus {
+    id: String,
+    offset: u64,
+    expires_at: String,
+}
+
+struct UploadSession {
+    mime: String,
+    expires_at: String,
+}
+
+/// Per-upload locks, held from checking an upload's offset to appending a chunk to it (or from
+/// reading it to finalizing it).
+#[derive(Debug, Clone, Default)]
+pub struct UploadLocks(RepoLocks);
+
+impl UploadLocks {
+    /// Wait for, and take, the lock of an upload. It's released when the guard is dropped.
+    pub async fn lock(&self, id: &str) -> tokio::sync::OwnedMutexGuard<()> {
+        self.0.lock(id).await
+    }
+}
+
+pub fn upload_path(config: &AppConfig, id: &str) -> std::path::PathBuf {
+    config.blob.path.join(format!("upload-{id}.part"))
+}
+
+/// Look up an unexpired upload session owned by the specified user.
+async fn find_session(db: &Db, id: &str, did: &str) -> Result<UploadSession> {
+    let r: Option<(String, String)> = sqlx::query_as(
+        r#"
+        SELECT mime, expires_at FROM blob_uploads
+            WHERE id = ? AND did = ? AND expires_at > datetime('now')
+        "#,
+    )
+    .bind(id)
+    .bind(did)
+    .fetch_optional(db)
+    .await
+    .context("failed to query upload session")?;
+
+    match r {
+        Some((mime, expires_at)) => Ok(UploadSession { mime, expires_at }),
+        None => Err(Error::with_message(
+            StatusCode::NOT_FOUND,
+            anyhow!("upload session {id} not found"),
+            ErrorMessage::new("UploadNotFound", "upload session not found or expired"),
+        )),
+    }
+}
+
+/// Fetch the number of bytes received so far for an upload.
+async fn current_offset(config: &AppConfig, id: &str) -> Result<u64> {
+    Ok(tokio::fs::metadata(upload_path(config, id))
+        .await
+        .context("failed to query upload metadata")?
+        .len())
+}
+
+async fn create_upload(
+    user: AuthenticatedUser,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    Json(input): Json<CreateUploadInput>,
+) -> Result<Json<UploadStatus>> {
+    if let Some(size) = input.size {
+        if size > config.blob.limit {
+            return Err(Error::with_status(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                anyhow!("size {} above limit {}", size, config.blob.limit),
+            ));
+        }
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let did = user.did();
+    let ttl = format!("+{} seconds", config.blob.upload_ttl);
+
+    tokio::fs::File::create_new(upload_path(&config, &id))
+        .await
+        .context("failed to create upload file")?;
+
+    let expires_at: String = sqlx::query_scalar(
+        r#"
+        INSERT INTO blob_uploads (id, did, mime, created_at, expires_at)
+            VALUES (?, ?, ?, datetime('now'), datetime('now', ?))
+            RETURNING expires_at
+        "#,
+    )
+    .bind(&id)
+    .bind(&did)
+    .bind(&input.mime_type)
+    .bind(&ttl)
+    .fetch_one(&db)
+    .await
+    .context("failed to create upload session")?;
+
+    Ok(Json(UploadStatus {
+        id,
+        offset: 0,
+        expires_at,
+    }))
+}
+
+async fn get_upload(
+    user: AuthenticatedUser,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    Query(input): Query<UploadParams>,
+) -> Result<Json<UploadStatus>> {
+    let session = find_session(&db, &input.id, &user.did()).await?;
+    let offset = current_offset(&config, &input.id).await?;
+
+    Ok(Json(UploadStatus {
+        id: input.id,
+        offset,
+        expires_at: session.expires_at,
+    }))
+}
+
+async fn upload_chunk(
+    user: AuthenticatedUser,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(locks): State<UploadLocks>,
+    Query(input): Query<UploadParams>,
+    request: Request<Body>,
+) -> Result<Json<UploadStatus>> {
+    let offset = request
+        .headers()
+        .get(UPLOAD_OFFSET)
+        .context("no upload-offset provided")?
+        .to_str()
+        .map_err(anyhow::Error::from)
+        .and_then(|s| s.parse::<u64>().map_err(anyhow::Error::from))
+        .map_err(|e| {
+            Error::with_message(
+                StatusCode::BAD_REQUEST,
+                e.context("invalid upload-offset header"),
+                ErrorMessage::new("InvalidRequest", "invalid upload-offset header"),
+            )
+        })?;
+
+    // Held until the chunk has been appended, so that a concurrent request for this upload sees
+    // the offset it leaves behind.
+    let _lock = locks.lock(&input.id).await;
+    let session = find_session(&db, &input.id, &user.did()).await?;
+
+    // The client must resume exactly where the server left off.
+    let current = current_offset(&config, &input.id).await?;
+    if offset != current {
+        let mut h = HeaderMap::new();
+        h.insert(UPLOAD_OFFSET, HeaderValue::from(current));
+
+        return Err(Error::with_message(
+            StatusCode::CONFLICT,
+            anyhow!("upload offset mismatch: client {offset}, server {current}"),
+            ErrorMessage::new(
+                "InvalidOffset",
+                format!("upload offset does not match the current offset {current}"),
+            ),
+        )
+        .with_headers(h));
+    }
+
+    let filename = upload_path(&config, &input.id);
+    let mut file = tokio::fs::File::options()
+        .append(true)
+        .open(&filename)
+        .await
+        .context("failed to open upload file")?;
+
+    let mut len = current;
+    let mut stream = request.into_body().into_data_stream();
+
+    // N.B: If the client disconnects partway through a chunk, whatever was received is kept.
+    // The client can query the offset and resume from there.
+    while let Some(bytes) = stream.try_next().await.context("failed to receive chunk")? {
+        len += bytes.len() as u64;
+
+        if len > config.blob.limit {
+            // Discard this chunk.
+            file.set_len(current)
+                .await
+                .context("failed to truncate upload")?;
+
+            return Err(Error::with_status(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                anyhow!("size above limit {}", config.blob.limit),
+            ));
+        }
+
+        file.write_all(&bytes)
+            .await
+            .context("failed to write chunk")?;
+    }
+
+    file.flush().await.context("failed to flush upload")?;
+
+    Ok(Json(UploadStatus {
+        id: input.id,
+        offset: len,
+        expires_at: session.expires_at,
+    }))
+}
+
+async fn finalize_upload(
+    user: AuthenticatedUser,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(stats): State<StorageStats>,
+    State(events): State<EventBus>,
+    State(locks): State<UploadLocks>,
+    Json(input): Json<UploadParams>,
+) -> Result<Json<repo::upload_blob::Output>> {
+    let did = user.did();
+    // Wait for any chunk still being appended.
+    let _lock = locks.lock(&input.id).await;
+    let session = find_session(&db, &input.id, &did).await?;
+
+    let filename = upload_path(&config, &input.id);
+    let mut file = tokio::fs::File::open(&filename)
+        .await
+        .context("failed to open upload file")?;
+
+    let mut len = 0usize;
+    let mut sha = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await.context("failed to read upload")?;
+        if n == 0 {
+            break;
+        }
+
+        len += n;
+        sha.update(&buf[..n]);
+    }
+
+    drop(file);
+
+    // Enforce the same policy as a single-shot `uploadBlob`.
+    if len as u64 > config.blob.limit {
+        return Err(Error::with_status(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            anyhow!("size {} above limit {}", len, config.blob.limit),
+        ));
+    }
+
+    let cid = blob_cid(sha.finalize().as_slice());
+    let cid_str = cid.to_string();
+
+    tokio::fs::rename(
+        &filename,
+        config.blob.path.join(format!("{}.blob", cid_str)),
+    )
+    .await
+    .context("failed to finalize blob")?;
+
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+
+    sqlx::query(r#"INSERT INTO blob_ref (cid, did, record) VALUES (?, ?, NULL)"#)
+        .bind(&cid_str)
+        .bind(&did)
+        .execute(&mut *tx)
+        .await
+        .context("failed to insert blob into database")?;
+
+    sqlx::query(r#"DELETE FROM blob_uploads WHERE id = ?"#)
+        .bind(&input.id)
+        .execute(&mut *tx)
+        .await
+        .context("failed to remove upload session")?;
+
+    tx.commit().await.context("failed to commit transaction")?;
+
+    if let Err(e) = stats
+        .record(
+            &did,
+            StorageDelta {
+                blob_bytes: len as i64,
+                ..Default::default()
+            },
+        )
+        .await
+    {
+        warn!("failed to update storage statistics for {did}: {e:?}");
+    }
+
+    events
+        .publish(Event::BlobUploaded {
+            did,
+            cid,
+            size: len as u64,
+        })
+        .await;
+
+    Ok(Json(
+        repo::upload_blob::OutputData {
+            blob: atrium_api::types::BlobRef::Typed(atrium_api::types::TypedBlobRef::Blob(
+                atrium_api::types::Blob {
+                    r#ref: atrium_api::types::CidLink(cid),
+                    mime_type: session.mime,
+                    size: len,
+                },
+            )),
+        }
+        .into(),
+    ))
+}
+
+/// Periodically discard expired upload sessions along with their partial data.
+pub async fn cleanup_uploads(config: AppConfig, db: Db) {
+    loop {
+        let r: std::result::Result<Vec<String>, _> = sqlx::query_scalar(
+            r#"DELETE FROM blob_uploads WHERE expires_at <= datetime('now') RETURNING id"#,
+        )
+        .fetch_all(&db)
+        .await;
+
+        match r {
+            Ok(ids) => {
+                for id in &ids {
+                    if let Err(e) = tokio::fs::remove_file(upload_path(&config, id)).await {
+                        warn!("failed to remove expired upload {id}: {e}");
+                    }
+                }
+
+                if !ids.is_empty() {
+                    info!("discarded {} expired uploads", ids.len());
+                }
+            }
+            Err(e) => warn!("failed to clean up expired uploads: {e}"),
+        }
+
+        tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+    }
+}
+
+#[rustfmt::skip]
+pub fn routes() -> Routes {
+    // AP /xrpc/_blob/createUpload
+    // AG /xrpc/_blob/upload
+    // AP /xrpc/_blob/upload (PATCH)
+    // AP /xrpc/_blob/finalizeUpload
+    Routes::new()
+        .route("/_blob/createUpload",   post(create_upload))
+        .route("/_blob/upload",          get(get_upload).patch(upload_chunk))
+        .route("/_blob/finalizeUpload", post(finalize_upload))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use axum::{
+        body::{to_bytes, Bytes},
+        response::IntoResponse,
+    };
+    use futures::StreamExt;
+    use serde_json::json;
+
+    use super::*;
+
+    const DID: &str = "did:plc:alice";
+
+    struct TestUpload {
+        config: AppConfig,
+        db: Db,
+        locks: UploadLocks,
+        id: String,
+    }
+
+    async fn test_upload() -> TestUpload {
+        let dir = crate::testing::temp_dir("upload");
+        let config = crate::testing::config(&dir, json!({}));
+
+        let db = crate::testing::file_db(&dir, 16).await;
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', '', '', '')"#,
+        )
+        .bind(DID)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let Json(status) = create_upload(
+            AuthenticatedUser::for_test(DID),
+            State(config.clone()),
+            State(db.clone()),
+            Json(CreateUploadInput {
+                mime_type: "application/octet-stream".to_string(),
+                size: None,
+            }),
+        )
+        .await
+        .unwrap();
+
+        TestUpload {
+            config,
+            db,
+            locks: UploadLocks::default(),
+            id: status.id,
+        }
+    }
+
+    /// Append a chunk, streamed as the specified pieces. A piece that's an error disconnects the
+    /// client at that point.
+    async fn patch(
+        upload: &TestUpload,
+        offset: u64,
+        pieces: Vec<std::io::Result<Bytes>>,
+    ) -> Result<Json<UploadStatus>> {
+        let body = futures::stream::iter(pieces).then(|piece| async move {
+            // Give concurrent requests a chance to interleave.
+            tokio::task::yield_now().await;
+            piece
+        });
+        let request = Request::builder()
+            .method("PATCH")
+            .header(UPLOAD_OFFSET, offset)
+            .body(Body::from_stream(body))
+            .unwrap();
+
+        upload_chunk(
+            AuthenticatedUser::for_test(DID),
+            State(upload.config.clone()),
+            State(upload.db.clone()),
+            State(upload.locks.clone()),
+            Query(UploadParams {
+                id: upload.id.clone(),
+            }),
+            request,
+        )
+        .await
+    }
+
+    async fn error_body(e: Error) -> (StatusCode, HeaderMap, serde_json::Value) {
+        let response = e.into_response();
+        let (status, headers) = (response.status(), response.headers().clone());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, headers, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn resume() {
+        let upload = test_upload().await;
+        let data: Vec<u8> = (0..900u32).map(|i| (i % 251) as u8).collect();
+        let (first, second, third) = (&data[..300], &data[300..600], &data[600..]);
+
+        let Json(status) = patch(&upload, 0, vec![Ok(Bytes::copy_from_slice(first))])
+            .await
+            .unwrap();
+        assert_eq!(status.offset, 300);
+
+        // The client disconnects partway through the second chunk. What arrived is kept.
+        let disconnected = patch(
+            &upload,
+            300,
+            vec![
+                Ok(Bytes::copy_from_slice(&second[..100])),
+                Err(std::io::ErrorKind::ConnectionReset.into()),
+            ],
+        )
+        .await;
+        assert!(disconnected.is_err());
+
+        // It resumes from the offset the server reports.
+        let Json(status) = get_upload(
+            AuthenticatedUser::for_test(DID),
+            State(upload.config.clone()),
+            State(upload.db.clone()),
+            Query(UploadParams {
+                id: upload.id.clone(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status.offset, 400);
+
+        patch(
+            &upload,
+            400,
+            vec![Ok(Bytes::copy_from_slice(&second[100..]))],
+        )
+        .await
+        .unwrap();
+        let Json(status) = patch(&upload, 600, vec![Ok(Bytes::copy_from_slice(third))])
+            .await
+            .unwrap();
+        assert_eq!(status.offset, data.len() as u64);
+
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build();
+        let Json(output) = finalize_upload(
+            AuthenticatedUser::for_test(DID),
+            State(upload.config.clone()),
+            State(upload.db.clone()),
+            State(StorageStats::new(None, client, upload.db.clone())),
+            State(EventBus::new()),
+            State(upload.locks.clone()),
+            Json(UploadParams {
+                id: upload.id.clone(),
+            }),
+        )
+        .await
+        .unwrap();
+
+        // The blob is identical to one uploaded in a single shot.
+        let atrium_api::types::BlobRef::Typed(atrium_api::types::TypedBlobRef::Blob(blob)) =
+            &output.blob
+        else {
+            panic!("unexpected blob ref {:?}", output.blob);
+        };
+        let expected = blob_cid(Sha256::digest(&data).as_slice());
+        assert_eq!(blob.r#ref.0, expected);
+        assert_eq!(blob.size, data.len());
+        assert_eq!(
+            std::fs::read(upload.config.blob.path.join(format!("{expected}.blob"))).unwrap(),
+            data
+        );
+    }
+
+    #[tokio::test]
+    async fn offset_mismatch() {
+        let upload = test_upload().await;
+
+        patch(&upload, 0, vec![Ok(Bytes::from_static(b"hello"))])
+            .await
+            .unwrap();
+
+        // Neither replaying a chunk nor skipping ahead is accepted, and the current offset is
+        // reported back.
+        for offset in [0, 10] {
+            let e = patch(&upload, offset, vec![Ok(Bytes::from_static(b"world"))])
+                .await
+                .unwrap_err();
+            let (status, headers, body) = error_body(e).await;
+            assert_eq!(status, StatusCode::CONFLICT);
+            assert_eq!(body["error"], "InvalidOffset");
+            assert_eq!(headers[UPLOAD_OFFSET], "5");
+        }
+
+        assert_eq!(
+            std::fs::read(upload_path(&upload.config, &upload.id)).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_chunks() {
+        const N: u8 = 8;
+
+        let upload = Arc::new(test_upload().await);
+
+        // Every client sends its own chunk for the same offset, a piece at a time.
+        let tasks: Vec<_> = (0..N)
+            .map(|i| {
+                let upload = upload.clone();
+                tokio::spawn(async move {
+                    let pieces = (0..10).map(|_| Ok(Bytes::from(vec![i; 10]))).collect();
+                    patch(&upload, 0, pieces).await
+                })
+            })
+            .collect();
+
+        let mut appended = Vec::new();
+        for (i, task) in tasks.into_iter().enumerate() {
+            match task.await.unwrap() {
+                Ok(Json(status)) => {
+                    assert_eq!(status.offset, 100);
+                    appended.push(i as u8);
+                }
+                Err(e) => assert_eq!(error_body(e).await.0, StatusCode::CONFLICT),
+            }
+        }
+
+        // Exactly one chunk was appended, whole.
+        assert_eq!(appended.len(), 1);
+        assert_eq!(
+            std::fs::read(upload_path(&upload.config, &upload.id)).unwrap(),
+            vec![appended[0]; 100]
+        );
+    }
+}
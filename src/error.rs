@@ -1,16 +1,41 @@
 use axum::{
     body::Body,
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     response::{IntoResponse, Response},
 };
+use serde::Serialize;
 use thiserror::Error;
 use tracing::error;
 
+/// An XRPC error body, as returned to the requester.
+///
+/// Reference: https://atproto.com/specs/xrpc#error-responses
+#[derive(Serialize, Debug, Clone)]
+pub struct ErrorMessage {
+    /// The machine-readable error name (e.g. `InvalidRequest`).
+    error: String,
+    /// A human-readable description of the error.
+    message: String,
+}
+
+impl ErrorMessage {
+    pub fn new(error: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            message: message.into(),
+        }
+    }
+}
+
 /// `axum`-compatible error handler.
 #[derive(Error)]
 pub struct Error {
     status: StatusCode,
     err: anyhow::Error,
+    /// The XRPC error body sent to the requester, if any.
+    message: Option<ErrorMessage>,
+    /// Additional headers attached to the response.
+    headers: HeaderMap,
 }
 
 impl Error {
@@ -22,8 +47,49 @@ impl Error {
         Self {
             status,
             err: err.into(),
+            message: None,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    /// Construct an error that will be returned to the requester as an XRPC error body.
+    pub fn with_message(
+        status: StatusCode,
+        err: impl Into<anyhow::Error>,
+        message: impl Into<ErrorMessage>,
+    ) -> Self {
+        Self {
+            status,
+            err: err.into(),
+            message: Some(message.into()),
+            headers: HeaderMap::new(),
         }
     }
+
+    /// An `ExpiredToken` error, for a credential that was once valid. Clients respond to this by
+    /// refreshing their session.
+    pub fn expired_token(err: impl Into<anyhow::Error>) -> Self {
+        Self::with_message(
+            StatusCode::BAD_REQUEST,
+            err,
+            ErrorMessage::new("ExpiredToken", "token has expired"),
+        )
+    }
+
+    /// An `InvalidToken` error, for a credential that is malformed, forged, or no longer usable.
+    pub fn invalid_token(err: impl Into<anyhow::Error>) -> Self {
+        Self::with_message(
+            StatusCode::BAD_REQUEST,
+            err,
+            ErrorMessage::new("InvalidToken", "token is invalid"),
+        )
+    }
+
+    /// Attach additional headers to the error response.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers.extend(headers);
+        self
+    }
 }
 
 impl From<anyhow::Error> for Error {
@@ -31,6 +97,8 @@ impl From<anyhow::Error> for Error {
         Self {
             status: StatusCode::INTERNAL_SERVER_ERROR,
             err,
+            message: None,
+            headers: HeaderMap::new(),
         }
     }
 }
@@ -51,10 +119,18 @@ impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
         error!("{:?}", self.err);
 
-        // N.B: Forward out the error message to the requester if this is a debug build.
-        // This is insecure for production builds, so we'll return an empty body if this
-        // is a release build.
-        if cfg!(debug_assertions) {
+        let mut resp = if let Some(message) = &self.message {
+            // XRPC error bodies are intended for the requester, so they're safe to return
+            // regardless of build type.
+            Response::builder()
+                .status(self.status)
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .body(Body::new(serde_json::to_string(message).unwrap()))
+                .unwrap()
+        } else if cfg!(debug_assertions) {
+            // N.B: Forward out the error message to the requester if this is a debug build.
+            // This is insecure for production builds, so we'll return an empty body if this
+            // is a release build.
             Response::builder()
                 .status(self.status)
                 .body(Body::new(format!("{:?}", self.err)))
@@ -64,6 +140,9 @@ impl IntoResponse for Error {
                 .status(self.status)
                 .body(Body::empty())
                 .unwrap()
-        }
+        };
+
+        resp.headers_mut().extend(self.headers);
+        resp
     }
 }
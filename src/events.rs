@@ -0,0 +1,313 @@
+//! An internal bus for events that more than one part of the PDS wants to observe.
+//!
+//! Write paths publish each event once, rather than calling every interested service in turn.
+//! Subscribers (the firehose, migration tracking, ...) each run in their own task, and receive
+//! events through a bounded queue in the order they were published. Publishing waits for room in
+//! every queue, so a subscriber that falls behind slows writers down rather than missing events;
+//! how far behind each subscriber is is reported with the [`EVENTS_LAG`] gauge.
+
+use std::{future::Future, sync::Arc};
+
+use atrium_api::com::atproto::sync::subscribe_repos::{AccountData, IdentityData, SyncData};
+use atrium_repo::Cid;
+use metrics::{counter, gauge};
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+use crate::{
+    config::RepoConfig,
+    firehose::{self, Commit, FirehoseProducer},
+    metrics::{EVENTS_LAG, EVENTS_PUBLISHED},
+    migration, storage, Db,
+};
+
+/// The default capacity of a subscriber's queue.
+pub const QUEUE_CAPACITY: usize = 1000;
+
+/// Something that happened on this PDS.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A commit was applied to a repository.
+    Commit(Arc<Commit>),
+    /// An account's status changed (e.g. it was created, or activated).
+    Account(AccountData),
+    /// An account's identity changed (e.g. its handle).
+    Identity(IdentityData),
+    /// A repository was replaced wholesale (e.g. imported, or rolled back), so consumers must
+    /// resynchronize it.
+    Sync(SyncData),
+    /// A blob was uploaded to an account.
+    BlobUploaded { did: String, cid: Cid, size: u64 },
+}
+
+impl Event {
+    fn kind(&self) -> &'static str {
+        match self {
+            Event::Commit(_) => "commit",
+            Event::Account(_) => "account",
+            Event::Identity(_) => "identity",
+            Event::Sync(_) => "sync",
+            Event::BlobUploaded { .. } => "blob",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Subscriber {
+    name: &'static str,
+    tx: mpsc::Sender<Event>,
+}
+
+impl Subscriber {
+    fn report_lag(&self) {
+        let queued = self.tx.max_capacity() - self.tx.capacity();
+        gauge!(EVENTS_LAG, "subscriber" => self.name).set(queued as f64);
+    }
+}
+
+/// The event bus. Subscribers are registered on startup, before the bus is shared.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Vec<Subscriber>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a subscriber, spawning a task that passes it each event in turn.
+    pub fn subscribe<F, Fut>(&mut self, name: &'static str, capacity: usize, mut handler: F)
+    where
+        F: FnMut(Event) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel(capacity);
+        let subscriber = Subscriber { name, tx };
+
+        let s = subscriber.clone();
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                handler(event).await;
+                s.report_lag();
+            }
+        });
+
+        self.subscribers.push(subscriber);
+    }
+
+    /// Publish an event to every subscriber.
+    pub async fn publish(&self, event: Event) {
+        counter!(EVENTS_PUBLISHED, "kind" => event.kind()).increment(1);
+
+        for s in &self.subscribers {
+            if s.tx.send(event.clone()).await.is_err() {
+                warn!("event subscriber {} has stopped", s.name);
+            }
+            s.report_lag();
+        }
+    }
+}
+
+/// Publish a [`Event::Sync`] for a repository that was replaced wholesale, now at `root`.
+///
+/// The repository has already been replaced by the time this is called, so a failure to describe
+/// it is logged rather than returned.
+pub async fn publish_sync(bus: &EventBus, config: &RepoConfig, did: &str, root: Cid, rev: &str) {
+    let sync = async {
+        let mut store = storage::open_store(config, did).await?;
+        let did = atrium_api::types::string::Did::new(did.to_string())
+            .map_err(|e| anyhow::anyhow!("invalid did: {e}"))?;
+        firehose::sync_data(&mut store, did, root, rev.to_string()).await
+    };
+
+    match sync.await {
+        Ok(sync) => bus.publish(Event::Sync(sync)).await,
+        Err(e) => warn!("failed to announce resynchronization of {did} at {rev}: {e:?}"),
+    }
+}
+
+/// Subscribe the firehose to the bus. Events are sequenced in the order they were published, which
+/// preserves the order of each repository's commits.
+///
+/// Commits are queued for the firehose in the transaction that made them (see
+/// [`firehose::enqueue`]), so the firehose only needs waking to broadcast them.
+/// Other events are queued as they arrive; while one can't be, the subscriber (and so every write
+/// publishing behind it) waits, rather than skipping it.
+pub fn subscribe_firehose(bus: &mut EventBus, fhp: FirehoseProducer) {
+    bus.subscribe("firehose", QUEUE_CAPACITY, move |event| {
+        let fhp = fhp.clone();
+        async move {
+            let r = match event {
+                Event::Commit(_) => {
+                    fhp.wake();
+                    return;
+                }
+                Event::Account(account) => fhp.account(account).await,
+                Event::Identity(identity) => fhp.identity(identity).await,
+                Event::Sync(sync) => fhp.sync(sync).await,
+                Event::BlobUploaded { .. } => return,
+            };
+            if let Err(e) = r {
+                error!("firehose has diverged from the repositories it describes: {e:?}");
+            }
+        }
+    });
+}
+
+/// Subscribe migration tracking to the bus.
+pub fn subscribe_migrations(bus: &mut EventBus, db: Db) {
+    bus.subscribe("migration", QUEUE_CAPACITY, move |event| {
+        let db = db.clone();
+        async move {
+            if let Event::BlobUploaded { did, .. } = event {
+                if let Err(e) = migration::record(&db, &did, migration::Step::BlobImported).await {
+                    warn!("failed to record migration step for {did}: {e:?}");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use atrium_api::types::string::{Datetime, Did};
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{firehose::RepoOp, record::block_cid};
+
+    #[tokio::test]
+    async fn probe() {
+        let db = crate::testing::memory_db().await;
+
+        let config = crate::testing::config("", serde_json::json!({}));
+        let (_, fhp) = crate::firehose::spawn(config.clone(), db.clone())
+            .await
+            .unwrap();
+
+        let mut bus = EventBus::new();
+        subscribe_firehose(&mut bus, fhp.clone());
+        let (tx, mut probe) = mpsc::unbounded_channel();
+        bus.subscribe("probe", 1, move |event| {
+            let tx = tx.clone();
+            async move {
+                let _ = tx.send(event);
+            }
+        });
+
+        // A firehose consumer, to compare the probe against.
+        let (ws, _) = tokio_tungstenite::connect_async(format!(
+            "ws://{}/xrpc/com.atproto.sync.subscribeRepos",
+            serve(fhp).await
+        ))
+        .await
+        .unwrap();
+        let (_, mut frames) = ws.split();
+
+        let cid = block_cid(b"commit");
+        let mut published = Vec::new();
+        for (i, did) in ["did:plc:alice", "did:plc:bob", "did:plc:alice"]
+            .into_iter()
+            .enumerate()
+        {
+            let did = Did::from_str(did).unwrap();
+            let event = if i == 1 {
+                Event::Account(AccountData {
+                    active: true,
+                    did: did.clone(),
+                    seq: 0,
+                    status: None,
+                    time: Datetime::now(),
+                })
+            } else {
+                let commit = Commit {
+                    car: Vec::new(),
+                    ops: vec![RepoOp::Create {
+                        cid,
+                        path: format!("app.bsky.feed.post/{i}"),
+                    }],
+                    cid,
+                    rev: format!("3l3qo2vutsw2{i}"),
+                    did: did.clone(),
+                    pcid: None,
+                    since: None,
+                    blobs: Vec::new(),
+                };
+
+                // As a write would, in the transaction that made the commit.
+                let mut conn = db.acquire().await.unwrap();
+                firehose::enqueue_commit(&mut conn, &config.firehose, &commit)
+                    .await
+                    .unwrap();
+                Event::Commit(Arc::new(commit))
+            };
+
+            let kind = event.kind();
+            bus.publish(event).await;
+            published.push(kind);
+
+            // The firehose broadcasts it.
+            let frame = loop {
+                match frames.next().await.unwrap().unwrap() {
+                    tokio_tungstenite::tungstenite::Message::Binary(frame) => break frame,
+                    _ => continue,
+                }
+            };
+            let contains = |needle: &[u8]| frame.windows(needle.len()).any(|w| w == needle);
+            assert!(contains(format!("#{kind}").as_bytes()));
+            assert!(contains(did.as_str().as_bytes()));
+        }
+        bus.publish(Event::BlobUploaded {
+            did: "did:plc:alice".to_string(),
+            cid,
+            size: 1,
+        })
+        .await;
+
+        published.push("blob");
+
+        // The probe sees every event, in order (including blob uploads, which the firehose has no
+        // use for).
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            seen.push(probe.recv().await.unwrap());
+        }
+        assert_eq!(seen.iter().map(Event::kind).collect::<Vec<_>>(), published);
+    }
+
+    async fn serve(fhp: FirehoseProducer) -> std::net::SocketAddr {
+        use axum::{
+            extract::{ConnectInfo, WebSocketUpgrade},
+            routing::get,
+        };
+
+        let app = axum::Router::new().route(
+            "/xrpc/com.atproto.sync.subscribeRepos",
+            get(
+                move |ws: WebSocketUpgrade,
+                      ConnectInfo(addr): ConnectInfo<std::net::SocketAddr>| async move {
+                    // Backfill from the start, so that no event is missed while connecting.
+                    let admission = fhp.admit(addr).unwrap();
+                    ws.on_upgrade(move |ws| async move {
+                        fhp.client_connection(ws.into(), Some(0), admission, None)
+                            .await;
+                    })
+                },
+            ),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
+            .await
+        });
+        addr
+    }
+}
@@ -1,26 +1,699 @@
-use std::{collections::VecDeque, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use atrium_api::{
     com::atproto::sync::{self},
     types::string::{Datetime, Did, Tid},
 };
-use atrium_repo::Cid;
-use axum::extract::ws::{Message, WebSocket};
-use metrics::{counter, gauge};
+use atrium_repo::{
+    blockstore::{AsyncBlockStoreRead, AsyncBlockStoreWrite, CarStore, DAG_CBOR, SHA2_256},
+    Cid,
+};
+use axum::{
+    body::Bytes,
+    extract::ws::{close_code, CloseFrame, Message},
+};
+use futures::{SinkExt, StreamExt};
+use metrics::{counter, gauge, histogram};
 use rand::Rng;
 use serde::{ser::SerializeMap, Serialize};
+use sqlx::SqliteConnection;
+use tokio::sync::mpsc::error::TrySendError;
 use tracing::{debug, error, info, warn};
+use url::Url;
 
 use crate::{
-    config::AppConfig,
-    metrics::{FIREHOSE_HISTORY, FIREHOSE_LISTENERS, FIREHOSE_MESSAGES, FIREHOSE_SEQUENCE},
-    Client,
+    config::{AppConfig, FirehoseConfig},
+    metrics::{
+        FIREHOSE_AUDIT_ANOMALIES, FIREHOSE_BACKFILLS, FIREHOSE_EVENTS_LOST, FIREHOSE_FRAMES_SENT,
+        FIREHOSE_FRAME_SIZE, FIREHOSE_HISTORY, FIREHOSE_HISTORY_BYTES, FIREHOSE_LISTENERS,
+        FIREHOSE_MESSAGES, FIREHOSE_REJECTED, FIREHOSE_SEQUENCE, FIREHOSE_SEQ_ANOMALIES,
+        RELAY_CONNECTIONS, RELAY_CRAWL_FAILURES, RELAY_CRAWL_OK, RELAY_SEQUENCE,
+    },
+    websocket::Socket,
+    Client, Db,
 };
 
 enum FirehoseMessage {
-    Broadcast(sync::subscribe_repos::Message),
-    Connect((axum::extract::ws::WebSocket, Option<i64>)),
+    /// Events have been queued in the outbox (see [`enqueue`]).
+    Wake,
+    Connect(
+        (
+            Socket,
+            Option<u64>,
+            Option<RelayConnection>,
+            Option<DidFilter>,
+            TrackedSubscriber,
+        ),
+    ),
+    /// Stop, once the messages queued before this one have been broadcast.
+    Shutdown,
+}
+
+/// Recent events retained in memory for backfilling subscribers, in sequence order: the sequence
+/// number, the DID of the repository the event pertains to (if any), and the serialized frame.
+/// Older events are backfilled from the durable event store instead (see [`backfill_stored`]).
+///
+/// Frames are serialized once, and the buffer is shared between the history, live broadcast and
+/// backfill, so that delivering an event to many subscribers doesn't copy it.
+///
+/// The oldest events are evicted past `firehose.history_size` events, or `firehose.history_max_bytes`
+/// bytes of frames (though the newest event is always retained).
+#[derive(Debug)]
+struct History {
+    events: VecDeque<(u64, Option<String>, Bytes)>,
+    /// The total size of the retained frames.
+    bytes: usize,
+    max_len: usize,
+    max_bytes: Option<usize>,
+}
+
+impl History {
+    fn new(max_len: usize, max_bytes: Option<usize>) -> Self {
+        Self {
+            events: VecDeque::with_capacity(max_len.min(16 * 1024)),
+            bytes: 0,
+            max_len,
+            max_bytes,
+        }
+    }
+
+    /// Append an event, evicting the oldest events past the limits.
+    fn push_back(&mut self, event: (u64, Option<String>, Bytes)) {
+        self.bytes += event.2.len();
+        self.events.push_back(event);
+
+        while self.events.len() > self.max_len.max(1)
+            || (self.events.len() > 1 && self.max_bytes.is_some_and(|max| self.bytes > max))
+        {
+            self.pop_front();
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<(u64, Option<String>, Bytes)> {
+        let event = self.events.pop_front()?;
+        self.bytes -= event.2.len();
+        Some(event)
+    }
+
+    /// Whether the events after `cursor` are all retained (i.e. none have been evicted).
+    fn retains(&self, cursor: u64) -> bool {
+        self.events
+            .front()
+            .is_none_or(|(first, _, _)| cursor + 1 >= *first)
+    }
+}
+
+impl std::ops::Deref for History {
+    type Target = VecDeque<(u64, Option<String>, Bytes)>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.events
+    }
+}
+
+/// The maximum number of frames read from the history at once while backfilling a subscriber.
+const BACKFILL_BATCH: usize = 256;
+/// The maximum size of the frames read from the history at once while backfilling a subscriber.
+/// A single frame larger than this is still delivered, on its own.
+const BACKFILL_BATCH_BYTES: usize = 4 * 1024 * 1024;
+/// The maximum number of events examined at once while backfilling a subscriber, including those
+/// filtered out.
+const BACKFILL_SCAN: usize = 16 * BACKFILL_BATCH;
+
+/// The maximum number of frames queued for delivery to a subscriber. A subscriber that falls
+/// further behind than this is disconnected.
+const CLIENT_QUEUE: usize = 1024;
+/// How long sending a single frame to a subscriber may take before it is disconnected.
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often each subscriber is pinged, regardless of how many events it's being sent.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// The number of consecutive pings a subscriber may leave unanswered before it is disconnected.
+const MAX_MISSED_PINGS: u32 = 3;
+
+/// How often the history is audited for sequence gaps and duplicates.
+const AUDIT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The maximum number of events read from the outbox at once.
+const OUTBOX_BATCH: usize = 64;
+
+/// How long to wait before asking a relay to crawl again after it acknowledged a request, if it
+/// hasn't subscribed in the meantime.
+const RECRAWL_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// The delay before retrying a failed `requestCrawl`, doubled for each consecutive failure.
+const CRAWL_BACKOFF_BASE: Duration = Duration::from_secs(5);
+/// The maximum delay before retrying a failed `requestCrawl`.
+const CRAWL_BACKOFF_MAX: Duration = Duration::from_secs(60 * 60);
+
+/// How long the addresses a relay's hostname resolves to are used to identify its connections
+/// before the hostname is resolved again.
+const RELAY_DNS_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// How long to keep retrying an event that failed to be queued for broadcast before giving up.
+const ENQUEUE_DEADLINE: Duration = Duration::from_secs(30);
+
+/// A set of repository DIDs that a subscriber is interested in.
+///
+/// N.B: This is a non-standard extension to `subscribeRepos` (the `dids` parameter).
+pub type DidFilter = HashSet<String>;
+
+/// The state of an upstream relay, as seen from this PDS.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayState {
+    /// The time of the last `requestCrawl` attempt (RFC 3339).
+    pub last_crawl: Option<String>,
+    /// The outcome of the last `requestCrawl` attempt.
+    pub last_crawl_status: Option<String>,
+    /// Whether the last `requestCrawl` attempt was acknowledged by the relay.
+    pub crawl_ok: bool,
+    /// The time of the last `requestCrawl` attempt acknowledged by the relay (RFC 3339).
+    pub last_crawl_ok: Option<String>,
+    /// The number of consecutive `requestCrawl` attempts that failed.
+    pub crawl_failures: u32,
+    /// The time of the next `requestCrawl` attempt, unless the relay connects first (RFC 3339).
+    pub next_crawl: Option<String>,
+    #[serde(skip)]
+    crawl_due: Option<Instant>,
+    /// The time this host last told us about an update with `notifyOfUpdate` (RFC 3339).
+    pub last_notified: Option<String>,
+    /// The number of firehose connections currently open from this relay.
+    pub connections: usize,
+    /// The last sequence number delivered to this relay.
+    pub last_seq: Option<u64>,
+    /// The addresses the relay's hostname last resolved to, and when they expire.
+    #[serde(skip)]
+    addresses: Option<(Instant, Vec<IpAddr>)>,
+}
+
+/// The result of auditing the history for sequence anomalies, which relays treat as data loss.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SeqAudit {
+    /// The time of the audit (RFC 3339).
+    pub time: String,
+    /// The number of events examined.
+    pub events: usize,
+    pub first_seq: Option<u64>,
+    pub last_seq: Option<u64>,
+    /// Ranges of sequence numbers (inclusive) missing from the history.
+    pub gaps: Vec<(u64, u64)>,
+    /// Sequence numbers repeated (or out of order) in the history.
+    pub duplicates: Vec<u64>,
+}
+
+impl SeqAudit {
+    /// Scan the history for gaps and duplicates.
+    fn run(history: &History) -> Self {
+        let mut audit = Self {
+            time: chrono::Utc::now().to_rfc3339(),
+            events: history.len(),
+            first_seq: history.front().map(|(seq, _, _)| *seq),
+            last_seq: history.back().map(|(seq, _, _)| *seq),
+            ..Default::default()
+        };
+
+        let mut prev: Option<u64> = None;
+        for (seq, _, _) in history.iter() {
+            match prev {
+                Some(p) if *seq <= p => audit.duplicates.push(*seq),
+                Some(p) if *seq > p + 1 => audit.gaps.push((p + 1, *seq - 1)),
+                _ => {}
+            }
+            prev = Some(prev.map_or(*seq, |p| p.max(*seq)));
+        }
+
+        audit
+    }
+
+    /// The number of anomalies found.
+    pub fn anomalies(&self) -> usize {
+        self.gaps.len() + self.duplicates.len()
+    }
+}
+
+/// Check that a sequence number directly follows the previous one, logging (and counting) an
+/// anomaly if it doesn't.
+fn check_seq(check: &'static str, prev: Option<u64>, seq: u64) -> bool {
+    match prev {
+        Some(prev) if seq != prev.wrapping_add(1) => {
+            error!("firehose sequence anomaly ({check}): {seq} follows {prev}");
+            counter!(FIREHOSE_SEQ_ANOMALIES, "check" => check).increment(1);
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Append an event to the history, verifying that it is contiguous with the last, and evicting the
+/// oldest events past its limits.
+fn append_history(history: &mut History, seq: u64, did: Option<String>, frame: Bytes) {
+    check_seq("append", history.back().map(|(seq, _, _)| *seq), seq);
+    history.push_back((seq, did, frame));
+    gauge!(FIREHOSE_HISTORY).set(history.len() as f64);
+    gauge!(FIREHOSE_HISTORY_BYTES).set(history.bytes as f64);
+}
+
+/// Periodically audit the history, retaining the result of the last audit.
+async fn run_audit(history: Arc<RwLock<History>>, last: Arc<RwLock<Option<SeqAudit>>>) {
+    let mut interval = tokio::time::interval(AUDIT_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let audit = SeqAudit::run(&history.read().unwrap());
+        gauge!(FIREHOSE_AUDIT_ANOMALIES).set(audit.anomalies() as f64);
+        if audit.anomalies() != 0 {
+            error!(
+                "firehose audit found {} gaps and {} duplicates: {:?} {:?}",
+                audit.gaps.len(),
+                audit.duplicates.len(),
+                audit.gaps,
+                audit.duplicates
+            );
+        }
+
+        *last.write().unwrap() = Some(audit);
+    }
+}
+
+/// Bookkeeping for upstream relays, keyed by relay hostname.
+#[derive(Clone, Debug, Default)]
+pub struct RelayTracker(Arc<RwLock<HashMap<String, RelayState>>>);
+
+impl RelayTracker {
+    pub fn new(config: &FirehoseConfig) -> Self {
+        let relays = config
+            .relays
+            .iter()
+            .filter_map(|r| relay_host(r).ok())
+            .map(|h| (h.to_string(), RelayState::default()))
+            .collect();
+
+        Self(Arc::new(RwLock::new(relays)))
+    }
+
+    /// Return a snapshot of the current state of all relays.
+    pub fn snapshot(&self) -> HashMap<String, RelayState> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn update(&self, host: &str, f: impl FnOnce(&mut RelayState)) {
+        let mut relays = self.0.write().unwrap();
+        f(relays.entry(host.to_string()).or_default());
+    }
+
+    /// Record that a host notified us of an update (with `notifyOfUpdate`).
+    pub fn notified(&self, host: &str) {
+        self.update(host, |r| {
+            r.last_notified = Some(chrono::Utc::now().to_rfc3339())
+        });
+    }
+
+    /// Record the outcome of a `requestCrawl` attempt, scheduling the next one.
+    fn crawled(&self, host: &str, status: std::result::Result<String, String>) {
+        let now = chrono::Utc::now();
+
+        self.update(host, |r| {
+            let delay = match &status {
+                Ok(_) => {
+                    r.last_crawl_ok = Some(now.to_rfc3339());
+                    r.crawl_failures = 0;
+                    RECRAWL_INTERVAL
+                }
+                Err(_) => {
+                    r.crawl_failures = r.crawl_failures.saturating_add(1);
+                    crawl_backoff(r.crawl_failures)
+                }
+            };
+
+            r.last_crawl = Some(now.to_rfc3339());
+            r.crawl_ok = status.is_ok();
+            r.crawl_due = Some(Instant::now() + delay);
+            r.next_crawl = chrono::Duration::from_std(delay)
+                .ok()
+                .map(|d| (now + d).to_rfc3339());
+
+            gauge!(RELAY_CRAWL_OK, "host" => host.to_string()).set(r.crawl_ok as u8 as f64);
+            gauge!(RELAY_CRAWL_FAILURES, "host" => host.to_string()).set(r.crawl_failures as f64);
+
+            r.last_crawl_status = Some(match status {
+                Ok(s) => s,
+                Err(e) => e,
+            });
+        });
+    }
+
+    /// Whether a relay should be asked to crawl now: it isn't subscribed, and isn't backing off.
+    fn crawl_due(&self, host: &str, now: Instant) -> bool {
+        self.0
+            .read()
+            .unwrap()
+            .get(host)
+            .is_none_or(|r| r.connections == 0 && r.crawl_due.is_none_or(|due| due <= now))
+    }
+
+    /// The point in time that the next `requestCrawl` attempt is due.
+    fn next_crawl(&self) -> Instant {
+        let now = Instant::now();
+
+        self.0
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|r| r.crawl_due)
+            .min()
+            .unwrap_or(now + RECRAWL_INTERVAL)
+            .clamp(now + CRAWL_BACKOFF_BASE, now + RECRAWL_INTERVAL)
+    }
+
+    /// The addresses a relay's hostname resolves to. They're cached for [`RELAY_DNS_TTL`], so that
+    /// connections aren't each held up by a lookup. A failed lookup is cached (as no addresses)
+    /// too.
+    async fn addresses(&self, host: &str) -> Vec<IpAddr> {
+        let now = Instant::now();
+        let cached = self
+            .0
+            .read()
+            .unwrap()
+            .get(host)
+            .and_then(|r| r.addresses.clone());
+        if let Some((expires, addrs)) = cached {
+            if now < expires {
+                return addrs;
+            }
+        }
+
+        let addrs = match tokio::net::lookup_host((host, 443)).await {
+            Ok(addrs) => addrs.map(|a| a.ip()).collect::<Vec<_>>(),
+            Err(e) => {
+                debug!("failed to resolve relay {host}: {e}");
+                Vec::new()
+            }
+        };
+        self.update(host, |r| {
+            r.addresses = Some((now + RELAY_DNS_TTL, addrs.clone()))
+        });
+        addrs
+    }
+
+    /// Identify which configured relay (if any) a subscriber's address belongs to.
+    ///
+    /// Addresses are matched against the `relay_addresses` mapping from the configuration,
+    /// and then against the addresses the relay's hostname resolves to (see [`Self::addresses`]).
+    async fn identify(&self, config: &FirehoseConfig, addr: IpAddr) -> Option<RelayConnection> {
+        for relay in &config.relays {
+            let host = match relay.host_str() {
+                Some(host) => host,
+                None => continue,
+            };
+
+            let known = config
+                .relay_addresses
+                .get(host)
+                .is_some_and(|a| a.contains(&addr));
+
+            let resolved = known || self.addresses(host).await.contains(&addr);
+
+            if resolved {
+                return Some(RelayConnection::new(self.clone(), host));
+            }
+        }
+
+        None
+    }
+}
+
+/// A firehose connection that has been identified as originating from a relay.
+///
+/// The relay's connection count is decremented when this is dropped.
+#[derive(Debug)]
+struct RelayConnection {
+    tracker: RelayTracker,
+    host: String,
+}
+
+impl RelayConnection {
+    fn new(tracker: RelayTracker, host: &str) -> Self {
+        tracker.update(host, |r| r.connections += 1);
+        gauge!(RELAY_CONNECTIONS, "host" => host.to_string()).increment(1);
+
+        Self {
+            tracker,
+            host: host.to_string(),
+        }
+    }
+
+    /// Record that a frame with the specified sequence number was delivered to this relay.
+    fn delivered(&self, seq: u64) {
+        gauge!(RELAY_SEQUENCE, "host" => self.host.clone()).set(seq as f64);
+        self.tracker.update(&self.host, |r| r.last_seq = Some(seq));
+    }
+}
+
+impl Drop for RelayConnection {
+    fn drop(&mut self) {
+        gauge!(RELAY_CONNECTIONS, "host" => self.host.clone()).decrement(1);
+        self.tracker.update(&self.host, |r| {
+            r.connections = r.connections.saturating_sub(1)
+        });
+    }
+}
+
+/// A firehose subscriber, as seen from this PDS.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriberInfo {
+    pub id: u64,
+    /// The remote address of the connection.
+    pub addr: SocketAddr,
+    /// The time the subscriber connected (RFC 3339).
+    pub connected_at: String,
+    /// The cursor the subscriber connected with, if any.
+    pub cursor: Option<u64>,
+    /// The upstream relay the connection was identified as coming from, if any.
+    pub relay: Option<String>,
+    /// The number of repositories the subscriber is interested in, if not all of them.
+    pub dids: Option<usize>,
+    /// Whether frames are compressed (with permessage-deflate).
+    pub deflate: bool,
+    /// Whether the subscriber has been backfilled, and is receiving live events.
+    pub live: bool,
+    /// The number of events sent to the subscriber since it started receiving live events.
+    pub frames_sent: u64,
+    /// The sequence number of the last event sent to the subscriber since it went live.
+    pub last_seq: Option<u64>,
+    /// The time the subscriber last answered a ping (RFC 3339).
+    pub last_pong: Option<String>,
+}
+
+/// The bookkeeping for a connected subscriber, updated by its send task.
+#[derive(Debug)]
+struct SubscriberStats {
+    addr: SocketAddr,
+    connected_at: chrono::DateTime<chrono::Utc>,
+    cursor: Option<u64>,
+    relay: Option<String>,
+    dids: Option<usize>,
+    deflate: bool,
+    live: AtomicBool,
+    frames_sent: AtomicU64,
+    /// Zero if no event has been sent yet (sequence numbers start at 1).
+    last_seq: AtomicU64,
+    last_pong: Mutex<Option<chrono::DateTime<chrono::Utc>>>,
+}
+
+/// The connections admitted to the firehose (see [`SubscriberTracker::admit`]).
+#[derive(Debug, Default)]
+struct Admitted {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+/// Why a connection wasn't admitted to the firehose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// Too many connections are open overall.
+    Total,
+    /// Too many connections are open from the connection's address.
+    Ip,
+}
+
+impl Rejection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Total => "total",
+            Self::Ip => "ip",
+        }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Total => write!(f, "too many firehose connections"),
+            Self::Ip => write!(f, "too many firehose connections from this address"),
+        }
+    }
+}
+
+/// A connection's place among those admitted to the firehose, released when dropped.
+#[derive(Debug)]
+pub struct Admission {
+    admitted: Arc<Mutex<Admitted>>,
+    addr: SocketAddr,
+}
+
+impl Admission {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        let mut admitted = self.admitted.lock().unwrap();
+        admitted.total -= 1;
+        if let Some(n) = admitted.by_ip.get_mut(&self.addr.ip()) {
+            *n -= 1;
+            if *n == 0 {
+                admitted.by_ip.remove(&self.addr.ip());
+            }
+        }
+    }
+}
+
+/// Tracks the subscribers connected to the firehose, for diagnostics. Shared with the send task of
+/// each subscriber, so that it can be read without involving the firehose task.
+#[derive(Debug, Clone, Default)]
+pub struct SubscriberTracker {
+    subscribers: Arc<RwLock<BTreeMap<u64, Arc<SubscriberStats>>>>,
+    admitted: Arc<Mutex<Admitted>>,
+}
+
+impl SubscriberTracker {
+    /// Admit a new connection, unless it would exceed the configured limits. Connections are
+    /// admitted before they're upgraded, and count against the limits until they close.
+    fn admit(
+        &self,
+        config: &FirehoseConfig,
+        addr: SocketAddr,
+    ) -> std::result::Result<Admission, Rejection> {
+        let mut admitted = self.admitted.lock().unwrap();
+        let from_ip = admitted.by_ip.get(&addr.ip()).copied().unwrap_or(0);
+        let rejection = if admitted.total >= config.max_subscribers {
+            Some(Rejection::Total)
+        } else if from_ip >= config.max_subscribers_per_ip {
+            Some(Rejection::Ip)
+        } else {
+            None
+        };
+        if let Some(rejection) = rejection {
+            counter!(FIREHOSE_REJECTED, "limit" => rejection.as_str()).increment(1);
+            return Err(rejection);
+        }
+
+        admitted.total += 1;
+        *admitted.by_ip.entry(addr.ip()).or_default() += 1;
+        Ok(Admission {
+            admitted: self.admitted.clone(),
+            addr,
+        })
+    }
+
+    /// Start tracking a new connection, until the returned handle is dropped.
+    fn track(
+        &self,
+        admission: Admission,
+        cursor: Option<u64>,
+        relay: Option<&RelayConnection>,
+        filter: &Option<DidFilter>,
+        deflate: bool,
+    ) -> TrackedSubscriber {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(SubscriberStats {
+            addr: admission.addr(),
+            connected_at: chrono::Utc::now(),
+            cursor,
+            relay: relay.map(|r| r.host.clone()),
+            dids: filter.as_ref().map(HashSet::len),
+            deflate,
+            live: AtomicBool::new(false),
+            frames_sent: AtomicU64::new(0),
+            last_seq: AtomicU64::new(0),
+            last_pong: Mutex::new(None),
+        });
+        self.subscribers.write().unwrap().insert(id, stats.clone());
+
+        TrackedSubscriber {
+            tracker: self.clone(),
+            id,
+            stats,
+            _admission: admission,
+        }
+    }
+
+    /// Take a snapshot of the connected subscribers, in the order they connected.
+    pub fn snapshot(&self) -> Vec<SubscriberInfo> {
+        self.subscribers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, s)| SubscriberInfo {
+                id: *id,
+                addr: s.addr,
+                connected_at: s.connected_at.to_rfc3339(),
+                cursor: s.cursor,
+                relay: s.relay.clone(),
+                dids: s.dids,
+                deflate: s.deflate,
+                live: s.live.load(Ordering::Relaxed),
+                frames_sent: s.frames_sent.load(Ordering::Relaxed),
+                last_seq: Some(s.last_seq.load(Ordering::Relaxed)).filter(|seq| *seq != 0),
+                last_pong: s.last_pong.lock().unwrap().map(|t| t.to_rfc3339()),
+            })
+            .collect()
+    }
+}
+
+/// A subscriber's entry in the [`SubscriberTracker`], removed when this is dropped.
+#[derive(Debug)]
+struct TrackedSubscriber {
+    tracker: SubscriberTracker,
+    id: u64,
+    stats: Arc<SubscriberStats>,
+    _admission: Admission,
+}
+
+impl TrackedSubscriber {
+    fn live(&self) {
+        self.stats.live.store(true, Ordering::Relaxed);
+    }
+
+    /// Record that a frame with the specified sequence number was sent.
+    fn sent(&self, seq: u64) {
+        self.stats.frames_sent.fetch_add(1, Ordering::Relaxed);
+        self.stats.last_seq.store(seq, Ordering::Relaxed);
+    }
+
+    fn pong(&self) {
+        *self.stats.last_pong.lock().unwrap() = Some(chrono::Utc::now());
+    }
+}
+
+impl Drop for TrackedSubscriber {
+    fn drop(&mut self) {
+        self.tracker.subscribers.write().unwrap().remove(&self.id);
+    }
 }
 
 enum FrameHeader {
@@ -52,6 +725,7 @@ impl Serialize for FrameHeader {
     }
 }
 
+#[derive(Debug, Clone)]
 pub enum RepoOp {
     Create { cid: Cid, path: String },
     Update { cid: Cid, path: String, prev: Cid },
@@ -76,6 +750,7 @@ impl Into<sync::subscribe_repos::RepoOp> for RepoOp {
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct Commit {
     /// The car file containing the commit blocks.
     pub car: Vec<u8>,
@@ -89,6 +764,9 @@ pub struct Commit {
     pub did: Did,
     /// The previous commit's CID (if applicable).
     pub pcid: Option<Cid>,
+    /// The previous commit's revision (if applicable), so that consumers can tell if they missed
+    /// any of the repository's commits.
+    pub since: Option<String>,
     /// Blobs that were created in this commit.
     pub blobs: Vec<Cid>,
 }
@@ -109,7 +787,7 @@ impl Into<sync::subscribe_repos::Commit> for Commit {
             repo: self.did,
             rev: Tid::new(self.rev).unwrap(),
             seq: 0,
-            since: None,
+            since: self.since.and_then(|since| Tid::new(since).ok()),
             time: Datetime::now(),
             too_big: false,
         }
@@ -117,254 +795,2600 @@ impl Into<sync::subscribe_repos::Commit> for Commit {
     }
 }
 
-/// A firehose producer. This is used to transmit messages to the firehose for broadcast.
-#[derive(Clone, Debug)]
-pub struct FirehoseProducer {
-    tx: tokio::sync::mpsc::Sender<FirehoseMessage>,
-}
-
-impl FirehoseProducer {
-    /// Broadcast an `#account` event.
-    pub async fn account(&self, account: impl Into<sync::subscribe_repos::Account>) {
-        let _ = self
-            .tx
-            .send(FirehoseMessage::Broadcast(
-                sync::subscribe_repos::Message::Account(Box::new(account.into())),
-            ))
-            .await;
+/// Mark a commit as too big to carry its blocks, replacing them with a CAR file holding none (but
+/// still rooted at the commit). Its operations are left as-is, so consumers know what changed, and
+/// fetch the blocks with `getRepo` or `getBlocks`.
+async fn mark_too_big(commit: &mut sync::subscribe_repos::Commit) {
+    let mut car = Vec::new();
+    if let Err(e) =
+        CarStore::create_with_roots(std::io::Cursor::new(&mut car), [commit.commit.0]).await
+    {
+        warn!(
+            "failed to create an empty car file for commit {}: {e}",
+            commit.commit.0
+        );
+        car.clear();
     }
 
-    /// Broadcast an `#identity` event.
-    pub async fn identity(&self, identity: impl Into<sync::subscribe_repos::Identity>) {
-        let _ = self
-            .tx
-            .send(FirehoseMessage::Broadcast(
-                sync::subscribe_repos::Message::Identity(Box::new(identity.into())),
-            ))
-            .await;
-    }
+    debug!(
+        "commit {} of {} is too big for the firehose ({} bytes of blocks)",
+        commit.commit.0,
+        commit.repo.as_str(),
+        commit.blocks.len()
+    );
+    commit.blocks = car;
+    commit.too_big = true;
+}
 
-    /// Broadcast a `#commit` event.
-    pub async fn commit(&self, commit: impl Into<sync::subscribe_repos::Commit>) {
-        let _ = self
-            .tx
-            .send(FirehoseMessage::Broadcast(
-                sync::subscribe_repos::Message::Commit(Box::new(commit.into())),
-            ))
-            .await;
-    }
+/// Describe a repository at its current commit for a `#sync` event, with a CAR file holding just
+/// the commit block.
+pub async fn sync_data(
+    repo: &mut impl AsyncBlockStoreRead,
+    did: Did,
+    root: Cid,
+    rev: String,
+) -> Result<sync::subscribe_repos::SyncData> {
+    let commit = repo
+        .read_block(root)
+        .await
+        .context("failed to read commit block")?;
 
-    pub async fn client_connection(&self, ws: WebSocket, cursor: Option<i64>) {
-        let _ = self.tx.send(FirehoseMessage::Connect((ws, cursor))).await;
-    }
+    let mut blocks = Vec::new();
+    let mut car = CarStore::create_with_roots(std::io::Cursor::new(&mut blocks), [root])
+        .await
+        .context("failed to create car file")?;
+    car.write_block(DAG_CBOR, SHA2_256, &commit)
+        .await
+        .context("failed to write commit block")?;
+    drop(car);
+
+    Ok(sync::subscribe_repos::SyncData {
+        blocks,
+        did,
+        rev,
+        seq: 0,
+        time: Datetime::now(),
+    })
 }
 
-/// Serialize a message.
-async fn serialize_message(
-    seq: u64,
+/// Sequence an event and queue it in the outbox for broadcast, returning its sequence number.
+///
+/// This should happen in the transaction that makes the change the event describes, so that events
+/// are sequenced in the order changes were made, and a crash can't lose one between the change and
+/// its broadcast. The firehose broadcasts queued events once woken ([`FirehoseProducer::wake`]),
+/// and on startup.
+pub async fn enqueue(
+    conn: &mut SqliteConnection,
     mut msg: sync::subscribe_repos::Message,
-) -> (&'static str, Vec<u8>) {
-    let mut dummy_seq = 0i64;
-    let (ty, nseq) = match &mut msg {
-        sync::subscribe_repos::Message::Account(m) => ("#account", &mut m.seq),
-        sync::subscribe_repos::Message::Commit(m) => ("#commit", &mut m.seq),
-        sync::subscribe_repos::Message::Identity(m) => ("#identity", &mut m.seq),
-        sync::subscribe_repos::Message::Sync(m) => ("#sync", &mut m.seq),
-        sync::subscribe_repos::Message::Info(_m) => ("#info", &mut dummy_seq),
-    };
-
-    // Set the sequence number.
-    *nseq = seq as i64;
+) -> Result<u64> {
+    let seq: i64 = sqlx::query_scalar(
+        r#"
+        INSERT INTO firehose_seq (id, seq) VALUES (0, 1)
+            ON CONFLICT (id) DO UPDATE SET seq = seq + 1
+            RETURNING seq
+        "#,
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context("failed to assign firehose sequence number")?;
+    let seq = seq as u64;
 
-    let hdr = FrameHeader::Message(ty.to_string());
+    let (ty, frame) = serialize_message(seq, &mut msg).await;
+    record_event(ty, frame.len());
 
-    let mut frame = Vec::new();
-    serde_ipld_dagcbor::to_writer(&mut frame, &hdr).unwrap();
-    serde_ipld_dagcbor::to_writer(&mut frame, &msg).unwrap();
+    let rev = match &msg {
+        sync::subscribe_repos::Message::Commit(m) => Some(m.rev.as_str()),
+        sync::subscribe_repos::Message::Sync(m) => Some(m.rev.as_str()),
+        _ => None,
+    };
+    sqlx::query(
+        r#"INSERT INTO firehose_outbox (seq, type, did, rev, frame) VALUES (?, ?, ?, ?, ?)"#,
+    )
+    .bind(seq as i64)
+    .bind(ty)
+    .bind(event_did(&msg))
+    .bind(rev)
+    .bind(&frame[..])
+    .execute(&mut *conn)
+    .await
+    .context("failed to queue firehose event")?;
 
-    (ty, frame)
+    Ok(seq)
 }
 
-/// Broadcast a message out to all clients.
-async fn broadcast_message(clients: &mut Vec<WebSocket>, msg: Message) -> Result<()> {
-    counter!(FIREHOSE_MESSAGES).increment(1);
-
-    for i in (0..clients.len()).rev() {
-        let client = &mut clients[i];
-        if let Err(e) = client.send(msg.clone()).await {
-            debug!("Firehose client disconnected: {e}");
-            clients.remove(i);
-        }
+/// Queue a `#commit` event for broadcast (see [`enqueue`]).
+///
+/// Commits whose blocks are larger than `firehose.too_big` are broadcast without them.
+pub async fn enqueue_commit(
+    conn: &mut SqliteConnection,
+    config: &FirehoseConfig,
+    commit: &Commit,
+) -> Result<u64> {
+    let mut commit: sync::subscribe_repos::Commit = commit.clone().into();
+    if commit.blocks.len() > config.too_big {
+        mark_too_big(&mut commit).await;
     }
 
-    gauge!(FIREHOSE_LISTENERS).set(clients.len() as f64);
-    Ok(())
+    enqueue(
+        conn,
+        sync::subscribe_repos::Message::Commit(Box::new(commit)),
+    )
+    .await
 }
 
-/// Handle a new connection from a websocket client created by subscribeRepos.
-async fn handle_connect(
-    mut ws: WebSocket,
-    seq: u64,
-    history: &VecDeque<(u64, &str, sync::subscribe_repos::Message)>,
-    cursor: Option<i64>,
-) -> anyhow::Result<WebSocket> {
-    if let Some(cursor) = cursor {
-        let mut frame = Vec::new();
-        let cursor = cursor as u64;
-
-        // Cursor specified; attempt to backfill the consumer.
-        if cursor > seq {
-            let hdr = FrameHeader::Error;
-            let msg = sync::subscribe_repos::Error::FutureCursor(Some(format!(
-                "cursor {cursor} is greater than the current sequence number {seq}"
-            )));
+/// A firehose producer. This is used to transmit messages to the firehose for broadcast.
+#[derive(Clone, Debug)]
+pub struct FirehoseProducer {
+    tx: tokio::sync::mpsc::Sender<FirehoseMessage>,
+    config: FirehoseConfig,
+    relays: RelayTracker,
+    subscribers: SubscriberTracker,
+    history: Arc<RwLock<History>>,
+    audit: Arc<RwLock<Option<SeqAudit>>>,
+    db: Db,
+}
 
-            serde_ipld_dagcbor::to_writer(&mut frame, &hdr).unwrap();
-            serde_ipld_dagcbor::to_writer(&mut frame, &msg).unwrap();
+impl FirehoseProducer {
+    /// Stop the firehose once the events already queued have been broadcast, closing every client
+    /// connection. The task returned by [`spawn`] finishes once clients have been sent everything.
+    pub async fn shutdown(&self) {
+        let _ = self.tx.send(FirehoseMessage::Shutdown).await;
+    }
 
-            // Drop the connection.
-            let _ = ws.send(Message::binary(frame)).await;
-            bail!("connection dropped: cursor {cursor} is greater than the current sequence number {seq}");
+    /// Wake the firehose to broadcast the events queued in the outbox.
+    pub fn wake(&self) {
+        // If the channel is full, the firehose has wakeups pending already. If the firehose has
+        // stopped, queued events stay in the outbox and are broadcast once it's restarted.
+        if let Err(TrySendError::Closed(_)) = self.tx.try_send(FirehoseMessage::Wake) {
+            warn!("firehose has stopped; queued events will be broadcast on restart");
         }
+    }
 
-        let mut it = history.iter();
-        while let Some((seq, ty, msg)) = it.next() {
-            if *seq > cursor {
-                break;
-            }
+    /// Queue an event for broadcast, in a transaction of its own, and wake the firehose.
+    ///
+    /// A failure to queue the event is retried until [`ENQUEUE_DEADLINE`], holding up the caller
+    /// (and so the writes behind it) rather than skipping the event. An event that still can't be
+    /// queued is counted in [`FIREHOSE_EVENTS_LOST`], as the firehose no longer reflects the repo.
+    async fn broadcast(
+        &self,
+        ty: &'static str,
+        msg: sync::subscribe_repos::Message,
+    ) -> Result<u64> {
+        let deadline = Instant::now() + ENQUEUE_DEADLINE;
+        let mut delay = Duration::from_millis(100);
 
-            let hdr = FrameHeader::Message(ty.to_string());
-            serde_ipld_dagcbor::to_writer(&mut frame, &hdr).unwrap();
-            serde_ipld_dagcbor::to_writer(&mut frame, msg).unwrap();
+        let seq = loop {
+            let r = async {
+                let mut tx = self
+                    .db
+                    .begin()
+                    .await
+                    .context("failed to begin transaction")?;
+                let seq = enqueue(&mut tx, msg.clone()).await?;
+                tx.commit().await.context("failed to commit transaction")?;
+                anyhow::Ok(seq)
+            };
 
-            if let Err(e) = ws.send(Message::binary(frame.clone())).await {
-                debug!("Firehose client disconnected during backfill: {e}");
-                break;
+            match r.await {
+                Ok(seq) => break seq,
+                Err(e) if Instant::now() + delay < deadline => {
+                    warn!("failed to queue {ty} event; retrying in {delay:?}: {e:?}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    counter!(FIREHOSE_EVENTS_LOST, "type" => ty).increment(1);
+                    return Err(e.context(format!("failed to queue {ty} event")));
+                }
             }
+        };
 
-            // Clear out the frame to begin a new one.
-            frame.clear();
-        }
+        self.wake();
+        Ok(seq)
     }
 
-    Ok(ws)
-}
+    /// Broadcast an `#account` event, returning its sequence number.
+    pub async fn account(&self, account: impl Into<sync::subscribe_repos::Account>) -> Result<u64> {
+        self.broadcast(
+            "#account",
+            sync::subscribe_repos::Message::Account(Box::new(account.into())),
+        )
+        .await
+    }
 
-pub async fn reconnect_relays(client: &Client, config: &AppConfig) {
-    // Avoid connecting to upstream relays in test mode.
-    if config.test {
-        return;
+    /// Broadcast an `#identity` event, returning its sequence number.
+    pub async fn identity(
+        &self,
+        identity: impl Into<sync::subscribe_repos::Identity>,
+    ) -> Result<u64> {
+        self.broadcast(
+            "#identity",
+            sync::subscribe_repos::Message::Identity(Box::new(identity.into())),
+        )
+        .await
     }
 
-    info!("attempting to reconnect to upstream relays");
-    for relay in &config.firehose.relays {
-        let host = match relay.host_str() {
-            Some(host) => host,
-            None => {
-                warn!("relay {} has no host specified", relay);
-                continue;
-            }
-        };
+    /// Broadcast a `#sync` event, telling consumers to resynchronize a repository from its current
+    /// commit (e.g. after it was imported) rather than from the commits they've seen. Returns its
+    /// sequence number.
+    pub async fn sync(&self, sync: impl Into<sync::subscribe_repos::Sync>) -> Result<u64> {
+        self.broadcast(
+            "#sync",
+            sync::subscribe_repos::Message::Sync(Box::new(sync.into())),
+        )
+        .await
+    }
 
-        let r = client
-            .post(format!("https://{host}/xrpc/com.atproto.sync.requestCrawl"))
-            .json(&serde_json::json!({
-                "hostname": format!("https://{}", config.host_name)
-            }))
-            .send()
+    /// Admit a new subscriber from the specified address, before its connection is upgraded,
+    /// unless it would exceed the configured limits (see [`FirehoseConfig::max_subscribers`]).
+    pub fn admit(&self, addr: SocketAddr) -> std::result::Result<Admission, Rejection> {
+        self.subscribers.admit(&self.config, addr)
+    }
+
+    /// Register a new subscriber, optionally only interested in events for a set of repositories.
+    ///
+    /// If a cursor is specified, the bulk of the backfill happens here (on the subscriber's own
+    /// task) so that a deep backfill doesn't hold up broadcasts to other subscribers.
+    pub async fn client_connection(
+        &self,
+        mut ws: Socket,
+        cursor: Option<u64>,
+        admission: Admission,
+        filter: Option<DidFilter>,
+    ) {
+        let relay = self
+            .relays
+            .identify(&self.config, admission.addr().ip())
             .await;
+        let tracked =
+            self.subscribers
+                .track(admission, cursor, relay.as_ref(), &filter, ws.deflate());
+        let cursor = match cursor {
+            Some(cursor) => {
+                let r = async {
+                    outdated_cursor(&mut ws, &self.db, &self.history, cursor).await?;
 
-        let r = match r {
-            Ok(r) => r,
-            Err(e) => {
-                error!("failed to hit upstream relay {host}: {e}");
-                continue;
+                    // Events may be evicted from the history while the subscriber is backfilled
+                    // from the store, in which case it's sent the rest from the store too.
+                    let mut cursor = cursor;
+                    loop {
+                        cursor = backfill_stored(&mut ws, &self.db, &self.history, cursor, &filter)
+                            .await?;
+                        match backfill(&mut ws, &self.history, cursor, &filter).await? {
+                            Backfilled::Caught(cursor) => break anyhow::Ok(cursor),
+                            Backfilled::Evicted(last) => cursor = last,
+                        }
+                    }
+                };
+                match r.await {
+                    Ok(cursor) => Some(cursor),
+                    Err(e) => {
+                        debug!("Firehose client disconnected during backfill: {e}");
+                        return;
+                    }
+                }
             }
+            None => None,
         };
 
-        let s = r.status();
-        if let Err(e) = r.error_for_status_ref() {
-            error!("failed to hit upstream relay {host}: {e}");
-        }
+        let _ = self
+            .tx
+            .send(FirehoseMessage::Connect((
+                ws, cursor, relay, filter, tracked,
+            )))
+            .await;
+    }
 
-        let b = r.json::<serde_json::Value>().await;
-        if let Ok(b) = b {
-            info!("relay {host}: {} {}", s, b);
-        } else {
-            info!("relay {host}: {}", s);
+    /// Whether subscribers may negotiate compression.
+    pub fn deflate(&self) -> bool {
+        self.config.deflate
+    }
+
+    /// Fetch the bookkeeping for upstream relays.
+    pub fn relays(&self) -> &RelayTracker {
+        &self.relays
+    }
+
+    /// Fetch the bookkeeping for connected subscribers.
+    pub fn subscribers(&self) -> &SubscriberTracker {
+        &self.subscribers
+    }
+
+    /// Fetch the result of the last audit of the history, if one has run yet.
+    pub fn last_audit(&self) -> Option<SeqAudit> {
+        self.audit.read().unwrap().clone()
+    }
+}
+
+/// A websocket client connected to the firehose.
+///
+/// Each client has its own queue of outbound frames, drained by a dedicated task, so that a slow
+/// client only holds up its own delivery rather than every other client's.
+struct Subscriber {
+    /// Frames queued for delivery, with their sequence numbers (if they are events).
+    queue: tokio::sync::mpsc::Sender<(Option<u64>, Message)>,
+    /// The point in time after which this client will be asked to reconnect, if any.
+    deadline: Option<Instant>,
+    /// The repositories this client is interested in, if it is not interested in all of them.
+    filter: Option<DidFilter>,
+    /// The task delivering frames to this client.
+    task: tokio::task::JoinHandle<()>,
+}
+
+/// The events a client must be sent from the history before its queue: those after `cursor`.
+struct CatchUp {
+    history: Arc<RwLock<History>>,
+    cursor: u64,
+}
+
+impl Subscriber {
+    /// Start delivering frames to a connected client, optionally catching it up from the history
+    /// first.
+    ///
+    /// The client is registered (and queues live events) before it's caught up, so that no event
+    /// broadcast in the meantime is missed. Once it's caught up, it's pinged every `ping_interval`.
+    fn spawn(
+        ws: Socket,
+        deadline: Option<Instant>,
+        relay: Option<RelayConnection>,
+        filter: Option<DidFilter>,
+        catch_up: Option<CatchUp>,
+        tracked: Option<TrackedSubscriber>,
+        ping_interval: Duration,
+    ) -> Self {
+        let (queue, rx) = tokio::sync::mpsc::channel(CLIENT_QUEUE);
+        let task = tokio::spawn(send_frames(
+            ws,
+            rx,
+            relay,
+            catch_up,
+            filter.clone(),
+            tracked,
+            ping_interval,
+        ));
+
+        Self {
+            queue,
+            deadline,
+            filter,
+            task,
         }
     }
 }
 
-/// The main entrypoint for the firehose.
+/// Deliver a client's queued frames in order, and consume the frames it sends, until its queue is
+/// closed, the client closes the connection or stops answering pings, or a send fails or times
+/// out. The connection is closed when this returns.
 ///
-/// This will broadcast all updates in this PDS out to anyone who is listening.
+/// If the client must be caught up first, queued events that were also in the history are skipped.
 ///
-/// Reference: https://atproto.com/specs/sync
-pub async fn spawn(
-    client: Client,
-    config: AppConfig,
-) -> (tokio::task::JoinHandle<()>, FirehoseProducer) {
-    let (tx, mut rx) = tokio::sync::mpsc::channel(1000);
-    let handle = tokio::spawn(async move {
-        let mut clients: Vec<WebSocket> = Vec::new();
-        let mut history = VecDeque::with_capacity(1000);
-        let mut seq = 1u64;
-
-        loop {
-            match tokio::time::timeout(Duration::from_secs(30), rx.recv()).await {
-                Ok(msg) => match msg {
-                    Some(FirehoseMessage::Broadcast(msg)) => {
-                        let (ty, by) = serialize_message(seq, msg.clone()).await;
+/// The client is pinged on its own timer, so that one that stops answering is disconnected even
+/// while events are flowing.
+async fn send_frames(
+    mut ws: Socket,
+    mut rx: tokio::sync::mpsc::Receiver<(Option<u64>, Message)>,
+    relay: Option<RelayConnection>,
+    catch_up: Option<CatchUp>,
+    filter: Option<DidFilter>,
+    tracked: Option<TrackedSubscriber>,
+    ping_interval: Duration,
+) {
+    let mut sent = None;
+    if let Some(CatchUp { history, cursor }) = catch_up {
+        match backfill(&mut ws, &history, cursor, &filter).await {
+            Ok(Backfilled::Caught(cursor)) => sent = Some(cursor),
+            // The events it's missing are only in the store now, so it must reconnect for them.
+            Ok(Backfilled::Evicted(cursor)) => {
+                debug!("Firehose client fell behind the history while connecting; closing at cursor {cursor}");
+                let close = Message::Close(Some(CloseFrame {
+                    code: close_code::RESTART,
+                    reason: format!("fell behind; reconnect with cursor={cursor}").into(),
+                }));
+                let _ = tokio::time::timeout(SEND_TIMEOUT, ws.send(close)).await;
+                return;
+            }
+            Err(e) => {
+                debug!("Firehose client disconnected during backfill: {e}");
+                return;
+            }
+        }
+    }
+    if let Some(tracked) = &tracked {
+        tracked.live();
+    }
 
-                        history.push_back((seq, ty, msg));
-                        gauge!(FIREHOSE_HISTORY).set(history.len() as f64);
+    let (mut sink, mut stream) = ws.split();
+    let mut last_seen = Instant::now();
+    let mut unanswered = 0;
+    let mut ping =
+        tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+    ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
-                        info!(
-                            "Broadcasting message {} {} to {} clients",
-                            seq,
-                            ty,
-                            clients.len()
-                        );
+    loop {
+        tokio::select! {
+            _ = ping.tick() => {
+                if unanswered >= MAX_MISSED_PINGS {
+                    warn!(
+                        "Firehose client missed {unanswered} pings (last seen {:?} ago); disconnecting",
+                        last_seen.elapsed()
+                    );
+                    let close = Message::Close(Some(CloseFrame {
+                        code: close_code::POLICY,
+                        reason: "too many unanswered pings".into(),
+                    }));
+                    let _ = tokio::time::timeout(SEND_TIMEOUT, sink.send(close)).await;
+                    return;
+                }
+                unanswered += 1;
 
-                        counter!(FIREHOSE_SEQUENCE).absolute(seq);
-                        seq = seq.wrapping_add(1);
+                let contents = rand::thread_rng()
+                    .sample_iter(rand::distributions::Alphanumeric)
+                    .take(15)
+                    .map(char::from)
+                    .collect::<String>();
 
-                        let _ = broadcast_message(&mut clients, Message::binary(by)).await;
+                // Send a websocket ping message.
+                // Reference: https://developer.mozilla.org/en-US/docs/Web/API/WebSockets_API/Writing_WebSocket_servers#pings_and_pongs_the_heartbeat_of_websockets
+                let msg = Message::Ping(axum::body::Bytes::from_owner(contents));
+                match tokio::time::timeout(SEND_TIMEOUT, sink.send(msg)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        debug!("Firehose client disconnected: {e}");
+                        return;
                     }
-                    Some(FirehoseMessage::Connect((ws, cursor))) => {
-                        match handle_connect(ws, seq, &mut history, cursor).await {
-                            Ok(r) => {
-                                gauge!(FIREHOSE_LISTENERS).increment(1);
-                                clients.push(r);
-                            }
-                            Err(e) => {
-                                error!("failed to connect new client: {e}");
-                            }
-                        }
+                    Err(_) => {
+                        warn!("Firehose client timed out; disconnecting");
+                        return;
                     }
-                    // All producers have been destroyed.
-                    None => break,
-                },
-                Err(_) => {
-                    if clients.is_empty() {
-                        reconnect_relays(&client, &config).await;
+                }
+            }
+            queued = rx.recv() => {
+                // The client was dropped from the firehose.
+                let Some((seq, msg)) = queued else {
+                    return;
+                };
+                if seq.zip(sent).is_some_and(|(seq, sent)| seq <= sent) {
+                    continue;
+                }
+
+                match tokio::time::timeout(SEND_TIMEOUT, sink.send(msg)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        debug!("Firehose client disconnected: {e}");
+                        return;
                     }
+                    Err(_) => {
+                        warn!("Firehose client timed out; disconnecting");
+                        return;
+                    }
+                }
 
-                    let contents = rand::thread_rng()
-                        .sample_iter(rand::distributions::Alphanumeric)
-                        .take(15)
-                        .map(char::from)
-                        .collect::<String>();
+                if let Some(seq) = seq {
+                    counter!(FIREHOSE_FRAMES_SENT, "source" => "live").increment(1);
 
-                    // Send a websocket ping message.
-                    // Reference: https://developer.mozilla.org/en-US/docs/Web/API/WebSockets_API/Writing_WebSocket_servers#pings_and_pongs_the_heartbeat_of_websockets
-                    let message = Message::Ping(axum::body::Bytes::from_owner(contents));
-                    let _ = broadcast_message(&mut clients, message).await;
+                    if let Some(relay) = &relay {
+                        relay.delivered(seq);
+                    }
+                    if let Some(tracked) = &tracked {
+                        tracked.sent(seq);
+                    }
+                }
+            }
+            received = stream.next() => match received {
+                Some(Ok(Message::Pong(_))) => {
+                    last_seen = Instant::now();
+                    unanswered = 0;
+                    if let Some(tracked) = &tracked {
+                        tracked.pong();
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    debug!("Firehose client closed the connection");
+                    return;
                 }
+                Some(Err(e)) => {
+                    debug!("Firehose client disconnected: {e}");
+                    return;
+                }
+                // Subscribers have nothing else to say.
+                Some(Ok(_)) => last_seen = Instant::now(),
+            }
+        }
+    }
+}
+
+/// The repository an event pertains to, if any.
+fn event_did(msg: &sync::subscribe_repos::Message) -> Option<&str> {
+    match msg {
+        sync::subscribe_repos::Message::Account(m) => Some(m.did.as_str()),
+        sync::subscribe_repos::Message::Commit(m) => Some(m.repo.as_str()),
+        sync::subscribe_repos::Message::Identity(m) => Some(m.did.as_str()),
+        sync::subscribe_repos::Message::Sync(m) => Some(m.did.as_str()),
+        sync::subscribe_repos::Message::Info(_) => None,
+    }
+}
+
+/// Whether a subscriber with the specified filter should receive a message.
+///
+/// Messages that don't pertain to a repository (e.g. `#info`) are delivered to everyone.
+fn filter_accepts(filter: &Option<DidFilter>, did: Option<&str>) -> bool {
+    match (filter, did) {
+        (Some(filter), Some(did)) => filter.contains(did),
+        _ => true,
+    }
+}
+
+/// Calculate the point in time that a new connection should be recycled, given an optional lifetime.
+///
+/// Up to 10% of the lifetime is added as random jitter so that clients that connected at around
+/// the same time (e.g. after a restart) don't all reconnect simultaneously.
+fn connection_deadline(now: Instant, lifetime: Option<Duration>) -> Option<Instant> {
+    lifetime.map(|lifetime| {
+        let jitter = rand::thread_rng().gen_range(0..=(lifetime.as_millis() / 10) as u64);
+        now + lifetime + Duration::from_millis(jitter)
+    })
+}
+
+/// Calculate the delay before retrying a relay's `requestCrawl`, given the number of consecutive
+/// failures. The delay doubles with each failure, up to a maximum, with up to 25% added as random
+/// jitter so that retries for several relays (or PDSes) don't line up.
+fn crawl_backoff(failures: u32) -> Duration {
+    let delay = CRAWL_BACKOFF_BASE
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(CRAWL_BACKOFF_MAX);
+    let jitter = rand::thread_rng().gen_range(0..=(delay.as_millis() / 4) as u64);
+
+    delay + Duration::from_millis(jitter)
+}
+
+/// Stamp a message with its sequence number and serialize it.
+async fn serialize_message(
+    seq: u64,
+    msg: &mut sync::subscribe_repos::Message,
+) -> (&'static str, Bytes) {
+    let mut dummy_seq = 0i64;
+    let (ty, nseq) = match msg {
+        sync::subscribe_repos::Message::Account(m) => ("#account", &mut m.seq),
+        sync::subscribe_repos::Message::Commit(m) => ("#commit", &mut m.seq),
+        sync::subscribe_repos::Message::Identity(m) => ("#identity", &mut m.seq),
+        sync::subscribe_repos::Message::Sync(m) => ("#sync", &mut m.seq),
+        sync::subscribe_repos::Message::Info(_m) => ("#info", &mut dummy_seq),
+    };
+
+    // Set the sequence number.
+    *nseq = seq as i64;
+
+    (ty, Bytes::from(encode_frame(ty, msg)))
+}
+
+/// Record metrics for a newly-serialized event of the specified type.
+fn record_event(ty: &'static str, len: usize) {
+    counter!(FIREHOSE_MESSAGES, "type" => ty).increment(1);
+    histogram!(FIREHOSE_FRAME_SIZE, "type" => ty).record(len as f64);
+}
+
+/// Broadcast a message out to all clients, by queueing it for each client's send task.
+///
+/// `seq` is the sequence number of the message, if it is an event, and `did` the repository
+/// it pertains to (for subscribers with a filter). Clients that have disconnected, or whose queue
+/// is full, are dropped.
+fn broadcast_message(
+    clients: &mut Vec<Subscriber>,
+    seq: Option<u64>,
+    did: Option<&str>,
+    msg: Message,
+) {
+    clients.retain(|client| {
+        if !filter_accepts(&client.filter, did) {
+            return true;
+        }
+
+        match client.queue.try_send((seq, msg.clone())) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("Firehose client fell more than {CLIENT_QUEUE} frames behind; disconnecting");
+                false
             }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    });
+
+    gauge!(FIREHOSE_LISTENERS).set(clients.len() as f64);
+}
+
+/// Drop clients that have disconnected, and close any client connections that have outlived
+/// their deadline.
+///
+/// Clients are sent a close frame with code 1012 (service restart) that includes the last sequence
+/// number broadcast, so they can reconnect with that cursor and be backfilled without losing events.
+fn expire_clients(clients: &mut Vec<Subscriber>, seq: u64) {
+    let now = Instant::now();
+
+    clients.retain(|client| {
+        // The client has disconnected (or was disconnected by its send task).
+        if client.queue.is_closed() {
+            return false;
+        }
+        if !client.deadline.is_some_and(|d| d <= now) {
+            return true;
         }
+
+        let cursor = seq.saturating_sub(1);
+        debug!("Firehose client exceeded its connection lifetime; closing at cursor {cursor}");
+
+        // The close frame is delivered after any frames still queued.
+        let _ = client.queue.try_send((
+            None,
+            Message::Close(Some(CloseFrame {
+                code: close_code::RESTART,
+                reason: format!("connection lifetime exceeded; reconnect with cursor={cursor}")
+                    .into(),
+            })),
+        ));
+        false
     });
 
-    (handle, FirehoseProducer { tx })
+    gauge!(FIREHOSE_LISTENERS).set(clients.len() as f64);
+}
+
+/// Close every client connection on shutdown, waiting for each client to be sent the frames
+/// already queued for it.
+///
+/// As when a connection outlives its deadline, clients are sent a close frame with code 1012
+/// (service restart) and the cursor to reconnect with.
+async fn close_clients(clients: Vec<Subscriber>, seq: u64) {
+    let cursor = seq.saturating_sub(1);
+    info!(
+        "Closing {} firehose clients at cursor {cursor}",
+        clients.len()
+    );
+
+    let mut tasks = Vec::with_capacity(clients.len());
+    for Subscriber { queue, task, .. } in clients {
+        // A client that has fallen behind (its queue is full) is disconnected without one.
+        let _ = queue.try_send((
+            None,
+            Message::Close(Some(CloseFrame {
+                code: close_code::RESTART,
+                reason: format!("server shutting down; reconnect with cursor={cursor}").into(),
+            })),
+        ));
+
+        // Dropping the queue ends the send task once it's delivered the close frame.
+        drop(queue);
+        tasks.push(task);
+    }
+
+    for task in tasks {
+        let _ = task.await;
+    }
+    gauge!(FIREHOSE_LISTENERS).set(0.0);
+}
+
+/// Serialize an event into a frame.
+fn encode_frame(ty: &str, msg: &sync::subscribe_repos::Message) -> Vec<u8> {
+    let hdr = FrameHeader::Message(ty.to_string());
+
+    // Reserve room for the event's blocks up front, rather than copying them each time the frame
+    // outgrows its buffer.
+    let blocks = match msg {
+        sync::subscribe_repos::Message::Commit(m) => m.blocks.len(),
+        sync::subscribe_repos::Message::Sync(m) => m.blocks.len(),
+        _ => 0,
+    };
+    let mut frame = Vec::with_capacity(blocks + 1024);
+    serde_ipld_dagcbor::to_writer(&mut frame, &hdr).unwrap();
+    serde_ipld_dagcbor::to_writer(&mut frame, msg).unwrap();
+    frame
+}
+
+/// A batch of frames read from the history for backfill.
+struct BackfillBatch {
+    /// The frames to deliver, along with their sequence numbers.
+    frames: Vec<(u64, Bytes)>,
+    /// The sequence number of the last event examined (whether or not it was filtered out).
+    last: Option<u64>,
+    /// Whether the history holds further events past this batch.
+    more: bool,
+}
+
+/// Read the next batch of frames after `after` from the history, bounded by [`BACKFILL_BATCH`],
+/// [`BACKFILL_BATCH_BYTES`] and [`BACKFILL_SCAN`].
+fn next_batch(history: &History, after: u64, filter: &Option<DidFilter>) -> BackfillBatch {
+    let start = history.partition_point(|(seq, _, _)| *seq <= after);
+
+    let mut frames = Vec::new();
+    let mut bytes = 0;
+    let mut last = None;
+    for (i, (seq, did, frame)) in history.range(start..).enumerate() {
+        if i == BACKFILL_SCAN || frames.len() == BACKFILL_BATCH {
+            return BackfillBatch {
+                frames,
+                last,
+                more: true,
+            };
+        }
+
+        if filter_accepts(filter, did.as_deref()) {
+            if !frames.is_empty() && bytes + frame.len() > BACKFILL_BATCH_BYTES {
+                return BackfillBatch {
+                    frames,
+                    last,
+                    more: true,
+                };
+            }
+
+            bytes += frame.len();
+            frames.push((*seq, frame.clone()));
+        }
+
+        last = Some(*seq);
+    }
+
+    BackfillBatch {
+        frames,
+        last,
+        more: false,
+    }
+}
+
+/// How far a subscriber was backfilled from the history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backfilled {
+    /// Up to this sequence number, the last event in the history.
+    Caught(u64),
+    /// Up to this sequence number, after which events were evicted before they could be sent.
+    Evicted(u64),
+}
+
+/// Deliver the events after `cursor` in the history to a subscriber, returning the sequence
+/// number of the last event examined.
+///
+/// The history is read in bounded batches, so that at most one batch of frames is held in memory
+/// for the subscriber at once, and with an await point between batches. If events the subscriber
+/// hasn't been sent yet are evicted in the meantime, this stops short.
+async fn backfill(
+    ws: &mut Socket,
+    history: &RwLock<History>,
+    mut cursor: u64,
+    filter: &Option<DidFilter>,
+) -> Result<Backfilled> {
+    gauge!(FIREHOSE_BACKFILLS).increment(1);
+
+    let r = async {
+        loop {
+            let batch = {
+                let history = history.read().unwrap();
+                if !history.retains(cursor) {
+                    return Ok(Backfilled::Evicted(cursor));
+                }
+                next_batch(&history, cursor, filter)
+            };
+
+            for (_seq, frame) in batch.frames {
+                ws.send(Message::Binary(frame)).await?;
+                counter!(FIREHOSE_FRAMES_SENT, "source" => "backfill").increment(1);
+            }
+
+            if let Some(last) = batch.last {
+                cursor = last;
+            }
+            if !batch.more {
+                return Ok(Backfilled::Caught(cursor));
+            }
+
+            tokio::task::yield_now().await;
+        }
+    }
+    .await;
+
+    gauge!(FIREHOSE_BACKFILLS).decrement(1);
+    r
+}
+
+/// Move a broadcast event from the outbox to the durable event store.
+async fn move_event(db: &Db, seq: u64) -> Result<()> {
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+    sqlx::query(
+        r#"
+        INSERT OR REPLACE INTO firehose_events (seq, type, did, frame)
+            SELECT seq, type, did, frame FROM firehose_outbox WHERE seq = ?
+        "#,
+    )
+    .bind(seq as i64)
+    .execute(&mut *tx)
+    .await
+    .context("failed to store firehose event")?;
+    sqlx::query(r#"DELETE FROM firehose_outbox WHERE seq = ?"#)
+        .bind(seq as i64)
+        .execute(&mut *tx)
+        .await
+        .context("failed to remove firehose event from the outbox")?;
+    tx.commit().await.context("failed to commit transaction")?;
+
+    Ok(())
+}
+
+/// Broadcast the events queued in the outbox, in sequence order, moving each to the durable event
+/// store. `next` is advanced past each event broadcast.
+async fn broadcast_outbox(
+    db: &Db,
+    history: &RwLock<History>,
+    clients: &mut Vec<Subscriber>,
+    next: &mut u64,
+    last_broadcast: &mut Option<u64>,
+) {
+    loop {
+        let batch: Vec<(i64, String, Option<String>, Vec<u8>)> = match sqlx::query_as(
+            r#"SELECT seq, type, did, frame FROM firehose_outbox ORDER BY seq LIMIT ?"#,
+        )
+        .bind(OUTBOX_BATCH as i64)
+        .fetch_all(db)
+        .await
+        {
+            Ok(batch) => batch,
+            Err(e) => {
+                error!("failed to read the firehose outbox: {e:?}");
+                return;
+            }
+        };
+        if batch.is_empty() {
+            return;
+        }
+
+        for (seq, ty, did, frame) in batch {
+            let seq = seq as u64;
+
+            // Once out of the outbox, the event isn't broadcast again; subscribers that miss it
+            // (e.g. because of a crash) are backfilled from the store when they reconnect.
+            while let Err(e) = move_event(db, seq).await {
+                error!("{e:?}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+
+            let frame = Bytes::from(frame);
+            append_history(
+                &mut history.write().unwrap(),
+                seq,
+                did.clone(),
+                frame.clone(),
+            );
+
+            info!(
+                "Broadcasting message {} {} to {} clients",
+                seq,
+                ty,
+                clients.len()
+            );
+
+            counter!(FIREHOSE_SEQUENCE).absolute(seq);
+            check_seq("broadcast", *last_broadcast, seq);
+            *last_broadcast = Some(seq);
+            broadcast_message(clients, Some(seq), did.as_deref(), Message::Binary(frame));
+
+            *next = seq + 1;
+        }
+    }
+}
+
+/// Return the sequence number of the oldest event retained, in the durable event store or the
+/// in-memory history.
+async fn oldest_retained(db: &Db, history: &RwLock<History>) -> Result<Option<u64>> {
+    let stored: Option<i64> = sqlx::query_scalar(r#"SELECT MIN(seq) FROM firehose_events"#)
+        .fetch_one(db)
+        .await
+        .context("failed to query stored firehose events")?;
+    let stored = stored.map(|seq| seq as u64);
+    let memory = history.read().unwrap().front().map(|(seq, _, _)| *seq);
+
+    Ok(match (stored, memory) {
+        (Some(stored), Some(memory)) => Some(stored.min(memory)),
+        (stored, memory) => stored.or(memory),
+    })
+}
+
+/// If events after `cursor` are no longer retained, tell the subscriber with an `#info` frame
+/// (`OutdatedCursor`), before it's backfilled from the oldest event that is.
+///
+/// A cursor of 0 asks for every event retained, so it's never outdated.
+async fn outdated_cursor(
+    ws: &mut Socket,
+    db: &Db,
+    history: &RwLock<History>,
+    cursor: u64,
+) -> Result<()> {
+    if cursor == 0 {
+        return Ok(());
+    }
+    let Some(oldest) = oldest_retained(db, history).await? else {
+        return Ok(());
+    };
+    if cursor + 1 >= oldest {
+        return Ok(());
+    }
+
+    let mut msg = sync::subscribe_repos::Message::Info(Box::new(
+        sync::subscribe_repos::InfoData {
+            name: "OutdatedCursor".to_string(),
+            message: Some(format!(
+                "cursor {cursor} is older than the oldest event retained ({oldest})"
+            )),
+        }
+        .into(),
+    ));
+    let (ty, frame) = serialize_message(0, &mut msg).await;
+    record_event(ty, frame.len());
+
+    ws.send(Message::Binary(frame)).await?;
+    Ok(())
+}
+
+/// Deliver the events after `cursor` from the durable event store to a subscriber, up to where the
+/// in-memory history takes over, returning the sequence number of the last event examined.
+///
+/// The store is read in batches of [`BACKFILL_BATCH`] events, in sequence order.
+async fn backfill_stored(
+    ws: &mut Socket,
+    db: &Db,
+    history: &RwLock<History>,
+    mut cursor: u64,
+    filter: &Option<DidFilter>,
+) -> Result<u64> {
+    let covered = |cursor: u64| {
+        history
+            .read()
+            .unwrap()
+            .front()
+            .is_some_and(|(first, _, _)| cursor + 1 >= *first)
+    };
+    if covered(cursor) {
+        return Ok(cursor);
+    }
+
+    gauge!(FIREHOSE_BACKFILLS).increment(1);
+
+    let r = async {
+        while !covered(cursor) {
+            let batch: Vec<(i64, Option<String>, Vec<u8>)> = sqlx::query_as(
+                r#"SELECT seq, did, frame FROM firehose_events WHERE seq > ? ORDER BY seq LIMIT ?"#,
+            )
+            .bind(cursor as i64)
+            .bind(BACKFILL_BATCH as i64)
+            .fetch_all(db)
+            .await
+            .context("failed to read stored firehose events")?;
+            let Some((last, _, _)) = batch.last() else {
+                break;
+            };
+            let last = *last as u64;
+
+            for (_seq, did, frame) in batch {
+                if filter_accepts(filter, did.as_deref()) {
+                    ws.send(Message::Binary(frame.into())).await?;
+                    counter!(FIREHOSE_FRAMES_SENT, "source" => "stored").increment(1);
+                }
+            }
+
+            cursor = last;
+        }
+
+        Ok(cursor)
+    }
+    .await;
+
+    gauge!(FIREHOSE_BACKFILLS).decrement(1);
+    r
+}
+
+/// The body of a `FutureCursor` error frame. Besides the standard `error` and `message`, it
+/// carries the cursor requested and the current sequence number, for clients to act on.
+///
+/// N.B: Fields are declared in DAG-CBOR key order.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+struct FutureCursor {
+    seq: u64,
+    error: &'static str,
+    cursor: u64,
+    message: String,
+}
+
+impl FutureCursor {
+    fn new(cursor: u64, seq: u64) -> Self {
+        Self {
+            seq,
+            error: "FutureCursor",
+            cursor,
+            message: format!("cursor {cursor} is greater than the current sequence number {seq}"),
+        }
+    }
+}
+
+/// Handle a new connection from a websocket client created by subscribeRepos.
+///
+/// The client has been backfilled up to `cursor` already (see
+/// [`FirehoseProducer::client_connection`]), so this only checks that the cursor isn't in the
+/// future: `next` is the sequence number of the next event to broadcast, so a cursor at the one
+/// before it is live. The client catches up on events sequenced since once it's registered.
+async fn handle_connect(mut ws: Socket, next: u64, cursor: Option<u64>) -> anyhow::Result<Socket> {
+    let seq = next.saturating_sub(1);
+    if let Some(cursor) = cursor.filter(|cursor| *cursor > seq) {
+        let mut frame = Vec::new();
+        let err = FutureCursor::new(cursor, seq);
+
+        serde_ipld_dagcbor::to_writer(&mut frame, &FrameHeader::Error).unwrap();
+        serde_ipld_dagcbor::to_writer(&mut frame, &err).unwrap();
+
+        // Drop the connection.
+        let _ = ws.send(Message::binary(frame)).await;
+        bail!("connection dropped: {}", err.message);
+    }
+
+    Ok(ws)
+}
+
+/// The hostname that a relay (or other host) is tracked under.
+fn relay_host(url: &Url) -> Result<&str> {
+    match url.host_str() {
+        Some(host) if !host.is_empty() => Ok(host),
+        _ => bail!("relay {url} has no host specified"),
+    }
+}
+
+/// Parse a hostname given by another host (e.g. to `notifyOfUpdate`) into the form it's tracked
+/// under. A bare hostname is accepted, as is a URL with no path beyond the root.
+pub fn parse_hostname(hostname: &str) -> Result<String> {
+    let url = if hostname.contains("://") {
+        Url::parse(hostname)
+    } else {
+        Url::parse(&format!("https://{hostname}"))
+    }
+    .with_context(|| format!("invalid hostname {hostname:?}"))?;
+
+    if !matches!(url.scheme(), "http" | "https")
+        || !url.username().is_empty()
+        || url.path() != "/"
+        || url.query().is_some()
+        || url.fragment().is_some()
+    {
+        bail!("invalid hostname {hostname:?}");
+    }
+
+    relay_host(&url).map(str::to_string)
+}
+
+/// Ask each upstream relay that isn't subscribed to crawl this PDS, unless it's backing off after
+/// a failed request.
+async fn reconnect_relays(client: &Client, config: &AppConfig, relays: &RelayTracker) {
+    let now = Instant::now();
+    for relay in &config.firehose.relays {
+        let host = match relay_host(relay) {
+            Ok(host) => host,
+            Err(e) => {
+                warn!("{e}");
+                continue;
+            }
+        };
+        if !relays.crawl_due(host, now) {
+            continue;
+        }
+
+        info!("requesting a crawl from upstream relay {host}");
+        let r = client
+            .post(format!("https://{host}/xrpc/com.atproto.sync.requestCrawl"))
+            .json(&serde_json::json!({
+                // Always the primary hostname, so that relays don't see one PDS as several.
+                "hostname": format!("https://{}", config.host_name)
+            }))
+            .send()
+            .await;
+
+        let r = match r {
+            Ok(r) => r,
+            Err(e) => {
+                error!("failed to hit upstream relay {host}: {e}");
+                relays.crawled(host, Err(e.to_string()));
+                continue;
+            }
+        };
+
+        let s = r.status();
+        if let Err(e) = r.error_for_status_ref() {
+            error!("failed to hit upstream relay {host}: {e}");
+            relays.crawled(host, Err(s.to_string()));
+        } else {
+            relays.crawled(host, Ok(s.to_string()));
+        }
+
+        let b = r.json::<serde_json::Value>().await;
+        if let Ok(b) = b {
+            info!("relay {host}: {} {}", s, b);
+        } else {
+            info!("relay {host}: {}", s);
+        }
+    }
+}
+
+/// Request crawls from upstream relays, once the PDS is serving. Relays are asked again until
+/// they subscribe, backing off (with jitter) from those that fail to respond.
+pub async fn crawl_relays(client: Client, config: AppConfig, relays: RelayTracker) {
+    // Avoid connecting to upstream relays in test mode.
+    if config.test {
+        return;
+    }
+
+    loop {
+        reconnect_relays(&client, &config, &relays).await;
+        tokio::time::sleep_until(relays.next_crawl().into()).await;
+    }
+}
+
+/// Return the sequence number of the next event to broadcast: the oldest still queued in the
+/// outbox, or else the one after the last sequenced.
+async fn restore_seq(db: &Db) -> Result<u64> {
+    let next: Option<i64> = sqlx::query_scalar(
+        r#"
+        SELECT COALESCE(
+            (SELECT MIN(seq) FROM firehose_outbox),
+            (SELECT seq + 1 FROM firehose_seq WHERE id = 0)
+        )
+        "#,
+    )
+    .fetch_one(db)
+    .await
+    .context("failed to query firehose sequence number")?;
+
+    Ok(next.map_or(1, |seq| seq as u64))
+}
+
+/// The main entrypoint for the firehose.
+///
+/// This will broadcast all updates in this PDS out to anyone who is listening, as they're queued
+/// in the outbox (see [`enqueue`]). Events left in the outbox by the previous run are broadcast
+/// first.
+///
+/// Reference: https://atproto.com/specs/sync
+pub async fn spawn(
+    config: AppConfig,
+    db: Db,
+) -> Result<(tokio::task::JoinHandle<()>, FirehoseProducer)> {
+    let mut seq = restore_seq(&db).await?;
+    if seq != 1 {
+        info!("resuming firehose at sequence number {seq}");
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1000);
+    let lifetime = config.firehose.connection_lifetime.map(Duration::from_secs);
+    let relays = RelayTracker::new(&config.firehose);
+    let history = Arc::new(RwLock::new(History::new(
+        config.firehose.history_size,
+        config.firehose.history_max_bytes,
+    )));
+    let audit = Arc::new(RwLock::new(None));
+    let producer = FirehoseProducer {
+        tx,
+        config: config.firehose.clone(),
+        relays: relays.clone(),
+        subscribers: SubscriberTracker::default(),
+        history: history.clone(),
+        audit: audit.clone(),
+        db: db.clone(),
+    };
+
+    tokio::spawn(run_audit(history.clone(), audit));
+
+    let handle = tokio::spawn(async move {
+        let mut clients: Vec<Subscriber> = Vec::new();
+        let mut last_broadcast = None;
+
+        // Broadcast events queued before a restart.
+        broadcast_outbox(&db, &history, &mut clients, &mut seq, &mut last_broadcast).await;
+
+        loop {
+            match tokio::time::timeout(Duration::from_secs(30), rx.recv()).await {
+                Ok(msg) => match msg {
+                    Some(FirehoseMessage::Wake) => {
+                        broadcast_outbox(
+                            &db,
+                            &history,
+                            &mut clients,
+                            &mut seq,
+                            &mut last_broadcast,
+                        )
+                        .await;
+                    }
+                    Some(FirehoseMessage::Connect((ws, cursor, relay, filter, tracked))) => {
+                        match handle_connect(ws, seq, cursor).await {
+                            Ok(ws) => {
+                                gauge!(FIREHOSE_LISTENERS).increment(1);
+                                let catch_up = cursor.map(|cursor| CatchUp {
+                                    history: history.clone(),
+                                    cursor,
+                                });
+                                clients.push(Subscriber::spawn(
+                                    ws,
+                                    connection_deadline(Instant::now(), lifetime),
+                                    relay,
+                                    filter,
+                                    catch_up,
+                                    Some(tracked),
+                                    PING_INTERVAL,
+                                ));
+                            }
+                            Err(e) => {
+                                error!("failed to connect new client: {e}");
+                            }
+                        }
+                    }
+                    // All producers have been destroyed, or the firehose is shutting down. Events
+                    // queued until now are broadcast first.
+                    Some(FirehoseMessage::Shutdown) | None => {
+                        broadcast_outbox(
+                            &db,
+                            &history,
+                            &mut clients,
+                            &mut seq,
+                            &mut last_broadcast,
+                        )
+                        .await;
+                        break;
+                    }
+                },
+                Err(_) => {
+                    // In case a wakeup was missed.
+                    broadcast_outbox(&db, &history, &mut clients, &mut seq, &mut last_broadcast)
+                        .await;
+                }
+            }
+
+            expire_clients(&mut clients, seq);
+        }
+
+        close_clients(clients, seq).await;
+    });
+
+    Ok((handle, producer))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deadline_jitter() {
+        let now = Instant::now();
+        let lifetime = Duration::from_secs(100);
+
+        assert_eq!(connection_deadline(now, None), None);
+        for _ in 0..100 {
+            let deadline = connection_deadline(now, Some(lifetime)).unwrap();
+            assert!(deadline >= now + lifetime);
+            assert!(deadline <= now + lifetime + Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn event_metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let did = Did::new("did:plc:test".to_string()).unwrap();
+        let messages = [
+            sync::subscribe_repos::Message::Commit(Box::new(
+                Commit {
+                    car: vec![],
+                    ops: vec![],
+                    cid: Cid::default(),
+                    rev: "3jzfcijpj2z2a".to_string(),
+                    did: did.clone(),
+                    pcid: None,
+                    since: None,
+                    blobs: vec![],
+                }
+                .into(),
+            )),
+            sync::subscribe_repos::Message::Account(Box::new(
+                sync::subscribe_repos::AccountData {
+                    active: true,
+                    did: did.clone(),
+                    seq: 0,
+                    status: None,
+                    time: Datetime::now(),
+                }
+                .into(),
+            )),
+            sync::subscribe_repos::Message::Identity(Box::new(
+                sync::subscribe_repos::IdentityData {
+                    did: did.clone(),
+                    handle: None,
+                    seq: 0,
+                    time: Datetime::now(),
+                }
+                .into(),
+            )),
+            sync::subscribe_repos::Message::Sync(Box::new(
+                sync::subscribe_repos::SyncData {
+                    blocks: vec![],
+                    did: did.clone(),
+                    rev: "3jzfcijpj2z2a".to_string(),
+                    seq: 0,
+                    time: Datetime::now(),
+                }
+                .into(),
+            )),
+            sync::subscribe_repos::Message::Info(Box::new(
+                sync::subscribe_repos::InfoData {
+                    message: None,
+                    name: "OutdatedCursor".to_string(),
+                }
+                .into(),
+            )),
+        ];
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            for (seq, mut msg) in messages.into_iter().enumerate() {
+                let (ty, frame) =
+                    futures::executor::block_on(serialize_message(seq as u64, &mut msg));
+                record_event(ty, frame.len());
+            }
+        });
+
+        let mut types = Vec::new();
+        for (key, _, _, value) in snapshotter.snapshot().into_vec() {
+            let key = key.key();
+            let ty = key
+                .labels()
+                .find(|l| l.key() == "type")
+                .map(|l| l.value().to_string())
+                .unwrap();
+
+            match (key.name(), value) {
+                (FIREHOSE_MESSAGES, DebugValue::Counter(n)) => {
+                    assert_eq!(n, 1);
+                    types.push(ty);
+                }
+                (FIREHOSE_FRAME_SIZE, DebugValue::Histogram(v)) => {
+                    assert_eq!(v.len(), 1);
+                    assert!(v[0].into_inner() > 0.0);
+                }
+                (name, _) => panic!("unexpected metric {name}"),
+            }
+        }
+
+        types.sort();
+        assert_eq!(
+            types,
+            ["#account", "#commit", "#identity", "#info", "#sync"]
+        );
+    }
+
+    fn identity(did: &str) -> sync::subscribe_repos::Message {
+        sync::subscribe_repos::Message::Identity(Box::new(
+            sync::subscribe_repos::IdentityData {
+                did: Did::new(did.to_string()).unwrap(),
+                handle: None,
+                seq: 0,
+                time: Datetime::now(),
+            }
+            .into(),
+        ))
+    }
+
+    /// Receive an `#identity` frame from the firehose, returning its sequence number and DID.
+    async fn recv_identity<S>(ws: &mut tokio_tungstenite::WebSocketStream<S>) -> (i64, String)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        use futures::StreamExt;
+
+        let tokio_tungstenite::tungstenite::Message::Binary(frame) =
+            ws.next().await.unwrap().unwrap()
+        else {
+            panic!("expected a binary frame");
+        };
+
+        decode_identity(&frame)
+    }
+
+    /// Decode an `#identity` frame, returning its sequence number and DID.
+    fn decode_identity(frame: &[u8]) -> (i64, String) {
+        use ipld_core::ipld::Ipld;
+
+        let hdr =
+            serde_ipld_dagcbor::to_vec(&FrameHeader::Message("#identity".to_string())).unwrap();
+        let body: Ipld =
+            serde_ipld_dagcbor::from_slice(frame.strip_prefix(&hdr[..]).unwrap()).unwrap();
+
+        match (body.get("seq"), body.get("did")) {
+            (Ok(Some(Ipld::Integer(seq))), Ok(Some(Ipld::String(did)))) => {
+                (*seq as i64, did.clone())
+            }
+            _ => panic!("malformed #identity event"),
+        }
+    }
+
+    /// Serve websocket upgrades on a local port, handing the server side of each connection back
+    /// to the test.
+    async fn ws_server() -> (
+        SocketAddr,
+        tokio::sync::mpsc::UnboundedReceiver<axum::extract::ws::WebSocket>,
+    ) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get(move |ws: axum::extract::WebSocketUpgrade| {
+                let tx = tx.clone();
+                async move {
+                    ws.on_upgrade(move |ws| async move {
+                        let _ = tx.send(ws);
+                    })
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn filtered_subscribers() {
+        use futures::StreamExt;
+
+        let (addr, mut rx) = ws_server().await;
+
+        let url = format!("ws://{addr}/");
+        let filter = Some(DidFilter::from(["did:plc:alice".to_string()]));
+
+        let (mut all, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let ws = rx.recv().await.unwrap();
+        let (mut alice, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let ws_alice = rx.recv().await.unwrap();
+
+        let mut clients = vec![
+            Subscriber::spawn(ws.into(), None, None, None, None, None, PING_INTERVAL),
+            Subscriber::spawn(
+                ws_alice.into(),
+                None,
+                None,
+                filter.clone(),
+                None,
+                None,
+                PING_INTERVAL,
+            ),
+        ];
+
+        let mut history = History::new(1000, None);
+        for (seq, did) in [
+            (1, "did:plc:alice"),
+            (2, "did:plc:bob"),
+            (3, "did:plc:alice"),
+        ] {
+            let mut msg = identity(did);
+            let (_, frame) = serialize_message(seq, &mut msg).await;
+            history.push_back((seq, Some(did.to_string()), frame.clone()));
+
+            broadcast_message(&mut clients, Some(seq), Some(did), Message::Binary(frame));
+        }
+        broadcast_message(&mut clients, None, None, Message::Ping(Default::default()));
+
+        for (seq, did) in [
+            (1, "did:plc:alice"),
+            (2, "did:plc:bob"),
+            (3, "did:plc:alice"),
+        ] {
+            assert_eq!(recv_identity(&mut all).await, (seq, did.to_string()));
+        }
+
+        // The filtered subscriber sees the same sequence numbers, but only for alice.
+        for seq in [1, 3] {
+            assert_eq!(
+                recv_identity(&mut alice).await,
+                (seq, "did:plc:alice".to_string())
+            );
+        }
+        assert!(alice.next().await.unwrap().unwrap().is_ping());
+
+        // Backfill is filtered too.
+        let (mut backfill, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let ws = rx.recv().await.unwrap();
+        let catch_up = CatchUp {
+            history: Arc::new(RwLock::new(history)),
+            cursor: 0,
+        };
+        let _backfilled = Subscriber::spawn(
+            ws.into(),
+            None,
+            None,
+            filter.clone(),
+            Some(catch_up),
+            None,
+            PING_INTERVAL,
+        );
+
+        for seq in [1, 3] {
+            assert_eq!(
+                recv_identity(&mut backfill).await,
+                (seq, "did:plc:alice".to_string())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber() {
+        use futures::StreamExt;
+
+        let (addr, mut rx) = ws_server().await;
+
+        let url = format!("ws://{addr}/");
+        let (fast, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let ws_fast = rx.recv().await.unwrap();
+        // A client that never reads, so that its socket's buffers fill up.
+        let (_slow, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let ws_slow = rx.recv().await.unwrap();
+
+        let mut clients = vec![
+            Subscriber::spawn(ws_fast.into(), None, None, None, None, None, PING_INTERVAL),
+            Subscriber::spawn(ws_slow.into(), None, None, None, None, None, PING_INTERVAL),
+        ];
+
+        const N: usize = 3 * CLIENT_QUEUE;
+        let reader = tokio::spawn(async move {
+            fast.take(N)
+                .filter_map(|m| async move { m.ok().filter(|m| m.is_binary()) })
+                .map(|m| m.into_data().len())
+                .collect::<Vec<_>>()
+                .await
+        });
+
+        let frame = Bytes::from(vec![0u8; 16 * 1024]);
+        for seq in 1..=N as u64 {
+            broadcast_message(
+                &mut clients,
+                Some(seq),
+                None,
+                Message::Binary(frame.clone()),
+            );
+
+            // Let the fast client keep up.
+            while clients[0].queue.capacity() < CLIENT_QUEUE / 2 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+
+        // The slow client was disconnected once its queue filled up, without holding up the fast
+        // one, which received every frame.
+        assert_eq!(clients.len(), 1);
+        let received = reader.await.unwrap();
+        assert_eq!(received.len(), N);
+        assert!(received.iter().all(|len| *len == frame.len()));
+    }
+
+    #[tokio::test]
+    async fn unresponsive_subscriber() {
+        use tokio_tungstenite::tungstenite::{
+            protocol::frame::coding::CloseCode, Message as WsMessage,
+        };
+
+        let (addr, mut rx) = ws_server().await;
+
+        let url = format!("ws://{addr}/");
+        let (mut live, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let ws_live = rx.recv().await.unwrap();
+        let (mut silent, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let ws_silent = rx.recv().await.unwrap();
+
+        let interval = Duration::from_millis(100);
+        let mut clients = vec![
+            Subscriber::spawn(ws_live.into(), None, None, None, None, None, interval),
+            Subscriber::spawn(ws_silent.into(), None, None, None, None, None, interval),
+        ];
+
+        // The live client reads its frames, which answers pings; the silent one doesn't.
+        let reader = tokio::spawn(async move {
+            while let Some(Ok(msg)) = live.next().await {
+                assert!(msg.is_ping());
+            }
+        });
+
+        let start = Instant::now();
+        while clients.len() > 1 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            expire_clients(&mut clients, 1);
+        }
+        assert!(start.elapsed() >= interval * MAX_MISSED_PINGS);
+
+        // The silent client was sent its pings, then a close frame.
+        let mut pings = 0;
+        let close = loop {
+            match silent.next().await.unwrap().unwrap() {
+                WsMessage::Ping(_) => pings += 1,
+                WsMessage::Close(close) => break close.unwrap(),
+                msg => panic!("unexpected {msg:?}"),
+            }
+        };
+        assert_eq!(pings, MAX_MISSED_PINGS);
+        assert_eq!(close.code, CloseCode::Policy);
+
+        // The live client is still connected.
+        assert!(!clients[0].queue.is_closed());
+        assert!(!reader.is_finished());
+    }
+
+    #[tokio::test]
+    async fn unresponsive_busy_subscriber() {
+        use tokio_tungstenite::tungstenite::{
+            protocol::frame::coding::CloseCode, Message as WsMessage,
+        };
+
+        let (addr, mut rx) = ws_server().await;
+
+        let url = format!("ws://{addr}/");
+        let (mut live, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let ws_live = rx.recv().await.unwrap();
+        let (mut silent, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let ws_silent = rx.recv().await.unwrap();
+
+        let interval = Duration::from_millis(100);
+        let mut clients = vec![
+            Subscriber::spawn(ws_live.into(), None, None, None, None, None, interval),
+            Subscriber::spawn(ws_silent.into(), None, None, None, None, None, interval),
+        ];
+
+        let reader = tokio::spawn(async move {
+            while let Some(Ok(msg)) = live.next().await {
+                assert!(msg.is_binary() || msg.is_ping());
+            }
+        });
+
+        // Events keep flowing to both clients, more often than they're pinged, and the silent one
+        // is still disconnected once it's left its pings unanswered.
+        let frame = Bytes::from_static(b"event");
+        let start = Instant::now();
+        let mut seq = 0;
+        while clients.len() > 1 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            seq += 1;
+            broadcast_message(
+                &mut clients,
+                Some(seq),
+                None,
+                Message::Binary(frame.clone()),
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            expire_clients(&mut clients, seq);
+        }
+        assert!(start.elapsed() >= interval * MAX_MISSED_PINGS);
+
+        // The silent client was sent events interleaved with its pings, then a close frame.
+        let mut pings = 0;
+        let mut events = 0;
+        let close = loop {
+            match silent.next().await.unwrap().unwrap() {
+                WsMessage::Ping(_) => pings += 1,
+                WsMessage::Binary(_) => events += 1,
+                WsMessage::Close(close) => break close.unwrap(),
+                msg => panic!("unexpected {msg:?}"),
+            }
+        };
+        assert_eq!(pings, MAX_MISSED_PINGS);
+        assert!(events > pings);
+        assert_eq!(close.code, CloseCode::Policy);
+
+        // The live client answered its pings, and is still connected.
+        assert!(!clients[0].queue.is_closed());
+        assert!(!reader.is_finished());
+    }
+
+    #[tokio::test]
+    async fn expired_subscriber() {
+        use tokio_tungstenite::tungstenite::{
+            protocol::frame::coding::CloseCode, Message as WsMessage,
+        };
+
+        let (addr, mut rx) = ws_server().await;
+
+        let url = format!("ws://{addr}/");
+        let history = Arc::new(RwLock::new(History::new(1000, None)));
+        let mut clients = Vec::new();
+        let mut seq = 1;
+
+        // Broadcast `n` events, as the firehose does: recorded in the history, then queued.
+        async fn broadcast(
+            history: &RwLock<History>,
+            clients: &mut Vec<Subscriber>,
+            seq: &mut u64,
+            n: u64,
+        ) {
+            for _ in 0..n {
+                let did = "did:plc:alice";
+                let (_, frame) = serialize_message(*seq, &mut identity(did)).await;
+                history
+                    .write()
+                    .unwrap()
+                    .push_back((*seq, Some(did.to_string()), frame.clone()));
+                broadcast_message(clients, Some(*seq), Some(did), Message::Binary(frame));
+                *seq += 1;
+            }
+        }
+
+        // A client whose connection outlives its deadline, with events before and after.
+        let (mut first, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let ws = rx.recv().await.unwrap();
+        let lifetime = Duration::from_millis(100);
+        clients.push(Subscriber::spawn(
+            ws.into(),
+            connection_deadline(Instant::now(), Some(lifetime)),
+            None,
+            None,
+            None,
+            None,
+            PING_INTERVAL,
+        ));
+
+        broadcast(&history, &mut clients, &mut seq, 5).await;
+        expire_clients(&mut clients, seq);
+        assert_eq!(clients.len(), 1);
+
+        tokio::time::sleep(lifetime + lifetime / 10).await;
+        broadcast(&history, &mut clients, &mut seq, 3).await;
+        expire_clients(&mut clients, seq);
+        assert!(clients.is_empty());
+        // Events keep coming while it reconnects.
+        broadcast(&history, &mut clients, &mut seq, 3).await;
+
+        // It's sent the events up to its deadline, then closed with 1012 and its cursor.
+        let mut received = Vec::new();
+        let close = loop {
+            match first.next().await.unwrap().unwrap() {
+                WsMessage::Binary(frame) => received.push(decode_identity(&frame).0 as u64),
+                WsMessage::Close(close) => break close.unwrap(),
+                msg => panic!("unexpected {msg:?}"),
+            }
+        };
+        assert_eq!(close.code, CloseCode::Restart);
+        let cursor: u64 = close
+            .reason
+            .rsplit_once("cursor=")
+            .unwrap()
+            .1
+            .parse()
+            .unwrap();
+        assert_eq!(cursor, *received.last().unwrap());
+
+        // Reconnecting with that cursor backfills what it missed, then picks up live events.
+        let (mut second, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let ws = rx.recv().await.unwrap();
+        clients.push(Subscriber::spawn(
+            ws.into(),
+            None,
+            None,
+            None,
+            Some(CatchUp {
+                history: history.clone(),
+                cursor,
+            }),
+            None,
+            PING_INTERVAL,
+        ));
+        broadcast(&history, &mut clients, &mut seq, 3).await;
+        let last = seq - 1;
+
+        while *received.last().unwrap() < last {
+            let (seq, _) = recv_identity(&mut second).await;
+            received.push(seq as u64);
+        }
+
+        // Every event was received exactly once, in order.
+        assert_eq!(received, (1..=last).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn bounded_backfill() {
+        const N: u64 = 10_000;
+
+        // Every hundredth event pertains to alice.
+        let mut history = History::new(N as usize, None);
+        for seq in 1..=N {
+            let did = if seq % 100 == 0 {
+                "did:plc:alice"
+            } else {
+                "did:plc:bob"
+            };
+            let mut msg = identity(did);
+            let (_, frame) = serialize_message(seq, &mut msg).await;
+            history.push_back((seq, Some(did.to_string()), frame));
+        }
+
+        // Batches stay within bounds, and together cover the history in order.
+        for (filter, expected) in [
+            (None, N),
+            (
+                Some(DidFilter::from(["did:plc:alice".to_string()])),
+                N / 100,
+            ),
+        ] {
+            let mut cursor = 0;
+            let mut seqs = Vec::new();
+            loop {
+                let batch = next_batch(&history, cursor, &filter);
+                assert!(batch.frames.len() <= BACKFILL_BATCH);
+                assert!(
+                    batch.frames.iter().map(|(_, f)| f.len()).sum::<usize>()
+                        <= BACKFILL_BATCH_BYTES
+                );
+
+                seqs.extend(batch.frames.iter().map(|(seq, _)| *seq));
+                cursor = batch.last.unwrap_or(cursor);
+                if !batch.more {
+                    break;
+                }
+            }
+
+            assert_eq!(seqs.len() as u64, expected);
+            assert!(seqs.windows(2).all(|w| w[0] < w[1]));
+            assert_eq!(cursor, N);
+        }
+
+        // Backfill a real subscriber, from partway through the history.
+        let (addr, mut rx) = ws_server().await;
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/"))
+            .await
+            .unwrap();
+        let mut ws = rx.recv().await.unwrap().into();
+
+        let history = Arc::new(RwLock::new(history));
+        let server = tokio::spawn({
+            let history = history.clone();
+            async move { backfill(&mut ws, &history, 100, &None).await.unwrap() }
+        });
+
+        for seq in 101..=N {
+            assert_eq!(recv_identity(&mut client).await.0, seq as i64);
+        }
+        assert_eq!(server.await.unwrap(), Backfilled::Caught(N));
+    }
+
+    #[tokio::test]
+    async fn too_big() {
+        let ops = (0..500)
+            .map(|i| RepoOp::Create {
+                cid: Cid::default(),
+                path: format!("app.bsky.feed.post/{i}"),
+            })
+            .collect::<Vec<_>>();
+        let commit = Commit {
+            car: vec![0; 3 * 1024 * 1024],
+            ops,
+            cid: Cid::default(),
+            rev: "3jzfcijpj2z2a".to_string(),
+            did: Did::new("did:plc:test".to_string()).unwrap(),
+            pcid: None,
+            since: None,
+            blobs: vec![],
+        };
+
+        let mut data: sync::subscribe_repos::Commit = commit.into();
+        mark_too_big(&mut data).await;
+        assert!(data.too_big);
+        assert_eq!(data.ops.len(), 500);
+        assert!(data.blocks.len() < 1024);
+
+        // The frame is within the limit, and still a valid commit.
+        let mut msg = sync::subscribe_repos::Message::Commit(Box::new(data));
+        let (_, frame) = serialize_message(1, &mut msg).await;
+        assert!(frame.len() < 1_000_000);
+
+        let hdr = serde_ipld_dagcbor::to_vec(&FrameHeader::Message("#commit".to_string())).unwrap();
+        let body: sync::subscribe_repos::Commit =
+            serde_ipld_dagcbor::from_slice(frame.strip_prefix(&hdr[..]).unwrap()).unwrap();
+        assert!(body.too_big);
+        assert_eq!(body.ops.len(), 500);
+        assert_eq!(body.ops[499].path, "app.bsky.feed.post/499");
+    }
+
+    #[tokio::test]
+    async fn since() {
+        let db = crate::testing::memory_db().await;
+        let config: FirehoseConfig =
+            serde_json::from_value(serde_json::json!({ "relays": [] })).unwrap();
+
+        // Two consecutive commits to a repository, the first of which is its first.
+        let commit = |rev: &str, since: Option<&str>| Commit {
+            car: vec![],
+            ops: vec![],
+            cid: Cid::default(),
+            rev: rev.to_string(),
+            did: Did::new("did:plc:test".to_string()).unwrap(),
+            pcid: None,
+            since: since.map(str::to_string),
+            blobs: vec![],
+        };
+        let mut conn = db.acquire().await.unwrap();
+        for (rev, since) in [
+            ("3jzfcijpj2z2a", None),
+            ("3jzfcijpj2z2b", Some("3jzfcijpj2z2a")),
+        ] {
+            enqueue_commit(&mut conn, &config, &commit(rev, since))
+                .await
+                .unwrap();
+        }
+
+        let frames: Vec<Vec<u8>> =
+            sqlx::query_scalar(r#"SELECT frame FROM firehose_outbox ORDER BY seq"#)
+                .fetch_all(&mut *conn)
+                .await
+                .unwrap();
+        let hdr = serde_ipld_dagcbor::to_vec(&FrameHeader::Message("#commit".to_string())).unwrap();
+        let commits = frames
+            .iter()
+            .map(|frame| {
+                serde_ipld_dagcbor::from_slice::<sync::subscribe_repos::Commit>(
+                    frame.strip_prefix(&hdr[..]).unwrap(),
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(commits[0].since, None);
+        assert_eq!(commits[1].since.as_ref(), Some(&commits[0].rev));
+    }
+
+    #[tokio::test]
+    async fn shared_frames() {
+        // A large event, as from a commit with many blocks.
+        let mut msg = sync::subscribe_repos::Message::Commit(Box::new(
+            Commit {
+                car: vec![0; 1024 * 1024],
+                ops: vec![],
+                cid: Cid::default(),
+                rev: "3jzfcijpj2z2a".to_string(),
+                did: Did::new("did:plc:test".to_string()).unwrap(),
+                pcid: None,
+                since: None,
+                blobs: vec![],
+            }
+            .into(),
+        ));
+        let (_, frame) = serialize_message(1, &mut msg).await;
+        assert!(frame.len() > 1024 * 1024);
+
+        let mut history = History::new(1000, None);
+        history.push_back((1, Some("did:plc:test".to_string()), frame.clone()));
+
+        // Each subscriber, whether live or backfilling, is handed the same buffer rather than a
+        // copy of the frame.
+        let (mut clients, mut queues): (Vec<_>, Vec<_>) = (0..50)
+            .map(|_| {
+                let (queue, rx) = tokio::sync::mpsc::channel(1);
+                let client = Subscriber {
+                    queue,
+                    deadline: None,
+                    filter: None,
+                    task: tokio::spawn(async {}),
+                };
+                (client, rx)
+            })
+            .unzip();
+        broadcast_message(
+            &mut clients,
+            Some(1),
+            Some("did:plc:test"),
+            Message::Binary(frame.clone()),
+        );
+        assert_eq!(clients.len(), 50);
+
+        for queue in &mut queues {
+            let Some((Some(1), Message::Binary(sent))) = queue.recv().await else {
+                panic!("expected the frame to be queued");
+            };
+            assert_eq!(sent.as_ptr(), frame.as_ptr());
+
+            let batch = next_batch(&history, 0, &None);
+            assert_eq!(batch.frames[0].1.as_ptr(), frame.as_ptr());
+        }
+    }
+
+    #[test]
+    fn history_limits() {
+        let frame = Bytes::from_static(&[0; 100]);
+
+        // Bounded by the number of events...
+        let mut history = History::new(10, None);
+        for seq in 1..=25 {
+            append_history(&mut history, seq, None, frame.clone());
+        }
+        assert_eq!(history.len(), 10);
+        assert_eq!(history.bytes, 10 * 100);
+        assert_eq!(history.front().map(|(seq, _, _)| *seq), Some(16));
+        assert!(history.retains(15) && !history.retains(14));
+
+        // ...and by their size, whichever is reached first.
+        let mut history = History::new(10, Some(450));
+        for seq in 1..=25 {
+            append_history(&mut history, seq, None, frame.clone());
+        }
+        assert_eq!(history.len(), 4);
+        assert_eq!(history.bytes, 4 * 100);
+        assert_eq!(SeqAudit::run(&history).first_seq, Some(22));
+
+        // The newest event is retained even if it's larger than the limit on its own.
+        append_history(&mut history, 26, None, Bytes::from_static(&[0; 1000]));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.bytes, 1000);
+    }
+
+    #[test]
+    fn seq_audit() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let frame = Bytes::from_static(b"frame");
+        let mut history = History::new(1000, None);
+        for seq in 1..=10 {
+            append_history(&mut history, seq, None, frame.clone());
+        }
+
+        let audit = SeqAudit::run(&history);
+        assert_eq!(audit.anomalies(), 0);
+        assert_eq!((audit.first_seq, audit.last_seq), (Some(1), Some(10)));
+
+        // Inject a gap (11 and 12 are skipped), then a duplicate.
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            append_history(&mut history, 13, None, frame.clone());
+            append_history(&mut history, 13, None, frame.clone());
+            append_history(&mut history, 14, None, frame.clone());
+        });
+
+        // Both are caught as they're appended...
+        let anomalies = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .find(|(key, _, _, _)| key.key().name() == FIREHOSE_SEQ_ANOMALIES)
+            .map(|(_, _, _, value)| value);
+        assert!(
+            matches!(anomalies, Some(DebugValue::Counter(2))),
+            "{anomalies:?}"
+        );
+
+        // ... and by the audit.
+        let audit = SeqAudit::run(&history);
+        assert_eq!(audit.gaps, [(11, 12)]);
+        assert_eq!(audit.duplicates, [13]);
+        assert_eq!(audit.anomalies(), 2);
+        assert_eq!(audit.events, 13);
+    }
+
+    #[tokio::test]
+    async fn seq_survives_restart() {
+        let db = crate::testing::memory_db().await;
+
+        let config = crate::testing::config("", serde_json::json!({}));
+        let commit = |rev: &str| Commit {
+            car: vec![],
+            ops: vec![],
+            cid: Cid::default(),
+            rev: rev.to_string(),
+            did: Did::new("did:plc:test".to_string()).unwrap(),
+            pcid: None,
+            since: None,
+            blobs: vec![],
+        };
+
+        let (handle, fhp) = spawn(config.clone(), db.clone()).await.unwrap();
+        let mut last = 0;
+        for rev in ["3jzfcijpj2z2a", "3jzfcijpj2z2b"] {
+            let mut conn = db.acquire().await.unwrap();
+            let seq = enqueue_commit(&mut conn, &fhp.config, &commit(rev))
+                .await
+                .unwrap();
+            assert!(seq > last);
+            last = seq;
+        }
+        fhp.wake();
+        while fhp.history.read().unwrap().back().map(|(seq, _, _)| *seq) != Some(last) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Restart the firehose, with a commit queued in the meantime (e.g. by a write that
+        // completed just before a crash).
+        handle.abort();
+        let _ = handle.await;
+        let mut conn = db.acquire().await.unwrap();
+        let queued = enqueue_commit(&mut conn, &fhp.config, &commit("3jzfcijpj2z2c"))
+            .await
+            .unwrap();
+        assert_eq!(queued, last + 1);
+        drop((conn, fhp));
+
+        // The queued commit is broadcast on startup, once, followed by new events.
+        let (_, fhp) = spawn(config, db.clone()).await.unwrap();
+        let mut conn = db.acquire().await.unwrap();
+        let seq = enqueue_commit(&mut conn, &fhp.config, &commit("3jzfcijpj2z2d"))
+            .await
+            .unwrap();
+        drop(conn);
+        fhp.wake();
+        assert!(seq > queued, "{seq} follows {queued}");
+        while fhp.history.read().unwrap().back().map(|(seq, _, _)| *seq) != Some(seq) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            fhp.history
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(seq, _, _)| *seq)
+                .collect::<Vec<_>>(),
+            [queued, seq]
+        );
+        let stored: Vec<i64> =
+            sqlx::query_scalar(r#"SELECT seq FROM firehose_events ORDER BY seq"#)
+                .fetch_all(&db)
+                .await
+                .unwrap();
+        assert_eq!(stored, [1, 2, 3, 4]);
+        let pending: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM firehose_outbox"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(pending, 0);
+
+        // The new sequence number is stamped on the frame, too.
+        let history = fhp.history.read().unwrap();
+        let (history_seq, _, frame) = history.back().unwrap();
+        assert_eq!(*history_seq, seq);
+        let hdr = serde_ipld_dagcbor::to_vec(&FrameHeader::Message("#commit".to_string())).unwrap();
+        let body: ipld_core::ipld::Ipld =
+            serde_ipld_dagcbor::from_slice(frame.strip_prefix(&hdr[..]).unwrap()).unwrap();
+        assert!(matches!(
+            body.get("seq"),
+            Ok(Some(ipld_core::ipld::Ipld::Integer(s))) if *s == seq as i128
+        ));
+    }
+
+    /// Connect a subscriber to the firehose, with a cursor.
+    async fn connect(
+        fhp: &FirehoseProducer,
+        rx: &mut tokio::sync::mpsc::UnboundedReceiver<axum::extract::ws::WebSocket>,
+        addr: SocketAddr,
+        cursor: u64,
+    ) -> tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>
+    {
+        let (client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/"))
+            .await
+            .unwrap();
+        let ws = rx.recv().await.unwrap();
+        let fhp = fhp.clone();
+        let admission = fhp.admit(addr).unwrap();
+        tokio::spawn(async move {
+            fhp.client_connection(ws.into(), Some(cursor), admission, None)
+                .await
+        });
+        client
+    }
+
+    #[tokio::test]
+    async fn cursors() {
+        use ipld_core::ipld::Ipld;
+
+        let db = crate::testing::memory_db().await;
+
+        let config = crate::testing::config("", serde_json::json!({}));
+        let (_, fhp) = spawn(config, db.clone()).await.unwrap();
+
+        let emit = |n: u64| {
+            let fhp = fhp.clone();
+            async move {
+                for _ in 0..n {
+                    let sync::subscribe_repos::Message::Identity(identity) =
+                        identity("did:plc:alice")
+                    else {
+                        unreachable!();
+                    };
+                    fhp.identity(*identity).await.unwrap();
+                }
+            }
+        };
+        emit(20).await;
+        while fhp.history.read().unwrap().back().map(|(seq, _, _)| *seq) != Some(20) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Events up to 5 are no longer retained.
+        sqlx::query(r#"DELETE FROM firehose_events WHERE seq <= 5"#)
+            .execute(&db)
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            fhp.history.write().unwrap().pop_front();
+        }
+
+        let (addr, mut rx) = ws_server().await;
+
+        // Too old: told so, then backfilled from the oldest event retained.
+        let mut old = connect(&fhp, &mut rx, addr, 2).await;
+        let frame = old.next().await.unwrap().unwrap().into_data();
+        let hdr = serde_ipld_dagcbor::to_vec(&FrameHeader::Message("#info".to_string())).unwrap();
+        let body: Ipld =
+            serde_ipld_dagcbor::from_slice(frame.strip_prefix(&hdr[..]).unwrap()).unwrap();
+        assert!(matches!(
+            body.get("name"),
+            Ok(Some(Ipld::String(name))) if name == "OutdatedCursor"
+        ));
+        for seq in 6..=20 {
+            assert_eq!(recv_identity(&mut old).await.0, seq);
+        }
+
+        // Within the window: backfilled from the cursor.
+        let mut within = connect(&fhp, &mut rx, addr, 10).await;
+        for seq in 11..=20 {
+            assert_eq!(recv_identity(&mut within).await.0, seq);
+        }
+
+        // From the start: every event retained, without being told that any are missing.
+        let mut start = connect(&fhp, &mut rx, addr, 0).await;
+        for seq in 6..=20 {
+            assert_eq!(recv_identity(&mut start).await.0, seq);
+        }
+
+        // At the current event: nothing to backfill, just new events.
+        let mut current = connect(&fhp, &mut rx, addr, 20).await;
+        emit(1).await;
+        for client in [&mut old, &mut within, &mut start, &mut current] {
+            assert_eq!(recv_identity(client).await.0, 21);
+        }
+
+        // In the future: told so, with both the cursor and the current sequence number.
+        let mut future = connect(&fhp, &mut rx, addr, 30).await;
+        let frame = future.next().await.unwrap().unwrap().into_data();
+        let hdr = serde_ipld_dagcbor::to_vec(&FrameHeader::Error).unwrap();
+        let body: Ipld =
+            serde_ipld_dagcbor::from_slice(frame.strip_prefix(&hdr[..]).unwrap()).unwrap();
+        let field = |name: &str| body.get(name).unwrap().unwrap().clone();
+        assert_eq!(field("error"), Ipld::String("FutureCursor".to_string()));
+        assert_eq!(field("cursor"), Ipld::Integer(30));
+        assert_eq!(field("seq"), Ipld::Integer(21));
+    }
+
+    #[tokio::test]
+    async fn connect_while_broadcasting() {
+        let db = crate::testing::memory_db().await;
+
+        let config = crate::testing::config("", serde_json::json!({}));
+        let (_, fhp) = spawn(config, db).await.unwrap();
+
+        let (addr, mut rx) = ws_server().await;
+
+        // Events are produced continuously while consumers connect.
+        const EVENTS: i64 = 500;
+        let producer = tokio::spawn({
+            let fhp = fhp.clone();
+            async move {
+                for _ in 0..EVENTS {
+                    let sync::subscribe_repos::Message::Identity(identity) =
+                        identity("did:plc:alice")
+                    else {
+                        unreachable!();
+                    };
+                    fhp.identity(*identity).await.unwrap();
+                }
+            }
+        });
+
+        let mut consumers = Vec::new();
+        for _ in 0..5 {
+            consumers.push(connect(&fhp, &mut rx, addr, 0).await);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        producer.await.unwrap();
+
+        // Each consumer sees every event exactly once, in order.
+        for mut consumer in consumers {
+            for seq in 1..=EVENTS {
+                assert_eq!(recv_identity(&mut consumer).await.0, seq);
+            }
+        }
+    }
+
+    #[test]
+    fn subscriber_limits() {
+        let config: FirehoseConfig = serde_json::from_value(serde_json::json!({
+            "relays": [],
+            "max_subscribers": 3,
+            "max_subscribers_per_ip": 2,
+        }))
+        .unwrap();
+        let tracker = SubscriberTracker::default();
+        let alice: SocketAddr = "192.0.2.1:1000".parse().unwrap();
+        let bob: SocketAddr = "192.0.2.2:1000".parse().unwrap();
+
+        // Connections from one address are limited on their own...
+        let a1 = tracker.admit(&config, alice).unwrap();
+        let a2 = tracker.admit(&config, alice).unwrap();
+        assert_eq!(tracker.admit(&config, alice).unwrap_err(), Rejection::Ip);
+
+        // ...and together with those from every other address.
+        let b1 = tracker.admit(&config, bob).unwrap();
+        assert_eq!(tracker.admit(&config, bob).unwrap_err(), Rejection::Total);
+
+        // Closed connections make room for new ones.
+        drop(a1);
+        let b2 = tracker.admit(&config, bob).unwrap();
+        drop(a2);
+        assert_eq!(tracker.admit(&config, bob).unwrap_err(), Rejection::Ip);
+        let a3 = tracker.admit(&config, alice).unwrap();
+
+        drop((b1, b2, a3));
+        let admitted = tracker.admitted.lock().unwrap();
+        assert_eq!(admitted.total, 0);
+        assert!(admitted.by_ip.is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscriber_tracking() {
+        let db = crate::testing::memory_db().await;
+
+        let config = crate::testing::config("", serde_json::json!({}));
+        let (_, fhp) = spawn(config, db).await.unwrap();
+
+        let (addr, mut rx) = ws_server().await;
+
+        let mut client = connect(&fhp, &mut rx, addr, 0).await;
+        while !fhp.subscribers().snapshot().first().is_some_and(|s| s.live) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let sync::subscribe_repos::Message::Identity(event) = identity("did:plc:alice") else {
+            unreachable!();
+        };
+        fhp.identity(*event).await.unwrap();
+        assert_eq!(recv_identity(&mut client).await.0, 1);
+
+        let subscribers = fhp.subscribers().snapshot();
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].cursor, Some(0));
+        assert_eq!(subscribers[0].relay, None);
+        assert!(!subscribers[0].deflate);
+        assert_eq!(subscribers[0].frames_sent, 1);
+        assert_eq!(subscribers[0].last_seq, Some(1));
+
+        // The subscriber is forgotten once it disconnects.
+        drop(client);
+        while !fhp.subscribers().snapshot().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        use tokio_tungstenite::tungstenite::{
+            protocol::frame::coding::CloseCode, Message as WsMessage,
+        };
+
+        let db = crate::testing::memory_db().await;
+
+        let config = crate::testing::config("", serde_json::json!({}));
+        let (handle, fhp) = spawn(config, db).await.unwrap();
+
+        let (addr, mut rx) = ws_server().await;
+
+        let emit = |n: u64| {
+            let fhp = fhp.clone();
+            async move {
+                for _ in 0..n {
+                    let sync::subscribe_repos::Message::Identity(identity) =
+                        identity("did:plc:alice")
+                    else {
+                        unreachable!();
+                    };
+                    fhp.identity(*identity).await.unwrap();
+                }
+            }
+        };
+
+        let mut ws = connect(&fhp, &mut rx, addr, 0).await;
+        emit(1).await;
+        assert_eq!(recv_identity(&mut ws).await.0, 1);
+
+        // Events queued before the shutdown are still delivered, followed by a close frame.
+        emit(50).await;
+        fhp.shutdown().await;
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .unwrap()
+            .unwrap();
+
+        for seq in 2..=51 {
+            assert_eq!(recv_identity(&mut ws).await.0, seq);
+        }
+        let WsMessage::Close(Some(close)) = ws.next().await.unwrap().unwrap() else {
+            panic!("expected a close frame");
+        };
+        assert_eq!(close.code, CloseCode::Restart);
+        assert!(close.reason.ends_with("cursor=51"));
+
+        // Nothing more is broadcast.
+        emit(1).await;
+        assert!(fhp
+            .history
+            .read()
+            .unwrap()
+            .back()
+            .is_some_and(|(seq, _, _)| *seq == 51));
+    }
+
+    #[tokio::test]
+    async fn stored_backfill() {
+        let db = crate::testing::memory_db().await;
+
+        let config = crate::testing::config("", serde_json::json!({}));
+        let (_, fhp) = spawn(config, db).await.unwrap();
+
+        // More events than the in-memory history holds.
+        let n = (fhp.config.history_size + 300) as u64;
+        for _ in 0..n {
+            let sync::subscribe_repos::Message::Identity(identity) = identity("did:plc:alice")
+            else {
+                unreachable!();
+            };
+            fhp.identity(*identity).await.unwrap();
+        }
+        while fhp.history.read().unwrap().back().map(|(seq, _, _)| *seq) != Some(n) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(fhp.history.read().unwrap().len(), fhp.config.history_size);
+
+        // A subscriber with a cursor older than the history is backfilled from the store, then
+        // from the history, without a gap.
+        let (addr, mut rx) = ws_server().await;
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/"))
+            .await
+            .unwrap();
+        let ws = rx.recv().await.unwrap();
+        let admission = fhp.admit(addr).unwrap();
+        tokio::spawn(async move {
+            fhp.client_connection(ws.into(), Some(10), admission, None)
+                .await
+        });
+
+        for seq in 11..=n {
+            assert_eq!(recv_identity(&mut client).await.0, seq as i64);
+        }
+    }
+
+    #[test]
+    fn relay_state() {
+        let tracker = RelayTracker::default();
+
+        tracker.crawled(
+            "relay.example.com",
+            Err("503 Service Unavailable".to_string()),
+        );
+
+        let conn = RelayConnection::new(tracker.clone(), "relay.example.com");
+        conn.delivered(42);
+
+        let state = tracker.snapshot()["relay.example.com"].clone();
+        assert!(!state.crawl_ok);
+        assert_eq!(
+            state.last_crawl_status.as_deref(),
+            Some("503 Service Unavailable")
+        );
+        assert_eq!(state.crawl_failures, 1);
+        assert!(state.next_crawl.is_some());
+        assert_eq!(state.connections, 1);
+        assert_eq!(state.last_seq, Some(42));
+
+        // Disconnecting the subscriber is reflected in the relay state.
+        drop(conn);
+        assert_eq!(tracker.snapshot()["relay.example.com"].connections, 0);
+
+        // The relay isn't asked again until its backoff has passed.
+        let now = Instant::now();
+        assert!(!tracker.crawl_due("relay.example.com", now));
+        assert!(tracker.crawl_due("relay.example.com", now + CRAWL_BACKOFF_MAX * 2));
+        assert!(tracker.crawl_due("other.example.com", now));
+
+        // A successful crawl resets the failure count.
+        tracker.crawled("relay.example.com", Ok("200 OK".to_string()));
+        let state = tracker.snapshot()["relay.example.com"].clone();
+        assert!(state.crawl_ok);
+        assert_eq!(state.crawl_failures, 0);
+        assert!(state.last_crawl_ok.is_some());
+    }
+
+    #[tokio::test]
+    async fn relay_identity() {
+        let config: FirehoseConfig = serde_json::from_value(serde_json::json!({
+            "relays": ["https://localhost", "https://relay.invalid"],
+            "relay_addresses": { "relay.invalid": ["192.0.2.1"] },
+        }))
+        .unwrap();
+        let tracker = RelayTracker::new(&config);
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        let host = |conn: Option<RelayConnection>| conn.map(|c| c.host.clone());
+
+        // Relays are identified by their configured addresses, or those their hostname resolves to.
+        assert_eq!(
+            host(
+                tracker
+                    .identify(&config, "192.0.2.1".parse().unwrap())
+                    .await
+            ),
+            Some("relay.invalid".to_string())
+        );
+        assert_eq!(
+            host(tracker.identify(&config, localhost).await),
+            Some("localhost".to_string())
+        );
+        assert_eq!(
+            host(
+                tracker
+                    .identify(&config, "192.0.2.2".parse().unwrap())
+                    .await
+            ),
+            None
+        );
+
+        // The resolved addresses are cached, rather than looked up for each connection...
+        tracker.update("localhost", |r| {
+            r.addresses = Some((Instant::now() + RELAY_DNS_TTL, Vec::new()))
+        });
+        assert_eq!(host(tracker.identify(&config, localhost).await), None);
+
+        // ...until they expire.
+        tracker.update("localhost", |r| {
+            r.addresses = Some((Instant::now(), Vec::new()))
+        });
+        assert_eq!(
+            host(tracker.identify(&config, localhost).await),
+            Some("localhost".to_string())
+        );
+    }
+
+    #[test]
+    fn hostnames() {
+        for (hostname, host) in [
+            ("pds.example.com", "pds.example.com"),
+            ("https://pds.example.com", "pds.example.com"),
+            ("https://pds.example.com/", "pds.example.com"),
+            ("http://localhost:2583", "localhost"),
+        ] {
+            assert_eq!(parse_hostname(hostname).unwrap(), host, "{hostname}");
+        }
+
+        for hostname in [
+            "",
+            "pds example.com",
+            "pds.example.com/xrpc",
+            "https://user@pds.example.com",
+            "https://pds.example.com/?q=1",
+            "wss://pds.example.com",
+        ] {
+            assert!(parse_hostname(hostname).is_err(), "{hostname}");
+        }
+    }
+
+    #[test]
+    fn crawl_backoff_grows() {
+        let within = |failures: u32, delay: Duration| {
+            let backoff = crawl_backoff(failures);
+            assert!(
+                backoff >= delay && backoff <= delay + delay / 4,
+                "{backoff:?}"
+            );
+        };
+
+        within(1, CRAWL_BACKOFF_BASE);
+        within(2, CRAWL_BACKOFF_BASE * 2);
+        within(4, CRAWL_BACKOFF_BASE * 8);
+        within(30, CRAWL_BACKOFF_MAX);
+        within(u32::MAX, CRAWL_BACKOFF_MAX);
+    }
 }
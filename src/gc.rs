@@ -0,0 +1,234 @@
+//! Garbage collection of expired sessions, tokens, one-time codes, commit log entries and stored
+//! firehose events.
+//!
+//! Each [`Store`] describes a table whose rows expire, and how long they are retained past their
+//! timestamp (i.e. the expiry plus a grace period). Expired rows are deleted in small batches, so
+//! that the sweeper never holds the database's write lock for long and can run alongside normal
+//! traffic.
+//!
+//! Resumable blob uploads are swept separately, as their partial data must also be removed (see
+//! `endpoints::cleanup_uploads`).
+
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::{Context, Result};
+use metrics::counter;
+use tracing::{info, warn};
+
+use crate::{config::GcConfig, metrics::GC_RECLAIMED, Db};
+
+/// A table of expiring entries.
+#[derive(Debug, Clone, Copy)]
+pub struct Store {
+    /// The name of the store, used in metrics and to override its retention in the config.
+    pub name: &'static str,
+    pub table: &'static str,
+    /// The column holding each entry's timestamp (e.g. its creation or expiry time).
+    pub column: &'static str,
+    /// An additional condition an entry must satisfy to be reclaimed.
+    pub filter: Option<&'static str>,
+    /// How long entries are retained past their timestamp, in seconds.
+    pub retention: u64,
+}
+
+/// All stores subject to garbage collection.
+pub const STORES: &[Store] = &[
+    Store {
+        name: "sessions",
+        table: "sessions",
+        column: "created_at",
+        filter: None,
+        retention: 90 * 24 * 60 * 60,
+    },
+    Store {
+        name: "exhausted_invites",
+        table: "invites",
+        column: "created_at",
+        // Codes issued to an account are kept, as they're listed with their uses.
+        filter: Some("count <= 0 AND did IS NULL"),
+        retention: 0,
+    },
+    Store {
+        name: "email_tokens",
+        table: "email_tokens",
+        column: "requested_at",
+        filter: None,
+        retention: crate::mail::TOKEN_TTL,
+    },
+    Store {
+        name: "reserved_keys",
+        table: "reserved_keys",
+        column: "created_at",
+        filter: None,
+        retention: crate::keys::RESERVATION_TTL,
+    },
+    Store {
+        name: "commit_log",
+        table: "commit_log",
+        column: "committed_at",
+        filter: None,
+        retention: 90 * 24 * 60 * 60,
+    },
+    Store {
+        name: "firehose_events",
+        table: "firehose_events",
+        column: "sequenced_at",
+        filter: None,
+        retention: 3 * 24 * 60 * 60,
+    },
+];
+
+/// Delete a store's expired entries, returning the number of entries deleted.
+async fn sweep_store(db: &Db, store: &Store, retention: u64, batch_size: u32) -> Result<u64> {
+    let filter = store
+        .filter
+        .map(|f| format!("AND ({f})"))
+        .unwrap_or_default();
+    let query = format!(
+        r#"
+        DELETE FROM {table} WHERE rowid IN (
+            SELECT rowid FROM {table}
+                WHERE {column} <= datetime('now', ?) {filter}
+                LIMIT ?
+        )
+        "#,
+        table = store.table,
+        column = store.column,
+    );
+
+    let mut total = 0;
+    loop {
+        let deleted = sqlx::query(&query)
+            .bind(format!("-{retention} seconds"))
+            .bind(batch_size)
+            .execute(db)
+            .await
+            .with_context(|| format!("failed to sweep {}", store.name))?
+            .rows_affected();
+
+        total += deleted;
+        counter!(GC_RECLAIMED, "store" => store.name).increment(deleted);
+
+        if deleted < batch_size as u64 {
+            return Ok(total);
+        }
+
+        // Let other writers in between batches.
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Delete expired entries from all stores, returning the number of entries deleted per store.
+pub async fn sweep(config: &GcConfig, db: &Db) -> Result<BTreeMap<&'static str, u64>> {
+    let mut reclaimed = BTreeMap::new();
+    for store in STORES {
+        let retention = config
+            .retention
+            .get(store.name)
+            .copied()
+            .unwrap_or(store.retention);
+
+        let n = sweep_store(db, store, retention, config.batch_size.max(1)).await?;
+        reclaimed.insert(store.name, n);
+    }
+
+    Ok(reclaimed)
+}
+
+/// Periodically delete expired entries from all stores.
+pub async fn run(config: GcConfig, db: Db) {
+    loop {
+        match sweep(&config, &db).await {
+            Ok(reclaimed) => {
+                let total: u64 = reclaimed.values().sum();
+                if total != 0 {
+                    info!("reclaimed {total} expired entries: {reclaimed:?}");
+                }
+            }
+            Err(e) => warn!("failed to collect expired entries: {e:?}"),
+        }
+
+        tokio::time::sleep(Duration::from_secs(config.interval)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sweep_expired() {
+        let db = crate::testing::memory_db().await;
+
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES
+                ('did:plc:alice', 'alice@example.com', '', '', '', '');
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        // Five expired sessions and two live ones.
+        for i in 0..7 {
+            let age = if i < 5 { "-100 days" } else { "-1 days" };
+            sqlx::query(
+                r#"INSERT INTO sessions (id, did, created_at) VALUES (?, 'did:plc:alice', datetime('now', ?))"#,
+            )
+            .bind(format!("session{i}"))
+            .bind(age)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+
+        // An exhausted invite, and one with uses remaining.
+        sqlx::query(r#"INSERT INTO invites (id, count) VALUES ('used', 0), ('unused', 1)"#)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        // A small batch size, so that several batches are needed.
+        let config = GcConfig {
+            batch_size: 2,
+            ..Default::default()
+        };
+        let reclaimed = sweep(&config, &db).await.unwrap();
+        assert_eq!(
+            reclaimed,
+            BTreeMap::from([
+                ("commit_log", 0),
+                ("email_tokens", 0),
+                ("exhausted_invites", 1),
+                ("firehose_events", 0),
+                ("reserved_keys", 0),
+                ("sessions", 5)
+            ])
+        );
+
+        let sessions: Vec<String> = sqlx::query_scalar(r#"SELECT id FROM sessions ORDER BY id"#)
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(sessions, ["session5", "session6"]);
+        let invites: Vec<String> = sqlx::query_scalar(r#"SELECT id FROM invites"#)
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert_eq!(invites, ["unused"]);
+
+        // Nothing further to reclaim, unless retention is shortened.
+        let reclaimed = sweep(&config, &db).await.unwrap();
+        assert!(reclaimed.values().all(|n| *n == 0));
+
+        let config = GcConfig {
+            retention: HashMap::from([("sessions".to_string(), 0)]),
+            ..Default::default()
+        };
+        let reclaimed = sweep(&config, &db).await.unwrap();
+        assert_eq!(reclaimed["sessions"], 2);
+    }
+}
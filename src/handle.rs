@@ -0,0 +1,617 @@
+//! Validation of handles, and their resolution to DIDs.
+//!
+//! Handles are compared in their normalized form (see [`normalize`]): lowercase, with
+//! internationalized labels in their ASCII (punycode) form. Every handle an account takes goes
+//! through [`validate`], which also applies this PDS's policy on which handles may be taken.
+//!
+//! A handle is resolved through the `_atproto.<handle>` DNS TXT record, falling back to
+//! `https://<handle>/.well-known/atproto-did`. Both lookups are bounded by timeouts, so that a slow
+//! DNS server or web host can't hang a request, and results (including failures) are cached.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, ensure, Context};
+use atrium_api::types::string::Did;
+use axum::{extract::State, http::StatusCode, Extension};
+use hickory_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    error::ResolveErrorKind,
+    TokioAsyncResolver,
+};
+use tracing::debug;
+
+use crate::{config::AppConfig, error::ErrorMessage, host::HandleHost, Client, Db, Error, Result};
+
+/// How long a resolved handle is cached.
+const POSITIVE_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long a handle that failed to resolve is cached.
+const NEGATIVE_TTL: Duration = Duration::from_secs(5 * 60);
+/// How long each method of resolution may take.
+const TIMEOUT: Duration = Duration::from_secs(3);
+/// The maximum size of a `/.well-known/atproto-did` response.
+const MAX_WELL_KNOWN_SIZE: usize = 2048;
+/// The maximum length of a handle, as of any domain name.
+const MAX_LENGTH: usize = 253;
+/// Top-level domains that don't resolve publicly, and so can't be handles.
+const DISALLOWED_TLDS: &[&str] = &[
+    "alt",
+    "arpa",
+    "example",
+    "internal",
+    "invalid",
+    "local",
+    "localhost",
+    "onion",
+];
+
+/// Normalize a handle, checking its syntax: a domain name of at least two labels, each of ASCII
+/// letters, digits and hyphens, and a top-level domain that doesn't start with a digit. Unicode
+/// labels are converted to punycode, and everything is lowercased.
+pub fn normalize(handle: &str) -> anyhow::Result<String> {
+    let handle = idna::domain_to_ascii(handle.trim())
+        .map_err(|_| anyhow!("invalid internationalized domain name"))?
+        .to_ascii_lowercase();
+    ensure!(!handle.is_empty(), "handle is empty");
+    ensure!(
+        handle.len() <= MAX_LENGTH,
+        "handle is longer than {MAX_LENGTH} characters"
+    );
+
+    let labels = handle.split('.').collect::<Vec<_>>();
+    ensure!(labels.len() >= 2, "handle must have at least two labels");
+    for label in &labels {
+        ensure!(!label.is_empty(), "handle has an empty label");
+        ensure!(
+            label.len() <= 63,
+            "label {label:?} is longer than 63 characters"
+        );
+        ensure!(
+            label
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-'),
+            "label {label:?} has characters other than letters, digits and hyphens"
+        );
+        ensure!(
+            !label.starts_with('-') && !label.ends_with('-'),
+            "label {label:?} starts or ends with a hyphen"
+        );
+
+        // Hyphens in the third and fourth places are reserved for encodings of unicode labels, of
+        // which only punycode is in use. Only the canonical encoding of a label is accepted, so
+        // that every handle has a single spelling.
+        if label.get(2..4) == Some("--") {
+            let (unicode, r) = idna::domain_to_unicode(label);
+            ensure!(
+                label.starts_with("xn--")
+                    && r.is_ok()
+                    && idna::domain_to_ascii(&unicode).ok().as_deref() == Some(*label),
+                "label {label:?} is not a valid encoding of a unicode label"
+            );
+        }
+    }
+
+    let tld = labels[labels.len() - 1];
+    ensure!(
+        !tld.starts_with(|c: char| c.is_ascii_digit()),
+        "top-level domain {tld:?} starts with a digit"
+    );
+
+    Ok(handle)
+}
+
+/// Validate a handle an account asks to take, returning it normalized, and whether it's under one
+/// of our handle domains (in which case nothing else proves the account may take it).
+///
+/// Besides the syntax (`InvalidHandle`), this checks that the handle isn't reserved, nor one of
+/// our own hostnames (`HandleNotAvailable`), and that a handle under our domains is a single
+/// plain ASCII label (`UnsupportedDomain`, `InvalidHandle`).
+pub fn validate(config: &AppConfig, handle: &str) -> Result<(String, bool)> {
+    let invalid = |e: anyhow::Error| {
+        let message = e.to_string();
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            e.context(format!("invalid handle {handle:?}")),
+            ErrorMessage::new("InvalidHandle", message),
+        )
+    };
+    let unavailable = |handle: &str| {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("handle {handle} is reserved"),
+            ErrorMessage::new("HandleNotAvailable", "handle is reserved"),
+        )
+    };
+
+    let handle = normalize(handle).map_err(invalid)?;
+    let tld = handle.rsplit('.').next().unwrap_or_default();
+    if DISALLOWED_TLDS.contains(&tld) {
+        return Err(invalid(anyhow!(
+            "the .{tld} top-level domain is not allowed"
+        )));
+    }
+
+    let reserved = |name: &str| {
+        config
+            .reserved_handles
+            .iter()
+            .any(|r| r.trim().eq_ignore_ascii_case(name))
+    };
+    let hostname = std::iter::once(&config.host_name)
+        .chain(&config.host_aliases)
+        .any(|h| h.eq_ignore_ascii_case(&handle));
+    if hostname || reserved(&handle) {
+        return Err(unavailable(&handle));
+    }
+
+    // Our handle domains may be nested (e.g. `example.com` and `pds.example.com`), so the handle is
+    // local if it's a single label under any of them.
+    let labels = config
+        .handle_domains()
+        .into_iter()
+        .filter_map(|domain| {
+            handle
+                .strip_suffix(domain.as_str())
+                .and_then(|label| label.strip_suffix('.'))
+                .map(str::to_string)
+        })
+        .collect::<Vec<_>>();
+    if labels.is_empty() {
+        return Ok((handle, false));
+    }
+
+    let Some(label) = labels.iter().find(|label| !label.contains('.')) else {
+        return Err(unsupported_domain(&handle));
+    };
+    if label.starts_with("xn--") {
+        return Err(invalid(anyhow!(
+            "handles under this server's domains must be plain ASCII"
+        )));
+    }
+    if reserved(label) {
+        return Err(unavailable(&handle));
+    }
+
+    Ok((handle, true))
+}
+
+/// Validate a handle for an account signing up, which must be under one of our handle domains.
+pub fn validate_local(config: &AppConfig, handle: &str) -> Result<String> {
+    match validate(config, handle)? {
+        (handle, true) => Ok(handle),
+        (handle, false) => Err(unsupported_domain(&handle)),
+    }
+}
+
+fn unsupported_domain(handle: &str) -> Error {
+    Error::with_message(
+        StatusCode::BAD_REQUEST,
+        anyhow!("handle {handle} is not under a supported domain"),
+        ErrorMessage::new("UnsupportedDomain", "handle domain is not supported"),
+    )
+}
+
+#[derive(Clone, Debug)]
+struct CachedHandle {
+    did: Option<String>,
+    fetched: Instant,
+}
+
+impl CachedHandle {
+    fn fresh_at(&self, now: Instant) -> bool {
+        let ttl = match self.did {
+            Some(_) => POSITIVE_TTL,
+            None => NEGATIVE_TTL,
+        };
+        now.duration_since(self.fetched) < ttl
+    }
+}
+
+/// Resolves handles to DIDs, with a cache.
+#[derive(Clone)]
+pub struct HandleResolver {
+    dns: TokioAsyncResolver,
+    cache: Arc<RwLock<HashMap<String, CachedHandle>>>,
+}
+
+impl std::fmt::Debug for HandleResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HandleResolver").finish_non_exhaustive()
+    }
+}
+
+impl HandleResolver {
+    /// Create a resolver using the system's DNS configuration, or public DNS if it can't be read.
+    pub fn new() -> Self {
+        let (config, mut opts) = hickory_resolver::system_conf::read_system_conf()
+            .unwrap_or_else(|_| (ResolverConfig::default(), ResolverOpts::default()));
+        opts.timeout = TIMEOUT;
+        opts.attempts = 1;
+
+        Self {
+            dns: TokioAsyncResolver::tokio(config, opts),
+            cache: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    fn get_at(&self, handle: &str, now: Instant) -> Option<CachedHandle> {
+        self.cache
+            .read()
+            .unwrap()
+            .get(handle)
+            .filter(|c| c.fresh_at(now))
+            .cloned()
+    }
+
+    fn insert_at(&self, handle: &str, did: Option<String>, now: Instant) {
+        let mut cache = self.cache.write().unwrap();
+        cache.retain(|_, c| c.fresh_at(now));
+        cache.insert(handle.to_string(), CachedHandle { did, fetched: now });
+    }
+
+    /// Drop a handle from the cache, e.g. after it was moved to another account.
+    pub fn invalidate(&self, handle: &str) {
+        self.cache.write().unwrap().remove(&handle.to_lowercase());
+    }
+
+    /// Resolve a handle to the DID it claims, returning `None` if it doesn't resolve.
+    ///
+    /// N.B: This does not check that the DID claims the handle in turn.
+    pub async fn resolve(&self, client: &Client, handle: &str) -> Option<String> {
+        let handle = handle.to_lowercase();
+        if let Some(c) = self.get_at(&handle, Instant::now()) {
+            return c.did;
+        }
+
+        let did = match self.resolve_dns(&handle).await {
+            Ok(Some(did)) => Some(did),
+            r => {
+                if let Err(e) = r {
+                    debug!("failed to resolve {handle} through DNS: {e:#}");
+                }
+                resolve_well_known(client, &handle)
+                    .await
+                    .inspect_err(|e| debug!("failed to resolve {handle} through HTTPS: {e:#}"))
+                    .ok()
+            }
+        };
+
+        self.insert_at(&handle, did.clone(), Instant::now());
+        did
+    }
+
+    /// Resolve a handle through its `_atproto` TXT record. A handle with no record, or with records
+    /// for more than one DID, doesn't resolve.
+    async fn resolve_dns(&self, handle: &str) -> anyhow::Result<Option<String>> {
+        let lookup =
+            tokio::time::timeout(TIMEOUT, self.dns.txt_lookup(format!("_atproto.{handle}.")))
+                .await
+                .context("timed out")?;
+        let lookup = match lookup {
+            Ok(lookup) => lookup,
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e).context("failed to look up TXT record"),
+        };
+
+        let mut dids = lookup
+            .iter()
+            .map(|txt| {
+                txt.txt_data()
+                    .iter()
+                    .map(|d| String::from_utf8_lossy(d))
+                    .collect::<String>()
+            })
+            .filter_map(|txt| parse_did(txt.strip_prefix("did=")?).ok())
+            .collect::<Vec<_>>();
+        dids.sort();
+        dids.dedup();
+
+        Ok(match dids.as_slice() {
+            [did] => Some(did.clone()),
+            _ => None,
+        })
+    }
+}
+
+impl Default for HandleResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serve `/.well-known/atproto-did` under the hostname of a hosted account's handle (see
+/// [`crate::host::route_handles`]), responding with the account's bare DID.
+pub async fn well_known(
+    State(db): State<Db>,
+    Extension(HandleHost(handle)): Extension<HandleHost>,
+) -> Result<String> {
+    let did: Option<String> = sqlx::query_scalar(r#"SELECT did FROM handles WHERE handle = ?"#)
+        .bind(&handle)
+        .fetch_optional(&db)
+        .await
+        .context("failed to query handle")?;
+
+    did.ok_or_else(|| {
+        Error::with_status(
+            StatusCode::NOT_FOUND,
+            anyhow!("no account has the handle {handle}"),
+        )
+    })
+}
+
+/// Parse a DID claimed for a handle.
+fn parse_did(s: &str) -> anyhow::Result<String> {
+    let did = Did::new(s.trim().to_string()).map_err(|e| anyhow::anyhow!("invalid DID: {e}"))?;
+    Ok(did.as_str().to_string())
+}
+
+/// Resolve a handle through `https://<handle>/.well-known/atproto-did`, which responds with the
+/// bare DID.
+async fn resolve_well_known(client: &Client, handle: &str) -> anyhow::Result<String> {
+    let fetch = async {
+        let mut response = client
+            .get(format!("https://{handle}/.well-known/atproto-did"))
+            .send()
+            .await
+            .context("failed to fetch")?
+            .error_for_status()
+            .context("bad response")?;
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.context("failed to read response")? {
+            body.extend_from_slice(&chunk);
+            if body.len() > MAX_WELL_KNOWN_SIZE {
+                bail!("response too large");
+            }
+        }
+        anyhow::Ok(body)
+    };
+
+    let body = tokio::time::timeout(TIMEOUT, fetch)
+        .await
+        .context("timed out")??;
+    parse_did(std::str::from_utf8(&body).context("response is not UTF-8")?)
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{body::to_bytes, response::IntoResponse};
+
+    use super::*;
+
+    async fn error_name(e: Error) -> String {
+        let body = to_bytes(e.into_response().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        body["error"].as_str().unwrap().to_string()
+    }
+
+    fn config() -> AppConfig {
+        crate::testing::config(
+            "",
+            serde_json::json!({
+                "host_aliases": ["vanity.example.com"],
+                "handle_domains": ["pds.example.com", "example.com"],
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn well_known_did() {
+        use axum::{http::header, middleware, routing::get, Router};
+
+        use crate::host;
+
+        let config = AppConfig {
+            strict_host: true,
+            ..config()
+        };
+        let db = crate::testing::memory_db().await;
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev)
+                VALUES ('did:plc:alice', 'alice@example.com', '', '', '', '');
+            INSERT INTO handles (did, handle, created_at)
+                VALUES ('did:plc:alice', 'alice.pds.example.com', datetime('now'));
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let app = Router::new()
+            .route("/.well-known/atproto-did", get(well_known))
+            .route("/xrpc/_health", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(
+                config.clone(),
+                host::enforce,
+            ))
+            .layer(middleware::from_fn_with_state(
+                config.clone(),
+                host::route_handles,
+            ))
+            .with_state(db);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        let get = |host: &'static str, path: &'static str| {
+            client
+                .get(format!("http://{addr}{path}"))
+                .header(header::HOST, host)
+                .send()
+        };
+
+        let resp = get("Alice.pds.example.com:443", "/.well-known/atproto-did")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        assert_eq!(resp.text().await.unwrap(), "did:plc:alice");
+
+        for (host, path, status) in [
+            // No account has this handle.
+            (
+                "bob.pds.example.com",
+                "/.well-known/atproto-did",
+                StatusCode::NOT_FOUND,
+            ),
+            // Our own hostnames aren't handles, and handles' hostnames don't serve the API.
+            (
+                "pds.example.com",
+                "/.well-known/atproto-did",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "vanity.example.com",
+                "/.well-known/atproto-did",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "alice.pds.example.com",
+                "/xrpc/_health",
+                StatusCode::NOT_FOUND,
+            ),
+            ("pds.example.com", "/xrpc/_health", StatusCode::OK),
+            // Nor are handles elsewhere served.
+            (
+                "alice.test",
+                "/.well-known/atproto-did",
+                StatusCode::NOT_FOUND,
+            ),
+        ] {
+            let resp = get(host, path).await.unwrap();
+            assert_eq!(resp.status(), status, "{host}{path}");
+        }
+    }
+
+    #[test]
+    fn syntax() {
+        for (handle, normalized) in [
+            ("alice.test", "alice.test"),
+            ("  Alice.Example.COM ", "alice.example.com"),
+            ("a-b.c0.example.com", "a-b.c0.example.com"),
+            ("1.example.com", "1.example.com"),
+            ("xn--bcher-kva.example", "xn--bcher-kva.example"),
+            ("Bücher.example", "xn--bcher-kva.example"),
+            ("日本.example.com", "xn--wgv71a.example.com"),
+        ] {
+            assert_eq!(normalize(handle).unwrap(), normalized, "{handle}");
+        }
+
+        let long = format!("{}.com", ["a".repeat(63).as_str(); 4].join("."));
+        assert_eq!(long.len(), 259);
+        for handle in [
+            "",
+            "alice",
+            "alice.",
+            ".alice.test",
+            "alice..test",
+            "al_ice.test",
+            "al ice.test",
+            "alice@example.com",
+            "-alice.test",
+            "alice-.test",
+            "alice.123",
+            "alice.1test",
+            &format!("{}.test", "a".repeat(64)),
+            &long,
+            // Reserved for encodings, which only punycode is.
+            "ab--cd.test",
+            // Not valid punycode, or not its canonical form.
+            "xn--.test",
+            "xn--a.test",
+            "xn--abc-.test",
+        ] {
+            assert!(normalize(handle).is_err(), "{handle}");
+        }
+    }
+
+    #[tokio::test]
+    async fn policy() {
+        let config = config();
+
+        assert_eq!(
+            validate(&config, "Alice.pds.example.com").unwrap(),
+            ("alice.pds.example.com".to_string(), true)
+        );
+        // Nested handle domains.
+        assert_eq!(
+            validate(&config, "alice.example.com").unwrap(),
+            ("alice.example.com".to_string(), true)
+        );
+        assert_eq!(
+            validate(&config, "alice.test").unwrap(),
+            ("alice.test".to_string(), false)
+        );
+        assert_eq!(
+            validate(&config, "bücher.test").unwrap(),
+            ("xn--bcher-kva.test".to_string(), false)
+        );
+        assert!(validate_local(&config, "alice.test").is_err());
+
+        for (handle, error) in [
+            ("alice", "InvalidHandle"),
+            ("alice.onion", "InvalidHandle"),
+            ("alice.localhost", "InvalidHandle"),
+            ("alice.example", "InvalidHandle"),
+            ("bücher.pds.example.com", "InvalidHandle"),
+            ("admin.pds.example.com", "HandleNotAvailable"),
+            ("Support.example.com", "HandleNotAvailable"),
+            ("pds.example.com", "HandleNotAvailable"),
+            ("vanity.example.com", "HandleNotAvailable"),
+            ("a.b.c.example.com", "UnsupportedDomain"),
+        ] {
+            let e = validate(&config, handle).unwrap_err();
+            assert_eq!(error_name(e).await, error, "{handle}");
+        }
+
+        // The list of reserved handles may be replaced, with labels or full handles.
+        let config = AppConfig {
+            reserved_handles: vec!["alice".to_string(), "Bob.Test".to_string()],
+            ..config
+        };
+        assert!(validate(&config, "admin.pds.example.com").is_ok());
+        for handle in ["alice.pds.example.com", "bob.test"] {
+            let e = validate(&config, handle).unwrap_err();
+            assert_eq!(error_name(e).await, "HandleNotAvailable", "{handle}");
+        }
+        assert!(validate(&config, "alice.test").is_ok());
+    }
+
+    #[tokio::test]
+    async fn cache() {
+        let resolver = HandleResolver::default();
+        let now = Instant::now();
+
+        resolver.insert_at("alice.test", Some("did:plc:alice".to_string()), now);
+        resolver.insert_at("nobody.test", None, now);
+        assert_eq!(
+            resolver.get_at("alice.test", now).unwrap().did.as_deref(),
+            Some("did:plc:alice")
+        );
+        assert!(resolver.get_at("nobody.test", now).unwrap().did.is_none());
+
+        // Failures are retried sooner than successes.
+        let later = now + NEGATIVE_TTL;
+        assert!(resolver.get_at("alice.test", later).is_some());
+        assert!(resolver.get_at("nobody.test", later).is_none());
+        assert!(resolver.get_at("alice.test", now + POSITIVE_TTL).is_none());
+
+        resolver.invalidate("Alice.test");
+        assert!(resolver.get_at("alice.test", now).is_none());
+
+        assert!(parse_did(" did:plc:alice\n").is_ok());
+        assert!(parse_did("<html>").is_err());
+    }
+}
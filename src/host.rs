@@ -0,0 +1,294 @@
+//! Hostnames this PDS is served under.
+//!
+//! A PDS has a primary hostname (`host_name`), and optionally aliases (`host_aliases`) for
+//! operators that terminate several vanity domains onto one instance. URLs in responses are
+//! derived from the hostname a request was made to ([`RequestHost`]), so that clients stay on the
+//! domain they chose. Identifiers that others rely on being stable, such as the service endpoint
+//! in DID documents and the hostname announced to relays, always use the primary hostname.
+//!
+//! Requests for any other hostname are served as if made to the primary, unless `strict_host` is
+//! set, in which case they're rejected (see [`enforce`]).
+//!
+//! The hostnames of handles under our handle domains (e.g. `alice.pds.example.com`) are the
+//! exception: they only serve what resolvers fetch from a handle (see [`route_handles`]), never
+//! the API.
+
+use std::convert::Infallible;
+
+use axum::{
+    extract::{FromRef, FromRequestParts, Request, State},
+    http::{header::HOST, request::Parts, HeaderMap, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::debug;
+
+use crate::{config::AppConfig, error::ErrorMessage};
+
+/// Paths served regardless of the requested hostname (e.g. to load balancer health checks, which
+/// often address the instance directly).
+const ANY_HOST: &[&str] = &["/xrpc/_health"];
+
+/// Paths served under the hostnames of handles, and only there.
+const HANDLE_PATHS: &[&str] = &["/.well-known/atproto-did"];
+
+/// The hostname a request was made to, as given by its `Host` header (or, over HTTP/2, its
+/// authority), normalized to lowercase without a port or trailing dot.
+fn requested_host(uri: &Uri, headers: &HeaderMap) -> Option<String> {
+    let host = headers
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| uri.host())?;
+
+    // Strip the port, taking care not to mangle bracketed IPv6 addresses.
+    let host = match host.rsplit_once(':') {
+        Some((h, port)) if !h.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            if h.starts_with('[') || !h.contains(':') {
+                h
+            } else {
+                host
+            }
+        }
+        _ => host,
+    };
+
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+/// Find the configured hostname matching a requested one.
+fn resolve<'a>(config: &'a AppConfig, host: &str) -> Option<&'a str> {
+    std::iter::once(&config.host_name)
+        .chain(&config.host_aliases)
+        .map(String::as_str)
+        .find(|h| h.eq_ignore_ascii_case(host))
+}
+
+/// Find the handle a requested hostname names: a single label under one of our handle domains,
+/// other than our own hostnames.
+fn resolve_handle(config: &AppConfig, host: &str) -> Option<String> {
+    if resolve(config, host).is_some() {
+        return None;
+    }
+
+    config.handle_domains().iter().find_map(|domain| {
+        host.strip_suffix(domain.as_str())
+            .and_then(|label| label.strip_suffix('.'))
+            .filter(|label| !label.is_empty() && !label.contains('.'))
+            .map(|_| host.to_string())
+    })
+}
+
+/// The handle whose hostname a request was made to (e.g. `alice.pds.example.com`). Only available
+/// to the paths served under handles' hostnames, as an extension set by [`route_handles`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandleHost(pub String);
+
+/// The configured hostname (the primary, or one of its aliases) a request was made to. Requests
+/// for other hostnames resolve to the primary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHost(String);
+
+impl RequestHost {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<S> FromRequestParts<S> for RequestHost
+where
+    S: Send + Sync,
+    AppConfig: FromRef<S>,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let config = AppConfig::from_ref(state);
+        let host = requested_host(&parts.uri, &parts.headers)
+            .and_then(|h| resolve(&config, &h).map(str::to_string))
+            .unwrap_or_else(|| config.host_name.clone());
+
+        Ok(RequestHost(host))
+    }
+}
+
+/// Middleware that routes requests by hostname: those made to a handle's hostname are only served
+/// [`HANDLE_PATHS`], and those paths are only served under a handle's hostname. Anything else is
+/// not found.
+pub async fn route_handles(
+    State(config): State<AppConfig>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let handle = requested_host(request.uri(), request.headers())
+        .and_then(|host| resolve_handle(&config, &host));
+    let handle_path = HANDLE_PATHS.contains(&request.uri().path());
+
+    match handle {
+        Some(handle) if handle_path => {
+            request.extensions_mut().insert(HandleHost(handle));
+            next.run(request).await
+        }
+        None if !handle_path => next.run(request).await,
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Middleware that rejects requests for unknown hostnames, if `strict_host` is set.
+///
+/// Requests without any hostname (e.g. HTTP/1.0 clients) are let through, as they can't have been
+/// meant for another site, as are requests for handles' hostnames (see [`route_handles`]).
+pub async fn enforce(State(config): State<AppConfig>, request: Request, next: Next) -> Response {
+    if !config.strict_host
+        || ANY_HOST.contains(&request.uri().path())
+        || request.extensions().get::<HandleHost>().is_some()
+    {
+        return next.run(request).await;
+    }
+
+    match requested_host(request.uri(), request.headers()) {
+        Some(host) if resolve(&config, &host).is_none() => {
+            debug!("rejecting request for unknown host {host:?}");
+            (
+                StatusCode::MISDIRECTED_REQUEST,
+                Json(ErrorMessage::new(
+                    "InvalidHost",
+                    format!("This PDS is not served under the hostname {host}"),
+                )),
+            )
+                .into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{middleware, routing::get, Router};
+
+    use super::*;
+
+    fn config(strict: bool) -> AppConfig {
+        crate::testing::config(
+            "",
+            serde_json::json!({
+                "host_aliases": ["vanity.example", "pds.other.example"],
+                "strict_host": strict,
+            }),
+        )
+    }
+
+    async fn serve(config: AppConfig) -> std::net::SocketAddr {
+        let app = Router::new()
+            .route(
+                "/xrpc/_url",
+                get(|host: RequestHost| async move { format!("did:web:{}", host.as_str()) }),
+            )
+            .route("/xrpc/_health", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(config.clone(), enforce))
+            .with_state(config);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    #[test]
+    fn hostnames() {
+        let uri = Uri::from_static("/xrpc/_url");
+        for (header, expected) in [
+            ("pds.example.com", "pds.example.com"),
+            ("PDS.Example.com:443", "pds.example.com"),
+            ("pds.example.com.", "pds.example.com"),
+            ("127.0.0.1:8000", "127.0.0.1"),
+            ("[::1]:8000", "[::1]"),
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.insert(HOST, header.parse().unwrap());
+            assert_eq!(
+                requested_host(&uri, &headers).as_deref(),
+                Some(expected),
+                "{header}"
+            );
+        }
+
+        let config = config(false);
+        assert_eq!(resolve(&config, "Vanity.Example"), Some("vanity.example"));
+        assert_eq!(resolve(&config, "elsewhere.example"), None);
+
+        assert_eq!(
+            resolve_handle(&config, "alice.pds.example.com").as_deref(),
+            Some("alice.pds.example.com")
+        );
+        assert_eq!(
+            resolve_handle(&config, "alice.vanity.example").as_deref(),
+            Some("alice.vanity.example")
+        );
+        for host in [
+            "pds.example.com",
+            "vanity.example",
+            "a.b.pds.example.com",
+            "alice.elsewhere.example",
+        ] {
+            assert_eq!(resolve_handle(&config, host), None, "{host}");
+        }
+    }
+
+    #[tokio::test]
+    async fn urls() {
+        let addr = serve(config(false)).await;
+        let client = reqwest::Client::new();
+
+        for (host, expected) in [
+            ("pds.example.com", "pds.example.com"),
+            ("pds.other.example:8443", "pds.other.example"),
+            ("Vanity.example", "vanity.example"),
+            // Unknown hosts fall back to the primary.
+            ("elsewhere.example", "pds.example.com"),
+        ] {
+            let resp = client
+                .get(format!("http://{addr}/xrpc/_url"))
+                .header(HOST, host)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK, "{host}");
+            assert_eq!(
+                resp.text().await.unwrap(),
+                format!("did:web:{expected}"),
+                "{host}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn strict() {
+        let addr = serve(config(true)).await;
+        let client = reqwest::Client::new();
+
+        for (host, path, status) in [
+            ("pds.example.com", "/xrpc/_url", StatusCode::OK),
+            ("vanity.example", "/xrpc/_url", StatusCode::OK),
+            (
+                "elsewhere.example",
+                "/xrpc/_url",
+                StatusCode::MISDIRECTED_REQUEST,
+            ),
+            ("elsewhere.example", "/xrpc/_health", StatusCode::OK),
+        ] {
+            let resp = client
+                .get(format!("http://{addr}{path}"))
+                .header(HOST, host)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), status, "{host}{path}");
+
+            if status != StatusCode::OK {
+                let body: serde_json::Value = resp.json().await.unwrap();
+                assert_eq!(body["error"], "InvalidHost");
+            }
+        }
+    }
+}
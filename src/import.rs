@@ -0,0 +1,493 @@
+//! Validation of repositories imported from elsewhere (e.g. during account migration).
+//!
+//! Imported CAR files can be very large, so validation never holds the file in memory. The CAR is
+//! read sequentially to build an index of block offsets (verifying each block's hash as it goes),
+//! and then the head commit and MST are walked by reading blocks back out of the file on demand.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use atrium_crypto::verify::Verifier;
+use atrium_repo::Cid;
+use ipld_core::ipld::Ipld;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::info;
+
+use crate::cbor::{self, CborError, Limits};
+
+/// SHA2-256 mulithash
+const IPLD_MH_SHA2_256: u64 = 0x12;
+
+/// Log validation progress every this many blocks.
+const PROGRESS_INTERVAL: usize = 10_000;
+
+/// Reasons an imported repository can be rejected.
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("malformed CAR file: {0}")]
+    MalformedCar(String),
+    #[error("block {0} does not match its CID")]
+    HashMismatch(Cid),
+    #[error("CAR file does not have exactly one root")]
+    InvalidRoots,
+    #[error("block {0} is referenced but missing from the CAR file")]
+    MissingBlock(Cid),
+    #[error("malformed commit: {0}")]
+    MalformedCommit(String),
+    #[error("commit is for {0}, not the importing account")]
+    WrongDid(String),
+    #[error("commit signature does not match the account's signing key")]
+    InvalidSignature,
+    #[error("commit revision {0} is not newer than the existing revision {1}")]
+    StaleRevision(String, String),
+    #[error("CAR file contains {0} blocks not reachable from the head commit")]
+    OrphanBlocks(usize),
+    #[error("invalid block: {0}")]
+    Cbor(#[from] CborError),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Options for validating an imported repository.
+pub struct ImportOptions<'a> {
+    /// The DID of the importing account.
+    pub did: &'a str,
+    /// The account's signing key, in `did:key` form.
+    pub signing_key: &'a str,
+    /// The revision of the account's existing repository, if any.
+    pub current_rev: Option<&'a str>,
+    /// The number of unreachable (historical) blocks tolerated in the CAR file.
+    pub orphan_tolerance: usize,
+}
+
+/// A validated repository.
+#[derive(Debug)]
+pub struct ImportedRepo {
+    /// The CID of the head commit.
+    pub root: Cid,
+    /// The revision of the head commit.
+    pub rev: String,
+    /// The total number of blocks in the CAR file.
+    pub blocks: usize,
+    /// The number of records in the repository.
+    pub records: usize,
+}
+
+fn read_varint(r: &mut impl Read) -> Result<Option<u64>, ImportError> {
+    let mut v = 0u64;
+    for i in 0..10 {
+        let mut b = [0u8; 1];
+        if r.read(&mut b)? == 0 {
+            if i == 0 {
+                // Clean EOF.
+                return Ok(None);
+            }
+
+            return Err(ImportError::MalformedCar("truncated varint".to_string()));
+        }
+
+        v |= ((b[0] & 0x7f) as u64) << (i * 7);
+        if b[0] & 0x80 == 0 {
+            return Ok(Some(v));
+        }
+    }
+
+    Err(ImportError::MalformedCar("varint too long".to_string()))
+}
+
+/// Sequentially scan a CAR file, returning its roots and an index of `CID -> (offset, length)`.
+///
+/// Each block's contents are hashed and checked against its CID.
+fn index_car(
+    r: &mut (impl Read + Seek),
+) -> Result<(Vec<Cid>, HashMap<Cid, (u64, usize)>), ImportError> {
+    let hdr_len =
+        read_varint(r)?.ok_or_else(|| ImportError::MalformedCar("missing header".to_string()))?;
+    if hdr_len > Limits::BLOCK.max_size as u64 {
+        return Err(CborError::TooLarge(hdr_len as usize).into());
+    }
+    let mut hdr = vec![0u8; hdr_len as usize];
+    r.read_exact(&mut hdr)?;
+
+    let hdr: Ipld = cbor::decode(&hdr, &Limits::BLOCK, "import")?;
+    let roots = match hdr.get("roots") {
+        Ok(Some(Ipld::List(roots))) => roots
+            .iter()
+            .map(|r| match r {
+                Ipld::Link(cid) => Ok(*cid),
+                _ => Err(ImportError::MalformedCar("invalid root".to_string())),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => return Err(ImportError::MalformedCar("missing roots".to_string())),
+    };
+
+    let mut index = HashMap::new();
+    let mut buf = Vec::new();
+    while let Some(len) = read_varint(r)? {
+        let start = r.stream_position()?;
+        let cid = Cid::read_bytes(&mut *r)
+            .map_err(|e| ImportError::MalformedCar(format!("invalid block cid: {e}")))?;
+        let offset = r.stream_position()?;
+        let data_len = len
+            .checked_sub(offset - start)
+            .ok_or_else(|| ImportError::MalformedCar(format!("block {cid} is truncated")))?
+            as usize;
+        if data_len > Limits::BLOCK.max_size {
+            return Err(CborError::TooLarge(data_len).into());
+        }
+
+        buf.resize(data_len, 0);
+        r.read_exact(&mut buf)?;
+
+        if cid.hash().code() != IPLD_MH_SHA2_256
+            || Sha256::digest(&buf).as_slice() != cid.hash().digest()
+        {
+            return Err(ImportError::HashMismatch(cid));
+        }
+
+        index.insert(cid, (offset, data_len));
+
+        if index.len() % PROGRESS_INTERVAL == 0 {
+            info!("import: indexed {} blocks", index.len());
+        }
+    }
+
+    Ok((roots, index))
+}
+
+fn read_block(
+    r: &mut (impl Read + Seek),
+    index: &HashMap<Cid, (u64, usize)>,
+    cid: &Cid,
+) -> Result<Ipld, ImportError> {
+    let (offset, len) = *index.get(cid).ok_or(ImportError::MissingBlock(*cid))?;
+    if len > Limits::BLOCK.max_size {
+        return Err(CborError::TooLarge(len).into());
+    }
+
+    let mut buf = vec![0u8; len];
+    r.seek(SeekFrom::Start(offset))?;
+    r.read_exact(&mut buf)?;
+
+    Ok(cbor::decode(&buf, &Limits::BLOCK, "import")?)
+}
+
+/// Verify that a commit belongs to `did` and is signed by `signing_key` (in `did:key` form),
+/// returning its MST root and revision.
+pub fn verify_signed_commit(
+    commit: Ipld,
+    did: &str,
+    signing_key: &str,
+) -> Result<(Cid, String), ImportError> {
+    let mut commit = match commit {
+        Ipld::Map(m) => m,
+        _ => return Err(ImportError::MalformedCommit("not a map".to_string())),
+    };
+
+    let sig = match commit.remove("sig") {
+        Some(Ipld::Bytes(sig)) => sig,
+        _ => {
+            return Err(ImportError::MalformedCommit(
+                "missing signature".to_string(),
+            ))
+        }
+    };
+
+    match commit.get("did") {
+        Some(Ipld::String(d)) if d == did => {}
+        Some(Ipld::String(d)) => return Err(ImportError::WrongDid(d.clone())),
+        _ => return Err(ImportError::MalformedCommit("missing did".to_string())),
+    }
+
+    let data = match commit.get("data") {
+        Some(Ipld::Link(data)) => *data,
+        _ => return Err(ImportError::MalformedCommit("missing data".to_string())),
+    };
+
+    let rev = match commit.get("rev") {
+        Some(Ipld::String(rev)) => rev.clone(),
+        _ => return Err(ImportError::MalformedCommit("missing rev".to_string())),
+    };
+
+    // The signature covers the DAG-CBOR encoding of the commit without the `sig` field.
+    let unsigned = serde_ipld_dagcbor::to_vec(&Ipld::Map(commit))
+        .map_err(|e| ImportError::MalformedCommit(e.to_string()))?;
+
+    let (alg, key) = atrium_crypto::did::parse_did_key(signing_key)
+        .map_err(|_| ImportError::InvalidSignature)?;
+    Verifier::default()
+        .verify(alg, &key, &unsigned, &sig)
+        .map_err(|_| ImportError::InvalidSignature)?;
+
+    Ok((data, rev))
+}
+
+/// Verify the head commit of an imported repository, returning its MST root and revision.
+fn verify_commit(commit: Ipld, opts: &ImportOptions) -> Result<(Cid, String), ImportError> {
+    let (data, rev) = verify_signed_commit(commit, opts.did, opts.signing_key)?;
+
+    if let Some(current) = opts.current_rev {
+        // N.B: TIDs sort lexicographically in time order.
+        if rev.as_str() <= current {
+            return Err(ImportError::StaleRevision(rev, current.to_string()));
+        }
+    }
+
+    Ok((data, rev))
+}
+
+/// Walk the MST rooted at `root`, adding every reachable node and record to `reachable`.
+///
+/// Returns the number of records in the tree.
+fn walk_mst(
+    r: &mut (impl Read + Seek),
+    index: &HashMap<Cid, (u64, usize)>,
+    root: Cid,
+    reachable: &mut HashSet<Cid>,
+) -> Result<usize, ImportError> {
+    let mut records = 0;
+    let mut stack = vec![root];
+
+    while let Some(cid) = stack.pop() {
+        if !reachable.insert(cid) {
+            continue;
+        }
+
+        if reachable.len() % PROGRESS_INTERVAL == 0 {
+            info!("import: walked {} blocks", reachable.len());
+        }
+
+        let node = read_block(r, index, &cid)?;
+
+        if let Ok(Some(Ipld::Link(l))) = node.get("l") {
+            stack.push(*l);
+        }
+
+        let entries = match node.get("e") {
+            Ok(Some(Ipld::List(e))) => e,
+            _ => return Err(ImportError::MalformedCar(format!("invalid MST node {cid}"))),
+        };
+
+        for e in entries {
+            match e.get("v") {
+                Ok(Some(Ipld::Link(v))) => {
+                    if !index.contains_key(v) {
+                        return Err(ImportError::MissingBlock(*v));
+                    }
+
+                    records += 1;
+                    reachable.insert(*v);
+                }
+                _ => {
+                    return Err(ImportError::MalformedCar(format!(
+                        "invalid MST entry in {cid}"
+                    )))
+                }
+            }
+
+            if let Ok(Some(Ipld::Link(t))) = e.get("t") {
+                stack.push(*t);
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+/// Count the blocks in a CAR file, verifying each against its CID.
+pub fn count_blocks(r: &mut (impl Read + Seek)) -> Result<usize, ImportError> {
+    index_car(r).map(|(_, index)| index.len())
+}
+
+/// Validate a CAR file containing a repository being imported into an account.
+///
+/// This is a blocking operation and should be run on a blocking thread.
+pub fn validate_car(path: &Path, opts: &ImportOptions) -> Result<ImportedRepo, ImportError> {
+    let mut r = BufReader::new(std::fs::File::open(path)?);
+
+    let (roots, index) = index_car(&mut r)?;
+    let root = match roots.as_slice() {
+        [root] => *root,
+        _ => return Err(ImportError::InvalidRoots),
+    };
+
+    // Verify the head commit before trusting anything else in the file.
+    let commit = read_block(&mut r, &index, &root)?;
+    let (data, rev) = verify_commit(commit, opts)?;
+
+    let mut reachable = HashSet::from([root]);
+    let records = walk_mst(&mut r, &index, data, &mut reachable)?;
+
+    let orphans = index.len() - reachable.len();
+    if orphans > opts.orphan_tolerance {
+        return Err(ImportError::OrphanBlocks(orphans));
+    }
+
+    info!(
+        "import: validated {} blocks ({} records, {} orphans) for {}",
+        index.len(),
+        records,
+        orphans,
+        opts.did
+    );
+
+    Ok(ImportedRepo {
+        root,
+        rev,
+        blocks: index.len(),
+        records,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use atrium_crypto::keypair::{Did as _, Secp256k1Keypair};
+    use atrium_repo::Multihash;
+
+    use super::*;
+
+    /// DAG-CBOR multicodec.
+    const IPLD_DAG_CBOR: u64 = 0x71;
+
+    fn dag_cbor_cid(data: &[u8]) -> Cid {
+        Cid::new_v1(
+            IPLD_DAG_CBOR,
+            Multihash::wrap(IPLD_MH_SHA2_256, Sha256::digest(data).as_slice()).unwrap(),
+        )
+    }
+
+    fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+        while v >= 0x80 {
+            out.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn encode(v: &Ipld) -> (Cid, Vec<u8>) {
+        let data = serde_ipld_dagcbor::to_vec(v).unwrap();
+        (dag_cbor_cid(&data), data)
+    }
+
+    /// Build a CAR containing a signed commit over an MST with a single record, plus `extra` blocks.
+    fn build_car(key: &Secp256k1Keypair, did: &str, extra: &[Vec<u8>]) -> Vec<u8> {
+        let (rcid, record) = encode(&Ipld::Map(BTreeMap::from([(
+            "text".to_string(),
+            Ipld::String("hello".to_string()),
+        )])));
+
+        let (mcid, mst) = encode(&Ipld::Map(BTreeMap::from([
+            ("l".to_string(), Ipld::Null),
+            (
+                "e".to_string(),
+                Ipld::List(vec![Ipld::Map(BTreeMap::from([
+                    ("p".to_string(), Ipld::Integer(0)),
+                    (
+                        "k".to_string(),
+                        Ipld::Bytes(b"app.bsky.feed.post/3jzfcijpj2z2a".to_vec()),
+                    ),
+                    ("v".to_string(), Ipld::Link(rcid)),
+                    ("t".to_string(), Ipld::Null),
+                ]))]),
+            ),
+        ])));
+
+        let mut commit = BTreeMap::from([
+            ("did".to_string(), Ipld::String(did.to_string())),
+            ("version".to_string(), Ipld::Integer(3)),
+            ("data".to_string(), Ipld::Link(mcid)),
+            ("rev".to_string(), Ipld::String("3jzfcijpj2z2a".to_string())),
+            ("prev".to_string(), Ipld::Null),
+        ]);
+        let unsigned = serde_ipld_dagcbor::to_vec(&Ipld::Map(commit.clone())).unwrap();
+        commit.insert("sig".to_string(), Ipld::Bytes(key.sign(&unsigned).unwrap()));
+        let (ccid, commit) = encode(&Ipld::Map(commit));
+
+        let hdr = serde_ipld_dagcbor::to_vec(&Ipld::Map(BTreeMap::from([
+            ("version".to_string(), Ipld::Integer(1)),
+            ("roots".to_string(), Ipld::List(vec![Ipld::Link(ccid)])),
+        ])))
+        .unwrap();
+
+        let mut car = Vec::new();
+        write_varint(&mut car, hdr.len() as u64);
+        car.extend_from_slice(&hdr);
+
+        let mut blocks = vec![(ccid, commit), (mcid, mst), (rcid, record)];
+        blocks.extend(extra.iter().map(|b| (dag_cbor_cid(b), b.clone())));
+
+        for (cid, data) in blocks {
+            let cid = cid.to_bytes();
+            write_varint(&mut car, (cid.len() + data.len()) as u64);
+            car.extend_from_slice(&cid);
+            car.extend_from_slice(&data);
+        }
+
+        car
+    }
+
+    fn validate(
+        car: &[u8],
+        key: &Secp256k1Keypair,
+        tolerance: usize,
+    ) -> Result<ImportedRepo, ImportError> {
+        let path = std::env::temp_dir().join(format!("import-{}.car", uuid::Uuid::new_v4()));
+        std::fs::write(&path, car).unwrap();
+
+        let r = validate_car(
+            &path,
+            &ImportOptions {
+                did: "did:plc:test",
+                signing_key: &key.did(),
+                current_rev: None,
+                orphan_tolerance: tolerance,
+            },
+        );
+
+        std::fs::remove_file(path).unwrap();
+        r
+    }
+
+    #[test]
+    fn valid_import() {
+        let key = Secp256k1Keypair::create(&mut rand::thread_rng());
+        let car = build_car(&key, "did:plc:test", &[]);
+
+        let r = validate(&car, &key, 0).unwrap();
+        assert_eq!(r.blocks, 3);
+        assert_eq!(r.records, 1);
+    }
+
+    #[test]
+    fn bad_signature() {
+        let key = Secp256k1Keypair::create(&mut rand::thread_rng());
+        let other = Secp256k1Keypair::create(&mut rand::thread_rng());
+        let car = build_car(&other, "did:plc:test", &[]);
+
+        assert!(matches!(
+            validate(&car, &key, 0),
+            Err(ImportError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn orphan_blocks() {
+        let key = Secp256k1Keypair::create(&mut rand::thread_rng());
+        let orphan = serde_ipld_dagcbor::to_vec(&Ipld::String("orphan".to_string())).unwrap();
+        let car = build_car(&key, "did:plc:test", &[orphan]);
+
+        assert!(matches!(
+            validate(&car, &key, 0),
+            Err(ImportError::OrphanBlocks(1))
+        ));
+
+        // Historical blocks are accepted within the configured tolerance.
+        assert!(validate(&car, &key, 1).is_ok());
+    }
+}
@@ -0,0 +1,539 @@
+//! Verification of repository heads against the blockstore.
+//!
+//! A repository's head pointer (in the database) and its blocks (in its CAR file) are written
+//! separately, and may disagree after a partial write or a restore from backup. The first time a
+//! repository is opened after startup (or after an admin asks for it to be re-checked), its head
+//! commit is verified: the commit block must exist and match its CID, decode, be signed by the
+//! account's key, and point to an MST root that is present.
+//!
+//! Heads that pass are remembered as known-good. If a head fails, the failure is logged and
+//! recorded for admins, and the repository falls back to its last known-good head.
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context};
+use atrium_api::types::string::Did;
+use atrium_crypto::keypair::Did as _;
+use atrium_repo::{
+    blockstore::{AsyncBlockStoreRead, AsyncBlockStoreWrite},
+    Cid, Repository,
+};
+use axum::http::StatusCode;
+use ipld_core::ipld::Ipld;
+use metrics::counter;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::{
+    cbor::{self, Limits},
+    config::RepoConfig,
+    error::ErrorMessage,
+    events::{self, EventBus},
+    import::{self, ImportError},
+    keys,
+    metrics::REPO_INTEGRITY_FAILURES,
+    storage,
+    tiering::Tiering,
+    Client, Db, Error, Result, SigningKey,
+};
+
+/// Reasons a repository head can fail verification.
+#[derive(Error, Debug)]
+pub enum IntegrityError {
+    #[error("block {0} is missing from the blockstore: {1}")]
+    MissingBlock(Cid, String),
+    #[error("block {0} does not match its CID")]
+    HashMismatch(Cid),
+    #[error("invalid head commit: {0}")]
+    Commit(#[from] ImportError),
+    #[error("failed to open blockstore: {0}")]
+    Storage(String),
+}
+
+/// The integrity state of a repository, as recorded for admins.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityStatus {
+    pub did: String,
+    /// The last head that passed verification.
+    pub good_root: Option<String>,
+    pub good_rev: Option<String>,
+    pub checked_at: Option<String>,
+    /// The most recent head that failed verification, and why.
+    pub failed_root: Option<String>,
+    pub failure: Option<String>,
+    pub failed_at: Option<String>,
+}
+
+/// Verifies repository heads on first access, caching the result.
+#[derive(Clone)]
+pub struct RepoIntegrity {
+    config: RepoConfig,
+    db: Db,
+    client: Client,
+    skey: SigningKey,
+    /// If set, repositories are moved back from the archive before they're opened.
+    tiering: Option<Tiering>,
+    /// If set, repositories rolled back to a known-good head are announced for resynchronization.
+    events: Option<EventBus>,
+    /// The head of each repository verified since startup.
+    verified: Arc<Mutex<HashMap<String, Cid>>>,
+}
+
+impl RepoIntegrity {
+    pub fn new(config: RepoConfig, db: Db, client: Client, skey: SigningKey) -> Self {
+        Self {
+            config,
+            db,
+            client,
+            skey,
+            tiering: None,
+            events: None,
+            verified: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Require repositories to be hot (see [`crate::tiering`]) before they're opened.
+    pub fn with_tiering(mut self, tiering: Tiering) -> Self {
+        self.tiering = Some(tiering);
+        self
+    }
+
+    /// Publish a `Sync` event when a repository is rolled back, so that consumers resynchronize it.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Forget that a repository's head (or if `did` is unset, every repository's head) was
+    /// verified, so that it is verified again on next access.
+    pub fn invalidate(&self, did: Option<&str>) {
+        let mut verified = self.verified.lock().unwrap();
+        match did {
+            Some(did) => {
+                verified.remove(did);
+            }
+            None => verified.clear(),
+        }
+    }
+
+    /// Open a repository at its head, verifying the head first if it hasn't been already.
+    pub async fn open(
+        &self,
+        did: impl Into<String>,
+    ) -> Result<Repository<impl AsyncBlockStoreRead + AsyncBlockStoreWrite>> {
+        let did = did.into();
+        let root = self.head(&did).await?;
+
+        Ok(storage::open_repo(&self.config, did, root)
+            .await
+            .context("failed to open repo")?)
+    }
+
+    /// Return the head of a repository, verifying it if it hasn't been already.
+    pub async fn head(&self, did: &str) -> Result<Cid> {
+        if let Some(tiering) = &self.tiering {
+            tiering.ensure_hot(did).await?;
+        }
+
+        let root: String = sqlx::query_scalar(r#"SELECT root FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_one(&self.db)
+            .await
+            .context("failed to query database")?;
+        let root = Cid::from_str(&root).context("invalid repository root")?;
+
+        if self.verified.lock().unwrap().get(did) == Some(&root) {
+            return Ok(root);
+        }
+
+        // The key this PDS signs the account's commits with.
+        let key = keys::account_key(&self.db, &self.skey, did)
+            .await?
+            .did()
+            .to_string();
+
+        let e = match self.verify(did, root, &key).await {
+            Ok(rev) => {
+                self.record_good(did, root, &rev).await?;
+                return Ok(root);
+            }
+            Err(e) => e,
+        };
+
+        error!("repository {did} failed an integrity check at head {root}: {e}");
+        counter!(REPO_INTEGRITY_FAILURES).increment(1);
+
+        sqlx::query(
+            r#"
+            INSERT INTO repo_integrity (did, failed_root, failure, failed_at)
+                VALUES (?, ?, ?, datetime('now'))
+                ON CONFLICT (did) DO UPDATE SET
+                    failed_root = excluded.failed_root,
+                    failure = excluded.failure,
+                    failed_at = excluded.failed_at
+            "#,
+        )
+        .bind(did)
+        .bind(root.to_string())
+        .bind(e.to_string())
+        .execute(&self.db)
+        .await
+        .context("failed to record integrity failure")?;
+
+        let fail = || {
+            Error::with_message(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                anyhow!("repository {did} is corrupt at head {root}: {e}"),
+                ErrorMessage::new(
+                    "InternalServerError",
+                    "repository failed an integrity check",
+                ),
+            )
+        };
+
+        // Fall back to the last known-good head, so long as it still passes itself.
+        let good: Option<String> =
+            sqlx::query_scalar(r#"SELECT good_root FROM repo_integrity WHERE did = ?"#)
+                .bind(did)
+                .fetch_one(&self.db)
+                .await
+                .context("failed to query known-good head")?;
+        let Some(good) = good.and_then(|g| Cid::from_str(&g).ok()) else {
+            error!("repository {did} has no known-good head to fall back to");
+            return Err(fail());
+        };
+        if good == root {
+            return Err(fail());
+        }
+
+        let rev = match self.verify(did, good, &key).await {
+            Ok(rev) => rev,
+            Err(e2) => {
+                error!("known-good head {good} of repository {did} also fails: {e2}");
+                return Err(fail());
+            }
+        };
+
+        error!("rolling repository {did} back from {root} to known-good head {good} ({rev})");
+        sqlx::query(r#"UPDATE accounts SET root = ?, rev = ? WHERE did = ? AND root = ?"#)
+            .bind(good.to_string())
+            .bind(&rev)
+            .bind(did)
+            .bind(root.to_string())
+            .execute(&self.db)
+            .await
+            .context("failed to roll back repository head")?;
+
+        self.record_good(did, good, &rev).await?;
+        if let Some(bus) = &self.events {
+            events::publish_sync(bus, &self.config, did, good, &rev).await;
+        }
+
+        Ok(good)
+    }
+
+    /// Remember a head as known-good.
+    async fn record_good(&self, did: &str, root: Cid, rev: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO repo_integrity (did, good_root, good_rev, checked_at)
+                VALUES (?, ?, ?, datetime('now'))
+                ON CONFLICT (did) DO UPDATE SET
+                    good_root = excluded.good_root,
+                    good_rev = excluded.good_rev,
+                    checked_at = excluded.checked_at
+            "#,
+        )
+        .bind(did)
+        .bind(root.to_string())
+        .bind(rev)
+        .execute(&self.db)
+        .await
+        .context("failed to record known-good head")?;
+
+        self.verified.lock().unwrap().insert(did.to_string(), root);
+        Ok(())
+    }
+
+    /// Verify a head commit against the blockstore, returning its revision. `key` is the key the
+    /// account's commits are signed with by this PDS.
+    async fn verify(
+        &self,
+        did: &str,
+        root: Cid,
+        key: &str,
+    ) -> std::result::Result<String, IntegrityError> {
+        let mut store = storage::open_store(&self.config, did)
+            .await
+            .map_err(|e| IntegrityError::Storage(format!("{e:?}")))?;
+
+        let commit: Ipld = read_verified(&mut store, root).await?;
+
+        // Commits are normally signed by this PDS (with the account's own key, if it has one), but
+        // an imported repository may still be at a head signed by the account's previous key, as
+        // advertised in its DID document. Only then is the document fetched.
+        let (data, rev) = match import::verify_signed_commit(commit.clone(), did, key) {
+            Err(ImportError::InvalidSignature) => {
+                let key = self
+                    .document_key(did)
+                    .await
+                    .ok_or(IntegrityError::Commit(ImportError::InvalidSignature))?;
+                import::verify_signed_commit(commit, did, &key)?
+            }
+            r => r?,
+        };
+
+        let _mst: Ipld = read_verified(&mut store, data).await?;
+
+        info!("verified head {root} ({rev}) of repository {did}");
+        Ok(rev)
+    }
+
+    /// Fetch the signing key advertised in an account's DID document.
+    async fn document_key(&self, did: &str) -> Option<String> {
+        let doc = crate::did::resolve(&self.client, Did::new(did.to_string()).ok()?)
+            .await
+            .ok()?;
+
+        doc.verification_method
+            .iter()
+            .find(|m| m.id.ends_with("#atproto"))
+            .map(|m| format!("did:key:{}", m.public_key_multibase))
+    }
+}
+
+/// Read a block, check it against its CID, and decode it.
+async fn read_verified(
+    store: &mut impl AsyncBlockStoreRead,
+    cid: Cid,
+) -> std::result::Result<Ipld, IntegrityError> {
+    let bytes = store
+        .read_block(cid)
+        .await
+        .map_err(|e| IntegrityError::MissingBlock(cid, e.to_string()))?;
+
+    if Sha256::digest(&bytes).as_slice() != cid.hash().digest() {
+        return Err(IntegrityError::HashMismatch(cid));
+    }
+
+    cbor::decode(&bytes, &Limits::BLOCK, "integrity")
+        .map_err(|e| IntegrityError::Commit(ImportError::Cbor(e)))
+}
+
+/// Fetch the integrity state of a repository.
+pub async fn status(db: &Db, did: &str) -> Result<Option<IntegrityStatus>> {
+    Ok(
+        sqlx::query_as(r#"SELECT * FROM repo_integrity WHERE did = ?"#)
+            .bind(did)
+            .fetch_optional(db)
+            .await
+            .context("failed to query repository integrity")?,
+    )
+}
+
+/// List repositories whose head has failed verification, most recent first.
+pub async fn failures(db: &Db) -> Result<Vec<IntegrityStatus>> {
+    Ok(sqlx::query_as(
+        r#"SELECT * FROM repo_integrity WHERE failed_root IS NOT NULL ORDER BY failed_at DESC"#,
+    )
+    .fetch_all(db)
+    .await
+    .context("failed to query repository integrity")?)
+}
+
+#[cfg(test)]
+mod test {
+    use atrium_crypto::keypair::Secp256k1Keypair;
+    use atrium_repo::blockstore::{CarStore, DAG_CBOR, SHA2_256};
+
+    use super::*;
+
+    /// Create a repository with an initial commit, returning its root and revision.
+    async fn create_repo(config: &RepoConfig, skey: &SigningKey, did: &str) -> (Cid, String) {
+        let file = tokio::fs::File::create(storage::repo_path(config, did).unwrap())
+            .await
+            .unwrap();
+        let mut store = CarStore::create(file).await.unwrap();
+
+        let builder = Repository::create(&mut store, Did::new(did.to_string()).unwrap())
+            .await
+            .unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        let repo = builder.finalize(sig).await.unwrap();
+
+        (repo.root(), repo.commit().rev().to_string())
+    }
+
+    #[tokio::test]
+    async fn fallback() {
+        let dir = crate::testing::temp_dir("integrity");
+        let config: RepoConfig =
+            serde_json::from_value(serde_json::json!({ "path": dir })).unwrap();
+
+        let db = crate::testing::memory_db().await;
+
+        let mut bus = EventBus::new();
+        let (tx, mut synced) = tokio::sync::mpsc::unbounded_channel();
+        bus.subscribe("probe", 1, move |event| {
+            let tx = tx.clone();
+            async move {
+                let _ = tx.send(event);
+            }
+        });
+
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let integrity = RepoIntegrity::new(
+            config.clone(),
+            db.clone(),
+            reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build(),
+            skey.clone(),
+        )
+        .with_events(bus);
+
+        let did = "did:plc:alice";
+        let (root, rev) = create_repo(&config, &skey, did).await;
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', ?, '', ?)"#,
+        )
+        .bind(did)
+        .bind(root.to_string())
+        .bind(&rev)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        // The intact head verifies, and becomes known-good.
+        assert_eq!(integrity.head(did).await.unwrap(), root);
+        let s = status(&db, did).await.unwrap().unwrap();
+        assert_eq!(s.good_root, Some(root.to_string()));
+        assert_eq!(s.failed_root, None);
+
+        // Point the head at a block that was never written (e.g. a lost write).
+        let bogus = {
+            let mut mem = Vec::new();
+            let mut store = CarStore::create(std::io::Cursor::new(&mut mem))
+                .await
+                .unwrap();
+            store
+                .write_block(DAG_CBOR, SHA2_256, &serde_ipld_dagcbor::to_vec(&1).unwrap())
+                .await
+                .unwrap()
+        };
+        sqlx::query(r#"UPDATE accounts SET root = ?, rev = 'bogus' WHERE did = ?"#)
+            .bind(bogus.to_string())
+            .bind(did)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        // The verification is cached, until invalidated.
+        integrity
+            .verified
+            .lock()
+            .unwrap()
+            .insert(did.to_string(), bogus);
+        assert_eq!(integrity.head(did).await.unwrap(), bogus);
+        integrity.invalidate(Some(did));
+
+        // The head falls back to the known-good head, and the failure is surfaced to admins.
+        assert_eq!(integrity.head(did).await.unwrap(), root);
+        let (head, head_rev): (String, String) =
+            sqlx::query_as(r#"SELECT root, rev FROM accounts WHERE did = ?"#)
+                .bind(did)
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert_eq!((head, head_rev), (root.to_string(), rev.clone()));
+
+        // Consumers are told to resynchronize from the known-good head.
+        let events::Event::Sync(sync) = synced.recv().await.unwrap() else {
+            panic!("expected a sync event");
+        };
+        assert_eq!((sync.did.as_str(), &sync.rev), (did, &rev));
+        let mut car = CarStore::open(std::io::Cursor::new(sync.blocks.clone()))
+            .await
+            .unwrap();
+        assert_eq!(car.roots().collect::<Vec<_>>(), [root]);
+        car.read_block(root).await.unwrap();
+
+        let failed = failures(&db).await.unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].failed_root, Some(bogus.to_string()));
+        assert!(failed[0]
+            .failure
+            .as_deref()
+            .unwrap()
+            .contains("missing from the blockstore"));
+
+        // Without a known-good head, the corruption is an error.
+        let did = "did:plc:bob";
+        create_repo(&config, &skey, did).await;
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, 'bob', '', ?, '', '')"#,
+        )
+        .bind(did)
+        .bind(bogus.to_string())
+        .execute(&db)
+        .await
+        .unwrap();
+        assert!(integrity.head(did).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[tokio::test]
+    async fn account_key() {
+        use atrium_crypto::keypair::Export;
+
+        let dir = crate::testing::temp_dir("integrity");
+        let config: RepoConfig =
+            serde_json::from_value(serde_json::json!({ "path": dir })).unwrap();
+
+        let db = crate::testing::memory_db().await;
+
+        // The client can't reach a PLC directory, so the DID document is never an option.
+        let client = reqwest_middleware::ClientBuilder::new(
+            reqwest::Client::builder()
+                .proxy(reqwest::Proxy::all("http://127.0.0.1:9").unwrap())
+                .build()
+                .unwrap(),
+        )
+        .build();
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let integrity = RepoIntegrity::new(config.clone(), db.clone(), client, skey);
+
+        // An account with a key of its own, which its commits are signed with.
+        let did = "did:plc:alice";
+        let key = Secp256k1Keypair::create(&mut rand::thread_rng());
+        sqlx::query(r#"INSERT INTO account_keys (did, secret) VALUES (?, ?)"#)
+            .bind(did)
+            .bind(key.export())
+            .execute(&db)
+            .await
+            .unwrap();
+        let (root, rev) = create_repo(&config, &SigningKey(Arc::new(key)), did).await;
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', ?, '', ?)"#,
+        )
+        .bind(did)
+        .bind(root.to_string())
+        .bind(&rev)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        // Its head verifies against its own key, without the DID document.
+        assert_eq!(integrity.head(did).await.unwrap(), root);
+        let s = status(&db, did).await.unwrap().unwrap();
+        assert_eq!(s.good_root, Some(root.to_string()));
+        assert_eq!(s.failed_root, None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+//! Invite codes, which are required to sign up if `invites.required` is set.
+//!
+//! Codes are created by the administrator, optionally for a specific account, or earned by
+//! accounts over time (see [`create_available`]). Each code may be used a set number of times;
+//! `invites.count` is the number of uses remaining, and every use is recorded in `invite_uses`.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+use atrium_api::{
+    com::atproto::server::defs::{InviteCode, InviteCodeData, InviteCodeUseData},
+    types::string::{Datetime, Did},
+};
+use axum::http::StatusCode;
+use sqlx::SqliteConnection;
+use uuid::Uuid;
+
+use crate::{config::InviteConfig, error::ErrorMessage, Db, Error, Result};
+
+/// The creator (and account) reported for codes created by the administrator.
+pub const ADMIN: &str = "admin";
+
+/// The maximum number of uses of any code.
+pub const MAX_USES: i64 = 100;
+
+/// Create `n` codes, each usable `uses` times. Codes for a specific account are listed by its
+/// `getAccountInviteCodes`.
+pub async fn create(
+    db: &Db,
+    for_account: Option<&str>,
+    created_by: &str,
+    uses: i64,
+    n: usize,
+) -> Result<Vec<String>> {
+    if !(1..=MAX_USES).contains(&uses) {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("invalid invite code use count {uses}"),
+            ErrorMessage::new(
+                "InvalidRequest",
+                format!("useCount must be between 1 and {MAX_USES}"),
+            ),
+        ));
+    }
+
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+    let mut codes = Vec::with_capacity(n);
+    for _ in 0..n {
+        let code = Uuid::new_v4().to_string();
+        sqlx::query(
+            r#"
+            INSERT INTO invites (id, did, count, available, created_by, created_at)
+                VALUES (?, ?, ?, ?, ?, datetime('now'))
+            "#,
+        )
+        .bind(&code)
+        .bind(for_account)
+        .bind(uses)
+        .bind(uses)
+        .bind(created_by)
+        .execute(&mut *tx)
+        .await
+        .context("failed to create invite code")?;
+        codes.push(code);
+    }
+    tx.commit().await.context("failed to commit transaction")?;
+
+    Ok(codes)
+}
+
+/// Consume a use of a code on behalf of a new account. Must be called in the transaction that
+/// creates the account, so that a use is only consumed if the signup goes through.
+pub async fn redeem(conn: &mut SqliteConnection, code: &str, did: &str) -> Result<()> {
+    // A single statement, so that concurrent signups can't both take the last use.
+    let redeemed: Option<String> = sqlx::query_scalar(
+        r#"
+        UPDATE invites SET count = count - 1
+            WHERE id = ? AND count > 0 AND NOT disabled
+            RETURNING id
+        "#,
+    )
+    .bind(code)
+    .fetch_optional(&mut *conn)
+    .await
+    .context("failed to check invite code")?;
+
+    if redeemed.is_none() {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("invalid invite code {code}"),
+            ErrorMessage::new("InvalidInviteCode", "invite code is invalid or used up"),
+        ));
+    }
+
+    sqlx::query(r#"INSERT INTO invite_uses (code, used_by) VALUES (?, ?)"#)
+        .bind(code)
+        .bind(did)
+        .execute(&mut *conn)
+        .await
+        .context("failed to record invite code use")?;
+
+    Ok(())
+}
+
+/// Create the codes an account has earned but not yet created: one per `interval` since it was
+/// created. Returns the number created.
+pub async fn create_available(config: &InviteConfig, db: &Db, did: &str) -> anyhow::Result<u64> {
+    let Some(interval) = config.interval.filter(|i| *i > 0) else {
+        return Ok(0);
+    };
+
+    // One at a time, each conditional on the codes created so far, so that concurrent requests
+    // can't create more codes than were earned.
+    let mut created = 0;
+    loop {
+        let n = sqlx::query(
+            r#"
+            INSERT INTO invites (id, did, count, available, created_by, created_at)
+                SELECT ?, did, 1, 1, did, datetime('now') FROM accounts
+                    WHERE did = ?
+                    AND (SELECT COUNT(*) FROM invites WHERE created_by = accounts.did)
+                        < CAST((julianday('now') - julianday(created_at)) * 86400 / ? AS INTEGER)
+            "#,
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(did)
+        .bind(interval as i64)
+        .execute(db)
+        .await
+        .context("failed to create invite code")?
+        .rows_affected();
+
+        if n == 0 {
+            return Ok(created);
+        }
+        created += n;
+    }
+}
+
+/// Disable codes, by code or by the account they're for. Returns the number disabled.
+pub async fn disable(db: &Db, codes: &[String], accounts: &[String]) -> anyhow::Result<u64> {
+    let codes = serde_json::to_string(codes).context("failed to encode codes")?;
+    let accounts = serde_json::to_string(accounts).context("failed to encode accounts")?;
+
+    let n = sqlx::query(
+        r#"
+        UPDATE invites SET disabled = TRUE
+            WHERE NOT disabled
+            AND (id IN (SELECT value FROM json_each(?)) OR did IN (SELECT value FROM json_each(?)))
+        "#,
+    )
+    .bind(codes)
+    .bind(accounts)
+    .execute(db)
+    .await
+    .context("failed to disable invite codes")?
+    .rows_affected();
+
+    Ok(n)
+}
+
+/// List the codes for an account, optionally including those used up.
+pub async fn list(db: &Db, did: &str, include_used: bool) -> anyhow::Result<Vec<InviteCode>> {
+    let codes: Vec<(String, i64, bool, Option<String>, String, String)> = sqlx::query_as(
+        r#"
+        SELECT id, available, disabled, did, created_by,
+            strftime('%Y-%m-%dT%H:%M:%fZ', created_at)
+            FROM invites
+            WHERE did = ? AND (? OR count > 0)
+            ORDER BY created_at, id
+        "#,
+    )
+    .bind(did)
+    .bind(include_used)
+    .fetch_all(db)
+    .await
+    .context("failed to list invite codes")?;
+
+    let mut r = Vec::with_capacity(codes.len());
+    for (code, available, disabled, for_account, created_by, created_at) in codes {
+        let uses: Vec<(String, String)> = sqlx::query_as(
+            r#"
+            SELECT used_by, strftime('%Y-%m-%dT%H:%M:%fZ', used_at)
+                FROM invite_uses WHERE code = ? ORDER BY used_at
+            "#,
+        )
+        .bind(&code)
+        .fetch_all(db)
+        .await
+        .context("failed to list invite code uses")?;
+
+        let uses = uses
+            .into_iter()
+            .map(|(used_by, used_at)| {
+                Ok(InviteCodeUseData {
+                    used_at: Datetime::from_str(&used_at)
+                        .map_err(|e| anyhow!("invalid use time {used_at:?}: {e}"))?,
+                    used_by: Did::new(used_by).map_err(|e| anyhow!("invalid DID: {e}"))?,
+                }
+                .into())
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        r.push(
+            InviteCodeData {
+                available,
+                code,
+                created_at: Datetime::from_str(&created_at)
+                    .map_err(|e| anyhow!("invalid creation time {created_at:?}: {e}"))?,
+                created_by,
+                disabled,
+                for_account: for_account.unwrap_or_else(|| ADMIN.to_string()),
+                uses,
+            }
+            .into(),
+        );
+    }
+
+    Ok(r)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn codes() {
+        let db = crate::testing::memory_db().await;
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev, created_at) VALUES
+                ('did:plc:alice', '', '', '', '', '', datetime('now', '-10 days'))
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        assert!(create(&db, None, ADMIN, 0, 1).await.is_err());
+        let codes = create(&db, Some("did:plc:alice"), ADMIN, 2, 2)
+            .await
+            .unwrap();
+        assert_eq!(codes.len(), 2);
+
+        // The last use can only be taken once.
+        let mut conn = db.acquire().await.unwrap();
+        redeem(&mut conn, &codes[0], "did:plc:bob").await.unwrap();
+        redeem(&mut conn, &codes[0], "did:plc:carol").await.unwrap();
+        assert!(redeem(&mut conn, &codes[0], "did:plc:dave").await.is_err());
+        drop(conn);
+
+        let listed = list(&db, "did:plc:alice", true).await.unwrap();
+        assert_eq!(listed.len(), 2);
+        let used = listed.iter().find(|c| c.code == codes[0]).unwrap();
+        assert_eq!(used.available, 2);
+        assert_eq!(used.uses.len(), 2);
+        assert_eq!(used.for_account, "did:plc:alice");
+        assert_eq!(list(&db, "did:plc:alice", false).await.unwrap().len(), 1);
+
+        // Disabled codes can't be used.
+        assert_eq!(disable(&db, &[codes[1].clone()], &[]).await.unwrap(), 1);
+        let mut conn = db.acquire().await.unwrap();
+        assert!(redeem(&mut conn, &codes[1], "did:plc:dave").await.is_err());
+        drop(conn);
+
+        // A code every 3 days, for an account created 10 days ago.
+        let config = InviteConfig {
+            required: true,
+            interval: Some(3 * 24 * 60 * 60),
+        };
+        assert_eq!(
+            create_available(&config, &db, "did:plc:alice")
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            create_available(&config, &db, "did:plc:alice")
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(list(&db, "did:plc:alice", true).await.unwrap().len(), 5);
+    }
+}
@@ -0,0 +1,149 @@
+//! Persistent state of background jobs, and recovery of jobs interrupted by a crash.
+//!
+//! Jobs that leave partial state behind if interrupted (e.g. a half-written file) record each
+//! state transition in the `jobs` table: `pending`, then `running`, then `done` or `failed`.
+//!
+//! On startup, [`recover`] finds jobs that never finished. A job still `running` is handed to the
+//! recovery handler of its [`JobKind`], which either completes it or rolls back its partial
+//! effects. A job still `pending` was never started, so it is simply failed.
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{compact, config::AppConfig, deletion, Db};
+
+/// A background job, as recorded in the database.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: i64,
+    pub kind: String,
+    /// What the job operates on (e.g. a DID).
+    pub subject: String,
+    pub state: String,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+}
+
+/// The outcome of recovering an interrupted job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// The job was completed (or had no effects to undo).
+    Completed,
+    /// The job's partial effects were undone.
+    RolledBack,
+}
+
+/// Recover a job that was interrupted while running.
+///
+/// Handlers must be idempotent, as recovery itself may be interrupted.
+pub type RecoverFn = fn(AppConfig, Db, Job) -> BoxFuture<'static, Result<Recovery>>;
+
+/// A kind of background job.
+#[derive(Debug, Clone, Copy)]
+pub struct JobKind {
+    pub name: &'static str,
+    pub recover: RecoverFn,
+}
+
+/// All kinds of jobs that are tracked (and recovered).
+pub const KINDS: &[JobKind] = &[compact::JOB, deletion::JOB];
+
+/// Create a pending job, unless an unfinished job of the same kind already exists for the subject.
+pub async fn create(db: &Db, kind: &JobKind, subject: &str) -> Result<Option<i64>> {
+    sqlx::query_scalar(
+        r#"INSERT INTO jobs (kind, subject) VALUES (?, ?) ON CONFLICT DO NOTHING RETURNING id"#,
+    )
+    .bind(kind.name)
+    .bind(subject)
+    .fetch_optional(db)
+    .await
+    .context("failed to create job")
+}
+
+/// Mark a job as running.
+pub async fn start(db: &Db, id: i64) -> Result<()> {
+    sqlx::query(r#"UPDATE jobs SET state = 'running', started_at = datetime('now') WHERE id = ?"#)
+        .bind(id)
+        .execute(db)
+        .await
+        .context("failed to start job")?;
+
+    Ok(())
+}
+
+/// Mark a job as finished, successfully or with an error.
+pub async fn finish(db: &Db, id: i64, error: Option<&str>) -> Result<()> {
+    let state = if error.is_some() { "failed" } else { "done" };
+    sqlx::query(
+        r#"UPDATE jobs SET state = ?, error = ?, finished_at = datetime('now') WHERE id = ?"#,
+    )
+    .bind(state)
+    .bind(error)
+    .bind(id)
+    .execute(db)
+    .await
+    .context("failed to finish job")?;
+
+    Ok(())
+}
+
+/// List the most recent jobs, optionally only those in a given state.
+pub async fn list(db: &Db, state: Option<&str>, limit: u32) -> Result<Vec<Job>> {
+    sqlx::query_as(r#"SELECT * FROM jobs WHERE (? IS NULL OR state = ?) ORDER BY id DESC LIMIT ?"#)
+        .bind(state)
+        .bind(state)
+        .bind(limit)
+        .fetch_all(db)
+        .await
+        .context("failed to list jobs")
+}
+
+/// Recover all jobs that were interrupted (e.g. by a crash), returning the number recovered.
+pub async fn recover(config: &AppConfig, db: &Db) -> Result<usize> {
+    let jobs: Vec<Job> =
+        sqlx::query_as(r#"SELECT * FROM jobs WHERE state IN ('pending', 'running') ORDER BY id"#)
+            .fetch_all(db)
+            .await
+            .context("failed to find interrupted jobs")?;
+
+    for job in &jobs {
+        let name = format!("{} job {} ({})", job.kind, job.id, job.subject);
+        let kind = KINDS.iter().find(|k| k.name == job.kind);
+
+        let error = match (job.state.as_str(), kind) {
+            ("pending", _) => {
+                info!("{name} was never started");
+                Some("interrupted before starting".to_string())
+            }
+            (_, None) => {
+                warn!("{name} is of an unknown kind, and can't be recovered");
+                Some("interrupted, and of an unknown kind".to_string())
+            }
+            (_, Some(kind)) => {
+                match (kind.recover)(config.clone(), db.clone(), job.clone()).await {
+                    Ok(Recovery::Completed) => {
+                        info!("{name} was interrupted, and has been completed");
+                        None
+                    }
+                    Ok(Recovery::RolledBack) => {
+                        info!("{name} was interrupted, and has been rolled back");
+                        Some("interrupted, and rolled back".to_string())
+                    }
+                    Err(e) => {
+                        warn!("{name} was interrupted, and failed to recover: {e:?}");
+                        Some(format!("interrupted, and failed to recover: {e:#}"))
+                    }
+                }
+            }
+        };
+
+        finish(db, job.id, error.as_deref()).await?;
+    }
+
+    Ok(jobs.len())
+}
@@ -0,0 +1,157 @@
+//! Per-account signing keys.
+//!
+//! Accounts created here sign with the PDS's own key. An account migrating here may instead bring
+//! its DID document up to date with a key generated for it ahead of time by `reserveSigningKey`;
+//! the reservation is claimed when the account is created, and the key is used for the account
+//! from then on. Reservations that go unclaimed expire after [`RESERVATION_TTL`].
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use atrium_crypto::keypair::{Did as _, Export, Secp256k1Keypair};
+use sqlx::SqliteConnection;
+
+use crate::{Db, SigningKey};
+
+/// How long a reserved key remains claimable, in seconds.
+pub const RESERVATION_TTL: u64 = 24 * 60 * 60;
+
+/// Generate and reserve a signing key, returning its public key in `did:key` form. A key reserved
+/// for a DID replaces any earlier reservation for it.
+pub async fn reserve(db: &Db, did: Option<&str>) -> anyhow::Result<String> {
+    let key = Secp256k1Keypair::create(&mut rand::thread_rng());
+    let public = key.did();
+
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+    if let Some(did) = did {
+        sqlx::query(r#"DELETE FROM reserved_keys WHERE did = ?"#)
+            .bind(did)
+            .execute(&mut *tx)
+            .await
+            .context("failed to replace reserved key")?;
+    }
+    sqlx::query(r#"INSERT INTO reserved_keys (key, did, secret) VALUES (?, ?, ?)"#)
+        .bind(&public)
+        .bind(did)
+        .bind(key.export())
+        .execute(&mut *tx)
+        .await
+        .context("failed to reserve key")?;
+    tx.commit().await.context("failed to commit transaction")?;
+
+    Ok(public)
+}
+
+/// Whether any unexpired reservation could be claimed by `did`.
+pub async fn has_reservation(db: &Db, did: &str) -> anyhow::Result<bool> {
+    sqlx::query_scalar(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM reserved_keys
+                WHERE (did = ? OR did IS NULL) AND created_at > datetime('now', ?)
+        )
+        "#,
+    )
+    .bind(did)
+    .bind(format!("-{RESERVATION_TTL} seconds"))
+    .fetch_one(db)
+    .await
+    .context("failed to query reserved keys")
+}
+
+/// Claim the reserved key `key` (in `did:key` form) for the account `did`, if it's reserved for
+/// that account or for none. Must be called in the transaction that creates the account.
+pub async fn claim(
+    conn: &mut SqliteConnection,
+    did: &str,
+    key: &str,
+) -> anyhow::Result<Option<SigningKey>> {
+    let secret: Option<Vec<u8>> = sqlx::query_scalar(
+        r#"
+        DELETE FROM reserved_keys
+            WHERE key = ? AND (did = ? OR did IS NULL) AND created_at > datetime('now', ?)
+            RETURNING secret
+        "#,
+    )
+    .bind(key)
+    .bind(did)
+    .bind(format!("-{RESERVATION_TTL} seconds"))
+    .fetch_optional(&mut *conn)
+    .await
+    .context("failed to claim reserved key")?;
+    let Some(secret) = secret else {
+        return Ok(None);
+    };
+
+    sqlx::query(r#"INSERT INTO account_keys (did, secret) VALUES (?, ?)"#)
+        .bind(did)
+        .bind(&secret)
+        .execute(&mut *conn)
+        .await
+        .context("failed to store account key")?;
+
+    let key = Secp256k1Keypair::import(&secret).context("failed to import reserved key")?;
+    Ok(Some(SigningKey(Arc::new(key))))
+}
+
+/// The key an account signs with: its own, if it has one, or else the PDS's (`skey`).
+pub async fn account_key(db: &Db, skey: &SigningKey, did: &str) -> anyhow::Result<SigningKey> {
+    let secret: Option<Vec<u8>> =
+        sqlx::query_scalar(r#"SELECT secret FROM account_keys WHERE did = ?"#)
+            .bind(did)
+            .fetch_optional(db)
+            .await
+            .context("failed to query account key")?;
+
+    match secret {
+        Some(secret) => {
+            let key = Secp256k1Keypair::import(&secret).context("failed to import account key")?;
+            Ok(SigningKey(Arc::new(key)))
+        }
+        None => Ok(skey.clone()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reservations() {
+        let db = crate::testing::memory_db().await;
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let (alice, bob) = ("did:plc:alice", "did:plc:bob");
+
+        assert!(!has_reservation(&db, alice).await.unwrap());
+        let stale = reserve(&db, Some(alice)).await.unwrap();
+        let key = reserve(&db, Some(alice)).await.unwrap();
+        assert!(has_reservation(&db, alice).await.unwrap());
+        assert!(!has_reservation(&db, bob).await.unwrap());
+
+        // A key can only be claimed by the account it was reserved for, and only once.
+        let mut conn = db.acquire().await.unwrap();
+        assert!(claim(&mut conn, alice, &stale).await.unwrap().is_none());
+        assert!(claim(&mut conn, bob, &key).await.unwrap().is_none());
+        let claimed = claim(&mut conn, alice, &key).await.unwrap().unwrap();
+        assert_eq!(claimed.did(), key);
+        assert!(claim(&mut conn, alice, &key).await.unwrap().is_none());
+        drop(conn);
+
+        assert_eq!(account_key(&db, &skey, alice).await.unwrap().did(), key);
+        assert_eq!(
+            account_key(&db, &skey, bob).await.unwrap().did(),
+            skey.did()
+        );
+
+        // Expired reservations can't be claimed.
+        let key = reserve(&db, None).await.unwrap();
+        sqlx::query(r#"UPDATE reserved_keys SET created_at = datetime('now', '-2 days')"#)
+            .execute(&db)
+            .await
+            .unwrap();
+        assert!(!has_reservation(&db, bob).await.unwrap());
+        let mut conn = db.acquire().await.unwrap();
+        assert!(claim(&mut conn, bob, &key).await.unwrap().is_none());
+    }
+}
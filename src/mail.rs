@@ -0,0 +1,283 @@
+//! Delivery of email to account holders, and the single-use codes (email tokens) sent with it to
+//! confirm sensitive actions, such as deleting an account. Only a hash of each token is stored.
+//!
+//! Mail is POSTed as JSON to the webhook configured under `[mail]`, leaving delivery to an email
+//! service. Without a webhook, mail is only logged, which is suitable for development only.
+
+use anyhow::{anyhow, Context};
+use rand::Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::{config::MailConfig, Client, Db, Error, Result};
+
+/// How long an email token remains usable after it was requested, in seconds.
+pub const TOKEN_TTL: u64 = 15 * 60;
+
+/// How many wrong tokens may be presented against an outstanding token before it's invalidated.
+pub const MAX_ATTEMPTS: i64 = 5;
+
+/// What an email token confirms. An account has at most one outstanding token per purpose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    DeleteAccount,
+    ConfirmEmail,
+    UpdateEmail,
+    ResetPassword,
+    SignIn,
+    PlcOperation,
+}
+
+impl Purpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::DeleteAccount => "delete_account",
+            Self::ConfirmEmail => "confirm_email",
+            Self::UpdateEmail => "update_email",
+            Self::ResetPassword => "reset_password",
+            Self::SignIn => "sign_in",
+            Self::PlcOperation => "plc_operation",
+        }
+    }
+
+    /// Generate a token. Sign-in tokens are numeric, to be easily entered on any device.
+    fn generate_token(&self) -> String {
+        match self {
+            Self::SignIn => format!("{:06}", rand::thread_rng().gen_range(0..1_000_000)),
+            _ => generate_token(),
+        }
+    }
+}
+
+/// An email, as POSTed to the webhook.
+#[derive(Serialize, Debug)]
+struct Mail<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    text: &'a str,
+}
+
+/// Sends email to account holders.
+#[derive(Debug, Clone)]
+pub struct Mailer {
+    config: Option<MailConfig>,
+    client: Client,
+}
+
+impl Mailer {
+    pub fn new(config: Option<MailConfig>, client: Client) -> Self {
+        Self { config, client }
+    }
+
+    /// Send an email.
+    pub async fn send(&self, to: &str, subject: &str, text: &str) -> anyhow::Result<()> {
+        let Some(config) = &self.config else {
+            info!("mail is not configured; not sending {subject:?} to {to}:\n{text}");
+            return Ok(());
+        };
+
+        self.client
+            .post(config.webhook.clone())
+            .json(&Mail {
+                from: &config.from,
+                to,
+                subject,
+                text,
+            })
+            .send()
+            .await
+            .context("failed to send mail")?
+            .error_for_status()
+            .context("mail webhook rejected mail")?;
+
+        Ok(())
+    }
+}
+
+/// Generate an email token, e.g. `ABCDE-23456`.
+fn generate_token() -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut rng = rand::thread_rng();
+    let mut group = || {
+        (0..5)
+            .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+            .collect::<String>()
+    };
+    format!("{}-{}", group(), group())
+}
+
+/// Hash an email token for storage. Tokens are case-insensitive, and may be entered with stray
+/// whitespace.
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.trim().to_ascii_uppercase().as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Issue a new email token for an account, replacing any outstanding token for the same purpose.
+pub async fn create_token(db: &Db, purpose: Purpose, did: &str) -> anyhow::Result<String> {
+    let token = purpose.generate_token();
+    sqlx::query(
+        r#"
+        INSERT INTO email_tokens (purpose, did, token) VALUES (?, ?, ?)
+            ON CONFLICT (purpose, did) DO UPDATE
+                SET token = excluded.token, requested_at = CURRENT_TIMESTAMP, attempts = 0
+        "#,
+    )
+    .bind(purpose.as_str())
+    .bind(did)
+    .bind(hash_token(&token))
+    .execute(db)
+    .await
+    .context("failed to store email token")?;
+
+    Ok(token)
+}
+
+/// Check an email token presented by an account, consuming it. A token that doesn't match the
+/// outstanding one is `InvalidToken`; one that matches but was requested too long ago is
+/// `ExpiredToken`. After [`MAX_ATTEMPTS`] wrong tokens, the outstanding one no longer matches.
+pub async fn consume_token(db: &Db, purpose: Purpose, did: &str, token: &str) -> Result<()> {
+    let fresh: Option<bool> = sqlx::query_scalar(
+        r#"
+        DELETE FROM email_tokens WHERE purpose = ? AND did = ? AND token = ? AND attempts < ?
+            RETURNING requested_at > datetime('now', ?)
+        "#,
+    )
+    .bind(purpose.as_str())
+    .bind(did)
+    .bind(hash_token(token))
+    .bind(MAX_ATTEMPTS)
+    .bind(format!("-{TOKEN_TTL} seconds"))
+    .fetch_optional(db)
+    .await
+    .context("failed to check email token")?;
+
+    if fresh.is_none() {
+        sqlx::query(
+            r#"UPDATE email_tokens SET attempts = attempts + 1 WHERE purpose = ? AND did = ?"#,
+        )
+        .bind(purpose.as_str())
+        .bind(did)
+        .execute(db)
+        .await
+        .context("failed to record email token attempt")?;
+    }
+
+    match fresh {
+        Some(true) => Ok(()),
+        Some(false) => Err(Error::expired_token(anyhow!(
+            "{} token for {did} has expired",
+            purpose.as_str()
+        ))),
+        None => Err(Error::invalid_token(anyhow!(
+            "invalid {} token for {did}",
+            purpose.as_str()
+        ))),
+    }
+}
+
+/// Check an email token presented without an account (e.g. to reset a forgotten password),
+/// consuming it. Returns the DID of the account it was issued to; errors as [`consume_token`].
+pub async fn redeem_token(db: &Db, purpose: Purpose, token: &str) -> Result<String> {
+    let redeemed: Option<(String, bool)> = sqlx::query_as(
+        r#"
+        DELETE FROM email_tokens WHERE purpose = ? AND token = ?
+            RETURNING did, requested_at > datetime('now', ?)
+        "#,
+    )
+    .bind(purpose.as_str())
+    .bind(hash_token(token))
+    .bind(format!("-{TOKEN_TTL} seconds"))
+    .fetch_optional(db)
+    .await
+    .context("failed to check email token")?;
+
+    match redeemed {
+        Some((did, true)) => Ok(did),
+        Some((did, false)) => Err(Error::expired_token(anyhow!(
+            "{} token for {did} has expired",
+            purpose.as_str()
+        ))),
+        None => Err(Error::invalid_token(anyhow!(
+            "invalid {} token",
+            purpose.as_str()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{body::to_bytes, response::IntoResponse};
+
+    use super::*;
+
+    async fn error_name(e: Error) -> String {
+        let body = to_bytes(e.into_response().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        body["error"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn tokens() {
+        let db = crate::testing::memory_db().await;
+        let (purpose, did) = (Purpose::DeleteAccount, "did:plc:alice");
+
+        let token = create_token(&db, purpose, did).await.unwrap();
+        assert_eq!(token.len(), 11);
+        let stored: String = sqlx::query_scalar(r#"SELECT token FROM email_tokens"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_ne!(stored, token);
+
+        let e = consume_token(&db, purpose, did, "AAAAA-AAAAA")
+            .await
+            .unwrap_err();
+        assert_eq!(error_name(e).await, "InvalidToken");
+        let e = consume_token(&db, purpose, "did:plc:bob", &token)
+            .await
+            .unwrap_err();
+        assert_eq!(error_name(e).await, "InvalidToken");
+
+        // Tokens are single-use, and case-insensitive.
+        consume_token(&db, purpose, did, &token.to_lowercase())
+            .await
+            .unwrap();
+        let e = consume_token(&db, purpose, did, &token).await.unwrap_err();
+        assert_eq!(error_name(e).await, "InvalidToken");
+
+        // Requesting a token replaces the outstanding one.
+        let stale = create_token(&db, purpose, did).await.unwrap();
+        let token = create_token(&db, purpose, did).await.unwrap();
+        if stale != token {
+            assert!(consume_token(&db, purpose, did, &stale).await.is_err());
+        }
+
+        sqlx::query(r#"UPDATE email_tokens SET requested_at = datetime('now', '-1 hours')"#)
+            .execute(&db)
+            .await
+            .unwrap();
+        let e = consume_token(&db, purpose, did, &token).await.unwrap_err();
+        assert_eq!(error_name(e).await, "ExpiredToken");
+
+        // Sign-in tokens are numeric, and only a few guesses are allowed.
+        let token = create_token(&db, Purpose::SignIn, did).await.unwrap();
+        assert!(token.len() == 6 && token.chars().all(|c| c.is_ascii_digit()));
+        for _ in 0..MAX_ATTEMPTS {
+            assert!(consume_token(&db, Purpose::SignIn, did, "ABCDEF")
+                .await
+                .is_err());
+        }
+        let e = consume_token(&db, Purpose::SignIn, did, &token)
+            .await
+            .unwrap_err();
+        assert_eq!(error_name(e).await, "InvalidToken");
+    }
+}
@@ -3,49 +3,101 @@ use std::{
     path::PathBuf,
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
-use atrium_api::types::string::Did;
 use atrium_crypto::keypair::{Export, Secp256k1Keypair};
-use auth::AuthenticatedUser;
+use auth::Revocations;
 use axum::{
-    body::Body,
-    extract::{FromRef, Request, State},
-    http::{self, HeaderMap, Response, StatusCode, Uri},
+    extract::{FromRef, Request},
+    middleware,
     response::IntoResponse,
     routing::get,
     Router,
 };
 use azure_core::credentials::TokenCredential;
+use capabilities::Capabilities;
 use clap::Parser;
 use clap_verbosity_flag::{log::LevelFilter, InfoLevel, Verbosity};
 use config::AppConfig;
+use did::DidCache;
+use endpoints::UploadLocks;
+use events::EventBus;
 use figment::{providers::Format, Figment};
 use firehose::FirehoseProducer;
 use http_cache_reqwest::{CacheMode, HttpCacheOptions, MokaManager};
-use rand::Rng;
+use policy::Policies;
+use ratelimit::{LoginLimiter, SignupLimiter, SyncLimiter, WriteLimiter};
 use serde::{Deserialize, Serialize};
 use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
+use storage::RepoLocks;
+use tiering::Tiering;
 use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 
-use anyhow::{anyhow, Context};
+use anyhow::Context;
 use tracing::{info, warn};
 
+mod aturi;
 mod auth;
+mod backlinks;
+mod capabilities;
+mod cbor;
+mod collections;
+mod commitlog;
+mod compact;
 mod config;
+mod deletion;
 mod did;
 mod endpoints;
 mod error;
+mod events;
 mod firehose;
+mod gc;
+mod handle;
+mod host;
+mod import;
+mod integrity;
+#[cfg(test)]
+mod interop;
+mod invites;
+mod jobs;
+mod keys;
+mod mail;
 mod metrics;
+mod migration;
 mod mmap;
+mod nsid;
+mod password;
 mod plc;
+mod policy;
+mod proxy;
+mod ratelimit;
+mod record;
+mod redact;
+mod reindex;
+mod signup_queue;
+mod stats;
+mod status;
 mod storage;
+#[cfg(test)]
+mod testing;
+mod tiering;
+mod timing;
+mod unsupported;
+mod verify;
+mod websocket;
 
 pub type Result<T> = std::result::Result<T, error::Error>;
 pub use error::Error;
+use handle::HandleResolver;
+use integrity::RepoIntegrity;
+use mail::Mailer;
+use stats::StorageStats;
+use unsupported::MethodTally;
 use uuid::Uuid;
+use verify::RelayVerifier;
 
 pub type Client = reqwest_middleware::ClientWithMiddleware;
 pub type Db = sqlx::SqlitePool;
@@ -105,6 +157,24 @@ struct AppState {
     client: Client,
     simple_client: reqwest::Client,
     firehose: FirehoseProducer,
+    events: EventBus,
+    policies: Policies,
+    write_limiter: WriteLimiter,
+    sync_limiter: SyncLimiter,
+    login_limiter: LoginLimiter,
+    signup_limiter: SignupLimiter,
+    did_cache: DidCache,
+    handle_resolver: HandleResolver,
+    revocations: Revocations,
+    method_tally: MethodTally,
+    relay_verifier: RelayVerifier,
+    storage_stats: StorageStats,
+    repo_integrity: RepoIntegrity,
+    repo_locks: RepoLocks,
+    upload_locks: UploadLocks,
+    tiering: Tiering,
+    mailer: Mailer,
+    capabilities: Arc<Capabilities>,
 
     signing_key: SigningKey,
     rotation_key: RotationKey,
@@ -132,187 +202,17 @@ Most API routes are under /xrpc/
     "#
 }
 
-/// HACK: store private user preferences in the PDS.
-///
-/// We shouldn't have to know about any bsky endpoints to store private user data.
-/// This will _very likely_ be changed in the future.
-mod actor_endpoints {
-    use atrium_api::app::bsky::actor;
-    use axum::{routing::post, Json};
-    use constcat::concat;
-
-    use super::*;
-
-    async fn put_preferences(
-        user: AuthenticatedUser,
-        State(db): State<Db>,
-        Json(input): Json<actor::put_preferences::Input>,
-    ) -> Result<()> {
-        let did = user.did();
-        let prefs = sqlx::types::Json(input.preferences.clone());
-        sqlx::query!(
-            r#"UPDATE accounts SET private_prefs = ? WHERE did = ?"#,
-            prefs,
-            did
-        )
-        .execute(&db)
-        .await
-        .context("failed to update user preferences")?;
-
-        Ok(())
-    }
-
-    async fn get_preferences(
-        user: AuthenticatedUser,
-        State(db): State<Db>,
-    ) -> Result<Json<actor::get_preferences::Output>> {
-        let did = user.did();
-        let json: Option<sqlx::types::Json<actor::defs::Preferences>> =
-            sqlx::query_scalar(r#"SELECT private_prefs FROM accounts WHERE did = ?"#)
-                .bind(did)
-                .fetch_one(&db)
-                .await
-                .context("failed to fetch preferences")?;
-
-        if let Some(prefs) = json {
-            Ok(Json(
-                actor::get_preferences::OutputData {
-                    preferences: prefs.0,
-                }
-                .into(),
-            ))
-        } else {
-            Ok(Json(
-                actor::get_preferences::OutputData {
-                    preferences: Vec::new(),
-                }
-                .into(),
-            ))
-        }
-    }
-
-    #[rustfmt::skip]
-    pub fn routes() -> Router<AppState> {
-        // AP /xrpc/app.bsky.actor.putPreferences
-        // AG /xrpc/app.bsky.actor.getPreferences
-        Router::new()
-            .route(concat!("/", actor::put_preferences::NSID), post(put_preferences))
-            .route(concat!("/", actor::get_preferences::NSID),  get(get_preferences))
-    }
-}
-
-/// Service proxy.
-///
-/// Reference: https://atproto.com/specs/xrpc#service-proxying
-async fn service_proxy(
-    url: Uri,
-    user: AuthenticatedUser,
-    State(skey): State<SigningKey>,
-    State(client): State<reqwest::Client>,
-    headers: HeaderMap,
-    request: Request<Body>,
-) -> Result<Response<Body>> {
-    let url_path = url.path_and_query().context("invalid service proxy url")?;
-    let lxm = url_path
-        .path()
-        .strip_prefix("/")
-        .with_context(|| format!("invalid service proxy url prefix: {}", url_path.path()))?;
-
-    let user_did = user.did();
-    let (did, id) = match headers.get("atproto-proxy") {
-        Some(val) => {
-            let val =
-                std::str::from_utf8(val.as_bytes()).context("proxy header not valid utf-8")?;
-
-            let (did, id) = val.split_once('#').context("invalid proxy header")?;
-
-            let did =
-                Did::from_str(did).map_err(|e| anyhow!("atproto proxy not a valid DID: {e}"))?;
-
-            (did, format!("#{id}"))
-        }
-        // HACK: Assume the bluesky appview by default.
-        None => (
-            Did::new("did:web:api.bsky.app".to_string()).unwrap(),
-            "#bsky_appview".to_string(),
-        ),
-    };
-
-    let did_doc = did::resolve(&Client::new(client.clone(), []), did.clone())
-        .await
-        .with_context(|| format!("failed to resolve did document {}", did.as_str()))?;
-
-    let service = match did_doc.service.iter().find(|s| s.id == id) {
-        Some(service) => service,
-        None => {
-            return Err(Error::with_status(
-                StatusCode::BAD_REQUEST,
-                anyhow!("could not find resolve service #{id}"),
-            ))
-        }
-    };
-
-    let url = service
-        .service_endpoint
-        .join(&format!("/xrpc{}", url_path))
-        .context("failed to construct target url")?;
-
-    let exp = (chrono::Utc::now() + std::time::Duration::from_secs(60)).timestamp();
-    let jti = rand::thread_rng()
-        .sample_iter(rand::distributions::Alphanumeric)
-        .take(10)
-        .map(char::from)
-        .collect::<String>();
-
-    // Mint a bearer token by signing a JSON web token.
-    // https://github.com/DavidBuchanan314/millipds/blob/5c7529a739d394e223c0347764f1cf4e8fd69f94/src/millipds/appview_proxy.py#L47-L59
-    let token = auth::sign(
-        &skey,
-        "JWT",
-        serde_json::json!({
-            "iss": user_did.as_str(),
-            "aud": did.as_str(),
-            "lxm": lxm,
-            "exp": exp,
-            "jti": jti,
-        }),
-    )
-    .context("failed to sign jwt")?;
-
-    let mut h = HeaderMap::new();
-    if let Some(hdr) = request.headers().get("atproto-accept-labelers") {
-        h.insert("atproto-accept-labelers", hdr.clone());
-    }
-    if let Some(hdr) = request.headers().get(http::header::CONTENT_TYPE) {
-        h.insert(http::header::CONTENT_TYPE, hdr.clone());
-    }
-
-    let r = client
-        .request(request.method().clone(), url)
-        .headers(h)
-        .header(axum::http::header::AUTHORIZATION, format!("Bearer {token}"))
-        .body(reqwest::Body::wrap_stream(
-            request.into_body().into_data_stream(),
-        ))
-        .send()
-        .await
-        .context("failed to send request")?;
-
-    let mut resp = Response::builder().status(r.status());
-    if let Some(hdrs) = resp.headers_mut() {
-        *hdrs = r.headers().clone();
-    }
-
-    let resp = resp
-        .body(Body::from_stream(r.bytes_stream()))
-        .context("failed to construct response")?;
-
-    Ok(resp)
-}
-
 async fn run() -> anyhow::Result<()> {
     let args = Args::parse();
 
+    // Read and parse the user-provided configuration.
+    let config_exists = args.config.exists();
+    let config: AppConfig = Figment::new()
+        .admerge(figment::providers::Toml::file(args.config.clone()))
+        .admerge(figment::providers::Env::prefixed("BLUEPDS_"))
+        .extract()
+        .context("failed to load configuration")?;
+
     // Set up trace logging to console and account for the user-provided verbosity flag.
     if args.verbosity.log_level_filter() != LevelFilter::Off {
         let lvl = match args.verbosity.log_level_filter() {
@@ -323,10 +223,16 @@ async fn run() -> anyhow::Result<()> {
             LevelFilter::Debug => tracing::Level::DEBUG,
             LevelFilter::Trace => tracing::Level::TRACE,
         };
-        tracing_subscriber::fmt().with_max_level(lvl).init();
+        tracing_subscriber::fmt()
+            .with_max_level(lvl)
+            .event_format(redact::Redacting::new(
+                config.log.redact,
+                tracing_subscriber::fmt::format(),
+            ))
+            .init();
     }
 
-    if !args.config.exists() {
+    if !config_exists {
         // Throw up a warning if the config file does not exist.
         //
         // This is not fatal because users can specify all configuration settings via
@@ -338,13 +244,6 @@ async fn run() -> anyhow::Result<()> {
         );
     }
 
-    // Read and parse the user-provided configuration.
-    let config: AppConfig = Figment::new()
-        .admerge(figment::providers::Toml::file(args.config))
-        .admerge(figment::providers::Env::prefixed("BLUEPDS_"))
-        .extract()
-        .context("failed to load configuration")?;
-
     if config.test {
         warn!("BluePDS starting up in TEST mode.");
         warn!("This means the application will not federate with the rest of the network.");
@@ -413,34 +312,146 @@ async fn run() -> anyhow::Result<()> {
         .await
         .context("failed to apply migrations")?;
 
-    let (_fh, fhp) = firehose::spawn(client.clone(), config.clone()).await;
+    // Recover background jobs interrupted by a crash, before anything else touches storage.
+    let recovered = jobs::recover(&config, &db)
+        .await
+        .context("failed to recover interrupted jobs")?;
+    if recovered != 0 {
+        info!("recovered {recovered} interrupted jobs");
+    }
+
+    // Resolve commits interrupted partway through (see `storage`).
+    let commits = storage::recover(&config.repo, &db)
+        .await
+        .context("failed to recover interrupted commits")?;
+    if commits.completed != 0 || !commits.lost.is_empty() {
+        warn!(
+            "recovered {} interrupted commits, {} of which lost blocks",
+            commits.completed + commits.lost.len(),
+            commits.lost.len()
+        );
+    }
+
+    let revocations = Revocations::load(&db)
+        .await
+        .context("failed to load revoked sessions")?;
+    let policies = Policies::load(&config.policy).context("failed to load record policies")?;
+    let (fh, fhp) = firehose::spawn(config.clone(), db.clone())
+        .await
+        .context("failed to start firehose")?;
+
+    // Write paths publish events once, for every subscriber to see.
+    let mut bus = EventBus::new();
+    events::subscribe_firehose(&mut bus, fhp.clone());
+    events::subscribe_migrations(&mut bus, db.clone());
+    tiering::subscribe(&mut bus, db.clone());
+
+    // Periodically discard abandoned resumable uploads.
+    tokio::spawn(endpoints::cleanup_uploads(config.clone(), db.clone()));
+    tokio::spawn(gc::run(config.gc.clone(), db.clone()));
+    tokio::spawn(migration::run(config.migration.clone(), db.clone()));
+    tokio::spawn(reindex::run(config.clone(), db.clone()));
+    if let Some(queue) = config.signup_queue.clone() {
+        tokio::spawn(signup_queue::run(queue, db.clone(), bus.clone()));
+    }
+
+    // Move the storage of inactive accounts to the archive, and back again on access.
+    let tiering = Tiering::new(&config, db.clone());
+    tokio::spawn(tiering::run(tiering.clone()));
+
+    let method_tally = MethodTally::default();
+    tokio::spawn(unsupported::report(method_tally.clone()));
+
+    let relay_verifier =
+        RelayVerifier::new(config.firehose.verify.clone(), client.clone(), db.clone());
+    if !config.test {
+        tokio::spawn(verify::run(relay_verifier.clone()));
+    }
+
+    let storage_stats =
+        StorageStats::new(config.repo.growth_alert.clone(), client.clone(), db.clone());
+
+    let repo_integrity = RepoIntegrity::new(
+        config.repo.clone(),
+        db.clone(),
+        client.clone(),
+        skey.clone(),
+    )
+    .with_tiering(tiering.clone())
+    .with_events(bus.clone());
 
     let addr = config
         .listen_address
         .clone()
         .unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8000));
 
+    // Catalogue the endpoints as they're registered, so that clients can discover them.
+    let (xrpc, endpoints) = endpoints::routes(&config).into_parts();
+    let capabilities = Arc::new(Capabilities::new(&config, endpoints));
+
+    let state = AppState {
+        cred,
+        config: config.clone(),
+        db: db.clone(),
+        client: client.clone(),
+        simple_client,
+        firehose: fhp.clone(),
+        events: bus,
+        policies,
+        write_limiter: WriteLimiter::new(&config.rate_limit),
+        sync_limiter: SyncLimiter::new(&config.rate_limit),
+        login_limiter: LoginLimiter::new(&config.rate_limit),
+        signup_limiter: SignupLimiter::new(&config.rate_limit),
+        did_cache: DidCache::new(&config.did_cache),
+        handle_resolver: HandleResolver::new(),
+        revocations,
+        method_tally: method_tally.clone(),
+        relay_verifier,
+        storage_stats,
+        repo_integrity,
+        repo_locks: RepoLocks::default(),
+        upload_locks: UploadLocks::default(),
+        tiering,
+        mailer: Mailer::new(config.mail.clone(), client.clone()),
+        capabilities,
+        signing_key: skey,
+        rotation_key: rkey,
+    };
+
     let app = Router::new()
         .route("/", get(index))
+        .route("/.well-known/atproto-did", get(handle::well_known))
         .nest(
             "/xrpc",
-            endpoints::routes()
-                .merge(actor_endpoints::routes())
-                .fallback(service_proxy),
+            xrpc.layer(middleware::from_fn_with_state(
+                state.clone(),
+                status::enforce,
+            )),
         )
+        .layer(middleware::from_fn_with_state(
+            config.clone(),
+            host::enforce,
+        ))
+        // Requests made to handles' hostnames are routed before anything else sees them.
+        .layer(middleware::from_fn_with_state(
+            config.clone(),
+            host::route_handles,
+        ))
         // .layer(RateLimitLayer::new(30, Duration::from_secs(30)))
         .layer(CorsLayer::permissive())
-        .layer(TraceLayer::new_for_http())
-        .with_state(AppState {
-            cred,
-            config: config.clone(),
-            db: db.clone(),
-            client: client.clone(),
-            simple_client,
-            firehose: fhp,
-            signing_key: skey,
-            rotation_key: rkey,
-        });
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|request: &Request<_>| {
+                // Tag each request with an ID, so that its log lines can be correlated even when
+                // user identifiers are redacted.
+                tracing::debug_span!(
+                    "request",
+                    id = %Uuid::new_v4(),
+                    method = %request.method(),
+                    uri = %request.uri(),
+                )
+            }),
+        )
+        .with_state(state);
 
     info!("listening on {addr}");
     info!("connect to: http://127.0.0.1:{}", addr.port());
@@ -458,7 +469,7 @@ async fn run() -> anyhow::Result<()> {
     .await
     .context("failed to query database")?;
 
-    if c == 0 {
+    if c == 0 && config.invites.required {
         let uuid = Uuid::new_v4().to_string();
 
         sqlx::query!(
@@ -486,21 +497,97 @@ async fn run() -> anyhow::Result<()> {
         .await
         .context("failed to bind address")?;
 
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown.cancel();
+        }
+    });
+
     // Serve the app, and request crawling from upstream relays.
-    let serve = tokio::spawn(async move {
-        axum::serve(listener, app.into_make_service())
+    let mut serve = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown.cancelled_owned())
             .await
             .context("failed to serve app")
+        }
     });
 
-    // Now that the app is live, request a crawl from upstream relays.
-    let _ = firehose::reconnect_relays(&client, &config).await;
+    // Now that the app is live, request crawls from upstream relays until they subscribe.
+    tokio::spawn(firehose::crawl_relays(
+        client.clone(),
+        config.clone(),
+        fhp.relays().clone(),
+    ));
+
+    tokio::select! {
+        r = &mut serve => {
+            return r
+                .map_err(|e| e.into())
+                .and_then(|r| r)
+                .context("failed to serve app");
+        }
+        _ = shutdown.cancelled() => {}
+    }
+
+    // New connections are no longer accepted. Let requests in flight finish, so that their events
+    // reach the firehose, then have the firehose deliver what it has queued and close connections.
+    let timeout = Duration::from_secs(config.shutdown_timeout);
+    info!("shutting down (waiting up to {timeout:?})");
+    let drain = async {
+        match serve.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("{e:?}"),
+            Err(e) => warn!("failed to join server: {e:?}"),
+        }
 
-    serve
-        .await
-        .map_err(|e| e.into())
-        .and_then(|r| r)
-        .context("failed to serve app")
+        fhp.shutdown().await;
+        if let Err(e) = fh.await {
+            warn!("failed to join firehose: {e:?}");
+        }
+    };
+    if tokio::time::timeout(timeout, drain).await.is_err() {
+        warn!("timed out draining requests and the firehose; exiting anyway");
+    }
+
+    Ok(())
+}
+
+/// Wait for the process to be asked to shut down, with SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("failed to listen for ctrl-c: {e:?}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("failed to listen for SIGTERM: {e:?}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
 #[tokio::main(flavor = "multi_thread")]
@@ -3,49 +3,221 @@
 use std::time::Duration;
 
 use anyhow::Context;
-use metrics::{describe_counter, describe_gauge};
+use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
 use metrics_exporter_prometheus::PrometheusBuilder;
 
 use crate::config;
 
 pub const AUTH_FAILED: &str = "bluepds.auth.failed"; // Counter.
+pub const AUTH_LOGIN_FAILED: &str = "bluepds.auth.login_failed"; // Counter.
+pub const AUTH_LOGIN_THROTTLED: &str = "bluepds.auth.login_throttled"; // Counter, labeled by key.
 
+pub const CBOR_REJECTED: &str = "bluepds.cbor.rejected"; // Counter, labeled by source and reason.
+
+pub const DID_CACHE_HITS: &str = "bluepds.did_cache.hits"; // Counter.
+pub const DID_CACHE_MISSES: &str = "bluepds.did_cache.misses"; // Counter.
+
+pub const EVENTS_LAG: &str = "bluepds.events.lag"; // Gauge, labeled by subscriber.
+pub const EVENTS_PUBLISHED: &str = "bluepds.events.published"; // Counter, labeled by kind.
+
+pub const FIREHOSE_AUDIT_ANOMALIES: &str = "bluepds.firehose.audit_anomalies"; // Gauge.
+pub const FIREHOSE_BACKFILLS: &str = "bluepds.firehose.backfills"; // Gauge.
+pub const FIREHOSE_EVENTS_LOST: &str = "bluepds.firehose.events_lost"; // Counter, labeled by type.
+pub const FIREHOSE_FRAME_SIZE: &str = "bluepds.firehose.frame_size"; // Histogram, labeled by type.
+pub const FIREHOSE_FRAMES_SENT: &str = "bluepds.firehose.frames_sent"; // Counter, labeled by source.
 pub const FIREHOSE_HISTORY: &str = "bluepds.firehose.history"; // Gauge.
+pub const FIREHOSE_HISTORY_BYTES: &str = "bluepds.firehose.history_bytes"; // Gauge.
 pub const FIREHOSE_LISTENERS: &str = "bluepds.firehose.listeners"; // Gauge.
-pub const FIREHOSE_MESSAGES: &str = "bluepds.firehose.messages"; // Counter.
+/// N.B: Prior to being labeled by event type (`#commit`, `#identity`, ...), this also counted
+/// websocket pings. Pings are no longer included.
+pub const FIREHOSE_MESSAGES: &str = "bluepds.firehose.messages"; // Counter, labeled by type.
+pub const FIREHOSE_REJECTED: &str = "bluepds.firehose.rejected"; // Counter, labeled by limit.
 pub const FIREHOSE_SEQUENCE: &str = "bluepds.firehose.sequence"; // Counter.
+pub const FIREHOSE_SEQ_ANOMALIES: &str = "bluepds.firehose.seq_anomalies"; // Counter, labeled by check.
+
+pub const GC_RECLAIMED: &str = "bluepds.gc.reclaimed"; // Counter, labeled by store.
+
+pub const MIGRATIONS_STUCK: &str = "bluepds.migrations.stuck"; // Gauge.
+
+pub const RELAY_CONNECTIONS: &str = "bluepds.relay.connections"; // Gauge, labeled by host.
+pub const RELAY_CRAWL_FAILURES: &str = "bluepds.relay.crawl_failures"; // Gauge, labeled by host.
+pub const RELAY_CRAWL_OK: &str = "bluepds.relay.crawl_ok"; // Gauge, labeled by host.
+pub const RELAY_DIVERGENT_REPOS: &str = "bluepds.relay.divergent_repos"; // Gauge.
+pub const RELAY_SEQUENCE: &str = "bluepds.relay.sequence"; // Gauge, labeled by host.
 
 pub const REPO_COMMITS: &str = "bluepds.repo.commits"; // Counter.
+pub const REPO_COMMIT_DURATION: &str = "bluepds.repo.commit_duration"; // Histogram.
+pub const REPO_COMMIT_STAGE: &str = "bluepds.repo.commit_stage"; // Histogram, labeled by stage.
+pub const REPO_INTEGRITY_FAILURES: &str = "bluepds.repo.integrity_failures"; // Counter.
 pub const REPO_OP_CREATE: &str = "bluepds.repo.op.create"; // Counter.
 pub const REPO_OP_UPDATE: &str = "bluepds.repo.op.update"; // Counter.
 pub const REPO_OP_DELETE: &str = "bluepds.repo.op.delete"; // Counter.
 
+pub const SIGNUP_THROTTLED: &str = "bluepds.signup.throttled"; // Counter.
+
+pub const SYNC_EXPENSIVE_OPS: &str = "bluepds.sync.expensive_ops"; // Gauge, labeled by class.
+
+pub const TIERING_MOVED: &str = "bluepds.tiering.moved"; // Counter, labeled by direction.
+
+pub const XRPC_UNSUPPORTED: &str = "bluepds.xrpc.unsupported"; // Counter, labeled by kind (and method, if known).
+
 /// Must be ran exactly once on startup. This will declare all of the instruments for `metrics`.
 pub fn setup(config: &Option<config::MetricConfig>) -> anyhow::Result<()> {
     describe_counter!(AUTH_FAILED, "The number of failed authentication attempts.");
+    describe_counter!(
+        AUTH_LOGIN_FAILED,
+        "Sign-ins (createSession) rejected for a wrong identifier or password."
+    );
+    describe_counter!(
+        AUTH_LOGIN_THROTTLED,
+        "Sign-ins rejected unchecked for too many recent failures, by the key (account or ip) \
+         that was throttled."
+    );
+
+    describe_counter!(
+        CBOR_REJECTED,
+        "Untrusted CBOR payloads rejected for exceeding limits or being malformed."
+    );
+
+    describe_counter!(
+        DID_CACHE_HITS,
+        "DID documents served from the cache, including to requests that waited on another's fetch."
+    );
+    describe_counter!(
+        DID_CACHE_MISSES,
+        "DID documents fetched from the PLC directory or a did:web host, on a miss or refresh."
+    );
+
+    describe_gauge!(
+        EVENTS_LAG,
+        "Events queued for each internal event subscriber, waiting to be handled."
+    );
+    describe_counter!(
+        EVENTS_PUBLISHED,
+        "Events published on the internal event bus, by kind."
+    );
 
+    describe_gauge!(
+        FIREHOSE_AUDIT_ANOMALIES,
+        "Sequence gaps and duplicates found in the firehose history by the last audit."
+    );
+    describe_gauge!(
+        FIREHOSE_BACKFILLS,
+        "The number of firehose consumers currently being backfilled."
+    );
+    describe_counter!(
+        FIREHOSE_EVENTS_LOST,
+        "Events that could not be queued for the firehose, by event type. Consumers have missed \
+         these, so the firehose no longer reflects the repositories they describe."
+    );
+    describe_histogram!(
+        FIREHOSE_FRAME_SIZE,
+        Unit::Bytes,
+        "The size of serialized firehose frames, by event type."
+    );
+    describe_counter!(
+        FIREHOSE_FRAMES_SENT,
+        "Frames delivered to firehose consumers, by source (live, backfill, or stored for backfill past the history)."
+    );
     describe_gauge!(FIREHOSE_HISTORY, "The size of the firehose history buffer.");
+    describe_gauge!(
+        FIREHOSE_HISTORY_BYTES,
+        "The total size of the frames in the firehose history buffer, in bytes."
+    );
     describe_gauge!(
         FIREHOSE_LISTENERS,
         "The number of active consumers on the firehose."
     );
     describe_counter!(
         FIREHOSE_MESSAGES,
-        "All messages that have been broadcast on the firehose."
+        "Events that have been broadcast on the firehose, by event type."
+    );
+    describe_counter!(
+        FIREHOSE_REJECTED,
+        "Firehose connections rejected for exceeding a limit, by limit (total or ip)."
     );
     describe_counter!(
         FIREHOSE_SEQUENCE,
         "The current sequence number on the firehose."
     );
+    describe_counter!(
+        FIREHOSE_SEQ_ANOMALIES,
+        "Firehose events sequenced out of order, by check (broadcast or append)."
+    );
+
+    describe_counter!(
+        GC_RECLAIMED,
+        "Expired entries deleted by garbage collection, by store."
+    );
+
+    describe_gauge!(
+        MIGRATIONS_STUCK,
+        "Inbound account migrations that have made no progress within the configured window."
+    );
+
+    describe_gauge!(
+        RELAY_CONNECTIONS,
+        "The number of firehose connections open from each relay."
+    );
+    describe_gauge!(
+        RELAY_CRAWL_FAILURES,
+        "The number of consecutive requestCrawls to each relay that have failed."
+    );
+    describe_gauge!(
+        RELAY_CRAWL_OK,
+        "Whether the last requestCrawl to each relay succeeded (1) or failed (0)."
+    );
+    describe_gauge!(
+        RELAY_DIVERGENT_REPOS,
+        "The number of repositories whose head on the verified relay has diverged past the grace period."
+    );
+    describe_gauge!(
+        RELAY_SEQUENCE,
+        "The last firehose sequence number delivered to each relay."
+    );
 
     describe_counter!(
         REPO_COMMITS,
         "The count of commits created for all repositories."
     );
+    describe_histogram!(
+        REPO_COMMIT_DURATION,
+        Unit::Seconds,
+        "The total time taken to apply a set of writes to a repository."
+    );
+    describe_histogram!(
+        REPO_COMMIT_STAGE,
+        Unit::Seconds,
+        "The time spent in each stage of the commit pipeline, per commit."
+    );
+    describe_counter!(
+        REPO_INTEGRITY_FAILURES,
+        "Repository heads that failed an integrity check when opened."
+    );
     describe_counter!(REPO_OP_CREATE, "The count of created records.");
     describe_counter!(REPO_OP_UPDATE, "The count of updated records.");
     describe_counter!(REPO_OP_DELETE, "The count of deleted records.");
 
+    describe_counter!(
+        SIGNUP_THROTTLED,
+        "Account creations rejected for exceeding their address's signup budget."
+    );
+
+    describe_gauge!(
+        SYNC_EXPENSIVE_OPS,
+        "Expensive sync operations (e.g. getRepo) in flight, by client class (ip or did)."
+    );
+
+    describe_counter!(
+        TIERING_MOVED,
+        "Accounts whose storage was moved to or back from the archive, by direction."
+    );
+
+    describe_counter!(
+        XRPC_UNSUPPORTED,
+        "Requests for XRPC methods this PDS doesn't serve, by kind (unimplemented or unknown)."
+    );
+
     if let Some(config) = config {
         match config {
             config::MetricConfig::PrometheusPush(prometheus_config) => {
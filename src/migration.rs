@@ -0,0 +1,309 @@
+//! Tracking of inbound account migrations.
+//!
+//! Migrating an account into this PDS takes several calls, all made while the account is
+//! deactivated: its repository is imported (`importRepo`), its blobs uploaded (`uploadBlob`) and
+//! its preferences transferred (`putPreferences`), before it is finally activated. Each step is
+//! recorded as it is taken, so that operators can see where a migration got stuck.
+//!
+//! Only deactivated accounts are tracked, and the record of a migration is removed once the
+//! account is activated. A migration that makes no progress within the configured window is
+//! reported as stuck (once).
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use metrics::gauge;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{config::MigrationConfig, metrics::MIGRATIONS_STUCK, Db};
+
+/// A step of an inbound migration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    RepoImported,
+    BlobImported,
+    PreferencesImported,
+}
+
+impl Step {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::RepoImported => "repoImported",
+            Self::BlobImported => "blobImported",
+            Self::PreferencesImported => "preferencesImported",
+        }
+    }
+}
+
+/// The state of an inbound migration.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationStatus {
+    pub did: String,
+    /// The last step taken.
+    pub step: String,
+    pub started_at: String,
+    pub updated_at: String,
+    pub repo_imported_at: Option<String>,
+    pub preferences_imported_at: Option<String>,
+    /// The number of records in the imported repository.
+    pub indexed_records: i64,
+    /// The number of distinct blobs referenced by the account's records.
+    pub expected_blobs: i64,
+    /// The number of blobs uploaded during the migration.
+    pub imported_blobs: i64,
+    /// The error of the last failed step, if it hasn't since been retried successfully.
+    pub error: Option<String>,
+    /// Whether the migration has made no progress within the configured window.
+    pub stuck: bool,
+}
+
+/// Start tracking a migration (if the account is deactivated), or bump its last update.
+async fn touch(db: &Db, did: &str, step: Step, error: Option<&str>) -> Result<bool> {
+    let r = sqlx::query(
+        r#"
+        INSERT INTO migrations (did, step, error)
+            SELECT did, ?, ? FROM accounts WHERE did = ? AND status = 'deactivated'
+        ON CONFLICT(did) DO UPDATE SET
+            step = excluded.step,
+            error = excluded.error,
+            updated_at = datetime('now'),
+            stuck = FALSE
+        "#,
+    )
+    .bind(step.as_str())
+    .bind(error)
+    .bind(did)
+    .execute(db)
+    .await
+    .context("failed to record migration step")?;
+
+    Ok(r.rows_affected() != 0)
+}
+
+/// Record a step of an account's migration. This does nothing unless the account is deactivated.
+pub async fn record(db: &Db, did: &str, step: Step) -> Result<()> {
+    if !touch(db, did, step, None).await? {
+        return Ok(());
+    }
+
+    let query = match step {
+        Step::RepoImported => {
+            r#"UPDATE migrations SET repo_imported_at = datetime('now') WHERE did = ?"#
+        }
+        Step::BlobImported => {
+            r#"UPDATE migrations SET blobs_imported = blobs_imported + 1 WHERE did = ?"#
+        }
+        Step::PreferencesImported => {
+            r#"UPDATE migrations SET preferences_imported_at = datetime('now') WHERE did = ?"#
+        }
+    };
+
+    sqlx::query(query)
+        .bind(did)
+        .execute(db)
+        .await
+        .context("failed to record migration step")?;
+
+    Ok(())
+}
+
+/// Record the failure of a step of an account's migration.
+pub async fn fail(db: &Db, did: &str, step: Step, error: &str) -> Result<()> {
+    touch(db, did, step, Some(error)).await.map(|_| ())
+}
+
+/// Record a step of an account's migration, logging rather than failing the request if that
+/// doesn't work out.
+pub async fn record_result<T, E: std::fmt::Display>(
+    db: &Db,
+    did: &str,
+    step: Step,
+    result: &std::result::Result<T, E>,
+) {
+    let r = match result {
+        Ok(_) => record(db, did, step).await,
+        Err(e) => fail(db, did, step, &e.to_string()).await,
+    };
+    if let Err(e) = r {
+        warn!("failed to record migration step for {did}: {e:?}");
+    }
+}
+
+const STATUS_QUERY: &str = r#"
+    SELECT
+        m.did, m.step, m.started_at, m.updated_at, m.repo_imported_at,
+        m.preferences_imported_at, m.error, m.stuck,
+        m.blobs_imported AS imported_blobs,
+        COALESCE((SELECT records FROM repo_stats s WHERE s.did = m.did), 0) AS indexed_records,
+        (SELECT COUNT(DISTINCT cid) FROM blob_ref b
+            WHERE b.did = m.did AND b.record IS NOT NULL) AS expected_blobs
+    FROM migrations m
+"#;
+
+/// Fetch the state of an account's migration, if it is being migrated.
+pub async fn status(db: &Db, did: &str) -> Result<Option<MigrationStatus>> {
+    sqlx::query_as(&format!("{STATUS_QUERY} WHERE m.did = ?"))
+        .bind(did)
+        .fetch_optional(db)
+        .await
+        .context("failed to query migration")
+}
+
+/// List all migrations in progress, least recently updated first.
+pub async fn list(db: &Db) -> Result<Vec<MigrationStatus>> {
+    sqlx::query_as(&format!("{STATUS_QUERY} ORDER BY m.updated_at, m.did"))
+        .fetch_all(db)
+        .await
+        .context("failed to list migrations")
+}
+
+/// Forget migrations of accounts that have since been activated (or deleted), and report those
+/// that have made no progress within the window. Returns the newly stuck migrations.
+pub async fn check(config: &MigrationConfig, db: &Db) -> Result<Vec<String>> {
+    let done = sqlx::query(
+        r#"
+        DELETE FROM migrations WHERE did NOT IN
+            (SELECT did FROM accounts WHERE status = 'deactivated')
+        "#,
+    )
+    .execute(db)
+    .await
+    .context("failed to remove finished migrations")?;
+    if done.rows_affected() != 0 {
+        info!("{} migrations finished", done.rows_affected());
+    }
+
+    let stuck: Vec<String> = sqlx::query_scalar(
+        r#"
+        UPDATE migrations SET stuck = TRUE
+            WHERE NOT stuck AND updated_at <= datetime('now', ?)
+            RETURNING did
+        "#,
+    )
+    .bind(format!("-{} seconds", config.stuck_after))
+    .fetch_all(db)
+    .await
+    .context("failed to find stuck migrations")?;
+
+    for did in &stuck {
+        warn!(
+            "migration of {did} has made no progress in {} seconds",
+            config.stuck_after
+        );
+    }
+
+    let total: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM migrations WHERE stuck"#)
+        .fetch_one(db)
+        .await
+        .context("failed to count stuck migrations")?;
+    gauge!(MIGRATIONS_STUCK).set(total as f64);
+
+    Ok(stuck)
+}
+
+/// Periodically check for finished and stuck migrations.
+pub async fn run(config: MigrationConfig, db: Db) {
+    loop {
+        if let Err(e) = check(&config, &db).await {
+            warn!("failed to check migrations: {e:?}");
+        }
+
+        tokio::time::sleep(Duration::from_secs(config.interval)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn steps() {
+        let db = crate::testing::memory_db().await;
+
+        for (did, status) in [("did:plc:alice", "deactivated"), ("did:plc:bob", "active")] {
+            sqlx::query(
+                r#"INSERT INTO accounts (did, email, password, root, plc_root, rev, status) VALUES (?, '', '', '', '', '', ?)"#,
+            )
+            .bind(did)
+            .bind(status)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+
+        // Active accounts aren't migrating.
+        record(&db, "did:plc:bob", Step::BlobImported)
+            .await
+            .unwrap();
+        assert_eq!(status(&db, "did:plc:bob").await.unwrap(), None);
+
+        // A failed import starts the migration, and is cleared by the retry.
+        let alice = "did:plc:alice";
+        fail(&db, alice, Step::RepoImported, "invalid signature")
+            .await
+            .unwrap();
+        let s = status(&db, alice).await.unwrap().unwrap();
+        assert_eq!(s.step, "repoImported");
+        assert_eq!(s.error.as_deref(), Some("invalid signature"));
+        assert_eq!(s.repo_imported_at, None);
+
+        record(&db, alice, Step::RepoImported).await.unwrap();
+        sqlx::query(r#"INSERT INTO repo_stats (did, records, window_start) VALUES (?, 42, 0)"#)
+            .bind(alice)
+            .execute(&db)
+            .await
+            .unwrap();
+        for (cid, record) in [
+            ("bafyone", Some("a")),
+            ("bafytwo", Some("b")),
+            ("bafytwo", Some("c")),
+        ] {
+            sqlx::query(r#"INSERT INTO blob_ref (cid, did, record) VALUES (?, ?, ?)"#)
+                .bind(cid)
+                .bind(alice)
+                .bind(record)
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+
+        let s = status(&db, alice).await.unwrap().unwrap();
+        assert_eq!(s.step, "repoImported");
+        assert_eq!(s.error, None);
+        assert!(s.repo_imported_at.is_some());
+        assert_eq!(
+            (s.indexed_records, s.expected_blobs, s.imported_blobs),
+            (42, 2, 0)
+        );
+
+        record(&db, alice, Step::BlobImported).await.unwrap();
+        record(&db, alice, Step::BlobImported).await.unwrap();
+        record(&db, alice, Step::PreferencesImported).await.unwrap();
+        let s = status(&db, alice).await.unwrap().unwrap();
+        assert_eq!(s.step, "preferencesImported");
+        assert_eq!(s.imported_blobs, 2);
+        assert!(s.preferences_imported_at.is_some());
+
+        // Without progress, the migration is reported as stuck exactly once.
+        let config = MigrationConfig {
+            stuck_after: 0,
+            ..Default::default()
+        };
+        assert_eq!(check(&config, &db).await.unwrap(), [alice]);
+        assert!(check(&config, &db).await.unwrap().is_empty());
+        assert!(status(&db, alice).await.unwrap().unwrap().stuck);
+        assert_eq!(list(&db).await.unwrap().len(), 1);
+
+        // Once activated, the migration is forgotten.
+        sqlx::query(r#"UPDATE accounts SET status = 'active' WHERE did = ?"#)
+            .bind(alice)
+            .execute(&db)
+            .await
+            .unwrap();
+        check(&config, &db).await.unwrap();
+        assert_eq!(status(&db, alice).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,147 @@
+//! Namespaced Identifier (NSID) validation.
+//!
+//! Reference: https://atproto.com/specs/nsid
+
+use thiserror::Error;
+
+/// The maximum length of an entire NSID.
+const MAX_LENGTH: usize = 317;
+/// The maximum length of the domain authority portion of an NSID.
+const MAX_AUTHORITY_LENGTH: usize = 253;
+/// The maximum length of an individual segment.
+const MAX_SEGMENT_LENGTH: usize = 63;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum NsidError {
+    #[error("NSID is longer than {MAX_LENGTH} characters")]
+    TooLong,
+    #[error("NSID must have at least three segments")]
+    TooFewSegments,
+    #[error("NSID authority is longer than {MAX_AUTHORITY_LENGTH} characters")]
+    AuthorityTooLong,
+    #[error("segment {0:?} must be between 1 and {MAX_SEGMENT_LENGTH} characters")]
+    InvalidSegmentLength(String),
+    #[error("authority segment {0:?} is invalid")]
+    InvalidAuthority(String),
+    #[error("name segment {0:?} is invalid")]
+    InvalidName(String),
+    #[error("namespace {0:?} is reserved")]
+    Reserved(String),
+}
+
+/// Validate the syntax of an NSID.
+pub fn validate(nsid: &str) -> Result<(), NsidError> {
+    if nsid.len() > MAX_LENGTH {
+        return Err(NsidError::TooLong);
+    }
+
+    let segments: Vec<&str> = nsid.split('.').collect();
+    let Some((name, authority)) = segments.split_last() else {
+        return Err(NsidError::TooFewSegments);
+    };
+    if segments.len() < 3 {
+        return Err(NsidError::TooFewSegments);
+    }
+
+    // Everything but the name is the authority, including the trailing period.
+    if nsid.len() - name.len() - 1 > MAX_AUTHORITY_LENGTH {
+        return Err(NsidError::AuthorityTooLong);
+    }
+
+    for (i, s) in authority.iter().enumerate() {
+        if s.is_empty() || s.len() > MAX_SEGMENT_LENGTH {
+            return Err(NsidError::InvalidSegmentLength(s.to_string()));
+        }
+
+        // Domain labels: alphanumerics and hyphens, not starting or ending with a hyphen.
+        // The top-level domain (the first segment) may not start with a digit.
+        if !s.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-')
+            || s.starts_with('-')
+            || s.ends_with('-')
+            || (i == 0 && s.starts_with(|c: char| c.is_ascii_digit()))
+        {
+            return Err(NsidError::InvalidAuthority(s.to_string()));
+        }
+    }
+
+    if name.is_empty() || name.len() > MAX_SEGMENT_LENGTH {
+        return Err(NsidError::InvalidSegmentLength(name.to_string()));
+    }
+
+    if !name.bytes().all(|c| c.is_ascii_alphanumeric())
+        || name.starts_with(|c: char| c.is_ascii_digit())
+    {
+        return Err(NsidError::InvalidName(name.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Check whether an NSID falls within one of the reserved namespaces.
+///
+/// Namespaces are either exact NSIDs (`app.example.record`) or prefixes ending in a wildcard
+/// (`com.atproto.*`).
+pub fn check_reserved<S: AsRef<str>>(nsid: &str, reserved: &[S]) -> Result<(), NsidError> {
+    for r in reserved {
+        let r = r.as_ref();
+        let matched = match r.strip_suffix('*') {
+            Some(prefix) => nsid.starts_with(prefix),
+            None => nsid == r,
+        };
+
+        if matched {
+            return Err(NsidError::Reserved(r.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nsid_syntax() {
+        let long_name = format!("com.example.{}", "a".repeat(64));
+        let long_authority = format!("{}.example.name", "a.".repeat(126));
+
+        #[rustfmt::skip]
+        let cases: &[(&str, bool)] = &[
+            ("app.bsky.feed.post",                   true),
+            ("com.example.fooBar",                   true),
+            ("com.example.foo2",                     true),
+            ("net.users.bob.ping",                   true),
+            ("a-0.b-1.c",                            true),
+            ("cn.8.lex.stuff",                       true),
+            ("com.example",                          false),
+            ("com.example.",                         false),
+            ("com..example.foo",                     false),
+            ("com.example.foo-bar",                  false),
+            ("com.example.3foo",                     false),
+            ("com.-example.foo",                     false),
+            ("com.example-.foo",                     false),
+            ("8.example.foo",                        false),
+            ("com.exa_mple.foo",                     false),
+            ("com.example.foo*",                     false),
+            ("com.exämple.foo",                      false),
+            (&long_name,                             false),
+            (&long_authority,                        false),
+        ];
+
+        for (nsid, valid) in cases {
+            assert_eq!(validate(nsid).is_ok(), *valid, "{nsid}");
+        }
+    }
+
+    #[test]
+    fn reserved() {
+        let reserved = ["com.atproto.*", "app.example.blocked"];
+
+        assert!(check_reserved("com.atproto.repo.strongRef", &reserved).is_err());
+        assert!(check_reserved("app.example.blocked", &reserved).is_err());
+        assert!(check_reserved("app.example.blockedNot", &reserved).is_ok());
+        assert!(check_reserved("app.bsky.feed.post", &reserved).is_ok());
+        assert!(check_reserved("com.atprotocol.foo", &reserved).is_ok());
+    }
+}
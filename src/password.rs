@@ -0,0 +1,117 @@
+//! The password policy, applied wherever an account's password is set.
+//!
+//! A password must be at least `password.min_length` characters long, must not be one of the most
+//! common passwords (bundled in `common_passwords.txt`), and must not be the account's handle or
+//! the local part of its email address. App passwords are generated, so they aren't subject to it.
+
+use anyhow::anyhow;
+use axum::http::StatusCode;
+
+use crate::{config::PasswordConfig, error::ErrorMessage, Error, Result};
+
+/// The most common passwords, in lowercase, one per line.
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+
+/// What a password is checked against, besides the policy itself.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Account<'a> {
+    pub handle: Option<&'a str>,
+    pub email: Option<&'a str>,
+}
+
+/// Check a password against the policy, returning an `InvalidPassword` error listing each rule
+/// it breaks.
+pub fn check(config: &PasswordConfig, password: &str, account: Account<'_>) -> Result<()> {
+    let lower = password.to_lowercase();
+    let mut failed = Vec::new();
+
+    if password.chars().count() < config.min_length {
+        failed.push(format!(
+            "must be at least {} characters long",
+            config.min_length
+        ));
+    }
+    if config.reject_common && COMMON_PASSWORDS.lines().any(|p| p == lower) {
+        failed.push("is too common".to_string());
+    }
+    if account
+        .handle
+        .is_some_and(|h| h.eq_ignore_ascii_case(password))
+    {
+        failed.push("must not be the handle".to_string());
+    }
+    if account
+        .email
+        .and_then(|e| e.split_once('@'))
+        .is_some_and(|(local, _)| local.eq_ignore_ascii_case(password))
+    {
+        failed.push("must not be the email address".to_string());
+    }
+
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!("Password {}", failed.join(", and "));
+    Err(Error::with_message(
+        StatusCode::BAD_REQUEST,
+        anyhow!("password rejected: {message}"),
+        ErrorMessage::new("InvalidPassword", message),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use axum::response::IntoResponse;
+
+    use super::*;
+
+    async fn message(e: Error) -> String {
+        let resp = e.into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "InvalidPassword");
+        body["message"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn policy() {
+        let config = PasswordConfig::default();
+        let account = Account {
+            handle: Some("alice.pds.example.com"),
+            email: Some("alice.smith@example.com"),
+        };
+
+        check(&config, "correct horse battery", account).unwrap();
+        assert_eq!(
+            message(check(&config, "x", account).unwrap_err()).await,
+            "Password must be at least 8 characters long"
+        );
+        assert_eq!(
+            message(check(&config, "Password", account).unwrap_err()).await,
+            "Password is too common"
+        );
+        assert_eq!(
+            message(check(&config, "ALICE.pds.example.com", account).unwrap_err()).await,
+            "Password must not be the handle"
+        );
+        assert_eq!(
+            message(check(&config, "alice.smith", account).unwrap_err()).await,
+            "Password must not be the email address"
+        );
+        assert_eq!(
+            message(check(&config, "letmein", account).unwrap_err()).await,
+            "Password must be at least 8 characters long, and is too common"
+        );
+
+        // Both rules can be relaxed.
+        let config = PasswordConfig {
+            min_length: 1,
+            reject_common: false,
+        };
+        check(&config, "letmein", account).unwrap();
+    }
+}
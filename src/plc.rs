@@ -1,11 +1,15 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, str::FromStr};
 
 use anyhow::{bail, Context};
+use atrium_repo::{
+    blockstore::{AsyncBlockStoreRead, AsyncBlockStoreWrite, CarStore, DAG_CBOR, SHA2_256},
+    Cid,
+};
 use base64::Engine;
 use serde::{Deserialize, Serialize};
 use tracing::debug;
 
-use crate::{Client, RotationKey};
+use crate::{config::PlcConfig, Client, RotationKey};
 
 /// The URL of the public PLC directory.
 const PLC_DIRECTORY: &str = "https://plc.directory/";
@@ -63,6 +67,75 @@ pub async fn sign_op(rkey: &RotationKey, op: PlcOperation) -> anyhow::Result<Sig
     Ok(op.sign(bytes))
 }
 
+/// Open the local log of a hosted identity's PLC operations.
+async fn open_log(config: &PlcConfig, did: &str) -> anyhow::Result<CarStore<tokio::fs::File>> {
+    let did_hash = did
+        .strip_prefix("did:plc:")
+        .context("did in unknown format")?;
+    let f = tokio::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(config.path.join(format!("{did_hash}.car")))
+        .await
+        .context("failed to open did doc")?;
+
+    CarStore::open(f)
+        .await
+        .context("failed to open did carstore")
+}
+
+/// Read an operation from the local log of a hosted identity, by its CID (e.g. `plc_root`).
+pub async fn read_op(
+    config: &PlcConfig,
+    did: &str,
+    cid: &str,
+) -> anyhow::Result<SignedPlcOperation> {
+    let mut log = open_log(config, did).await?;
+    let op = log
+        .read_block(Cid::from_str(cid).context("invalid PLC operation CID")?)
+        .await
+        .context("failed to read PLC operation")?;
+
+    serde_ipld_dagcbor::from_slice(&op).context("failed to decode PLC operation")
+}
+
+/// Append an operation to the local log of a hosted identity, returning its CID (to be recorded
+/// as the account's `plc_root`, and as the `prev` of its next operation).
+pub async fn append_op(
+    config: &PlcConfig,
+    did: &str,
+    op: &SignedPlcOperation,
+) -> anyhow::Result<String> {
+    let mut log = open_log(config, did).await?;
+    let bytes = serde_ipld_dagcbor::to_vec(op).context("failed to encode PLC operation")?;
+    let cid = log
+        .write_block(DAG_CBOR, SHA2_256, &bytes)
+        .await
+        .context("failed to write PLC operation")?;
+
+    Ok(cid.to_string())
+}
+
+/// Compute the CID of an operation, as referenced by the `prev` of the operation that follows it.
+pub fn op_cid(op: &SignedPlcOperation) -> anyhow::Result<String> {
+    let bytes = serde_ipld_dagcbor::to_vec(op).context("failed to encode PLC operation")?;
+    Ok(crate::record::block_cid(&bytes).to_string())
+}
+
+/// Fetch the latest operation of an identity from the public directory.
+pub async fn last_op(client: &Client, did: &str) -> anyhow::Result<SignedPlcOperation> {
+    client
+        .get(format!("{PLC_DIRECTORY}{did}/log/last"))
+        .send()
+        .await
+        .context("failed to send directory request")?
+        .error_for_status()
+        .context("directory rejected request")?
+        .json()
+        .await
+        .context("failed to decode PLC operation")
+}
+
 /// Submit a PLC operation to the public directory.
 pub async fn submit(client: &Client, did: &str, op: &SignedPlcOperation) -> anyhow::Result<()> {
     debug!("submitting {} {}", did, serde_json::to_string(&op).unwrap());
@@ -0,0 +1,448 @@
+//! Operator policies for records written to hosted repositories.
+//!
+//! Before a write is committed, each record created or updated is passed to the configured
+//! policies, which may allow it, reject it (failing the write with `InvalidRecord`), or annotate it
+//! with self-labels that are added to the record before it's written. Policies run with a time
+//! budget; one that fails or runs out of time allows or rejects the write, per `policy.fail_open`.
+//!
+//! The built-in policy is a list of [`Rules`], read from a TOML file:
+//!
+//! ```toml
+//! [[rule]]
+//! collection = "app.bsky.actor.profile"  # Or a prefix, e.g. "app.bsky.feed.*". Optional.
+//! field = "description"                  # Only look at this (dotted) field. Optional.
+//! words = ["spam"]                       # Match any of these, case-insensitively. Optional.
+//! reject = "Profiles may not mention spam."
+//!
+//! [[rule]]
+//! collection = "app.bsky.feed.post"
+//! words = ["spoiler"]
+//! label = "spoiler"
+//! ```
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, ensure, Context};
+use axum::http::StatusCode;
+use figment::{
+    providers::{Format, Toml},
+    Figment,
+};
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::{config::PolicyConfig, error::ErrorMessage, Error, Result};
+
+/// A record about to be written.
+#[derive(Debug, Clone, Copy)]
+pub struct RecordWrite<'a> {
+    pub did: &'a str,
+    pub collection: &'a str,
+    pub rkey: &'a str,
+    pub record: &'a Value,
+}
+
+/// What a policy decided about a record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Reject the write, with a message for the client.
+    Reject(String),
+    /// Allow the write, adding these self-labels to the record.
+    Annotate(Vec<String>),
+}
+
+/// A policy for records written to hosted repositories.
+pub trait Policy: Send + Sync {
+    /// A name for the policy, used in logs.
+    fn name(&self) -> &str;
+
+    fn check<'a>(&'a self, write: RecordWrite<'a>) -> BoxFuture<'a, anyhow::Result<Verdict>>;
+}
+
+/// The policies in effect, and how they're run.
+#[derive(Clone)]
+pub struct Policies {
+    policies: Vec<Arc<dyn Policy>>,
+    timeout: Duration,
+    fail_open: bool,
+}
+
+impl Policies {
+    pub fn new(config: &PolicyConfig, policies: Vec<Arc<dyn Policy>>) -> Self {
+        Self {
+            policies,
+            timeout: Duration::from_millis(config.timeout),
+            fail_open: config.fail_open,
+        }
+    }
+
+    /// Set up the configured policies.
+    pub fn load(config: &PolicyConfig) -> anyhow::Result<Self> {
+        let mut policies: Vec<Arc<dyn Policy>> = Vec::new();
+        if let Some(path) = &config.rules {
+            policies.push(Arc::new(Rules::load(path)?));
+        }
+
+        Ok(Self::new(config, policies))
+    }
+
+    /// Check a record against each policy, applying any annotations to it.
+    pub async fn apply(
+        &self,
+        did: &str,
+        collection: &str,
+        rkey: &str,
+        record: &mut Value,
+    ) -> Result<()> {
+        let mut labels = Vec::new();
+        for policy in &self.policies {
+            let write = RecordWrite {
+                did,
+                collection,
+                rkey,
+                record: &*record,
+            };
+
+            let verdict = match tokio::time::timeout(self.timeout, policy.check(write)).await {
+                Ok(Ok(verdict)) => verdict,
+                Ok(Err(e)) => self.failed(policy.as_ref(), e)?,
+                Err(_) => self.failed(policy.as_ref(), anyhow!("timed out"))?,
+            };
+
+            match verdict {
+                Verdict::Allow => {}
+                Verdict::Reject(message) => {
+                    return Err(Error::with_message(
+                        StatusCode::BAD_REQUEST,
+                        anyhow!("policy {} rejected {collection}/{rkey}", policy.name()),
+                        ErrorMessage::new("InvalidRecord", message),
+                    ))
+                }
+                Verdict::Annotate(l) => labels.extend(l),
+            }
+        }
+
+        if !labels.is_empty() {
+            add_self_labels(record, labels);
+        }
+
+        Ok(())
+    }
+
+    /// Handle a policy that failed to reach a verdict.
+    fn failed(&self, policy: &dyn Policy, e: anyhow::Error) -> Result<Verdict> {
+        warn!("policy {} failed: {e:?}", policy.name());
+
+        if self.fail_open {
+            Ok(Verdict::Allow)
+        } else {
+            Err(Error::with_message(
+                StatusCode::SERVICE_UNAVAILABLE,
+                e.context(format!("policy {} failed", policy.name())),
+                ErrorMessage::new("PolicyUnavailable", "record could not be checked"),
+            ))
+        }
+    }
+}
+
+/// Add self-labels to a record, alongside any it already has.
+fn add_self_labels(record: &mut Value, labels: Vec<String>) {
+    let Some(object) = record.as_object_mut() else {
+        return;
+    };
+
+    let existing = object.entry("labels").or_insert_with(|| {
+        json!({
+            "$type": "com.atproto.label.defs#selfLabels",
+            "values": [],
+        })
+    });
+
+    match existing.get_mut("values").and_then(Value::as_array_mut) {
+        Some(values) => {
+            for label in labels {
+                if !values.iter().any(|v| v["val"] == label.as_str()) {
+                    values.push(json!({ "val": label }));
+                }
+            }
+        }
+        // Labels of some other kind; leave them be.
+        None => warn!("not labeling record with unsupported labels {existing}"),
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    /// Reject matching records, with this message.
+    Reject(String),
+    /// Add this self-label to matching records.
+    Label(String),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Rule {
+    /// The collection this rule applies to, or a prefix ending in `*`. All, if unset.
+    collection: Option<String>,
+    /// The (dotted) field to match against. The whole record, if unset.
+    field: Option<String>,
+    /// Match records containing any of these words (case-insensitively). All, if unset.
+    #[serde(default)]
+    words: Vec<String>,
+    #[serde(flatten)]
+    action: Action,
+}
+
+impl Rule {
+    fn matches(&self, write: &RecordWrite) -> bool {
+        let collection = match self.collection.as_deref() {
+            None => true,
+            Some(c) => match c.strip_suffix('*') {
+                Some(prefix) => write.collection.starts_with(prefix),
+                None => write.collection == c,
+            },
+        };
+        if !collection {
+            return false;
+        }
+        if self.words.is_empty() {
+            return true;
+        }
+
+        let value = match &self.field {
+            Some(field) => field.split('.').try_fold(write.record, |v, key| v.get(key)),
+            None => Some(write.record),
+        };
+
+        let mut stack = value.into_iter().collect::<Vec<_>>();
+        while let Some(v) = stack.pop() {
+            match v {
+                Value::String(s) => {
+                    let s = s.to_lowercase();
+                    if self.words.iter().any(|w| s.contains(&w.to_lowercase())) {
+                        return true;
+                    }
+                }
+                Value::Array(a) => stack.extend(a),
+                Value::Object(o) => stack.extend(o.values()),
+                _ => {}
+            }
+        }
+
+        false
+    }
+}
+
+/// The built-in policy: a list of rules, each rejecting or labeling the records it matches.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Rules {
+    #[serde(default, rename = "rule")]
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        ensure!(path.exists(), "policy rules file {path:?} does not exist");
+
+        Figment::new()
+            .merge(Toml::file(path))
+            .extract()
+            .with_context(|| format!("failed to load policy rules from {path:?}"))
+    }
+
+    fn verdict(&self, write: &RecordWrite) -> Verdict {
+        let mut labels = Vec::new();
+        for rule in self.rules.iter().filter(|r| r.matches(write)) {
+            match &rule.action {
+                Action::Reject(message) => return Verdict::Reject(message.clone()),
+                Action::Label(label) => labels.push(label.clone()),
+            }
+        }
+
+        if labels.is_empty() {
+            Verdict::Allow
+        } else {
+            Verdict::Annotate(labels)
+        }
+    }
+}
+
+impl Policy for Rules {
+    fn name(&self) -> &str {
+        "rules"
+    }
+
+    fn check<'a>(&'a self, write: RecordWrite<'a>) -> BoxFuture<'a, anyhow::Result<Verdict>> {
+        Box::pin(async move { Ok(self.verdict(&write)) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rules() -> Rules {
+        Figment::new()
+            .merge(Toml::string(
+                r#"
+                [[rule]]
+                collection = "app.bsky.actor.profile"
+                field = "description"
+                words = ["Spam"]
+                reject = "Profiles may not mention spam."
+
+                [[rule]]
+                collection = "app.bsky.feed.*"
+                words = ["spoiler"]
+                label = "spoiler"
+                "#,
+            ))
+            .extract()
+            .unwrap()
+    }
+
+    fn config(fail_open: bool) -> PolicyConfig {
+        PolicyConfig {
+            rules: None,
+            timeout: 50,
+            fail_open,
+        }
+    }
+
+    #[tokio::test]
+    async fn reject() {
+        let policies = Policies::new(&config(true), vec![Arc::new(rules())]);
+
+        let mut profile = json!({ "displayName": "spam", "description": "I love SPAM" });
+        assert!(policies
+            .apply(
+                "did:plc:alice",
+                "app.bsky.actor.profile",
+                "self",
+                &mut profile
+            )
+            .await
+            .is_err());
+
+        // Only the configured field is checked.
+        let mut profile = json!({ "displayName": "spam", "description": "hi" });
+        let before = profile.clone();
+        policies
+            .apply(
+                "did:plc:alice",
+                "app.bsky.actor.profile",
+                "self",
+                &mut profile,
+            )
+            .await
+            .unwrap();
+        assert_eq!(profile, before);
+    }
+
+    #[tokio::test]
+    async fn annotate() {
+        let policies = Policies::new(&config(true), vec![Arc::new(rules())]);
+
+        let mut post = json!({ "text": "Spoiler: it was a sled" });
+        policies
+            .apply(
+                "did:plc:alice",
+                "app.bsky.feed.post",
+                "3l3qo2vutsw2b",
+                &mut post,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            post["labels"],
+            json!({
+                "$type": "com.atproto.label.defs#selfLabels",
+                "values": [{ "val": "spoiler" }],
+            })
+        );
+
+        // Existing self-labels are kept, and not duplicated.
+        let mut post = json!({
+            "text": "spoiler",
+            "labels": {
+                "$type": "com.atproto.label.defs#selfLabels",
+                "values": [{ "val": "nudity" }, { "val": "spoiler" }],
+            },
+        });
+        policies
+            .apply(
+                "did:plc:alice",
+                "app.bsky.feed.post",
+                "3l3qo2vutsw2c",
+                &mut post,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            post["labels"]["values"],
+            json!([{ "val": "nudity" }, { "val": "spoiler" }])
+        );
+
+        // Other collections are left alone.
+        let mut like = json!({ "text": "spoiler" });
+        policies
+            .apply(
+                "did:plc:alice",
+                "app.bsky.graph.list",
+                "3l3qo2vutsw2d",
+                &mut like,
+            )
+            .await
+            .unwrap();
+        assert!(like.get("labels").is_none());
+    }
+
+    struct Slow;
+
+    impl Policy for Slow {
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        fn check<'a>(&'a self, _: RecordWrite<'a>) -> BoxFuture<'a, anyhow::Result<Verdict>> {
+            Box::pin(async {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                Ok(Verdict::Reject("too late".to_string()))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let mut post = json!({ "text": "hello" });
+
+        // A policy that runs out of time allows the write...
+        let policies = Policies::new(&config(true), vec![Arc::new(Slow), Arc::new(rules())]);
+        policies
+            .apply(
+                "did:plc:alice",
+                "app.bsky.feed.post",
+                "3l3qo2vutsw2b",
+                &mut post,
+            )
+            .await
+            .unwrap();
+
+        // ...unless configured to fail closed.
+        let policies = Policies::new(&config(false), vec![Arc::new(Slow)]);
+        let start = std::time::Instant::now();
+        assert!(policies
+            .apply(
+                "did:plc:alice",
+                "app.bsky.feed.post",
+                "3l3qo2vutsw2b",
+                &mut post
+            )
+            .await
+            .is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,452 @@
+//! Service proxying to the AppView and other atproto services.
+//!
+//! Upstream responses, including XRPC errors, are returned to the requester as-is. Only failures to
+//! reach the upstream service at all are reported as errors from this PDS.
+//!
+//! The labelers a client asks to be applied (`atproto-accept-labelers`) are forwarded unmodified,
+//! and the labelers the upstream service applied (`atproto-content-labelers`) are reflected back,
+//! along with this PDS's own labeler if one is configured.
+//!
+//! Reference: https://atproto.com/specs/xrpc#service-proxying
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+use atrium_api::types::string::Did;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, Response, StatusCode, Uri},
+};
+use rand::Rng;
+
+use crate::{
+    auth::{self, AuthenticatedUser},
+    config::AppConfig,
+    did::DidCache,
+    error::ErrorMessage,
+    keys, Client, Db, Error, Result, SigningKey,
+};
+
+/// Headers that only apply to a single connection, and are not forwarded to the requester.
+///
+/// Reference: https://www.rfc-editor.org/rfc/rfc9110#section-7.6.1
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Request headers forwarded to the upstream service.
+const FORWARDED: &[&str] = &["atproto-accept-labelers", "content-type"];
+
+/// The response header listing the labelers applied to the content of a response.
+const CONTENT_LABELERS: &str = "atproto-content-labelers";
+
+/// Select the request headers forwarded to the upstream service, with all of their values.
+fn forwarded_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut h = HeaderMap::new();
+    for name in FORWARDED {
+        for value in headers.get_all(*name) {
+            h.append(*name, value.clone());
+        }
+    }
+    h
+}
+
+/// Add a labeler to the `atproto-content-labelers` header, unless it's already listed.
+fn append_labeler(headers: &mut HeaderMap, labeler: &str) -> Result<()> {
+    let existing = headers
+        .get_all(CONTENT_LABELERS)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<_>>();
+
+    // Entries may carry parameters (e.g. `did:plc:xyz;redact`).
+    if existing
+        .iter()
+        .any(|v| v.split(';').next().map(str::trim) == Some(labeler))
+    {
+        return Ok(());
+    }
+
+    let value = existing
+        .into_iter()
+        .chain([labeler])
+        .collect::<Vec<_>>()
+        .join(", ");
+    let value = HeaderValue::from_str(&value).context("invalid labeler")?;
+    headers.insert(CONTENT_LABELERS, value);
+
+    Ok(())
+}
+
+/// Report a failure to reach an upstream service.
+fn upstream_failure(err: reqwest::Error) -> Error {
+    // Only name the upstream service in debug builds.
+    let message = match err.url().and_then(|u| u.host_str()) {
+        Some(host) if cfg!(debug_assertions) => format!("failed to reach upstream service {host}"),
+        _ => "failed to reach upstream service".to_string(),
+    };
+
+    Error::with_message(
+        StatusCode::BAD_GATEWAY,
+        anyhow::Error::new(err).context("failed to send request"),
+        ErrorMessage::new("UpstreamFailure", message),
+    )
+}
+
+/// Send a request upstream, and return its response (successful or not) to the requester.
+///
+/// If this PDS operates a labeler, it's listed among the labelers applied to the response.
+async fn forward(
+    request: reqwest::RequestBuilder,
+    labeler: Option<&str>,
+) -> Result<Response<Body>> {
+    let r = request.send().await.map_err(upstream_failure)?;
+
+    let mut resp = Response::builder().status(r.status());
+    if let Some(hdrs) = resp.headers_mut() {
+        *hdrs = r.headers().clone();
+        for name in HOP_BY_HOP {
+            hdrs.remove(*name);
+        }
+
+        if let Some(labeler) = labeler {
+            append_labeler(hdrs, labeler)?;
+        }
+    }
+
+    let resp = resp
+        .body(Body::from_stream(r.bytes_stream()))
+        .context("failed to construct response")?;
+
+    Ok(resp)
+}
+
+/// Service proxy.
+pub async fn service_proxy(
+    url: Uri,
+    user: AuthenticatedUser,
+    State(config): State<AppConfig>,
+    State(db): State<Db>,
+    State(skey): State<SigningKey>,
+    State(client): State<reqwest::Client>,
+    State(cache): State<DidCache>,
+    headers: HeaderMap,
+    request: Request<Body>,
+) -> Result<Response<Body>> {
+    let url_path = url.path_and_query().context("invalid service proxy url")?;
+    let lxm = url_path
+        .path()
+        .strip_prefix("/")
+        .with_context(|| format!("invalid service proxy url prefix: {}", url_path.path()))?;
+
+    // Direct messages are out of reach of unprivileged app passwords.
+    if lxm.starts_with("chat.bsky.") {
+        user.require_privileged()?;
+    }
+
+    let user_did = user.did();
+    let (did, id) = match headers.get("atproto-proxy") {
+        Some(val) => {
+            let val =
+                std::str::from_utf8(val.as_bytes()).context("proxy header not valid utf-8")?;
+
+            let (did, id) = val.split_once('#').context("invalid proxy header")?;
+
+            let did =
+                Did::from_str(did).map_err(|e| anyhow!("atproto proxy not a valid DID: {e}"))?;
+
+            (did, format!("#{id}"))
+        }
+        // HACK: Assume the bluesky appview by default.
+        None => (
+            Did::new("did:web:api.bsky.app".to_string()).unwrap(),
+            "#bsky_appview".to_string(),
+        ),
+    };
+
+    let did_doc = cache
+        .resolve(
+            &Client::new(client.clone(), []),
+            &config,
+            &db,
+            did.clone(),
+            false,
+        )
+        .await
+        .with_context(|| format!("failed to resolve did document {}", did.as_str()))?
+        .doc;
+
+    let service = match did_doc.service.iter().find(|s| s.id == id) {
+        Some(service) => service,
+        None => {
+            return Err(Error::with_status(
+                StatusCode::BAD_REQUEST,
+                anyhow!("could not find resolve service #{id}"),
+            ))
+        }
+    };
+
+    let url = service
+        .service_endpoint
+        .join(&format!("/xrpc{}", url_path))
+        .context("failed to construct target url")?;
+
+    let exp = (chrono::Utc::now() + std::time::Duration::from_secs(60)).timestamp();
+    let jti = rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(10)
+        .map(char::from)
+        .collect::<String>();
+
+    // Mint a bearer token by signing a JSON web token, with the key in the account's DID document.
+    // https://github.com/DavidBuchanan314/millipds/blob/5c7529a739d394e223c0347764f1cf4e8fd69f94/src/millipds/appview_proxy.py#L47-L59
+    let skey = keys::account_key(&db, &skey, &user_did).await?;
+    let token = auth::sign(
+        &skey,
+        "JWT",
+        serde_json::json!({
+            "iss": user_did.as_str(),
+            "aud": did.as_str(),
+            "lxm": lxm,
+            "exp": exp,
+            "jti": jti,
+        }),
+    )
+    .context("failed to sign jwt")?;
+
+    forward(
+        client
+            .request(request.method().clone(), url)
+            .headers(forwarded_headers(request.headers()))
+            .header(axum::http::header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(reqwest::Body::wrap_stream(
+                request.into_body().into_data_stream(),
+            )),
+        config.labeler.as_deref(),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{http, response::IntoResponse, routing::get, Router};
+
+    use super::*;
+
+    const BLOCKED: &str = r#"{"error":"BlockedActor","message":"Requester has blocked actor"}"#;
+
+    async fn mock_upstream() -> String {
+        let app = Router::new()
+            .route(
+                "/blocked",
+                get(|| async {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        [(
+                            http::header::CONTENT_TYPE,
+                            "application/json; charset=utf-8",
+                        )],
+                        BLOCKED,
+                    )
+                }),
+            )
+            .route(
+                "/limited",
+                get(|| async {
+                    (
+                        StatusCode::TOO_MANY_REQUESTS,
+                        [
+                            ("content-type", "application/json"),
+                            ("ratelimit-limit", "3000"),
+                            ("ratelimit-remaining", "0"),
+                            ("ratelimit-reset", "1700000000"),
+                        ],
+                        r#"{"error":"RateLimitExceeded","message":"Rate Limit Exceeded"}"#,
+                    )
+                }),
+            )
+            .route(
+                "/broken",
+                get(|| async {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        [("content-type", "text/plain")],
+                        "\tnot even json\n",
+                    )
+                }),
+            );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn passthrough() {
+        let base = mock_upstream().await;
+        let client = reqwest::Client::new();
+
+        for (path, status, body, content_type) in [
+            (
+                "/blocked",
+                StatusCode::BAD_REQUEST,
+                BLOCKED,
+                "application/json; charset=utf-8",
+            ),
+            (
+                "/limited",
+                StatusCode::TOO_MANY_REQUESTS,
+                r#"{"error":"RateLimitExceeded","message":"Rate Limit Exceeded"}"#,
+                "application/json",
+            ),
+            (
+                "/broken",
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "\tnot even json\n",
+                "text/plain",
+            ),
+        ] {
+            let resp = forward(client.get(format!("{base}{path}")), None)
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), status, "{path}");
+            assert_eq!(resp.headers()[http::header::CONTENT_TYPE], content_type);
+            if path == "/limited" {
+                assert_eq!(resp.headers()["ratelimit-limit"], "3000");
+                assert_eq!(resp.headers()["ratelimit-remaining"], "0");
+                assert_eq!(resp.headers()["ratelimit-reset"], "1700000000");
+            }
+
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(bytes, body.as_bytes(), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        // Bind and immediately drop a listener, so that nothing is listening on the port.
+        let addr = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let err = forward(reqwest::Client::new().get(format!("http://{addr}/")), None)
+            .await
+            .unwrap_err();
+        let resp = err.into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "UpstreamFailure");
+        assert_eq!(
+            body["message"],
+            "failed to reach upstream service 127.0.0.1"
+        );
+    }
+
+    #[tokio::test]
+    async fn labelers() {
+        // An upstream service that applies the labelers it was asked to.
+        let app = Router::new().route(
+            "/",
+            get(|headers: HeaderMap| async move {
+                let mut h = HeaderMap::new();
+                for value in headers.get_all("atproto-accept-labelers") {
+                    h.append(CONTENT_LABELERS, value.clone());
+                }
+                h
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut request = HeaderMap::new();
+        request.append(
+            "atproto-accept-labelers",
+            HeaderValue::from_static("did:plc:ar7c4by46qjdydhdevvrndac;redact"),
+        );
+        request.append(
+            "atproto-accept-labelers",
+            HeaderValue::from_static("did:plc:labeler2"),
+        );
+        request.insert("x-unrelated", HeaderValue::from_static("1"));
+
+        // The accepted labelers are forwarded unmodified, and nothing else.
+        let forwarded = forwarded_headers(&request);
+        assert_eq!(
+            forwarded
+                .get_all("atproto-accept-labelers")
+                .iter()
+                .collect::<Vec<_>>(),
+            [
+                "did:plc:ar7c4by46qjdydhdevvrndac;redact",
+                "did:plc:labeler2"
+            ]
+        );
+        assert!(!forwarded.contains_key("x-unrelated"));
+
+        let client = reqwest::Client::new();
+        let send = |labeler| {
+            forward(
+                client
+                    .get(format!("http://{addr}/"))
+                    .headers(forwarded.clone()),
+                labeler,
+            )
+        };
+
+        // The applied labelers are reflected back to the client.
+        let resp = send(None).await.unwrap();
+        assert_eq!(
+            resp.headers()
+                .get_all(CONTENT_LABELERS)
+                .iter()
+                .collect::<Vec<_>>(),
+            [
+                "did:plc:ar7c4by46qjdydhdevvrndac;redact",
+                "did:plc:labeler2"
+            ]
+        );
+
+        // This PDS's own labeler is appended.
+        let resp = send(Some("did:web:labeler.pds.example.com")).await.unwrap();
+        assert_eq!(
+            resp.headers()[CONTENT_LABELERS],
+            "did:plc:ar7c4by46qjdydhdevvrndac;redact, did:plc:labeler2, did:web:labeler.pds.example.com"
+        );
+
+        // ... but not twice.
+        let resp = send(Some("did:plc:labeler2")).await.unwrap();
+        assert_eq!(
+            resp.headers()
+                .get_all(CONTENT_LABELERS)
+                .iter()
+                .collect::<Vec<_>>(),
+            [
+                "did:plc:ar7c4by46qjdydhdevvrndac;redact",
+                "did:plc:labeler2"
+            ]
+        );
+    }
+}
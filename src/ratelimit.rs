@@ -0,0 +1,934 @@
+//! Rate limiting primitives.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use axum::{
+    extract::{ConnectInfo, FromRef, FromRequestParts},
+    http::{request::Parts, HeaderMap, HeaderValue, StatusCode},
+};
+use metrics::{counter, gauge};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    auth::{AuthenticatedUser, Revocations},
+    config::{AppConfig, RateLimitConfig},
+    error::{Error, ErrorMessage},
+    metrics::{AUTH_LOGIN_THROTTLED, SIGNUP_THROTTLED, SYNC_EXPENSIVE_OPS},
+    Db, SigningKey,
+};
+
+/// Points consumed by a record creation.
+pub const POINTS_CREATE: u32 = 3;
+/// Points consumed by a record update.
+pub const POINTS_UPDATE: u32 = 2;
+/// Points consumed by a record deletion.
+pub const POINTS_DELETE: u32 = 1;
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A fixed window of consumed points.
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    /// The time at which this window began.
+    start: Instant,
+    /// The number of points consumed within this window.
+    consumed: u32,
+}
+
+impl Window {
+    fn new(now: Instant) -> Self {
+        Self {
+            start: now,
+            consumed: 0,
+        }
+    }
+
+    /// Return the window as of `now`, starting a new one if the current window has elapsed.
+    fn current(self, now: Instant, len: Duration) -> Self {
+        if now.duration_since(self.start) >= len {
+            Self::new(now)
+        } else {
+            self
+        }
+    }
+}
+
+/// How often a limiter's tracked entries may be pruned of expired ones. Pruning scans every entry,
+/// so once there are enough to be worth pruning, it's amortized over the requests in between
+/// rather than run on each.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The entries a limiter tracks, e.g. per-client budgets, which are pruned of expired ones every
+/// so often.
+#[derive(Debug)]
+struct Entries<K, V> {
+    map: HashMap<K, V>,
+    /// The last time the entries were pruned.
+    pruned: Option<Instant>,
+}
+
+impl<K, V> Default for Entries<K, V> {
+    fn default() -> Self {
+        Self {
+            map: HashMap::new(),
+            pruned: None,
+        }
+    }
+}
+
+impl<K, V> std::ops::Deref for Entries<K, V> {
+    type Target = HashMap<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+impl<K, V> std::ops::DerefMut for Entries<K, V> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.map
+    }
+}
+
+impl<K, V> Entries<K, V> {
+    /// Keep only the entries for which `keep` returns true, if there are at least `threshold` of
+    /// them and they haven't been pruned within the last [`PRUNE_INTERVAL`].
+    fn prune(&mut self, now: Instant, threshold: usize, keep: impl FnMut(&K, &mut V) -> bool) {
+        if self.map.len() < threshold
+            || self
+                .pruned
+                .is_some_and(|t| now.duration_since(t) < PRUNE_INTERVAL)
+        {
+            return;
+        }
+
+        self.map.retain(keep);
+        self.pruned = Some(now);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Budget {
+    hour: Window,
+    day: Window,
+}
+
+/// The remaining budget within a single window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BudgetStatus {
+    /// The total number of points available within the window.
+    pub limit: u32,
+    /// The number of points remaining within the window.
+    pub remaining: u32,
+    /// The length of the window.
+    pub window: Duration,
+    /// The time until the window resets.
+    pub reset: Duration,
+}
+
+impl BudgetStatus {
+    fn new(w: Window, limit: u32, len: Duration, now: Instant) -> Self {
+        Self {
+            limit,
+            remaining: limit.saturating_sub(w.consumed),
+            window: len,
+            reset: len.saturating_sub(now.duration_since(w.start)),
+        }
+    }
+
+    /// Headers describing this budget, as emitted by the reference PDS.
+    fn headers(&self) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        let reset = (chrono::Utc::now() + self.reset).timestamp();
+
+        h.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        h.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        h.insert("ratelimit-reset", HeaderValue::from(reset));
+        h.insert(
+            "ratelimit-policy",
+            HeaderValue::from_str(&format!("{};w={}", self.limit, self.window.as_secs())).unwrap(),
+        );
+        h
+    }
+}
+
+/// The write budgets for an individual account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteBudget {
+    pub hourly: BudgetStatus,
+    pub daily: BudgetStatus,
+}
+
+/// A per-DID limiter for repository writes.
+///
+/// Writes are charged in points, matching the reference implementation: creates cost 3 points,
+/// updates cost 2, and deletes cost 1. Each account has an hourly and a daily point budget.
+///
+/// This only applies to writes made by users themselves. Administrative operations bypass it.
+#[derive(Clone, Debug)]
+pub struct WriteLimiter {
+    hourly: u32,
+    daily: u32,
+    budgets: Arc<Mutex<Entries<String, Budget>>>,
+}
+
+impl WriteLimiter {
+    /// The number of tracked accounts past which expired budgets are pruned.
+    const PRUNE_THRESHOLD: usize = 10_000;
+
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            hourly: config.write_points_hourly,
+            daily: config.write_points_daily,
+            budgets: Arc::new(Mutex::new(Entries::default())),
+        }
+    }
+
+    /// Query the remaining write budget for an account without consuming anything.
+    pub fn budget(&self, did: &str) -> WriteBudget {
+        self.budget_at(did, Instant::now())
+    }
+
+    fn budget_at(&self, did: &str, now: Instant) -> WriteBudget {
+        let budgets = self.budgets.lock().unwrap();
+        let (hour, day) = match budgets.get(did) {
+            Some(b) => (b.hour.current(now, HOUR), b.day.current(now, DAY)),
+            None => (Window::new(now), Window::new(now)),
+        };
+
+        WriteBudget {
+            hourly: BudgetStatus::new(hour, self.hourly, HOUR, now),
+            daily: BudgetStatus::new(day, self.daily, DAY, now),
+        }
+    }
+
+    /// Consume `points` from an account's write budget.
+    ///
+    /// Returns a `RateLimitExceeded` error (and consumes nothing) if either budget would be exceeded.
+    pub fn consume(&self, did: &str, points: u32) -> Result<WriteBudget, Error> {
+        self.consume_at(did, points, Instant::now())
+    }
+
+    fn consume_at(&self, did: &str, points: u32, now: Instant) -> Result<WriteBudget, Error> {
+        let mut budgets = self.budgets.lock().unwrap();
+        budgets.prune(now, Self::PRUNE_THRESHOLD, |_, b| {
+            now.duration_since(b.day.start) < DAY
+        });
+
+        let budget = budgets.entry(did.to_string()).or_insert(Budget {
+            hour: Window::new(now),
+            day: Window::new(now),
+        });
+
+        budget.hour = budget.hour.current(now, HOUR);
+        budget.day = budget.day.current(now, DAY);
+
+        for (w, limit, len) in [
+            (budget.hour, self.hourly, HOUR),
+            (budget.day, self.daily, DAY),
+        ] {
+            if w.consumed.saturating_add(points) > limit {
+                let status = BudgetStatus::new(w, limit, len, now);
+
+                return Err(Error::with_message(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    anyhow!("write budget exceeded for {did}"),
+                    ErrorMessage::new("RateLimitExceeded", "Rate Limit Exceeded"),
+                )
+                .with_headers(status.headers()));
+            }
+        }
+
+        budget.hour.consumed += points;
+        budget.day.consumed += points;
+
+        Ok(WriteBudget {
+            hourly: BudgetStatus::new(budget.hour, self.hourly, HOUR, now),
+            daily: BudgetStatus::new(budget.day, self.daily, DAY, now),
+        })
+    }
+}
+
+/// The client making a request: the authenticated account if there is one, or else the IP address
+/// the request came from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClientId {
+    Did(String),
+    Ip(IpAddr),
+}
+
+impl ClientId {
+    /// The class of client, as used to label metrics.
+    pub fn class(&self) -> &'static str {
+        match self {
+            ClientId::Did(_) => "did",
+            ClientId::Ip(_) => "ip",
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for ClientId
+where
+    S: Send + Sync,
+    SigningKey: FromRef<S>,
+    Db: FromRef<S>,
+    Revocations: FromRef<S>,
+    AppConfig: FromRef<S>,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // An invalid token isn't an error here; the client is just identified by its address.
+        if parts
+            .headers
+            .contains_key(axum::http::header::AUTHORIZATION)
+        {
+            if let Ok(user) = AuthenticatedUser::from_request_parts(parts, state).await {
+                return Ok(ClientId::Did(user.did()));
+            }
+        }
+
+        let config = AppConfig::from_ref(state);
+        Ok(ClientId::Ip(client_ip(parts, &config.rate_limit)))
+    }
+}
+
+/// The IP address a request came from (see [`resolve_client_ip`]), or the unspecified address if
+/// it isn't known (e.g. in tests, which don't serve over a socket).
+fn client_ip(parts: &Parts, config: &RateLimitConfig) -> IpAddr {
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    resolve_client_ip(peer, &parts.headers, config)
+}
+
+/// Resolve the address of the client behind a connection from `peer`.
+///
+/// The client IP header is only believed for connections from a trusted proxy: anyone else could
+/// claim any address in it. Each proxy appends the address it received the request from, so the
+/// client is the nearest address that isn't itself a trusted proxy. Anything further along was
+/// supplied by the client, and is ignored.
+fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap, config: &RateLimitConfig) -> IpAddr {
+    let trusted = |ip: IpAddr| config.trusted_proxies.iter().any(|r| r.contains(ip));
+    if !trusted(peer) {
+        return peer;
+    }
+
+    let hops = headers
+        .get_all(config.client_ip_header.as_str())
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+
+    let mut ip = peer;
+    for hop in hops.into_iter().rev() {
+        // A malformed hop can't be attributed to anyone; settle for the last proxy that we trust.
+        let Ok(hop) = hop.parse::<IpAddr>() else {
+            break;
+        };
+        ip = hop;
+        if !trusted(hop) {
+            break;
+        }
+    }
+    ip
+}
+
+/// The IP address a request came from, regardless of any authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientIp(pub IpAddr);
+
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+    AppConfig: FromRef<S>,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let config = AppConfig::from_ref(state);
+        Ok(ClientIp(client_ip(parts, &config.rate_limit)))
+    }
+}
+
+/// A per-IP limiter for account creation, with an hourly and a daily budget of signups.
+///
+/// Every attempt counts, whether or not it succeeds, so that an address can't probe for free.
+#[derive(Clone, Debug)]
+pub struct SignupLimiter {
+    hourly: u32,
+    daily: u32,
+    budgets: Arc<Mutex<Entries<IpAddr, Budget>>>,
+}
+
+impl SignupLimiter {
+    /// The number of tracked addresses past which expired budgets are pruned.
+    const PRUNE_THRESHOLD: usize = 10_000;
+
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            hourly: config.signups_hourly,
+            daily: config.signups_daily,
+            budgets: Arc::new(Mutex::new(Entries::default())),
+        }
+    }
+
+    /// Consume a signup attempt from an address's budget, returning a `RateLimitExceeded` error
+    /// (and consuming nothing) if either budget is spent.
+    pub fn consume(&self, ip: IpAddr) -> Result<(), Error> {
+        self.consume_at(ip, Instant::now())
+    }
+
+    fn consume_at(&self, ip: IpAddr, now: Instant) -> Result<(), Error> {
+        let mut budgets = self.budgets.lock().unwrap();
+        budgets.prune(now, Self::PRUNE_THRESHOLD, |_, b| {
+            now.duration_since(b.day.start) < DAY
+        });
+
+        let budget = budgets.entry(ip).or_insert(Budget {
+            hour: Window::new(now),
+            day: Window::new(now),
+        });
+        budget.hour = budget.hour.current(now, HOUR);
+        budget.day = budget.day.current(now, DAY);
+
+        for (w, limit, len) in [
+            (budget.hour, self.hourly, HOUR),
+            (budget.day, self.daily, DAY),
+        ] {
+            if w.consumed >= limit {
+                counter!(SIGNUP_THROTTLED).increment(1);
+
+                let status = BudgetStatus::new(w, limit, len, now);
+                let mut headers = status.headers();
+                headers.insert(
+                    axum::http::header::RETRY_AFTER,
+                    HeaderValue::from(status.reset.as_secs().max(1)),
+                );
+                return Err(Error::with_message(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    anyhow!("signup budget exceeded for {ip}"),
+                    ErrorMessage::new(
+                        "RateLimitExceeded",
+                        "Too many accounts created; try again later",
+                    ),
+                )
+                .with_headers(headers));
+            }
+        }
+
+        budget.hour.consumed += 1;
+        budget.day.consumed += 1;
+        Ok(())
+    }
+}
+
+/// What a [`LoginLimiter`] counts failures against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum LoginKey {
+    Account(String),
+    Ip(IpAddr),
+}
+
+impl LoginKey {
+    fn class(&self) -> &'static str {
+        match self {
+            LoginKey::Account(_) => "account",
+            LoginKey::Ip(_) => "ip",
+        }
+    }
+}
+
+/// Brute-force protection for sign-ins.
+///
+/// Failed sign-ins are counted against both the account identifier and the IP address they came
+/// from, in fixed windows. Once either has too many failures within its window, further attempts
+/// are rejected with `RateLimitExceeded` without checking the password at all, until the window
+/// resets. A successful sign-in clears the account's failures, but not the address's.
+#[derive(Clone, Debug)]
+pub struct LoginLimiter {
+    account_limit: u32,
+    ip_limit: u32,
+    window: Duration,
+    failures: Arc<Mutex<Entries<LoginKey, Window>>>,
+}
+
+impl LoginLimiter {
+    /// The number of tracked keys past which expired windows are pruned.
+    const PRUNE_THRESHOLD: usize = 10_000;
+
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            account_limit: config.login_failures_account,
+            ip_limit: config.login_failures_ip,
+            window: Duration::from_secs(config.login_window),
+            failures: Arc::new(Mutex::new(Entries::default())),
+        }
+    }
+
+    fn keys(identifier: &str, ip: IpAddr) -> [LoginKey; 2] {
+        [
+            LoginKey::Account(identifier.trim().to_lowercase()),
+            LoginKey::Ip(ip),
+        ]
+    }
+
+    fn limit(&self, key: &LoginKey) -> u32 {
+        match key {
+            LoginKey::Account(_) => self.account_limit,
+            LoginKey::Ip(_) => self.ip_limit,
+        }
+    }
+
+    /// Check whether a sign-in for `identifier` from `ip` may be attempted, returning a
+    /// `RateLimitExceeded` error (with a `Retry-After` header) if not.
+    pub fn check(&self, identifier: &str, ip: IpAddr) -> Result<(), Error> {
+        self.check_at(identifier, ip, Instant::now())
+    }
+
+    fn check_at(&self, identifier: &str, ip: IpAddr, now: Instant) -> Result<(), Error> {
+        let failures = self.failures.lock().unwrap();
+        for key in Self::keys(identifier, ip) {
+            let Some(w) = failures.get(&key) else {
+                continue;
+            };
+            let w = w.current(now, self.window);
+            if w.consumed < self.limit(&key) {
+                continue;
+            }
+
+            counter!(AUTH_LOGIN_THROTTLED, "key" => key.class()).increment(1);
+
+            let reset = self
+                .window
+                .saturating_sub(now.duration_since(w.start))
+                .as_secs()
+                .max(1);
+            let mut headers = HeaderMap::new();
+            headers.insert(axum::http::header::RETRY_AFTER, HeaderValue::from(reset));
+            return Err(Error::with_message(
+                StatusCode::TOO_MANY_REQUESTS,
+                anyhow!("too many failed sign-ins for {key:?}"),
+                ErrorMessage::new(
+                    "RateLimitExceeded",
+                    "Too many failed sign-in attempts; try again later",
+                ),
+            )
+            .with_headers(headers));
+        }
+
+        Ok(())
+    }
+
+    /// Record a failed sign-in for `identifier` from `ip`.
+    pub fn fail(&self, identifier: &str, ip: IpAddr) {
+        self.fail_at(identifier, ip, Instant::now());
+    }
+
+    fn fail_at(&self, identifier: &str, ip: IpAddr, now: Instant) {
+        let mut failures = self.failures.lock().unwrap();
+        let window = self.window;
+        failures.prune(now, Self::PRUNE_THRESHOLD, |_, w| {
+            now.duration_since(w.start) < window
+        });
+
+        for key in Self::keys(identifier, ip) {
+            let w = failures.entry(key).or_insert(Window::new(now));
+            *w = w.current(now, self.window);
+            w.consumed = w.consumed.saturating_add(1);
+        }
+    }
+
+    /// Record a successful sign-in for `identifier`, clearing its failures.
+    pub fn succeed(&self, identifier: &str) {
+        self.failures
+            .lock()
+            .unwrap()
+            .remove(&LoginKey::Account(identifier.trim().to_lowercase()));
+    }
+}
+
+type Slots = Arc<Mutex<HashMap<ClientId, Arc<Semaphore>>>>;
+
+/// A per-client limit on concurrent expensive sync operations (e.g. `getRepo`).
+///
+/// This is independent of the request rate: it stops a single client from saturating storage
+/// bandwidth with many parallel exports. An operation past the limit waits briefly for one of the
+/// client's others to finish, and is otherwise rejected with `RateLimitExceeded`.
+#[derive(Clone, Debug)]
+pub struct SyncLimiter {
+    concurrency: usize,
+    timeout: Duration,
+    clients: Slots,
+}
+
+/// A slot held by an expensive sync operation, released when dropped.
+#[derive(Debug)]
+pub struct SyncPermit {
+    client: ClientId,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+    clients: Slots,
+}
+
+/// Release the caller's reference to a client's slots, forgetting the client if it has no other
+/// operations in flight or waiting (i.e. the only references are the caller's and the map's).
+fn release(clients: &Slots, client: &ClientId, semaphore: &Arc<Semaphore>) {
+    let mut clients = clients.lock().unwrap();
+    if Arc::strong_count(&semaphore) == 2 {
+        clients.remove(client);
+    }
+}
+
+impl Drop for SyncPermit {
+    fn drop(&mut self) {
+        gauge!(SYNC_EXPENSIVE_OPS, "class" => self.client.class()).decrement(1);
+
+        drop(self.permit.take());
+        release(&self.clients, &self.client, &self.semaphore);
+    }
+}
+
+impl SyncLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self::with_limits(
+            config.sync_concurrency,
+            Duration::from_millis(config.sync_queue_timeout),
+        )
+    }
+
+    fn with_limits(concurrency: usize, timeout: Duration) -> Self {
+        Self {
+            concurrency: concurrency.max(1),
+            timeout,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Acquire a slot for an expensive operation on behalf of a client, waiting briefly if all of
+    /// the client's slots are taken.
+    pub async fn acquire(&self, client: &ClientId) -> Result<SyncPermit, Error> {
+        let semaphore = self
+            .clients
+            .lock()
+            .unwrap()
+            .entry(client.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.concurrency)))
+            .clone();
+
+        let permit =
+            match tokio::time::timeout(self.timeout, semaphore.clone().acquire_owned()).await {
+                Ok(Ok(permit)) => permit,
+                _ => {
+                    release(&self.clients, client, &semaphore);
+
+                    let mut headers = HeaderMap::new();
+                    headers.insert(
+                        axum::http::header::RETRY_AFTER,
+                        HeaderValue::from(self.timeout.as_secs().max(1)),
+                    );
+                    return Err(Error::with_message(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        anyhow!("too many concurrent sync operations from {client:?}"),
+                        ErrorMessage::new("RateLimitExceeded", "Too many concurrent requests"),
+                    )
+                    .with_headers(headers));
+                }
+            };
+
+        gauge!(SYNC_EXPENSIVE_OPS, "class" => client.class()).increment(1);
+        Ok(SyncPermit {
+            client: client.clone(),
+            semaphore,
+            permit: Some(permit),
+            clients: self.clients.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::response::IntoResponse;
+
+    use super::*;
+
+    fn limiter(hourly: u32, daily: u32) -> WriteLimiter {
+        WriteLimiter::new(&RateLimitConfig {
+            write_points_hourly: hourly,
+            write_points_daily: daily,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn write_points() {
+        let l = limiter(10, 100);
+        let now = Instant::now();
+
+        // 1 create + 1 update + 1 delete = 6 points.
+        let b = l
+            .consume_at(
+                "did:plc:a",
+                POINTS_CREATE + POINTS_UPDATE + POINTS_DELETE,
+                now,
+            )
+            .unwrap();
+        assert_eq!(b.hourly.remaining, 4);
+        assert_eq!(b.daily.remaining, 94);
+
+        // Another create fits, but a create after that does not.
+        l.consume_at("did:plc:a", POINTS_CREATE, now).unwrap();
+        assert!(l.consume_at("did:plc:a", POINTS_CREATE, now).is_err());
+
+        // The rejected write must not have consumed anything.
+        let b = l.budget_at("did:plc:a", now);
+        assert_eq!(b.hourly.remaining, 1);
+        l.consume_at("did:plc:a", POINTS_DELETE, now).unwrap();
+
+        // Other accounts are unaffected.
+        assert_eq!(l.budget_at("did:plc:b", now).hourly.remaining, 10);
+
+        // The hourly budget resets after an hour, but the daily budget does not.
+        let later = now + HOUR;
+        let b = l.budget_at("did:plc:a", later);
+        assert_eq!(b.hourly.remaining, 10);
+        assert_eq!(b.daily.remaining, 90);
+    }
+
+    #[test]
+    fn write_budgets_pruned() {
+        let l = limiter(10, 100);
+        let now = Instant::now();
+
+        for i in 0..WriteLimiter::PRUNE_THRESHOLD {
+            l.consume_at(&format!("did:plc:{i}"), POINTS_DELETE, now)
+                .unwrap();
+        }
+        let later = now + HOUR;
+        l.consume_at("did:plc:a", POINTS_DELETE, later).unwrap();
+        assert_eq!(
+            l.budgets.lock().unwrap().len(),
+            WriteLimiter::PRUNE_THRESHOLD + 1
+        );
+
+        // Once their daily windows have elapsed, accounts that stopped writing are forgotten.
+        l.consume_at("did:plc:b", POINTS_DELETE, now + DAY).unwrap();
+        let budgets = l.budgets.lock().unwrap();
+        assert_eq!(budgets.len(), 2);
+        assert!(budgets.contains_key("did:plc:a"));
+        drop(budgets);
+
+        // Which doesn't reset the budgets of the accounts that are kept.
+        assert_eq!(l.budget_at("did:plc:a", now + DAY).daily.remaining, 99);
+    }
+
+    #[test]
+    fn amortized_pruning() {
+        let mut entries = Entries::default();
+        let now = Instant::now();
+        let mut pruned = 0;
+        let mut prune = |entries: &mut Entries<u32, ()>, now: Instant| {
+            entries.prune(now, 2, |_, _| {
+                pruned += 1;
+                false
+            })
+        };
+
+        // Too few entries to be worth pruning.
+        entries.insert(1, ());
+        prune(&mut entries, now);
+        assert_eq!(entries.len(), 1);
+
+        entries.insert(2, ());
+        prune(&mut entries, now);
+        assert!(entries.is_empty());
+
+        // Not again until the interval has passed, however many entries there are.
+        entries.extend([(3, ()), (4, ())]);
+        prune(&mut entries, now + PRUNE_INTERVAL / 2);
+        assert_eq!(entries.len(), 2);
+        prune(&mut entries, now + PRUNE_INTERVAL);
+        assert!(entries.is_empty());
+
+        assert_eq!(pruned, 4);
+    }
+
+    #[test]
+    fn login_failures() {
+        let l = LoginLimiter::new(&RateLimitConfig {
+            login_failures_account: 3,
+            login_failures_ip: 5,
+            login_window: 60,
+            ..Default::default()
+        });
+        let (a, b): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let now = Instant::now();
+
+        // Failures for an account count regardless of address, and of the identifier's case.
+        l.fail_at("alice.test", a, now);
+        l.fail_at("Alice.test", b, now);
+        l.check_at("alice.test", a, now).unwrap();
+        l.fail_at("alice.test", b, now);
+        assert!(l.check_at("alice.test", a, now).is_err());
+        l.check_at("bob.test", a, now).unwrap();
+
+        // Failures from an address count regardless of account.
+        l.fail_at("carol.test", b, now);
+        l.fail_at("dave.test", b, now);
+        let e = l.check_at("erin.test", b, now).unwrap_err();
+        let response = e.into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers()[axum::http::header::RETRY_AFTER],
+            HeaderValue::from(60u64)
+        );
+        l.check_at("erin.test", a, now).unwrap();
+
+        // Both reset with the window.
+        let later = now + Duration::from_secs(60);
+        l.check_at("alice.test", b, later).unwrap();
+
+        // A successful sign-in clears the account's failures, but not the address's.
+        l.succeed("alice.test");
+        l.check_at("alice.test", a, now).unwrap();
+        assert!(l.check_at("alice.test", b, now).is_err());
+    }
+
+    #[test]
+    fn signups() {
+        let l = SignupLimiter::new(&RateLimitConfig {
+            signups_hourly: 2,
+            signups_daily: 3,
+            ..Default::default()
+        });
+        let (a, b): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let now = Instant::now();
+
+        l.consume_at(a, now).unwrap();
+        l.consume_at(a, now).unwrap();
+        let response = l.consume_at(a, now).unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response
+            .headers()
+            .contains_key(axum::http::header::RETRY_AFTER));
+        l.consume_at(b, now).unwrap();
+
+        // The hourly budget resets after an hour, but the daily budget does not.
+        let later = now + HOUR;
+        l.consume_at(a, later).unwrap();
+        assert!(l.consume_at(a, later).is_err());
+    }
+
+    #[test]
+    fn client_ips() {
+        let config: RateLimitConfig = serde_json::from_value(serde_json::json!({
+            "trusted_proxies": ["10.0.0.0/8", "::1"],
+        }))
+        .unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let forwarded = |v: &str| {
+            let mut h = HeaderMap::new();
+            h.insert("x-forwarded-for", HeaderValue::from_str(v).unwrap());
+            h
+        };
+
+        // Only trusted proxies may speak for the client.
+        let h = forwarded("198.51.100.7");
+        assert_eq!(
+            resolve_client_ip(ip("192.0.2.1"), &h, &config),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            resolve_client_ip(ip("10.1.2.3"), &h, &config),
+            ip("198.51.100.7")
+        );
+        assert_eq!(
+            resolve_client_ip(ip("::1"), &h, &config),
+            ip("198.51.100.7")
+        );
+
+        // Addresses the client itself prepended are ignored, as are chains of trusted proxies.
+        let h = forwarded("203.0.113.9, 198.51.100.7, 10.0.0.2");
+        assert_eq!(
+            resolve_client_ip(ip("10.1.2.3"), &h, &config),
+            ip("198.51.100.7")
+        );
+
+        // Without the header, or with garbage in it, the proxy is all there is to go on.
+        let proxy = ip("10.1.2.3");
+        assert_eq!(resolve_client_ip(proxy, &HeaderMap::new(), &config), proxy);
+        assert_eq!(
+            resolve_client_ip(proxy, &forwarded("unknown"), &config),
+            proxy
+        );
+
+        assert!(
+            serde_json::from_value::<RateLimitConfig>(serde_json::json!({
+                "trusted_proxies": ["10.0.0.0/33"],
+            }))
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_concurrency() {
+        let l = SyncLimiter::with_limits(2, Duration::from_millis(50));
+        let alice = ClientId::Ip("192.0.2.1".parse().unwrap());
+        let bob = ClientId::Did("did:plc:bob".to_string());
+
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let tasks = (0..8)
+            .map(|_| {
+                let (l, alice, in_flight, peak) =
+                    (l.clone(), alice.clone(), in_flight.clone(), peak.clone());
+                tokio::spawn(async move {
+                    let _permit = l.acquire(&alice).await?;
+
+                    let n = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(n, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(250)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                    Ok::<_, Error>(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // While alice is saturated, bob proceeds unimpeded.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let start = Instant::now();
+        let permit = l.acquire(&bob).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+        drop(permit);
+
+        let mut rejected = 0;
+        for task in tasks {
+            if let Err(e) = task.await.unwrap() {
+                let resp = e.into_response();
+                assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+                assert_eq!(resp.headers()[axum::http::header::RETRY_AFTER], "1");
+                rejected += 1;
+            }
+        }
+
+        assert_eq!(rejected, 6);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+
+        // Idle clients are forgotten.
+        assert!(l.clients.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+//! Validation of record values against the atproto data model.
+//!
+//! Records are written as JSON, and stored as DAG-CBOR. Some JSON values have no canonical
+//! DAG-CBOR representation in the data model (floats, most notably), so a client computing the CID
+//! of such a record locally may disagree with the CID this PDS returns. Rather than pick one
+//! representation, records containing them are rejected.
+//!
+//! Reference: https://atproto.com/specs/data-model
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, ensure};
+use atrium_repo::{
+    blockstore::{DAG_CBOR, SHA2_256},
+    Cid, Multihash,
+};
+use axum::http::StatusCode;
+use base64::Engine;
+use ipld_core::ipld::Ipld;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{error::ErrorMessage, Error};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RecordError {
+    #[error("record must be an object")]
+    NotObject,
+    #[error("floats are not allowed (at {0})")]
+    Float(String),
+    #[error("integer out of range (at {0})")]
+    IntegerRange(String),
+    #[error("invalid $bytes (at {0})")]
+    InvalidBytes(String),
+    #[error("invalid $link (at {0})")]
+    InvalidLink(String),
+    #[error("$type must be a non-empty string (at {0})")]
+    InvalidType(String),
+}
+
+impl From<RecordError> for Error {
+    fn from(e: RecordError) -> Self {
+        Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!("invalid record: {e}"),
+            ErrorMessage::new("InvalidRecord", e.to_string()),
+        )
+    }
+}
+
+/// Check that a record value only contains constructs with a canonical DAG-CBOR representation.
+pub fn validate(record: &Value) -> Result<(), RecordError> {
+    if !record.is_object() {
+        return Err(RecordError::NotObject);
+    }
+
+    validate_value(record, "$")
+}
+
+fn validate_value(value: &Value, path: &str) -> Result<(), RecordError> {
+    match value {
+        Value::Null | Value::Bool(_) | Value::String(_) => Ok(()),
+        Value::Number(n) => {
+            if n.is_i64() {
+                Ok(())
+            } else if n.is_u64() {
+                Err(RecordError::IntegerRange(path.to_string()))
+            } else {
+                Err(RecordError::Float(path.to_string()))
+            }
+        }
+        Value::Array(a) => a
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, v)| validate_value(v, &format!("{path}[{i}]"))),
+        Value::Object(o) => {
+            if let Some(bytes) = o.get("$bytes") {
+                // Unpadded base64, per the data model. Padding is tolerated.
+                let valid = o.len() == 1
+                    && bytes.as_str().is_some_and(|b| {
+                        base64::prelude::BASE64_STANDARD_NO_PAD
+                            .decode(b.trim_end_matches('='))
+                            .is_ok()
+                    });
+
+                return if valid {
+                    Ok(())
+                } else {
+                    Err(RecordError::InvalidBytes(path.to_string()))
+                };
+            }
+            if let Some(link) = o.get("$link") {
+                let valid = o.len() == 1 && link.as_str().is_some_and(|l| Cid::from_str(l).is_ok());
+
+                return if valid {
+                    Ok(())
+                } else {
+                    Err(RecordError::InvalidLink(path.to_string()))
+                };
+            }
+            if let Some(ty) = o.get("$type") {
+                if !ty.as_str().is_some_and(|t| !t.is_empty()) {
+                    return Err(RecordError::InvalidType(path.to_string()));
+                }
+            }
+
+            o.iter()
+                .try_for_each(|(k, v)| validate_value(v, &format!("{path}.{k}")))
+        }
+    }
+}
+
+/// Find the blobs referenced by a record, i.e. `{ "$type": "blob", "ref": { "$link": ... } }`.
+pub fn blob_refs(record: &Value) -> anyhow::Result<Vec<Cid>> {
+    let mut cids = Vec::new();
+    let mut stack = vec![record];
+    while let Some(v) = stack.pop() {
+        match v {
+            Value::Array(values) => stack.extend(values),
+            Value::Object(map) => {
+                if map.get("$type").and_then(Value::as_str) == Some("blob") {
+                    if let Some(link) = map
+                        .get("ref")
+                        .and_then(|r| r.get("$link"))
+                        .and_then(Value::as_str)
+                    {
+                        cids.push(Cid::from_str(link)?);
+                    }
+                }
+
+                stack.extend(map.values());
+            }
+            _ => {}
+        }
+    }
+
+    Ok(cids)
+}
+
+/// Compute the CID of a DAG-CBOR block.
+pub fn block_cid(block: &[u8]) -> Cid {
+    Cid::new_v1(
+        DAG_CBOR,
+        Multihash::wrap(SHA2_256, Sha256::digest(block).as_slice()).unwrap(),
+    )
+}
+
+/// Check that a record, as read back from storage, re-serializes to a block with the CID it was
+/// stored under.
+pub fn verify_cid(cid: &Cid, record: &Ipld) -> anyhow::Result<()> {
+    let block = serde_ipld_dagcbor::to_vec(record)?;
+    let actual = block_cid(&block);
+    ensure!(
+        actual == *cid,
+        "record stored as {cid} re-serializes to {actual}"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use atrium_api::types::Unknown;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn values() {
+        for valid in [
+            json!({ "$type": "app.bsky.feed.post", "text": "hi" }),
+            json!({ "min": i64::MIN, "max": i64::MAX, "zero": 0, "neg": -1 }),
+            json!({ "ünïcödé": { "日本語": { "emoji 🦋": [1, { "ключ": null }] } } }),
+            json!({ "data": { "$bytes": "aGVsbG8gd29ybGQ" } }),
+            json!({ "data": { "$bytes": "aGVsbG8gd29ybGQ=" } }),
+            json!({ "data": { "$bytes": "" } }),
+            json!({
+                "blob": {
+                    "$type": "blob",
+                    "ref": { "$link": "bafkreibme22gw2h7y2h7tg2fhqotaqjucnbc24deqo72b6mkl2egezxhvy" },
+                    "mimeType": "image/png",
+                    "size": 12345,
+                }
+            }),
+        ] {
+            assert_eq!(validate(&valid), Ok(()), "{valid}");
+        }
+
+        for (invalid, e) in [
+            (json!("text"), RecordError::NotObject),
+            (json!([1, 2]), RecordError::NotObject),
+            (json!({ "a": 1.5 }), RecordError::Float("$.a".into())),
+            (
+                json!({ "a": [0, 1.0] }),
+                RecordError::Float("$.a[1]".into()),
+            ),
+            (
+                json!({ "a": { "b": (i64::MAX as u64) + 1 } }),
+                RecordError::IntegerRange("$.a.b".into()),
+            ),
+            (
+                json!({ "a": u64::MAX }),
+                RecordError::IntegerRange("$.a".into()),
+            ),
+            (
+                json!({ "a": { "$bytes": "not base64!" } }),
+                RecordError::InvalidBytes("$.a".into()),
+            ),
+            (
+                json!({ "a": { "$bytes": "aGk", "extra": 1 } }),
+                RecordError::InvalidBytes("$.a".into()),
+            ),
+            (
+                json!({ "a": { "$bytes": 1 } }),
+                RecordError::InvalidBytes("$.a".into()),
+            ),
+            (
+                json!({ "a": { "$link": "not a cid" } }),
+                RecordError::InvalidLink("$.a".into()),
+            ),
+            (json!({ "$type": 1 }), RecordError::InvalidType("$".into())),
+            (json!({ "$type": "" }), RecordError::InvalidType("$".into())),
+        ] {
+            assert_eq!(validate(&invalid), Err(e), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for value in [
+            json!({ "min": i64::MIN, "max": i64::MAX, "small": [-24, -25, 23, 24, 255, 256] }),
+            // Keys are sorted by length first, then bytewise, regardless of input order.
+            json!({ "zz": 1, "a": 2, "é": 3, "b": { "ключ": 4, "日本": 5, "k": 6 } }),
+            json!({ "data": { "$bytes": "AAEC/w" }, "link": { "$link": "bafkreibme22gw2h7y2h7tg2fhqotaqjucnbc24deqo72b6mkl2egezxhvy" } }),
+        ] {
+            validate(&value).unwrap();
+
+            // As written by the repository.
+            let record: Unknown = serde_json::from_value(value.clone()).unwrap();
+            let block = serde_ipld_dagcbor::to_vec(&record).unwrap();
+            let cid = block_cid(&block);
+
+            // As read back.
+            let ipld: Ipld = serde_ipld_dagcbor::from_slice(&block).unwrap();
+            verify_cid(&cid, &ipld).unwrap();
+            assert_eq!(serde_ipld_dagcbor::to_vec(&ipld).unwrap(), block, "{value}");
+        }
+
+        let ipld: Ipld = serde_ipld_dagcbor::from_slice(
+            &serde_ipld_dagcbor::to_vec(&json!({ "a": 1 })).unwrap(),
+        )
+        .unwrap();
+        assert!(verify_cid(&block_cid(b"something else"), &ipld).is_err());
+    }
+}
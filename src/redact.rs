@@ -0,0 +1,321 @@
+//! Redaction of user identifiers in logs.
+//!
+//! Redaction is applied to each fully-formatted log line (see [`Redacting`]), so that every log
+//! site inherits the configured [`Redaction`] policy, including identifiers embedded in messages
+//! and span fields. The following are redacted:
+//!
+//! - DIDs and IP addresses (with or without a port), wherever they appear.
+//! - Email addresses, wherever they appear.
+//! - Handles, in fields named `handle` (a handle can't be told apart from any other domain name).
+//!
+//! Events logged with the [`AUDIT`] target are never redacted.
+
+use std::{
+    borrow::Cow,
+    fmt::{self, Write as _},
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
+use sha2::{Digest, Sha256};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
+
+use crate::config::Redaction;
+
+/// The target of audit log events (e.g. `info!(target: AUDIT, ...)`), which are never redacted.
+pub const AUDIT: &str = "audit";
+
+/// The length of a hashed identifier, in bytes of the SHA-256 digest.
+const HASH_LEN: usize = 6;
+
+/// Fields whose values are always redacted as handles.
+const HANDLE_FIELDS: &[&str] = &["handle"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind<'a> {
+    Did { method: &'a str, id: &'a str },
+    Handle,
+    Email { local: &'a str, domain: &'a str },
+    Ip(IpAddr),
+}
+
+fn digest(s: &str) -> String {
+    Sha256::digest(s.as_bytes())[..HASH_LEN]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Keep the first `n` characters of a string.
+fn prefix(s: &str, n: usize) -> &str {
+    s.char_indices().nth(n).map(|(i, _)| &s[..i]).unwrap_or(s)
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | ':' | '-' | '_' | '%' | '@' | '+')
+}
+
+/// Whether a token is the value of a handle field (e.g. `handle=alice.example.com`).
+fn is_handle_field(before: &str) -> bool {
+    let before = before.strip_suffix('"').unwrap_or(before);
+    let Some(before) = before.strip_suffix('=') else {
+        return false;
+    };
+
+    HANDLE_FIELDS.iter().any(|f| {
+        before
+            .strip_suffix(f)
+            .is_some_and(|b| !b.ends_with(|c: char| c.is_ascii_alphanumeric() || c == '_'))
+    })
+}
+
+/// Classify a token, returning the identifier it contains (e.g. without a port) and its kind.
+fn classify(token: &str) -> Option<(&str, Kind<'_>)> {
+    if let Some(rest) = token.strip_prefix("did:") {
+        let (method, id) = rest.split_once(':')?;
+        if !method.is_empty() && method.chars().all(|c| c.is_ascii_lowercase()) && !id.is_empty() {
+            return Some((token, Kind::Did { method, id }));
+        }
+        return None;
+    }
+
+    if let Some((local, domain)) = token.split_once('@') {
+        if !local.is_empty() && domain.contains('.') && !domain.contains('@') {
+            return Some((token, Kind::Email { local, domain }));
+        }
+        return None;
+    }
+
+    if let Ok(ip) = IpAddr::from_str(token) {
+        return Some((token, Kind::Ip(ip)));
+    }
+
+    // An IPv4 address with a port. IPv6 addresses with a port are bracketed, so the address is
+    // already a token of its own.
+    let (ip, port) = token.rsplit_once(':')?;
+    match (Ipv4Addr::from_str(ip), port.parse::<u16>()) {
+        (Ok(addr), Ok(_)) => Some((ip, Kind::Ip(IpAddr::V4(addr)))),
+        _ => None,
+    }
+}
+
+fn replace(out: &mut String, token: &str, kind: Kind<'_>, policy: Redaction) {
+    let _ = match (policy, kind) {
+        (Redaction::Off, _) => write!(out, "{token}"),
+        (Redaction::Hash, Kind::Did { method, .. }) => {
+            write!(out, "did:{method}:#{}", digest(token))
+        }
+        (Redaction::Hash, Kind::Handle) => write!(out, "handle#{}", digest(token)),
+        (Redaction::Hash, Kind::Email { .. }) => write!(out, "email#{}", digest(token)),
+        (Redaction::Hash, Kind::Ip(ip)) => write!(out, "ip#{}", digest(&ip.to_string())),
+        (Redaction::Truncate, Kind::Did { method, id }) => {
+            write!(out, "did:{method}:{}…", prefix(id, 4))
+        }
+        (Redaction::Truncate, Kind::Handle) => write!(out, "{}…", prefix(token, 3)),
+        (Redaction::Truncate, Kind::Email { local, domain }) => {
+            write!(out, "{}…@{domain}", prefix(local, 1))
+        }
+        (Redaction::Truncate, Kind::Ip(IpAddr::V4(ip))) => {
+            let [a, b, _, _] = ip.octets();
+            write!(out, "{a}.{b}.0.0/16")
+        }
+        (Redaction::Truncate, Kind::Ip(IpAddr::V6(ip))) => {
+            let [a, b, ..] = ip.segments();
+            write!(out, "{a:x}:{b:x}::/32")
+        }
+    };
+}
+
+/// Redact user identifiers in a line of text.
+pub fn redact(line: &str, policy: Redaction) -> Cow<'_, str> {
+    if policy == Redaction::Off {
+        return Cow::Borrowed(line);
+    }
+
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find(is_token_char) {
+        let (before, tail) = rest.split_at(start);
+        let end = tail.find(|c| !is_token_char(c)).unwrap_or(tail.len());
+        let (token, tail) = tail.split_at(end);
+        out.push_str(before);
+
+        // Trailing punctuation (e.g. at the end of a sentence) is usually not part of the
+        // identifier, but may be (e.g. `2001:db8::`).
+        let trimmed = token.trim_end_matches(['.', ':', '-']);
+        let kind = if is_handle_field(&out) {
+            Some((token, Kind::Handle))
+        } else {
+            classify(token).or_else(|| classify(trimmed))
+        };
+
+        match kind {
+            Some((ident, kind)) => {
+                replace(&mut out, ident, kind, policy);
+                out.push_str(&token[ident.len()..]);
+            }
+            None => out.push_str(token),
+        }
+
+        rest = tail;
+    }
+
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+/// An event formatter that redacts user identifiers from the output of another formatter.
+///
+/// Redacted lines are formatted without ANSI colors, as escape sequences would otherwise be
+/// interleaved with the identifiers.
+#[derive(Debug, Clone)]
+pub struct Redacting<F> {
+    policy: Redaction,
+    inner: F,
+}
+
+impl<F> Redacting<F> {
+    pub fn new(policy: Redaction, inner: F) -> Self {
+        Self { policy, inner }
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for Redacting<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        if self.policy == Redaction::Off || event.metadata().target() == AUDIT {
+            return self.inner.format_event(ctx, writer, event);
+        }
+
+        let mut line = String::new();
+        self.inner
+            .format_event(ctx, Writer::new(&mut line), event)?;
+        writer.write_str(&redact(&line, self.policy))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::info;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture(policy: Redaction) -> String {
+        let out = Capture::default();
+        let writer = out.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .event_format(Redacting::new(
+                policy,
+                tracing_subscriber::fmt::format().without_time(),
+            ))
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", id = "4a1c7d0e-9f1b-4c5e-8d2a-3b6f0e1a2c3d");
+            let _guard = span.enter();
+
+            info!(
+                did = "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+                handle = "alice.example.com",
+                "failed to open repository of did:plc:ewvi7nxzyoun6zhxrhs64oiz for 203.0.113.7:443"
+            );
+            info!("login from 2001:db8:85a3::8a2e:370:7334 as alice@example.com");
+            info!(target: AUDIT, did = "did:plc:ewvi7nxzyoun6zhxrhs64oiz", "account deleted");
+        });
+
+        let out = out.0.lock().unwrap();
+        String::from_utf8(out.clone()).unwrap()
+    }
+
+    #[test]
+    fn log_output() {
+        const IDENTIFIERS: &[&str] = &[
+            "ewvi7nxzyoun6zhxrhs64oiz",
+            "alice.example.com",
+            "203.0.113.7",
+            "2001:db8:85a3::8a2e:370:7334",
+            "alice@example.com",
+        ];
+
+        let off = capture(Redaction::Off);
+        for ident in IDENTIFIERS {
+            assert!(off.contains(ident), "{ident} missing from {off}");
+        }
+
+        for policy in [Redaction::Hash, Redaction::Truncate] {
+            let out = capture(policy);
+            let lines = out.lines().collect::<Vec<_>>();
+            assert_eq!(lines.len(), 3);
+
+            // Only the audit log retains identifiers.
+            for ident in IDENTIFIERS {
+                assert!(!lines[0].contains(ident), "{ident} in {}", lines[0]);
+                assert!(!lines[1].contains(ident), "{ident} in {}", lines[1]);
+            }
+            assert!(lines[2].contains("did:plc:ewvi7nxzyoun6zhxrhs64oiz"));
+
+            // The request ID is kept for correlation.
+            for line in &lines {
+                assert!(line.contains("id=\"4a1c7d0e-9f1b-4c5e-8d2a-3b6f0e1a2c3d\""));
+            }
+        }
+
+        let truncated = capture(Redaction::Truncate);
+        assert!(truncated.contains("did=\"did:plc:ewvi…\""));
+        assert!(truncated.contains("handle=\"ali…\""));
+        assert!(truncated.contains("for 203.0.0.0/16:443"));
+        assert!(truncated.contains("from 2001:db8::/32 as a…@example.com"));
+    }
+
+    #[test]
+    fn hashes() {
+        let line = "did:plc:abc did:plc:abc did:plc:abd handle=bob.test 10.0.0.1 10.0.0.1:80.";
+        let redacted = redact(line, Redaction::Hash);
+
+        let tokens = redacted.split(' ').collect::<Vec<_>>();
+        assert!(tokens[0].starts_with("did:plc:#"));
+        // Identifiers hash consistently, so that lines can be correlated.
+        assert_eq!(tokens[0], tokens[1]);
+        assert_ne!(tokens[1], tokens[2]);
+        assert!(tokens[3].starts_with("handle=handle#"));
+        // The port and trailing punctuation are preserved.
+        assert_eq!(format!("{}:80.", tokens[4]), tokens[5]);
+
+        // Things that aren't identifiers are left alone.
+        let line = "bluepds::firehose: 12:34:56 did:Web:x ratelimit=3000 nothandle=x.y";
+        assert_eq!(redact(line, Redaction::Hash), line);
+    }
+}
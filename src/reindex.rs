@@ -0,0 +1,605 @@
+//! Rebuilding of metadata for repositories written by an earlier version.
+//!
+//! Earlier versions of this PDS didn't maintain all of the metadata derived from repositories
+//! (storage statistics, blob references and backlinks). On upgrade, every existing repository is
+//! queued in the `reindex` table, and a background pass rebuilds its metadata from its blockstore.
+//! The pass reads records at a limited rate (`reindex.records_per_second`), so as not to starve
+//! live traffic, and is resumable: a repository leaves the queue in the same transaction that
+//! replaces its metadata, so a restart picks up where the last run left off.
+//!
+//! A repository is indexed at a particular head. If a write moves the head in the meantime, the
+//! index is discarded and the repository is indexed again. Writes to a queued repository don't
+//! update its storage statistics (see [`pending`]), as they're about to be replaced wholesale.
+//!
+//! Until a repository has been indexed, endpoints that depend on its metadata compute it on the
+//! fly instead (see [`blobs`]).
+
+use std::{
+    collections::BTreeSet,
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use atrium_repo::Cid;
+use futures::TryStreamExt;
+use serde::Serialize;
+use sqlx::SqliteConnection;
+use tracing::{info, warn};
+
+use crate::{aturi::AtUri, backlinks, config::AppConfig, record, storage, Db};
+
+/// The number of records read between pauses to respect the rate limit.
+const THROTTLE_BATCH: usize = 100;
+/// The number of times a repository is indexed in one attempt, if writes keep moving its head.
+const MAX_RACES: usize = 3;
+
+/// Metadata derived from a repository at a particular head.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepoIndex {
+    pub records: i64,
+    /// The blobs referenced by each record, by record path.
+    pub blobs: Vec<(String, Cid)>,
+    /// The subjects referenced by each record, by record path.
+    pub backlinks: Vec<(String, Vec<AtUri>)>,
+}
+
+/// The state of the background pass.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Progress {
+    /// The number of repositories not yet indexed, including those that failed.
+    pub remaining: i64,
+    /// The number of repositories that couldn't be indexed, and are no longer retried.
+    pub failed: i64,
+}
+
+/// Repository and blob files found on disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inventory {
+    pub repos: usize,
+    /// DIDs of repository files without an account.
+    pub orphaned: Vec<String>,
+    pub blobs: usize,
+}
+
+/// Derive a repository's metadata from its blockstore, optionally limiting the number of records
+/// read per second.
+pub async fn scan(
+    config: &AppConfig,
+    did: &str,
+    root: Cid,
+    records_per_second: Option<u32>,
+) -> Result<RepoIndex> {
+    let mut repo = storage::open_repo(&config.repo, did, root).await?;
+    let keys: Vec<String> = {
+        let mut tree = repo.tree();
+        let keys = Box::pin(tree.keys())
+            .try_collect()
+            .await
+            .context("failed to iterate repo keys")?;
+        keys
+    };
+
+    let mut index = RepoIndex::default();
+    let mut batch = Instant::now();
+    for (i, key) in keys.iter().enumerate() {
+        let record: Option<serde_json::Value> =
+            repo.get_raw(key).await.context("failed to read record")?;
+
+        if let Some(record) = record {
+            index.records += 1;
+
+            if let Ok(cids) = record::blob_refs(&record) {
+                index
+                    .blobs
+                    .extend(cids.into_iter().map(|c| (key.clone(), c)));
+            }
+
+            if let Some((collection, _rkey)) = key.split_once('/') {
+                let subjects = backlinks::extract(&config.repo.backlinks, collection, &record);
+                if !subjects.is_empty() {
+                    index.backlinks.push((key.clone(), subjects));
+                }
+            }
+        }
+
+        if let Some(rate) = records_per_second.filter(|_| (i + 1) % THROTTLE_BATCH == 0) {
+            let target = Duration::from_secs_f64(THROTTLE_BATCH as f64 / rate.max(1) as f64);
+            tokio::time::sleep(target.saturating_sub(batch.elapsed())).await;
+            batch = Instant::now();
+        }
+    }
+
+    Ok(index)
+}
+
+/// Replace a repository's metadata with an index of it at `root`, removing it from the queue.
+/// Returns `false` (changing nothing) if the repository's head has since moved on from `root`.
+pub async fn apply(
+    config: &AppConfig,
+    db: &Db,
+    did: &str,
+    root: &Cid,
+    index: &RepoIndex,
+) -> Result<bool> {
+    let block_bytes = storage::repo_size(&config.repo, did).await?;
+
+    let mut tx = db.begin().await.context("failed to begin transaction")?;
+
+    let head: Option<String> = sqlx::query_scalar(r#"SELECT root FROM accounts WHERE did = ?"#)
+        .bind(did)
+        .fetch_optional(&mut *tx)
+        .await
+        .context("failed to query repository head")?;
+    if head != Some(root.to_string()) {
+        return Ok(false);
+    }
+
+    // References from records are rebuilt. Those to blobs not (yet) referenced by any record are
+    // kept, as the blobs are still owned by the account.
+    sqlx::query(r#"DELETE FROM blob_ref WHERE did = ? AND record IS NOT NULL"#)
+        .bind(did)
+        .execute(&mut *tx)
+        .await
+        .context("failed to clear blob references")?;
+    for (key, cid) in &index.blobs {
+        let cid = cid.to_string();
+        let r = sqlx::query(
+            r#"UPDATE blob_ref SET record = ? WHERE cid = ? AND did = ? AND record IS NULL"#,
+        )
+        .bind(key)
+        .bind(&cid)
+        .bind(did)
+        .execute(&mut *tx)
+        .await
+        .context("failed to update blob_ref")?;
+
+        if r.rows_affected() == 0 {
+            sqlx::query(r#"INSERT INTO blob_ref (record, cid, did) VALUES (?, ?, ?)"#)
+                .bind(key)
+                .bind(&cid)
+                .bind(did)
+                .execute(&mut *tx)
+                .await
+                .context("failed to update blob_ref")?;
+        }
+    }
+
+    sqlx::query(r#"DELETE FROM backlinks WHERE did = ?"#)
+        .bind(did)
+        .execute(&mut *tx)
+        .await
+        .context("failed to clear backlinks")?;
+    for (key, subjects) in &index.backlinks {
+        if let Some((collection, rkey)) = key.split_once('/') {
+            backlinks::link(&mut tx, did, collection, rkey, subjects).await?;
+        }
+    }
+
+    let cids: Vec<String> =
+        sqlx::query_scalar(r#"SELECT DISTINCT cid FROM blob_ref WHERE did = ?"#)
+            .bind(did)
+            .fetch_all(&mut *tx)
+            .await
+            .context("failed to query blob references")?;
+    let mut blob_bytes = 0;
+    for cid in cids {
+        // Blobs that were never uploaded take up no space.
+        if let Ok(meta) = tokio::fs::metadata(config.blob.path.join(format!("{cid}.blob"))).await {
+            blob_bytes += meta.len() as i64;
+        }
+    }
+
+    // The growth window restarts at the recomputed total, so that this doesn't count as growth.
+    let total = block_bytes as i64 + blob_bytes;
+    sqlx::query(
+        r#"
+        INSERT INTO repo_stats (did, block_bytes, blob_bytes, records, window_start, window_base)
+            VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT(did) DO UPDATE SET
+            block_bytes = excluded.block_bytes,
+            blob_bytes = excluded.blob_bytes,
+            records = excluded.records,
+            window_start = excluded.window_start,
+            window_base = excluded.window_base
+        "#,
+    )
+    .bind(did)
+    .bind(block_bytes as i64)
+    .bind(blob_bytes)
+    .bind(index.records)
+    .bind(chrono::Utc::now().timestamp())
+    .bind(total)
+    .execute(&mut *tx)
+    .await
+    .context("failed to update repo stats")?;
+
+    sqlx::query(r#"DELETE FROM reindex WHERE did = ?"#)
+        .bind(did)
+        .execute(&mut *tx)
+        .await
+        .context("failed to dequeue repository")?;
+
+    tx.commit().await.context("failed to commit index")?;
+    Ok(true)
+}
+
+/// Whether a repository is queued to be indexed. Writers check this in the transaction that moves
+/// the repository's head, and skip updating its storage statistics if so.
+pub async fn pending(conn: &mut SqliteConnection, did: &str) -> Result<bool> {
+    sqlx::query_scalar(r#"SELECT EXISTS(SELECT 1 FROM reindex WHERE did = ?)"#)
+        .bind(did)
+        .fetch_one(conn)
+        .await
+        .context("failed to query reindex queue")
+}
+
+/// The blobs owned by a repository at `root`, in CID order. If the repository hasn't been indexed
+/// yet, those referenced by its records are found by scanning it.
+pub async fn blobs(config: &AppConfig, db: &Db, did: &str, root: Cid) -> Result<Vec<String>> {
+    let mut cids: Vec<String> =
+        sqlx::query_scalar(r#"SELECT DISTINCT cid FROM blob_ref WHERE did = ? ORDER BY cid"#)
+            .bind(did)
+            .fetch_all(db)
+            .await
+            .context("failed to query blobs")?;
+
+    let mut conn = db.acquire().await.context("failed to acquire connection")?;
+    if pending(&mut conn, did).await? {
+        drop(conn);
+        let index = scan(config, did, root, None).await?;
+        let mut all = cids.into_iter().collect::<BTreeSet<_>>();
+        all.extend(index.blobs.iter().map(|(_, c)| c.to_string()));
+        cids = all.into_iter().collect();
+    }
+
+    Ok(cids)
+}
+
+/// Report the state of the background pass.
+pub async fn progress(db: &Db, max_attempts: u32) -> Result<Progress> {
+    let (remaining, failed): (i64, i64) =
+        sqlx::query_as(r#"SELECT COUNT(*), COALESCE(SUM(attempts >= ?), 0) FROM reindex"#)
+            .bind(max_attempts)
+            .fetch_one(db)
+            .await
+            .context("failed to query reindex progress")?;
+
+    Ok(Progress { remaining, failed })
+}
+
+/// List the stems of the files in a directory with an extension.
+async fn list_files(dir: &Path, extension: &str) -> Result<Vec<String>> {
+    let mut stems = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("failed to read {}", dir.display()))?;
+    while let Some(entry) = entries.next_entry().await.context("failed to read entry")? {
+        let path = entry.path();
+        if path.extension().is_some_and(|e| e == extension) {
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                stems.push(stem.to_string());
+            }
+        }
+    }
+
+    Ok(stems)
+}
+
+/// Take stock of the repository and blob files on disk.
+pub async fn inventory(config: &AppConfig, db: &Db) -> Result<Inventory> {
+    let mut inventory = Inventory::default();
+
+    for id in list_files(&config.repo.path, "car").await? {
+        inventory.repos += 1;
+
+        let did = storage::file_did(&id);
+        let known: bool =
+            sqlx::query_scalar(r#"SELECT EXISTS(SELECT 1 FROM accounts WHERE did = ?)"#)
+                .bind(&did)
+                .fetch_one(db)
+                .await
+                .context("failed to query account")?;
+        if !known {
+            inventory.orphaned.push(did);
+        }
+    }
+
+    // Skip partial uploads and other temporary files, which are named by a prefix.
+    inventory.blobs = list_files(&config.blob.path, "blob")
+        .await?
+        .iter()
+        .filter(|stem| Cid::from_str(stem).is_ok())
+        .count();
+
+    Ok(inventory)
+}
+
+/// Index a queued repository, retrying if writes move its head in the meantime.
+async fn reindex(config: &AppConfig, db: &Db, did: &str) -> Result<()> {
+    for _ in 0..MAX_RACES {
+        let root: String = sqlx::query_scalar(r#"SELECT root FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_one(db)
+            .await
+            .context("failed to query repository head")?;
+        let root = Cid::from_str(&root).context("invalid repository head")?;
+
+        let index = scan(config, did, root, Some(config.reindex.records_per_second)).await?;
+        if apply(config, db, did, &root, &index).await? {
+            return Ok(());
+        }
+    }
+
+    bail!("repository kept changing while being indexed")
+}
+
+/// Index all queued repositories, returning the number indexed.
+pub async fn reindex_all(config: &AppConfig, db: &Db) -> Result<usize> {
+    let max_attempts = config.reindex.max_attempts;
+    let total = progress(db, max_attempts).await?;
+    if total.remaining == total.failed {
+        return Ok(0);
+    }
+
+    let inventory = inventory(config, db).await?;
+    info!(
+        "indexing {} repositories written by an earlier version ({} repository files and {} blobs on disk)",
+        total.remaining - total.failed,
+        inventory.repos,
+        inventory.blobs
+    );
+    for did in &inventory.orphaned {
+        warn!("repository file for {did} has no account");
+    }
+
+    let mut done = 0;
+    loop {
+        let dids: Vec<String> = sqlx::query_scalar(
+            r#"SELECT did FROM reindex WHERE attempts < ? ORDER BY did LIMIT 100"#,
+        )
+        .bind(max_attempts)
+        .fetch_all(db)
+        .await
+        .context("failed to query reindex queue")?;
+        if dids.is_empty() {
+            break;
+        }
+
+        for did in dids {
+            match reindex(config, db, &did).await {
+                Ok(()) => {
+                    done += 1;
+                    if done % 100 == 0 {
+                        let p = progress(db, max_attempts).await?;
+                        info!("indexed {done} repositories, {} remaining", p.remaining);
+                    }
+                }
+                Err(e) => {
+                    warn!("failed to index {did}: {e:?}");
+                    sqlx::query(
+                        r#"UPDATE reindex SET attempts = attempts + 1, error = ? WHERE did = ?"#,
+                    )
+                    .bind(format!("{e:#}"))
+                    .bind(&did)
+                    .execute(db)
+                    .await
+                    .context("failed to record reindex failure")?;
+                }
+            }
+        }
+    }
+
+    let p = progress(db, max_attempts).await?;
+    info!(
+        "indexed {done} repositories written by an earlier version ({} failed)",
+        p.failed
+    );
+    Ok(done)
+}
+
+/// Index all queued repositories in the background.
+pub async fn run(config: AppConfig, db: Db) {
+    if let Err(e) = reindex_all(&config, &db).await {
+        warn!("failed to index repositories: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use atrium_api::types::string::Did;
+    use atrium_crypto::keypair::Secp256k1Keypair;
+    use atrium_repo::{blockstore::CarStore, Repository};
+    use serde_json::json;
+
+    use super::*;
+    use crate::SigningKey;
+
+    /// Write a repository with the specified records, as an earlier version would have, returning
+    /// its head.
+    async fn legacy_repo(
+        config: &AppConfig,
+        skey: &SigningKey,
+        did: &str,
+        records: &[(&str, serde_json::Value)],
+    ) -> Cid {
+        let file = tokio::fs::File::create(storage::repo_path(&config.repo, did).unwrap())
+            .await
+            .unwrap();
+        let mut store = CarStore::create(file).await.unwrap();
+        let builder = Repository::create(&mut store, Did::new(did.to_string()).unwrap())
+            .await
+            .unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        let mut repo = builder.finalize(sig).await.unwrap();
+
+        for (key, record) in records {
+            let (builder, _) = repo.add_raw(key, record).await.unwrap();
+            let sig = skey.sign(&builder.bytes()).unwrap();
+            builder.finalize(sig).await.unwrap();
+        }
+
+        repo.root()
+    }
+
+    #[tokio::test]
+    async fn legacy() {
+        let dir = crate::testing::temp_dir("reindex");
+        let config = crate::testing::config(&dir, json!({ "reindex": { "max_attempts": 1 } }));
+
+        let db = crate::testing::memory_db().await;
+
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let (alice, bob) = ("did:plc:alice", "did:plc:bob");
+
+        // A blob on disk, along with a partial upload.
+        let image = record::block_cid(b"image");
+        std::fs::write(dir.join("blob").join(format!("{image}.blob")), b"1234").unwrap();
+        std::fs::write(dir.join("blob").join("temp-1.blob"), b"12").unwrap();
+
+        let post = |text: &str| {
+            json!({
+                "$type": "app.bsky.feed.post",
+                "text": text,
+                "embed": {
+                    "$type": "app.bsky.embed.images",
+                    "images": [{
+                        "alt": "",
+                        "image": {
+                            "$type": "blob",
+                            "ref": { "$link": image.to_string() },
+                            "mimeType": "image/png",
+                            "size": 4,
+                        },
+                    }],
+                },
+            })
+        };
+        let like = json!({
+            "$type": "app.bsky.feed.like",
+            "subject": {
+                "uri": "at://did:plc:bob/app.bsky.feed.post/3jzfcijpj2z2a",
+                "cid": image.to_string(),
+            },
+        });
+
+        let heads = [
+            (
+                alice,
+                legacy_repo(
+                    &config,
+                    &skey,
+                    alice,
+                    &[
+                        ("app.bsky.feed.post/3jzfcijpj2z2a", post("one")),
+                        ("app.bsky.feed.post/3jzfcijpj2z2b", post("two")),
+                        ("app.bsky.feed.like/3jzfcijpj2z2c", like),
+                    ],
+                )
+                .await,
+            ),
+            (bob, legacy_repo(&config, &skey, bob, &[]).await),
+        ];
+        for (did, root) in heads {
+            sqlx::query(
+                r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', ?, '', '')"#,
+            )
+            .bind(did)
+            .bind(root.to_string())
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+
+        // A repository left behind by a deleted account, and an account whose repository is gone.
+        legacy_repo(&config, &skey, "did:plc:carol", &[]).await;
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES ('did:plc:dave', '', '', ?, '', '')"#,
+        )
+        .bind(image.to_string())
+        .execute(&db)
+        .await
+        .unwrap();
+
+        // As queued by the migration on upgrade.
+        sqlx::query(r#"INSERT INTO reindex (did) SELECT did FROM accounts"#)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            inventory(&config, &db).await.unwrap(),
+            Inventory {
+                repos: 3,
+                orphaned: vec!["did:plc:carol".to_string()],
+                blobs: 1,
+            }
+        );
+
+        // Until indexed, blobs are found by scanning the repository.
+        assert_eq!(
+            blobs(&config, &db, alice, heads[0].1).await.unwrap(),
+            [image.to_string()]
+        );
+
+        assert_eq!(reindex_all(&config, &db).await.unwrap(), 2);
+        assert_eq!(
+            progress(&db, 1).await.unwrap(),
+            Progress {
+                remaining: 1,
+                failed: 1
+            }
+        );
+        let error: Option<String> =
+            sqlx::query_scalar(r#"SELECT error FROM reindex WHERE did = 'did:plc:dave'"#)
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert!(error.is_some());
+
+        // The metadata matches that of a fresh index.
+        let fresh = scan(&config, alice, heads[0].1, None).await.unwrap();
+        assert_eq!(fresh.records, 3);
+        assert_eq!(fresh.blobs.len(), 2);
+        assert_eq!(fresh.backlinks.len(), 1);
+
+        let stats = crate::stats::get(&db, alice).await.unwrap().unwrap();
+        assert_eq!(stats.records, fresh.records);
+        assert_eq!(stats.blob_bytes, 4);
+        assert_eq!(
+            stats.block_bytes as u64,
+            storage::repo_size(&config.repo, alice).await.unwrap()
+        );
+        let refs: i64 = sqlx::query_scalar(
+            r#"SELECT COUNT(*) FROM blob_ref WHERE did = ? AND record IS NOT NULL"#,
+        )
+        .bind(alice)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(refs as usize, fresh.blobs.len());
+        let links = crate::backlinks::lookup(
+            &db,
+            AtUri::from_str("at://did:plc:bob/app.bsky.feed.post/3jzfcijpj2z2a").unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(links.len(), fresh.backlinks.len());
+
+        let stats = crate::stats::get(&db, bob).await.unwrap().unwrap();
+        assert_eq!((stats.records, stats.blob_bytes), (0, 0));
+
+        // Indexed repositories are served from the index, and aren't indexed again.
+        assert_eq!(
+            blobs(&config, &db, alice, heads[0].1).await.unwrap(),
+            [image.to_string()]
+        );
+        assert_eq!(reindex_all(&config, &db).await.unwrap(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
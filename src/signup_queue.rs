@@ -0,0 +1,156 @@
+//! The signup queue, which throttles the activation of new accounts.
+//!
+//! If `[signup_queue]` is configured, new accounts are created deactivated and queued. Every
+//! `interval`, the next `batch` of queued accounts is activated, which is announced on the
+//! firehose like any other change of status. Accounts check their place with
+//! `com.atproto.temp.checkSignupQueue`, and can't activate themselves while queued.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use sqlx::SqliteConnection;
+use tracing::{info, warn};
+
+use crate::{
+    config::SignupQueueConfig,
+    events::EventBus,
+    status::{self, AccountStatus},
+    Db,
+};
+
+/// Queue a new account for activation. Must be called in the transaction that creates it.
+pub async fn enqueue(conn: &mut SqliteConnection, did: &str) -> anyhow::Result<()> {
+    sqlx::query(r#"INSERT INTO signup_queue (did) VALUES (?)"#)
+        .bind(did)
+        .execute(conn)
+        .await
+        .context("failed to queue account")?;
+    Ok(())
+}
+
+/// An account's place in the queue, starting from 1, or `None` if it isn't queued.
+pub async fn place(db: &Db, did: &str) -> anyhow::Result<Option<i64>> {
+    sqlx::query_scalar(
+        r#"
+        SELECT (SELECT COUNT(*) FROM signup_queue q WHERE q.id <= signup_queue.id)
+            FROM signup_queue WHERE did = ?
+        "#,
+    )
+    .bind(did)
+    .fetch_optional(db)
+    .await
+    .context("failed to query signup queue")
+}
+
+/// How long until the account at `place` in the queue is activated, at most.
+pub fn estimated_time(config: &SignupQueueConfig, place: i64) -> Duration {
+    let batch = i64::from(config.batch.max(1));
+    let batches = (place.max(1) + batch - 1) / batch;
+    Duration::from_secs(config.interval * batches as u64)
+}
+
+/// Activate the next `n` queued accounts, returning their DIDs. Accounts whose status changed
+/// while they were queued (e.g. they were taken down) leave the queue without being activated.
+pub async fn activate(db: &Db, events: &EventBus, n: u32) -> anyhow::Result<Vec<String>> {
+    let queued: Vec<(String, Option<String>)> = sqlx::query_as(
+        r#"
+        SELECT q.did, a.status FROM signup_queue q
+            LEFT JOIN accounts a ON a.did = q.did
+            ORDER BY q.id
+            LIMIT ?
+        "#,
+    )
+    .bind(n)
+    .fetch_all(db)
+    .await
+    .context("failed to query signup queue")?;
+
+    let mut activated = Vec::new();
+    for (did, status) in queued {
+        if status.as_deref() == Some(AccountStatus::Deactivated.as_str()) {
+            status::set_status(db, events, &did, AccountStatus::Active)
+                .await
+                .map_err(|e| anyhow::anyhow!("failed to activate {did}: {e:?}"))?;
+            activated.push(did.clone());
+        }
+
+        sqlx::query(r#"DELETE FROM signup_queue WHERE did = ?"#)
+            .bind(&did)
+            .execute(db)
+            .await
+            .context("failed to dequeue account")?;
+    }
+
+    Ok(activated)
+}
+
+/// Periodically activate the next batch of queued accounts.
+pub async fn run(config: SignupQueueConfig, db: Db, events: EventBus) {
+    loop {
+        match activate(&db, &events, config.batch).await {
+            Ok(activated) if !activated.is_empty() => {
+                info!("activated {} queued accounts", activated.len());
+            }
+            Ok(_) => {}
+            Err(e) => warn!("failed to activate queued accounts: {e:?}"),
+        }
+
+        tokio::time::sleep(Duration::from_secs(config.interval)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn queue() {
+        let db = crate::testing::memory_db().await;
+        let events = EventBus::new();
+        let config = SignupQueueConfig {
+            batch: 2,
+            interval: 60,
+        };
+
+        for (did, status) in [
+            ("did:plc:alice", "deactivated"),
+            ("did:plc:bob", "takendown"),
+            ("did:plc:carol", "deactivated"),
+        ] {
+            sqlx::query(
+                r#"
+                INSERT INTO accounts (did, email, password, root, plc_root, rev, status)
+                    VALUES (?, ?, '', '', '', '', ?)
+                "#,
+            )
+            .bind(did)
+            .bind(format!("{did}@example.com"))
+            .bind(status)
+            .execute(&db)
+            .await
+            .unwrap();
+            let mut conn = db.acquire().await.unwrap();
+            enqueue(&mut conn, did).await.unwrap();
+        }
+
+        assert_eq!(place(&db, "did:plc:carol").await.unwrap(), Some(3));
+        assert_eq!(place(&db, "did:plc:dave").await.unwrap(), None);
+        assert_eq!(estimated_time(&config, 3), Duration::from_secs(120));
+
+        // The account taken down while queued leaves the queue, but stays taken down.
+        let activated = activate(&db, &events, config.batch).await.unwrap();
+        assert_eq!(activated, ["did:plc:alice"]);
+        assert_eq!(place(&db, "did:plc:carol").await.unwrap(), Some(1));
+        let status: String =
+            sqlx::query_scalar(r#"SELECT status FROM accounts WHERE did = 'did:plc:bob'"#)
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert_eq!(status, "takendown");
+
+        let activated = activate(&db, &events, config.batch).await.unwrap();
+        assert_eq!(activated, ["did:plc:carol"]);
+        assert_eq!(place(&db, "did:plc:carol").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,364 @@
+//! Per-repository storage statistics.
+//!
+//! Block bytes, blob bytes and record counts are tracked incrementally as repositories change,
+//! so that the accounts responsible for disk growth can be found without scanning storage.
+//!
+//! If configured, an alert is raised (at most once per window) when an account's storage grows
+//! by more than a threshold within a window.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{config::GrowthAlertConfig, Client, Db};
+
+/// A change in a repository's storage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageDelta {
+    pub block_bytes: i64,
+    pub blob_bytes: i64,
+    pub records: i64,
+}
+
+/// Storage used by a repository.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct RepoStats {
+    pub did: String,
+    pub block_bytes: i64,
+    pub blob_bytes: i64,
+    pub records: i64,
+    /// The sum of block and blob bytes.
+    pub total_bytes: i64,
+}
+
+/// An account whose storage grew past the configured threshold within a window.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct GrowthAlert {
+    pub did: String,
+    /// The growth since the start of the window, in bytes.
+    pub growth: i64,
+    /// The current storage used by the account, in bytes.
+    pub total_bytes: i64,
+    /// The length of the window, in seconds.
+    pub window: u64,
+}
+
+/// Tracks storage statistics, raising growth alerts as configured.
+#[derive(Clone)]
+pub struct StorageStats {
+    config: Option<GrowthAlertConfig>,
+    client: Client,
+    db: Db,
+}
+
+impl StorageStats {
+    pub fn new(config: Option<GrowthAlertConfig>, client: Client, db: Db) -> Self {
+        Self { config, client, db }
+    }
+
+    /// Record a change in a repository's storage.
+    pub async fn record(&self, did: &str, delta: StorageDelta) -> Result<()> {
+        if let Some(alert) = self
+            .record_at(did, delta, chrono::Utc::now().timestamp())
+            .await?
+        {
+            self.alert(&alert).await;
+        }
+
+        Ok(())
+    }
+
+    /// Record a change in a repository's storage at the specified time (in unix seconds),
+    /// returning an alert if one should be raised.
+    async fn record_at(
+        &self,
+        did: &str,
+        delta: StorageDelta,
+        now: i64,
+    ) -> Result<Option<GrowthAlert>> {
+        let mut tx = self
+            .db
+            .begin()
+            .await
+            .context("failed to begin transaction")?;
+
+        sqlx::query(r#"INSERT OR IGNORE INTO repo_stats (did, window_start) VALUES (?, ?)"#)
+            .bind(did)
+            .bind(now)
+            .execute(&mut *tx)
+            .await
+            .context("failed to create repo stats")?;
+
+        let (total, window_start, window_base, alerted): (i64, i64, i64, bool) = sqlx::query_as(
+            r#"
+            UPDATE repo_stats SET
+                block_bytes = MAX(block_bytes + ?, 0),
+                blob_bytes = MAX(blob_bytes + ?, 0),
+                records = MAX(records + ?, 0)
+            WHERE did = ?
+            RETURNING block_bytes + blob_bytes, window_start, window_base, alerted
+            "#,
+        )
+        .bind(delta.block_bytes)
+        .bind(delta.blob_bytes)
+        .bind(delta.records)
+        .bind(did)
+        .fetch_one(&mut *tx)
+        .await
+        .context("failed to update repo stats")?;
+
+        let alert = if let Some(config) = &self.config {
+            // Start a new window if the current one has elapsed, based at the storage used prior
+            // to this change.
+            let (window_base, alerted) = if now - window_start >= config.window as i64 {
+                let base = total - (delta.block_bytes + delta.blob_bytes);
+                sqlx::query(
+                    r#"UPDATE repo_stats SET window_start = ?, window_base = ?, alerted = FALSE WHERE did = ?"#,
+                )
+                .bind(now)
+                .bind(base)
+                .bind(did)
+                .execute(&mut *tx)
+                .await
+                .context("failed to reset growth window")?;
+
+                (base, false)
+            } else {
+                (window_base, alerted)
+            };
+
+            let growth = total - window_base;
+            if !alerted && growth > config.threshold as i64 {
+                sqlx::query(r#"UPDATE repo_stats SET alerted = TRUE WHERE did = ?"#)
+                    .bind(did)
+                    .execute(&mut *tx)
+                    .await
+                    .context("failed to update repo stats")?;
+
+                Some(GrowthAlert {
+                    did: did.to_string(),
+                    growth,
+                    total_bytes: total,
+                    window: config.window,
+                })
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        tx.commit().await.context("failed to commit repo stats")?;
+        Ok(alert)
+    }
+
+    /// Raise a growth alert.
+    async fn alert(&self, alert: &GrowthAlert) {
+        warn!(
+            "storage for {} grew by {} bytes within {}s (now {} bytes)",
+            alert.did, alert.growth, alert.window, alert.total_bytes
+        );
+
+        let Some(webhook) = self.config.as_ref().and_then(|c| c.webhook.as_ref()) else {
+            return;
+        };
+
+        let r = self
+            .client
+            .post(webhook.clone())
+            .json(alert)
+            .send()
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|r| r.error_for_status().map_err(anyhow::Error::from));
+        if let Err(e) = r {
+            warn!("failed to deliver growth alert for {}: {e:?}", alert.did);
+        }
+    }
+}
+
+/// Fetch the storage used by a repository.
+pub async fn get(db: &Db, did: &str) -> Result<Option<RepoStats>> {
+    sqlx::query_as(
+        r#"
+        SELECT did, block_bytes, blob_bytes, records, block_bytes + blob_bytes AS total_bytes
+            FROM repo_stats WHERE did = ?
+        "#,
+    )
+    .bind(did)
+    .fetch_optional(db)
+    .await
+    .context("failed to query repo stats")
+}
+
+/// List repositories by storage used, largest first.
+pub async fn top(db: &Db, limit: u32, offset: u32) -> Result<Vec<RepoStats>> {
+    sqlx::query_as(
+        r#"
+        SELECT did, block_bytes, blob_bytes, records, block_bytes + blob_bytes AS total_bytes
+            FROM repo_stats
+            ORDER BY total_bytes DESC, did
+            LIMIT ? OFFSET ?
+        "#,
+    )
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(db)
+    .await
+    .context("failed to query repo stats")
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{routing::post, Json};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn growth() {
+        let db = crate::testing::memory_db().await;
+
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES
+                ('did:plc:alice', 'alice@example.com', '', '', '', ''),
+                ('did:plc:bob', 'bob@example.com', '', '', '', ''),
+                ('did:plc:carol', 'carol@example.com', '', '', '', '');
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        // A mock webhook that records the alerts it receives.
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let app = axum::Router::new().route(
+            "/",
+            post({
+                let received = received.clone();
+                move |Json(v): Json<serde_json::Value>| async move {
+                    received.lock().unwrap().push(v);
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let stats = StorageStats::new(
+            Some(GrowthAlertConfig {
+                threshold: 1000,
+                window: 60,
+                webhook: Some(format!("http://{addr}/").parse().unwrap()),
+            }),
+            reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build(),
+            db.clone(),
+        );
+
+        let blocks = |n| StorageDelta {
+            block_bytes: n,
+            blob_bytes: 0,
+            records: 1,
+        };
+        let blobs = |n| StorageDelta {
+            block_bytes: 0,
+            blob_bytes: n,
+            records: 0,
+        };
+
+        // Alice grows past the threshold within the window, and the alert fires once.
+        let now = 1_000_000;
+        assert_eq!(
+            stats
+                .record_at("did:plc:alice", blocks(600), now)
+                .await
+                .unwrap(),
+            None
+        );
+        let alert = stats
+            .record_at("did:plc:alice", blobs(600), now + 10)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            alert,
+            GrowthAlert {
+                did: "did:plc:alice".to_string(),
+                growth: 1200,
+                total_bytes: 1200,
+                window: 60,
+            }
+        );
+        assert_eq!(
+            stats
+                .record_at("did:plc:alice", blobs(5000), now + 20)
+                .await
+                .unwrap(),
+            None
+        );
+
+        // The next window starts afresh.
+        assert_eq!(
+            stats
+                .record_at("did:plc:alice", blocks(100), now + 70)
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(stats
+            .record_at("did:plc:alice", blobs(1000), now + 80)
+            .await
+            .unwrap()
+            .is_some());
+
+        // Slow growth across windows doesn't alert.
+        for i in 0..5 {
+            let r = stats
+                .record_at("did:plc:bob", blocks(900), now + i * 60)
+                .await;
+            assert_eq!(r.unwrap(), None);
+        }
+        stats
+            .record_at("did:plc:carol", blocks(100), now)
+            .await
+            .unwrap();
+
+        // Alerts are delivered to the webhook.
+        stats.alert(&alert).await;
+        assert_eq!(
+            received.lock().unwrap().as_slice(),
+            [serde_json::to_value(&alert).unwrap()]
+        );
+
+        let top2 = top(&db, 2, 0).await.unwrap();
+        assert_eq!(
+            top2.iter()
+                .map(|s| (s.did.as_str(), s.total_bytes))
+                .collect::<Vec<_>>(),
+            [("did:plc:alice", 7300), ("did:plc:bob", 4500)]
+        );
+        assert_eq!(top2[1].records, 5);
+
+        let rest = top(&db, 2, 2).await.unwrap();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].did, "did:plc:carol");
+
+        // Counters never go negative.
+        stats
+            .record_at("did:plc:carol", blocks(-1000), now)
+            .await
+            .unwrap();
+        assert_eq!(
+            get(&db, "did:plc:carol")
+                .await
+                .unwrap()
+                .unwrap()
+                .block_bytes,
+            0
+        );
+    }
+}
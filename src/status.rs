@@ -0,0 +1,604 @@
+//! Enforcement of account status (e.g. deactivation) across XRPC endpoints.
+//!
+//! Each endpoint is assigned a [`Class`] by the [`POLICY`] table, and each account status permits
+//! some set of classes:
+//!
+//! | Class     | Examples                                      | Deactivated          | Taken down / suspended |
+//! |-----------|-----------------------------------------------|----------------------|------------------------|
+//! | `Account` | `com.atproto.server.*`, `identity.*`          | allowed              | allowed                |
+//! | `Read`    | `repo.getRecord`, `actor.getPreferences`      | allowed              | `AccountTakedown`      |
+//! | `Migrate` | `importRepo`, `uploadBlob`, `putPreferences`  | allowed              | `AccountTakedown`      |
+//! | `Write`   | record writes                                 | `AccountDeactivated` | `AccountTakedown`      |
+//! | `Proxy`   | proxied `app.bsky.*` and `chat.bsky.*`        | `AccountDeactivated` | `AccountTakedown`      |
+//!
+//! That is, a deactivated account may still log in, read its own data, manage its account and
+//! reactivate, but may not change its repository or act on the network. It may however receive an
+//! inbound migration (see [`crate::migration`]), which happens before the account is activated.
+//!
+//! Only authenticated requests are subject to this policy; the status checked is that of the
+//! authenticated account.
+//!
+//! Account listings likewise include only active accounts by default on public surfaces, and
+//! every account on admin surfaces (see [`listing_statuses`]).
+//!
+//! Every change of status is announced on the firehose with an `#account` event (see
+//! [`set_status`]), as is an account's deletion (see [`deleted_event`]). Relays rely on these to
+//! stop serving the content of accounts that are no longer active.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+use atrium_api::{
+    com::atproto::sync::subscribe_repos::AccountData,
+    types::string::{Datetime, Did},
+};
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+
+use crate::{
+    auth::AuthenticatedUser,
+    error::ErrorMessage,
+    events::{Event, EventBus},
+    AppState, Db, Error, Result,
+};
+
+/// The status of a hosted account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountStatus {
+    Active,
+    Deactivated,
+    Takendown,
+    Suspended,
+}
+
+impl AccountStatus {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "active" => Some(Self::Active),
+            "deactivated" => Some(Self::Deactivated),
+            "takendown" => Some(Self::Takendown),
+            "suspended" => Some(Self::Suspended),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Active => "active",
+            Self::Deactivated => "deactivated",
+            Self::Takendown => "takendown",
+            Self::Suspended => "suspended",
+        }
+    }
+}
+
+/// Build the `#account` event announcing that an account now has the specified status.
+pub fn account_event(did: &str, status: AccountStatus) -> anyhow::Result<AccountData> {
+    let status = match status {
+        AccountStatus::Active => None,
+        status => Some(status.as_str()),
+    };
+    account_data(did, status)
+}
+
+/// Build the `#account` event announcing that an account was deleted.
+pub fn deleted_event(did: &str) -> anyhow::Result<AccountData> {
+    account_data(did, Some("deleted"))
+}
+
+fn account_data(did: &str, status: Option<&str>) -> anyhow::Result<AccountData> {
+    Ok(AccountData {
+        active: status.is_none(),
+        did: Did::from_str(did).map_err(|e| anyhow!("invalid did {did:?}: {e}"))?,
+        seq: 0, // Filled by firehose later.
+        status: status.map(str::to_string),
+        time: Datetime::now(),
+    })
+}
+
+/// Change the status of an account, announcing the change on the firehose. Returns the previous
+/// status; nothing is announced if it was unchanged.
+pub async fn set_status(
+    db: &Db,
+    events: &EventBus,
+    did: &str,
+    status: AccountStatus,
+) -> Result<AccountStatus> {
+    let event = account_event(did, status)?;
+    let previous: Option<String> =
+        sqlx::query_scalar(r#"SELECT status FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_optional(db)
+            .await
+            .with_context(|| format!("failed to query account {did}"))?;
+    let Some(previous) = previous else {
+        return Err(Error::with_message(
+            StatusCode::NOT_FOUND,
+            anyhow!("account {did} not found"),
+            ErrorMessage::new("AccountNotFound", "account not found"),
+        ));
+    };
+    let previous = AccountStatus::parse(&previous).unwrap_or(AccountStatus::Active);
+    if previous == status {
+        return Ok(previous);
+    }
+
+    sqlx::query(r#"UPDATE accounts SET status = ? WHERE did = ?"#)
+        .bind(status.as_str())
+        .bind(did)
+        .execute(db)
+        .await
+        .with_context(|| format!("failed to update status of {did}"))?;
+
+    events.publish(Event::Account(event)).await;
+    Ok(previous)
+}
+
+/// Change the status of an account at its owner's request. An account may move itself between
+/// active and deactivated, but not out of a takedown or suspension.
+pub async fn set_own_status(
+    db: &Db,
+    events: &EventBus,
+    did: &str,
+    status: AccountStatus,
+) -> Result<AccountStatus> {
+    let current: Option<String> =
+        sqlx::query_scalar(r#"SELECT status FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_optional(db)
+            .await
+            .with_context(|| format!("failed to query account {did}"))?;
+
+    if let Some(current @ (AccountStatus::Takendown | AccountStatus::Suspended)) =
+        current.as_deref().and_then(AccountStatus::parse)
+    {
+        return Err(Error::with_message(
+            StatusCode::BAD_REQUEST,
+            anyhow!(
+                "{did} is {} and may not change its own status",
+                current.as_str()
+            ),
+            ErrorMessage::new("AccountTakedown", "account has been taken down"),
+        ));
+    }
+
+    set_status(db, events, did, status).await
+}
+
+/// Reject requests for a repository whose account isn't active, as the sync endpoints must not
+/// serve them. Repositories not hosted here are left to the endpoint.
+pub async fn require_hosted(db: &Db, did: &str) -> Result<()> {
+    let status: Option<String> = sqlx::query_scalar(r#"SELECT status FROM accounts WHERE did = ?"#)
+        .bind(did)
+        .fetch_optional(db)
+        .await
+        .with_context(|| format!("failed to query account {did}"))?;
+
+    let (error, message) = match status.as_deref().and_then(AccountStatus::parse) {
+        None | Some(AccountStatus::Active) => return Ok(()),
+        Some(AccountStatus::Deactivated) => ("RepoDeactivated", "Repo has been deactivated"),
+        Some(AccountStatus::Takendown) => ("RepoTakendown", "Repo has been takendown"),
+        Some(AccountStatus::Suspended) => ("RepoSuspended", "Repo has been suspended"),
+    };
+
+    Err(Error::with_message(
+        StatusCode::BAD_REQUEST,
+        anyhow!("repository {did} is not active"),
+        ErrorMessage::new(error, format!("{message}: {did}")),
+    ))
+}
+
+/// The kind of access an endpoint represents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    /// Session and account management.
+    Account,
+    /// Reading data.
+    Read,
+    /// Importing the account's repository, blobs or preferences, as during a migration.
+    Migrate,
+    /// Modifying the account's repository.
+    Write,
+    /// Requests proxied to an AppView or other service on behalf of the account.
+    Proxy,
+}
+
+/// The class of each endpoint. Entries are either exact endpoint paths (relative to `/xrpc/`) or
+/// prefixes ending in `*`; the first match wins. Unlisted endpoints are [`Class::Read`].
+#[rustfmt::skip]
+pub const POLICY: &[(&str, Class)] = &[
+    ("app.bsky.actor.getPreferences",   Class::Read),
+    ("app.bsky.actor.putPreferences",   Class::Migrate),
+    ("app.bsky.*",                      Class::Proxy),
+    ("chat.bsky.*",                     Class::Proxy),
+    ("com.atproto.repo.applyWrites",    Class::Write),
+    ("com.atproto.repo.createRecord",   Class::Write),
+    ("com.atproto.repo.putRecord",      Class::Write),
+    ("com.atproto.repo.deleteRecord",   Class::Write),
+    ("com.atproto.repo.uploadBlob",     Class::Migrate),
+    ("com.atproto.repo.importRepo",     Class::Migrate),
+    ("_blob/*",                         Class::Migrate),
+    ("com.atproto.server.*",            Class::Account),
+    ("com.atproto.identity.*",          Class::Account),
+];
+
+/// Look up the class of an endpoint.
+pub fn classify(endpoint: &str) -> Class {
+    POLICY
+        .iter()
+        .find(|(pattern, _)| match pattern.strip_suffix('*') {
+            Some(prefix) => endpoint.starts_with(prefix),
+            None => endpoint == *pattern,
+        })
+        .map_or(Class::Read, |(_, class)| *class)
+}
+
+/// Determine whether an account with the specified status may call an endpoint, returning the
+/// XRPC error name to reject the request with if not.
+pub fn check(status: AccountStatus, endpoint: &str) -> Option<&'static str> {
+    match (status, classify(endpoint)) {
+        (AccountStatus::Active, _) => None,
+        (_, Class::Account) => None,
+        (AccountStatus::Deactivated, Class::Read | Class::Migrate) => None,
+        (AccountStatus::Deactivated, Class::Write | Class::Proxy) => Some("AccountDeactivated"),
+        (AccountStatus::Takendown | AccountStatus::Suspended, _) => Some("AccountTakedown"),
+    }
+}
+
+/// Resolve the flags of an account listing to the statuses it includes.
+///
+/// Active accounts are always included. Unset flags default to `default` (i.e. `false` for public
+/// listings and `true` for admin listings); `includeTakendown` covers suspended accounts too. An
+/// explicit `status` overrides the flags.
+pub fn listing_statuses(
+    include_deactivated: Option<bool>,
+    include_takendown: Option<bool>,
+    status: Option<&str>,
+    default: bool,
+) -> Result<Vec<AccountStatus>> {
+    if let Some(status) = status {
+        let status = AccountStatus::parse(status).ok_or_else(|| {
+            Error::with_message(
+                StatusCode::BAD_REQUEST,
+                anyhow!("invalid status filter {status:?}"),
+                ErrorMessage::new("InvalidRequest", format!("unknown status {status:?}")),
+            )
+        })?;
+        return Ok(vec![status]);
+    }
+
+    let mut statuses = vec![AccountStatus::Active];
+    if include_deactivated.unwrap_or(default) {
+        statuses.push(AccountStatus::Deactivated);
+    }
+    if include_takendown.unwrap_or(default) {
+        statuses.extend([AccountStatus::Takendown, AccountStatus::Suspended]);
+    }
+
+    Ok(statuses)
+}
+
+/// An account, as listed.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountListing {
+    pub did: String,
+    pub handle: Option<String>,
+    pub email: String,
+    pub status: String,
+    pub root: String,
+    pub rev: String,
+    pub created_at: Option<String>,
+    /// Where the account's storage lives: "hot", "cold" (archived) or "warming" (see `tiering`).
+    pub tier: String,
+    pub last_active: Option<String>,
+    pub tiered_at: Option<String>,
+}
+
+/// List accounts with one of the specified statuses, in DID order, starting after `cursor`.
+pub async fn list_accounts(
+    db: &Db,
+    statuses: &[AccountStatus],
+    cursor: Option<&str>,
+    limit: u32,
+) -> anyhow::Result<Vec<AccountListing>> {
+    let statuses = serde_json::to_string(&statuses.iter().map(|s| s.as_str()).collect::<Vec<_>>())
+        .context("failed to encode statuses")?;
+
+    // N.B: This is served by the `accounts_status` index on `(status, did)`.
+    sqlx::query_as(
+        r#"
+        SELECT
+            accounts.did,
+            (SELECT handle FROM handles WHERE handles.did = accounts.did
+                ORDER BY created_at DESC LIMIT 1) AS handle,
+            email, status, root, rev, created_at,
+            COALESCE(t.tier, 'hot') AS tier, t.last_active, t.tiered_at
+        FROM accounts
+        LEFT JOIN account_tiers t ON t.did = accounts.did
+        WHERE status IN (SELECT value FROM json_each(?))
+            AND (? IS NULL OR accounts.did > ?)
+        ORDER BY accounts.did
+        LIMIT ?
+        "#,
+    )
+    .bind(statuses)
+    .bind(cursor)
+    .bind(cursor)
+    .bind(limit)
+    .fetch_all(db)
+    .await
+    .context("failed to list accounts")
+}
+
+/// Middleware that applies the [`POLICY`] to authenticated requests.
+pub async fn enforce(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response> {
+    let (mut parts, body) = request.into_parts();
+
+    // Unauthenticated requests are left to the endpoint to accept or reject.
+    if let Ok(user) = AuthenticatedUser::from_request_parts(&mut parts, &state).await {
+        let did = user.did();
+        let status = user.status();
+
+        let path = parts.uri.path();
+        let endpoint = path
+            .strip_prefix("/xrpc/")
+            .unwrap_or_else(|| path.trim_start_matches('/'));
+
+        if let Some(error) = check(status, endpoint) {
+            return Err(Error::with_message(
+                StatusCode::BAD_REQUEST,
+                anyhow!("{did} ({status:?}) may not call {endpoint}"),
+                ErrorMessage::new(error, format!("account is {status:?}").to_lowercase()),
+            ));
+        }
+    }
+
+    Ok(next.run(Request::from_parts(parts, body)).await)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matrix() {
+        use AccountStatus::*;
+
+        const DEACTIVATED: Option<&str> = Some("AccountDeactivated");
+        const TAKEDOWN: Option<&str> = Some("AccountTakedown");
+
+        // (endpoint, [active, deactivated, takendown, suspended])
+        #[rustfmt::skip]
+        let cases = [
+            ("com.atproto.server.createSession",    [None, None,        None,     None]),
+            ("com.atproto.server.activateAccount",  [None, None,        None,     None]),
+            ("com.atproto.server.getSession",       [None, None,        None,     None]),
+            ("com.atproto.identity.updateHandle",   [None, None,        None,     None]),
+            ("com.atproto.repo.getRecord",          [None, None,        TAKEDOWN, TAKEDOWN]),
+            ("com.atproto.sync.getRepo",            [None, None,        TAKEDOWN, TAKEDOWN]),
+            ("app.bsky.actor.getPreferences",       [None, None,        TAKEDOWN, TAKEDOWN]),
+            ("app.bsky.actor.putPreferences",       [None, None,        TAKEDOWN, TAKEDOWN]),
+            ("com.atproto.repo.applyWrites",        [None, DEACTIVATED, TAKEDOWN, TAKEDOWN]),
+            ("com.atproto.repo.createRecord",       [None, DEACTIVATED, TAKEDOWN, TAKEDOWN]),
+            ("com.atproto.repo.putRecord",          [None, DEACTIVATED, TAKEDOWN, TAKEDOWN]),
+            ("com.atproto.repo.deleteRecord",       [None, DEACTIVATED, TAKEDOWN, TAKEDOWN]),
+            ("com.atproto.repo.uploadBlob",         [None, None,        TAKEDOWN, TAKEDOWN]),
+            ("com.atproto.repo.importRepo",         [None, None,        TAKEDOWN, TAKEDOWN]),
+            ("_blob/createUpload",                  [None, None,        TAKEDOWN, TAKEDOWN]),
+            ("app.bsky.feed.getTimeline",           [None, DEACTIVATED, TAKEDOWN, TAKEDOWN]),
+            ("chat.bsky.convo.sendMessage",         [None, DEACTIVATED, TAKEDOWN, TAKEDOWN]),
+        ];
+
+        for (endpoint, expected) in cases {
+            for (status, expected) in [Active, Deactivated, Takendown, Suspended]
+                .into_iter()
+                .zip(expected)
+            {
+                assert_eq!(
+                    check(status, endpoint),
+                    expected,
+                    "{endpoint} as {status:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn listing_flags() {
+        use AccountStatus::*;
+
+        // (deactivated, takendown, status, default, expected)
+        #[rustfmt::skip]
+        let cases: [(Option<bool>, Option<bool>, Option<&str>, bool, &[AccountStatus]); 8] = [
+            (None,        None,        None,                false, &[Active]),
+            (None,        None,        None,                true,  &[Active, Deactivated, Takendown, Suspended]),
+            (Some(true),  None,        None,                false, &[Active, Deactivated]),
+            (None,        Some(true),  None,                false, &[Active, Takendown, Suspended]),
+            (Some(false), None,        None,                true,  &[Active, Takendown, Suspended]),
+            (Some(false), Some(false), None,                true,  &[Active]),
+            (Some(true),  Some(true),  Some("deactivated"), false, &[Deactivated]),
+            (None,        None,        Some("suspended"),   true,  &[Suspended]),
+        ];
+
+        for (deactivated, takendown, status, default, expected) in cases {
+            assert_eq!(
+                listing_statuses(deactivated, takendown, status, default).unwrap(),
+                expected,
+                "{deactivated:?} {takendown:?} {status:?} {default}"
+            );
+        }
+
+        assert!(listing_statuses(None, None, Some("bogus"), true).is_err());
+    }
+
+    #[tokio::test]
+    async fn transitions() {
+        use AccountStatus::*;
+
+        let db = crate::testing::memory_db().await;
+        let did = "did:plc:alice";
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', '', '', '')"#,
+        )
+        .bind(did)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let mut events = EventBus::new();
+        let (tx, mut probe) = tokio::sync::mpsc::unbounded_channel();
+        events.subscribe("probe", 1, move |event| {
+            let tx = tx.clone();
+            async move {
+                let _ = tx.send(event);
+            }
+        });
+        // (status, active, announced status)
+        #[rustfmt::skip]
+        let cases = [
+            (Takendown,   false, Some("takendown")),
+            (Active,      true,  None),
+            (Suspended,   false, Some("suspended")),
+            (Deactivated, false, Some("deactivated")),
+            (Active,      true,  None),
+        ];
+
+        for (status, active, expected) in cases {
+            set_status(&db, &events, did, status).await.unwrap();
+
+            let Some(Event::Account(account)) = probe.recv().await else {
+                panic!("expected an #account event");
+            };
+            assert_eq!(account.did.as_str(), did);
+            assert_eq!(account.active, active, "{status:?}");
+            assert_eq!(account.status.as_deref(), expected, "{status:?}");
+        }
+
+        // An unchanged status isn't announced again.
+        assert_eq!(set_status(&db, &events, did, Active).await.unwrap(), Active);
+        set_status(&db, &events, did, Takendown).await.unwrap();
+        let Some(Event::Account(account)) = probe.recv().await else {
+            panic!("expected an #account event");
+        };
+        assert_eq!(account.status.as_deref(), Some("takendown"));
+
+        assert!(set_status(&db, &events, "did:plc:bob", Takendown)
+            .await
+            .is_err());
+
+        let deleted = deleted_event(did).unwrap();
+        assert!(!deleted.active);
+        assert_eq!(deleted.status.as_deref(), Some("deleted"));
+    }
+
+    #[tokio::test]
+    async fn own_status() {
+        use axum::response::IntoResponse as _;
+        use AccountStatus::*;
+
+        let db = crate::testing::memory_db().await;
+        let did = "did:plc:alice";
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', '', '', '')"#,
+        )
+        .bind(did)
+        .execute(&db)
+        .await
+        .unwrap();
+        let events = EventBus::new();
+
+        require_hosted(&db, did).await.unwrap();
+        require_hosted(&db, "did:plc:bob").await.unwrap();
+
+        set_own_status(&db, &events, did, Deactivated)
+            .await
+            .unwrap();
+        let err = require_hosted(&db, did).await.unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+
+        set_own_status(&db, &events, did, Active).await.unwrap();
+        require_hosted(&db, did).await.unwrap();
+
+        // A moderation action can't be undone by the account itself.
+        set_status(&db, &events, did, Takendown).await.unwrap();
+        assert!(set_own_status(&db, &events, did, Active).await.is_err());
+        assert!(require_hosted(&db, did).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn list() {
+        use AccountStatus::*;
+
+        let db = crate::testing::memory_db().await;
+
+        // Two accounts in each state.
+        for (i, status) in ["active", "deactivated", "takendown", "suspended"]
+            .iter()
+            .enumerate()
+        {
+            for j in 0..2 {
+                let did = format!("did:plc:{i}{j}");
+                sqlx::query(
+                    r#"
+                    INSERT INTO accounts (did, email, password, root, plc_root, rev, status)
+                        VALUES (?, ?, '', '', '', '', ?)
+                    "#,
+                )
+                .bind(&did)
+                .bind(format!("{i}{j}@example.com"))
+                .bind(status)
+                .execute(&db)
+                .await
+                .unwrap();
+                sqlx::query(r#"INSERT INTO handles (did, handle) VALUES (?, ?)"#)
+                    .bind(&did)
+                    .bind(format!("{i}{j}.example.com"))
+                    .execute(&db)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let dids =
+            |accounts: Vec<AccountListing>| accounts.into_iter().map(|a| a.did).collect::<Vec<_>>();
+
+        let active = list_accounts(&db, &[Active], None, 100).await.unwrap();
+        assert_eq!(active[0].handle.as_deref(), Some("00.example.com"));
+        assert_eq!(dids(active), ["did:plc:00", "did:plc:01"]);
+
+        let r = list_accounts(&db, &[Active, Deactivated], None, 100).await;
+        assert_eq!(
+            dids(r.unwrap()),
+            ["did:plc:00", "did:plc:01", "did:plc:10", "did:plc:11"]
+        );
+
+        let r = list_accounts(&db, &[Takendown, Suspended], None, 100).await;
+        assert_eq!(
+            dids(r.unwrap()),
+            ["did:plc:20", "did:plc:21", "did:plc:30", "did:plc:31"]
+        );
+
+        // Pagination, across every status.
+        let all = [Active, Deactivated, Takendown, Suspended];
+        let page = list_accounts(&db, &all, None, 3).await.unwrap();
+        assert_eq!(dids(page), ["did:plc:00", "did:plc:01", "did:plc:10"]);
+        let page = list_accounts(&db, &all, Some("did:plc:10"), 3)
+            .await
+            .unwrap();
+        assert_eq!(dids(page), ["did:plc:11", "did:plc:20", "did:plc:21"]);
+        let page = list_accounts(&db, &all, Some("did:plc:31"), 3)
+            .await
+            .unwrap();
+        assert!(page.is_empty());
+    }
+}
@@ -1,25 +1,134 @@
 //! ATProto user repository datastore functionality.
+//!
+//! # Commit protocol
+//!
+//! A repository lives in two stores: its blocks in the blockstore (an append-only CAR file), and
+//! its head pointer (`accounts.root`) in the database, alongside metadata derived from its records
+//! (blob references, backlinks and the like). A crash may interrupt a commit at any point, so a
+//! commit writes to them in this order:
+//!
+//! 1. **Stage.** The commit's blocks are appended to the blockstore and synced to disk
+//!    ([`stage`]). Nothing refers to them yet.
+//! 2. **Commit.** The head pointer and metadata are updated in a single database transaction,
+//!    which also records the staged blocks ([`record_staged`]) and queues the commit's firehose
+//!    event ([`crate::firehose::enqueue`]). This is the commit point.
+//! 3. **Durable.** The staged blocks are marked durable ([`mark_durable`]), forgetting the record
+//!    of them.
+//!
+//! This upholds the following invariants:
+//!
+//! - The head pointer only ever refers to blocks that have been synced to disk.
+//! - Metadata always describes the commit the head pointer refers to.
+//! - Blocks of a commit that didn't reach the commit point are never referred to. They're left in
+//!   the blockstore as garbage, for compaction (see [`crate::compact`]) to discard.
+//!
+//! A commit interrupted after the commit point leaves a record of its staged blocks. On startup,
+//! [`recover`] resolves these by always rolling forward: the transaction committed, so the commit
+//! stands. If its blocks are missing nonetheless (i.e. storage lost writes it claimed to have
+//! synced), that is reported, and left to integrity checks (see [`crate::integrity`]) to fall back
+//! to the last known-good head.
+//!
+//! Operations that replace a repository wholesale (imports and compaction) instead write a new
+//! file alongside it and sync it, then rename it into place. An import, which also moves the head
+//! pointer, commits the new head pointer first and only then renames the file, moving the head
+//! pointer back should the rename fail. A failed transaction leaves the repository untouched, and
+//! a crash in between leaves a head whose blocks are missing, which integrity checks fall back
+//! from.
+//!
+//! Commits to a repository are serialized by its write lock ([`RepoLocks`]), held from reading the
+//! head to moving it, so that each commit builds on the one before it.
 
-use std::str::FromStr;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, Weak},
+};
 
 use anyhow::{Context, Result};
 use atrium_repo::{
     blockstore::{AsyncBlockStoreRead, AsyncBlockStoreWrite, CarStore},
     Cid, Repository,
 };
+use sqlx::SqliteConnection;
+use tracing::{error, info};
 
 use crate::{config::RepoConfig, mmap::MappedFile, Db};
 
+/// Per-repository write locks.
+#[derive(Debug, Clone, Default)]
+pub struct RepoLocks(Arc<Mutex<HashMap<String, Weak<tokio::sync::Mutex<()>>>>>);
+
+impl RepoLocks {
+    /// Wait for, and take, the write lock of a repository. It's released when the guard is dropped.
+    pub async fn lock(&self, did: &str) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.0.lock().unwrap();
+            // Only the locks of repositories being written are kept.
+            locks.retain(|_, l| l.strong_count() > 0);
+            match locks.get(did).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    locks.insert(did.to_string(), Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+
+        lock.lock_owned().await
+    }
+}
+
+/// Return the name under which a user's files are stored: the identifier of a `did:plc`, or
+/// `web.<host>` for a `did:web`. A `did:plc` identifier never contains a dot, so the two can't
+/// collide.
+pub fn file_id(did: &str) -> Result<String> {
+    if let Some(id) = did.strip_prefix("did:plc:") {
+        return Ok(id.to_string());
+    }
+
+    let host = did
+        .strip_prefix("did:web:")
+        .context("did in unknown format")?;
+    anyhow::ensure!(
+        !host.is_empty()
+            && host
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'.' || b == b'-'),
+        "unsupported did:web host {host:?}"
+    );
+    Ok(format!("web.{host}"))
+}
+
+/// Return the DID whose files are stored under a name (the inverse of [`file_id`]).
+pub fn file_did(id: &str) -> String {
+    match id.strip_prefix("web.") {
+        Some(host) => format!("did:web:{host}"),
+        None => format!("did:plc:{id}"),
+    }
+}
+
+/// Return the path of the CAR file backing a user's repository.
+pub fn repo_path(config: &RepoConfig, did: &str) -> Result<PathBuf> {
+    Ok(config.path.join(format!("{}.car", file_id(did)?)))
+}
+
+/// Return the size of the CAR file backing a user's repository, in bytes.
+pub async fn repo_size(config: &RepoConfig, did: &str) -> Result<u64> {
+    let meta = tokio::fs::metadata(repo_path(config, did)?)
+        .await
+        .context("failed to query repository file")?;
+
+    Ok(meta.len())
+}
+
 pub async fn open_store(
     config: &RepoConfig,
     did: impl Into<String>,
 ) -> Result<impl AsyncBlockStoreRead + AsyncBlockStoreWrite> {
     let did = did.into();
-    let id = did
-        .strip_prefix("did:plc:")
-        .context("did in unknown format")?;
-
-    let p = config.path.join(id).with_extension("car");
+    let p = repo_path(config, &did)?;
 
     let f = std::fs::File::options()
         .read(true)
@@ -33,37 +142,415 @@ pub async fn open_store(
         .context("failed to open car store")?)
 }
 
-pub async fn open_repo_db(
+pub async fn open_repo(
     config: &RepoConfig,
-    db: &Db,
     did: impl Into<String>,
+    cid: Cid,
 ) -> Result<Repository<impl AsyncBlockStoreRead + AsyncBlockStoreWrite>> {
     let did = did.into();
-    let cid = sqlx::query_scalar!(
+    let store = open_store(config, did)
+        .await
+        .context("failed to open storage")?;
+
+    Ok(Repository::open(store, cid)
+        .await
+        .context("failed to open repo")?)
+}
+
+/// The blocks of a commit, appended to a repository's blockstore.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StagedCommit {
+    pub did: String,
+    /// The commit the blocks belong to.
+    pub root: Cid,
+    /// The length of the blockstore before and after the blocks were appended, in bytes.
+    pub len_before: u64,
+    pub len_after: u64,
+}
+
+/// Stage the blocks of a commit, syncing those appended to a repository's blockstore since it was
+/// `len_before` bytes long to disk.
+pub async fn stage(
+    config: &RepoConfig,
+    did: &str,
+    root: Cid,
+    len_before: u64,
+) -> Result<StagedCommit> {
+    let f = tokio::fs::File::options()
+        .write(true)
+        .open(repo_path(config, did)?)
+        .await
+        .context("failed to open repository file")?;
+    f.sync_data()
+        .await
+        .context("failed to sync repository file")?;
+    let len_after = f
+        .metadata()
+        .await
+        .context("failed to query repository file")?
+        .len();
+
+    Ok(StagedCommit {
+        did: did.to_string(),
+        root,
+        len_before,
+        len_after,
+    })
+}
+
+/// Record a commit's staged blocks, as part of the transaction updating the head pointer.
+pub async fn record_staged(conn: &mut SqliteConnection, staged: &StagedCommit) -> Result<()> {
+    sqlx::query(
         r#"
-        SELECT root FROM accounts
-        WHERE did = ?
+        INSERT OR REPLACE INTO staged_commits (did, root, len_before, len_after)
+            VALUES (?, ?, ?, ?)
         "#,
-        did
     )
-    .fetch_one(db)
+    .bind(&staged.did)
+    .bind(staged.root.to_string())
+    .bind(staged.len_before as i64)
+    .bind(staged.len_after as i64)
+    .execute(conn)
     .await
-    .context("failed to query database")?;
+    .context("failed to record staged commit")?;
 
-    open_repo(config, did, Cid::from_str(&cid).unwrap()).await
+    Ok(())
 }
 
-pub async fn open_repo(
-    config: &RepoConfig,
-    did: impl Into<String>,
-    cid: Cid,
-) -> Result<Repository<impl AsyncBlockStoreRead + AsyncBlockStoreWrite>> {
-    let did = did.into();
-    let store = open_store(config, did)
+/// Mark a commit's staged blocks as durable, once the transaction updating the head pointer has
+/// committed.
+pub async fn mark_durable(db: &Db, staged: &StagedCommit) -> Result<()> {
+    sqlx::query(r#"DELETE FROM staged_commits WHERE did = ? AND root = ?"#)
+        .bind(&staged.did)
+        .bind(staged.root.to_string())
+        .execute(db)
         .await
-        .context("failed to open storage")?;
+        .context("failed to mark commit durable")?;
 
-    Ok(Repository::open(store, cid)
+    Ok(())
+}
+
+/// Forget any staged commit of a repository, once it has been replaced wholesale.
+pub async fn forget_staged(conn: &mut SqliteConnection, did: &str) -> Result<()> {
+    sqlx::query(r#"DELETE FROM staged_commits WHERE did = ?"#)
+        .bind(did)
+        .execute(conn)
         .await
-        .context("failed to open repo")?)
+        .context("failed to forget staged commit")?;
+
+    Ok(())
+}
+
+/// The outcome of recovering interrupted commits.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CommitRecovery {
+    /// The number of commits rolled forward.
+    pub completed: usize,
+    /// Repositories whose committed blocks were missing from the blockstore.
+    pub lost: Vec<String>,
+}
+
+/// Check that a staged commit's blocks are present in the blockstore.
+async fn staged_present(config: &RepoConfig, staged: &StagedCommit) -> Result<()> {
+    let len = repo_size(config, &staged.did).await?;
+    anyhow::ensure!(
+        len >= staged.len_after,
+        "blockstore is {len} bytes long, expected at least {}",
+        staged.len_after
+    );
+
+    let mut store = open_store(config, &staged.did).await?;
+    store
+        .read_block(staged.root)
+        .await
+        .context("commit block is missing")?;
+
+    Ok(())
+}
+
+/// Resolve commits interrupted between the commit point and being marked durable. This must run
+/// on startup, before any repository is written to.
+pub async fn recover(config: &RepoConfig, db: &Db) -> Result<CommitRecovery> {
+    let rows: Vec<(String, String, i64, i64)> =
+        sqlx::query_as(r#"SELECT did, root, len_before, len_after FROM staged_commits"#)
+            .fetch_all(db)
+            .await
+            .context("failed to find staged commits")?;
+
+    let mut recovery = CommitRecovery::default();
+    for (did, root, len_before, len_after) in rows {
+        let staged = StagedCommit {
+            root: Cid::from_str(&root).context("invalid staged commit root")?,
+            did,
+            len_before: len_before as u64,
+            len_after: len_after as u64,
+        };
+
+        match staged_present(config, &staged).await {
+            Ok(()) => {
+                info!(
+                    "rolled forward interrupted commit {} of {}",
+                    staged.root, staged.did
+                );
+                recovery.completed += 1;
+            }
+            Err(e) => {
+                error!(
+                    "committed blocks of {} at {} are missing from the blockstore: {e:#}",
+                    staged.did, staged.root
+                );
+                recovery.lost.push(staged.did.clone());
+            }
+        }
+
+        mark_durable(db, &staged).await?;
+    }
+
+    Ok(recovery)
+}
+
+#[cfg(test)]
+mod test {
+    use atrium_api::types::string::Did;
+    use atrium_crypto::keypair::Secp256k1Keypair;
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::SigningKey;
+
+    #[tokio::test]
+    async fn locks() {
+        let locks = RepoLocks::default();
+
+        let alice = locks.lock("did:plc:alice").await;
+        // Other repositories aren't held up.
+        drop(locks.lock("did:plc:bob").await);
+        assert!(tokio::time::timeout(
+            std::time::Duration::from_millis(20),
+            locks.lock("did:plc:alice")
+        )
+        .await
+        .is_err());
+
+        drop(alice);
+        drop(locks.lock("did:plc:alice").await);
+        assert!(locks
+            .0
+            .lock()
+            .unwrap()
+            .values()
+            .all(|l| l.strong_count() == 0));
+    }
+
+    #[test]
+    fn file_ids() {
+        for did in [
+            "did:plc:abcdefghijklmnopqrstuvwx",
+            "did:web:alice.example.com",
+        ] {
+            assert_eq!(file_did(&file_id(did).unwrap()), did);
+        }
+        assert_eq!(file_id("did:web:example.com").unwrap(), "web.example.com");
+
+        for did in [
+            "did:web:",
+            "did:web:example.com%3A8080",
+            "did:web:../x",
+            "did:key:z",
+        ] {
+            assert!(file_id(did).is_err(), "{did}");
+        }
+    }
+
+    /// Where a commit is interrupted.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Crash {
+        /// After appending the blocks, before syncing them.
+        BeforeStage,
+        /// After staging the blocks, before committing the transaction.
+        DuringTransaction,
+        /// After committing the transaction, before marking the blocks durable.
+        BeforeDurable,
+        Never,
+    }
+
+    /// Commit a record to a repository following the commit protocol, up until a crash. Returns
+    /// the root of the new commit.
+    async fn commit(
+        config: &RepoConfig,
+        db: &Db,
+        skey: &SigningKey,
+        did: &str,
+        rkey: &str,
+        crash: Crash,
+    ) -> Cid {
+        let head: String = sqlx::query_scalar(r#"SELECT root FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_one(db)
+            .await
+            .unwrap();
+        let len_before = repo_size(config, did).await.unwrap();
+
+        let key = format!("app.bsky.feed.post/{rkey}");
+        let mut repo = open_repo(config, did, Cid::from_str(&head).unwrap())
+            .await
+            .unwrap();
+        let (builder, _) = repo
+            .add_raw(&key, &serde_json::json!({ "text": rkey }))
+            .await
+            .unwrap();
+        let sig = skey.sign(&builder.bytes()).unwrap();
+        builder.finalize(sig).await.unwrap();
+        let (root, rev) = (repo.root(), repo.commit().rev().to_string());
+        drop(repo);
+
+        if crash == Crash::BeforeStage {
+            return root;
+        }
+        let staged = stage(config, did, root, len_before).await.unwrap();
+
+        let mut tx = db.begin().await.unwrap();
+        sqlx::query(r#"UPDATE accounts SET root = ?, rev = ? WHERE did = ?"#)
+            .bind(root.to_string())
+            .bind(rev)
+            .bind(did)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        sqlx::query(r#"INSERT INTO blob_ref (cid, did, record) VALUES ('bafyblob', ?, ?)"#)
+            .bind(did)
+            .bind(&key)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        record_staged(&mut tx, &staged).await.unwrap();
+
+        if crash == Crash::DuringTransaction {
+            // The transaction is rolled back when dropped.
+            return root;
+        }
+        tx.commit().await.unwrap();
+
+        if crash == Crash::BeforeDurable {
+            return root;
+        }
+        mark_durable(db, &staged).await.unwrap();
+
+        root
+    }
+
+    #[tokio::test]
+    async fn crash_recovery() {
+        let dir = crate::testing::temp_dir("storage");
+        let config: RepoConfig =
+            serde_json::from_value(serde_json::json!({ "path": dir })).unwrap();
+
+        let db = crate::testing::memory_db().await;
+
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let did = "did:plc:alice";
+        let (root, rev) = {
+            let file = tokio::fs::File::create(repo_path(&config, did).unwrap())
+                .await
+                .unwrap();
+            let mut store = CarStore::create(file).await.unwrap();
+            let builder = Repository::create(&mut store, Did::new(did.to_string()).unwrap())
+                .await
+                .unwrap();
+            let sig = skey.sign(&builder.bytes()).unwrap();
+            let repo = builder.finalize(sig).await.unwrap();
+            (repo.root(), repo.commit().rev().to_string())
+        };
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', ?, '', ?)"#,
+        )
+        .bind(did)
+        .bind(root.to_string())
+        .bind(&rev)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        // After recovery, the head, metadata and blockstore must agree.
+        let assert_consistent = |head: Cid, records: i64| {
+            let (config, db) = (config.clone(), db.clone());
+            async move {
+                let root: String = sqlx::query_scalar(r#"SELECT root FROM accounts WHERE did = ?"#)
+                    .bind(did)
+                    .fetch_one(&db)
+                    .await
+                    .unwrap();
+                assert_eq!(root, head.to_string());
+
+                let refs: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM blob_ref"#)
+                    .fetch_one(&db)
+                    .await
+                    .unwrap();
+                assert_eq!(refs, records);
+
+                let mut repo = open_repo(&config, did, head).await.unwrap();
+                let mut tree = repo.tree();
+                let keys: Vec<String> = Box::pin(tree.keys()).try_collect().await.unwrap();
+                assert_eq!(keys.len() as i64, records);
+
+                let staged: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM staged_commits"#)
+                    .fetch_one(&db)
+                    .await
+                    .unwrap();
+                assert_eq!(staged, 0);
+            }
+        };
+
+        // Commits interrupted before the commit point never happened.
+        for (i, crash) in [Crash::BeforeStage, Crash::DuringTransaction]
+            .into_iter()
+            .enumerate()
+        {
+            commit(&config, &db, &skey, did, &format!("lost{i}"), crash).await;
+            assert_eq!(
+                recover(&config, &db).await.unwrap(),
+                CommitRecovery::default(),
+                "{crash:?}"
+            );
+            assert_consistent(root, 0).await;
+        }
+
+        // Commits interrupted after the commit point are rolled forward.
+        let root = commit(&config, &db, &skey, did, "a", Crash::BeforeDurable).await;
+        let r = recover(&config, &db).await.unwrap();
+        assert_eq!((r.completed, r.lost.len()), (1, 0));
+        assert_consistent(root, 1).await;
+
+        // Uninterrupted commits need no recovery.
+        let root = commit(&config, &db, &skey, did, "b", Crash::Never).await;
+        assert_eq!(
+            recover(&config, &db).await.unwrap(),
+            CommitRecovery::default()
+        );
+        assert_consistent(root, 2).await;
+
+        // Committed blocks lost by storage are reported, but the commit isn't rolled back.
+        let root = commit(&config, &db, &skey, did, "c", Crash::BeforeDurable).await;
+        let len_before: i64 = sqlx::query_scalar(r#"SELECT len_before FROM staged_commits"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(repo_path(&config, did).unwrap())
+            .unwrap()
+            .set_len(len_before as u64)
+            .unwrap();
+
+        let r = recover(&config, &db).await.unwrap();
+        assert_eq!((r.completed, r.lost), (0, vec![did.to_string()]));
+        let head: String = sqlx::query_scalar(r#"SELECT root FROM accounts WHERE did = ?"#)
+            .bind(did)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(head, root.to_string());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
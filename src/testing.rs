@@ -0,0 +1,76 @@
+//! Fixtures shared by the unit tests.
+
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+use crate::{config::AppConfig, Db};
+
+/// Create a fresh directory for a test's files, with the `repo`, `blob` and `plc` directories
+/// already in place.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bluepds-{name}-{}", uuid::Uuid::new_v4()));
+    for sub in ["repo", "blob", "plc"] {
+        std::fs::create_dir_all(dir.join(sub)).unwrap();
+    }
+    dir
+}
+
+/// A test-mode configuration for `pds.example.com`, keeping its files under `dir` (or relative to
+/// the working directory, given an empty path, for tests that never touch them).
+///
+/// `overrides` is merged into the defaults, so that e.g. `{ "blob": { "resumable": true } }`
+/// replaces only that setting.
+pub fn config(dir: impl AsRef<Path>, overrides: Value) -> AppConfig {
+    let dir = dir.as_ref();
+    let mut config = json!({
+        "key": dir.join("default.key"),
+        "host_name": "pds.example.com",
+        "firehose": { "relays": [] },
+        "plc": { "path": dir.join("plc") },
+        "repo": { "path": dir.join("repo") },
+        "blob": { "path": dir.join("blob"), "limit": 1024 },
+        "db": "",
+        "test": true,
+    });
+    merge(&mut config, overrides);
+    serde_json::from_value(config).unwrap()
+}
+
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (k, v) in overrides {
+                merge(base.entry(k).or_insert(Value::Null), v);
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+/// A migrated in-memory database.
+pub async fn memory_db() -> Db {
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&db).await.unwrap();
+    db
+}
+
+/// A migrated database file in `dir`, for tests that race on real connections.
+pub async fn file_db(dir: &Path, max_connections: u32) -> Db {
+    let db = SqlitePoolOptions::new()
+        .max_connections(max_connections)
+        .connect_with(
+            SqliteConnectOptions::new()
+                .filename(dir.join("sqlite.db"))
+                .create_if_missing(true),
+        )
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&db).await.unwrap();
+    db
+}
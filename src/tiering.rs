@@ -0,0 +1,512 @@
+//! Tiering of inactive accounts' storage to an archive.
+//!
+//! Accounts that have been inactive (no writes, identity or status changes, blob uploads or logins)
+//! for `tiering.after_days` have their repository's blockstore, and the blobs only they reference,
+//! moved to the archive at `tiering.path` (e.g. a mounted bucket). Metadata stays in the database,
+//! so listing repositories and reporting their status is unaffected.
+//!
+//! The first access to a cold account's storage starts moving it back ("warming" it), and is
+//! answered with `ServiceUnavailable` and a `Retry-After` header until that's done.
+//!
+//! Files are copied before the tier is switched, and only removed from their previous location
+//! after, so an interrupted move leaves the account readable from wherever its tier says it is.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use chrono::{DateTime, Utc};
+use metrics::counter;
+use tracing::{info, warn};
+
+use crate::{
+    config::{AppConfig, RepoConfig, TieringConfig},
+    error::ErrorMessage,
+    events::{Event, EventBus, QUEUE_CAPACITY},
+    metrics::TIERING_MOVED,
+    storage, Db, Error, Result,
+};
+
+/// Where an account's storage currently lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    Hot,
+    /// In the archive.
+    Cold,
+    /// Being moved back from the archive.
+    Warming,
+}
+
+impl Tier {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "hot" => Some(Tier::Hot),
+            "cold" => Some(Tier::Cold),
+            "warming" => Some(Tier::Warming),
+            _ => None,
+        }
+    }
+}
+
+/// Moves the storage of accounts between tiers.
+#[derive(Debug, Clone)]
+pub struct Tiering {
+    config: TieringConfig,
+    repo: RepoConfig,
+    blobs: PathBuf,
+    db: Db,
+}
+
+impl Tiering {
+    pub fn new(config: &AppConfig, db: Db) -> Self {
+        Self {
+            config: config.tiering.clone(),
+            repo: config.repo.clone(),
+            blobs: config.blob.path.clone(),
+            db,
+        }
+    }
+
+    /// Return the tier of an account's storage.
+    pub async fn tier(&self, did: &str) -> anyhow::Result<Tier> {
+        let tier: Option<String> =
+            sqlx::query_scalar(r#"SELECT tier FROM account_tiers WHERE did = ?"#)
+                .bind(did)
+                .fetch_optional(&self.db)
+                .await
+                .context("failed to query account tier")?;
+
+        Ok(tier.as_deref().and_then(Tier::parse).unwrap_or(Tier::Hot))
+    }
+
+    /// Check that an account's storage is hot. If it isn't, it's moved back in the background, and
+    /// a `ServiceUnavailable` error asks the client to retry once that's done.
+    pub async fn ensure_hot(&self, did: &str) -> Result<()> {
+        if self.tier(did).await? == Tier::Hot {
+            return Ok(());
+        }
+
+        // Only the first request to find the account cold starts moving it back.
+        let claimed = sqlx::query(
+            r#"UPDATE account_tiers SET tier = 'warming' WHERE did = ? AND tier = 'cold'"#,
+        )
+        .bind(did)
+        .execute(&self.db)
+        .await
+        .context("failed to update account tier")?
+        .rows_affected()
+            == 1;
+        if claimed {
+            let tiering = self.clone();
+            let did = did.to_string();
+            tokio::spawn(async move {
+                if let Err(e) = tiering.rehydrate(&did).await {
+                    warn!("failed to move {did} back from the archive: {e:?}");
+                    let _ = sqlx::query(r#"UPDATE account_tiers SET tier = 'cold' WHERE did = ?"#)
+                        .bind(&did)
+                        .execute(&tiering.db)
+                        .await;
+                }
+            });
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            axum::http::header::RETRY_AFTER,
+            HeaderValue::from(self.config.retry_after.max(1)),
+        );
+        Err(Error::with_message(
+            StatusCode::SERVICE_UNAVAILABLE,
+            anyhow!("account {did} is being moved back from the archive"),
+            ErrorMessage::new(
+                "ServiceUnavailable",
+                "This account is being restored from archive storage. Please retry shortly.",
+            ),
+        )
+        .with_headers(headers))
+    }
+
+    /// The path of the archive, if tiering is configured.
+    fn archive(&self) -> anyhow::Result<&Path> {
+        self.config
+            .path
+            .as_deref()
+            .context("no archive path is configured")
+    }
+
+    /// The locations of an account's repository: hot, and in the archive.
+    fn repo_paths(&self, did: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
+        let hot = storage::repo_path(&self.repo, did)?;
+        let cold = self
+            .archive()?
+            .join("repo")
+            .join(hot.file_name().context("invalid repository path")?);
+        Ok((hot, cold))
+    }
+
+    /// The locations of a blob: hot, and in the archive.
+    fn blob_paths(&self, cid: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
+        let name = format!("{cid}.blob");
+        Ok((
+            self.blobs.join(&name),
+            self.archive()?.join("blob").join(name),
+        ))
+    }
+
+    /// Remove an account's repository and the specified blobs, from whichever tier holds them.
+    pub async fn remove(&self, did: &str, blobs: &[String]) -> anyhow::Result<()> {
+        let mut files = vec![storage::repo_path(&self.repo, did)?];
+        files.extend(
+            blobs
+                .iter()
+                .map(|cid| self.blobs.join(format!("{cid}.blob"))),
+        );
+        if self.archive().is_ok() {
+            files.push(self.repo_paths(did)?.1);
+            for cid in blobs {
+                files.push(self.blob_paths(cid)?.1);
+            }
+        }
+
+        for file in &files {
+            match tokio::fs::remove_file(file).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("failed to remove {file:?}")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Move an account's storage to the archive, if it has not been active since `last_active`.
+    /// Returns whether it was moved.
+    async fn archive_account(&self, did: &str, last_active: &str) -> anyhow::Result<bool> {
+        // Blobs referenced by other accounts stay hot, as they may be read through them.
+        let blobs: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT DISTINCT cid FROM blob_ref
+                WHERE did = ? AND cid NOT IN (SELECT cid FROM blob_ref WHERE did != ?)
+            "#,
+        )
+        .bind(did)
+        .bind(did)
+        .fetch_all(&self.db)
+        .await
+        .context("failed to query blobs")?;
+
+        let mut files = vec![self.repo_paths(did)?];
+        for cid in &blobs {
+            let (hot, cold) = self.blob_paths(cid)?;
+            if tokio::fs::try_exists(&hot).await.unwrap_or(false) {
+                files.push((hot, cold));
+            }
+        }
+
+        for (hot, cold) in &files {
+            copy_file(hot, cold).await?;
+        }
+
+        // The account may have become active while its files were copied.
+        let moved = sqlx::query(
+            r#"
+            UPDATE account_tiers SET tier = 'cold', tiered_at = CURRENT_TIMESTAMP
+                WHERE did = ? AND tier = 'hot' AND last_active = ?
+            "#,
+        )
+        .bind(did)
+        .bind(last_active)
+        .execute(&self.db)
+        .await
+        .context("failed to update account tier")?
+        .rows_affected()
+            == 1;
+
+        for (hot, cold) in &files {
+            let stale = if moved { hot } else { cold };
+            tokio::fs::remove_file(stale)
+                .await
+                .with_context(|| format!("failed to remove {stale:?}"))?;
+        }
+
+        if moved {
+            counter!(TIERING_MOVED, "direction" => "archived").increment(1);
+        }
+        Ok(moved)
+    }
+
+    /// Move an account's storage back from the archive.
+    async fn rehydrate(&self, did: &str) -> anyhow::Result<()> {
+        let blobs: Vec<String> =
+            sqlx::query_scalar(r#"SELECT DISTINCT cid FROM blob_ref WHERE did = ?"#)
+                .bind(did)
+                .fetch_all(&self.db)
+                .await
+                .context("failed to query blobs")?;
+
+        let mut files = vec![self.repo_paths(did)?];
+        for cid in &blobs {
+            let (hot, cold) = self.blob_paths(cid)?;
+            if tokio::fs::try_exists(&cold).await.unwrap_or(false) {
+                files.push((hot, cold));
+            }
+        }
+
+        for (hot, cold) in &files {
+            copy_file(cold, hot).await?;
+        }
+
+        // Moving back counts as activity, so the account isn't archived again straight away.
+        sqlx::query(
+            r#"
+            UPDATE account_tiers
+                SET tier = 'hot', tiered_at = CURRENT_TIMESTAMP, last_active = CURRENT_TIMESTAMP
+                WHERE did = ?
+            "#,
+        )
+        .bind(did)
+        .execute(&self.db)
+        .await
+        .context("failed to update account tier")?;
+
+        for (_, cold) in &files {
+            tokio::fs::remove_file(cold)
+                .await
+                .with_context(|| format!("failed to remove {cold:?}"))?;
+        }
+
+        counter!(TIERING_MOVED, "direction" => "rehydrated").increment(1);
+        info!("moved {did} back from the archive");
+        Ok(())
+    }
+
+    /// Move the storage of accounts inactive as of `now` to the archive, returning the number of
+    /// accounts moved.
+    pub async fn archive_inactive(&self, now: DateTime<Utc>) -> anyhow::Result<u64> {
+        self.archive()?;
+
+        let cutoff = now - chrono::Duration::days(self.config.after_days as i64);
+        let inactive: Vec<(String, String)> = sqlx::query_as(
+            r#"
+            SELECT did, last_active FROM account_tiers
+                WHERE tier = 'hot' AND last_active <= ?
+                ORDER BY last_active
+            "#,
+        )
+        .bind(cutoff.format("%Y-%m-%d %H:%M:%S").to_string())
+        .fetch_all(&self.db)
+        .await
+        .context("failed to query inactive accounts")?;
+
+        let mut moved = 0;
+        for (did, last_active) in inactive {
+            match self.archive_account(&did, &last_active).await {
+                Ok(true) => moved += 1,
+                Ok(false) => {}
+                Err(e) => warn!("failed to move {did} to the archive: {e:?}"),
+            }
+        }
+
+        Ok(moved)
+    }
+}
+
+/// Copy a file, syncing the copy before it's put in place.
+async fn copy_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = to.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("failed to create {parent:?}"))?;
+    }
+
+    let tmp = to.with_extension("tmp");
+    tokio::fs::copy(from, &tmp)
+        .await
+        .with_context(|| format!("failed to copy {from:?}"))?;
+    tokio::fs::File::open(&tmp)
+        .await
+        .context("failed to open copy")?
+        .sync_all()
+        .await
+        .context("failed to sync copy")?;
+    tokio::fs::rename(&tmp, to)
+        .await
+        .with_context(|| format!("failed to move copy to {to:?}"))?;
+
+    Ok(())
+}
+
+/// Record that an account was active just now.
+pub async fn touch(db: &Db, did: &str) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO account_tiers (did, last_active) VALUES (?, CURRENT_TIMESTAMP)
+            ON CONFLICT (did) DO UPDATE SET last_active = excluded.last_active
+        "#,
+    )
+    .bind(did)
+    .execute(db)
+    .await
+    .context("failed to record account activity")?;
+
+    Ok(())
+}
+
+/// Subscribe activity tracking to the bus.
+pub fn subscribe(bus: &mut EventBus, db: Db) {
+    bus.subscribe("tiering", QUEUE_CAPACITY, move |event| {
+        let db = db.clone();
+        async move {
+            let did = match &event {
+                Event::Commit(commit) => commit.did.to_string(),
+                Event::Account(account) => account.did.to_string(),
+                Event::Identity(identity) => identity.did.to_string(),
+                Event::Sync(sync) => sync.did.to_string(),
+                Event::BlobUploaded { did, .. } => did.clone(),
+            };
+
+            if let Err(e) = touch(&db, &did).await {
+                warn!("failed to record activity of {did}: {e:?}");
+            }
+        }
+    });
+}
+
+/// Periodically move the storage of inactive accounts to the archive.
+pub async fn run(tiering: Tiering) {
+    // Accounts left warming by a restart are still cold, and will be moved back on next access.
+    if let Err(e) = sqlx::query(r#"UPDATE account_tiers SET tier = 'cold' WHERE tier = 'warming'"#)
+        .execute(&tiering.db)
+        .await
+    {
+        warn!("failed to reset warming accounts: {e:?}");
+    }
+
+    if tiering.config.path.is_none() {
+        return;
+    }
+
+    loop {
+        match tiering.archive_inactive(Utc::now()).await {
+            Ok(0) => {}
+            Ok(n) => info!("moved {n} inactive accounts to the archive"),
+            Err(e) => warn!("failed to move inactive accounts to the archive: {e:?}"),
+        }
+
+        tokio::time::sleep(Duration::from_secs(tiering.config.interval)).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use atrium_api::types::string::Did;
+    use atrium_crypto::keypair::Secp256k1Keypair;
+    use atrium_repo::{blockstore::CarStore, Repository};
+    use axum::response::IntoResponse;
+
+    use super::*;
+    use crate::SigningKey;
+
+    #[tokio::test]
+    async fn archive_and_rehydrate() {
+        let dir = crate::testing::temp_dir("tiering");
+        let config = crate::testing::config(
+            &dir,
+            serde_json::json!({
+                "tiering": { "path": dir.join("archive"), "after_days": 30, "retry_after": 5 },
+            }),
+        );
+
+        let db = crate::testing::memory_db().await;
+
+        let skey = SigningKey(Arc::new(Secp256k1Keypair::create(&mut rand::thread_rng())));
+        let did = "did:plc:alice";
+        let root = {
+            let file = tokio::fs::File::create(storage::repo_path(&config.repo, did).unwrap())
+                .await
+                .unwrap();
+            let mut store = CarStore::create(file).await.unwrap();
+            let builder = Repository::create(&mut store, Did::new(did.to_string()).unwrap())
+                .await
+                .unwrap();
+            let sig = skey.sign(&builder.bytes()).unwrap();
+            builder.finalize(sig).await.unwrap().root()
+        };
+        sqlx::query(
+            r#"INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES (?, '', '', ?, '', '')"#,
+        )
+        .bind(did)
+        .bind(root.to_string())
+        .execute(&db)
+        .await
+        .unwrap();
+        touch(&db, did).await.unwrap();
+
+        // A blob only this account references, and one shared with another account.
+        for (cid, dids) in [
+            ("bafyowned", vec![did]),
+            ("bafyshared", vec![did, "did:plc:bob"]),
+        ] {
+            std::fs::write(dir.join("blob").join(format!("{cid}.blob")), cid).unwrap();
+            for d in dids {
+                sqlx::query(r#"INSERT INTO blob_ref (cid, did, record) VALUES (?, ?, NULL)"#)
+                    .bind(cid)
+                    .bind(d)
+                    .execute(&db)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let tiering = Tiering::new(&config, db.clone());
+        let repo = storage::repo_path(&config.repo, did).unwrap();
+        let archived = dir
+            .join("archive")
+            .join("repo")
+            .join(repo.file_name().unwrap());
+
+        // A recently active account stays put.
+        assert_eq!(tiering.archive_inactive(Utc::now()).await.unwrap(), 0);
+        assert_eq!(tiering.tier(did).await.unwrap(), Tier::Hot);
+        tiering.ensure_hot(did).await.unwrap();
+
+        // A month later, it's moved to the archive, apart from the shared blob.
+        let later = Utc::now() + chrono::Duration::days(31);
+        assert_eq!(tiering.archive_inactive(later).await.unwrap(), 1);
+        assert_eq!(tiering.tier(did).await.unwrap(), Tier::Cold);
+        assert!(!repo.exists());
+        assert!(archived.exists());
+        assert!(!dir.join("blob").join("bafyowned.blob").exists());
+        assert!(dir.join("archive/blob/bafyowned.blob").exists());
+        assert!(dir.join("blob").join("bafyshared.blob").exists());
+
+        // The first access asks the client to come back later, and starts moving it back.
+        let resp = tiering.ensure_hot(did).await.unwrap_err().into_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[axum::http::header::RETRY_AFTER], "5");
+
+        let mut tries = 0;
+        while tiering.ensure_hot(did).await.is_err() {
+            tries += 1;
+            assert!(tries < 500, "account was not moved back");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Once hot, it's read normally.
+        assert!(repo.exists());
+        assert!(!archived.exists());
+        assert_eq!(
+            std::fs::read(dir.join("blob").join("bafyowned.blob")).unwrap(),
+            b"bafyowned"
+        );
+        let repo = storage::open_repo(&config.repo, did, root).await.unwrap();
+        assert_eq!(repo.root(), root);
+
+        // Moving back counts as activity.
+        assert_eq!(tiering.archive_inactive(Utc::now()).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,165 @@
+//! Per-stage timing of the repository commit pipeline.
+//!
+//! Each commit accumulates the time spent in each [`Stage`] (a commit with several writes visits
+//! some stages more than once), and emits one histogram sample per stage once it completes.
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use metrics::histogram;
+use tracing::{debug_span, Instrument};
+
+use crate::metrics::{REPO_COMMIT_DURATION, REPO_COMMIT_STAGE};
+
+/// A stage of the commit pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Waiting for the repository's write lock.
+    LockWait,
+    /// Modifying the MST.
+    Mst,
+    /// Writing the commit block and collecting blocks for the firehose.
+    BlockWrite,
+    /// Signing the commit.
+    Sign,
+    /// Waiting to begin the database transaction that moves the repository head.
+    DbBegin,
+    /// Moving the repository head and updating indexes in the database.
+    Sequence,
+    /// Publishing the commit to the event bus, for the firehose (and other subscribers).
+    Firehose,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 7] = [
+        Stage::LockWait,
+        Stage::Mst,
+        Stage::BlockWrite,
+        Stage::Sign,
+        Stage::DbBegin,
+        Stage::Sequence,
+        Stage::Firehose,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::LockWait => "lock_wait",
+            Stage::Mst => "mst",
+            Stage::BlockWrite => "block_write",
+            Stage::Sign => "sign",
+            Stage::DbBegin => "db_begin",
+            Stage::Sequence => "sequence",
+            Stage::Firehose => "firehose",
+        }
+    }
+}
+
+/// Accumulates the time spent in each stage of a single commit.
+#[derive(Debug)]
+pub struct CommitTimer {
+    start: Instant,
+    stages: [Duration; Stage::ALL.len()],
+}
+
+impl Default for CommitTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommitTimer {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            stages: Default::default(),
+        }
+    }
+
+    /// Run a future as part of a stage, attributing the time it takes to that stage.
+    pub async fn time<F: Future>(&mut self, stage: Stage, fut: F) -> F::Output {
+        let start = Instant::now();
+        let r = fut
+            .instrument(debug_span!("commit_stage", stage = stage.as_str()))
+            .await;
+
+        self.stages[stage as usize] += start.elapsed();
+        r
+    }
+
+    /// Attribute time to a stage that was measured separately.
+    pub fn add(&mut self, stage: Stage, elapsed: Duration) {
+        self.stages[stage as usize] += elapsed;
+    }
+
+    /// Emit the stage timings for this commit.
+    pub fn record(self) {
+        for stage in Stage::ALL {
+            histogram!(REPO_COMMIT_STAGE, "stage" => stage.as_str())
+                .record(self.stages[stage as usize].as_secs_f64());
+        }
+
+        histogram!(REPO_COMMIT_DURATION).record(self.start.elapsed().as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn stages() {
+        let mut timer = CommitTimer::new();
+        for stage in Stage::ALL {
+            timer
+                .time(stage, tokio::time::sleep(Duration::from_millis(10)))
+                .await;
+        }
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || timer.record());
+
+        let mut stages = Vec::new();
+        let mut sum = 0.0;
+        let mut total = 0.0;
+        for (key, _, _, value) in snapshotter.snapshot().into_vec() {
+            let DebugValue::Histogram(v) = value else {
+                panic!("unexpected metric {}", key.key().name());
+            };
+            assert_eq!(v.len(), 1);
+
+            match key.key().name() {
+                REPO_COMMIT_STAGE => {
+                    let stage = key.key().labels().next().unwrap().value().to_string();
+                    stages.push(stage);
+                    sum += v[0].into_inner();
+                }
+                REPO_COMMIT_DURATION => total = v[0].into_inner(),
+                name => panic!("unexpected metric {name}"),
+            }
+        }
+
+        stages.sort();
+        assert_eq!(
+            stages,
+            [
+                "block_write",
+                "db_begin",
+                "firehose",
+                "lock_wait",
+                "mst",
+                "sequence",
+                "sign"
+            ]
+        );
+
+        // The stages account for (nearly) all of the commit.
+        assert!(sum >= 0.07);
+        assert!(sum <= total);
+        assert!(total - sum < 0.01, "stages {sum}s of {total}s");
+    }
+}
@@ -0,0 +1,294 @@
+//! Handling of XRPC methods that aren't served by this PDS.
+//!
+//! Requests for methods without a local route fall through to here. Methods in namespaces served
+//! by other services (e.g. `app.bsky.*`), or explicitly directed elsewhere with `atproto-proxy`,
+//! are proxied as before. Of the rest:
+//!
+//! - Known atproto methods that this PDS doesn't implement (yet) fail with `MethodNotImplemented`
+//!   (501), so that clients can tell an old PDS from a mistake on their end.
+//! - Anything else is unknown, and fails with `InvalidRequest` (404).
+//!
+//! Both are logged at debug level, and tallied per method. The tally is logged and reset daily, so
+//! that maintainers can see which methods are most in demand.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    handler::Handler,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use metrics::counter;
+use serde::Serialize;
+use tracing::{debug, info};
+
+use crate::{error::ErrorMessage, metrics::XRPC_UNSUPPORTED, nsid, proxy, AppState};
+
+/// Methods in this PDS's own namespace that are served by other services, and are proxied.
+const UPSTREAM: &[&str] = &[
+    "com.atproto.label.queryLabels",
+    "com.atproto.moderation.createReport",
+];
+
+/// Known atproto methods that this PDS does not implement.
+const UNIMPLEMENTED: &[&str] = &[
+    "com.atproto.admin.deleteAccount",
+    "com.atproto.admin.disableAccountInvites",
+    "com.atproto.admin.enableAccountInvites",
+    "com.atproto.admin.getAccountInfo",
+    "com.atproto.admin.getAccountInfos",
+    "com.atproto.admin.getInviteCodes",
+    "com.atproto.admin.getSubjectStatus",
+    "com.atproto.admin.searchAccounts",
+    "com.atproto.admin.sendEmail",
+    "com.atproto.admin.updateAccountEmail",
+    "com.atproto.admin.updateAccountPassword",
+    "com.atproto.admin.updateSubjectStatus",
+    "com.atproto.sync.getCheckout",
+    "com.atproto.sync.getHead",
+    "com.atproto.sync.getHostStatus",
+    "com.atproto.sync.listHosts",
+    "com.atproto.sync.listReposByCollection",
+    "com.atproto.sync.requestCrawl",
+    "com.atproto.temp.addReservedHandle",
+    "com.atproto.temp.fetchLabels",
+    "com.atproto.temp.requestPhoneVerification",
+];
+
+/// The namespace of this PDS's own methods. Unknown methods in it are never proxied.
+const LOCAL_NAMESPACE: &str = "com.atproto.";
+
+/// The maximum number of distinct methods tallied per day, bounding the memory used by clients
+/// probing random paths. Methods beyond that are tallied together.
+const MAX_TALLIED: usize = 1000;
+/// The method under which methods beyond [`MAX_TALLIED`] are tallied.
+const OTHER: &str = "(other)";
+
+/// Why a method isn't served.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum Unsupported {
+    /// A known method that isn't implemented.
+    Unimplemented,
+    /// A method this PDS knows nothing about.
+    Unknown,
+}
+
+impl Unsupported {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unimplemented => "unimplemented",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// A method requested since the tally was last reset, and how often.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TallyEntry {
+    pub method: String,
+    pub kind: Unsupported,
+    pub count: u64,
+}
+
+/// A per-method tally of requests for unsupported methods.
+#[derive(Clone, Default)]
+pub struct MethodTally(Arc<Mutex<HashMap<(Unsupported, String), u64>>>);
+
+impl MethodTally {
+    fn record(&self, kind: Unsupported, method: &str) {
+        let mut tally = self.0.lock().unwrap();
+        let method = if tally.len() < MAX_TALLIED || tally.contains_key(&(kind, method.to_string()))
+        {
+            method
+        } else {
+            OTHER
+        };
+
+        *tally.entry((kind, method.to_string())).or_default() += 1;
+    }
+
+    /// The current tally, most requested first.
+    pub fn snapshot(&self) -> Vec<TallyEntry> {
+        let mut entries = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((kind, method), count)| TallyEntry {
+                method: method.clone(),
+                kind: *kind,
+                count: *count,
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.method.cmp(&b.method)));
+        entries
+    }
+
+    /// Take the current tally, resetting it.
+    fn take(&self) -> Vec<TallyEntry> {
+        let entries = self.snapshot();
+        self.0.lock().unwrap().clear();
+        entries
+    }
+}
+
+/// Classify a method without a local route. Returns `None` if the method should be proxied.
+fn classify(method: &str, headers: &HeaderMap) -> Option<Unsupported> {
+    if nsid::validate(method).is_err() {
+        return Some(Unsupported::Unknown);
+    }
+    if UNIMPLEMENTED.contains(&method) {
+        return Some(Unsupported::Unimplemented);
+    }
+    if headers.contains_key("atproto-proxy")
+        || UPSTREAM.contains(&method)
+        || !method.starts_with(LOCAL_NAMESPACE)
+    {
+        return None;
+    }
+
+    Some(Unsupported::Unknown)
+}
+
+/// Check whether a method without a local route is served elsewhere, returning the error response
+/// if it isn't.
+pub fn check(tally: &MethodTally, method: &str, headers: &HeaderMap) -> Option<Response> {
+    let kind = classify(method, headers)?;
+
+    debug!("request for {} method {method:?}", kind.as_str());
+    tally.record(kind, method);
+
+    Some(match kind {
+        Unsupported::Unimplemented => {
+            counter!(XRPC_UNSUPPORTED, "kind" => kind.as_str(), "method" => method.to_string())
+                .increment(1);
+
+            (
+                StatusCode::NOT_IMPLEMENTED,
+                Json(ErrorMessage::new(
+                    "MethodNotImplemented",
+                    format!("Method not implemented: {method}"),
+                )),
+            )
+                .into_response()
+        }
+        Unsupported::Unknown => {
+            counter!(XRPC_UNSUPPORTED, "kind" => kind.as_str()).increment(1);
+
+            (
+                StatusCode::NOT_FOUND,
+                Json(ErrorMessage::new(
+                    "InvalidRequest",
+                    format!("Unknown method: {method}"),
+                )),
+            )
+                .into_response()
+        }
+    })
+}
+
+/// The fallback for XRPC requests without a local route.
+pub async fn fallback(State(state): State<AppState>, request: Request) -> Response {
+    let method = request.uri().path().trim_start_matches('/').to_string();
+
+    match check(&state.method_tally, &method, request.headers()) {
+        Some(response) => response,
+        None => proxy::service_proxy.call(request, state).await,
+    }
+}
+
+/// Log and reset the tally of unsupported methods once a day.
+pub async fn report(tally: MethodTally) {
+    let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+    // The first tick completes immediately.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        for e in tally.take() {
+            info!(
+                "{} method {:?} requested {} times today",
+                e.kind.as_str(),
+                e.method,
+                e.count
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{routing::get, Router};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn methods() {
+        let tally = MethodTally::default();
+        let t = tally.clone();
+        let app = Router::new()
+            .route("/com.atproto.server.describeServer", get(|| async { "ok" }))
+            .fallback(|request: Request| async move {
+                let method = request.uri().path().trim_start_matches('/').to_string();
+                check(&t, &method, request.headers())
+                    .unwrap_or_else(|| StatusCode::BAD_GATEWAY.into_response())
+            });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        for (method, status, error) in [
+            ("com.atproto.server.describeServer", StatusCode::OK, None),
+            (
+                "com.atproto.admin.deleteAccount",
+                StatusCode::NOT_IMPLEMENTED,
+                Some("MethodNotImplemented"),
+            ),
+            (
+                "com.atproto.server.describeServr",
+                StatusCode::NOT_FOUND,
+                Some("InvalidRequest"),
+            ),
+            (
+                "not a method",
+                StatusCode::NOT_FOUND,
+                Some("InvalidRequest"),
+            ),
+            // Other namespaces are proxied.
+            ("app.bsky.feed.getTimeline", StatusCode::BAD_GATEWAY, None),
+        ] {
+            let resp = client
+                .get(format!("http://{addr}/{method}"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), status, "{method}");
+
+            if let Some(error) = error {
+                let body: serde_json::Value = resp.json().await.unwrap();
+                assert_eq!(body["error"], error, "{method}");
+            }
+        }
+
+        let tally = tally.snapshot();
+        assert_eq!(tally.len(), 3);
+        assert!(tally.contains(&TallyEntry {
+            method: "com.atproto.admin.deleteAccount".to_string(),
+            kind: Unsupported::Unimplemented,
+            count: 1,
+        }));
+        assert!(tally
+            .iter()
+            .all(|e| e.method != "com.atproto.server.describeServer"));
+    }
+}
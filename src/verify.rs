@@ -0,0 +1,290 @@
+//! Verification that an upstream relay's view of hosted repositories matches local state.
+//!
+//! The relay is periodically asked for the latest commit of each active hosted repository. Any
+//! repository whose head on the relay differs from ours is tracked, and an alert is raised if it
+//! remains divergent for longer than the configured grace period.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use atrium_api::com::atproto::sync;
+use metrics::gauge;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{config::RelayVerifyConfig, metrics::RELAY_DIVERGENT_REPOS, Client, Db};
+
+/// A repository whose head on the relay does not match local state.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RepoDivergence {
+    pub did: String,
+    /// The revision of the repository on this PDS.
+    pub local_rev: String,
+    /// The revision of the repository according to the relay, if the relay knows of it.
+    pub relay_rev: Option<String>,
+    /// How long the repository has been divergent, in seconds.
+    pub divergent_for: u64,
+    /// Whether the divergence has outlasted the grace period.
+    pub alert: bool,
+}
+
+/// Periodically compares hosted repositories against a relay.
+#[derive(Clone)]
+pub struct RelayVerifier {
+    config: Option<RelayVerifyConfig>,
+    client: Client,
+    db: Db,
+    /// The point in time at which each divergent repository was first seen diverging.
+    divergent: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl RelayVerifier {
+    pub fn new(config: Option<RelayVerifyConfig>, client: Client, db: Db) -> Self {
+        Self {
+            config,
+            client,
+            db,
+            divergent: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether relay verification has been configured.
+    pub fn enabled(&self) -> bool {
+        self.config.is_some()
+    }
+
+    /// Fetch the relay's view of a repository's head revision.
+    async fn relay_rev(&self, config: &RelayVerifyConfig, did: &str) -> Result<Option<String>> {
+        let r = self
+            .client
+            .get(
+                config
+                    .relay
+                    .join(&format!(
+                        "/xrpc/{}?did={did}",
+                        sync::get_latest_commit::NSID
+                    ))
+                    .context("failed to construct relay URL")?,
+            )
+            .send()
+            .await
+            .context("failed to query relay")?;
+
+        // The relay reports repositories it doesn't know of as an error (e.g. `RepoNotFound`).
+        if r.status().is_client_error() {
+            return Ok(None);
+        }
+
+        let r: sync::get_latest_commit::Output = r
+            .error_for_status()
+            .context("relay returned an error")?
+            .json()
+            .await
+            .context("failed to decode relay response")?;
+
+        Ok(Some(r.rev.to_string()))
+    }
+
+    /// Compare all active hosted repositories against the relay, returning those that diverge.
+    pub async fn check(&self) -> Result<Vec<RepoDivergence>> {
+        self.check_at(Instant::now()).await
+    }
+
+    async fn check_at(&self, now: Instant) -> Result<Vec<RepoDivergence>> {
+        let Some(config) = &self.config else {
+            return Ok(Vec::new());
+        };
+        let grace = Duration::from_secs(config.grace_period);
+
+        let accounts: Vec<(String, String)> =
+            sqlx::query_as(r#"SELECT did, rev FROM accounts WHERE status = 'active' ORDER BY did"#)
+                .fetch_all(&self.db)
+                .await
+                .context("failed to query accounts")?;
+
+        let mut report = Vec::new();
+        for (did, local_rev) in accounts {
+            let relay_rev = match self.relay_rev(config, &did).await {
+                Ok(r) => r,
+                Err(e) => {
+                    // Don't count relay outages against individual repositories.
+                    warn!("failed to fetch {did} from relay {}: {e:?}", config.relay);
+                    continue;
+                }
+            };
+
+            let mut divergent = self.divergent.lock().unwrap();
+            if relay_rev.as_deref() == Some(local_rev.as_str()) {
+                divergent.remove(&did);
+                continue;
+            }
+
+            let since = *divergent.entry(did.clone()).or_insert(now);
+            let divergent_for = now.duration_since(since);
+
+            report.push(RepoDivergence {
+                did,
+                local_rev,
+                relay_rev,
+                divergent_for: divergent_for.as_secs(),
+                alert: divergent_for >= grace,
+            });
+        }
+
+        // Forget about repositories that no longer exist or are no longer active.
+        self.divergent
+            .lock()
+            .unwrap()
+            .retain(|did, _| report.iter().any(|r| &r.did == did));
+
+        let alerts = report.iter().filter(|r| r.alert).collect::<Vec<_>>();
+        for r in &alerts {
+            warn!(
+                "relay {} has diverged on {} for {}s (local rev {}, relay rev {})",
+                config.relay,
+                r.did,
+                r.divergent_for,
+                r.local_rev,
+                r.relay_rev.as_deref().unwrap_or("<none>"),
+            );
+        }
+
+        gauge!(RELAY_DIVERGENT_REPOS).set(alerts.len() as f64);
+        Ok(report)
+    }
+}
+
+/// Periodically verify hosted repositories against the configured relay, if any.
+pub async fn run(verifier: RelayVerifier) {
+    let Some(config) = verifier.config.clone() else {
+        return;
+    };
+
+    info!("verifying repositories against relay {}", config.relay);
+    loop {
+        tokio::time::sleep(Duration::from_secs(config.interval)).await;
+
+        if let Err(e) = verifier.check().await {
+            warn!("failed to verify repositories against relay: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{extract::Query, http::StatusCode, response::IntoResponse, routing::get, Json};
+
+    use super::*;
+
+    /// A mock relay that is in sync with `did:plc:alice`, behind on `did:plc:bob`, and has never
+    /// heard of `did:plc:carol`.
+    async fn mock_relay() -> url::Url {
+        async fn get_latest_commit(
+            Query(q): Query<HashMap<String, String>>,
+        ) -> axum::response::Response {
+            let rev = match q["did"].as_str() {
+                "did:plc:alice" => "3jzfcijpj2z2b",
+                "did:plc:bob" => "3jzfcijpj2z2a",
+                _ => {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        Json(serde_json::json!({ "error": "RepoNotFound" })),
+                    )
+                        .into_response()
+                }
+            };
+
+            Json(serde_json::json!({
+                "cid": "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm",
+                "rev": rev,
+            }))
+            .into_response()
+        }
+
+        let app = axum::Router::new().route(
+            "/xrpc/com.atproto.sync.getLatestCommit",
+            get(get_latest_commit),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        format!("http://{addr}").parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn divergent_repo() {
+        let db = crate::testing::memory_db().await;
+
+        sqlx::query(
+            r#"
+            INSERT INTO accounts (did, email, password, root, plc_root, rev) VALUES
+                ('did:plc:alice', 'alice@example.com', '', '', '', '3jzfcijpj2z2b'),
+                ('did:plc:bob', 'bob@example.com', '', '', '', '3jzfcijpj2z2b'),
+                ('did:plc:carol', 'carol@example.com', '', '', '', '3jzfcijpj2z2b');
+            "#,
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let verifier = RelayVerifier::new(
+            Some(RelayVerifyConfig {
+                relay: mock_relay().await,
+                interval: 60,
+                grace_period: 60,
+            }),
+            reqwest_middleware::ClientBuilder::new(reqwest::Client::new()).build(),
+            db.clone(),
+        );
+
+        // Divergent repositories are reported, but not alerted on within the grace period.
+        let now = Instant::now();
+        let report = verifier.check_at(now).await.unwrap();
+        assert_eq!(
+            report,
+            [
+                RepoDivergence {
+                    did: "did:plc:bob".to_string(),
+                    local_rev: "3jzfcijpj2z2b".to_string(),
+                    relay_rev: Some("3jzfcijpj2z2a".to_string()),
+                    divergent_for: 0,
+                    alert: false,
+                },
+                RepoDivergence {
+                    did: "did:plc:carol".to_string(),
+                    local_rev: "3jzfcijpj2z2b".to_string(),
+                    relay_rev: None,
+                    divergent_for: 0,
+                    alert: false,
+                },
+            ]
+        );
+
+        // Once the grace period has elapsed, the divergence is alerted on.
+        let report = verifier
+            .check_at(now + Duration::from_secs(61))
+            .await
+            .unwrap();
+        assert_eq!(report.len(), 2);
+        assert!(report.iter().all(|r| r.alert && r.divergent_for == 61));
+
+        // The relay catches up on bob; the divergence is cleared.
+        sqlx::query(r#"UPDATE accounts SET rev = '3jzfcijpj2z2a' WHERE did = 'did:plc:bob'"#)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let report = verifier
+            .check_at(now + Duration::from_secs(120))
+            .await
+            .unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].did, "did:plc:carol");
+    }
+}